@@ -0,0 +1,203 @@
+//! Keeping the accept loop useful when file descriptors run out
+//!
+//! Once the process hits its open files limit, accept() fails with EMFILE
+//! (or ENFILE for the system-wide table) while the connection stays in the
+//! listen queue, so the listener stays readable and a naive loop spins on
+//! the error. Three things keep that in check:
+//!
+//! - `Backoff` sleeps between failed accepts, doubling from 5ms up to a
+//!   second, and starts over on the first success
+//! - a reserve descriptor (/dev/null, opened by `reserve_fd`) is given up
+//!   on EMFILE/ENFILE so the connection at the head of the queue can be
+//!   accepted and reset right away, rather than left to time out; the
+//!   reserve is reopened afterwards
+//! - `raise_nofile_limit` lifts the soft RLIMIT_NOFILE to the hard limit
+//!   at startup (`--raise-nofile`)
+//!
+//! Failures are counted in `tcpstrip_accept_failures_total{reason}`
+//! (`fd_limit`, `memory` or `other`) and shed connections in
+//! `tcpstrip_accept_shed_total`.
+
+use std::fs::File;
+use std::io;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use tokio::net::TcpListener;
+
+use crate::metrics;
+
+const BACKOFF_MIN: Duration = Duration::from_millis(5);
+const BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Why accept() failed, as far as the loop cares
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Out of file descriptors (EMFILE, ENFILE)
+    FdLimit,
+    /// Out of kernel memory (ENOBUFS, ENOMEM)
+    Memory,
+    Other,
+}
+
+impl Failure {
+    pub fn of(error: &io::Error) -> Self {
+        match error.raw_os_error() {
+            Some(libc::EMFILE | libc::ENFILE) => Failure::FdLimit,
+            Some(libc::ENOBUFS | libc::ENOMEM) => Failure::Memory,
+            _ => Failure::Other,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Failure::FdLimit => "fd_limit",
+            Failure::Memory => "memory",
+            Failure::Other => "other",
+        }
+    }
+
+    /// Whether retrying right away would fail the same way
+    pub fn is_exhaustion(self) -> bool {
+        self != Failure::Other
+    }
+}
+
+/// Count a failed accept
+pub fn count(failure: Failure) {
+    metrics::registry()
+        .labeled_counter("tcpstrip_accept_failures_total", "Failed accepts on the proxy's listeners, by cause", "reason")
+        .with(failure.label())
+        .inc();
+}
+
+/// Delay between failed accepts of one listener
+#[derive(Debug, Default)]
+pub struct Backoff {
+    next: Option<Duration>,
+}
+
+impl Backoff {
+    /// How long to wait after another failure
+    pub fn failed(&mut self) -> Duration {
+        let delay = self.next.unwrap_or(BACKOFF_MIN);
+        self.next = Some((delay * 2).min(BACKOFF_MAX));
+        delay
+    }
+
+    pub fn succeeded(&mut self) {
+        self.next = None;
+    }
+}
+
+fn reserve() -> &'static Mutex<Option<File>> {
+    static RESERVE: OnceLock<Mutex<Option<File>>> = OnceLock::new();
+    RESERVE.get_or_init(Mutex::default)
+}
+
+/// Hold a descriptor back for `shed` to give up
+pub fn reserve_fd() -> io::Result<()> {
+    let file = File::open("/dev/null")?;
+    *reserve().lock().unwrap() = Some(file);
+    Ok(())
+}
+
+/// Out of descriptors: accept the connection at the head of `listener`'s
+/// queue with the reserve and reset it; whether one was shed
+pub fn shed(listener: &TcpListener) -> bool {
+    let mut reserve = reserve().lock().unwrap();
+    if reserve.take().is_none() {
+        return false;
+    }
+    let shed = match socket2::SockRef::from(listener).accept() {
+        Ok((socket, _)) => {
+            let _ = socket.set_linger(Some(Duration::ZERO));
+            true
+        }
+        Err(_) => false,
+    };
+    // Another task may have taken the descriptor meanwhile; the next
+    // failure tries again
+    *reserve = File::open("/dev/null").ok();
+    if shed {
+        metrics::registry()
+            .counter("tcpstrip_accept_shed_total", "Connections reset on accept because the proxy was out of file descriptors")
+            .inc();
+    }
+    shed
+}
+
+/// Raise the soft open files limit to the hard limit; the limits before
+/// and after
+#[cfg(unix)]
+pub fn raise_nofile_limit() -> io::Result<(u64, u64)> {
+    let mut limit = getrlimit_nofile()?;
+    let before = limit.rlim_cur;
+    if limit.rlim_cur < limit.rlim_max {
+        limit.rlim_cur = limit.rlim_max;
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok((rlim(before), rlim(limit.rlim_cur)))
+}
+
+/// The soft open files limit
+#[cfg(unix)]
+pub fn nofile_limit() -> io::Result<u64> {
+    Ok(rlim(getrlimit_nofile()?.rlim_cur))
+}
+
+#[cfg(unix)]
+fn getrlimit_nofile() -> io::Result<libc::rlimit> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(limit)
+}
+
+// rlim_t is not u64 everywhere
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+fn rlim(value: libc::rlim_t) -> u64 {
+    value as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::default();
+        let delays: Vec<u64> = (0..10).map(|_| backoff.failed().as_millis() as u64).collect();
+        assert_eq!(delays, [5, 10, 20, 40, 80, 160, 320, 640, 1000, 1000]);
+        backoff.succeeded();
+        assert_eq!(backoff.failed(), BACKOFF_MIN);
+
+        assert_eq!(Failure::of(&io::Error::from_raw_os_error(libc::EMFILE)), Failure::FdLimit);
+        assert_eq!(Failure::of(&io::Error::from_raw_os_error(libc::ENOBUFS)), Failure::Memory);
+        assert!(!Failure::of(&io::Error::from_raw_os_error(libc::ECONNABORTED)).is_exhaustion());
+    }
+
+    #[tokio::test]
+    async fn test_shed_resets_queued_connection() {
+        use tokio::io::AsyncReadExt;
+
+        reserve_fd().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        // The handshake completes in the kernel before accept()
+        while !shed(&listener) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let mut buf = [0u8; 1];
+        let e = client.read(&mut buf).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+        assert!(reserve().lock().unwrap().is_some());
+    }
+}
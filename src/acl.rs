@@ -0,0 +1,183 @@
+//! Source address ACLs (`--allow`, `--deny`, `--acl-file`)
+//!
+//! Clients are admitted or turned away by the address they connect from,
+//! as soon as they are accepted (and their PROXY protocol header, if one is
+//! expected, names them) and before anything is dialed; a UDP client before
+//! it gets a session. The most specific rule matching the address decides,
+//! whatever order the rules were given in, so `--allow 10.0.0.0/8 --deny
+//! 10.9.0.0/16` lets in all of 10/8 but one subnet; a deny rule beats an
+//! allow rule for the same subnet. An address no rule matches is turned
+//! away if there are allow rules, since those make a list of who may
+//! connect, and admitted if there are only deny rules.
+//!
+//! `--acl-file` reads further rules from a file, one `allow CIDR` or `deny
+//! CIDR` a line, with `#` starting a comment.
+//!
+//! Every rule counts the clients it decided on in
+//! `tcpstrip_acl_hits_total{rule}` (`rule="deny 10.9.0.0/16"`), and those
+//! no rule matched count under `rule="default"`.
+
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::metrics::{self, Metric};
+use crate::source_stats::Subnet;
+
+/// Label value of the decisions no rule made
+pub const DEFAULT_RULE: &str = "default";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Allow => write!(f, "allow"),
+            Action::Deny => write!(f, "deny"),
+        }
+    }
+}
+
+/// One `--allow`/`--deny` argument or `--acl-file` line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rule {
+    pub action: Action,
+    pub subnet: Subnet,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.action, self.subnet)
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    /// `allow CIDR` or `deny CIDR`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (action, subnet) = match s.split_whitespace().collect::<Vec<_>>()[..] {
+            ["allow", subnet] => (Action::Allow, subnet),
+            ["deny", subnet] => (Action::Deny, subnet),
+            _ => return Err(format!("invalid rule '{}' (expected allow CIDR or deny CIDR)", s)),
+        };
+        Ok(Self {
+            action,
+            subnet: subnet.parse()?,
+        })
+    }
+}
+
+/// Read the rules in an `--acl-file`
+pub fn load(path: &Path) -> io::Result<Vec<Rule>> {
+    let text = std::fs::read_to_string(path)?;
+    let mut rules = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let rule = line
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", number + 1, e)))?;
+        rules.push(rule);
+    }
+    Ok(rules)
+}
+
+/// The rules of all listeners
+#[derive(Debug)]
+pub struct Acl {
+    rules: Vec<(Rule, Arc<Metric>)>,
+    /// What no rule matching means
+    default: Action,
+    default_hits: Arc<Metric>,
+}
+
+impl Acl {
+    pub fn new(rules: &[Rule]) -> Self {
+        let hits = metrics::registry().labeled_counter("tcpstrip_acl_hits_total", "Clients admitted or turned away, per ACL rule", "rule");
+        Self {
+            rules: rules.iter().map(|rule| (*rule, hits.with(&rule.to_string()))).collect(),
+            default: match rules.iter().any(|rule| rule.action == Action::Allow) {
+                true => Action::Deny,
+                false => Action::Allow,
+            },
+            default_hits: hits.with(DEFAULT_RULE),
+        }
+    }
+
+    /// Whether a client from `ip` may connect, and the rule that decided
+    /// (None for the default)
+    pub fn check(&self, ip: IpAddr) -> (Action, Option<&Rule>) {
+        let decided = self
+            .rules
+            .iter()
+            .filter(|(rule, _)| rule.subnet.contains(ip))
+            .max_by_key(|(rule, _)| (rule.subnet.prefix_len(), rule.action == Action::Deny));
+        match decided {
+            Some((rule, hits)) => {
+                hits.inc();
+                (rule.action, Some(rule))
+            }
+            None => {
+                self.default_hits.inc();
+                (self.default, None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_most_specific_rule_decides() {
+        let rules: Vec<Rule> = ["deny 10.9.0.0/16", "allow 10.0.0.0/8", "allow 10.9.1.7", "deny 10.9.1.7/32"]
+            .iter()
+            .map(|rule| rule.parse().unwrap())
+            .collect();
+        let acl = Acl::new(&rules);
+        let check = |ip: &str| acl.check(ip.parse().unwrap());
+
+        assert_eq!(check("10.1.2.3"), (Action::Allow, Some(&rules[1])));
+        assert_eq!(check("10.9.5.5"), (Action::Deny, Some(&rules[0])));
+        // Deny wins a tie
+        assert_eq!(check("10.9.1.7"), (Action::Deny, Some(&rules[3])));
+        // A dual-stack listener's IPv4 clients match IPv4 rules
+        assert_eq!(check("::ffff:10.1.2.3").0, Action::Allow);
+        // Allow rules make an allowlist
+        assert_eq!(check("192.0.2.1"), (Action::Deny, None));
+        assert_eq!(check("2001:db8::1"), (Action::Deny, None));
+
+        let acl = Acl::new(&["deny 192.0.2.0/24".parse().unwrap()]);
+        assert_eq!(acl.check("192.0.2.1".parse().unwrap()).0, Action::Deny);
+        assert_eq!(acl.check("198.51.100.1".parse().unwrap()), (Action::Allow, None));
+
+        assert!("permit 10.0.0.0/8".parse::<Rule>().is_err());
+        assert!("allow 10.0.0.0/33".parse::<Rule>().is_err());
+        assert_eq!("deny  ::/0".parse::<Rule>().unwrap().to_string(), "deny ::/0");
+    }
+
+    #[test]
+    fn test_load() {
+        let path = std::env::temp_dir().join(format!("tcpstrip-acl-{}", std::process::id()));
+        std::fs::write(&path, "# trading floor\nallow 10.0.0.0/8  # racks\n\ndeny 10.9.0.0/16\n").unwrap();
+        let rules = load(&path).unwrap();
+        assert_eq!(rules.iter().map(|rule| rule.to_string()).collect::<Vec<_>>(), ["allow 10.0.0.0/8", "deny 10.9.0.0/16"]);
+
+        std::fs::write(&path, "allow 10.0.0.0/8\nallow everyone\n").unwrap();
+        let e = load(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().starts_with("line 2: "), "{}", e);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+//! Admin HTTP listener
+//!
+//! A deliberately tiny HTTP/1.1 responder for operational endpoints. It is
+//! meant to be bound to a management address, handles one request per
+//! connection and never shares a task with the forwarding path.
+//!
+//! Endpoints:
+//! - `GET /metrics` - Prometheus text exposition of the metrics registry
+//! - `GET /connections/top?n=N` - live connections ranked by CPU time
+//! - `POST /connections/<id>/kill` - abort a connection's task
+//! - `GET /kill-switch` - whether the kill switch is engaged
+//! - `POST /kill-switch/{engage,release}` - reset every relayed connection
+//!   and refuse new ones, or accept them again (see `killswitch`)
+//! - `GET /quic` - QUIC connections through the UDP relay (`--quic`)
+//! - `GET /fix` - FIX sessions on observed connections (`--fix-observe`)
+//! - `GET /features` - feature flag rules in the order they are consulted
+//! - `GET /capture` - classic BPF programs of the capture sockets
+//!   (`--verify-egress`, `--watch-backend-options`), in `tcpdump -ddd` form
+//! - `POST /features/<name>/{enable,disable,reset}?route=DEST` - override a
+//!   feature flag for DEST (IP or IP:PORT; every route if omitted)
+//! - `GET /history?src=&route=&since=&until=&limit=N` - recorded
+//!   connections (with `--history-db`, see `history::Query`)
+//! - `GET /debug/pprof/profile?seconds=N` - flamegraph SVG of an N second
+//!   CPU profile (with the `profiling` feature, see `profile`)
+
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::features::{self, Feature, FeatureSetting};
+use crate::route::Destination;
+use crate::{connections, fix_session, killswitch, metrics, quic};
+
+const MAX_REQUEST_HEAD: usize = 8192;
+const DEFAULT_TOP_CONNECTIONS: usize = 10;
+
+/// A response produced by the router
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into(),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+/// Bind the admin listener and serve requests forever
+pub async fn serve(addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Admin API listening on {}", listener.local_addr()?);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("Admin accept failed: {}", e);
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream).await {
+                debug!("Admin request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream) -> Result<()> {
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0u8; 1024];
+
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..n]);
+        if head.len() > MAX_REQUEST_HEAD {
+            break;
+        }
+    }
+
+    let request_line = head.split(|&b| b == b'\r').next().unwrap_or_default();
+    let request_line = String::from_utf8_lossy(request_line);
+    let mut parts = request_line.split_whitespace();
+
+    let response = match (parts.next(), parts.next()) {
+        // Takes as long as the profile, so it is not answered by `route`
+        #[cfg(all(feature = "profiling", unix))]
+        (Some(method), Some(target)) if target.split('?').next() == Some("/debug/pprof/profile") => {
+            profile(method, target).await
+        }
+        (Some(method), Some(target)) => route(method, target),
+        _ => Response::text(400, "malformed request\n"),
+    };
+
+    let header = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Dispatch a request to its endpoint
+pub fn route(method: &str, target: &str) -> Response {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    if let Some(id) = path.strip_prefix("/connections/").and_then(|p| p.strip_suffix("/kill")) {
+        return kill_connection(method, id);
+    }
+    if let Some(rest) = path.strip_prefix("/features/") {
+        return override_feature(method, rest, query);
+    }
+
+    match (method, path) {
+        ("GET", "/metrics") => Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: metrics::registry().render(),
+        },
+        (_, "/metrics") => Response::text(405, "method not allowed\n"),
+        ("GET", "/connections/top") => {
+            let n = query
+                .split('&')
+                .find_map(|kv| kv.strip_prefix("n="))
+                .and_then(|n| n.parse().ok())
+                .unwrap_or(DEFAULT_TOP_CONNECTIONS);
+            Response::text(200, connections::registry().render_top(n))
+        }
+        (_, "/connections/top") => Response::text(405, "method not allowed\n"),
+        ("GET", "/quic") => Response::text(200, quic::registry().render()),
+        (_, "/quic") => Response::text(405, "method not allowed\n"),
+        ("GET", "/fix") => Response::text(200, fix_session::registry().render()),
+        (_, "/fix") => Response::text(405, "method not allowed\n"),
+        ("GET", "/features") => Response::text(200, features::flags().render()),
+        (_, "/features") => Response::text(405, "method not allowed\n"),
+        #[cfg(target_os = "linux")]
+        ("GET", "/capture") => capture_programs(),
+        #[cfg(target_os = "linux")]
+        (_, "/capture") => Response::text(405, "method not allowed\n"),
+        ("GET", "/kill-switch") => Response::text(200, kill_switch_state()),
+        (_, "/kill-switch") => Response::text(405, "method not allowed\n"),
+        ("POST", "/kill-switch/engage") => {
+            if let Some(mode) = killswitch::unavailable_in() {
+                return Response::text(409, format!("the kill switch has no connections to reset in {} mode\n", mode));
+            }
+            if killswitch::engage() {
+                warn!("Kill switch engaged via admin API: resetting every connection");
+            }
+            Response::text(200, kill_switch_state())
+        }
+        ("POST", "/kill-switch/release") => {
+            if killswitch::release() {
+                info!("Kill switch released via admin API: accepting connections again");
+            }
+            Response::text(200, kill_switch_state())
+        }
+        (_, "/kill-switch/engage" | "/kill-switch/release") => Response::text(405, "method not allowed\n"),
+        #[cfg(feature = "history")]
+        ("GET", "/history") => search_history(query),
+        #[cfg(feature = "history")]
+        (_, "/history") => Response::text(405, "method not allowed\n"),
+        _ => Response::text(404, "not found\n"),
+    }
+}
+
+fn override_feature(method: &str, rest: &str, query: &str) -> Response {
+    if method != "POST" {
+        return Response::text(405, "method not allowed\n");
+    }
+    let Some((name, action)) = rest.split_once('/') else {
+        return Response::text(404, "not found\n");
+    };
+    let feature: Feature = match name.parse() {
+        Ok(feature) => feature,
+        Err(e) => return Response::text(400, format!("{}\n", e)),
+    };
+    let destination = match query.split('&').find_map(|kv| kv.strip_prefix("route=")) {
+        Some(route) => match route.parse() {
+            Ok(destination) => destination,
+            Err(e) => return Response::text(400, format!("{}\n", e)),
+        },
+        None => Destination::Any,
+    };
+    let enabled = match action {
+        "enable" => true,
+        "disable" => false,
+        "reset" => {
+            return match features::flags().reset_override(destination, feature) {
+                true => {
+                    info!("Feature {} override for {:?} reset via admin API", feature, destination);
+                    Response::text(200, "reset\n")
+                }
+                false => Response::text(404, "no such override\n"),
+            };
+        }
+        _ => return Response::text(404, "not found\n"),
+    };
+    let setting = FeatureSetting { feature, enabled };
+    features::flags().set_override(destination, setting);
+    info!("Feature {} set for {:?} via admin API", setting, destination);
+    Response::text(200, format!("{}\n", setting))
+}
+
+#[cfg(target_os = "linux")]
+fn capture_programs() -> Response {
+    let programs = crate::capture::captures().render();
+    match programs.is_empty() {
+        true => Response::text(404, "no capture sockets (start with --verify-egress or --watch-backend-options)\n"),
+        false => Response::text(200, programs),
+    }
+}
+
+#[cfg(feature = "history")]
+fn search_history(query: &str) -> Response {
+    let Some(history) = crate::history::get() else {
+        return Response::text(404, "history is not recorded (start with --history-db)\n");
+    };
+    let query = match crate::history::Query::parse(query) {
+        Ok(query) => query,
+        Err(e) => return Response::text(400, format!("{}\n", e)),
+    };
+    match history.search(&query) {
+        Ok(found) => Response::text(200, found),
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => Response::text(400, format!("{}\n", e)),
+        Err(e) => {
+            warn!("History search failed: {}", e);
+            Response::text(500, "history search failed\n")
+        }
+    }
+}
+
+#[cfg(all(feature = "profiling", unix))]
+async fn profile(method: &str, target: &str) -> Response {
+    if method != "GET" {
+        return Response::text(405, "method not allowed\n");
+    }
+    let query = target.split_once('?').map_or("", |(_, query)| query);
+    let duration = match crate::profile::parse_seconds(query) {
+        Ok(duration) => duration,
+        Err(e) => return Response::text(400, format!("{}\n", e)),
+    };
+    match crate::profile::flamegraph(duration).await {
+        Ok(Some(svg)) => Response {
+            status: 200,
+            content_type: "image/svg+xml",
+            body: svg,
+        },
+        Ok(None) => Response::text(200, "no samples: the proxy was idle\n"),
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Response::text(409, format!("{}\n", e)),
+        Err(e) => {
+            warn!("CPU profile failed: {}", e);
+            Response::text(500, "profile failed\n")
+        }
+    }
+}
+
+fn kill_switch_state() -> &'static str {
+    match killswitch::is_engaged() {
+        true => "engaged\n",
+        false => "released\n",
+    }
+}
+
+fn kill_connection(method: &str, id: &str) -> Response {
+    if method != "POST" {
+        return Response::text(405, "method not allowed\n");
+    }
+    let Ok(id) = id.parse() else {
+        return Response::text(400, "invalid connection id\n");
+    };
+    match connections::registry().kill(id) {
+        true => {
+            info!("Connection {} killed via admin API", id);
+            Response::text(200, "killed\n")
+        }
+        false => Response::text(404, "no such connection\n"),
+    }
+}
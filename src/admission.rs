@@ -0,0 +1,204 @@
+//! Admission control (`--max-connections`)
+//!
+//! Every accepted TCP connection needs a slot before anything else is done
+//! for it, across all listeners. Slots are the permits of a semaphore
+//! sized by `--max-connections`; a connection gives its slot back when its
+//! task ends. What happens to a connection that finds every slot taken is
+//! up to `Saturated`:
+//!
+//! - `reject` resets it straight away (RST, not a FIN the client might
+//!   mistake for a served session)
+//! - `queue` holds it until a slot frees up, resetting it if none does
+//!   within the queue timeout
+//! - `log` lets it through anyway and only counts it, to find the right
+//!   limit before enforcing one
+//!
+//! Connections holding a slot (or let through over the limit) are counted
+//! in `tcpstrip_connections_active`, the highest that has been in
+//! `tcpstrip_connections_peak`. Connections found the proxy saturated are
+//! counted in `tcpstrip_admission_saturated_total{action}` with the action
+//! taken, and those reset (rejected, or timed out in the queue) in
+//! `tcpstrip_admission_rejects_total`.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::metrics::{self, Metric};
+
+/// What happens to a connection while every slot is taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Saturated {
+    #[default]
+    Reject,
+    Queue,
+    Log,
+}
+
+impl fmt::Display for Saturated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Saturated::Reject => write!(f, "reject"),
+            Saturated::Queue => write!(f, "queue"),
+            Saturated::Log => write!(f, "log"),
+        }
+    }
+}
+
+impl FromStr for Saturated {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Saturated::Reject),
+            "queue" => Ok(Saturated::Queue),
+            "log" => Ok(Saturated::Log),
+            _ => Err(format!("unknown saturation action '{}' (expected reject, queue or log)", s)),
+        }
+    }
+}
+
+/// The connection slots shared by all listeners
+#[derive(Debug)]
+pub struct Admission {
+    slots: Arc<Semaphore>,
+    saturated: Saturated,
+    queue_timeout: Duration,
+    active: Arc<Metric>,
+    peak: Arc<Metric>,
+    saturated_total: Arc<Metric>,
+    rejects: Arc<Metric>,
+}
+
+impl Admission {
+    pub fn new(max_connections: usize, saturated: Saturated, queue_timeout: Duration) -> Arc<Self> {
+        let registry = metrics::registry();
+        Arc::new(Self {
+            slots: Arc::new(Semaphore::new(max_connections.min(Semaphore::MAX_PERMITS))),
+            saturated,
+            queue_timeout,
+            active: registry.gauge("tcpstrip_connections_active", "Client connections holding a slot (or let through over --max-connections)"),
+            peak: registry.gauge("tcpstrip_connections_peak", "Highest tcpstrip_connections_active so far"),
+            saturated_total: registry
+                .labeled_counter(
+                    "tcpstrip_admission_saturated_total",
+                    "Client connections accepted while every --max-connections slot was taken, by the action taken",
+                    "action",
+                )
+                .with(&saturated.to_string()),
+            rejects: registry.counter("tcpstrip_admission_rejects_total", "Client connections reset for want of a --max-connections slot"),
+        })
+    }
+
+    /// A slot for a new connection, to hold for as long as it is open;
+    /// the action to take instead when there is none. Rejected
+    /// connections are counted here, queued ones by `queue`
+    pub fn try_admit(&self) -> Result<Slot, Saturated> {
+        if let Ok(permit) = self.slots.clone().try_acquire_owned() {
+            return Ok(self.slot(Some(permit)));
+        }
+        self.saturated_total.inc();
+        match self.saturated {
+            Saturated::Reject => {
+                self.rejects.inc();
+                Err(Saturated::Reject)
+            }
+            Saturated::Queue => Err(Saturated::Queue),
+            Saturated::Log => Ok(self.slot(None)),
+        }
+    }
+
+    /// Wait up to the queue timeout for a slot; `None` if the connection
+    /// is to be reset
+    pub async fn queue(&self) -> Option<Slot> {
+        match tokio::time::timeout(self.queue_timeout, self.slots.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Some(self.slot(Some(permit))),
+            _ => {
+                self.rejects.inc();
+                None
+            }
+        }
+    }
+
+    /// Whether a connection accepted now would find every slot taken
+    pub fn is_saturated(&self) -> bool {
+        self.slots.available_permits() == 0
+    }
+
+    fn slot(&self, permit: Option<OwnedSemaphorePermit>) -> Slot {
+        self.active.inc();
+        self.peak.set_max(self.active.get());
+        Slot {
+            _permit: permit,
+            active: self.active.clone(),
+        }
+    }
+}
+
+/// One admitted connection's slot, given back when dropped
+#[derive(Debug)]
+pub struct Slot {
+    /// `None` for a connection let through over the limit
+    _permit: Option<OwnedSemaphorePermit>,
+    active: Arc<Metric>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.active.dec();
+    }
+}
+
+/// Close `stream` with a RST rather than a FIN
+pub fn reset(stream: TcpStream) {
+    let _ = socket2::SockRef::from(&stream).set_linger(Some(Duration::ZERO));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_saturation() {
+        let admission = Admission::new(2, Saturated::Reject, Duration::ZERO);
+        let first = admission.try_admit().unwrap();
+        let _second = admission.try_admit().unwrap();
+        assert!(admission.is_saturated());
+        assert_eq!(admission.try_admit().unwrap_err(), Saturated::Reject);
+        drop(first);
+        assert!(admission.try_admit().is_ok());
+
+        // Queued connections get the next slot that frees up, or time out
+        let admission = Admission::new(1, Saturated::Queue, Duration::from_millis(200));
+        let first = admission.try_admit().unwrap();
+        assert_eq!(admission.try_admit().unwrap_err(), Saturated::Queue);
+        let queued = tokio::spawn({
+            let admission = admission.clone();
+            async move { admission.queue().await.is_some() }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(first);
+        assert!(queued.await.unwrap());
+        let _held = admission.try_admit().unwrap();
+        let started = tokio::time::Instant::now();
+        assert!(admission.queue().await.is_none());
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        // Log only counts
+        let admission = Admission::new(1, Saturated::Log, Duration::ZERO);
+        let _first = admission.try_admit().unwrap();
+        let _second = admission.try_admit().unwrap();
+        assert!(admission.is_saturated());
+    }
+
+    #[test]
+    fn test_parse_saturated() {
+        assert_eq!("queue".parse::<Saturated>().unwrap(), Saturated::Queue);
+        assert_eq!(Saturated::Log.to_string(), "log");
+        assert!("drop".parse::<Saturated>().is_err());
+    }
+}
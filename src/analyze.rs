@@ -0,0 +1,592 @@
+//! Offline audit of packet captures
+//!
+//! `tcp-proxy analyze FILE` reads a pcap capture and reports every TCP flow
+//! whose segments carried a timestamp option, i.e. whose host clock was
+//! visible on the wire, with the tick rate of the clock and TSvals that
+//! went backwards (which PAWS on the receiver drops).
+//!
+//! Day-long colo captures run to many gigabytes, so the file is streamed
+//! through a pipeline: the reading thread only locates each segment's
+//! 4-tuple and hands it to one of several workers, chosen by a hash of the
+//! flow that is the same for both directions. Every flow is therefore
+//! tracked by a single worker that sees its segments in capture order.
+//! Flows are reported as they close (after a RST, or a FIN each way), idle
+//! ones after `IDLE_TIMEOUT` of capture time and the rest at the end.
+//!
+//! Classic pcap files with Ethernet, raw IP, BSD loopback and Linux cooked
+//! (v1/v2) link types are read; pcapng is not.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::packet::{
+    parse_ethernet_frame, parse_ip_packet, FlowKey, TcpSegment, TCP_FLAG_ACK, TCP_FLAG_FIN, TCP_FLAG_RST,
+    TCP_FLAG_SYN,
+};
+use crate::tcp_analysis::{extract_timestamp, parse_tcp_options, TcpOptionType};
+
+/// Segments handed to a worker at once
+const BATCH_LEN: usize = 512;
+/// Batches queued per worker before the reader waits
+const QUEUE_LEN: usize = 16;
+/// Open flows without a segment for this long (capture time) are reported
+/// and forgotten
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+/// How long closed flows are kept for their last ACKs
+const CLOSED_LINGER: Duration = Duration::from_secs(5);
+/// How often (capture time) workers look for idle flows
+const SWEEP_EVERY: Duration = Duration::from_secs(60);
+/// Largest record accepted whatever the file's snaplen says
+const MAX_RECORD_LEN: u32 = 262_144;
+/// TCP options fit in 40 bytes
+const MAX_OPTIONS_LEN: usize = 40;
+
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const PCAPNG_MAGIC: u32 = 0x0a0d_0d0a;
+
+/// How frames of a capture start
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkType {
+    Ethernet,
+    /// A bare IPv4 or IPv6 packet
+    Raw,
+    /// BSD loopback: a 4-byte address family
+    Null,
+    /// Linux cooked capture (`tcpdump -i any`)
+    LinuxSll,
+    LinuxSll2,
+}
+
+impl LinkType {
+    /// The pcap LINKTYPE_ value
+    fn from_pcap(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(LinkType::Null),
+            1 => Some(LinkType::Ethernet),
+            101 | 228 | 229 => Some(LinkType::Raw),
+            113 => Some(LinkType::LinuxSll),
+            276 => Some(LinkType::LinuxSll2),
+            _ => None,
+        }
+    }
+
+    /// Locate the TCP segment in a frame; offsets are relative to the
+    /// returned slice
+    fn locate(self, frame: &[u8]) -> Option<(&[u8], TcpSegment)> {
+        let packet = match self {
+            LinkType::Ethernet => return parse_ethernet_frame(frame).map(|segment| (frame, segment)),
+            LinkType::Raw => frame,
+            LinkType::Null => frame.get(4..)?,
+            LinkType::LinuxSll => frame.get(16..)?,
+            LinkType::LinuxSll2 => frame.get(20..)?,
+        };
+        parse_ip_packet(packet).map(|segment| (packet, segment))
+    }
+}
+
+/// Streaming reader of a classic pcap file
+pub struct PcapReader<R> {
+    input: R,
+    link_type: LinkType,
+    big_endian: bool,
+    nanos: bool,
+    max_len: u32,
+}
+
+/// One captured frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    /// Capture time since the Unix epoch
+    pub time: Duration,
+    /// Bytes captured, at the start of the buffer passed to `next`
+    pub len: usize,
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Read the file header
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut header = [0u8; 24];
+        input.read_exact(&mut header)?;
+        let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let (big_endian, nanos) = match (magic, magic.swap_bytes()) {
+            (PCAP_MAGIC_MICROS, _) => (false, false),
+            (PCAP_MAGIC_NANOS, _) => (false, true),
+            (_, PCAP_MAGIC_MICROS) => (true, false),
+            (_, PCAP_MAGIC_NANOS) => (true, true),
+            (PCAPNG_MAGIC, _) => {
+                return Err(invalid("pcapng is not supported; convert with editcap -F pcap"));
+            }
+            _ => return Err(invalid("not a pcap file")),
+        };
+        let field = |at: usize| {
+            let bytes = header[at..at + 4].try_into().unwrap();
+            match big_endian {
+                true => u32::from_be_bytes(bytes),
+                false => u32::from_le_bytes(bytes),
+            }
+        };
+        let link_type = LinkType::from_pcap(field(20) & 0xffff)
+            .ok_or_else(|| invalid(format!("unsupported link type {}", field(20) & 0xffff)))?;
+        Ok(Self {
+            input,
+            link_type,
+            big_endian,
+            nanos,
+            max_len: field(16).max(MAX_RECORD_LEN),
+        })
+    }
+
+    pub fn link_type(&self) -> LinkType {
+        self.link_type
+    }
+
+    /// Read the next frame into `buf`; None at the end of the file
+    ///
+    /// A record cut off by the end of the file (a capture that was killed)
+    /// also ends it, with a warning.
+    pub fn next(&mut self, buf: &mut Vec<u8>) -> io::Result<Option<Record>> {
+        let mut header = [0u8; 16];
+        match read_full(&mut self.input, &mut header)? {
+            0 => return Ok(None),
+            16 => {}
+            _ => {
+                warn!("Capture ends in the middle of a record header");
+                return Ok(None);
+            }
+        }
+        let field = |at: usize| {
+            let bytes = header[at..at + 4].try_into().unwrap();
+            match self.big_endian {
+                true => u32::from_be_bytes(bytes),
+                false => u32::from_le_bytes(bytes),
+            }
+        };
+        let (secs, fraction, len) = (field(0), field(4), field(8));
+        if len > self.max_len {
+            return Err(invalid(format!("corrupt record of {} bytes", len)));
+        }
+        let fraction = match self.nanos {
+            true => Duration::from_nanos(fraction as u64),
+            false => Duration::from_micros(fraction as u64),
+        };
+
+        buf.resize(len as usize, 0);
+        if read_full(&mut self.input, buf)? < buf.len() {
+            warn!("Capture ends in the middle of a record");
+            return Ok(None);
+        }
+        Ok(Some(Record {
+            time: Duration::from_secs(secs as u64) + fraction,
+            len: len as usize,
+        }))
+    }
+}
+
+/// Like `read_exact`, but returns how much was read before the end
+fn read_full(input: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// What a worker needs of one segment
+struct Segment {
+    time: Duration,
+    flow: FlowKey,
+    flags: u8,
+    options: [u8; MAX_OPTIONS_LEN],
+    options_len: u8,
+}
+
+/// The same key for both directions of a connection
+fn canonical(flow: FlowKey) -> FlowKey {
+    match (flow.src, flow.src_port) <= (flow.dst, flow.dst_port) {
+        true => flow,
+        false => flow.reversed(),
+    }
+}
+
+/// Timestamps seen in one direction of a flow
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Direction {
+    /// Segments carrying a timestamp option
+    pub timestamped: u64,
+    /// TSvals lower than the one before
+    pub regressions: u64,
+    first: Option<(Duration, u32)>,
+    last: Option<(Duration, u32)>,
+}
+
+impl Direction {
+    fn record(&mut self, time: Duration, ts_val: u32) {
+        self.timestamped += 1;
+        if let Some((_, last)) = self.last {
+            if (ts_val.wrapping_sub(last) as i32) < 0 {
+                self.regressions += 1;
+            }
+        }
+        self.first.get_or_insert((time, ts_val));
+        self.last = Some((time, ts_val));
+    }
+
+    /// TSval ticks per second, once the flow has run for a second
+    pub fn clock_hz(&self) -> Option<f64> {
+        let ((start, first), (end, last)) = (self.first?, self.last?);
+        let elapsed = end.checked_sub(start)?.as_secs_f64();
+        (elapsed >= 1.0).then(|| last.wrapping_sub(first) as f64 / elapsed)
+    }
+}
+
+/// One flow that carried timestamps
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlowReport {
+    /// The direction of the first segment seen, usually the client's SYN
+    pub flow: FlowKey,
+    pub first_seen: Duration,
+    pub last_seen: Duration,
+    pub segments: u64,
+    /// `flow`'s direction, then the other
+    pub directions: [Direction; 2],
+}
+
+impl fmt::Display for FlowReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hz = |direction: &Direction| match direction.clock_hz() {
+            Some(hz) => format!("{:.0}Hz", hz),
+            None => "-".to_string(),
+        };
+        let [forward, back] = &self.directions;
+        write!(
+            f,
+            "{} -> {} duration={:.1}s segments={} timestamped={}/{} clock={}/{} tsval_regressions={}",
+            SocketAddr::new(self.flow.src, self.flow.src_port),
+            SocketAddr::new(self.flow.dst, self.flow.dst_port),
+            self.last_seen.saturating_sub(self.first_seen).as_secs_f64(),
+            self.segments,
+            forward.timestamped,
+            back.timestamped,
+            hz(forward),
+            hz(back),
+            forward.regressions + back.regressions,
+        )
+    }
+}
+
+struct FlowState {
+    report: FlowReport,
+    fin: [bool; 2],
+    closed: bool,
+}
+
+impl FlowState {
+    fn new(segment: &Segment) -> Self {
+        Self {
+            report: FlowReport {
+                flow: segment.flow,
+                first_seen: segment.time,
+                last_seen: segment.time,
+                segments: 0,
+                directions: Default::default(),
+            },
+            fin: [false; 2],
+            closed: false,
+        }
+    }
+
+    fn record(&mut self, segment: &Segment) {
+        let direction = usize::from(segment.flow != self.report.flow);
+        self.report.last_seen = self.report.last_seen.max(segment.time);
+        self.report.segments += 1;
+
+        let options = parse_tcp_options(&segment.options[..segment.options_len as usize]);
+        if let Some(ts) = options
+            .iter()
+            .find(|option| option.kind == TcpOptionType::Timestamp)
+            .and_then(extract_timestamp)
+        {
+            self.report.directions[direction].record(segment.time, ts.ts_val);
+        }
+
+        if segment.flags & TCP_FLAG_FIN != 0 {
+            self.fin[direction] = true;
+        }
+        if segment.flags & TCP_FLAG_RST != 0 || self.fin == [true; 2] {
+            self.closed = true;
+        }
+    }
+}
+
+/// Totals over a whole capture
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub packets: u64,
+    pub tcp_segments: u64,
+    pub flows: u64,
+    /// Flows with a timestamp option on at least one segment
+    pub timestamped_flows: u64,
+    pub timestamped_segments: u64,
+    pub tsval_regressions: u64,
+}
+
+impl Summary {
+    fn add(&mut self, other: Summary) {
+        self.packets += other.packets;
+        self.tcp_segments += other.tcp_segments;
+        self.flows += other.flows;
+        self.timestamped_flows += other.timestamped_flows;
+        self.timestamped_segments += other.timestamped_segments;
+        self.tsval_regressions += other.tsval_regressions;
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "packets={} tcp_segments={} flows={} timestamped_flows={} timestamped_segments={} tsval_regressions={}",
+            self.packets,
+            self.tcp_segments,
+            self.flows,
+            self.timestamped_flows,
+            self.timestamped_segments,
+            self.tsval_regressions,
+        )
+    }
+}
+
+/// Track the flows of one shard
+fn work(batches: Receiver<Vec<Segment>>, reports: Sender<FlowReport>) -> Summary {
+    let mut flows: HashMap<FlowKey, FlowState> = HashMap::new();
+    let mut summary = Summary::default();
+    let mut finish = |state: FlowState| {
+        let report = state.report;
+        let [forward, back] = &report.directions;
+        summary.flows += 1;
+        if forward.timestamped + back.timestamped > 0 {
+            summary.timestamped_flows += 1;
+            summary.timestamped_segments += forward.timestamped + back.timestamped;
+            summary.tsval_regressions += forward.regressions + back.regressions;
+            let _ = reports.send(report);
+        }
+    };
+
+    let mut next_sweep = None;
+    for batch in batches {
+        let mut now = Duration::ZERO;
+        for segment in batch {
+            now = now.max(segment.time);
+            let key = canonical(segment.flow);
+            // A new connection on the 4-tuple of a closed one
+            let reopened = segment.flags & (TCP_FLAG_SYN | TCP_FLAG_ACK) == TCP_FLAG_SYN
+                && flows.get(&key).is_some_and(|state| state.closed);
+            if reopened {
+                finish(flows.remove(&key).expect("flow just looked up"));
+            }
+            flows.entry(key).or_insert_with(|| FlowState::new(&segment)).record(&segment);
+            if segment.flags & TCP_FLAG_RST != 0 {
+                finish(flows.remove(&key).expect("flow just recorded"));
+            }
+        }
+
+        let next = *next_sweep.get_or_insert(now + SWEEP_EVERY);
+        if now >= next {
+            let expired: Vec<FlowKey> = flows
+                .iter()
+                .filter(|(_, state)| {
+                    let timeout = if state.closed { CLOSED_LINGER } else { IDLE_TIMEOUT };
+                    now.saturating_sub(state.report.last_seen) >= timeout
+                })
+                .map(|(key, _)| *key)
+                .collect();
+            for key in expired {
+                finish(flows.remove(&key).expect("expired flow"));
+            }
+            next_sweep = Some(now + SWEEP_EVERY);
+        }
+    }
+
+    for (_, state) in flows.drain() {
+        finish(state);
+    }
+    summary
+}
+
+/// Audit the capture in `input` with `workers` worker threads, calling
+/// `on_flow` (on the calling thread) for each flow that carried timestamps
+/// as soon as it is done
+pub fn analyze<R: Read>(input: R, workers: usize, mut on_flow: impl FnMut(FlowReport)) -> io::Result<Summary> {
+    let mut reader = PcapReader::new(input)?;
+    let link_type = reader.link_type();
+    let workers = workers.max(1);
+    let (report_tx, report_rx) = mpsc::channel();
+
+    std::thread::scope(|scope| {
+        let mut shards: Vec<(SyncSender<Vec<Segment>>, Vec<Segment>)> = Vec::with_capacity(workers);
+        let mut handles = Vec::with_capacity(workers);
+        for _ in 0..workers {
+            let (batch_tx, batch_rx) = mpsc::sync_channel(QUEUE_LEN);
+            let reports = report_tx.clone();
+            handles.push(scope.spawn(move || work(batch_rx, reports)));
+            shards.push((batch_tx, Vec::with_capacity(BATCH_LEN)));
+        }
+        drop(report_tx);
+
+        let mut summary = Summary::default();
+        let mut buf = Vec::new();
+        let result = loop {
+            let record = match reader.next(&mut buf) {
+                Ok(Some(record)) => record,
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            };
+            summary.packets += 1;
+            let Some((packet, tcp)) = link_type.locate(&buf[..record.len]) else {
+                continue;
+            };
+            summary.tcp_segments += 1;
+
+            let flow = tcp.flow_key(packet);
+            let raw_options = tcp.options(packet);
+            let mut options = [0u8; MAX_OPTIONS_LEN];
+            options[..raw_options.len()].copy_from_slice(raw_options);
+            let segment = Segment {
+                time: record.time,
+                flow,
+                flags: tcp.flags(packet),
+                options,
+                options_len: raw_options.len() as u8,
+            };
+
+            let mut hasher = DefaultHasher::new();
+            canonical(flow).hash(&mut hasher);
+            let (batches, pending) = &mut shards[(hasher.finish() % workers as u64) as usize];
+            pending.push(segment);
+            if pending.len() == BATCH_LEN {
+                // Only fails if the worker panicked, which join reports
+                let _ = batches.send(std::mem::replace(pending, Vec::with_capacity(BATCH_LEN)));
+                report_rx.try_iter().for_each(&mut on_flow);
+            }
+        };
+
+        for (batches, pending) in shards {
+            if !pending.is_empty() {
+                let _ = batches.send(pending);
+            }
+        }
+        // Workers finish once their queues are closed and drained
+        report_rx.iter().for_each(&mut on_flow);
+        for handle in handles {
+            summary.add(handle.join().map_err(|_| io::Error::other("analysis worker panicked"))?);
+        }
+        result.map(|()| summary)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A raw IPv4 segment between 10.0.0.1 and the server 10.0.0.2:9000,
+    /// with a timestamp option if `ts_val` is given
+    fn segment(src_port: u16, dst_port: u16, flags: u8, ts_val: Option<u32>) -> Vec<u8> {
+        let options_len = if ts_val.is_some() { 12 } else { 0 };
+        let total_len = 40 + options_len;
+        let (src, dst) = if src_port == 9000 { (2, 1) } else { (1, 2) };
+        let mut packet = vec![
+            0x45, 0, 0, total_len as u8, 0, 0, 0x40, 0, 64, 6, 0, 0, // IPv4, TCP
+            10, 0, 0, src, 10, 0, 0, dst,
+        ];
+        packet.extend_from_slice(&src_port.to_be_bytes());
+        packet.extend_from_slice(&dst_port.to_be_bytes());
+        packet.extend_from_slice(&[0; 8]);
+        packet.extend_from_slice(&[((20 + options_len) as u8 / 4) << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        if let Some(ts_val) = ts_val {
+            packet.extend_from_slice(&[1, 1, 8, 10]);
+            packet.extend_from_slice(&ts_val.to_be_bytes());
+            packet.extend_from_slice(&0u32.to_be_bytes());
+        }
+        packet
+    }
+
+    fn pcap(records: &[(u64, Vec<u8>)]) -> Vec<u8> {
+        let mut file = Vec::new();
+        for field in [PCAP_MAGIC_MICROS, 0x0004_0002, 0, 0, 65535, 101] {
+            file.extend_from_slice(&field.to_le_bytes());
+        }
+        for (millis, packet) in records {
+            let (secs, micros) = ((millis / 1000) as u32, (millis % 1000 * 1000) as u32);
+            for field in [secs, micros, packet.len() as u32, packet.len() as u32] {
+                file.extend_from_slice(&field.to_le_bytes());
+            }
+            file.extend_from_slice(packet);
+        }
+        file
+    }
+
+    #[test]
+    fn test_analyze_capture() {
+        let mut records = Vec::new();
+        // A connection with 1 kHz timestamps and one TSval going back
+        records.push((0, segment(40000, 9000, TCP_FLAG_SYN, Some(5000))));
+        records.push((1, segment(9000, 40000, TCP_FLAG_SYN | TCP_FLAG_ACK, Some(70))));
+        for i in 0..20u32 {
+            let ts_val = if i == 10 { 4000 } else { 5002 + i * 100 };
+            records.push((2 + i as u64 * 100, segment(40000, 9000, TCP_FLAG_ACK, Some(ts_val))));
+        }
+        records.push((2100, segment(40000, 9000, TCP_FLAG_RST, Some(7100))));
+        // Stripped connections on many ports, closed with FINs
+        for port in 0..50u16 {
+            records.push((3000, segment(41000 + port, 9000, TCP_FLAG_SYN, None)));
+            records.push((3001, segment(9000, 41000 + port, TCP_FLAG_FIN | TCP_FLAG_ACK, None)));
+            records.push((3002, segment(41000 + port, 9000, TCP_FLAG_FIN | TCP_FLAG_ACK, None)));
+        }
+        records.push((3003, vec![0x60; 10]));
+        let file = pcap(&records);
+
+        let mut reports = Vec::new();
+        let summary = analyze(file.as_slice(), 3, |report| reports.push(report)).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                packets: 174,
+                tcp_segments: 173,
+                flows: 51,
+                timestamped_flows: 1,
+                timestamped_segments: 23,
+                tsval_regressions: 1,
+            }
+        );
+        assert_eq!(reports.len(), 1);
+        let report = &reports[0];
+        assert_eq!((report.flow.src_port, report.segments), (40000, 23));
+        assert_eq!(report.directions[1].timestamped, 1);
+        let hz = report.directions[0].clock_hz().unwrap();
+        assert!((hz - 1000.0).abs() < 1.0, "{}", hz);
+        assert_eq!(report.directions[1].clock_hz(), None);
+
+        let mut truncated = file.clone();
+        truncated.truncate(file.len() - 5);
+        assert_eq!(analyze(truncated.as_slice(), 1, |_| {}).unwrap().packets, 173);
+        assert!(analyze(&PCAPNG_MAGIC.to_le_bytes().repeat(6)[..], 1, |_| {}).is_err());
+    }
+}
@@ -0,0 +1,275 @@
+//! Hugepage-backed buffer arena
+//!
+//! Every proxied connection needs two forwarding buffers. With thousands of
+//! connections, buffers scattered over the heap cost a TLB entry per 4 KiB
+//! page touched; carving them out of one region of explicit hugepages
+//! (MAP_HUGETLB) covers 2 MiB per entry instead.
+//!
+//! Hugepages must be reserved up front (`vm.nr_hugepages`). If they are
+//! not, the arena falls back to ordinary memory aligned to 2 MiB and asks
+//! for transparent hugepages instead, and if every slot is taken callers
+//! fall back to a heap buffer, so running out never fails a connection.
+
+use std::alloc::{self, Layout};
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::sync::{Arc, Mutex};
+
+use bytes::BytesMut;
+use tracing::info;
+
+use crate::metrics::{self, Metric};
+
+const HUGEPAGE_SIZE: usize = 2 * 1024 * 1024;
+/// Slots start on cache-line boundaries
+const SLOT_ALIGN: usize = 64;
+
+/// How the arena's memory was obtained
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Explicit hugepages from the reserved pool
+    HugeTlb,
+    /// Regular memory, transparent hugepages if the kernel grants them
+    Heap,
+}
+
+/// A fixed number of equally sized buffers in one contiguous region
+pub struct BufferArena {
+    base: NonNull<u8>,
+    layout: Layout,
+    backing: Backing,
+    slot_size: usize,
+    free: Mutex<Vec<u32>>,
+    in_use: Arc<Metric>,
+}
+
+// The region is only reachable through slots handed out under the lock
+unsafe impl Send for BufferArena {}
+unsafe impl Sync for BufferArena {}
+
+impl BufferArena {
+    /// Reserve `slots` buffers of at least `slot_size` bytes each
+    pub fn new(slot_size: usize, slots: u32) -> io::Result<Arc<Self>> {
+        let slot_size = slot_size.max(1).next_multiple_of(SLOT_ALIGN);
+        let len = (slot_size * slots as usize).next_multiple_of(HUGEPAGE_SIZE);
+        let layout = Layout::from_size_align(len, HUGEPAGE_SIZE)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let (base, backing) = match map_hugetlb(len) {
+            Some(base) => (base, Backing::HugeTlb),
+            None => (alloc_heap(layout)?, Backing::Heap),
+        };
+
+        let registry = metrics::registry();
+        registry.gauge("tcpstrip_arena_slots", "Buffers in the forwarding buffer arena").set(slots as u64);
+        registry
+            .gauge("tcpstrip_arena_hugetlb", "1 if the buffer arena is backed by explicit hugepages")
+            .set((backing == Backing::HugeTlb) as u64);
+        let in_use = registry.gauge("tcpstrip_arena_slots_in_use", "Arena buffers currently handed out");
+
+        info!(
+            "Buffer arena: {} x {} bytes ({} MiB, {:?})",
+            slots,
+            slot_size,
+            len / (1024 * 1024),
+            backing
+        );
+
+        Ok(Arc::new(Self {
+            base,
+            layout,
+            backing,
+            slot_size,
+            free: Mutex::new((0..slots).rev().collect()),
+            in_use,
+        }))
+    }
+
+    pub fn backing(&self) -> Backing {
+        self.backing
+    }
+
+    pub fn slot_size(&self) -> usize {
+        self.slot_size
+    }
+
+    /// Take a free slot, or None if all of them are in use
+    pub fn alloc(self: &Arc<Self>) -> Option<ArenaBuffer> {
+        let slot = self.free.lock().unwrap().pop()?;
+        self.in_use.inc();
+        Some(ArenaBuffer {
+            arena: self.clone(),
+            slot,
+            len: self.slot_size,
+        })
+    }
+}
+
+impl Drop for BufferArena {
+    fn drop(&mut self) {
+        match self.backing {
+            Backing::HugeTlb => unmap_hugetlb(self.base, self.layout.size()),
+            Backing::Heap => unsafe { alloc::dealloc(self.base.as_ptr(), self.layout) },
+        }
+    }
+}
+
+/// One slot of an arena; returned to it on drop
+pub struct ArenaBuffer {
+    arena: Arc<BufferArena>,
+    slot: u32,
+    /// Usable bytes; up to the slot size
+    len: usize,
+}
+
+impl Deref for ArenaBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        let size = self.arena.slot_size;
+        unsafe { std::slice::from_raw_parts(self.arena.base.as_ptr().add(self.slot as usize * size), self.len) }
+    }
+}
+
+impl DerefMut for ArenaBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        let size = self.arena.slot_size;
+        unsafe { std::slice::from_raw_parts_mut(self.arena.base.as_ptr().add(self.slot as usize * size), self.len) }
+    }
+}
+
+impl Drop for ArenaBuffer {
+    fn drop(&mut self) {
+        self.arena.free.lock().unwrap().push(self.slot);
+        self.arena.in_use.dec();
+    }
+}
+
+/// A forwarding buffer from the arena, or from the heap when there is no
+/// arena or it is exhausted
+pub enum Buffer {
+    Arena(ArenaBuffer),
+    Heap(BytesMut),
+}
+
+impl Buffer {
+    /// A buffer of `size` bytes
+    pub fn alloc(arena: Option<&Arc<BufferArena>>, size: usize) -> Self {
+        match arena.filter(|a| a.slot_size >= size).and_then(|a| a.alloc()) {
+            // Slots of an arena sized for the largest route's buffers hand
+            // out only what this connection asked for
+            Some(mut buf) => {
+                buf.len = size;
+                Buffer::Arena(buf)
+            }
+            None => Buffer::Heap(BytesMut::zeroed(size)),
+        }
+    }
+
+    pub fn is_arena(&self) -> bool {
+        matches!(self, Buffer::Arena(_))
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Buffer::Arena(buf) => buf,
+            Buffer::Heap(buf) => buf,
+        }
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Buffer::Arena(buf) => buf,
+            Buffer::Heap(buf) => buf,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn map_hugetlb(len: usize) -> Option<NonNull<u8>> {
+    let addr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB | libc::MAP_POPULATE,
+            -1,
+            0,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        return None;
+    }
+    NonNull::new(addr as *mut u8)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn map_hugetlb(_len: usize) -> Option<NonNull<u8>> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn unmap_hugetlb(base: NonNull<u8>, len: usize) {
+    unsafe {
+        libc::munmap(base.as_ptr() as *mut libc::c_void, len);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unmap_hugetlb(_base: NonNull<u8>, _len: usize) {}
+
+fn alloc_heap(layout: Layout) -> io::Result<NonNull<u8>> {
+    let base = NonNull::new(unsafe { alloc::alloc_zeroed(layout) })
+        .ok_or_else(|| io::Error::new(io::ErrorKind::OutOfMemory, "cannot allocate buffer arena"))?;
+    // Best effort: without reserved hugepages, transparent ones are the
+    // next best thing
+    #[cfg(target_os = "linux")]
+    unsafe {
+        libc::madvise(base.as_ptr() as *mut libc::c_void, layout.size(), libc::MADV_HUGEPAGE);
+    }
+    Ok(base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_are_reused_and_exhaust() {
+        let arena = BufferArena::new(1000, 2).unwrap();
+        assert_eq!(arena.slot_size(), 1024);
+
+        let mut a = arena.alloc().unwrap();
+        let b = arena.alloc().unwrap();
+        assert!(arena.alloc().is_none());
+        a[..5].copy_from_slice(b"hello");
+        assert_ne!(a.as_ptr(), b.as_ptr());
+
+        let slot = a.as_ptr();
+        drop(a);
+        assert_eq!(arena.alloc().unwrap().as_ptr(), slot);
+    }
+
+    #[test]
+    fn test_buffer_falls_back_to_heap() {
+        let arena = BufferArena::new(512, 1).unwrap();
+        let first = Buffer::alloc(Some(&arena), 512);
+        let second = Buffer::alloc(Some(&arena), 512);
+        assert!(first.is_arena());
+        assert!(!second.is_arena());
+        assert_eq!(Buffer::alloc(Some(&BufferArena::new(512, 1).unwrap()), 100).len(), 100);
+        assert_eq!(second.len(), 512);
+
+        // Requests larger than a slot never come from the arena
+        drop(first);
+        assert!(!Buffer::alloc(Some(&arena), 4096).is_arena());
+        assert!(!Buffer::alloc(None, 512).is_arena());
+    }
+}
@@ -0,0 +1,305 @@
+//! Backend TCP option change alerts (Linux only)
+//!
+//! Stripping only protects what the proxy controls. If a backend changes
+//! the options it answers with, because the exchange enabled timestamps or
+//! a gateway was swapped for a different stack, the leak this tool exists
+//! to prevent can come back without anything failing. The watcher captures
+//! the SYN-ACKs arriving from backends the proxy is connecting to, reduces
+//! their options to a signature and warns when a backend's signature
+//! differs from the one it presented first. Inside a `--maintenance`
+//! window, when venues swap gateways, the change is only logged; either way
+//! it is counted in `tcpstrip_backend_option_changes_total{window}`.
+//!
+//! A backend only echoes options the SYN offered, so a backend enabling
+//! timestamps is visible only if the proxy's SYNs still carry them; the
+//! order and set of the remaining options still identify its stack.
+//!
+//! The capture filter only passes incoming TCP segments with SYN and ACK
+//! set; correlation with the proxy's dials happens in userspace:
+//!
+//! ```text
+//! ld  pkttype         ; jeq #PACKET_OUTGOING, drop
+//! ldh [12]            ; IPv4 (unfragmented) or IPv6 carrying TCP
+//! ldb [tcp + 13]      ; and #SYN|ACK ; jne #SYN|ACK, drop
+//! ret #256
+//! ```
+//!
+//! Alongside the proxy the filter is confined further to the routes' traffic
+//! (see `capture::Scope`).
+//!
+//! Requires CAP_NET_RAW.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::OwnedFd;
+use std::sync::{Arc, Mutex};
+
+use tracing::{info, warn};
+
+use crate::capture;
+use crate::metrics;
+use crate::packet::parse_ethernet_frame;
+use crate::trading_hours::{AlertWindow, Schedule};
+use crate::tcp_analysis::{parse_tcp_options, TcpOptionType};
+
+const TCP_FLAG_SYN_ACK: u32 = 0x12;
+/// Headers only; SYN-ACKs carry no payload worth copying
+const CAPTURE_LEN: u32 = 256;
+
+/// The options of a SYN-ACK in order, e.g. `mss,sok,ts,nop,ws7`
+///
+/// MSS values are left out since they vary with the path; the window scale
+/// is kept because it changes with the stack and its tuning.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptionSignature(String);
+
+impl OptionSignature {
+    pub fn from_options(options: &[u8]) -> Self {
+        let parts: Vec<String> = parse_tcp_options(options)
+            .iter()
+            .map(|option| match option.kind {
+                TcpOptionType::EndOfOptionList => "eol".to_string(),
+                TcpOptionType::NoOperation => "nop".to_string(),
+                TcpOptionType::MaximumSegmentSize => "mss".to_string(),
+                TcpOptionType::WindowScale => format!("ws{}", option.data.first().copied().unwrap_or(0)),
+                TcpOptionType::SackPermitted => "sok".to_string(),
+                TcpOptionType::Sack => "sack".to_string(),
+                TcpOptionType::Timestamp => "ts".to_string(),
+                TcpOptionType::Mptcp => "mptcp".to_string(),
+                TcpOptionType::FastOpen => "tfo".to_string(),
+                TcpOptionType::Unknown(kind) => format!("?{}", kind),
+            })
+            .collect();
+        Self(parts.join(","))
+    }
+
+    pub fn has_timestamp(&self) -> bool {
+        self.0.split(',').any(|part| part == "ts")
+    }
+}
+
+impl fmt::Display for OptionSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.is_empty() {
+            true => write!(f, "(none)"),
+            false => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// How a SYN-ACK compares to what the backend sent before
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Observation {
+    /// First SYN-ACK seen from this backend
+    First,
+    Unchanged,
+    /// The backend answered with these options before
+    Changed(OptionSignature),
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Backends with connection attempts in flight, by number of attempts
+    dialing: HashMap<SocketAddr, usize>,
+    /// Signature each backend presented first (or after its last change)
+    baselines: HashMap<SocketAddr, OptionSignature>,
+}
+
+/// Per-backend option signatures, fed by the capture thread
+#[derive(Debug, Default)]
+pub struct BackendOptions {
+    state: Mutex<State>,
+}
+
+impl BackendOptions {
+    /// Attribute SYN-ACKs from `backend` to the proxy until the returned
+    /// guard is dropped
+    pub fn dialing(self: &Arc<Self>, backend: SocketAddr) -> Dialing {
+        *self.state.lock().unwrap().dialing.entry(backend).or_default() += 1;
+        Dialing {
+            options: self.clone(),
+            backend,
+        }
+    }
+
+    /// The signature a backend presented last, if it was seen
+    pub fn signature(&self, backend: &SocketAddr) -> Option<OptionSignature> {
+        self.state.lock().unwrap().baselines.get(backend).cloned()
+    }
+
+    /// Record a SYN-ACK from `backend`; None if the proxy is not dialing it
+    pub fn observe(&self, backend: SocketAddr, signature: OptionSignature) -> Option<Observation> {
+        let mut state = self.state.lock().unwrap();
+        if !state.dialing.contains_key(&backend) {
+            return None;
+        }
+        Some(match state.baselines.insert(backend, signature.clone()) {
+            None => Observation::First,
+            Some(previous) if previous == signature => Observation::Unchanged,
+            Some(previous) => Observation::Changed(previous),
+        })
+    }
+
+    fn backends_with_timestamps(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.baselines.values().filter(|s| s.has_timestamp()).count() as u64
+    }
+}
+
+/// Keeps SYN-ACKs from a backend attributed to the proxy
+pub struct Dialing {
+    options: Arc<BackendOptions>,
+    backend: SocketAddr,
+}
+
+impl Drop for Dialing {
+    fn drop(&mut self) {
+        let mut state = self.options.state.lock().unwrap();
+        if let Some(count) = state.dialing.get_mut(&self.backend) {
+            *count -= 1;
+            if *count == 0 {
+                state.dialing.remove(&self.backend);
+            }
+        }
+    }
+}
+
+/// A capture socket for SYN-ACKs on the interface facing the backends
+pub struct BackendWatcher {
+    interface: String,
+    fd: Arc<OwnedFd>,
+    options: Arc<BackendOptions>,
+    /// When backends changing their options is expected
+    maintenance: Option<Schedule>,
+}
+
+impl BackendWatcher {
+    pub fn open(interface: &str, options: Arc<BackendOptions>, maintenance: Option<Schedule>) -> io::Result<Self> {
+        let fd = capture::captures().open("watch-backend-options", interface, capture_filter())?;
+        Ok(Self {
+            interface: interface.to_string(),
+            fd,
+            options,
+            maintenance,
+        })
+    }
+
+    /// Check SYN-ACKs until the capture socket fails
+    pub fn run(self) -> io::Result<()> {
+        info!("Watching backend SYN-ACK options on {}", self.interface);
+
+        let registry = metrics::registry();
+        let checked = registry.counter("tcpstrip_backend_synacks_total", "Backend SYN-ACKs whose options were checked");
+        let changes =
+            registry.labeled_counter("tcpstrip_backend_option_changes_total", "Backends that changed the TCP options they answer with", "window");
+        let with_timestamps =
+            registry.gauge("tcpstrip_backends_with_timestamps", "Backends whose last SYN-ACK carried a timestamp option");
+
+        let mut buf = vec![0u8; CAPTURE_LEN as usize];
+        loop {
+            let n = capture::recv(&self.fd, &mut buf)?;
+            let frame = &buf[..n];
+            let Some(segment) = parse_ethernet_frame(frame) else {
+                continue;
+            };
+            let key = segment.flow_key(frame);
+            let backend = SocketAddr::new(key.src, key.src_port);
+            let signature = OptionSignature::from_options(segment.options(frame));
+
+            let Some(observation) = self.options.observe(backend, signature.clone()) else {
+                continue;
+            };
+            checked.inc();
+            with_timestamps.set(self.options.backends_with_timestamps());
+
+            match observation {
+                Observation::First => info!("Backend {} answers with TCP options {}", backend, signature),
+                Observation::Unchanged => {}
+                Observation::Changed(previous) => {
+                    let window = AlertWindow::now(self.maintenance.as_ref());
+                    changes.with(&window.to_string()).inc();
+                    let timestamps = match signature.has_timestamp() && !previous.has_timestamp() {
+                        true => " (now answers with timestamps)",
+                        false => "",
+                    };
+                    match window {
+                        AlertWindow::Maintenance => info!(
+                            "Backend {} changed its TCP options in a maintenance window: {} -> {}{}",
+                            backend, previous, signature, timestamps
+                        ),
+                        AlertWindow::Unexpected => {
+                            warn!("Backend {} changed its TCP options: {} -> {}{}", backend, previous, signature, timestamps)
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Classic BPF program accepting incoming TCP segments with SYN and ACK set
+fn capture_filter() -> Vec<libc::sock_filter> {
+    use capture::*;
+
+    vec![
+        insn(LD_W_ABS, 0, 0, SKF_AD_OFF + SKF_AD_PKTTYPE),
+        insn(JEQ_K, 16, 0, libc::PACKET_OUTGOING as u32),
+        insn(LD_H_ABS, 0, 0, 12),
+        insn(JEQ_K, 5, 0, libc::ETH_P_IP as u32),
+        insn(JEQ_K, 0, 13, libc::ETH_P_IPV6 as u32),
+        insn(LD_B_ABS, 0, 0, 14 + 6), // IPv6 next header
+        insn(JEQ_K, 0, 11, libc::IPPROTO_TCP as u32),
+        insn(LD_B_ABS, 0, 0, 14 + 40 + 13), // TCP flags behind a bare IPv6 header
+        insn(JA, 0, 0, 6),
+        insn(LD_B_ABS, 0, 0, 14 + 9), // IPv4 protocol
+        insn(JEQ_K, 0, 7, libc::IPPROTO_TCP as u32),
+        insn(LD_H_ABS, 0, 0, 14 + 6), // IPv4 fragment offset
+        insn(JSET_K, 5, 0, 0x1fff),
+        insn(LDX_B_MSH, 0, 0, 14), // X = IPv4 header length
+        insn(LD_B_IND, 0, 0, 14 + 13),
+        insn(AND_K, 0, 0, TCP_FLAG_SYN_ACK),
+        insn(JEQ_K, 0, 1, TCP_FLAG_SYN_ACK),
+        insn(RET_K, 0, 0, CAPTURE_LEN),
+        insn(RET_K, 0, 0, 0),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        // mss 1460, sackOK, ts, nop, wscale 7 (Linux SYN-ACK)
+        let linux = [2, 4, 5, 180, 4, 2, 8, 10, 0, 0, 0, 1, 0, 0, 0, 2, 1, 3, 3, 7];
+        let signature = OptionSignature::from_options(&linux);
+        assert_eq!(signature.to_string(), "mss,sok,ts,nop,ws7");
+        assert!(signature.has_timestamp());
+        assert_eq!(OptionSignature::from_options(&[]).to_string(), "(none)");
+    }
+
+    #[test]
+    fn test_changes_are_reported_for_dialed_backends() {
+        let options = Arc::new(BackendOptions::default());
+        let backend: SocketAddr = "10.1.0.5:9000".parse().unwrap();
+        let plain = OptionSignature::from_options(&[2, 4, 5, 180, 1, 3, 3, 7]);
+        let with_ts = OptionSignature::from_options(&[2, 4, 5, 180, 1, 3, 3, 7, 1, 1, 8, 10, 0, 0, 0, 1, 0, 0, 0, 0]);
+
+        assert_eq!(options.observe(backend, plain.clone()), None);
+
+        let dial = options.dialing(backend);
+        assert_eq!(options.observe(backend, plain.clone()), Some(Observation::First));
+        assert_eq!(options.observe(backend, plain.clone()), Some(Observation::Unchanged));
+        assert_eq!(
+            options.observe(backend, with_ts.clone()),
+            Some(Observation::Changed(plain.clone()))
+        );
+        assert_eq!(options.backends_with_timestamps(), 1);
+
+        drop(dial);
+        assert_eq!(options.observe(backend, plain), None);
+        assert_eq!(options.signature(&backend), Some(with_ts));
+    }
+}
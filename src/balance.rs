@@ -0,0 +1,626 @@
+//! Spreading connections over a pool of backends
+//!
+//! With several `--target`s the socket proxy fronts a pool of gateway
+//! instances and `--balance` picks one for each new connection:
+//!
+//! - `round-robin` hands them out in turn
+//! - `weighted` does the same in proportion to `HOST:PORT@WEIGHT`
+//! - `least-conn` picks the backend with the fewest live connections
+//!   relative to its weight
+//! - `latency` picks the one that has been quickest to accept connections,
+//!   scaled by its live connections; one pick in `LATENCY_PROBE_EVERY` goes
+//!   round-robin instead, so slow backends are re-measured and can win back
+//!   traffic
+//! - `source-hash` sends every connection from one client IP to the same
+//!   backend, for venues that pin a member's sessions to one gateway
+//!
+//! A target that cannot be connected to is taken out of rotation for
+//! `RETRY_AFTER`, after which new connections try it again; one failing
+//! its health checks (see `health`) stays out until it passes them. With
+//! `--backup-target` the pool is a primary/backup pair (or group): while
+//! every target is out, new connections go to the backup instead, and back
+//! to the targets as soon as one accepts connections again. Both switches
+//! are logged and counted in `tcpstrip_failovers_total` and
+//! `tcpstrip_failover_active`.
+//!
+//! Targets given by name follow their DNS records (see `dns`): when a name
+//! moves, `Pool::set_addr` points its member at the new address, which
+//! starts out in rotation with nothing measured. Connections already made
+//! to the old address keep their lease on the member. A name with records
+//! of both families also gives its member a fallback address of the other
+//! family, which leases carry for Happy Eyeballs (see `eyeballs`).
+//!
+//! Source hashing is rendezvous hashing: each client goes to the backend
+//! scoring highest for it, weighted, so adding or removing a target only
+//! moves the clients that gain or lose that target. The client's port is
+//! left out, as it changes with every reconnect.
+//!
+//! A connection holds a lease on its backend for as long as it lives, so the
+//! pool (and `tcpstrip_route_active_connections{route}`) knows how many
+//! connections each backend is serving; the totals per backend are in the
+//! `tcpstrip_route_*_total` counters.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use crate::metrics::{self, LabeledMetric, Metric};
+
+/// Largest `@WEIGHT` of a target
+pub const MAX_WEIGHT: u32 = 100;
+/// One in this many `latency` picks goes round-robin
+pub const LATENCY_PROBE_EVERY: usize = 16;
+/// How long a target that refused or timed out a connection stays out of
+/// rotation
+pub const RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// How `--balance` picks a backend for a new connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    Weighted,
+    LeastConn,
+    Latency,
+    SourceHash,
+}
+
+impl Strategy {
+    /// Whether target weights mean anything to the strategy
+    pub fn uses_weights(self) -> bool {
+        matches!(self, Strategy::Weighted | Strategy::LeastConn | Strategy::SourceHash)
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Strategy::RoundRobin => write!(f, "round-robin"),
+            Strategy::Weighted => write!(f, "weighted"),
+            Strategy::LeastConn => write!(f, "least-conn"),
+            Strategy::Latency => write!(f, "latency"),
+            Strategy::SourceHash => write!(f, "source-hash"),
+        }
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "weighted" => Ok(Strategy::Weighted),
+            "least-conn" => Ok(Strategy::LeastConn),
+            "latency" => Ok(Strategy::Latency),
+            "source-hash" => Ok(Strategy::SourceHash),
+            _ => Err(format!(
+                "unknown balancing strategy '{}' (expected round-robin, weighted, least-conn, latency or source-hash)",
+                s
+            )),
+        }
+    }
+}
+
+/// One `--target`: `HOST:PORT`, optionally with `@WEIGHT`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub host: String,
+    pub weight: u32,
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.weight {
+            1 => write!(f, "{}", self.host),
+            weight => write!(f, "{}@{}", self.host, weight),
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, weight) = match s.rsplit_once('@') {
+            Some((host, weight)) => {
+                let weight = weight
+                    .parse()
+                    .ok()
+                    .filter(|weight| (1..=MAX_WEIGHT).contains(weight))
+                    .ok_or_else(|| format!("invalid weight '{}' (expected 1-{})", weight, MAX_WEIGHT))?;
+                (host, weight)
+            }
+            None => (s, 1),
+        };
+        Ok(Self {
+            host: host.to_string(),
+            weight,
+        })
+    }
+}
+
+/// A `--target` of a pool, by position, or its `--backup-target`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Member {
+    Target(usize),
+    Backup,
+}
+
+#[derive(Debug)]
+struct Backend {
+    /// Current address and its active connections gauge
+    addr: RwLock<(SocketAddr, Arc<Metric>)>,
+    /// Address of the other family to race against `addr`
+    fallback: RwLock<Option<SocketAddr>>,
+    weight: u32,
+    /// Live connections from this pool
+    connections: AtomicUsize,
+    /// Moving average of connect times in microseconds, 0 until measured
+    connect_micros: AtomicU64,
+    /// `clock_ms` until which the backend is out of rotation, 0 if it is in
+    down_until: AtomicU64,
+    /// Cleared while the backend fails its health checks
+    healthy: AtomicBool,
+}
+
+impl Backend {
+    fn new(addr: SocketAddr, weight: u32) -> Arc<Self> {
+        Arc::new(Self {
+            addr: RwLock::new((addr, active_connections().with(&addr.to_string()))),
+            fallback: RwLock::new(None),
+            weight,
+            connections: AtomicUsize::new(0),
+            connect_micros: AtomicU64::new(0),
+            down_until: AtomicU64::new(0),
+            healthy: AtomicBool::new(true),
+        })
+    }
+
+    fn addr(&self) -> SocketAddr {
+        self.addr.read().unwrap_or_else(|e| e.into_inner()).0
+    }
+
+    #[cfg(test)]
+    fn active(&self) -> Arc<Metric> {
+        self.addr.read().unwrap_or_else(|e| e.into_inner()).1.clone()
+    }
+
+    fn usable(&self, now: u64) -> bool {
+        self.healthy.load(Ordering::Relaxed) && self.down_until.load(Ordering::Relaxed) <= now
+    }
+}
+
+fn active_connections() -> Arc<LabeledMetric> {
+    metrics::registry().labeled_gauge(
+        "tcpstrip_route_active_connections",
+        "Connections currently forwarded to each backend",
+        "route",
+    )
+}
+
+/// Milliseconds since the first call, never 0
+fn clock_ms() -> u64 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed().as_millis() as u64 + 1
+}
+
+/// Backends sharing new connections according to a strategy
+#[derive(Debug)]
+pub struct Pool {
+    backends: Vec<Arc<Backend>>,
+    strategy: Strategy,
+    /// Backend indexes in weighted round-robin order
+    schedule: Vec<usize>,
+    next: AtomicUsize,
+    /// Where new connections go while every backend is out of rotation
+    backup: Option<Arc<Backend>>,
+    failed_over: AtomicBool,
+    failovers: Arc<Metric>,
+    failover_active: Arc<Metric>,
+}
+
+impl Pool {
+    /// A round-robin pool of `addrs`, which must not be empty
+    pub fn new(addrs: &[SocketAddr]) -> Self {
+        let backends: Vec<(SocketAddr, u32)> = addrs.iter().map(|&addr| (addr, 1)).collect();
+        Self::with_strategy(&backends, Strategy::RoundRobin)
+    }
+
+    /// A pool of weighted backends, which must not be empty
+    pub fn with_strategy(backends: &[(SocketAddr, u32)], strategy: Strategy) -> Self {
+        assert!(!backends.is_empty(), "a pool needs at least one backend");
+        let weights: Vec<u32> = backends.iter().map(|&(_, weight)| weight.max(1)).collect();
+        let registry = metrics::registry();
+        Self {
+            backends: backends
+                .iter()
+                .zip(&weights)
+                .map(|(&(addr, _), &weight)| Backend::new(addr, weight))
+                .collect(),
+            strategy,
+            schedule: schedule(&weights),
+            next: AtomicUsize::new(0),
+            backup: None,
+            failed_over: AtomicBool::new(false),
+            failovers: registry.counter(
+                "tcpstrip_failovers_total",
+                "Switches of new connections to the backup target and back",
+            ),
+            failover_active: registry.gauge(
+                "tcpstrip_failover_active",
+                "1 while new connections go to the backup target",
+            ),
+        }
+    }
+
+    /// Send new connections to `backup` while every backend is out of
+    /// rotation
+    pub fn with_backup(mut self, backup: SocketAddr) -> Self {
+        self.backup = Some(Backend::new(backup, 1));
+        self
+    }
+
+    pub fn backup(&self) -> Option<SocketAddr> {
+        self.backup.as_ref().map(|backup| backup.addr())
+    }
+
+    pub fn addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.backends.iter().map(|backend| backend.addr())
+    }
+
+    /// The targets, then the backup if there is one
+    pub fn members(&self) -> impl Iterator<Item = Member> {
+        (0..self.backends.len()).map(Member::Target).chain(self.backup.as_ref().map(|_| Member::Backup))
+    }
+
+    /// Panics if the pool has no such member
+    fn member(&self, member: Member) -> &Backend {
+        match member {
+            Member::Target(index) => &self.backends[index],
+            Member::Backup => self.backup.as_ref().expect("pool has no backup"),
+        }
+    }
+
+    pub fn addr(&self, member: Member) -> SocketAddr {
+        self.member(member).addr()
+    }
+
+    /// Point a member at a new address, in rotation and unmeasured
+    pub fn set_addr(&self, member: Member, addr: SocketAddr) {
+        let backend = self.member(member);
+        *backend.addr.write().unwrap_or_else(|e| e.into_inner()) = (addr, active_connections().with(&addr.to_string()));
+        backend.connect_micros.store(0, Ordering::Relaxed);
+        backend.down_until.store(0, Ordering::Relaxed);
+        backend.healthy.store(true, Ordering::Relaxed);
+    }
+
+    pub fn fallback(&self, member: Member) -> Option<SocketAddr> {
+        *self.member(member).fallback.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Give a member an address of the other family for Happy Eyeballs, or
+    /// take it away
+    pub fn set_fallback(&self, member: Member, fallback: Option<SocketAddr>) {
+        *self.member(member).fallback.write().unwrap_or_else(|e| e.into_inner()) = fallback;
+    }
+
+    /// Put a member in or out of rotation by health
+    pub fn set_healthy(&self, member: Member, healthy: bool) {
+        self.member(member).healthy.store(healthy, Ordering::Relaxed);
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// The backend for a new connection from `client`
+    pub fn pick_for(&self, client: IpAddr) -> Lease {
+        match self.strategy {
+            Strategy::SourceHash => self.lease(self.rendezvous(client)),
+            _ => self.pick(),
+        }
+    }
+
+    /// The backend for a new connection, whoever the client
+    ///
+    /// Source hashing needs the client and falls back to round-robin here.
+    fn pick(&self) -> Lease {
+        let turn = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.backends.len();
+        let index = match self.strategy {
+            Strategy::RoundRobin | Strategy::SourceHash => turn % count,
+            Strategy::Weighted => self.schedule[turn % self.schedule.len()],
+            Strategy::LeastConn => self.lowest(turn, |backend| {
+                backend.connections.load(Ordering::Relaxed) as f64 / backend.weight as f64
+            }),
+            Strategy::Latency if turn.is_multiple_of(LATENCY_PROBE_EVERY) => (turn / LATENCY_PROBE_EVERY) % count,
+            // Unmeasured backends score 0 and are tried first
+            Strategy::Latency => self.lowest(turn, |backend| {
+                let connections = backend.connections.load(Ordering::Relaxed) as f64;
+                backend.connect_micros.load(Ordering::Relaxed) as f64 * (connections + 1.0)
+            }),
+        };
+        self.lease(index)
+    }
+
+    /// Lease the backend at `index`, or the next one in rotation if it is
+    /// out, or the backup if they all are
+    fn lease(&self, index: usize) -> Lease {
+        let now = clock_ms();
+        let count = self.backends.len();
+        let usable = (0..count)
+            .map(|offset| &self.backends[(index + offset) % count])
+            .find(|backend| backend.usable(now));
+        let backend = match (usable, &self.backup) {
+            (Some(backend), _) => {
+                if self.failed_over.swap(false, Ordering::Relaxed) {
+                    self.failovers.inc();
+                    self.failover_active.set(0);
+                    info!("Target {} is back in rotation, failing back from backup {}", backend.addr(), self.backup().unwrap());
+                }
+                backend
+            }
+            (None, Some(backup)) => {
+                if !self.failed_over.swap(true, Ordering::Relaxed) {
+                    self.failovers.inc();
+                    self.failover_active.set(1);
+                    warn!("All targets are out of rotation, failing over to backup {}", backup.addr());
+                }
+                backup
+            }
+            // Nowhere else to go; try it anyway
+            (None, None) => &self.backends[index],
+        }
+        .clone();
+        let (addr, active) = backend.addr.read().unwrap_or_else(|e| e.into_inner()).clone();
+        let fallback = *backend.fallback.read().unwrap_or_else(|e| e.into_inner());
+        backend.connections.fetch_add(1, Ordering::Relaxed);
+        active.inc();
+        Lease {
+            addr,
+            fallback,
+            backend,
+            active,
+        }
+    }
+
+    /// Index of the backend `client` hashes to: the highest of the weighted
+    /// scores `-weight / ln(hash)` with the hash scaled into (0, 1)
+    fn rendezvous(&self, client: IpAddr) -> usize {
+        let now = clock_ms();
+        let score = |backend: &Backend| {
+            if !backend.usable(now) {
+                return f64::NEG_INFINITY;
+            }
+            let hash = rendezvous_hash(client, backend.addr());
+            let unit = (hash >> 11) as f64 / (1u64 << 53) as f64;
+            -(backend.weight as f64) / unit.max(f64::MIN_POSITIVE).ln()
+        };
+        (0..self.backends.len())
+            .max_by(|&a, &b| score(&self.backends[a]).total_cmp(&score(&self.backends[b])))
+            .unwrap_or(0)
+    }
+
+    /// Index of the backend in rotation with the lowest score; ties go to
+    /// whichever comes first from a rotating start, so they take turns
+    fn lowest(&self, turn: usize, score: impl Fn(&Backend) -> f64) -> usize {
+        let now = clock_ms();
+        let score = |backend: &Backend| match backend.usable(now) {
+            true => score(backend),
+            false => f64::INFINITY,
+        };
+        let count = self.backends.len();
+        (0..count)
+            .map(|offset| (turn + offset) % count)
+            .min_by(|&a, &b| score(&self.backends[a]).total_cmp(&score(&self.backends[b])))
+            .unwrap_or(0)
+    }
+}
+
+/// FNV-1a of `client|backend`, mixed so that similar inputs score
+/// independently; stable across restarts and hosts
+fn rendezvous_hash(client: IpAddr, backend: SocketAddr) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in format!("{}|{}", client, backend).bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    // splitmix64 finalizer
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+/// Smooth weighted round-robin order: each backend appears `weight` times
+/// per cycle, spread out rather than in runs
+fn schedule(weights: &[u32]) -> Vec<usize> {
+    let total: i64 = weights.iter().map(|&weight| weight as i64).sum();
+    let mut current = vec![0i64; weights.len()];
+    (0..total)
+        .map(|_| {
+            for (current, &weight) in current.iter_mut().zip(weights) {
+                *current += weight as i64;
+            }
+            let best = (0..weights.len()).fold(0, |best, i| if current[i] > current[best] { i } else { best });
+            current[best] -= total;
+            best
+        })
+        .collect()
+}
+
+/// A connection's claim on its backend, released on drop
+#[derive(Debug)]
+pub struct Lease {
+    pub addr: SocketAddr,
+    /// The backend's address of the other family, if it has one
+    pub fallback: Option<SocketAddr>,
+    backend: Arc<Backend>,
+    /// The gauge of `addr`, which the backend may have moved away from
+    active: Arc<Metric>,
+}
+
+impl Lease {
+    /// The backend accepted the connection after `elapsed`: feed that into
+    /// its average for the latency strategy, and put it back in rotation if
+    /// it was out
+    pub fn connected(&self, elapsed: Duration) {
+        let sample = (elapsed.as_micros() as u64).max(1);
+        let _ = self
+            .backend
+            .connect_micros
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| match average {
+                0 => Some(sample),
+                average => Some((average * 7 + sample) / 8),
+            });
+        if self.backend.down_until.swap(0, Ordering::Relaxed) != 0 {
+            info!("Target {} accepts connections again, back in rotation", self.addr);
+        }
+    }
+
+    /// The backend could not be connected to: take it out of rotation for
+    /// `RETRY_AFTER`
+    pub fn failed(&self) {
+        let until = clock_ms() + RETRY_AFTER.as_millis() as u64;
+        if self.backend.down_until.swap(until, Ordering::Relaxed) == 0 {
+            warn!("Target {} is unreachable, taking it out of rotation", self.addr);
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.backend.connections.fetch_sub(1, Ordering::Relaxed);
+        self.active.dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_robin_leases() {
+        let addrs: Vec<SocketAddr> = ["192.0.2.10:9000", "192.0.2.11:9000", "192.0.2.12:9000"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let pool = Pool::new(&addrs);
+        let leases: Vec<Lease> = (0..4).map(|_| pool.pick()).collect();
+        let picked: Vec<SocketAddr> = leases.iter().map(|lease| lease.addr).collect();
+        assert_eq!(picked, [addrs[0], addrs[1], addrs[2], addrs[0]]);
+
+        assert_eq!(pool.backends[0].active().get(), 2);
+        assert_eq!(pool.backends[2].active().get(), 1);
+        drop(leases);
+        assert!(pool.backends.iter().all(|backend| backend.active().get() == 0));
+    }
+
+    #[test]
+    fn test_strategies() {
+        let addrs: Vec<SocketAddr> = ["192.0.2.20:9000", "192.0.2.21:9000"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let target: Target = "gw1.example.com:9000@3".parse().unwrap();
+        assert_eq!(target.weight, 3);
+        assert_eq!(target.to_string(), "gw1.example.com:9000@3");
+        assert!("gw1.example.com:9000@0".parse::<Target>().is_err());
+        assert_eq!("least-conn".parse(), Ok(Strategy::LeastConn));
+
+        // 3:1, interleaved
+        assert_eq!(schedule(&[3, 1]), [0, 0, 1, 0]);
+        assert_eq!(schedule(&[1, 1, 2]), [2, 0, 1, 2]);
+
+        // Least-connections fills the idle backend first
+        let pool = Pool::with_strategy(&[(addrs[0], 1), (addrs[1], 1)], Strategy::LeastConn);
+        let first = pool.pick();
+        let held: Vec<Lease> = (0..3).map(|_| pool.pick()).collect();
+        assert_eq!(held.iter().filter(|lease| lease.addr == first.addr).count(), 1);
+        drop(first);
+        assert_ne!(pool.pick().addr, held[0].addr);
+
+        // Latency measures both, then prefers the quicker one
+        let pool = Pool::with_strategy(&[(addrs[0], 1), (addrs[1], 1)], Strategy::Latency);
+        pool.next.store(1, Ordering::Relaxed);
+        for _ in 0..2 {
+            let lease = pool.pick();
+            let micros = if lease.addr == addrs[0] { 900 } else { 100 };
+            lease.connected(Duration::from_micros(micros));
+        }
+        assert!((0..5).all(|_| pool.pick().addr == addrs[1]));
+    }
+
+    #[test]
+    fn test_source_hash_sticky() {
+        let addrs: Vec<SocketAddr> = (30..34).map(|host| format!("192.0.2.{}:9000", host).parse().unwrap()).collect();
+        let backends: Vec<(SocketAddr, u32)> = addrs.iter().map(|&addr| (addr, 1)).collect();
+        let pool = Pool::with_strategy(&backends, Strategy::SourceHash);
+        let clients: Vec<IpAddr> = (0..200).map(|host| IpAddr::from([10, 0, (host / 256) as u8, host as u8])).collect();
+
+        let picked: Vec<SocketAddr> = clients.iter().map(|&client| pool.pick_for(client).addr).collect();
+        let again: Vec<SocketAddr> = clients.iter().map(|&client| pool.pick_for(client).addr).collect();
+        assert_eq!(picked, again);
+        assert!(addrs.iter().all(|addr| picked.iter().filter(|&picked| picked == addr).count() > 20));
+
+        // Losing a backend only moves the clients that were on it
+        let fewer = Pool::with_strategy(&backends[..3], Strategy::SourceHash);
+        for (&client, &addr) in clients.iter().zip(&picked) {
+            if addr != addrs[3] {
+                assert_eq!(fewer.pick_for(client).addr, addr);
+            }
+        }
+    }
+
+    #[test]
+    fn test_failover_to_backup() {
+        let primary: SocketAddr = "192.0.2.40:9000".parse().unwrap();
+        let backup: SocketAddr = "192.0.2.41:9000".parse().unwrap();
+        let pool = Pool::new(&[primary]).with_backup(backup);
+        assert_eq!(pool.pick().addr, primary);
+
+        // Out of rotation after a failed connect, back after a good one
+        pool.pick().failed();
+        let lease = pool.pick();
+        assert_eq!(lease.addr, backup);
+        assert_eq!(pool.failover_active.get(), 1);
+        pool.backends[0].down_until.store(1, Ordering::Relaxed);
+        let retry = pool.pick();
+        assert_eq!(retry.addr, primary);
+        retry.connected(Duration::from_micros(50));
+        assert_eq!(pool.backends[0].down_until.load(Ordering::Relaxed), 0);
+        assert_eq!(pool.failover_active.get(), 0);
+    }
+
+    #[test]
+    fn test_member_moves() {
+        let old: SocketAddr = "192.0.2.50:9000".parse().unwrap();
+        let new: SocketAddr = "192.0.2.51:9000".parse().unwrap();
+        let pool = Pool::new(&[old]);
+        assert_eq!(pool.members().collect::<Vec<_>>(), [Member::Target(0)]);
+        let lease = pool.pick();
+        lease.failed();
+
+        // Back in rotation at the new address; the old lease still counts
+        // against the old one
+        pool.set_addr(Member::Target(0), new);
+        let moved = pool.pick();
+        assert_eq!((moved.addr, pool.addr(Member::Target(0))), (new, new));
+        assert_eq!(moved.fallback, None);
+        assert_eq!(pool.backends[0].active().get(), 1);
+        drop(lease);
+        assert_eq!(pool.backends[0].active().get(), 1);
+        assert_eq!(pool.backends[0].connections.load(Ordering::Relaxed), 1);
+
+        let v6: SocketAddr = "[2001:db8::51]:9000".parse().unwrap();
+        pool.set_fallback(Member::Target(0), Some(v6));
+        assert_eq!(pool.pick().fallback, Some(v6));
+        assert_eq!(pool.fallback(Member::Target(0)), Some(v6));
+    }
+}
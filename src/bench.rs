@@ -0,0 +1,154 @@
+//! Round trip latency benchmark
+//!
+//! `tcp-proxy bench` opens a number of connections to an echoing target
+//! (usually a proxy in front of an echo server) and plays ping-pong on each:
+//! write a message, read it back, repeat. Every round trip is timed and the
+//! percentiles are printed, optionally next to the same run against a
+//! baseline address (the echo server itself) to show what the proxy adds.
+//!
+//! Each connection gets its own blocking thread and `TCP_NODELAY`, so what
+//! is measured is the network and the proxy, not a runtime's scheduling.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long one round trip may take before the run fails
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shape of a run
+#[derive(Debug, Clone, Copy)]
+pub struct Load {
+    pub connections: usize,
+    pub messages: usize,
+    pub size: usize,
+}
+
+/// Round trip times of a run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Latencies {
+    /// Sorted ascending
+    samples: Vec<Duration>,
+    elapsed: Duration,
+}
+
+impl Latencies {
+    fn new(mut samples: Vec<Duration>, elapsed: Duration) -> Self {
+        samples.sort_unstable();
+        Self { samples, elapsed }
+    }
+
+    /// The round trip time `p` percent of the samples are at or under
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (p / 100.0 * self.samples.len() as f64).ceil() as usize;
+        self.samples[rank.clamp(1, self.samples.len()) - 1]
+    }
+
+    pub fn max(&self) -> Duration {
+        self.samples.last().copied().unwrap_or_default()
+    }
+
+    pub fn count(&self) -> usize {
+        self.samples.len()
+    }
+}
+
+impl fmt::Display for Latencies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rate = self.count() as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON);
+        write!(
+            f,
+            "{} round trips ({:.0}/s): p50 {}us  p90 {}us  p99 {}us  max {}us",
+            self.count(),
+            rate,
+            self.percentile(50.0).as_micros(),
+            self.percentile(90.0).as_micros(),
+            self.percentile(99.0).as_micros(),
+            self.max().as_micros()
+        )
+    }
+}
+
+/// Run `load` against `target`, which must echo what it is sent
+pub fn run(target: SocketAddr, load: Load) -> io::Result<Latencies> {
+    let start = Instant::now();
+    let samples = thread::scope(|scope| {
+        let workers: Vec<_> = (0..load.connections)
+            .map(|_| scope.spawn(move || ping_pong(target, load.messages, load.size)))
+            .collect();
+        let mut samples = Vec::with_capacity(load.connections * load.messages);
+        for worker in workers {
+            samples.extend(worker.join().map_err(|_| io::Error::other("benchmark thread panicked"))??);
+        }
+        Ok::<_, io::Error>(samples)
+    })?;
+    Ok(Latencies::new(samples, start.elapsed()))
+}
+
+fn ping_pong(target: SocketAddr, messages: usize, size: usize) -> io::Result<Vec<Duration>> {
+    let mut stream = TcpStream::connect_timeout(&target, IO_TIMEOUT)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    let message: Vec<u8> = (0..size).map(|i| i as u8).collect();
+    let mut echo = vec![0u8; size];
+    let mut samples = Vec::with_capacity(messages);
+    for _ in 0..messages {
+        let sent = Instant::now();
+        stream.write_all(&message)?;
+        stream.read_exact(&mut echo)?;
+        samples.push(sent.elapsed());
+        if echo != message {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the target did not echo what it was sent"));
+        }
+    }
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_percentiles() {
+        let latencies = Latencies::new((1..=100).rev().map(Duration::from_micros).collect(), Duration::from_secs(1));
+        assert_eq!(latencies.percentile(50.0), Duration::from_micros(50));
+        assert_eq!(latencies.percentile(99.0), Duration::from_micros(99));
+        assert_eq!(latencies.percentile(0.0), Duration::from_micros(1));
+        assert_eq!(latencies.max(), Duration::from_micros(100));
+
+        let empty = Latencies::new(Vec::new(), Duration::ZERO);
+        assert_eq!(empty.percentile(99.0), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_run_against_echo() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(2) {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut writer = stream.try_clone().unwrap();
+                    let _ = io::copy(&mut stream, &mut writer);
+                });
+            }
+        });
+
+        let latencies = run(target, Load {
+            connections: 2,
+            messages: 50,
+            size: 64,
+        })
+        .unwrap();
+        assert_eq!(latencies.count(), 100);
+        assert!(latencies.percentile(50.0) <= latencies.max());
+    }
+}
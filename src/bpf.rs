@@ -0,0 +1,279 @@
+//! Minimal bpf(2) wrapper (Linux only)
+//!
+//! The AF_XDP, sockmap and XDP ingress datapaths each need a handful of
+//! maps and one small program. Rather than pull in libbpf and ship
+//! compiled objects, the programs are assembled by hand and loaded through
+//! the raw syscall; this module holds the shared plumbing.
+
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+// bpf(2) commands, map/program types and helpers (linux/bpf.h)
+pub const BPF_MAP_CREATE: libc::c_int = 0;
+pub const BPF_MAP_LOOKUP_ELEM: libc::c_int = 1;
+pub const BPF_MAP_UPDATE_ELEM: libc::c_int = 2;
+pub const BPF_MAP_DELETE_ELEM: libc::c_int = 3;
+pub const BPF_PROG_LOAD: libc::c_int = 5;
+pub const BPF_PROG_ATTACH: libc::c_int = 8;
+#[cfg(test)]
+pub const BPF_PROG_TEST_RUN: libc::c_int = 10;
+pub const BPF_LINK_CREATE: libc::c_int = 28;
+
+pub const BPF_MAP_TYPE_HASH: u32 = 1;
+pub const BPF_MAP_TYPE_ARRAY: u32 = 2;
+pub const BPF_MAP_TYPE_SOCKMAP: u32 = 15;
+pub const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+
+pub const BPF_PROG_TYPE_SK_SKB: u32 = 14;
+pub const BPF_PROG_TYPE_XDP: u32 = 6;
+
+pub const BPF_XDP: u32 = 37;
+pub const BPF_SK_SKB_VERDICT: u32 = 38;
+
+pub const BPF_PSEUDO_MAP_FD: u8 = 1;
+
+pub const BPF_FUNC_MAP_LOOKUP_ELEM: i32 = 1;
+pub const BPF_FUNC_GET_SOCKET_COOKIE: i32 = 46;
+pub const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+pub const BPF_FUNC_SK_REDIRECT_MAP: i32 = 52;
+
+/// Only create an element that does not exist yet
+pub const BPF_NOEXIST: u64 = 1;
+
+/// One BPF instruction (struct bpf_insn)
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct BpfInsn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+impl BpfInsn {
+    pub const fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Self {
+        Self {
+            code,
+            regs: src << 4 | dst,
+            off,
+            imm,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct BpfMapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct BpfMapElemAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct BpfProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct BpfProgAttachAttr {
+    target_fd: u32,
+    attach_bpf_fd: u32,
+    attach_type: u32,
+    attach_flags: u32,
+}
+
+#[cfg(test)]
+#[repr(C)]
+#[derive(Default)]
+struct BpfTestRunAttr {
+    prog_fd: u32,
+    retval: u32,
+    data_size_in: u32,
+    data_size_out: u32,
+    data_in: u64,
+    data_out: u64,
+    repeat: u32,
+    duration: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct BpfLinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+fn bpf<T>(cmd: libc::c_int, attr: &mut T) -> io::Result<libc::c_long> {
+    let rc = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *mut T as *mut libc::c_void,
+            mem::size_of::<T>() as libc::c_uint,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(rc)
+}
+
+/// bpf(2) commands that return a new file descriptor
+fn bpf_fd<T>(cmd: libc::c_int, attr: &mut T) -> io::Result<OwnedFd> {
+    bpf(cmd, attr).map(|fd| unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+pub fn create_map(map_type: u32, key_size: u32, value_size: u32, max_entries: u32) -> io::Result<OwnedFd> {
+    let mut attr = BpfMapCreateAttr {
+        map_type,
+        key_size,
+        value_size,
+        max_entries,
+        ..Default::default()
+    };
+    bpf_fd(BPF_MAP_CREATE, &mut attr)
+}
+
+pub fn update_elem<K, V>(map: &OwnedFd, key: &K, value: &V, flags: u64) -> io::Result<()> {
+    let mut attr = BpfMapElemAttr {
+        map_fd: map.as_raw_fd() as u32,
+        key: key as *const K as u64,
+        value: value as *const V as u64,
+        flags,
+        ..Default::default()
+    };
+    bpf(BPF_MAP_UPDATE_ELEM, &mut attr).map(drop)
+}
+
+pub fn lookup_elem<K, V>(map: &OwnedFd, key: &K, value: &mut V) -> io::Result<()> {
+    let mut attr = BpfMapElemAttr {
+        map_fd: map.as_raw_fd() as u32,
+        key: key as *const K as u64,
+        value: value as *mut V as u64,
+        ..Default::default()
+    };
+    bpf(BPF_MAP_LOOKUP_ELEM, &mut attr).map(drop)
+}
+
+pub fn delete_elem<K>(map: &OwnedFd, key: &K) -> io::Result<()> {
+    let mut attr = BpfMapElemAttr {
+        map_fd: map.as_raw_fd() as u32,
+        key: key as *const K as u64,
+        ..Default::default()
+    };
+    bpf(BPF_MAP_DELETE_ELEM, &mut attr).map(drop)
+}
+
+/// Load a program, folding the verifier log into the error on failure
+///
+/// The log of a program with loops easily outgrows any buffer, and a
+/// truncated log fails the load, so it is only asked for on a second
+/// attempt once the first has failed.
+pub fn load_program(
+    prog_type: u32,
+    expected_attach_type: u32,
+    name: &str,
+    insns: &[BpfInsn],
+) -> io::Result<OwnedFd> {
+    let license = b"Dual MIT/GPL\0";
+    let mut prog_name = [0u8; 16];
+    let len = name.len().min(prog_name.len() - 1);
+    prog_name[..len].copy_from_slice(&name.as_bytes()[..len]);
+
+    let mut attr = BpfProgLoadAttr {
+        prog_type,
+        insn_cnt: insns.len() as u32,
+        insns: insns.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        prog_name,
+        expected_attach_type,
+        ..Default::default()
+    };
+    let e = match bpf_fd(BPF_PROG_LOAD, &mut attr) {
+        Ok(prog) => return Ok(prog),
+        Err(e) => e,
+    };
+
+    let mut log = vec![0u8; 64 * 1024];
+    attr.log_level = 1;
+    attr.log_size = log.len() as u32;
+    attr.log_buf = log.as_mut_ptr() as u64;
+    let _ = bpf_fd(BPF_PROG_LOAD, &mut attr);
+    let end = log.iter().position(|&b| b == 0).unwrap_or(log.len());
+    let log = String::from_utf8_lossy(&log[..end]);
+    // The verdict is at the end of the log
+    let tail: Vec<&str> = log.trim().lines().rev().take(20).collect();
+    let tail: Vec<&str> = tail.into_iter().rev().collect();
+    Err(io::Error::new(e.kind(), format!("loading {} program: {} {}", name, e, tail.join("\n"))))
+}
+
+/// Attach a program to a map (sockmap verdict programs); it stays attached
+/// for as long as the map exists
+pub fn attach_to_map(prog: &OwnedFd, map: &OwnedFd, attach_type: u32) -> io::Result<()> {
+    let mut attr = BpfProgAttachAttr {
+        target_fd: map.as_raw_fd() as u32,
+        attach_bpf_fd: prog.as_raw_fd() as u32,
+        attach_type,
+        attach_flags: 0,
+    };
+    bpf(BPF_PROG_ATTACH, &mut attr).map(drop)
+}
+
+/// Attach a program to an interface through a BPF link; dropping the link
+/// detaches it
+pub fn link_create(prog: &OwnedFd, ifindex: u32, attach_type: u32) -> io::Result<OwnedFd> {
+    let mut attr = BpfLinkCreateAttr {
+        prog_fd: prog.as_raw_fd() as u32,
+        target_ifindex: ifindex,
+        attach_type,
+        flags: 0,
+    };
+    bpf_fd(BPF_LINK_CREATE, &mut attr)
+}
+
+/// Run a program once on `packet` without attaching it, returning its
+/// verdict and the packet as the program left it
+#[cfg(test)]
+pub fn test_run(prog: &OwnedFd, packet: &[u8]) -> io::Result<(u32, Vec<u8>)> {
+    let mut out = vec![0u8; packet.len() + 256];
+    let mut attr = BpfTestRunAttr {
+        prog_fd: prog.as_raw_fd() as u32,
+        data_size_in: packet.len() as u32,
+        data_size_out: out.len() as u32,
+        data_in: packet.as_ptr() as u64,
+        data_out: out.as_mut_ptr() as u64,
+        repeat: 1,
+        ..Default::default()
+    };
+    bpf(BPF_PROG_TEST_RUN, &mut attr)?;
+    out.truncate(attr.data_size_out as usize);
+    Ok((attr.retval, out))
+}
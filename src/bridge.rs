@@ -0,0 +1,476 @@
+//! AF_PACKET bridge datapath (Linux only)
+//!
+//! In bridge mode tcpstrip sits between two interfaces like a bump in the
+//! wire: every frame received on one side is parsed, has its TCP options
+//! scrubbed and is transmitted on the other side. Unlike the socket proxy
+//! this works on the actual segments the hosts exchange, so it can remove
+//! timestamp options that the local kernel would otherwise always emit.
+//!
+//! The "inside" interface faces the hosts being protected and the
+//! "outside" interface faces the network (exchange, cross-connect). The
+//! configured policy is applied to inside->outside traffic and its
+//! return-path counterpart to traffic coming back (see
+//! `ScrubPolicy::for_return_path`).
+//!
+//! Requires CAP_NET_RAW. NIC offloads that coalesce frames (GRO/LRO) must
+//! be disabled on both interfaces, otherwise the kernel hands us frames
+//! larger than the MTU which cannot be transmitted again.
+
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+
+use tracing::{debug, info, warn};
+
+use crate::entropy::EntropyConfig;
+use crate::packet::{parse_ethernet_frame, QueueStats, ScrubStats};
+use crate::scrub::{ScrubPolicy, Scrubber};
+
+/// Bridge configuration
+#[derive(Debug, Clone)]
+pub struct BridgeConfig {
+    /// Interface facing the protected hosts
+    pub inside: String,
+    /// Interface facing the network
+    pub outside: String,
+    /// Scrubbing applied to inside->outside traffic
+    pub policy: ScrubPolicy,
+    /// Randomness for spoofed timestamps
+    pub entropy: EntropyConfig,
+    /// Size of the per-direction receive buffer
+    pub frame_buffer_size: usize,
+}
+
+/// Counters for both directions of the bridge
+#[derive(Debug, Default)]
+pub struct BridgeStats {
+    pub outbound: ScrubStats,
+    pub inbound: ScrubStats,
+}
+
+/// A raw AF_PACKET socket bound to a single interface
+struct PacketSocket {
+    fd: OwnedFd,
+    name: String,
+}
+
+impl PacketSocket {
+    fn open(name: &str) -> io::Result<Self> {
+        let c_name = CString::new(name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"))?;
+        let ifindex = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let protocol = (libc::ETH_P_ALL as u16).to_be();
+        let raw = unsafe {
+            libc::socket(
+                libc::AF_PACKET,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                protocol as libc::c_int,
+            )
+        };
+        if raw < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as libc::c_ushort;
+        addr.sll_protocol = protocol;
+        addr.sll_ifindex = ifindex as libc::c_int;
+        let rc = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // A bridge must see frames addressed to the hosts behind it
+        let mreq = libc::packet_mreq {
+            mr_ifindex: ifindex as libc::c_int,
+            mr_type: libc::PACKET_MR_PROMISC as libc::c_ushort,
+            mr_alen: 0,
+            mr_address: [0; 8],
+        };
+        let rc = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_PACKET,
+                libc::PACKET_ADD_MEMBERSHIP,
+                &mreq as *const _ as *const libc::c_void,
+                mem::size_of::<libc::packet_mreq>() as libc::socklen_t,
+            )
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Don't receive our own transmissions (Linux 4.20+). Older kernels
+        // are handled by the pkttype check in recv().
+        let ignore: libc::c_int = 1;
+        unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_PACKET,
+                libc::PACKET_IGNORE_OUTGOING,
+                &ignore as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            );
+        }
+
+        Ok(Self {
+            fd,
+            name: name.to_string(),
+        })
+    }
+
+    /// Receive one inbound frame, skipping frames this host transmitted
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+            let mut addr_len = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+            let n = unsafe {
+                libc::recvfrom(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                    &mut addr as *mut _ as *mut libc::sockaddr,
+                    &mut addr_len,
+                )
+            };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            if addr.sll_pkttype == libc::PACKET_OUTGOING {
+                continue;
+            }
+            return Ok(n as usize);
+        }
+    }
+
+    fn send(&self, frame: &[u8]) -> io::Result<()> {
+        let n = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                frame.as_ptr() as *const libc::c_void,
+                frame.len(),
+                0,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// An opened bridge, ready to run
+pub struct Bridge {
+    config: BridgeConfig,
+    inside: Arc<PacketSocket>,
+    outside: Arc<PacketSocket>,
+    stats: Arc<BridgeStats>,
+}
+
+impl Bridge {
+    /// Open raw sockets on both interfaces
+    pub fn open(config: BridgeConfig) -> io::Result<Self> {
+        let inside = Arc::new(PacketSocket::open(&config.inside)?);
+        let outside = Arc::new(PacketSocket::open(&config.outside)?);
+
+        Ok(Self {
+            config,
+            inside,
+            outside,
+            stats: Arc::new(BridgeStats::default()),
+        })
+    }
+
+    pub fn stats(&self) -> Arc<BridgeStats> {
+        self.stats.clone()
+    }
+
+    /// Forward frames in both directions until an I/O error occurs
+    ///
+    /// Each direction runs on its own OS thread with blocking I/O; this
+    /// call blocks until one of them fails.
+    pub fn run(self) -> io::Result<()> {
+        info!(
+            "Bridging {} <-> {} (timestamps: {:?})",
+            self.config.inside, self.config.outside, self.config.policy.timestamps
+        );
+
+        let (outbound_scrubber, inbound_scrubber) =
+            Scrubber::pair(self.config.policy.clone(), &self.config.entropy)?;
+        let outbound = spawn_direction(
+            "bridge-out",
+            self.inside.clone(),
+            self.outside.clone(),
+            outbound_scrubber,
+            self.config.frame_buffer_size,
+            self.stats.clone(),
+            |stats| &stats.outbound,
+        )?;
+        let inbound = spawn_direction(
+            "bridge-in",
+            self.outside.clone(),
+            self.inside.clone(),
+            inbound_scrubber,
+            self.config.frame_buffer_size,
+            self.stats.clone(),
+            |stats| &stats.inbound,
+        )?;
+
+        // Whichever direction fails first ends the bridge
+        let (tx, rx) = std::sync::mpsc::channel();
+        for handle in [outbound, inbound] {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let result = handle
+                    .join()
+                    .unwrap_or_else(|_| Err(io::Error::other("bridge thread panicked")));
+                let _ = tx.send(result);
+            });
+        }
+        rx.recv()
+            .unwrap_or_else(|_| Err(io::Error::other("bridge threads exited")))
+    }
+}
+
+fn spawn_direction(
+    name: &str,
+    rx: Arc<PacketSocket>,
+    tx: Arc<PacketSocket>,
+    mut scrubber: Scrubber,
+    buffer_size: usize,
+    stats: Arc<BridgeStats>,
+    select: fn(&BridgeStats) -> &ScrubStats,
+) -> io::Result<thread::JoinHandle<io::Result<()>>> {
+    thread::Builder::new().name(name.to_string()).spawn(move || {
+        let stats = select(&stats);
+        // AF_PACKET does not tell which RX queue a frame came in on, so the
+        // whole interface counts as one
+        let queue_stats = QueueStats::new(&rx.name);
+        let mut buf = vec![0u8; buffer_size];
+
+        loop {
+            let n = rx.recv(&mut buf)?;
+            let frame = &buf[..n];
+            stats.packets.fetch_add(1, Ordering::Relaxed);
+
+            let segment = parse_ethernet_frame(frame);
+            let rewritten = segment.as_ref().and_then(|segment| {
+                stats.tcp_segments.fetch_add(1, Ordering::Relaxed);
+                scrubber.scrub(frame, segment)
+            });
+            queue_stats.record(frame, segment.as_ref(), rewritten.is_some());
+
+            let out = match &rewritten {
+                Some(new_frame) => {
+                    stats.rewritten.fetch_add(1, Ordering::Relaxed);
+                    debug!("{} -> {}: rewrote TCP options", rx.name, tx.name);
+                    new_frame.as_slice()
+                }
+                None => frame,
+            };
+
+            if let Err(e) = tx.send(out) {
+                // Oversized (GRO) frames and transient queue overflows are
+                // dropped, just like a switch would
+                stats.send_errors.fetch_add(1, Ordering::Relaxed);
+                warn!("{} -> {}: send failed ({} bytes): {}", rx.name, tx.name, out.len(), e);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::TimestampAction;
+    use crate::scrub::EchoPolicy;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::process::Command;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    /// Client, bridge and server namespaces, deleted on drop
+    struct Namespaces {
+        client: String,
+        bridge: String,
+        server: String,
+    }
+
+    impl Namespaces {
+        /// client h0 <-> bi [bridge] bo <-> p0 server
+        fn create(tag: &str) -> Self {
+            let prefix = format!("tsx{}{}", std::process::id(), tag);
+            let namespaces = Self {
+                client: format!("{}c", prefix),
+                bridge: format!("{}b", prefix),
+                server: format!("{}s", prefix),
+            };
+            assert!(ip(&["netns", "add", &namespaces.client]), "creating network namespaces needs root and the ip tool");
+            let (c, b, s) = (&namespaces.client[..], &namespaces.bridge[..], &namespaces.server[..]);
+            let steps: &[&[&str]] = &[
+                &["netns", "add", b],
+                &["netns", "add", s],
+                &["link", "add", "h0", "netns", c, "type", "veth", "peer", "name", "bi", "netns", b],
+                &["link", "add", "bo", "netns", b, "type", "veth", "peer", "name", "p0", "netns", s],
+                &["-n", c, "addr", "add", "10.213.0.1/24", "dev", "h0"],
+                &["-n", s, "addr", "add", "10.213.0.2/24", "dev", "p0"],
+                &["-n", c, "link", "set", "h0", "up"],
+                &["-n", b, "link", "set", "bi", "up"],
+                &["-n", b, "link", "set", "bo", "up"],
+                &["-n", s, "link", "set", "p0", "up"],
+            ];
+            for step in steps {
+                assert!(ip(step), "ip {} failed", step.join(" "));
+            }
+            namespaces
+        }
+    }
+
+    impl Drop for Namespaces {
+        fn drop(&mut self) {
+            for namespace in [&self.client, &self.bridge, &self.server] {
+                ip(&["netns", "del", namespace]);
+            }
+        }
+    }
+
+    fn ip(args: &[&str]) -> bool {
+        Command::new("ip")
+            .args(args)
+            .stderr(std::process::Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    /// Move the calling thread into a named network namespace
+    fn enter(namespace: &str) {
+        let file = File::open(format!("/var/run/netns/{}", namespace)).unwrap();
+        assert_eq!(unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) }, 0, "{}", io::Error::last_os_error());
+    }
+
+    fn timestamps_negotiated(stream: &TcpStream) -> bool {
+        /// tcpi_options bit (linux/tcp.h)
+        const TCPI_OPT_TIMESTAMPS: u8 = 1;
+
+        let mut info: libc::tcp_info = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+        let rc = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                &mut info as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(rc, 0);
+        info.tcpi_options & TCPI_OPT_TIMESTAMPS != 0
+    }
+
+    /// Play ping-pong between two kernel stacks through the bridge, with
+    /// the client's TSvals randomized, for `duration`
+    fn survive_rewriting(tag: &str, echo: EchoPolicy, duration: Duration) {
+        let namespaces = Namespaces::create(tag);
+
+        let (opened, stats) = mpsc::channel();
+        let bridge_namespace = namespaces.bridge.clone();
+        thread::spawn(move || {
+            enter(&bridge_namespace);
+            let bridge = Bridge::open(BridgeConfig {
+                inside: "bi".into(),
+                outside: "bo".into(),
+                policy: ScrubPolicy {
+                    timestamps: TimestampAction::Randomize,
+                    echo,
+                    ..ScrubPolicy::default()
+                },
+                entropy: EntropyConfig::Seeded(7),
+                frame_buffer_size: 65536,
+            })
+            .unwrap();
+            opened.send(bridge.stats()).unwrap();
+            // Ends when the namespaces go and take the interfaces along
+            let _ = bridge.run();
+        });
+        let stats = stats.recv().unwrap();
+
+        let (listening, listened) = mpsc::channel();
+        let server_namespace = namespaces.server.clone();
+        thread::spawn(move || {
+            enter(&server_namespace);
+            let listener = TcpListener::bind("10.213.0.2:0").unwrap();
+            listening.send(listener.local_addr().unwrap()).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let _ = io::copy(&mut stream, &mut writer);
+        });
+        let server = listened.recv().unwrap();
+
+        let client_namespace = namespaces.client.clone();
+        let round_trips = thread::spawn(move || {
+            enter(&client_namespace);
+            let mut stream = TcpStream::connect_timeout(&server, Duration::from_secs(5)).unwrap();
+            assert!(timestamps_negotiated(&stream));
+            // A stalled connection shows up as a timed out read
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            stream.set_nodelay(true).unwrap();
+
+            let start = Instant::now();
+            let mut round_trips = 0u64;
+            let mut echo = [0u8; 100];
+            while start.elapsed() < duration {
+                let message = [round_trips as u8; 100];
+                stream.write_all(&message).unwrap();
+                stream.read_exact(&mut echo).unwrap();
+                assert_eq!(echo, message);
+                round_trips += 1;
+                thread::sleep(Duration::from_millis(10));
+            }
+            round_trips
+        })
+        .join()
+        .unwrap();
+
+        assert!(round_trips > 0);
+        assert!(stats.outbound.rewritten.load(Ordering::Relaxed) > round_trips);
+        assert!(stats.inbound.rewritten.load(Ordering::Relaxed) > round_trips);
+    }
+
+    /// Kernel stacks on both sides, so PAWS and RTT estimation see the
+    /// rewritten timestamps as they would on the wire. Needs root; runs one
+    /// connection per policy for TCPSTRIP_NETNS_SECS (10 by default), so a
+    /// soak test is
+    ///
+    /// ```text
+    /// sudo TCPSTRIP_NETNS_SECS=14400 cargo test --release bridge::tests -- --ignored
+    /// ```
+    #[test]
+    #[ignore = "needs root and the ip tool"]
+    fn test_connections_survive_rewriting() {
+        let secs = std::env::var("TCPSTRIP_NETNS_SECS").ok().and_then(|secs| secs.parse().ok()).unwrap_or(10);
+        survive_rewriting("t", EchoPolicy::Translated, Duration::from_secs(secs));
+        survive_rewriting("z", EchoPolicy::Zero, Duration::from_secs(secs));
+    }
+}
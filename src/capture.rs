@@ -0,0 +1,564 @@
+//! Filtered AF_PACKET capture sockets (Linux only)
+//!
+//! The egress verifier and the backend option watcher both look at a
+//! trickle of packets on an interface that otherwise carries the full
+//! trading load. Each attaches a small classic BPF program so that only
+//! the packets it wants are ever copied to userspace.
+//!
+//! Alongside the proxy, both are further confined to the routes' traffic:
+//! once the routes are known, `Captures` appends a check of the listen
+//! ports and target addresses to every capture socket's program (`Scope`)
+//! and attaches it again, and does so again whenever a target's name
+//! resolves elsewhere. The programs in force are served on the admin
+//! listener (`GET /capture`) in `tcpdump -ddd` form:
+//!
+//! ```text
+//! ...                 ; the socket's own checks, then instead of accepting:
+//! ldh [12]            ; IPv4 (unfragmented) or IPv6 carrying TCP
+//! ldh [tcp + 0]       ; jeq #listen_port, accept ...
+//! ldh [tcp + 2]       ; jeq #listen_port, accept ...
+//! ld  [ip dst]        ; jne #target, next ; ldh [tcp + 2] ; jeq #port, accept
+//! ld  [ip src]        ; jne #target, next ; ldh [tcp + 0] ; jeq #port, accept
+//! ret #0
+//! ```
+
+use std::collections::BTreeSet;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+
+use tracing::{info, warn};
+
+use crate::balance::Pool;
+
+// Classic BPF opcodes and ancillary data offsets (linux/filter.h)
+pub const LD_W_ABS: u16 = 0x20;
+pub const LD_H_ABS: u16 = 0x28;
+pub const LD_B_ABS: u16 = 0x30;
+pub const LD_H_IND: u16 = 0x48;
+pub const LD_B_IND: u16 = 0x50;
+pub const LDX_B_MSH: u16 = 0xb1;
+pub const AND_K: u16 = 0x54;
+pub const MOD_K: u16 = 0x94;
+pub const JA: u16 = 0x05;
+pub const JEQ_K: u16 = 0x15;
+pub const JSET_K: u16 = 0x45;
+pub const RET_K: u16 = 0x06;
+
+pub const SKF_AD_OFF: u32 = (-0x1000i32) as u32;
+pub const SKF_AD_PKTTYPE: u32 = 4;
+pub const SKF_AD_RANDOM: u32 = 56;
+
+pub fn insn(code: u16, jt: u8, jf: u8, k: u32) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+/// Raw socket on `interface` receiving only what `filter` accepts
+pub fn open(interface: &str, filter: &[libc::sock_filter]) -> io::Result<OwnedFd> {
+    let c_name = CString::new(interface)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"))?;
+    let ifindex = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+    if ifindex == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let protocol = (libc::ETH_P_ALL as u16).to_be();
+    let raw = unsafe {
+        libc::socket(
+            libc::AF_PACKET,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            protocol as libc::c_int,
+        )
+    };
+    if raw < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+
+    // Attach the filter before binding so no unfiltered packets queue up
+    attach(&fd, filter)?;
+
+    let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as libc::c_ushort;
+    addr.sll_protocol = protocol;
+    addr.sll_ifindex = ifindex as libc::c_int;
+    let rc = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const _ as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+/// Replace the program of capture socket `fd` with `filter`
+fn attach(fd: &OwnedFd, filter: &[libc::sock_filter]) -> io::Result<()> {
+    let prog = libc::sock_fprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_ptr() as *mut libc::sock_filter,
+    };
+    let rc = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_FILTER,
+            &prog as *const _ as *const libc::c_void,
+            mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Block until the next accepted frame arrives
+pub fn recv(fd: &OwnedFd, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        let n = unsafe { libc::recv(fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+        if n >= 0 {
+            return Ok(n as usize);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// The traffic of the configured routes: TCP segments from or to one of
+/// their listen ports, or exchanged with one of their targets
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    pub listen_ports: BTreeSet<u16>,
+    /// Targets that stay put (SNI routes, --mirror, --via)
+    pub targets: BTreeSet<SocketAddr>,
+    /// Targets that follow DNS, with their backups and fallback addresses
+    pub pools: Vec<Arc<Pool>>,
+}
+
+impl Scope {
+    /// Every target address as of now
+    pub fn addrs(&self) -> BTreeSet<SocketAddr> {
+        let mut addrs = self.targets.clone();
+        for pool in &self.pools {
+            for member in pool.members() {
+                addrs.insert(pool.addr(member));
+                addrs.extend(pool.fallback(member));
+            }
+        }
+        addrs
+            .into_iter()
+            .map(|addr| SocketAddr::new(addr.ip().to_canonical(), addr.port()))
+            .collect()
+    }
+
+    /// `filter` accepting only the segments in scope
+    ///
+    /// The accepting `ret` of `filter` (the one with a nonzero length) is
+    /// turned into a jump to the scope check appended after it, which
+    /// accepts with the same length. Jumps within `filter` are unaffected.
+    pub fn confine(&self, filter: &[libc::sock_filter]) -> Vec<libc::sock_filter> {
+        let Some(accept) = filter.iter().position(|insn| insn.code == RET_K && insn.k != 0) else {
+            return filter.to_vec();
+        };
+        let mut program = filter.to_vec();
+        program[accept] = insn(JA, 0, 0, (filter.len() - accept - 1) as u32);
+        program.extend(self.check(filter[accept].k));
+        program
+    }
+
+    /// Check a segment against the scope, accepting `len` bytes of it
+    fn check(&self, len: u32) -> Vec<libc::sock_filter> {
+        let addrs = self.addrs();
+        let mut check = Check::default();
+
+        check.push(insn(LD_H_ABS, 0, 0, 12));
+        check.push(insn(JEQ_K, 0, 1, libc::ETH_P_IPV6 as u32));
+        check.goto(Label::Ipv6);
+        check.push(insn(JEQ_K, 1, 0, libc::ETH_P_IP as u32));
+        check.goto(Label::Drop);
+
+        check.push(insn(LD_B_ABS, 0, 0, 14 + 9)); // IPv4 protocol
+        check.push(insn(JEQ_K, 1, 0, libc::IPPROTO_TCP as u32));
+        check.goto(Label::Drop);
+        check.push(insn(LD_H_ABS, 0, 0, 14 + 6)); // later fragments have no ports
+        check.push(insn(JSET_K, 0, 1, 0x1fff));
+        check.goto(Label::Drop);
+        check.push(insn(LDX_B_MSH, 0, 0, 14)); // X = IPv4 header length
+        check.ports(&self.listen_ports, insn(LD_H_IND, 0, 0, 14));
+        check.ports(&self.listen_ports, insn(LD_H_IND, 0, 0, 14 + 2));
+        for addr in &addrs {
+            if let IpAddr::V4(ip) = addr.ip() {
+                check.endpoint(&[(14 + 16, u32::from(ip))], insn(LD_H_IND, 0, 0, 14 + 2), addr.port());
+                check.endpoint(&[(14 + 12, u32::from(ip))], insn(LD_H_IND, 0, 0, 14), addr.port());
+            }
+        }
+        check.goto(Label::Drop);
+
+        check.label(Label::Ipv6);
+        check.push(insn(LD_B_ABS, 0, 0, 14 + 6)); // IPv6 next header
+        check.push(insn(JEQ_K, 1, 0, libc::IPPROTO_TCP as u32));
+        check.goto(Label::Drop);
+        check.ports(&self.listen_ports, insn(LD_H_ABS, 0, 0, 14 + 40));
+        check.ports(&self.listen_ports, insn(LD_H_ABS, 0, 0, 14 + 40 + 2));
+        for addr in &addrs {
+            if let IpAddr::V6(ip) = addr.ip() {
+                let words = ip.octets().chunks(4).map(|word| u32::from_be_bytes(word.try_into().unwrap())).collect::<Vec<_>>();
+                let at = |base: u32| -> Vec<(u32, u32)> { (0..4).map(|i| (base + 4 * i, words[i as usize])).collect() };
+                check.endpoint(&at(14 + 24), insn(LD_H_ABS, 0, 0, 14 + 40 + 2), addr.port());
+                check.endpoint(&at(14 + 8), insn(LD_H_ABS, 0, 0, 14 + 40), addr.port());
+            }
+        }
+        check.goto(Label::Drop);
+
+        check.label(Label::Accept);
+        check.push(insn(RET_K, 0, 0, len));
+        check.label(Label::Drop);
+        check.push(insn(RET_K, 0, 0, 0));
+        check.resolve()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Label {
+    Ipv6,
+    Accept,
+    Drop,
+}
+
+/// A scope check under construction; long jumps go through `ja`, whose
+/// offset is not limited to 255 instructions
+#[derive(Default)]
+struct Check {
+    insns: Vec<libc::sock_filter>,
+    labels: Vec<(Label, usize)>,
+    gotos: Vec<(usize, Label)>,
+}
+
+impl Check {
+    fn push(&mut self, insn: libc::sock_filter) {
+        self.insns.push(insn);
+    }
+
+    fn goto(&mut self, label: Label) {
+        self.gotos.push((self.insns.len(), label));
+        self.push(insn(JA, 0, 0, 0));
+    }
+
+    fn label(&mut self, label: Label) {
+        self.labels.push((label, self.insns.len()));
+    }
+
+    /// Accept if the port `load` reads is one of `ports`
+    fn ports(&mut self, ports: &BTreeSet<u16>, load: libc::sock_filter) {
+        if ports.is_empty() {
+            return;
+        }
+        self.push(load);
+        for &port in ports {
+            self.push(insn(JEQ_K, 0, 1, port as u32));
+            self.goto(Label::Accept);
+        }
+    }
+
+    /// Accept if the address words at `words` (offset, value) and the port
+    /// `load` reads match
+    fn endpoint(&mut self, words: &[(u32, u32)], load: libc::sock_filter, port: u16) {
+        // Each word is a load and a compare; then the port and the jump
+        let mut left = 2 * words.len() + 3;
+        for &(offset, value) in words {
+            self.push(insn(LD_W_ABS, 0, 0, offset));
+            left -= 2;
+            self.push(insn(JEQ_K, 0, left as u8, value));
+        }
+        self.push(load);
+        self.push(insn(JEQ_K, 0, 1, port as u32));
+        self.goto(Label::Accept);
+    }
+
+    fn resolve(mut self) -> Vec<libc::sock_filter> {
+        for &(at, label) in &self.gotos {
+            let (_, target) = self.labels.iter().find(|(name, _)| *name == label).expect("undefined label");
+            self.insns[at].k = (target - at - 1) as u32;
+        }
+        self.insns
+    }
+}
+
+/// A capture socket known to `Captures`
+struct Capture {
+    /// What the socket is for, e.g. `verify-egress`
+    name: String,
+    interface: String,
+    fd: Weak<OwnedFd>,
+    /// The socket's own program, before confining
+    filter: Vec<libc::sock_filter>,
+    /// The program attached now
+    attached: Vec<libc::sock_filter>,
+}
+
+#[derive(Default)]
+struct State {
+    /// None until the routes are known
+    scope: Option<Scope>,
+    sockets: Vec<Capture>,
+}
+
+/// The capture sockets of the process and the routes' traffic they are
+/// confined to
+#[derive(Default)]
+pub struct Captures {
+    state: Mutex<State>,
+}
+
+/// The process-wide capture sockets
+pub fn captures() -> &'static Captures {
+    static CAPTURES: OnceLock<Captures> = OnceLock::new();
+    CAPTURES.get_or_init(Captures::default)
+}
+
+impl Captures {
+    /// Open a capture socket named `name` on `interface` with `filter`,
+    /// confined to the routes' traffic once they are known
+    pub fn open(&self, name: &str, interface: &str, filter: Vec<libc::sock_filter>) -> io::Result<Arc<OwnedFd>> {
+        let mut state = self.state.lock().unwrap();
+        let attached = match &state.scope {
+            Some(scope) => scope.confine(&filter),
+            None => filter.clone(),
+        };
+        let fd = Arc::new(open(interface, &attached)?);
+        state.sockets.push(Capture {
+            name: name.to_string(),
+            interface: interface.to_string(),
+            fd: Arc::downgrade(&fd),
+            filter,
+            attached,
+        });
+        Ok(fd)
+    }
+
+    /// Confine every capture socket to `scope`
+    pub fn confine(&self, scope: Scope) {
+        let mut state = self.state.lock().unwrap();
+        info!(
+            "Confining packet capture to {} listen port(s) and {} target address(es)",
+            scope.listen_ports.len(),
+            scope.addrs().len()
+        );
+        state.scope = Some(scope);
+        state.attach();
+    }
+
+    /// Check the scope's targets again, for when one resolved elsewhere
+    pub fn refresh(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.attach();
+    }
+
+    /// The program attached to each open capture socket
+    pub fn render(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut out = String::new();
+        for capture in state.sockets.iter().filter(|capture| capture.fd.strong_count() > 0) {
+            out.push_str(&format!("# {} on {}\n{}\n", capture.name, capture.interface, render(&capture.attached)));
+        }
+        out
+    }
+}
+
+impl State {
+    /// Attach the confined program to every socket whose program changed
+    fn attach(&mut self) {
+        let Some(scope) = &self.scope else {
+            return;
+        };
+        self.sockets.retain(|capture| capture.fd.strong_count() > 0);
+        for capture in &mut self.sockets {
+            let program = scope.confine(&capture.filter);
+            if program.iter().map(key).eq(capture.attached.iter().map(key)) {
+                continue;
+            }
+            let Some(fd) = capture.fd.upgrade() else {
+                continue;
+            };
+            match attach(&fd, &program) {
+                Ok(()) => capture.attached = program,
+                Err(e) => warn!("Could not confine {} capture on {}: {}", capture.name, capture.interface, e),
+            }
+        }
+    }
+}
+
+fn key(insn: &libc::sock_filter) -> (u16, u8, u8, u32) {
+    (insn.code, insn.jt, insn.jf, insn.k)
+}
+
+/// `program` as `tcpdump -ddd` prints one: its length, then one
+/// `code jt jf k` line per instruction
+pub fn render(program: &[libc::sock_filter]) -> String {
+    let mut out = format!("{}\n", program.len());
+    for insn in program {
+        out.push_str(&format!("{} {} {} {}\n", insn.code, insn.jt, insn.jf, insn.k));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `program` on `frame` the way the kernel would, with `pkttype` as
+    /// the packet type; the number of bytes accepted
+    fn run(program: &[libc::sock_filter], frame: &[u8], pkttype: u32) -> u32 {
+        let load = |at: u32, len: usize| -> Option<u32> {
+            let bytes = frame.get(at as usize..at as usize + len)?;
+            Some(bytes.iter().fold(0, |word, &byte| word << 8 | byte as u32))
+        };
+        let (mut a, mut x, mut pc) = (0u32, 0u32, 0usize);
+        loop {
+            let insn = program[pc];
+            pc += 1;
+            let loaded = match insn.code {
+                LD_W_ABS if insn.k == SKF_AD_OFF + SKF_AD_PKTTYPE => Some(pkttype),
+                LD_W_ABS => load(insn.k, 4),
+                LD_H_ABS => load(insn.k, 2),
+                LD_B_ABS => load(insn.k, 1),
+                LD_H_IND => load(x + insn.k, 2),
+                LD_B_IND => load(x + insn.k, 1),
+                LDX_B_MSH => {
+                    x = 4 * (load(insn.k, 1).unwrap_or(0) & 0xf);
+                    continue;
+                }
+                AND_K => Some(a & insn.k),
+                JA => {
+                    pc += insn.k as usize;
+                    continue;
+                }
+                JEQ_K | JSET_K => {
+                    let taken = match insn.code {
+                        JEQ_K => a == insn.k,
+                        _ => a & insn.k != 0,
+                    };
+                    pc += if taken { insn.jt } else { insn.jf } as usize;
+                    continue;
+                }
+                RET_K => return insn.k,
+                code => panic!("opcode {:#x} not interpreted", code),
+            };
+            // Loads past the end of the packet drop it
+            match loaded {
+                Some(value) => a = value,
+                None => return 0,
+            }
+        }
+    }
+
+    /// An Ethernet + IPv4 + TCP frame
+    fn frame(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+        let (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) = (src.ip(), dst.ip()) else {
+            return frame6(src, dst);
+        };
+        let mut frame = vec![0u8; 14];
+        frame[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+        frame.extend_from_slice(&[0x45, 0, 0, 40, 0, 1, 0x40, 0, 64, 6, 0, 0]);
+        frame.extend_from_slice(&src_ip.octets());
+        frame.extend_from_slice(&dst_ip.octets());
+        frame.extend_from_slice(&src.port().to_be_bytes());
+        frame.extend_from_slice(&dst.port().to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0x50, 0x10, 0xff, 0xff, 0, 0, 0, 0]);
+        frame
+    }
+
+    fn frame6(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+        let (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) = (src.ip(), dst.ip()) else {
+            panic!("mixed address families");
+        };
+        let mut frame = vec![0u8; 14];
+        frame[12..14].copy_from_slice(&0x86ddu16.to_be_bytes());
+        frame.extend_from_slice(&[0x60, 0, 0, 0, 0, 20, 6, 64]);
+        frame.extend_from_slice(&src_ip.octets());
+        frame.extend_from_slice(&dst_ip.octets());
+        frame.extend_from_slice(&src.port().to_be_bytes());
+        frame.extend_from_slice(&dst.port().to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0x50, 0x10, 0xff, 0xff, 0, 0, 0, 0]);
+        frame
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    /// Outgoing packets only, 128 bytes of them
+    fn outgoing() -> Vec<libc::sock_filter> {
+        vec![
+            insn(LD_W_ABS, 0, 0, SKF_AD_OFF + SKF_AD_PKTTYPE),
+            insn(JEQ_K, 0, 1, libc::PACKET_OUTGOING as u32),
+            insn(RET_K, 0, 0, 128),
+            insn(RET_K, 0, 0, 0),
+        ]
+    }
+
+    #[test]
+    fn test_confined_to_routes() {
+        let scope = Scope {
+            listen_ports: [9999].into(),
+            targets: [addr("10.1.0.5:9000"), addr("[2001:db8::5]:9000")].into(),
+            pools: vec![Arc::new(Pool::new(&[addr("10.1.0.6:443")]))],
+        };
+        let program = scope.confine(&outgoing());
+        let out = libc::PACKET_OUTGOING as u32;
+        let accepted = |src: &str, dst: &str| run(&program, &frame(addr(src), addr(dst)), out);
+
+        // Upstream legs, either way, and the client legs of the listener
+        assert_eq!(accepted("10.0.0.7:51234", "10.1.0.5:9000"), 128);
+        assert_eq!(accepted("10.1.0.5:9000", "10.0.0.7:51234"), 128);
+        assert_eq!(accepted("10.0.0.7:51234", "10.1.0.6:443"), 128);
+        assert_eq!(accepted("10.0.0.7:9999", "192.0.2.1:40000"), 128);
+        assert_eq!(accepted("[2001:db8::7]:51234", "[2001:db8::5]:9000"), 128);
+        assert_eq!(accepted("[2001:db8::9]:40000", "[2001:db8::7]:9999"), 128);
+
+        // A target's address on another port, or another host on its port
+        assert_eq!(accepted("10.0.0.7:51234", "10.1.0.5:22"), 0);
+        assert_eq!(accepted("10.0.0.7:51234", "10.1.0.9:9000"), 0);
+        assert_eq!(accepted("[2001:db8::7]:51234", "[2001:db8::6]:9000"), 0);
+        assert_eq!(accepted("[2001:db8::5]:9001", "[2001:db8::7]:51234"), 0);
+
+        // The socket's own checks still come first
+        let upstream = frame(addr("10.0.0.7:51234"), addr("10.1.0.5:9000"));
+        assert_eq!(run(&program, &upstream, libc::PACKET_HOST as u32), 0);
+
+        // Later fragments carry no ports to match
+        let mut fragment = upstream.clone();
+        fragment[20..22].copy_from_slice(&0x0010u16.to_be_bytes());
+        assert_eq!(run(&program, &fragment, out), 0);
+    }
+
+    #[test]
+    fn test_long_scopes_jump_past_255() {
+        let targets: BTreeSet<SocketAddr> = (1..=64).map(|i| addr(&format!("[2001:db8::{:x}]:9000", i))).collect();
+        let scope = Scope {
+            listen_ports: [9999].into(),
+            targets,
+            ..Default::default()
+        };
+        let program = scope.confine(&outgoing());
+        assert!(program.len() > 255 * 2);
+        let out = libc::PACKET_OUTGOING as u32;
+        assert_eq!(run(&program, &frame(addr("[2001:db8::ff]:1"), addr("[2001:db8::40]:9000")), out), 128);
+        assert_eq!(run(&program, &frame(addr("10.0.0.7:9999"), addr("10.0.0.8:1")), out), 128);
+        assert_eq!(run(&program, &frame(addr("[2001:db8::ff]:1"), addr("[2001:db8::41]:9000")), out), 0);
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(render(&outgoing()), "4\n32 0 0 4294963204\n21 0 1 4\n6 0 0 128\n6 0 0 0\n");
+    }
+}
@@ -0,0 +1,370 @@
+//! Registry of live proxied connections
+//!
+//! Every accepted connection is registered here for as long as its task
+//! runs, so the admin listener can list them, rank them by the CPU time
+//! their task has burnt and abort one that misbehaves.
+//!
+//! CPU time is measured per poll: `CpuTimed` reads the thread CPU clock
+//! before and after polling the connection's future and charges the
+//! difference to the connection. That costs two clock reads per wakeup, so
+//! it is only enabled on request (`--cpu-accounting`).
+//!
+//! Once a connection is routed it gets a fingerprint: a hash of the client
+//! address, the backend and the accept time that the proxy logs next to
+//! both legs' addresses. Searching for it finds every record of the
+//! connection, and the logged addresses join those records with the
+//! application logs on either side.
+//!
+//! The listing, CPU accounting and kill switch exist only for the admin
+//! listener, so without the `admin` feature they are not compiled at all:
+//! connections get an `unlisted` guard that carries the fingerprint and
+//! nothing else, and code on the connection path that tried to register or
+//! time a connection would not build.
+
+#[cfg(feature = "admin")]
+use std::collections::BTreeMap;
+use std::fmt;
+#[cfg(feature = "admin")]
+use std::fmt::Write;
+#[cfg(feature = "admin")]
+use std::future::Future;
+use std::net::SocketAddr;
+#[cfg(feature = "admin")]
+use std::pin::Pin;
+#[cfg(feature = "admin")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "admin")]
+use std::sync::Mutex;
+use std::sync::{Arc, OnceLock};
+#[cfg(feature = "admin")]
+use std::task::{Context, Poll};
+#[cfg(feature = "admin")]
+use std::time::Duration;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "admin")]
+use tokio::task::AbortHandle;
+
+/// Stable identifier of one proxied connection
+///
+/// FNV-1a (64 bit) of `<client>|<backend>|<accept time in ms since the
+/// epoch>`, so it can be recomputed from the logged fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Fingerprint(u64);
+
+impl Fingerprint {
+    pub fn new(client: SocketAddr, backend: SocketAddr, started: SystemTime) -> Self {
+        let started_ms = started.duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+        let input = format!("{}|{}|{}", client, backend, started_ms);
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in input.bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        Self(hash)
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// A live connection as seen by the registry
+#[derive(Debug)]
+pub struct Connection {
+    pub id: u64,
+    pub client: SocketAddr,
+    pub started: Instant,
+    /// Wall clock time of the accept, for the fingerprint
+    pub accepted_at: SystemTime,
+    #[cfg(feature = "admin")]
+    cpu_ns: AtomicU64,
+    #[cfg(feature = "admin")]
+    abort: OnceLock<AbortHandle>,
+    fingerprint: OnceLock<Fingerprint>,
+}
+
+impl Connection {
+    fn new(id: u64, client: SocketAddr) -> Arc<Self> {
+        Arc::new(Connection {
+            id,
+            client,
+            started: Instant::now(),
+            accepted_at: SystemTime::now(),
+            #[cfg(feature = "admin")]
+            cpu_ns: AtomicU64::new(0),
+            #[cfg(feature = "admin")]
+            abort: OnceLock::new(),
+            fingerprint: OnceLock::new(),
+        })
+    }
+
+    /// Record the backend the connection was routed to
+    pub fn set_backend(&self, backend: SocketAddr) -> Fingerprint {
+        *self.fingerprint.get_or_init(|| Fingerprint::new(self.client, backend, self.accepted_at))
+    }
+
+    /// Set once the connection has been routed
+    pub fn fingerprint(&self) -> Option<Fingerprint> {
+        self.fingerprint.get().copied()
+    }
+
+    /// CPU time charged to this connection's task so far
+    #[cfg(feature = "admin")]
+    pub fn cpu_time(&self) -> Duration {
+        Duration::from_nanos(self.cpu_ns.load(Ordering::Relaxed))
+    }
+
+    /// Let the registry abort the task running this connection
+    #[cfg(feature = "admin")]
+    pub fn set_abort_handle(&self, handle: AbortHandle) {
+        let _ = self.abort.set(handle);
+    }
+}
+
+/// Registry of all live connections in the process
+#[cfg(feature = "admin")]
+#[derive(Debug, Default)]
+pub struct Registry {
+    connections: Mutex<BTreeMap<u64, Arc<Connection>>>,
+}
+
+#[cfg(feature = "admin")]
+impl Registry {
+    /// Register a connection; it stays listed until the guard is dropped
+    pub fn register(&'static self, id: u64, client: SocketAddr) -> ConnectionGuard {
+        let connection = Connection::new(id, client);
+        self.connections.lock().unwrap().insert(id, connection.clone());
+        ConnectionGuard {
+            registry: Some(self),
+            connection,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The `n` connections that used the most CPU, heaviest first
+    pub fn top_cpu(&self, n: usize) -> Vec<Arc<Connection>> {
+        let mut connections: Vec<_> = self.connections.lock().unwrap().values().cloned().collect();
+        connections.sort_by_key(|c| std::cmp::Reverse(c.cpu_ns.load(Ordering::Relaxed)));
+        connections.truncate(n);
+        connections
+    }
+
+    /// Abort the task running connection `id`, closing both legs
+    ///
+    /// Returns false if there is no such connection or it cannot be aborted.
+    pub fn kill(&self, id: u64) -> bool {
+        let connections = self.connections.lock().unwrap();
+        match connections.get(&id).and_then(|c| c.abort.get()) {
+            Some(handle) => {
+                handle.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Plain-text table of the top CPU consumers for the admin listener
+    pub fn render_top(&self, n: usize) -> String {
+        let mut out = String::from("id\tclient\tage_s\tcpu_ms\tfingerprint\n");
+        for c in self.top_cpu(n) {
+            let _ = writeln!(
+                out,
+                "{}\t{}\t{}\t{:.3}\t{}",
+                c.id,
+                c.client,
+                c.started.elapsed().as_secs(),
+                c.cpu_time().as_secs_f64() * 1000.0,
+                c.fingerprint().map_or("-".to_string(), |f| f.to_string())
+            );
+        }
+        out
+    }
+}
+
+/// Removes a connection from the registry when its task ends (or is aborted)
+pub struct ConnectionGuard {
+    #[cfg(feature = "admin")]
+    registry: Option<&'static Registry>,
+    connection: Arc<Connection>,
+}
+
+impl ConnectionGuard {
+    /// A connection no registry lists, which cannot be killed
+    pub fn unlisted(id: u64, client: SocketAddr) -> Self {
+        Self {
+            #[cfg(feature = "admin")]
+            registry: None,
+            connection: Connection::new(id, client),
+        }
+    }
+
+    pub fn connection(&self) -> &Arc<Connection> {
+        &self.connection
+    }
+}
+
+#[cfg(feature = "admin")]
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Some(registry) = self.registry {
+            registry.connections.lock().unwrap().remove(&self.connection.id);
+        }
+    }
+}
+
+/// The global registry
+#[cfg(feature = "admin")]
+pub fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(Registry::default)
+}
+
+/// Future wrapper charging the CPU time of every poll to a connection
+#[cfg(feature = "admin")]
+pub struct CpuTimed<F> {
+    inner: Pin<Box<F>>,
+    connection: Arc<Connection>,
+}
+
+#[cfg(feature = "admin")]
+impl<F: Future> CpuTimed<F> {
+    pub fn new(connection: Arc<Connection>, inner: F) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            connection,
+        }
+    }
+}
+
+#[cfg(feature = "admin")]
+impl<F: Future> Future for CpuTimed<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // A poll runs start to finish on one worker thread, so the thread
+        // clock delta is exactly what this connection consumed
+        let before = thread_cpu_ns();
+        let result = self.inner.as_mut().poll(cx);
+        let spent = thread_cpu_ns().saturating_sub(before);
+        self.connection.cpu_ns.fetch_add(spent, Ordering::Relaxed);
+        result
+    }
+}
+
+/// CPU time consumed by the calling thread
+#[cfg(all(feature = "admin", unix))]
+fn thread_cpu_ns() -> u64 {
+    let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+    if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) } != 0 {
+        return 0;
+    }
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// No per-thread CPU clock here; connections are listed with zero CPU time
+#[cfg(all(feature = "admin", not(unix)))]
+fn thread_cpu_ns() -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "admin")]
+    fn leaked_registry() -> &'static Registry {
+        Box::leak(Box::default())
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn test_guard_unregisters() {
+        let registry = leaked_registry();
+        let guard = registry.register(1, "10.0.0.7:51234".parse().unwrap());
+        let _other = registry.register(2, "10.0.0.8:40000".parse().unwrap());
+        assert_eq!(registry.len(), 2);
+        drop(guard);
+        assert_eq!(registry.len(), 1);
+        assert!(!registry.kill(1));
+    }
+
+    #[cfg(feature = "admin")]
+    #[tokio::test]
+    async fn test_cpu_time_is_charged_and_ranked() {
+        let registry = leaked_registry();
+        let idle = registry.register(1, "10.0.0.7:51234".parse().unwrap());
+        let busy = registry.register(2, "10.0.0.8:40000".parse().unwrap());
+
+        CpuTimed::new(busy.connection().clone(), async {
+            let deadline = Instant::now() + Duration::from_millis(20);
+            while Instant::now() < deadline {
+                std::hint::spin_loop();
+            }
+        })
+        .await;
+
+        assert!(busy.connection().cpu_time() >= Duration::from_millis(10));
+        let top = registry.top_cpu(1);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].id, 2);
+        assert!(registry.render_top(5).lines().nth(1).unwrap().starts_with("2\t10.0.0.8:40000\t"));
+        drop(idle);
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn test_fingerprint() {
+        let client = "10.0.0.7:51234".parse().unwrap();
+        let backend = "10.1.0.5:9000".parse().unwrap();
+        let started = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+
+        let fingerprint = Fingerprint::new(client, backend, started);
+        assert_eq!(fingerprint, Fingerprint::new(client, backend, started));
+        assert_ne!(fingerprint, Fingerprint::new(client, "10.1.0.6:9000".parse().unwrap(), started));
+        assert_eq!(fingerprint.to_string().len(), 16);
+
+        let registry = leaked_registry();
+        let guard = registry.register(1, client);
+        assert_eq!(guard.connection().fingerprint(), None);
+        let routed = guard.connection().set_backend(backend);
+        assert_eq!(guard.connection().fingerprint(), Some(routed));
+        assert!(registry.render_top(1).lines().nth(1).unwrap().ends_with(&routed.to_string()));
+    }
+
+    #[test]
+    fn test_unlisted_guard_routes() {
+        let guard = ConnectionGuard::unlisted(3, "10.0.0.7:51234".parse().unwrap());
+        assert_eq!(guard.connection().fingerprint(), None);
+        let routed = guard.connection().set_backend("10.1.0.5:9000".parse().unwrap());
+        assert_eq!(guard.connection().fingerprint(), Some(routed));
+        #[cfg(feature = "admin")]
+        assert!(registry().top_cpu(usize::MAX).iter().all(|c| !Arc::ptr_eq(c, guard.connection())));
+    }
+
+    #[cfg(feature = "admin")]
+    #[tokio::test]
+    async fn test_kill_aborts_task() {
+        let registry = leaked_registry();
+        let guard = registry.register(7, "10.0.0.7:51234".parse().unwrap());
+        let connection = guard.connection().clone();
+        let task = tokio::spawn(async move {
+            let _guard = guard;
+            std::future::pending::<()>().await
+        });
+        connection.set_abort_handle(task.abort_handle());
+
+        assert!(registry.kill(7));
+        assert!(task.await.unwrap_err().is_cancelled());
+        assert!(registry.is_empty());
+    }
+}
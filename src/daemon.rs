@@ -0,0 +1,139 @@
+//! Running in the background without a supervisor (Unix only)
+//!
+//! `--daemon` detaches the proxy the classic way: fork, `setsid` to leave
+//! the terminal's session, fork again so the daemon is no session leader
+//! and can never acquire a controlling terminal, then `/` as working
+//! directory and `/dev/null` as standard input and output (logs go to
+//! `--log-file`). The command that started it waits until the daemon is
+//! serving and exits with status 1 if it never got there, so scripts can
+//! tell a failed start from a good one.
+//!
+//! `--pidfile` writes the process id and holds an exclusive lock on the
+//! file for the life of the process: a second instance given the same file
+//! refuses to start instead of overwriting it, while a file left behind by
+//! a crashed one is simply reused. The file is removed on shutdown; once
+//! `--user` has dropped root, the proxy usually may no longer unlink it
+//! from a root-owned directory such as `/run`, so it is emptied instead
+//! and left for the next start to reuse.
+//!
+//! Both must happen before the runtime starts any threads.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+
+/// Write end of the pipe the starting command waits on, -1 once told
+static READY: AtomicI32 = AtomicI32::new(-1);
+
+/// The locked pidfile, removed on shutdown
+static PIDFILE: Mutex<Option<(PathBuf, File)>> = Mutex::new(None);
+
+fn check(rc: libc::c_int) -> io::Result<libc::c_int> {
+    match rc {
+        -1 => Err(io::Error::last_os_error()),
+        rc => Ok(rc),
+    }
+}
+
+/// Detach from the terminal; returns in the daemon only
+///
+/// The original process exits once `ready` is called, or with status 1 if
+/// the daemon exits first.
+pub fn daemonize() -> io::Result<()> {
+    let mut fds = [-1 as RawFd; 2];
+    check(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+    let [read, write] = fds;
+    if check(unsafe { libc::fork() })? != 0 {
+        unsafe { libc::close(write) };
+        // A byte once the daemon is serving, EOF if it died first
+        let mut byte = 0u8;
+        let n = loop {
+            let n = unsafe { libc::read(read, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+            if n >= 0 || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                break n;
+            }
+        };
+        std::process::exit(if n == 1 { 0 } else { 1 });
+    }
+    unsafe { libc::close(read) };
+    check(unsafe { libc::setsid() })?;
+    if check(unsafe { libc::fork() })? != 0 {
+        unsafe { libc::_exit(0) };
+    }
+
+    std::env::set_current_dir("/")?;
+    let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        check(unsafe { libc::dup2(null.as_raw_fd(), fd) })?;
+    }
+    // Helpers run later (nft, ip) must not hold the starting command up
+    check(unsafe { libc::fcntl(write, libc::F_SETFD, libc::FD_CLOEXEC) })?;
+    READY.store(write, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Let the command that started the daemon exit; does nothing otherwise
+pub fn ready() {
+    let fd = READY.swap(-1, Ordering::Relaxed);
+    if fd >= 0 {
+        unsafe {
+            libc::write(fd, b"1".as_ptr() as *const libc::c_void, 1);
+            libc::close(fd);
+        }
+    }
+}
+
+/// Write this process's id to `path` and keep the file locked
+///
+/// `path` should be absolute, as the daemon works from `/`.
+pub fn write_pidfile(path: &Path) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).create(true).truncate(false).open(path)?;
+    if let Err(e) = check(unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) }) {
+        if e.kind() != io::ErrorKind::WouldBlock {
+            return Err(e);
+        }
+        let pid = fs::read_to_string(path).unwrap_or_default();
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("already locked by a running instance (pid {})", pid.trim()),
+        ));
+    }
+    file.set_len(0)?;
+    writeln!(file, "{}", std::process::id())?;
+    *PIDFILE.lock().unwrap_or_else(|e| e.into_inner()) = Some((path.to_path_buf(), file));
+    Ok(())
+}
+
+/// Remove the pidfile, if one was written, or empty it if it cannot be
+/// removed so it no longer names this process
+pub fn remove_pidfile() {
+    if let Some((path, file)) = PIDFILE.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        if fs::remove_file(path).is_err() {
+            let _ = file.set_len(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pidfile() {
+        let path = std::env::temp_dir().join(format!("tcpstrip-{}.pid", std::process::id()));
+        fs::write(&path, "999999\n").unwrap();
+        // A stale file is taken over
+        write_pidfile(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+        // flock locks belong to the open file, so a second open conflicts
+        // even within one process
+        let e = write_pidfile(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert!(e.to_string().contains(&std::process::id().to_string()), "{}", e);
+        remove_pidfile();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,127 @@
+//! Shared loop for L3 packet datapaths
+//!
+//! Several platforms offer a way to pull whole IP packets out of the kernel
+//! and hand them back afterwards: TUN devices on Linux, divert sockets on
+//! macOS and FreeBSD (and NFQUEUE, should we grow that backend). They differ
+//! only in how packets are received and re-injected, so each one implements
+//! `PacketBackend` and shares the scrubbing loop below.
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+
+use tracing::{debug, warn};
+
+use crate::packet::{parse_ip_packet, ScrubStats};
+use crate::scrub::Scrubber;
+
+/// Kernel interface the bridge moves frames through
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Datapath {
+    /// Raw AF_PACKET sockets: works everywhere, one copy per frame
+    #[default]
+    AfPacket,
+    /// AF_XDP sockets fed by an XDP redirect program: zero-copy where the
+    /// driver supports it, one pinned worker per NIC queue
+    AfXdp,
+}
+
+impl fmt::Display for Datapath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Datapath::AfPacket => write!(f, "af_packet"),
+            Datapath::AfXdp => write!(f, "af_xdp"),
+        }
+    }
+}
+
+impl FromStr for Datapath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "af_packet" => Ok(Datapath::AfPacket),
+            "af_xdp" => Ok(Datapath::AfXdp),
+            _ => Err(format!("unknown datapath '{}' (expected af_packet or af_xdp)", s)),
+        }
+    }
+}
+
+/// A source of IP packets that must be handed back to the kernel
+pub trait PacketBackend {
+    /// Per-packet context needed to re-inject it (e.g. the divert address)
+    type Origin;
+
+    /// Short description for log messages
+    fn name(&self) -> &str;
+
+    /// Block until the next packet arrives
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<(usize, Self::Origin)>;
+
+    /// Return a (possibly rewritten) packet to the stack
+    fn reinject(&mut self, packet: &[u8], origin: &Self::Origin) -> io::Result<()>;
+
+    /// Whether the packet is coming back from the network, as opposed to
+    /// leaving the host
+    fn is_inbound(&self, _origin: &Self::Origin) -> bool {
+        false
+    }
+}
+
+/// Scrub packets from `backend` until receiving fails
+///
+/// `scrubbers` is the outbound/return-path pair from `Scrubber::pair`.
+pub fn run<B: PacketBackend>(
+    backend: &mut B,
+    scrubbers: &mut (Scrubber, Scrubber),
+    buffer_size: usize,
+    stats: &ScrubStats,
+) -> io::Result<()> {
+    let mut buf = vec![0u8; buffer_size];
+    loop {
+        let (n, origin) = match backend.recv(&mut buf) {
+            Ok(received) => received,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let packet = &buf[..n];
+        stats.packets.fetch_add(1, Ordering::Relaxed);
+
+        let scrubber = match backend.is_inbound(&origin) {
+            true => &mut scrubbers.1,
+            false => &mut scrubbers.0,
+        };
+        let rewritten = parse_ip_packet(packet).and_then(|segment| {
+            stats.tcp_segments.fetch_add(1, Ordering::Relaxed);
+            scrubber.scrub(packet, &segment)
+        });
+
+        let out = match &rewritten {
+            Some(new_packet) => {
+                stats.rewritten.fetch_add(1, Ordering::Relaxed);
+                debug!("{}: rewrote TCP options", backend.name());
+                new_packet.as_slice()
+            }
+            None => packet,
+        };
+
+        if let Err(e) = backend.reinject(out, &origin) {
+            stats.send_errors.fetch_add(1, Ordering::Relaxed);
+            warn!("{}: re-injection failed ({} bytes): {}", backend.name(), out.len(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_datapath() {
+        assert_eq!("af_packet".parse(), Ok(Datapath::AfPacket));
+        assert_eq!("af_xdp".parse(), Ok(Datapath::AfXdp));
+        assert_eq!(Datapath::AfXdp.to_string(), "af_xdp");
+        assert!("xdp".parse::<Datapath>().is_err());
+    }
+}
@@ -0,0 +1,102 @@
+//! Timeouts and retries of upstream connects
+//!
+//! Left alone, a dial to a backend that blackholes SYNs lasts as long as
+//! the kernel's SYN retransmissions (over two minutes by default), and the
+//! first failure fails the client. `--connect-timeout` bounds each attempt
+//! and `--connect-retries` gives the client more of them, each after a
+//! pause that starts at `--connect-backoff` and doubles per retry up to
+//! `MAX_BACKOFF`. A failed pool target is out of rotation (see `balance`),
+//! so retries go to the next one.
+//!
+//! Failed attempts are logged with their class and counted in
+//! `tcpstrip_connect_failures_total{reason}`, so a backend that refuses
+//! can be told from one that is unreachable, one that never answers, and
+//! a proxy that ran out of local ports.
+
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use crate::metrics;
+
+/// Longest pause between two attempts
+pub const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Why an upstream connect failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectFailure {
+    /// No answer within `--connect-timeout` or the SYN retransmissions
+    Timeout,
+    /// The backend answered the SYN with a reset
+    Refused,
+    /// No route, or ICMP unreachable from the network
+    Unreachable,
+    /// The connection was reset or aborted while being set up
+    Reset,
+    /// No local address or port left to connect from
+    NoLocalAddress,
+    Other,
+}
+
+impl ConnectFailure {
+    pub fn of(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::TimedOut => ConnectFailure::Timeout,
+            io::ErrorKind::ConnectionRefused => ConnectFailure::Refused,
+            io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => ConnectFailure::Unreachable,
+            io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => ConnectFailure::Reset,
+            io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable => ConnectFailure::NoLocalAddress,
+            _ => ConnectFailure::Other,
+        }
+    }
+
+    /// Count a failed attempt in `tcpstrip_connect_failures_total`
+    pub fn count(self) {
+        metrics::registry()
+            .labeled_counter(
+                "tcpstrip_connect_failures_total",
+                "Failed upstream connection attempts by failure class",
+                "reason",
+            )
+            .with(&self.to_string())
+            .inc();
+    }
+}
+
+impl fmt::Display for ConnectFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectFailure::Timeout => write!(f, "timeout"),
+            ConnectFailure::Refused => write!(f, "refused"),
+            ConnectFailure::Unreachable => write!(f, "unreachable"),
+            ConnectFailure::Reset => write!(f, "reset"),
+            ConnectFailure::NoLocalAddress => write!(f, "no-local-address"),
+            ConnectFailure::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// The pause before retry `retry` (0 for the first), starting at `initial`
+pub fn backoff(initial: Duration, retry: u32) -> Duration {
+    initial.saturating_mul(1 << retry.min(16)).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_classes_and_backoff() {
+        let failure = |kind| ConnectFailure::of(&io::Error::from(kind));
+        assert_eq!(failure(io::ErrorKind::ConnectionRefused), ConnectFailure::Refused);
+        assert_eq!(failure(io::ErrorKind::TimedOut), ConnectFailure::Timeout);
+        assert_eq!(failure(io::ErrorKind::HostUnreachable), ConnectFailure::Unreachable);
+        assert_eq!(failure(io::ErrorKind::AddrNotAvailable).to_string(), "no-local-address");
+        assert_eq!(failure(io::ErrorKind::PermissionDenied), ConnectFailure::Other);
+
+        let initial = Duration::from_millis(50);
+        let pauses: Vec<u128> = (0..8).map(|retry| backoff(initial, retry).as_millis()).collect();
+        assert_eq!(pauses, [50, 100, 200, 400, 800, 1600, 3200, 5000]);
+        assert_eq!(backoff(initial, u32::MAX), MAX_BACKOFF);
+    }
+}
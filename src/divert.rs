@@ -0,0 +1,169 @@
+//! Divert-socket datapath (macOS and FreeBSD)
+//!
+//! The BSD counterpart of TUN mode: a firewall rule diverts matching
+//! packets to a divert socket bound to a port, we rewrite their TCP options
+//! and write them back through the same socket, which re-injects them just
+//! after the rule that diverted them. No routing changes are needed and
+//! packets that are not diverted never reach userspace.
+//!
+//! ```text
+//! # FreeBSD (ipfw, requires ipdivert.ko)
+//! ipfw add 100 divert 7473 tcp from me to 203.0.113.0/24 out
+//! ipfw add 110 divert 7473 tcp from 203.0.113.0/24 to me in
+//! # pf builds with divert support
+//! pass out proto tcp to 203.0.113.0/24 divert-packet port 7473
+//! ```
+//!
+//! Divert sockets carry IPv4 only. Requires root.
+
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+
+use tracing::info;
+
+use crate::datapath::{self, PacketBackend};
+use crate::entropy::EntropyConfig;
+use crate::packet::ScrubStats;
+use crate::scrub::{ScrubPolicy, Scrubber};
+
+/// Divert datapath configuration
+#[derive(Debug, Clone)]
+pub struct DivertConfig {
+    /// Divert port the firewall rules send packets to
+    pub port: u16,
+    /// Scrubbing applied to diverted packets
+    pub policy: ScrubPolicy,
+    /// Randomness for spoofed timestamps
+    pub entropy: EntropyConfig,
+    /// Size of the packet read buffer
+    pub packet_buffer_size: usize,
+}
+
+struct DivertSocket {
+    name: String,
+    fd: OwnedFd,
+}
+
+impl DivertSocket {
+    fn bind(port: u16) -> io::Result<Self> {
+        let raw = unsafe { libc::socket(libc::AF_INET, libc::SOCK_RAW, libc::IPPROTO_DIVERT) };
+        if raw < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+
+        let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+        addr.sin_family = libc::AF_INET as libc::sa_family_t;
+        addr.sin_port = port.to_be();
+        let rc = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+            )
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            name: format!("divert:{}", port),
+            fd,
+        })
+    }
+}
+
+impl PacketBackend for DivertSocket {
+    /// The kernel encodes the diverting rule and interface in the source
+    /// address; handing it back re-injects the packet where it left off
+    type Origin = libc::sockaddr_in;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<(usize, libc::sockaddr_in)> {
+        let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+        let mut addr_len = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+        let n = unsafe {
+            libc::recvfrom(
+                self.fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+                &mut addr as *mut _ as *mut libc::sockaddr,
+                &mut addr_len,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((n as usize, addr))
+    }
+
+    /// Outbound packets are diverted with an unspecified address, inbound
+    /// ones with the address of the interface they arrived on
+    fn is_inbound(&self, origin: &libc::sockaddr_in) -> bool {
+        origin.sin_addr.s_addr != 0
+    }
+
+    fn reinject(&mut self, packet: &[u8], origin: &libc::sockaddr_in) -> io::Result<()> {
+        let rc = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                packet.as_ptr() as *const libc::c_void,
+                packet.len(),
+                0,
+                origin as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+            )
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// A bound divert socket, ready to run
+pub struct DivertScrubber {
+    config: DivertConfig,
+    socket: DivertSocket,
+    scrubbers: (Scrubber, Scrubber),
+    stats: Arc<ScrubStats>,
+}
+
+impl DivertScrubber {
+    pub fn open(config: DivertConfig) -> io::Result<Self> {
+        let socket = DivertSocket::bind(config.port)?;
+        let scrubbers = Scrubber::pair(config.policy.clone(), &config.entropy)?;
+
+        Ok(Self {
+            config,
+            socket,
+            scrubbers,
+            stats: Arc::new(ScrubStats::default()),
+        })
+    }
+
+    pub fn stats(&self) -> Arc<ScrubStats> {
+        self.stats.clone()
+    }
+
+    /// Scrub diverted packets until the socket fails
+    pub fn run(mut self) -> io::Result<()> {
+        info!(
+            "Scrubbing packets diverted to port {} (timestamps: {:?})",
+            self.config.port, self.config.policy.timestamps
+        );
+
+        datapath::run(
+            &mut self.socket,
+            &mut self.scrubbers,
+            self.config.packet_buffer_size,
+            &self.stats,
+        )
+    }
+}
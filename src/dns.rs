@@ -0,0 +1,253 @@
+//! Following the DNS records of targets given by name
+//!
+//! A target's name is resolved once at startup, but gateway records do
+//! change, e.g. when a venue points a name at a standby during maintenance.
+//! For every target given by name, a background task resolves the name
+//! again once the TTL of its records has run out (clamped to
+//! `MIN_REFRESH`..`MAX_REFRESH`). If the address in use is no longer among
+//! the answers, the target moves to a new one, preferring the same address
+//! family. New connections then go to the new address; established ones
+//! stay where they are. Moves are logged and counted in
+//! `tcpstrip_dns_changes_total{route}`. The fallback address of the other
+//! family (see `eyeballs`) follows the answers too.
+//!
+//! Addresses come from the system resolver, as at startup, so /etc/hosts
+//! and the search list apply. The system resolver does not report TTLs, so
+//! they are asked of the first nameserver in /etc/resolv.conf directly;
+//! where that fails (no resolv.conf, a name only in /etc/hosts), the name
+//! is resolved again every `FALLBACK_TTL`.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tracing::{info, warn};
+
+use crate::entropy::EntropyConfig;
+use crate::eyeballs;
+use crate::metrics;
+
+/// Shortest wait between resolutions, whatever the TTL
+pub const MIN_REFRESH: Duration = Duration::from_secs(5);
+/// Longest wait between resolutions, whatever the TTL
+pub const MAX_REFRESH: Duration = Duration::from_secs(3600);
+/// Wait between resolutions when the TTL is unknown
+pub const FALLBACK_TTL: Duration = Duration::from_secs(30);
+/// How long to wait for the nameserver's answer
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+/// The host part of a `HOST:PORT` target, if it is a name rather than an
+/// address
+pub fn target_name(target: &str) -> Option<&str> {
+    if target.parse::<SocketAddr>().is_ok() {
+        return None;
+    }
+    target.rsplit_once(':').map(|(host, _)| host).filter(|host| !host.is_empty())
+}
+
+/// First `nameserver` of a resolv.conf
+fn nameserver(resolv_conf: &str) -> Option<SocketAddr> {
+    resolv_conf
+        .lines()
+        .filter_map(|line| line.strip_prefix("nameserver"))
+        .filter_map(|rest| rest.split_whitespace().next())
+        .find_map(|addr| addr.parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+}
+
+/// A recursive query for `name`'s records of `qtype`
+fn encode_query(id: u16, name: &str, qtype: u16) -> Option<Vec<u8>> {
+    let mut query = Vec::with_capacity(18 + name.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    // RD set, one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return None;
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Some(query)
+}
+
+/// Offset just past the (possibly compressed) name at `pos`
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            // A pointer ends the name
+            _ if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            _ => pos += 1 + len as usize,
+        }
+    }
+}
+
+/// The lowest TTL among the answers of the response to query `id`, None if
+/// there are none (or the response is not one)
+fn answer_ttl(msg: &[u8], id: u16) -> Option<u32> {
+    let header = msg.get(..12)?;
+    let is_response = header[2] & 0x80 != 0;
+    let rcode = header[3] & 0x0f;
+    if u16::from_be_bytes([header[0], header[1]]) != id || !is_response || rcode != 0 {
+        return None;
+    }
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    let answers = u16::from_be_bytes([header[6], header[7]]);
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(msg, pos)? + 4;
+    }
+    let mut ttl: Option<u32> = None;
+    for _ in 0..answers {
+        pos = skip_name(msg, pos)?;
+        let record = msg.get(pos..pos + 10)?;
+        let record_ttl = u32::from_be_bytes(record[4..8].try_into().unwrap());
+        let rdlength = u16::from_be_bytes([record[8], record[9]]) as usize;
+        // CNAMEs count too: the chain is only as fresh as its shortest link
+        ttl = Some(ttl.map_or(record_ttl, |ttl| ttl.min(record_ttl)));
+        pos += 10 + rdlength;
+    }
+    ttl
+}
+
+/// Ask the nameserver how long `name`'s records of `qtype` may be cached
+async fn query_ttl(server: SocketAddr, name: &str, qtype: u16) -> io::Result<Option<u32>> {
+    let id = EntropyConfig::Os.open()?.next_u32() as u16;
+    let query = encode_query(id, name, qtype)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid name '{}'", name)))?;
+    let bind: SocketAddr = match server {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(server).await?;
+    socket.send(&query).await?;
+
+    let mut buf = [0u8; 1232];
+    tokio::time::timeout(QUERY_TIMEOUT, async {
+        // Skip stray datagrams until the answer to this query arrives
+        loop {
+            let n = socket.recv(&mut buf).await?;
+            if buf[..n].starts_with(&id.to_be_bytes()) {
+                return Ok(answer_ttl(&buf[..n], id));
+            }
+        }
+    })
+    .await
+    .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "nameserver did not answer")))
+}
+
+/// How long until `name` should be resolved again
+async fn refresh_after(name: &str, family: &SocketAddr) -> Duration {
+    let Some(server) = std::fs::read_to_string("/etc/resolv.conf").ok().as_deref().and_then(nameserver) else {
+        return FALLBACK_TTL;
+    };
+    let qtype = if family.is_ipv4() { TYPE_A } else { TYPE_AAAA };
+    match query_ttl(server, name, qtype).await {
+        Ok(Some(ttl)) => Duration::from_secs(ttl as u64).clamp(MIN_REFRESH, MAX_REFRESH),
+        _ => FALLBACK_TTL,
+    }
+}
+
+/// Where a target should move, if anywhere, given what its name resolves
+/// to now
+fn next_addr(current: SocketAddr, resolved: &[SocketAddr]) -> Option<SocketAddr> {
+    if resolved.contains(&current) {
+        return None;
+    }
+    resolved
+        .iter()
+        .find(|addr| addr.is_ipv4() == current.is_ipv4())
+        .or(resolved.first())
+        .copied()
+}
+
+/// Follow the name of `target` (`HOST:PORT`), currently at `current` with
+/// `fallback`, calling `moved` with each new address and fallback; targets
+/// given as addresses are left alone
+pub fn spawn(
+    target: String,
+    current: SocketAddr,
+    fallback: Option<SocketAddr>,
+    moved: impl Fn(SocketAddr, Option<SocketAddr>) + Send + 'static,
+) {
+    let Some(name) = target_name(&target).map(str::to_string) else {
+        return;
+    };
+    let changes = metrics::registry()
+        .labeled_counter("tcpstrip_dns_changes_total", "Targets moved to a new address by DNS", "route")
+        .with(&target);
+
+    tokio::spawn(async move {
+        let (mut current, mut fallback) = (current, fallback);
+        loop {
+            tokio::time::sleep(refresh_after(&name, &current).await).await;
+            let resolved: Vec<SocketAddr> = match tokio::net::lookup_host(target.as_str()).await {
+                Ok(addrs) => addrs.collect(),
+                Err(e) => {
+                    warn!("Could not resolve target {} again, staying at {}: {}", target, current, e);
+                    continue;
+                }
+            };
+            let addr = match next_addr(current, &resolved) {
+                Some(addr) => {
+                    info!("Target {} moved from {} to {}", target, current, addr);
+                    changes.inc();
+                    addr
+                }
+                None => current,
+            };
+            let other = eyeballs::fallback(addr, &resolved);
+            if (addr, other) != (current, fallback) {
+                moved(addr, other);
+                (current, fallback) = (addr, other);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ttls_and_moves() {
+        assert_eq!(target_name("gw1.example.com:9000"), Some("gw1.example.com"));
+        assert_eq!(target_name("192.0.2.1:9000"), None);
+        assert_eq!(target_name("[2001:db8::1]:9000"), None);
+        assert_eq!(
+            nameserver("# generated\nsearch example.com\nnameserver 10.255.255.53\nnameserver ::1\n"),
+            Some("10.255.255.53:53".parse().unwrap())
+        );
+
+        // Answer for gw.example.com: a CNAME (TTL 60) to an A record (TTL 300)
+        let mut msg = encode_query(0x1234, "gw.example.com", TYPE_A).unwrap();
+        msg[2..8].copy_from_slice(&[0x81, 0x80, 0, 1, 0, 2]);
+        msg.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 5, 2, b'g', b'x', 0xc0, 15]);
+        msg.extend_from_slice(&[0xc0, 44, 0, 1, 0, 1, 0, 0, 1, 44, 0, 4, 192, 0, 2, 7]);
+        assert_eq!(answer_ttl(&msg, 0x1234), Some(60));
+        assert_eq!(answer_ttl(&msg, 0x4321), None);
+        msg[3] = 0x83; // NXDOMAIN
+        assert_eq!(answer_ttl(&msg, 0x1234), None);
+        assert!(encode_query(1, "bad..name", TYPE_A).is_none());
+
+        let current: SocketAddr = "192.0.2.1:9000".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::7]:9000".parse().unwrap();
+        let v4: SocketAddr = "192.0.2.7:9000".parse().unwrap();
+        assert_eq!(next_addr(current, &[v6, current]), None);
+        assert_eq!(next_addr(current, &[v6, v4]), Some(v4));
+        assert_eq!(next_addr(current, &[v6]), Some(v6));
+        assert_eq!(next_addr(current, &[]), None);
+    }
+}
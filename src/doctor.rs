@@ -0,0 +1,239 @@
+//! Host checks for the proxy's connection setup (Linux only)
+//!
+//! How the kernel treats SYNs decides whether the proxy keeps accepting
+//! order flow during a SYN flood or a reconnect storm at market open, and
+//! how long a dial to a dead backend hangs before failing over. `--doctor`
+//! reads the relevant sysctls and reports each against the listener the
+//! proxy would open. `tune` sets the ones that warn to values that do not,
+//! except `tcp_syn_retries`: it covers every dial on the host, and is
+//! better lowered for the proxy alone with `--syn-retries`.
+//!
+//! SYN cookies deserve a note of their own: the kernel encodes window
+//! scaling and SACK in the TSval of the cookie, so connections accepted
+//! through cookies lose both once timestamps are stripped.
+
+use std::fmt;
+use std::fs;
+use std::io;
+
+/// The settings the report covers
+const SYSCTLS: &[&str] = &[
+    "net.ipv4.tcp_syncookies",
+    "net.ipv4.tcp_max_syn_backlog",
+    "net.core.somaxconn",
+    "net.ipv4.tcp_abort_on_overflow",
+    "net.ipv4.tcp_synack_retries",
+    "net.ipv4.tcp_syn_retries",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Warn,
+}
+
+/// One setting and what it means for the proxy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub setting: &'static str,
+    pub value: String,
+    pub status: Status,
+    pub note: String,
+    /// Value that clears the warning, where a host setting can
+    pub fix: Option<i64>,
+}
+
+/// What the proxy's listener and upstream dials are configured with
+#[derive(Debug, Clone, Copy)]
+pub struct ListenerConfig {
+    /// Backlog passed to listen()
+    pub backlog: u32,
+    /// --syn-retries for upstream connections, if given
+    pub syn_retries: Option<u8>,
+}
+
+/// Findings for every setting the report covers
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub findings: Vec<Finding>,
+}
+
+impl Report {
+    /// Read the host's settings
+    pub fn collect(config: ListenerConfig) -> io::Result<Self> {
+        let mut values = Vec::new();
+        for &setting in SYSCTLS {
+            values.push((setting, read_sysctl(setting)?));
+        }
+        Ok(Self::evaluate(&values, config))
+    }
+
+    fn evaluate(values: &[(&'static str, i64)], config: ListenerConfig) -> Self {
+        let findings = values
+            .iter()
+            .map(|&(setting, value)| {
+                let (status, note) = assess(setting, value, config);
+                Finding {
+                    setting,
+                    value: value.to_string(),
+                    status,
+                    note,
+                    fix: fix(setting, value, config).filter(|_| status == Status::Warn),
+                }
+            })
+            .collect();
+        Self { findings }
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.findings.iter().filter(|f| f.status == Status::Warn).count()
+    }
+
+    /// Write the fix of every finding that has one; the findings fixed
+    pub fn apply(&self) -> io::Result<Vec<&Finding>> {
+        let fixable: Vec<_> = self.findings.iter().filter(|f| f.fix.is_some()).collect();
+        for finding in &fixable {
+            write_sysctl(finding.setting, finding.fix.unwrap_or_default())?;
+        }
+        Ok(fixable)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for finding in &self.findings {
+            let status = match finding.status {
+                Status::Ok => "ok",
+                Status::Warn => "WARN",
+            };
+            writeln!(f, "[{:4}] {} = {}", status, finding.setting, finding.value)?;
+            writeln!(f, "       {}", finding.note)?;
+        }
+        writeln!(f, "{} warning(s)", self.warnings())
+    }
+}
+
+fn assess(setting: &str, value: i64, config: ListenerConfig) -> (Status, String) {
+    let backlog = config.backlog as i64;
+    match setting {
+        "net.ipv4.tcp_syncookies" => match value {
+            0 => (Status::Warn, "disabled: a SYN flood fills the SYN queue and real clients are dropped".to_string()),
+            1 => (
+                Status::Ok,
+                "sent when the SYN queue overflows; those connections lose window scaling and SACK while timestamps are stripped"
+                    .to_string(),
+            ),
+            _ => (
+                Status::Warn,
+                "sent for every SYN: connections lose window scaling and SACK while timestamps are stripped".to_string(),
+            ),
+        },
+        "net.ipv4.tcp_max_syn_backlog" if value < backlog => (
+            Status::Warn,
+            format!("below the listen backlog of {}, half-open connections are dropped first", backlog),
+        ),
+        "net.ipv4.tcp_max_syn_backlog" => (Status::Ok, "half-open connections per listener".to_string()),
+        "net.core.somaxconn" if value < backlog => (
+            Status::Warn,
+            format!("caps the accept queue below the listen backlog of {}", backlog),
+        ),
+        "net.core.somaxconn" => (Status::Ok, format!("the listen backlog of {} is not capped", backlog)),
+        "net.ipv4.tcp_abort_on_overflow" if value != 0 => (
+            Status::Warn,
+            "clients are reset instead of retrying when the accept queue overflows".to_string(),
+        ),
+        "net.ipv4.tcp_abort_on_overflow" => (Status::Ok, "clients retry when the accept queue overflows".to_string()),
+        "net.ipv4.tcp_synack_retries" => (
+            Status::Ok,
+            format!("half-open connections are kept for about {}s", backoff_secs(value)),
+        ),
+        "net.ipv4.tcp_syn_retries" => match config.syn_retries {
+            Some(retries) => (
+                Status::Ok,
+                format!(
+                    "overridden by --syn-retries {}: dials to a dead backend fail after about {}s",
+                    retries,
+                    backoff_secs(retries as i64)
+                ),
+            ),
+            None if value > 3 => (
+                Status::Warn,
+                format!(
+                    "dials to a dead backend hang for about {}s; lower it for upstream connections with --syn-retries",
+                    backoff_secs(value)
+                ),
+            ),
+            None => (
+                Status::Ok,
+                format!("dials to a dead backend fail after about {}s", backoff_secs(value)),
+            ),
+        },
+        _ => (Status::Ok, String::new()),
+    }
+}
+
+/// The value `tune` sets for a setting that warns; None where the fix is
+/// not the host's
+fn fix(setting: &str, value: i64, config: ListenerConfig) -> Option<i64> {
+    let backlog = config.backlog as i64;
+    match setting {
+        "net.ipv4.tcp_syncookies" if value != 1 => Some(1),
+        "net.ipv4.tcp_max_syn_backlog" | "net.core.somaxconn" if value < backlog => Some(backlog),
+        "net.ipv4.tcp_abort_on_overflow" if value != 0 => Some(0),
+        _ => None,
+    }
+}
+
+/// Time until the last of `retries` retransmissions (1s initial RTO,
+/// doubling) has timed out
+fn backoff_secs(retries: i64) -> i64 {
+    (1i64 << (retries.clamp(0, 30) + 1)) - 1
+}
+
+fn read_sysctl(name: &str) -> io::Result<i64> {
+    let path = format!("/proc/sys/{}", name.replace('.', "/"));
+    let value = fs::read_to_string(&path)?;
+    value
+        .trim()
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("unexpected value in {}", path)))
+}
+
+fn write_sysctl(name: &str, value: i64) -> io::Result<()> {
+    let path = format!("/proc/sys/{}", name.replace('.', "/"));
+    fs::write(&path, value.to_string()).map_err(|e| io::Error::new(e.kind(), format!("could not write {}: {}", path, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let values = [
+            ("net.ipv4.tcp_syncookies", 0),
+            ("net.ipv4.tcp_max_syn_backlog", 512),
+            ("net.core.somaxconn", 64),
+            ("net.ipv4.tcp_abort_on_overflow", 0),
+            ("net.ipv4.tcp_synack_retries", 5),
+            ("net.ipv4.tcp_syn_retries", 6),
+        ];
+        let config = ListenerConfig {
+            backlog: 128,
+            syn_retries: None,
+        };
+        let report = Report::evaluate(&values, config);
+        let warned: Vec<_> = report.findings.iter().filter(|f| f.status == Status::Warn).map(|f| f.setting).collect();
+        assert_eq!(
+            warned,
+            ["net.ipv4.tcp_syncookies", "net.core.somaxconn", "net.ipv4.tcp_syn_retries"]
+        );
+        assert!(report.to_string().contains("about 127s"));
+        let fixes: Vec<_> = report.findings.iter().filter_map(|f| Some((f.setting, f.fix?))).collect();
+        assert_eq!(fixes, [("net.ipv4.tcp_syncookies", 1), ("net.core.somaxconn", 128)]);
+
+        let report = Report::evaluate(&values, ListenerConfig { syn_retries: Some(2), ..config });
+        assert_eq!(report.warnings(), 2);
+        assert!(report.to_string().contains("fail after about 7s"));
+    }
+}
@@ -0,0 +1,93 @@
+//! DSCP marking per route
+//!
+//! `--dscp` takes `[DEST=]VALUE` rules (see `route`): the socket proxy
+//! sets the code point on its upstream connections, the packet datapaths
+//! rewrite it in the IP header of outgoing segments. That serves both ways
+//! operators use it: marking order flow as EF so the network prioritizes
+//! it, and setting client traffic to 0 so QoS markings the host's OS or
+//! applications chose do not leave the network.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::route::RouteRule;
+
+/// A differentiated services code point (0-63)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dscp(u8);
+
+impl Dscp {
+    pub const MAX: u8 = 63;
+
+    pub fn new(value: u8) -> Option<Self> {
+        (value <= Self::MAX).then_some(Self(value))
+    }
+
+    pub fn value(self) -> u8 {
+        self.0
+    }
+
+    /// The IPv4 TOS / IPv6 traffic class byte with this code point and no
+    /// ECN bits
+    pub fn tos(self) -> u8 {
+        self.0 << 2
+    }
+}
+
+impl fmt::Display for Dscp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for Dscp {
+    type Err = String;
+
+    /// A number, or one of the names from RFC 2474/2597/3246 (`ef`,
+    /// `cs0`-`cs7`, `af11`-`af43`)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_ascii_lowercase();
+        let value = match lower.as_str() {
+            "ef" => Some(46),
+            _ => match (lower.get(..2), lower.get(2..).map(str::as_bytes)) {
+                (Some("cs"), Some([class @ b'0'..=b'7'])) => Some((class - b'0') << 3),
+                (Some("af"), Some([class @ b'1'..=b'4', drop @ b'1'..=b'3'])) => {
+                    Some(((class - b'0') << 3) | ((drop - b'0') << 1))
+                }
+                _ => lower.parse().ok(),
+            },
+        };
+        value
+            .and_then(Dscp::new)
+            .ok_or_else(|| format!("invalid DSCP '{}' (expected 0-63, ef, csN or afXY)", s))
+    }
+}
+
+/// One `--dscp` argument
+pub type DscpRule = RouteRule<Dscp>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::route::lookup;
+
+    #[test]
+    fn test_parse_and_lookup() {
+        assert_eq!("ef".parse::<Dscp>().unwrap().tos(), 0xb8);
+        assert_eq!("AF41".parse::<Dscp>().unwrap().value(), 34);
+        assert_eq!("cs6".parse::<Dscp>().unwrap().value(), 48);
+        assert!("64".parse::<Dscp>().is_err());
+        assert!("af44".parse::<Dscp>().is_err());
+
+        let rules: Vec<DscpRule> = ["10.1.0.5:9000=ef", "10.1.0.5=af21", "[2001:db8::1]:443=cs4", "0"]
+            .iter()
+            .map(|rule| rule.parse().unwrap())
+            .collect();
+        let dscp = |dest: &str| lookup(&rules, dest.parse().unwrap()).map(Dscp::value);
+        assert_eq!(dscp("10.1.0.5:9000"), Some(46));
+        assert_eq!(dscp("10.1.0.5:9001"), Some(18));
+        assert_eq!(dscp("[2001:db8::1]:443"), Some(32));
+        assert_eq!(dscp("192.0.2.1:80"), Some(0));
+        assert!("10.1.0.5:=ef".parse::<DscpRule>().is_err());
+    }
+}
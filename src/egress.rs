@@ -0,0 +1,246 @@
+//! Where upstream connections leave the host
+//!
+//! By default the routing table picks the interface and source address of
+//! every upstream connection, which on a host with a dedicated low-latency
+//! NIC or VLAN for the venue may well be the management network.
+//! `--bind-source` pins the source address (one per address family) and
+//! `--bind-device` the interface (SO_BINDTODEVICE, Linux only), so traffic
+//! leaves where it should whatever the routes say. Health probes leave the
+//! same way, so they check the path clients take.
+//!
+//! `--source-ports` confines the local ports of upstream connections to a
+//! range, so the exchange's firewall can be scoped to it and the proxy's
+//! flows picked out of a capture. Ports are handed out in turn, skipping
+//! ones in use; each is held by one connection at a time, so the range
+//! bounds how many upstream connections can be open at once.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpStream;
+
+/// Where in the port range the next upstream connection starts looking
+static NEXT_PORT: AtomicUsize = AtomicUsize::new(0);
+
+/// An inclusive range of local ports (`FIRST-LAST`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl PortRange {
+    fn len(&self) -> usize {
+        (self.last - self.first) as usize + 1
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        (self.first..=self.last).contains(&port)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid port range '{}' (expected FIRST-LAST, 1-65535)", s);
+        let (first, last) = s.split_once('-').ok_or_else(invalid)?;
+        let (first, last) = (first.parse::<u16>().map_err(|_| invalid())?, last.parse::<u16>().map_err(|_| invalid())?);
+        if first == 0 || first > last {
+            return Err(invalid());
+        }
+        Ok(PortRange { first, last })
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+/// Source address, port and interface of upstream connections
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Egress {
+    /// At most one per address family
+    sources: Vec<IpAddr>,
+    device: Option<String>,
+    ports: Option<PortRange>,
+}
+
+impl Egress {
+    /// Check that the addresses are local (and one per family) and that the
+    /// interface exists and may be bound to
+    pub fn new(sources: Vec<IpAddr>, device: Option<String>, ports: Option<PortRange>) -> io::Result<Self> {
+        if sources.iter().filter(|ip| ip.is_ipv4()).count() > 1 || sources.iter().filter(|ip| ip.is_ipv6()).count() > 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "at most one source address per address family"));
+        }
+        let mut egress = Egress { sources, device, ports: None };
+        for &ip in &egress.sources {
+            let target = SocketAddr::new(ip, 0);
+            egress
+                .bind(&Socket::new(Domain::for_address(target), Type::STREAM, Some(Protocol::TCP))?, target, None)
+                .map_err(|e| io::Error::new(e.kind(), format!("cannot send from {}: {}", ip, e)))?;
+        }
+        if egress.device.is_some() {
+            egress.bind_device(&Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?)?;
+        }
+        egress.ports = ports;
+        Ok(egress)
+    }
+
+    /// The source address for connections to `target`, if one was given for
+    /// its family
+    pub fn source_for(&self, target: SocketAddr) -> Option<IpAddr> {
+        self.sources.iter().find(|ip| ip.is_ipv4() == target.is_ipv4()).copied()
+    }
+
+    /// Send whatever `socket` sends through the interface, if one was given
+    pub fn bind_device(&self, socket: &Socket) -> io::Result<()> {
+        let Some(device) = &self.device else {
+            return Ok(());
+        };
+        #[cfg(target_os = "linux")]
+        return socket
+            .bind_device(Some(device.as_bytes()))
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot bind to interface {}: {}", device, e)));
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (socket, device);
+            Err(io::Error::new(io::ErrorKind::Unsupported, "binding to an interface is only supported on Linux"))
+        }
+    }
+
+    /// Bind `socket`, about to connect to `target`, to the source address
+    /// (`source` if given, e.g. the client's), a port of the range and the
+    /// interface
+    pub fn bind(&self, socket: &Socket, target: SocketAddr, source: Option<IpAddr>) -> io::Result<()> {
+        let source = source.or_else(|| self.source_for(target));
+        match self.ports {
+            Some(ports) => {
+                let ip = source.unwrap_or(match target {
+                    SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+                });
+                bind_port(socket, ip, ports)?;
+            }
+            None => {
+                if let Some(ip) = source {
+                    socket.bind(&SocketAddr::new(ip, 0).into())?;
+                }
+            }
+        }
+        self.bind_device(socket)
+    }
+
+    /// Connect to `target` the way upstream connections do
+    pub async fn connect(&self, target: SocketAddr) -> io::Result<TcpStream> {
+        let socket = stream_socket(target)?;
+        self.bind(&socket, target, None)?;
+        connect(socket, target).await
+    }
+}
+
+/// A TCP socket for connecting to `target`, non-blocking from the start so
+/// nothing done to it before `connect` can stall a runtime worker
+pub fn stream_socket(target: SocketAddr) -> io::Result<Socket> {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    return Socket::new(Domain::for_address(target), Type::STREAM.nonblocking(), Some(Protocol::TCP));
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    {
+        let socket = Socket::new(Domain::for_address(target), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }
+}
+
+/// Connect `socket`, set up with whatever options the connection needs,
+/// to `target` without blocking: the SYN goes out (EINPROGRESS), the
+/// runtime wakes the task once the socket is writable, and the outcome is
+/// read from SO_ERROR. Dropping the future abandons the attempt, which is
+/// how connect timeouts and Happy Eyeballs races cancel one.
+pub async fn connect(socket: Socket, target: SocketAddr) -> io::Result<TcpStream> {
+    tokio::net::TcpSocket::from_std_stream(socket.into()).connect(target).await
+}
+
+/// Bind `socket` to the next free port of `ports` at `ip`
+fn bind_port(socket: &Socket, ip: IpAddr, ports: PortRange) -> io::Result<()> {
+    let start = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+    for i in 0..ports.len() {
+        let port = ports.first + ((start + i) % ports.len()) as u16;
+        match socket.bind(&SocketAddr::new(ip, port).into()) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AddrNotAvailable, format!("every source port in {} is in use", ports)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_connect_does_not_block_the_runtime() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let socket = stream_socket(target).unwrap();
+        socket.set_nodelay(true).unwrap();
+        // On one thread, the accept can only run while the connect waits
+        let (stream, accepted) = tokio::join!(connect(socket, target), listener.accept());
+        let stream = stream.unwrap();
+        assert_eq!(accepted.unwrap().1, stream.local_addr().unwrap());
+        assert!(stream.nodelay().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_bind_source() {
+        let loopback: IpAddr = "127.0.0.1".parse().unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.2:0").await;
+        // Not every host routes all of 127/8 to lo
+        let Ok(listener) = listener else {
+            return;
+        };
+        let target = listener.local_addr().unwrap();
+
+        let egress = Egress::new(vec![loopback], None, None).unwrap();
+        assert_eq!(egress.source_for(target), Some(loopback));
+        assert_eq!(egress.source_for("[2001:db8::1]:9000".parse().unwrap()), None);
+        let stream = egress.connect(target).await.unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), loopback);
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer.ip(), loopback);
+
+        assert_eq!(Egress::default().source_for(target), None);
+        let two_v4 = vec![loopback, "127.0.0.2".parse().unwrap()];
+        assert_eq!(Egress::new(two_v4, None, None).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(Egress::new(vec!["192.0.2.1".parse().unwrap()], None, None).is_err());
+    }
+
+    #[test]
+    fn test_source_ports() {
+        assert_eq!("40000-40999".parse::<PortRange>().unwrap().len(), 1000);
+        assert_eq!("7-7".parse::<PortRange>().unwrap().to_string(), "7-7");
+        for bad in ["40999-40000", "0-10", "40000", "40000-70000", "a-b"] {
+            assert!(bad.parse::<PortRange>().is_err(), "{}", bad);
+        }
+
+        // Two ports serve two sockets, then the range is used up
+        let ports: PortRange = "47311-47312".parse().unwrap();
+        let egress = Egress::new(Vec::new(), None, Some(ports)).unwrap();
+        let target: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let socket = || Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).unwrap();
+        let (a, b, c) = (socket(), socket(), socket());
+        egress.bind(&a, target, None).unwrap();
+        egress.bind(&b, target, Some(Ipv4Addr::LOCALHOST.into())).unwrap();
+        let port = |s: &Socket| s.local_addr().unwrap().as_socket().unwrap().port();
+        assert!(ports.contains(port(&a)) && ports.contains(port(&b)) && port(&a) != port(&b));
+        assert_eq!(b.local_addr().unwrap().as_socket().unwrap().ip(), IpAddr::from(Ipv4Addr::LOCALHOST));
+        assert_eq!(egress.bind(&c, target, None).unwrap_err().kind(), io::ErrorKind::AddrNotAvailable);
+    }
+}
@@ -0,0 +1,208 @@
+//! Entropy sources for timestamp spoofing
+//!
+//! Spoofed timestamps are only as good as the randomness behind them:
+//! production deployments want values an observer cannot predict, while
+//! tests and lab reproductions want the exact same sequence on every run.
+//! The spoofing code therefore draws from an `EntropySource` chosen at
+//! startup instead of a hardcoded generator:
+//!
+//! - `os` - the kernel CSPRNG (getrandom / /dev/urandom)
+//! - `seeded:<u64>` - a deterministic SplitMix64 stream for reproducible runs
+//! - `file:<path>` - bytes from an external entropy file (e.g. a hardware
+//!   RNG dump), rewound when exhausted
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use tracing::warn;
+
+/// A source of random 32-bit values
+pub trait EntropySource: Send {
+    fn next_u32(&mut self) -> u32;
+}
+
+/// Which entropy source to use, as selected on the command line
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum EntropyConfig {
+    #[default]
+    Os,
+    Seeded(u64),
+    File(PathBuf),
+}
+
+impl fmt::Display for EntropyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntropyConfig::Os => write!(f, "os"),
+            EntropyConfig::Seeded(seed) => write!(f, "seeded:{}", seed),
+            EntropyConfig::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for EntropyConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "os" => Ok(EntropyConfig::Os),
+            Some(("seeded", seed)) => seed
+                .parse()
+                .map(EntropyConfig::Seeded)
+                .map_err(|_| format!("invalid seed: {}", seed)),
+            Some(("file", path)) if !path.is_empty() => Ok(EntropyConfig::File(path.into())),
+            _ => Err(format!(
+                "unknown entropy source '{}' (expected os, seeded:<u64> or file:<path>)",
+                s
+            )),
+        }
+    }
+}
+
+impl EntropyConfig {
+    /// Instantiate the configured source
+    pub fn open(&self) -> io::Result<Box<dyn EntropySource>> {
+        Ok(match self {
+            EntropyConfig::Os => Box::new(OsEntropy::new()?),
+            EntropyConfig::Seeded(seed) => Box::new(SeededEntropy::new(*seed)),
+            EntropyConfig::File(path) => Box::new(FileEntropy::open(path.clone())?),
+        })
+    }
+}
+
+/// Kernel CSPRNG
+///
+/// Reads are buffered so a busy datapath does not pay a syscall per value.
+pub struct OsEntropy {
+    urandom: File,
+    buf: [u8; 256],
+    pos: usize,
+}
+
+impl OsEntropy {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            urandom: File::open("/dev/urandom")?,
+            buf: [0; 256],
+            pos: 256,
+        })
+    }
+}
+
+impl EntropySource for OsEntropy {
+    fn next_u32(&mut self) -> u32 {
+        if self.pos + 4 > self.buf.len() {
+            // /dev/urandom never blocks or runs dry once the pool is seeded
+            self.urandom
+                .read_exact(&mut self.buf)
+                .expect("reading /dev/urandom failed");
+            self.pos = 0;
+        }
+        let bytes = &self.buf[self.pos..self.pos + 4];
+        self.pos += 4;
+        u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+}
+
+/// Deterministic SplitMix64 generator for reproducible runs
+pub struct SeededEntropy {
+    state: u64,
+}
+
+impl SeededEntropy {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+}
+
+impl EntropySource for SeededEntropy {
+    fn next_u32(&mut self) -> u32 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        ((z ^ (z >> 31)) >> 32) as u32
+    }
+}
+
+/// Values read sequentially from an external entropy file
+pub struct FileEntropy {
+    path: PathBuf,
+    reader: BufReader<File>,
+}
+
+impl FileEntropy {
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let file = File::open(&path)?;
+        if file.metadata()?.len() < 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("entropy file {} is shorter than 4 bytes", path.display()),
+            ));
+        }
+        Ok(Self {
+            path,
+            reader: BufReader::new(file),
+        })
+    }
+}
+
+impl EntropySource for FileEntropy {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0u8; 4];
+        if self.reader.read_exact(&mut bytes).is_err() {
+            warn!("Entropy file {} exhausted, rewinding", self.path.display());
+            self.reader
+                .seek(SeekFrom::Start(0))
+                .and_then(|_| self.reader.read_exact(&mut bytes))
+                .expect("re-reading entropy file failed");
+        }
+        u32::from_be_bytes(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_entropy_config() {
+        assert_eq!("os".parse(), Ok(EntropyConfig::Os));
+        assert_eq!("seeded:42".parse(), Ok(EntropyConfig::Seeded(42)));
+        assert_eq!(
+            "file:/var/lib/rng.bin".parse(),
+            Ok(EntropyConfig::File("/var/lib/rng.bin".into()))
+        );
+        assert!("seeded:abc".parse::<EntropyConfig>().is_err());
+        assert!("lcg".parse::<EntropyConfig>().is_err());
+    }
+
+    #[test]
+    fn test_seeded_source_is_reproducible() {
+        let mut a = SeededEntropy::new(7);
+        let mut b = SeededEntropy::new(7);
+        let mut c = SeededEntropy::new(8);
+
+        let run_a: Vec<u32> = (0..16).map(|_| a.next_u32()).collect();
+        let run_b: Vec<u32> = (0..16).map(|_| b.next_u32()).collect();
+        let run_c: Vec<u32> = (0..16).map(|_| c.next_u32()).collect();
+
+        assert_eq!(run_a, run_b);
+        assert_ne!(run_a, run_c);
+    }
+
+    #[test]
+    fn test_file_source_rewinds() {
+        let path = std::env::temp_dir().join(format!("tcpstrip-entropy-{}", std::process::id()));
+        std::fs::write(&path, [0, 0, 0, 1, 0, 0, 0, 2]).unwrap();
+
+        let mut source = FileEntropy::open(path.clone()).unwrap();
+        let values: Vec<u32> = (0..3).map(|_| source.next_u32()).collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(values, vec![1, 2, 1]);
+    }
+}
@@ -0,0 +1,113 @@
+//! Happy Eyeballs for dual-stack targets (RFC 8305)
+//!
+//! A target name may resolve to both A and AAAA records, and a gateway is
+//! sometimes only reachable over one family: an IPv6 route that blackholes
+//! SYNs would otherwise cost every connection a full connect timeout. The
+//! member keeps the first address the resolver returned (its preferred
+//! family, per RFC 6724) and the first one of the other family as a
+//! fallback. `race` dials the preferred address and, if it has not
+//! connected after the Connection Attempt Delay (`--happy-eyeballs-delay`,
+//! `CONNECTION_ATTEMPT_DELAY` by default) or fails before that, the
+//! fallback as well; whichever connects first is used and the other
+//! attempt is dropped.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// RFC 8305's recommended head start of the preferred family
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// The first of `resolved` in the other family than `primary`
+pub fn fallback(primary: SocketAddr, resolved: &[SocketAddr]) -> Option<SocketAddr> {
+    resolved.iter().find(|addr| addr.is_ipv4() != primary.is_ipv4()).copied()
+}
+
+/// Connect to `primary`, or to `fallback` if that connects first after
+/// `delay`; the address used and its connection, or `primary`'s error if
+/// both fail
+pub async fn race<T, E, F, Fut>(
+    primary: SocketAddr,
+    fallback: Option<SocketAddr>,
+    delay: Duration,
+    connect: F,
+) -> Result<(SocketAddr, T), E>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let Some(fallback) = fallback else {
+        return connect(primary).await.map(|stream| (primary, stream));
+    };
+    let first = connect(primary);
+    tokio::pin!(first);
+    let early_error = tokio::select! {
+        result = &mut first => match result {
+            Ok(stream) => return Ok((primary, stream)),
+            Err(e) => Some(e),
+        },
+        _ = tokio::time::sleep(delay) => None,
+    };
+    let second = connect(fallback);
+    if let Some(e) = early_error {
+        return second.await.map(|stream| (fallback, stream)).map_err(|_| e);
+    }
+    tokio::pin!(second);
+    tokio::select! {
+        result = &mut first => match result {
+            Ok(stream) => Ok((primary, stream)),
+            Err(e) => second.await.map(|stream| (fallback, stream)).map_err(|_| e),
+        },
+        result = &mut second => match result {
+            Ok(stream) => Ok((fallback, stream)),
+            Err(_) => first.await.map(|stream| (primary, stream)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn test_race() {
+        let v4: SocketAddr = "192.0.2.1:9000".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:9000".parse().unwrap();
+        assert_eq!(fallback(v6, &[v6, "[2001:db8::2]:9000".parse().unwrap(), v4]), Some(v4));
+        assert_eq!(fallback(v4, &[v4]), None);
+
+        // `delay` stands in for how long each address takes to connect;
+        // None never connects and Some(Err) fails after the delay
+        let dial = |v6_takes: Option<Result<u64, u64>>, v4_takes: Result<u64, u64>| {
+            move |addr: SocketAddr| async move {
+                let takes = if addr.is_ipv6() { v6_takes } else { Some(v4_takes) };
+                let Some(takes) = takes else {
+                    return std::future::pending().await;
+                };
+                let millis = takes.unwrap_or_else(|millis| millis);
+                tokio::time::sleep(Duration::from_millis(millis)).await;
+                takes.map(|_| addr).map_err(|_| addr)
+            }
+        };
+        let delay = Duration::from_millis(50);
+
+        // The preferred family wins inside its head start
+        let (addr, _) = race(v6, Some(v4), delay, dial(Some(Ok(10)), Ok(0))).await.unwrap();
+        assert_eq!(addr, v6);
+        // A blackholed family costs only the delay
+        let started = Instant::now();
+        let (addr, _) = race(v6, Some(v4), delay, dial(None, Ok(10))).await.unwrap();
+        assert_eq!(addr, v4);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        // A refused one costs nothing
+        let (addr, _) = race(v6, Some(v4), Duration::from_secs(5), dial(Some(Err(0)), Ok(0))).await.unwrap();
+        assert_eq!(addr, v4);
+        // Both started, the preferred one still connects first
+        let (addr, _) = race(v6, Some(v4), delay, dial(Some(Ok(70)), Ok(100))).await.unwrap();
+        assert_eq!(addr, v6);
+        // Both failing reports the preferred address's error
+        assert_eq!(race(v6, Some(v4), delay, dial(Some(Err(60)), Err(0))).await.unwrap_err(), v6);
+        assert_eq!(race(v4, None, delay, dial(None, Err(0))).await.unwrap_err(), v4);
+    }
+}
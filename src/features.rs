@@ -0,0 +1,186 @@
+//! Runtime feature flags
+//!
+//! Newer forwarding subsystems can be switched off and on per route while
+//! the proxy runs, so they can be rolled out to one low-risk route first and
+//! flipped back instantly without a restart. A flag only gates a subsystem
+//! that is configured at all (`--sockmap`, a spinning `--idle-policy`);
+//! features are on wherever no rule says otherwise.
+//!
+//! Startup rules come from `--feature [DEST=][no-]NAME`; the admin API adds
+//! overrides on top, which win over them and can be reset. Within each set
+//! the first rule matching the feature and destination wins, and an
+//! override for the same feature and destination replaces the older one.
+//! Connections read the flags when they are set up, so flipping one
+//! affects new connections only.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{OnceLock, RwLock};
+
+use crate::route::{Destination, RouteRule};
+
+/// A subsystem that can be gated per route
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    /// Kernel splicing of connection legs (`--sockmap`)
+    Sockmap,
+    /// Polling idle connections instead of parking (`--idle-policy`)
+    Spin,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 2] = [Feature::Sockmap, Feature::Spin];
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Feature::Sockmap => write!(f, "sockmap"),
+            Feature::Spin => write!(f, "spin"),
+        }
+    }
+}
+
+impl FromStr for Feature {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Feature::ALL
+            .into_iter()
+            .find(|feature| feature.to_string() == s)
+            .ok_or_else(|| format!("unknown feature '{}' (expected sockmap or spin)", s))
+    }
+}
+
+/// A feature switched on or off, as in `sockmap` or `no-sockmap`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeatureSetting {
+    pub feature: Feature,
+    pub enabled: bool,
+}
+
+impl fmt::Display for FeatureSetting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.enabled {
+            true => write!(f, "{}", self.feature),
+            false => write!(f, "no-{}", self.feature),
+        }
+    }
+}
+
+impl FromStr for FeatureSetting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, enabled) = match s.strip_prefix("no-") {
+            Some(name) => (name, false),
+            None => (s, true),
+        };
+        Ok(Self {
+            feature: name.parse()?,
+            enabled,
+        })
+    }
+}
+
+/// One `--feature` argument
+pub type FeatureRule = RouteRule<FeatureSetting>;
+
+#[derive(Debug, Default)]
+pub struct Flags {
+    rules: RwLock<Vec<FeatureRule>>,
+    overrides: RwLock<Vec<FeatureRule>>,
+}
+
+impl Flags {
+    /// Replace the startup rules
+    pub fn configure(&self, rules: Vec<FeatureRule>) {
+        *self.rules.write().unwrap() = rules;
+    }
+
+    /// Whether `feature` is enabled for connections to `destination`
+    pub fn enabled(&self, feature: Feature, destination: SocketAddr) -> bool {
+        let find = |rules: &[FeatureRule]| {
+            rules
+                .iter()
+                .find(|rule| rule.value.feature == feature && rule.destination.matches(destination))
+                .map(|rule| rule.value.enabled)
+        };
+        find(&self.overrides.read().unwrap())
+            .or_else(|| find(&self.rules.read().unwrap()))
+            .unwrap_or(true)
+    }
+
+    /// Switch a feature for a destination, ahead of every other rule
+    pub fn set_override(&self, destination: Destination, setting: FeatureSetting) {
+        let mut overrides = self.overrides.write().unwrap();
+        overrides.retain(|rule| !(rule.destination == destination && rule.value.feature == setting.feature));
+        overrides.insert(0, RouteRule {
+            destination,
+            value: setting,
+        });
+    }
+
+    /// Drop the override for a feature and destination; false if there was
+    /// none
+    pub fn reset_override(&self, destination: Destination, feature: Feature) -> bool {
+        let mut overrides = self.overrides.write().unwrap();
+        let before = overrides.len();
+        overrides.retain(|rule| !(rule.destination == destination && rule.value.feature == feature));
+        overrides.len() != before
+    }
+
+    /// Overrides, then startup rules, one per line in the order they are
+    /// consulted
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let sets = [("override", &self.overrides), ("startup", &self.rules)];
+        for (source, rules) in sets {
+            for rule in rules.read().unwrap().iter() {
+                let destination = match rule.destination {
+                    Destination::Any => "*".to_string(),
+                    Destination::Host(ip) => ip.to_string(),
+                    Destination::Service(addr) => addr.to_string(),
+                };
+                out.push_str(&format!("{} {} {}\n", source, destination, rule.value));
+            }
+        }
+        out
+    }
+}
+
+/// The global flags
+pub fn flags() -> &'static Flags {
+    static FLAGS: OnceLock<Flags> = OnceLock::new();
+    FLAGS.get_or_init(Flags::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rollout_and_override() {
+        let flags = Flags::default();
+        // Splice only the canary route
+        flags.configure(vec!["10.1.0.5:9000=sockmap".parse().unwrap(), "no-sockmap".parse().unwrap()]);
+        let canary: SocketAddr = "10.1.0.5:9000".parse().unwrap();
+        let other: SocketAddr = "10.1.0.6:9000".parse().unwrap();
+        assert!(flags.enabled(Feature::Sockmap, canary));
+        assert!(!flags.enabled(Feature::Sockmap, other));
+        assert!(flags.enabled(Feature::Spin, other));
+
+        // Flip the canary back, then undo that
+        let host = Destination::Host(canary.ip());
+        let off = "no-sockmap".parse().unwrap();
+        flags.set_override(host, off);
+        assert!(!flags.enabled(Feature::Sockmap, canary));
+        assert_eq!(flags.render().lines().next(), Some("override 10.1.0.5 no-sockmap"));
+        assert!(flags.reset_override(host, Feature::Sockmap));
+        assert!(!flags.reset_override(host, Feature::Sockmap));
+        assert!(flags.enabled(Feature::Sockmap, canary));
+
+        assert!("no-io_uring".parse::<FeatureSetting>().is_err());
+    }
+}
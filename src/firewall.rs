@@ -0,0 +1,369 @@
+//! Firewall rules for transparent deployment (Linux only)
+//!
+//! To put the socket proxy in the path of traffic that is not addressed to
+//! it, the host's firewall has to steer the intercepted ports into the
+//! listener. This module renders the rules for nftables or iptables and,
+//! with `--manage-firewall`, installs them at startup and removes them
+//! again on shutdown, so a stopped proxy never leaves traffic pointing at
+//! a closed port.
+//!
+//! Two ways of steering are supported:
+//!
+//! - REDIRECT rewrites the destination to the proxy's port (NAT); the
+//!   listener needs nothing special.
+//! - TPROXY delivers the packets unmodified to the proxy's listening
+//!   socket, which must be IP_TRANSPARENT, and needs a policy route that
+//!   treats the marked packets as local.
+//!
+//! Only traffic arriving from the network (PREROUTING) is intercepted, so
+//! the proxy's own upstream connections are never looped back into it.
+//! Everything lives in a table or chain named `tcpstrip`; leftovers from a
+//! proxy that did not shut down cleanly are removed before installing.
+//! Requires CAP_NET_ADMIN and the `nft` or `iptables` and `ip` binaries.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::process::{Command as Process, Stdio};
+use std::str::FromStr;
+
+use tracing::{info, warn};
+
+/// Name of the nftables table and iptables chain holding the rules
+const NAME: &str = "tcpstrip";
+/// Firewall mark and routing table for TPROXY'd packets
+const TPROXY_MARK: u32 = 0x7474;
+const TPROXY_TABLE: u32 = 7474;
+
+/// Tool the rules are installed with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    #[default]
+    Nftables,
+    Iptables,
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Nftables => write!(f, "nftables"),
+            Backend::Iptables => write!(f, "iptables"),
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nftables" | "nft" => Ok(Backend::Nftables),
+            "iptables" => Ok(Backend::Iptables),
+            _ => Err(format!("unknown firewall backend '{}' (expected nftables or iptables)", s)),
+        }
+    }
+}
+
+/// How intercepted traffic reaches the proxy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InterceptMode {
+    /// Destination NAT to the proxy's port
+    #[default]
+    Redirect,
+    /// Transparent delivery to an IP_TRANSPARENT listener
+    Tproxy,
+}
+
+impl fmt::Display for InterceptMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterceptMode::Redirect => write!(f, "redirect"),
+            InterceptMode::Tproxy => write!(f, "tproxy"),
+        }
+    }
+}
+
+impl FromStr for InterceptMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "redirect" => Ok(InterceptMode::Redirect),
+            "tproxy" => Ok(InterceptMode::Tproxy),
+            _ => Err(format!("unknown intercept mode '{}' (expected redirect or tproxy)", s)),
+        }
+    }
+}
+
+/// What to intercept and where to send it
+#[derive(Debug, Clone)]
+pub struct FirewallConfig {
+    pub backend: Backend,
+    pub mode: InterceptMode,
+    /// Port the proxy listens on
+    pub proxy_port: u16,
+    /// TCP destination ports to steer into the proxy
+    pub ports: Vec<u16>,
+    /// Only intercept traffic arriving on this interface
+    pub interface: Option<String>,
+}
+
+/// One external command, with optional standard input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Command {
+    pub program: &'static str,
+    pub args: Vec<String>,
+    pub input: Option<String>,
+}
+
+impl Command {
+    fn new(program: &'static str, args: &[&str]) -> Self {
+        Self {
+            program,
+            args: args.iter().map(|a| a.to_string()).collect(),
+            input: None,
+        }
+    }
+
+    fn run(&self) -> io::Result<()> {
+        let mut child = Process::new(self.program)
+            .args(&self.args)
+            .stdin(if self.input.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", self.program, e)))?;
+        if let (Some(input), Some(mut stdin)) = (&self.input, child.stdin.take()) {
+            stdin.write_all(input.as_bytes())?;
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(io::Error::other(format!("`{}` failed: {}", self, stderr.trim())));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.program)?;
+        for arg in &self.args {
+            if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || "\"'$;&|<>".contains(c)) {
+                write!(f, " '{}'", arg)?;
+            } else {
+                write!(f, " {}", arg)?;
+            }
+        }
+        if let Some(input) = &self.input {
+            write!(f, " <<'EOF'\n{}EOF", input)?;
+        }
+        Ok(())
+    }
+}
+
+/// Commands that install the rules, and the ones that take them down again
+#[derive(Debug, Clone)]
+pub struct RulePlan {
+    pub install: Vec<Command>,
+    pub remove: Vec<Command>,
+}
+
+impl RulePlan {
+    pub fn new(config: &FirewallConfig) -> Self {
+        let (mut install, mut remove) = match config.backend {
+            Backend::Nftables => nftables(config),
+            Backend::Iptables => iptables(config),
+        };
+        if config.mode == InterceptMode::Tproxy {
+            // Marked packets must be routed to the local stack even though
+            // their destination is not a local address
+            let mark = format!("{:#x}", TPROXY_MARK);
+            let table = TPROXY_TABLE.to_string();
+            install.push(Command::new("ip", &["rule", "add", "fwmark", &mark, "lookup", &table]));
+            install.push(Command::new("ip", &["route", "add", "local", "0.0.0.0/0", "dev", "lo", "table", &table]));
+            remove.push(Command::new("ip", &["route", "del", "local", "0.0.0.0/0", "dev", "lo", "table", &table]));
+            remove.push(Command::new("ip", &["rule", "del", "fwmark", &mark, "lookup", &table]));
+        }
+        Self { install, remove }
+    }
+}
+
+impl fmt::Display for RulePlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# install")?;
+        for command in &self.install {
+            writeln!(f, "{}", command)?;
+        }
+        writeln!(f, "# remove on shutdown")?;
+        for command in &self.remove {
+            writeln!(f, "{}", command)?;
+        }
+        Ok(())
+    }
+}
+
+fn nftables(config: &FirewallConfig) -> (Vec<Command>, Vec<Command>) {
+    let ports = config.ports.iter().map(u16::to_string).collect::<Vec<_>>().join(", ");
+    let iif = match &config.interface {
+        Some(name) => format!("iifname \"{}\" ", name),
+        None => String::new(),
+    };
+    let (chain, rules) = match config.mode {
+        InterceptMode::Redirect => (
+            "type nat hook prerouting priority dstnat; policy accept;",
+            format!("\t\t{}tcp dport {{ {} }} redirect to :{}\n", iif, ports, config.proxy_port),
+        ),
+        InterceptMode::Tproxy => (
+            "type filter hook prerouting priority mangle; policy accept;",
+            format!(
+                "\t\t{iif}meta l4proto tcp socket transparent 1 meta mark set {mark:#x} accept\n\
+                 \t\t{iif}tcp dport {{ {ports} }} meta mark set {mark:#x} tproxy to :{port} accept\n",
+                iif = iif,
+                mark = TPROXY_MARK,
+                ports = ports,
+                port = config.proxy_port
+            ),
+        ),
+    };
+    let script = format!(
+        "table ip {name} {{\n\tchain prerouting {{\n\t\t{chain}\n{rules}\t}}\n}}\n",
+        name = NAME,
+        chain = chain,
+        rules = rules
+    );
+
+    let mut install = Command::new("nft", &["-f", "-"]);
+    install.input = Some(script);
+    let remove = Command::new("nft", &["delete", "table", "ip", NAME]);
+    (vec![install], vec![remove])
+}
+
+fn iptables(config: &FirewallConfig) -> (Vec<Command>, Vec<Command>) {
+    let chain = NAME.to_uppercase();
+    let proxy_port = config.proxy_port.to_string();
+    let mark = format!("{:#x}", TPROXY_MARK);
+    let table = match config.mode {
+        InterceptMode::Redirect => "nat",
+        InterceptMode::Tproxy => "mangle",
+    };
+    let ipt = |args: &[&str]| {
+        let mut command = Command::new("iptables", &["-t", table]);
+        command.args.extend(args.iter().map(|a| a.to_string()));
+        command
+    };
+
+    let mut install = vec![ipt(&["-N", &chain])];
+    if config.mode == InterceptMode::Tproxy {
+        install.push(ipt(&["-A", &chain, "-p", "tcp", "-m", "socket", "--transparent", "-j", "MARK", "--set-mark", &mark]));
+        install.push(ipt(&["-A", &chain, "-m", "mark", "--mark", &mark, "-j", "ACCEPT"]));
+    }
+    for port in &config.ports {
+        let port = port.to_string();
+        let mut rule = vec!["-A", &chain, "-p", "tcp", "--dport", &port];
+        match config.mode {
+            InterceptMode::Redirect => rule.extend(["-j", "REDIRECT", "--to-ports", &proxy_port]),
+            InterceptMode::Tproxy => rule.extend(["-j", "TPROXY", "--on-port", &proxy_port, "--tproxy-mark", &mark]),
+        }
+        install.push(ipt(&rule));
+    }
+
+    let mut hook = vec!["PREROUTING"];
+    if let Some(name) = &config.interface {
+        hook.extend(["-i", name.as_str()]);
+    }
+    hook.extend(["-j", &chain]);
+    install.push(ipt(&[&["-A"], hook.as_slice()].concat()));
+
+    let remove = vec![
+        ipt(&[&["-D"], hook.as_slice()].concat()),
+        ipt(&["-F", &chain]),
+        ipt(&["-X", &chain]),
+    ];
+    (install, remove)
+}
+
+/// Installed rules; removed when dropped
+pub struct InstalledRules {
+    plan: RulePlan,
+}
+
+impl Drop for InstalledRules {
+    fn drop(&mut self) {
+        let mut clean = true;
+        for command in &self.plan.remove {
+            if let Err(e) = command.run() {
+                warn!("Removing firewall rules: {}", e);
+                clean = false;
+            }
+        }
+        if clean {
+            info!("Firewall rules removed");
+        }
+    }
+}
+
+/// Install the rules for `config`, replacing any left behind by an earlier run
+pub fn install(config: &FirewallConfig) -> io::Result<InstalledRules> {
+    if config.ports.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no ports to intercept"));
+    }
+    let plan = RulePlan::new(config);
+    for command in &plan.remove {
+        let _ = command.run();
+    }
+
+    // Once the first command went through, dropping the guard undoes
+    // whatever was installed
+    plan.install[0].run()?;
+    let rules = InstalledRules { plan };
+    for command in &rules.plan.install[1..] {
+        command.run()?;
+    }
+    info!(
+        "Installed {} {} rules steering TCP ports {:?} into port {}",
+        config.backend, config.mode, config.ports, config.proxy_port
+    );
+    Ok(rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(backend: Backend, mode: InterceptMode) -> FirewallConfig {
+        FirewallConfig {
+            backend,
+            mode,
+            proxy_port: 8080,
+            ports: vec![80, 443],
+            interface: Some("eth1".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_nftables_redirect() {
+        let plan = RulePlan::new(&config(Backend::Nftables, InterceptMode::Redirect));
+        assert_eq!(plan.install.len(), 1);
+        let script = plan.install[0].input.as_deref().unwrap();
+        assert!(script.starts_with("table ip tcpstrip {\n"));
+        assert!(script.contains("type nat hook prerouting priority dstnat;"));
+        assert!(script.contains("iifname \"eth1\" tcp dport { 80, 443 } redirect to :8080\n"));
+        assert_eq!(plan.remove[0].to_string(), "nft delete table ip tcpstrip");
+        assert!(plan.to_string().contains("nft -f - <<'EOF'\ntable ip tcpstrip {"));
+    }
+
+    #[test]
+    fn test_iptables_tproxy() {
+        let plan = RulePlan::new(&config(Backend::Iptables, InterceptMode::Tproxy));
+        let install: Vec<_> = plan.install.iter().map(Command::to_string).collect();
+        let remove: Vec<_> = plan.remove.iter().map(Command::to_string).collect();
+        assert_eq!(install[0], "iptables -t mangle -N TCPSTRIP");
+        assert!(install.contains(
+            &"iptables -t mangle -A TCPSTRIP -p tcp --dport 443 -j TPROXY --on-port 8080 --tproxy-mark 0x7474".to_string()
+        ));
+        assert!(install.contains(&"iptables -t mangle -A PREROUTING -i eth1 -j TCPSTRIP".to_string()));
+        assert!(install.contains(&"ip rule add fwmark 0x7474 lookup 7474".to_string()));
+        assert_eq!(remove[0], "iptables -t mangle -D PREROUTING -i eth1 -j TCPSTRIP");
+        assert_eq!(remove.last().unwrap(), "ip rule del fwmark 0x7474 lookup 7474");
+    }
+}
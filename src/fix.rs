@@ -0,0 +1,208 @@
+//! FIX Logon checks
+//!
+//! Exchanges count failed logons against a member, and every connection
+//! the proxy accepts normally costs a gateway session at once. On guarded
+//! routes the socket proxy therefore holds off dialing until the client's
+//! first message is a plausible Logon: a known (or the configured)
+//! BeginString, a BodyLength matching the message, MsgType A and a correct
+//! CheckSum. Anything else is dropped without the gateway ever seeing it.
+//! The message is only peeked at and reaches the gateway as sent.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// BeginStrings of the FIX versions the guard recognises
+pub const BEGIN_STRINGS: &[&str] = &["FIX.4.0", "FIX.4.1", "FIX.4.2", "FIX.4.3", "FIX.4.4", "FIXT.1.1"];
+
+/// Longest Logon the guard waits for
+pub const MAX_LOGON_LEN: usize = 4096;
+
+pub(crate) const SOH: u8 = 0x01;
+
+/// What a guarded route expects of a client's Logon
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogonGuard {
+    /// BeginString the gateway speaks; any known one if None
+    pub begin_string: Option<String>,
+}
+
+impl fmt::Display for LogonGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.begin_string {
+            Some(begin_string) => write!(f, "{}", begin_string),
+            None => write!(f, "any"),
+        }
+    }
+}
+
+impl FromStr for LogonGuard {
+    type Err = String;
+
+    /// `any` or a BeginString such as `FIX.4.4`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(LogonGuard::default()),
+            _ if BEGIN_STRINGS.contains(&s) => Ok(LogonGuard {
+                begin_string: Some(s.to_string()),
+            }),
+            _ => Err(format!("unknown BeginString '{}' (expected any or one of {})", s, BEGIN_STRINGS.join(", "))),
+        }
+    }
+}
+
+/// Why a client's first bytes are not an acceptable Logon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogonError {
+    /// Not all of the message has arrived (yet)
+    Incomplete,
+    BeginString,
+    BodyLength,
+    MsgType,
+    CheckSum,
+}
+
+impl fmt::Display for LogonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogonError::Incomplete => write!(f, "incomplete"),
+            LogonError::BeginString => write!(f, "begin_string"),
+            LogonError::BodyLength => write!(f, "body_length"),
+            LogonError::MsgType => write!(f, "msg_type"),
+            LogonError::CheckSum => write!(f, "checksum"),
+        }
+    }
+}
+
+/// One `tag=value<SOH>` field at the start of `buf`: the value and the
+/// length of the whole field, or None if its SOH has not arrived
+fn field<'a>(buf: &'a [u8], tag: &[u8]) -> Option<Result<(&'a [u8], usize), ()>> {
+    let prefix_len = tag.len() + 1;
+    if buf.len() < prefix_len {
+        // Compare what there is, so garbage is not mistaken for a partial
+        // message
+        let prefix = [tag, b"="].concat();
+        return match prefix.starts_with(buf) {
+            true => None,
+            false => Some(Err(())),
+        };
+    }
+    if !buf.starts_with(tag) || buf[tag.len()] != b'=' {
+        return Some(Err(()));
+    }
+    let end = buf[prefix_len..].iter().position(|&b| b == SOH)?;
+    Some(Ok((&buf[prefix_len..prefix_len + end], prefix_len + end + 1)))
+}
+
+/// Check that `buf` starts with a complete, plausible FIX Logon and return
+/// its length
+///
+/// Fields are checked in order as they arrive, so garbage is rejected
+/// before the rest of a message could have come in.
+pub fn check_logon(buf: &[u8], guard: &LogonGuard) -> Result<usize, LogonError> {
+    let incomplete = |pos: usize, max: usize| match pos < max {
+        true => LogonError::Incomplete,
+        false => LogonError::BodyLength,
+    };
+
+    // 8=BeginString
+    let (begin_string, mut pos) = match field(buf, b"8") {
+        None if buf.len() < MAX_LOGON_LEN => return Err(LogonError::Incomplete),
+        None | Some(Err(())) => return Err(LogonError::BeginString),
+        Some(Ok(field)) => field,
+    };
+    let accepted = match &guard.begin_string {
+        Some(expected) => begin_string == expected.as_bytes(),
+        None => BEGIN_STRINGS.iter().any(|known| begin_string == known.as_bytes()),
+    };
+    if !accepted {
+        return Err(LogonError::BeginString);
+    }
+
+    // 9=BodyLength, counting from the next field up to the CheckSum's
+    let (body_length, len) = match field(&buf[pos..], b"9") {
+        None => return Err(incomplete(buf.len(), MAX_LOGON_LEN)),
+        Some(Err(())) => return Err(LogonError::BodyLength),
+        Some(Ok(field)) => field,
+    };
+    pos += len;
+    let body_length: usize = std::str::from_utf8(body_length)
+        .ok()
+        .filter(|digits| !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|digits| digits.parse().ok())
+        .ok_or(LogonError::BodyLength)?;
+    // The CheckSum field is always 7 bytes
+    let body_end = pos + body_length;
+    if body_end + 7 > MAX_LOGON_LEN {
+        return Err(LogonError::BodyLength);
+    }
+
+    // 35=A must come first in the body
+    match field(&buf[pos..body_end.min(buf.len())], b"35") {
+        None if buf.len() < body_end => return Err(LogonError::Incomplete),
+        None | Some(Err(())) => return Err(LogonError::MsgType),
+        Some(Ok((msg_type, _))) if msg_type != b"A" => return Err(LogonError::MsgType),
+        Some(Ok(_)) => {}
+    }
+
+    // 10=NNN right where BodyLength says the body ends
+    if buf.len() < body_end {
+        return Err(LogonError::Incomplete);
+    }
+    let checksum = match field(&buf[body_end..], b"10") {
+        None if buf.len() < body_end + 7 => return Err(LogonError::Incomplete),
+        None | Some(Err(())) => return Err(LogonError::BodyLength),
+        Some(Ok((checksum, 7))) => checksum,
+        Some(Ok(_)) => return Err(LogonError::CheckSum),
+    };
+    let sum = buf[..body_end].iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    match std::str::from_utf8(checksum).ok().and_then(|digits| digits.parse::<u8>().ok()) {
+        Some(expected) if expected == sum => Ok(body_end + 7),
+        _ => Err(LogonError::CheckSum),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A message with correct BodyLength and CheckSum
+    fn message(begin_string: &str, body: &str) -> Vec<u8> {
+        let head = format!("8={}\x019={}\x01{}", begin_string, body.len(), body);
+        let sum = head.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        format!("{}10={:03}\x01", head, sum).into_bytes()
+    }
+
+    #[test]
+    fn test_check_logon() {
+        let any = LogonGuard::default();
+        let logon = message("FIX.4.4", "35=A\x0149=CLIENT\x0156=VENUE\x0134=1\x0152=20261015-14:00:00\x0198=0\x01108=30\x01");
+        assert_eq!(check_logon(&logon, &any), Ok(logon.len()));
+        assert_eq!(check_logon(&logon, &"FIXT.1.1".parse().unwrap()), Err(LogonError::BeginString));
+
+        // Every prefix is merely incomplete, and trailing messages are fine
+        for end in 0..logon.len() {
+            assert_eq!(check_logon(&logon[..end], &any), Err(LogonError::Incomplete), "prefix of {}", end);
+        }
+        let mut pipelined = logon.clone();
+        pipelined.extend_from_slice(b"8=FIX.4.4\x01");
+        assert_eq!(check_logon(&pipelined, &any), Ok(logon.len()));
+
+        // Garbage is rejected as soon as it shows
+        assert_eq!(check_logon(b"GET / HTTP/1.1\r\n", &any), Err(LogonError::BeginString));
+        assert_eq!(check_logon(b"8=FIX.9.9\x01", &any), Err(LogonError::BeginString));
+        assert_eq!(check_logon(b"8=FIX.4.4\x019=x\x01", &any), Err(LogonError::BodyLength));
+        assert_eq!(check_logon(b"8=FIX.4.4\x019=999999\x01", &any), Err(LogonError::BodyLength));
+        let heartbeat = message("FIX.4.4", "35=0\x0149=CLIENT\x01");
+        assert_eq!(check_logon(&heartbeat, &any), Err(LogonError::MsgType));
+
+        let mut corrupted = logon.clone();
+        let at = corrupted.len() - 3;
+        corrupted[at] = if corrupted[at] == b'0' { b'1' } else { b'0' };
+        assert_eq!(check_logon(&corrupted, &any), Err(LogonError::CheckSum));
+        let mut short_body = message("FIX.4.4", "35=A\x0198=0\x01");
+        short_body.insert(short_body.len() - 8, b'x');
+        assert_eq!(check_logon(&short_body, &any), Err(LogonError::BodyLength));
+
+        assert!("FIX.5.0".parse::<LogonGuard>().is_err());
+    }
+}
@@ -0,0 +1,610 @@
+//! FIX session observation (`--fix-observe`)
+//!
+//! On observed routes the userspace relay reads the FIX messages both sides
+//! send as the bytes go past, after writing them on and without changing
+//! any. Only a few session fields are looked at: BeginString (8), MsgType
+//! (35), MsgSeqNum (34), PossDupFlag (43), HeartBtInt (108),
+//! ResetSeqNumFlag (141), the NewSeqNo (36) and GapFillFlag (123) of a
+//! SequenceReset, and the CompIDs (49, 56) of the Logon. Fields are
+//! scanned as they arrive, so a message split across reads needs no
+//! buffering and forwarding still does not allocate per message.
+//!
+//! Each side's MsgSeqNum is followed from its first message: a number above
+//! the expected one is a gap, one below it that is not a PossDup resend is
+//! a regression. Logons with ResetSeqNumFlag and SequenceResets move the
+//! expectation as the protocol does. Once a Logon set HeartBtInt, a side
+//! silent for longer than the interval plus a fifth is late; that is
+//! counted when its next message arrives, or when the connection closes
+//! without the side having sent a Logout.
+//!
+//! Live sessions are listed by the admin listener's `/fix` with their
+//! CompIDs, last sequence numbers and how long each side has been silent.
+//! Messages are counted in `tcpstrip_fix_messages_total{msg_type}`, Logons
+//! and Logouts in `tcpstrip_fix_logons_total` and
+//! `tcpstrip_fix_logouts_total`, sequence problems in
+//! `tcpstrip_fix_sequence_errors_total{kind}` (`gap` or `low`), late
+//! heartbeats in `tcpstrip_fix_late_heartbeats_total` and bytes that are
+//! not FIX in `tcpstrip_fix_malformed_total`.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use crate::fix::SOH;
+use crate::metrics::{self, LabeledMetric, Metric};
+
+/// Longest field value kept; longer ones are cut, and are no numbers
+const MAX_VALUE_LEN: usize = 32;
+
+/// Which side of the connection sent a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Client to server
+    Upstream,
+    /// Server to client
+    Downstream,
+}
+
+impl Direction {
+    fn index(self) -> usize {
+        match self {
+            Direction::Upstream => 0,
+            Direction::Downstream => 1,
+        }
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Upstream => write!(f, "client->server"),
+            Direction::Downstream => write!(f, "server->client"),
+        }
+    }
+}
+
+/// A field value, kept inline so scanning never allocates
+#[derive(Debug, Default, Clone, Copy)]
+struct Value {
+    bytes: [u8; MAX_VALUE_LEN],
+    len: usize,
+    truncated: bool,
+}
+
+impl Value {
+    fn push(&mut self, byte: u8) {
+        match self.len < MAX_VALUE_LEN {
+            true => {
+                self.bytes[self.len] = byte;
+                self.len += 1;
+            }
+            false => self.truncated = true,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    fn as_str(&self) -> &str {
+        std::str::from_utf8(self.as_bytes()).unwrap_or("?")
+    }
+
+    fn number(&self) -> Option<u64> {
+        if self.truncated || self.len == 0 {
+            return None;
+        }
+        self.as_bytes()
+            .iter()
+            .try_fold(0u64, |n, &byte| byte.is_ascii_digit().then(|| n.checked_mul(10)?.checked_add((byte - b'0') as u64))?)
+    }
+
+    fn flag(&self) -> bool {
+        self.as_bytes() == b"Y"
+    }
+}
+
+/// The fields of one message the session follows
+#[derive(Debug, Default, Clone, Copy)]
+struct Message {
+    begin_string: Value,
+    msg_type: Value,
+    seq: Option<u64>,
+    poss_dup: bool,
+    heartbeat: Option<u64>,
+    reset_seq: bool,
+    new_seq: Option<u64>,
+    gap_fill: bool,
+    sender: Value,
+    target: Value,
+}
+
+/// What the scanner found
+#[derive(Debug)]
+enum Scanned<'a> {
+    Message(&'a Message),
+    /// Bytes that are not FIX; reported once until a message scans again
+    Malformed,
+}
+
+/// Incremental tag=value scanner for one direction
+#[derive(Debug, Default)]
+struct Parser {
+    /// Past a BeginString, before the CheckSum
+    in_message: bool,
+    /// Reading the value of `tag`, else its digits
+    in_value: bool,
+    tag: u32,
+    /// The current field is not tag=value; skip to its SOH
+    skipping: bool,
+    /// Malformed input was reported since the last message
+    lost: bool,
+    value: Value,
+    message: Message,
+}
+
+impl Parser {
+    fn feed(&mut self, bytes: &[u8], mut found: impl FnMut(Scanned<'_>)) {
+        for &byte in bytes {
+            if self.skipping {
+                self.skipping = byte != SOH;
+                continue;
+            }
+            if self.in_value {
+                match byte {
+                    SOH => {
+                        self.in_value = false;
+                        self.field(&mut found);
+                        self.tag = 0;
+                    }
+                    _ => self.value.push(byte),
+                }
+                continue;
+            }
+            match byte {
+                b'0'..=b'9' if self.tag < 100_000_000 => self.tag = self.tag * 10 + (byte - b'0') as u32,
+                b'=' if self.tag > 0 => {
+                    self.in_value = true;
+                    self.value = Value::default();
+                }
+                _ => {
+                    self.malformed(&mut found);
+                    self.skipping = byte != SOH;
+                    self.tag = 0;
+                }
+            }
+        }
+    }
+
+    /// Take in the field just completed
+    fn field(&mut self, found: &mut impl FnMut(Scanned<'_>)) {
+        let (value, message) = (&self.value, &mut self.message);
+        match self.tag {
+            8 => {
+                if self.in_message {
+                    // The previous message never got its CheckSum
+                    self.malformed(found);
+                }
+                self.in_message = true;
+                self.message = Message {
+                    begin_string: self.value,
+                    ..Message::default()
+                };
+            }
+            _ if !self.in_message => self.malformed(found),
+            10 => {
+                self.in_message = false;
+                match message.msg_type.len {
+                    0 => self.malformed(found),
+                    _ => {
+                        self.lost = false;
+                        found(Scanned::Message(&self.message));
+                    }
+                }
+            }
+            34 => message.seq = value.number(),
+            35 => message.msg_type = *value,
+            36 => message.new_seq = value.number(),
+            43 => message.poss_dup = value.flag(),
+            49 => message.sender = *value,
+            56 => message.target = *value,
+            108 => message.heartbeat = value.number(),
+            123 => message.gap_fill = value.flag(),
+            141 => message.reset_seq = value.flag(),
+            _ => {}
+        }
+    }
+
+    fn malformed(&mut self, found: &mut impl FnMut(Scanned<'_>)) {
+        self.in_message = false;
+        if !self.lost {
+            self.lost = true;
+            found(Scanned::Malformed);
+        }
+    }
+}
+
+/// What one side of a session has sent
+#[derive(Debug, Default)]
+struct Side {
+    messages: u64,
+    /// MsgSeqNum its next new message should carry
+    expected: Option<u64>,
+    last_seq: Option<u64>,
+    last_message: Option<Instant>,
+    logged_on: bool,
+    logged_out: bool,
+    sequence_errors: u64,
+    late: u64,
+}
+
+impl Side {
+    fn state(&self) -> &'static str {
+        match (self.logged_on, self.logged_out) {
+            (_, true) => "logged-out",
+            (true, false) => "logged-on",
+            (false, false) => "-",
+        }
+    }
+
+    /// How long the side had been silent at `now`, if that is longer than
+    /// `heartbeat` allows
+    fn late_by(&self, heartbeat: Option<Duration>, now: Instant) -> Option<Duration> {
+        let (heartbeat, last) = (heartbeat?, self.last_message?);
+        let silent = now.saturating_duration_since(last);
+        (silent > heartbeat + heartbeat / 5).then_some(silent)
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    begin_string: Option<String>,
+    /// CompIDs of the client's Logon
+    sender_comp_id: Option<String>,
+    target_comp_id: Option<String>,
+    heartbeat: Option<Duration>,
+    sides: [Side; 2],
+}
+
+/// Metrics a session updates, looked up once so messages only count
+#[derive(Debug)]
+struct Metrics {
+    messages: Arc<LabeledMetric>,
+    logons: Arc<Metric>,
+    logouts: Arc<Metric>,
+    sequence_errors: Arc<LabeledMetric>,
+    late_heartbeats: Arc<Metric>,
+    malformed: Arc<Metric>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = metrics::registry();
+        Self {
+            messages: registry.labeled_counter("tcpstrip_fix_messages_total", "FIX messages seen on observed connections, by MsgType", "msg_type"),
+            logons: registry.counter("tcpstrip_fix_logons_total", "FIX Logons seen on observed connections"),
+            logouts: registry.counter("tcpstrip_fix_logouts_total", "FIX Logouts seen on observed connections"),
+            sequence_errors: registry.labeled_counter(
+                "tcpstrip_fix_sequence_errors_total",
+                "FIX MsgSeqNums above (gap) or below (low) the expected one",
+                "kind",
+            ),
+            late_heartbeats: registry.counter(
+                "tcpstrip_fix_late_heartbeats_total",
+                "FIX sides silent for longer than their heartbeat interval allows",
+            ),
+            malformed: registry.counter("tcpstrip_fix_malformed_total", "Runs of bytes on observed connections that are not FIX"),
+        }
+    }
+}
+
+/// A FIX session observed on one proxied connection
+#[derive(Debug)]
+pub struct Session {
+    pub conn_id: u64,
+    pub client: SocketAddr,
+    pub target: SocketAddr,
+    state: Mutex<State>,
+    metrics: Metrics,
+}
+
+impl Session {
+    /// An observer for the bytes `direction` carries
+    pub fn observer(&self, direction: Direction) -> Observer<'_> {
+        Observer {
+            session: self,
+            direction,
+            parser: Parser::default(),
+        }
+    }
+
+    fn message(&self, direction: Direction, message: &Message, now: Instant) {
+        let msg_type = message.msg_type.as_bytes();
+        self.metrics.messages.with(message.msg_type.as_str()).inc();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let state = &mut *state;
+        if let Some(silent) = state.sides[direction.index()].late_by(state.heartbeat, now) {
+            self.late(direction, &mut state.sides[direction.index()], silent, state.heartbeat);
+        }
+
+        if msg_type == b"A" {
+            if let Some(interval) = message.heartbeat {
+                state.heartbeat = (interval > 0).then(|| Duration::from_secs(interval));
+            }
+            if direction == Direction::Upstream {
+                state.begin_string = Some(message.begin_string.as_str().to_string());
+                state.sender_comp_id = Some(message.sender.as_str().to_string());
+                state.target_comp_id = Some(message.target.as_str().to_string());
+            }
+            self.metrics.logons.inc();
+            info!(
+                "Connection {}: FIX Logon from {} to {} ({}), heartbeat {}s",
+                self.conn_id,
+                message.sender.as_str(),
+                message.target.as_str(),
+                message.begin_string.as_str(),
+                message.heartbeat.unwrap_or_default()
+            );
+        }
+        let side = &mut state.sides[direction.index()];
+        side.messages += 1;
+        side.last_message = Some(now);
+        match msg_type {
+            b"A" => {
+                side.logged_on = true;
+                side.logged_out = false;
+                if message.reset_seq {
+                    side.expected = Some(1);
+                }
+            }
+            b"5" => {
+                side.logged_out = true;
+                self.metrics.logouts.inc();
+                info!("Connection {}: FIX Logout {}", self.conn_id, direction);
+            }
+            _ => {}
+        }
+
+        // A SequenceReset without GapFillFlag sets the number whatever its own
+        let reset = msg_type == b"4" && !message.gap_fill;
+        if let Some(seq) = message.seq.filter(|_| !reset) {
+            match side.expected {
+                Some(expected) if !message.poss_dup && seq != expected => {
+                    let kind = if seq > expected { "gap" } else { "low" };
+                    side.sequence_errors += 1;
+                    self.metrics.sequence_errors.with(kind).inc();
+                    warn!("Connection {}: FIX {} MsgSeqNum {} where {} was expected", self.conn_id, direction, seq, expected);
+                }
+                _ => {}
+            }
+            if !message.poss_dup || side.expected.is_none() {
+                side.expected = Some(seq + 1);
+                side.last_seq = Some(seq);
+            }
+        }
+        if msg_type == b"4" {
+            if let Some(new_seq) = message.new_seq {
+                side.expected = Some(new_seq);
+            }
+        }
+    }
+
+    fn late(&self, direction: Direction, side: &mut Side, silent: Duration, heartbeat: Option<Duration>) {
+        side.late += 1;
+        self.metrics.late_heartbeats.inc();
+        warn!(
+            "Connection {}: FIX {} silent for {}ms, heartbeat interval {}s",
+            self.conn_id,
+            direction,
+            silent.as_millis(),
+            heartbeat.unwrap_or_default().as_secs()
+        );
+    }
+
+    /// Count sides that went quiet without logging out, at close
+    fn closed(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let heartbeat = state.heartbeat;
+        for direction in [Direction::Upstream, Direction::Downstream] {
+            let side = &mut state.sides[direction.index()];
+            if side.logged_on && !side.logged_out {
+                if let Some(silent) = side.late_by(heartbeat, now) {
+                    self.late(direction, side, silent, heartbeat);
+                }
+            }
+        }
+    }
+}
+
+/// Scans the bytes one direction of a session carries
+#[derive(Debug)]
+pub struct Observer<'a> {
+    session: &'a Session,
+    direction: Direction,
+    parser: Parser,
+}
+
+impl Observer<'_> {
+    /// Look at bytes that were just forwarded
+    pub fn observe(&mut self, bytes: &[u8]) {
+        self.observe_at(bytes, Instant::now());
+    }
+
+    fn observe_at(&mut self, bytes: &[u8], now: Instant) {
+        let (session, direction) = (self.session, self.direction);
+        self.parser.feed(bytes, |scanned| match scanned {
+            Scanned::Message(message) => session.message(direction, message, now),
+            Scanned::Malformed => session.metrics.malformed.inc(),
+        });
+    }
+}
+
+/// Live observed sessions, for the admin listener
+#[derive(Debug, Default)]
+pub struct Registry {
+    sessions: Mutex<BTreeMap<u64, Arc<Session>>>,
+}
+
+impl Registry {
+    /// Observe connection `conn_id`; it stays listed until the guard is
+    /// dropped
+    pub fn register(&'static self, conn_id: u64, client: SocketAddr, target: SocketAddr) -> SessionGuard {
+        let session = Arc::new(Session {
+            conn_id,
+            client,
+            target,
+            state: Mutex::default(),
+            metrics: Metrics::new(),
+        });
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner()).insert(conn_id, session.clone());
+        SessionGuard { registry: self, session }
+    }
+
+    /// Plain-text table of the live sessions, by connection id
+    pub fn render(&self) -> String {
+        let mut out = String::from("connection\tclient\ttarget\tbegin_string\tsender_comp_id\ttarget_comp_id\theartbeat_s");
+        for direction in ["up", "down"] {
+            let _ = write!(
+                out,
+                "\tstate_{0}\tmessages_{0}\tseq_{0}\tseq_errors_{0}\tlate_{0}\tsilent_ms_{0}",
+                direction
+            );
+        }
+        out.push('\n');
+        let now = Instant::now();
+        for session in self.sessions.lock().unwrap_or_else(|e| e.into_inner()).values() {
+            let state = session.state.lock().unwrap_or_else(|e| e.into_inner());
+            let _ = write!(
+                out,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                session.conn_id,
+                session.client,
+                session.target,
+                state.begin_string.as_deref().unwrap_or("-"),
+                state.sender_comp_id.as_deref().unwrap_or("-"),
+                state.target_comp_id.as_deref().unwrap_or("-"),
+                state.heartbeat.map_or("-".to_string(), |heartbeat| heartbeat.as_secs().to_string())
+            );
+            for side in &state.sides {
+                let _ = write!(
+                    out,
+                    "\t{}\t{}\t{}\t{}\t{}\t{}",
+                    side.state(),
+                    side.messages,
+                    side.last_seq.map_or("-".to_string(), |seq| seq.to_string()),
+                    side.sequence_errors,
+                    side.late,
+                    side.last_message.map_or("-".to_string(), |last| now.saturating_duration_since(last).as_millis().to_string())
+                );
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Removes a session from the registry when its connection ends
+#[derive(Debug)]
+pub struct SessionGuard {
+    registry: &'static Registry,
+    session: Arc<Session>,
+}
+
+impl SessionGuard {
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.session.closed(Instant::now());
+        self.registry.sessions.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.session.conn_id);
+    }
+}
+
+/// The global registry
+pub fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(Registry::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A FIX message from `|`-separated fields; BodyLength and CheckSum are
+    /// not checked here
+    fn message(fields: &str) -> Vec<u8> {
+        format!("8=FIX.4.4|9=0|{}|10=000|", fields).replace('|', "\x01").into_bytes()
+    }
+
+    fn side(session: &Session, direction: Direction) -> (u64, Option<u64>, Option<u64>, u64, u64, &'static str) {
+        let state = session.state.lock().unwrap();
+        let side = &state.sides[direction.index()];
+        (side.messages, side.last_seq, side.expected, side.sequence_errors, side.late, side.state())
+    }
+
+    #[test]
+    fn test_sessions() {
+        let guard = registry().register(802_001, "10.0.0.1:40000".parse().unwrap(), "10.1.0.5:9878".parse().unwrap());
+        let session = guard.session();
+        let (mut up, mut down) = (session.observer(Direction::Upstream), session.observer(Direction::Downstream));
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        // A Logon split at every byte, then the answer in one read
+        for byte in message("35=A|34=1|49=MEMBER|56=VENUE|108=30|141=Y|98=0").chunks(1) {
+            up.observe_at(byte, at(0));
+        }
+        down.observe_at(&message("35=A|34=1|49=VENUE|56=MEMBER|108=30|141=Y"), at(0));
+        assert_eq!(side(session, Direction::Upstream), (1, Some(1), Some(2), 0, 0, "logged-on"));
+        let table = registry().render();
+        assert!(table.contains("802001\t10.0.0.1:40000\t10.1.0.5:9878\tFIX.4.4\tMEMBER\tVENUE\t30\tlogged-on\t1\t1\t0\t0\t"), "{}", table);
+
+        // 2 and 3 arrive, 4 is skipped; a PossDup resend of 3 is fine
+        let mut stream = [message("35=D|34=2"), message("35=D|34=3"), message("35=D|34=5"), message("35=D|34=3|43=Y")].concat();
+        // Garbage in between only costs what it overlaps
+        stream.splice(0..0, b"GET / HTTP/1.1\r\n\x01".iter().copied());
+        up.observe_at(&stream, at(10));
+        assert_eq!(side(session, Direction::Upstream), (5, Some(5), Some(6), 1, 0, "logged-on"));
+
+        // Quiet for 40s of a 30s interval is late; then a gap fill
+        down.observe_at(&message("35=0|34=2"), at(40));
+        down.observe_at(&message("35=4|34=3|43=Y|123=Y|36=7"), at(41));
+        down.observe_at(&message("35=0|34=7"), at(42));
+        // A SequenceReset without GapFillFlag moves it anywhere, even down
+        down.observe_at(&message("35=4|34=99|36=2"), at(43));
+        down.observe_at(&message("35=0|34=2"), at(44));
+        assert_eq!(side(session, Direction::Downstream), (6, Some(2), Some(3), 0, 1, "logged-on"));
+
+        up.observe_at(&message("35=5|34=6"), at(45));
+        assert_eq!(side(session, Direction::Upstream), (6, Some(6), Some(7), 1, 0, "logged-out"));
+        // A number that went backwards
+        down.observe_at(&message("35=0|34=1"), at(46));
+        assert_eq!(side(session, Direction::Downstream).3, 1);
+
+        // The server went quiet without logging out; the client did log out
+        session.closed(at(100));
+        assert_eq!((side(session, Direction::Upstream).4, side(session, Direction::Downstream).4), (0, 2));
+        drop(guard);
+        assert!(!registry().render().contains("802001"));
+    }
+
+    #[test]
+    fn test_values() {
+        let mut value = Value::default();
+        b"12345".iter().for_each(|&byte| value.push(byte));
+        assert_eq!(value.number(), Some(12345));
+        value.push(b'x');
+        assert_eq!(value.number(), None);
+        let mut long = Value::default();
+        [b'1'; MAX_VALUE_LEN + 1].iter().for_each(|&byte| long.push(byte));
+        assert_eq!(long.number(), None);
+        assert_eq!(Value::default().number(), None);
+    }
+}
@@ -0,0 +1,454 @@
+//! Userspace forwarding between the two legs of a proxied connection
+//!
+//! This is the path every byte takes unless the connection is spliced in
+//! the kernel, so it must not allocate once a connection is set up: the
+//! buffers are taken (from the arena, if there is one) before the first
+//! read, and the loops only move bytes between them and the sockets. The
+//! tests hold it to that with a counting allocator.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::ReadHalf;
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+use crate::arena::{Buffer, BufferArena};
+use crate::fix_session::{self, Direction};
+use crate::mirror::Mirror;
+use crate::record::Recorder;
+use crate::idle::{self, IdleClock, IdlePolicy, IdleState};
+use crate::metrics;
+use crate::ratelimit::RateLimit;
+use crate::route::{BufferSizes, ForwardPriority};
+use crate::tuning::QuickAck;
+
+/// Forward data bidirectionally between client and server with minimal copying
+///
+/// Returns the number of bytes forwarded client->server and server->client.
+/// An EOF from one side is passed on as a shutdown of the other leg's
+/// sending side (a FIN) while the other direction keeps flowing, so this
+/// returns once both sides have closed, or as soon as either direction
+/// fails. Both directions run in one task; `priority` decides which of them is
+/// polled first on each wakeup, and `idle` whether a direction with nothing
+/// to read waits for the socket or keeps polling it, and `rate` how fast
+/// either direction may go. With `quickack` rearm, TCP_QUICKACK is set
+/// again on a leg after every read from it. `taps` see the bytes
+/// once they are written on; with an idle clock among them, the connection
+/// is closed once it times out, and with a cut once that is made.
+#[allow(clippy::too_many_arguments)]
+pub async fn forward_data(
+    mut client_stream: TcpStream,
+    mut server_stream: TcpStream,
+    buffers: BufferSizes,
+    arena: Option<&Arc<BufferArena>>,
+    priority: ForwardPriority,
+    idle: IdlePolicy,
+    rate: RateLimit,
+    quickack: QuickAck,
+    taps: Taps<'_>,
+    conn_id: u64,
+) -> io::Result<(u64, u64)> {
+    // Split streams for bidirectional forwarding
+    let (mut client_read, mut client_write) = client_stream.split();
+    let (mut server_read, mut server_write) = server_stream.split();
+    
+    // Pre-allocate buffers to minimize allocations
+    let mut client_to_server_buf = Buffer::alloc(arena, buffers.upstream);
+    let mut server_to_client_buf = Buffer::alloc(arena, buffers.downstream);
+    if arena.is_some() && !(client_to_server_buf.is_arena() && server_to_client_buf.is_arena()) {
+        metrics::registry()
+            .counter("tcpstrip_arena_exhausted_total", "Forwarding buffers taken from the heap because the arena was full")
+            .inc();
+    }
+    let mut bytes_up = 0u64;
+    let mut bytes_down = 0u64;
+    let rearm = quickack == QuickAck::Rearm;
+    
+    // Bidirectional forwarding with minimal copying
+    let client_to_server = async {
+        let mut idle = IdleState::new(idle);
+        let mut fix = taps.fix.map(|session| session.observer(Direction::Upstream));
+        let mut bucket = rate.bucket(Direction::Upstream);
+        loop {
+            match read(&mut client_read, &mut client_to_server_buf, &mut idle, rearm).await {
+                Ok(0) => return half_close(&mut server_write, "client->server", conn_id).await,
+                Ok(n) => {
+                    if let Err(e) = server_write.write_all(&client_to_server_buf[..n]).await {
+                        warn!("Connection {} client->server write error: {}", conn_id, e);
+                        return false;
+                    }
+                    bytes_up += n as u64;
+                    if let Some(clock) = taps.idle {
+                        clock.touch();
+                    }
+                    if let Some(mirror) = taps.mirror {
+                        mirror.copy(&client_to_server_buf[..n]);
+                    }
+                    if let Some(recorder) = taps.record {
+                        recorder.record(Direction::Upstream, &client_to_server_buf[..n]);
+                    }
+                    if let Some(fix) = &mut fix {
+                        fix.observe(&client_to_server_buf[..n]);
+                    }
+                    if let Some(bucket) = &mut bucket {
+                        bucket.throttle(n).await;
+                    }
+                }
+                Err(e) => {
+                    warn!("Connection {} client->server read error: {}", conn_id, e);
+                    return false;
+                }
+            }
+        }
+    };
+    
+    let server_to_client = async {
+        let mut idle = IdleState::new(idle);
+        let mut fix = taps.fix.map(|session| session.observer(Direction::Downstream));
+        let mut bucket = rate.bucket(Direction::Downstream);
+        loop {
+            match read(&mut server_read, &mut server_to_client_buf, &mut idle, rearm).await {
+                Ok(0) => return half_close(&mut client_write, "server->client", conn_id).await,
+                Ok(n) => {
+                    if let Err(e) = client_write.write_all(&server_to_client_buf[..n]).await {
+                        warn!("Connection {} server->client write error: {}", conn_id, e);
+                        return false;
+                    }
+                    bytes_down += n as u64;
+                    if let Some(clock) = taps.idle {
+                        clock.touch();
+                    }
+                    if let Some(recorder) = taps.record {
+                        recorder.record(Direction::Downstream, &server_to_client_buf[..n]);
+                    }
+                    if let Some(fix) = &mut fix {
+                        fix.observe(&server_to_client_buf[..n]);
+                    }
+                    if let Some(bucket) = &mut bucket {
+                        bucket.throttle(n).await;
+                    }
+                }
+                Err(e) => {
+                    warn!("Connection {} server->client read error: {}", conn_id, e);
+                    return false;
+                }
+            }
+        }
+    };
+    
+    // Run both directions concurrently; each resolves to whether it ended
+    // with a clean EOF
+    let expired = async {
+        idle::expired(taps.idle).await;
+        debug!("Connection {} closed after {:?} idle", conn_id, taps.idle.map(IdleClock::timeout).unwrap_or_default());
+    };
+    let cut = cut(taps.cut);
+    {
+        tokio::pin!(client_to_server, server_to_client, expired, cut);
+        let (mut up_open, mut down_open) = (true, true);
+        while up_open || down_open {
+            let (upstream, clean) = match priority {
+                ForwardPriority::Fair => tokio::select! {
+                    clean = &mut client_to_server, if up_open => (true, clean),
+                    clean = &mut server_to_client, if down_open => (false, clean),
+                    _ = &mut expired => break,
+                    _ = &mut cut => break,
+                },
+                ForwardPriority::Upstream => tokio::select! {
+                    biased;
+                    clean = &mut client_to_server, if up_open => (true, clean),
+                    clean = &mut server_to_client, if down_open => (false, clean),
+                    _ = &mut expired => break,
+                    _ = &mut cut => break,
+                },
+                ForwardPriority::Downstream => tokio::select! {
+                    biased;
+                    clean = &mut server_to_client, if down_open => (false, clean),
+                    clean = &mut client_to_server, if up_open => (true, clean),
+                    _ = &mut expired => break,
+                    _ = &mut cut => break,
+                },
+            };
+            if !clean {
+                break;
+            }
+            match upstream {
+                true => up_open = false,
+                false => down_open = false,
+            }
+        }
+    }
+
+    Ok((bytes_up, bytes_down))
+}
+
+/// What else sees the bytes a connection forwards, never holding them up
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Taps<'a> {
+    /// Follows the FIX sessions both ways (--fix-observe)
+    pub fix: Option<&'a fix_session::Session>,
+    /// Gets a copy of the client's bytes (--mirror)
+    pub mirror: Option<&'a Mirror>,
+    /// Records both directions (--record-dir)
+    pub record: Option<&'a Recorder>,
+    /// Told whenever bytes move, for the route's --idle-timeout
+    pub idle: Option<&'a IdleClock>,
+    /// Ends the connection early, for the kill switch and --trading-hours
+    pub cut: Option<&'a Cut>,
+}
+
+impl Taps<'_> {
+    /// Whether no tap needs the bytes themselves; spliced connections
+    /// keep their idle timeout without userspace seeing them
+    pub fn is_empty(&self) -> bool {
+        self.fix.is_none() && self.mirror.is_none() && self.record.is_none()
+    }
+}
+
+/// Ends a relay before its legs close
+///
+/// A relay watching it stops forwarding and returns what it forwarded so
+/// far, so the connection is accounted for as one that closed.
+#[derive(Debug, Default)]
+pub struct Cut {
+    made: AtomicBool,
+    notify: Notify,
+}
+
+impl Cut {
+    pub fn make(&self) {
+        self.made.store(true, Ordering::Release);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_made(&self) -> bool {
+        self.made.load(Ordering::Acquire)
+    }
+
+    /// Resolve once the cut is made
+    pub async fn made(&self) {
+        loop {
+            // Registered before the check, so a cut made in between wakes it
+            let notified = self.notify.notified();
+            if self.is_made() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// `Cut::made`, or never without a cut
+pub async fn cut(cut: Option<&Cut>) {
+    match cut {
+        Some(cut) => cut.made().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Pass an EOF on by shutting down the sending side of the other leg;
+/// whether that went through
+async fn half_close<W: AsyncWriteExt + Unpin>(write: &mut W, direction: &str, conn_id: u64) -> bool {
+    match write.shutdown().await {
+        Ok(()) => true,
+        // The peer may be gone already, which ends the connection anyway
+        Err(e) => {
+            debug!("Connection {} {} shutdown failed: {}", conn_id, direction, e);
+            false
+        }
+    }
+}
+
+/// Read from one leg, polling it first if the idle policy says to spin,
+/// and re-arming TCP_QUICKACK on it if `rearm`
+///
+/// Spinning yields to the runtime between attempts, so the other direction
+/// of the connection (and other connections on the same worker) still run.
+async fn read(stream: &mut ReadHalf<'_>, buf: &mut [u8], idle: &mut IdleState, rearm: bool) -> io::Result<usize> {
+    if idle.spins() {
+        loop {
+            match stream.try_read(buf) {
+                Ok(n) => {
+                    idle.busy();
+                    if rearm {
+                        rearm_quickack(stream);
+                    }
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+            if !idle.spin() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+    let n = stream.read(buf).await?;
+    idle.busy();
+    if rearm {
+        rearm_quickack(stream);
+    }
+    Ok(n)
+}
+
+/// Set TCP_QUICKACK again, which the kernel clears once it falls back to
+/// delayed ACKs, so the next segment to arrive is acknowledged at once
+fn rearm_quickack(stream: &ReadHalf<'_>) {
+    #[cfg(target_os = "linux")]
+    let _ = socket2::SockRef::from(stream.as_ref()).set_quickack(true);
+    #[cfg(not(target_os = "linux"))]
+    let _ = stream;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc_count::allocations;
+    use tokio::net::TcpListener;
+
+    /// Both ends of a loopback connection
+    async fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (connected, accepted) = tokio::join!(TcpStream::connect(listener.local_addr().unwrap()), listener.accept());
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    #[test]
+    fn test_steady_state_forwarding_does_not_allocate() {
+        assert_forwarding_does_not_allocate(IdlePolicy::Park, false, QuickAck::On);
+    }
+
+    #[test]
+    fn test_spinning_does_not_allocate() {
+        assert_forwarding_does_not_allocate(IdlePolicy::Spin(std::time::Duration::from_secs(1)), false, QuickAck::On);
+    }
+
+    #[test]
+    fn test_observing_fix_does_not_allocate() {
+        assert_forwarding_does_not_allocate(IdlePolicy::Park, true, QuickAck::On);
+    }
+
+    #[test]
+    fn test_rearming_quickack_does_not_allocate() {
+        assert_forwarding_does_not_allocate(IdlePolicy::Park, false, QuickAck::Rearm);
+    }
+
+    #[tokio::test]
+    async fn test_half_close_keeps_other_direction_open() {
+        let (mut client, proxy_client) = connected_pair().await;
+        let (proxy_server, mut server) = connected_pair().await;
+        let relay = tokio::spawn(async move {
+            let buffers = BufferSizes::both(4096);
+            forward_data(proxy_client, proxy_server, buffers, None, ForwardPriority::Fair, IdlePolicy::Park, RateLimit::default(), QuickAck::On, Taps::default(), 0).await
+        });
+
+        // The client sends its request and a FIN, and only then the server
+        // answers
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        server.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+        server.write_all(b"response").await.unwrap();
+        drop(server);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+
+        assert_eq!(relay.await.unwrap().unwrap(), (7, 8));
+    }
+
+    #[tokio::test]
+    async fn test_cut_returns_what_was_forwarded() {
+        let (mut client, proxy_client) = connected_pair().await;
+        let (proxy_server, mut server) = connected_pair().await;
+        let cut = Arc::new(Cut::default());
+        let relay = tokio::spawn({
+            let cut = cut.clone();
+            async move {
+                let taps = Taps {
+                    cut: Some(&cut),
+                    ..Taps::default()
+                };
+                forward_data(proxy_client, proxy_server, BufferSizes::both(4096), None, ForwardPriority::Fair, IdlePolicy::Park, RateLimit::default(), QuickAck::On, taps, 0).await
+            }
+        });
+
+        client.write_all(b"order").await.unwrap();
+        let mut order = [0u8; 5];
+        server.read_exact(&mut order).await.unwrap();
+        // Both legs are still open
+        cut.make();
+        assert_eq!(relay.await.unwrap().unwrap(), (5, 0));
+        assert_eq!(server.read(&mut order).await.unwrap(), 0);
+    }
+
+    fn assert_forwarding_does_not_allocate(idle: IdlePolicy, fix: bool, quickack: QuickAck) {
+        const WARMUP: u64 = 100;
+        const MESSAGES: u64 = 1000;
+
+        // Everything runs on this thread, so the counter sees all of it
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap();
+        runtime.block_on(async {
+            let arena = BufferArena::new(4096, 2).unwrap();
+            let fix = fix.then(|| fix_session::registry().register(0, "127.0.0.1:1".parse().unwrap(), "127.0.0.1:2".parse().unwrap()));
+            let (mut client, proxy_client) = connected_pair().await;
+            let (proxy_server, mut server) = connected_pair().await;
+            let relay = tokio::spawn({
+                let arena = arena.clone();
+                async move {
+                    let buffers = BufferSizes::both(4096);
+                    let taps = Taps {
+                        fix: fix.as_ref().map(|guard| guard.session()),
+                        mirror: None,
+                        record: None,
+                        idle: None,
+                        cut: None,
+                    };
+                    forward_data(proxy_client, proxy_server, buffers, Some(&arena), ForwardPriority::Fair, idle, RateLimit::default(), quickack, taps, 0).await
+                }
+            });
+            let echo = tokio::spawn(async move {
+                let mut buf = [0u8; 64];
+                loop {
+                    let n = server.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    server.write_all(&buf[..n]).await.unwrap();
+                }
+            });
+
+            // A resent Heartbeat, padded with Text to the same size
+            let mut order = [0x5a; 64];
+            let heartbeat = b"8=FIX.4.4\x019=40\x0135=0\x0134=1\x0143=Y\x0158=";
+            order[..heartbeat.len()].copy_from_slice(heartbeat);
+            order[56..].copy_from_slice(b"\x0110=000\x01");
+            let mut fill = [0u8; 64];
+            for _ in 0..WARMUP {
+                client.write_all(&order).await.unwrap();
+                client.read_exact(&mut fill).await.unwrap();
+            }
+            let before = allocations();
+            for _ in 0..MESSAGES {
+                client.write_all(&order).await.unwrap();
+                client.read_exact(&mut fill).await.unwrap();
+            }
+            assert_eq!(allocations() - before, 0, "allocations while forwarding {} messages", MESSAGES);
+
+            drop(client);
+            let (bytes_up, bytes_down) = relay.await.unwrap().unwrap();
+            assert_eq!((bytes_up, bytes_down), (64 * (WARMUP + MESSAGES), 64 * (WARMUP + MESSAGES)));
+            echo.await.unwrap();
+
+            // Both buffers went back to the arena
+            let buffers = (arena.alloc(), arena.alloc());
+            assert!(buffers.0.is_some() && buffers.1.is_some());
+        });
+    }
+}
@@ -0,0 +1,260 @@
+//! Active upstream health checks
+//!
+//! Failed dials take a target out of rotation only after a client has
+//! paid for the failure. With `--health-interval` every target (and the
+//! `--backup-target`) is probed in the background instead: a TCP connect,
+//! or with `--health-send`/`--health-expect` a small exchange such as a
+//! FIX TestRequest or an HTTP request line. A target is taken out of
+//! rotation after `fall` failed probes in a row and put back after `rise`
+//! good ones; both are logged, `tcpstrip_route_healthy{route}` is 1 or 0,
+//! and failed probes are counted in
+//! `tcpstrip_health_check_failures_total{route}`. Taking a target out is
+//! a warning, except inside a `--maintenance` window; either change is
+//! counted in `tcpstrip_health_changes_total{window}`.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{info, warn};
+
+use crate::balance::Pool;
+use crate::egress::Egress;
+use crate::trading_hours::{AlertWindow, Schedule};
+use crate::via::Via;
+use crate::metrics;
+
+/// Most bytes read while waiting for the expected reply
+const MAX_REPLY_LEN: usize = 4096;
+
+/// How to probe targets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    pub interval: Duration,
+    /// Limit for the whole probe, connect and exchange
+    pub timeout: Duration,
+    /// Good probes in a row that put a target back in rotation
+    pub rise: u32,
+    /// Failed probes in a row that take a target out
+    pub fall: u32,
+    /// Sent once connected
+    pub send: Option<Vec<u8>>,
+    /// Must appear in what the target sends back
+    pub expect: Option<Vec<u8>>,
+    /// Where probes leave from, as upstream connections do
+    pub egress: Egress,
+    /// The egress proxy probes go through, as upstream connections do
+    pub via: Option<Via>,
+    /// When targets going down or up is expected
+    pub maintenance: Option<Schedule>,
+}
+
+/// Decode `\r`, `\n`, `\t`, `\\` and `\xHH` escapes, so probes can carry
+/// control characters such as FIX's SOH (`\x01`)
+pub fn parse_bytes(s: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'r') => out.push(b'\r'),
+            Some(b'n') => out.push(b'\n'),
+            Some(b't') => out.push(b'\t'),
+            Some(b'\\') => out.push(b'\\'),
+            Some(b'x') => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                let byte = std::str::from_utf8(&hex)
+                    .ok()
+                    .filter(|hex| hex.len() == 2)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| format!("invalid \\x escape in '{}'", s))?;
+                out.push(byte);
+            }
+            _ => return Err(format!("invalid escape in '{}' (expected \\r, \\n, \\t, \\\\ or \\xHH)", s)),
+        }
+    }
+    Ok(out)
+}
+
+/// Probe `addr` once
+pub async fn probe(addr: SocketAddr, check: &HealthCheck) -> io::Result<()> {
+    let exchange = async {
+        let mut stream = match &check.via {
+            Some(via) => {
+                let mut stream = check.egress.connect(via.addr).await?;
+                via.tunnel(&mut stream, addr).await?;
+                stream
+            }
+            None => check.egress.connect(addr).await?,
+        };
+        if let Some(send) = &check.send {
+            stream.write_all(send).await?;
+        }
+        let Some(expect) = &check.expect else {
+            return Ok(());
+        };
+        let mut reply = Vec::new();
+        let mut buf = [0u8; 1024];
+        while reply.len() < MAX_REPLY_LEN {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            reply.extend_from_slice(&buf[..n]);
+            if reply.windows(expect.len()).any(|window| window == expect.as_slice()) {
+                return Ok(());
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected reply"))
+    };
+    tokio::time::timeout(check.timeout, exchange)
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")))
+}
+
+/// Consecutive probe results of one target
+#[derive(Debug)]
+struct Streak {
+    healthy: bool,
+    count: u32,
+}
+
+impl Streak {
+    /// Record a probe; the new state if it flipped
+    fn record(&mut self, ok: bool, check: &HealthCheck) -> Option<bool> {
+        if ok == self.healthy {
+            self.count = 0;
+            return None;
+        }
+        self.count += 1;
+        let needed = if ok { check.rise } else { check.fall };
+        if self.count < needed.max(1) {
+            return None;
+        }
+        self.healthy = ok;
+        self.count = 0;
+        Some(ok)
+    }
+}
+
+/// Probe every target of `pool` (and its backup) every interval, for as
+/// long as the runtime lives
+pub fn spawn(pool: Arc<Pool>, check: Arc<HealthCheck>) {
+    let registry = metrics::registry();
+    let healthy = registry.labeled_gauge("tcpstrip_route_healthy", "1 while a target passes its health checks", "route");
+    let failures = registry.labeled_counter(
+        "tcpstrip_health_check_failures_total",
+        "Failed health check probes per target",
+        "route",
+    );
+    let changes = registry.labeled_counter(
+        "tcpstrip_health_changes_total",
+        "Targets taken out of or put back into rotation by health checks",
+        "window",
+    );
+
+    for member in pool.members() {
+        let (pool, check) = (pool.clone(), check.clone());
+        let (healthy, failures, changes) = (healthy.clone(), failures.clone(), changes.clone());
+        tokio::spawn(async move {
+            let mut addr = pool.addr(member);
+            let mut gauge = healthy.with(&addr.to_string());
+            gauge.set(1);
+            let mut streak = Streak { healthy: true, count: 0 };
+            let mut interval = tokio::time::interval(check.interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                // The target's name moved (see `dns`): start over
+                if pool.addr(member) != addr {
+                    gauge.set(0);
+                    addr = pool.addr(member);
+                    gauge = healthy.with(&addr.to_string());
+                    gauge.set(1);
+                    streak = Streak { healthy: true, count: 0 };
+                }
+                let result = probe(addr, &check).await;
+                if pool.addr(member) != addr {
+                    continue;
+                }
+                if result.is_err() {
+                    failures.with(&addr.to_string()).inc();
+                }
+                let changed = streak.record(result.is_ok(), &check);
+                let window = AlertWindow::now(check.maintenance.as_ref());
+                match (changed, result, window) {
+                    (Some(true), _, AlertWindow::Maintenance) => {
+                        info!("Target {} passes health checks again in a maintenance window, back in rotation", addr)
+                    }
+                    (Some(true), _, AlertWindow::Unexpected) => info!("Target {} passes health checks again, back in rotation", addr),
+                    (Some(false), Err(e), AlertWindow::Maintenance) => info!(
+                        "Target {} failed {} health checks ({}) in a maintenance window, taking it out of rotation",
+                        addr, check.fall, e
+                    ),
+                    (Some(false), Err(e), AlertWindow::Unexpected) => {
+                        warn!("Target {} failed {} health checks ({}), taking it out of rotation", addr, check.fall, e)
+                    }
+                    _ => {}
+                }
+                if let Some(healthy) = changed {
+                    changes.with(&window.to_string()).inc();
+                    gauge.set(healthy as u64);
+                    pool.set_healthy(member, healthy);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_probe_and_streaks() {
+        assert_eq!(parse_bytes("8=FIX.4.4\\x019=5\\r\\n").unwrap(), b"8=FIX.4.4\x019=5\r\n");
+        assert!(parse_bytes("\\x0").is_err());
+        assert!(parse_bytes("\\q").is_err());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 64];
+                let n = stream.read(&mut buf).await.unwrap_or(0);
+                let reply: &[u8] = if buf[..n].starts_with(b"PING") { b"+PONG\r\n" } else { b"-ERR\r\n" };
+                let _ = stream.write_all(reply).await;
+            }
+        });
+
+        let mut check = HealthCheck {
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(1),
+            rise: 2,
+            fall: 2,
+            send: Some(b"PING\r\n".to_vec()),
+            expect: Some(b"PONG".to_vec()),
+            egress: Egress::default(),
+            via: None,
+            maintenance: None,
+        };
+        assert!(probe(addr, &check).await.is_ok());
+        check.send = Some(b"HELLO\r\n".to_vec());
+        assert_eq!(probe(addr, &check).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // One bad probe in between resets the count
+        let mut streak = Streak { healthy: true, count: 0 };
+        assert_eq!(streak.record(false, &check), None);
+        assert_eq!(streak.record(true, &check), None);
+        assert_eq!(streak.record(false, &check), None);
+        assert_eq!(streak.record(false, &check), Some(false));
+        assert_eq!(streak.record(true, &check), None);
+        assert_eq!(streak.record(true, &check), Some(true));
+    }
+}
@@ -0,0 +1,384 @@
+//! Searchable connection history
+//!
+//! The connection log line says who connected through which route, but
+//! only for as long as the log files are kept, and answering a question
+//! from them means grepping rotated files. With `--history-db` every routed
+//! connection is also written to a SQLite database, indexed by client
+//! host, route and accept time, and searched from the admin API's
+//! `/history`.
+//!
+//! Rows are written by a dedicated thread so forwarding never waits on the
+//! disk. If that thread falls behind, records are dropped and counted in
+//! `tcpstrip_history_dropped_total` rather than queued without bound.
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::types::Value;
+use rusqlite::Connection;
+use tracing::warn;
+
+use crate::connections::Fingerprint;
+use crate::metrics::{self, Metric};
+use crate::route::Destination;
+
+/// Records waiting for the writer thread
+const QUEUE_DEPTH: usize = 4096;
+const DEFAULT_LIMIT: usize = 100;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS connections (
+        fingerprint TEXT PRIMARY KEY,
+        conn_id INTEGER NOT NULL,
+        accepted_at INTEGER NOT NULL,
+        client TEXT NOT NULL,
+        client_ip TEXT NOT NULL,
+        listener TEXT NOT NULL,
+        source TEXT NOT NULL,
+        route TEXT NOT NULL,
+        route_ip TEXT NOT NULL,
+        closed_at INTEGER,
+        bytes_up INTEGER,
+        bytes_down INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS connections_by_client ON connections (client_ip, accepted_at);
+    CREATE INDEX IF NOT EXISTS connections_by_route ON connections (route_ip, accepted_at);
+    CREATE INDEX IF NOT EXISTS connections_by_time ON connections (accepted_at);
+";
+
+/// A routed connection, as in its log line
+#[derive(Debug, Clone, Copy)]
+pub struct Opened {
+    pub conn_id: u64,
+    pub fingerprint: Fingerprint,
+    pub accepted_at: SystemTime,
+    pub client: SocketAddr,
+    pub listener: SocketAddr,
+    /// Local address of the backend leg
+    pub source: SocketAddr,
+    pub route: SocketAddr,
+}
+
+#[derive(Debug)]
+enum Record {
+    Opened(Opened),
+    Closed {
+        fingerprint: Fingerprint,
+        closed_at: SystemTime,
+        bytes: Option<(u64, u64)>,
+    },
+}
+
+/// A search, from `/history`'s query string
+///
+/// `src` and `route` are an IP (any port) or IP:PORT. `since` and `until`
+/// are Unix seconds or anything SQLite reads as a UTC time, such as
+/// `2026-10-15T14:00:00Z`; `since` is inclusive and `until` exclusive.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Query {
+    pub src: Option<Destination>,
+    pub route: Option<Destination>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub limit: usize,
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Result<Self, String> {
+        let mut parsed = Query {
+            limit: DEFAULT_LIMIT,
+            ..Default::default()
+        };
+        for (key, value) in query.split('&').filter_map(|kv| kv.split_once('=')) {
+            match key {
+                "src" => parsed.src = Some(value.parse()?),
+                "route" => parsed.route = Some(value.parse()?),
+                "since" => parsed.since = Some(value.to_string()),
+                "until" => parsed.until = Some(value.to_string()),
+                "limit" => parsed.limit = value.parse().map_err(|_| format!("invalid limit '{}'", value))?,
+                _ => return Err(format!("unknown parameter '{}'", key)),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// The history database and its writer
+#[derive(Debug)]
+pub struct History {
+    db: Arc<Mutex<Connection>>,
+    records: SyncSender<Record>,
+    dropped: Arc<Metric>,
+}
+
+impl History {
+    /// Open (or create) the database at `path` and start its writer
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let db = Connection::open(path).map_err(io::Error::other)?;
+        db.execute_batch(SCHEMA).map_err(io::Error::other)?;
+        let db = Arc::new(Mutex::new(db));
+
+        let (records, queue) = mpsc::sync_channel(QUEUE_DEPTH);
+        let writer = db.clone();
+        thread::Builder::new()
+            .name("history".into())
+            .spawn(move || write_records(&writer, queue))?;
+
+        Ok(Self {
+            db,
+            records,
+            dropped: metrics::registry().counter(
+                "tcpstrip_history_dropped_total",
+                "Connection history records dropped because the writer fell behind",
+            ),
+        })
+    }
+
+    /// Record a connection; its row is completed when the entry is dropped
+    pub fn record(&self, opened: Opened) -> Entry<'_> {
+        self.send(Record::Opened(opened));
+        Entry {
+            history: self,
+            fingerprint: opened.fingerprint,
+            bytes: None,
+        }
+    }
+
+    fn send(&self, record: Record) {
+        if self.records.try_send(record).is_err() {
+            self.dropped.inc();
+        }
+    }
+
+    /// Matching connections in accept order, one per line
+    ///
+    /// Fails with `InvalidInput` for times SQLite cannot read.
+    pub fn search(&self, query: &Query) -> io::Result<String> {
+        let db = self.db.lock().unwrap();
+        let mut conditions = Vec::new();
+        let mut params = Vec::new();
+
+        for (column, destination) in [("client", query.src), ("route", query.route)] {
+            match destination {
+                None | Some(Destination::Any) => {}
+                Some(Destination::Host(ip)) => {
+                    conditions.push(format!("{}_ip = ?", column));
+                    params.push(Value::Text(ip.to_canonical().to_string()));
+                }
+                Some(Destination::Service(addr)) => {
+                    conditions.push(format!("{} = ?", column));
+                    params.push(Value::Text(canonical(addr).to_string()));
+                }
+            }
+        }
+        for (condition, time) in [("accepted_at >= ?", &query.since), ("accepted_at < ?", &query.until)] {
+            if let Some(time) = time {
+                conditions.push(condition.to_string());
+                params.push(Value::Integer(epoch_ms(&db, time)?));
+            }
+        }
+
+        let mut sql = "SELECT strftime('%Y-%m-%dT%H:%M:%fZ', accepted_at / 1000.0, 'unixepoch'), fingerprint, conn_id, \
+                       client, listener, source, route, \
+                       strftime('%Y-%m-%dT%H:%M:%fZ', closed_at / 1000.0, 'unixepoch'), bytes_up, bytes_down \
+                       FROM connections"
+            .to_string();
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        sql.push_str(" ORDER BY accepted_at LIMIT ?");
+        params.push(Value::Integer(query.limit.min(i64::MAX as usize) as i64));
+
+        let mut statement = db.prepare(&sql).map_err(io::Error::other)?;
+        let rows = statement
+            .query_map(rusqlite::params_from_iter(params), |row| {
+                let mut line = format!(
+                    "{} [{}] connection {}: {} -> {} proxied from {} -> {}",
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, String>(4)?,
+                    row.get::<_, String>(5)?,
+                    row.get::<_, String>(6)?,
+                );
+                match row.get::<_, Option<String>>(7)? {
+                    Some(closed_at) => line.push_str(&format!(", closed {}", closed_at)),
+                    None => line.push_str(", open"),
+                }
+                if let (Some(up), Some(down)) = (row.get::<_, Option<i64>>(8)?, row.get::<_, Option<i64>>(9)?) {
+                    line.push_str(&format!(", {} bytes up, {} down", up, down));
+                }
+                line.push('\n');
+                Ok(line)
+            })
+            .map_err(io::Error::other)?;
+        rows.collect::<Result<String, _>>().map_err(io::Error::other)
+    }
+}
+
+/// A connection's history row, completed on drop
+#[derive(Debug)]
+pub struct Entry<'a> {
+    history: &'a History,
+    fingerprint: Fingerprint,
+    bytes: Option<(u64, u64)>,
+}
+
+impl Entry<'_> {
+    /// Note the bytes forwarded, once the connection is done with
+    pub fn finish(&mut self, bytes_up: u64, bytes_down: u64) {
+        self.bytes = Some((bytes_up, bytes_down));
+    }
+}
+
+impl Drop for Entry<'_> {
+    fn drop(&mut self) {
+        self.history.send(Record::Closed {
+            fingerprint: self.fingerprint,
+            closed_at: SystemTime::now(),
+            bytes: self.bytes,
+        });
+    }
+}
+
+fn write_records(db: &Mutex<Connection>, queue: Receiver<Record>) {
+    for record in queue {
+        let db = db.lock().unwrap();
+        let result = match record {
+            Record::Opened(opened) => db.execute(
+                "INSERT OR REPLACE INTO connections \
+                 (fingerprint, conn_id, accepted_at, client, client_ip, listener, source, route, route_ip) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                rusqlite::params![
+                    opened.fingerprint.to_string(),
+                    opened.conn_id as i64,
+                    unix_ms(opened.accepted_at),
+                    canonical(opened.client).to_string(),
+                    opened.client.ip().to_canonical().to_string(),
+                    canonical(opened.listener).to_string(),
+                    canonical(opened.source).to_string(),
+                    canonical(opened.route).to_string(),
+                    opened.route.ip().to_canonical().to_string(),
+                ],
+            ),
+            Record::Closed {
+                fingerprint,
+                closed_at,
+                bytes,
+            } => db.execute(
+                "UPDATE connections SET closed_at = ?2, bytes_up = ?3, bytes_down = ?4 WHERE fingerprint = ?1",
+                rusqlite::params![
+                    fingerprint.to_string(),
+                    unix_ms(closed_at),
+                    bytes.map(|(up, _)| up as i64),
+                    bytes.map(|(_, down)| down as i64),
+                ],
+            ),
+        };
+        if let Err(e) = result {
+            warn!("Failed to write connection history: {}", e);
+        }
+    }
+}
+
+/// Addresses as stored, so v4-mapped clients of a dual-stack listener
+/// match their IPv4 form
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+fn unix_ms(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as i64).unwrap_or(0)
+}
+
+/// A `since`/`until` value in ms since the epoch
+fn epoch_ms(db: &Connection, time: &str) -> io::Result<i64> {
+    if let Ok(seconds) = time.parse::<i64>() {
+        return Ok(seconds.saturating_mul(1000));
+    }
+    let ms: Option<i64> = db
+        .query_row("SELECT CAST(unixepoch(?1, 'subsec') * 1000 AS INTEGER)", [time], |row| row.get(0))
+        .map_err(io::Error::other)?;
+    ms.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid time '{}'", time)))
+}
+
+static HISTORY: OnceLock<History> = OnceLock::new();
+
+/// Open the global history; later calls keep the first database
+pub fn open(path: &Path) -> io::Result<&'static History> {
+    let history = History::open(path)?;
+    Ok(HISTORY.get_or_init(|| history))
+}
+
+/// The global history, if `--history-db` was given
+pub fn get() -> Option<&'static History> {
+    HISTORY.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn wait_for(history: &History, query: &Query, lines: usize) -> String {
+        for _ in 0..200 {
+            let found = history.search(query).unwrap();
+            if found.lines().count() == lines && !found.contains(", open") {
+                return found;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        panic!("history never showed {} closed connections", lines);
+    }
+
+    #[test]
+    fn test_record_and_search() {
+        let history = History::open(Path::new(":memory:")).unwrap();
+        let listener: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        let source: SocketAddr = "10.0.0.1:51804".parse().unwrap();
+        let route: SocketAddr = "10.1.0.5:9000".parse().unwrap();
+        // 2026-10-15T14:02:00Z and 14:10:00Z
+        let base = UNIX_EPOCH + Duration::from_secs(1_792_072_920);
+        for (conn_id, client, accepted_at) in [
+            (0, "10.0.0.7:52706", base),
+            (1, "10.0.0.8:41000", base),
+            (2, "[::ffff:10.0.0.7]:52800", base + Duration::from_secs(480)),
+        ] {
+            let client: SocketAddr = client.parse().unwrap();
+            let mut entry = history.record(Opened {
+                conn_id,
+                fingerprint: Fingerprint::new(client, route, accepted_at),
+                accepted_at,
+                client,
+                listener,
+                source,
+                route,
+            });
+            entry.finish(100, 2000);
+        }
+
+        let all = wait_for(&history, &Query::parse("").unwrap(), 3);
+        assert!(all.starts_with("2026-10-15T14:02:00.000Z ["));
+        assert!(all.contains("connection 0: 10.0.0.7:52706 -> 10.0.0.1:8080 proxied from 10.0.0.1:51804 -> 10.1.0.5:9000"));
+        assert!(all.contains("100 bytes up, 2000 down"));
+
+        // The mapped client is found under its IPv4 address
+        let host = Query::parse("src=10.0.0.7&route=10.1.0.5:9000").unwrap();
+        assert_eq!(history.search(&host).unwrap().lines().count(), 2);
+        let window = Query::parse("src=10.0.0.7&since=2026-10-15T14:00:00Z&until=2026-10-15T14:05:00Z").unwrap();
+        assert_eq!(history.search(&window).unwrap().lines().count(), 1);
+        let seconds = Query::parse("since=1792073400").unwrap();
+        assert!(history.search(&seconds).unwrap().contains("connection 2:"));
+
+        let bad = Query::parse("since=yesterday").unwrap();
+        assert_eq!(history.search(&bad).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(Query::parse("host=10.0.0.7").is_err());
+    }
+}
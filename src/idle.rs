@@ -0,0 +1,254 @@
+//! What forwarding workers do when there is nothing to forward
+//!
+//! Blocking until the kernel signals new data costs no CPU but adds a
+//! wakeup (tens of microseconds with a scheduler hop) to the first packet
+//! after a quiet spell. Polling in a loop instead takes that latency away
+//! and burns the core. `--idle-policy` picks between the two, or lets
+//! recent traffic decide; like other per-route settings it is given as
+//! `[DEST=]POLICY` rules.
+//!
+//! The socket proxy applies the rule matching each connection's backend.
+//! The AF_XDP bridge's queue workers are not tied to a route and use the
+//! catch-all rule; the other packet datapaths always block.
+//!
+//! Separately, a connection that moves no bytes either way for its route's
+//! `--idle-timeout` is closed, so half-dead sessions (a client that
+//! vanished without a FIN or RST) do not hold on to gateway slots. Such
+//! closes are counted in `tcpstrip_idle_timeouts_total`.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::metrics;
+
+/// Spin bound of a plain `spin`
+pub const DEFAULT_SPIN: Duration = Duration::from_micros(100);
+/// Longest an adaptive worker spins before parking
+pub const ADAPTIVE_MAX_SPIN: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdlePolicy {
+    /// Block right away (lowest CPU)
+    #[default]
+    Park,
+    /// Poll for up to this long before blocking (lowest wake latency)
+    Spin(Duration),
+    /// Spin while traffic has recently been arriving in quick succession,
+    /// park when it has been sparse
+    Adaptive,
+}
+
+impl fmt::Display for IdlePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdlePolicy::Park => write!(f, "park"),
+            IdlePolicy::Spin(bound) => write!(f, "spin:{}", bound.as_micros()),
+            IdlePolicy::Adaptive => write!(f, "adaptive"),
+        }
+    }
+}
+
+impl FromStr for IdlePolicy {
+    type Err = String;
+
+    /// `park`, `spin`, `spin:MICROS` or `adaptive`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None => match s {
+                "park" => Ok(IdlePolicy::Park),
+                "spin" => Ok(IdlePolicy::Spin(DEFAULT_SPIN)),
+                "adaptive" => Ok(IdlePolicy::Adaptive),
+                _ => Err(format!("unknown idle policy '{}' (expected park, spin[:MICROS] or adaptive)", s)),
+            },
+            Some(("spin", micros)) => micros
+                .parse()
+                .map(|micros| IdlePolicy::Spin(Duration::from_micros(micros)))
+                .map_err(|_| format!("invalid spin bound '{}' (expected microseconds)", micros)),
+            Some(_) => Err(format!("unknown idle policy '{}' (expected park, spin[:MICROS] or adaptive)", s)),
+        }
+    }
+}
+
+/// An `--idle-timeout`: whole seconds, at least one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdleTimeout(pub Duration);
+
+impl fmt::Display for IdleTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}s", self.0.as_secs())
+    }
+}
+
+impl FromStr for IdleTimeout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<u64>() {
+            Ok(secs) if secs > 0 => Ok(IdleTimeout(Duration::from_secs(secs))),
+            _ => Err(format!("invalid idle timeout '{}' (expected seconds)", s)),
+        }
+    }
+}
+
+/// When a connection last moved bytes, for its idle timeout
+///
+/// Both directions `touch` it as they forward; `expired` resolves once
+/// neither has for the timeout.
+#[derive(Debug)]
+pub struct IdleClock {
+    timeout: Duration,
+    started: Instant,
+    /// Nanoseconds from `started` to the last touch
+    touched: AtomicU64,
+}
+
+impl IdleClock {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            started: Instant::now(),
+            touched: AtomicU64::new(0),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn touch(&self) {
+        self.touched.store(self.started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Resolve once the connection has gone the timeout without moving
+    /// bytes, counting it as timed out
+    pub async fn expired(&self) {
+        wait_idle(self.timeout, || {
+            self.started.elapsed().saturating_sub(Duration::from_nanos(self.touched.load(Ordering::Relaxed)))
+        })
+        .await
+    }
+}
+
+/// Resolve once `idle_for` reports at least `timeout`, checking whenever it
+/// could first have got there, and count a timed out connection
+pub async fn wait_idle(timeout: Duration, mut idle_for: impl FnMut() -> Duration) {
+    loop {
+        let idle = idle_for();
+        if idle >= timeout {
+            break;
+        }
+        tokio::time::sleep(timeout - idle).await;
+    }
+    metrics::registry()
+        .counter("tcpstrip_idle_timeouts_total", "Connections closed for moving no bytes for their --idle-timeout")
+        .inc();
+}
+
+/// `IdleClock::expired`, or never without a clock
+pub async fn expired(clock: Option<&IdleClock>) {
+    match clock {
+        Some(clock) => clock.expired().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Idle tracking for one worker loop
+///
+/// The loop calls `busy` whenever it moved data and `spin` whenever it
+/// found nothing, polling again if that returns true and blocking
+/// otherwise.
+#[derive(Debug, Clone)]
+pub struct IdleState {
+    policy: IdlePolicy,
+    /// When the current idle stretch began
+    idle_since: Option<Instant>,
+    /// Moving average of recent idle stretches, for the adaptive policy
+    average_gap: Duration,
+}
+
+impl IdleState {
+    pub fn new(policy: IdlePolicy) -> Self {
+        Self {
+            policy,
+            idle_since: None,
+            // Park until traffic shows it is worth spinning for
+            average_gap: ADAPTIVE_MAX_SPIN,
+        }
+    }
+
+    /// Whether the policy ever polls instead of blocking
+    pub fn spins(&self) -> bool {
+        self.policy != IdlePolicy::Park
+    }
+
+    pub fn busy(&mut self) {
+        if let (Some(since), IdlePolicy::Adaptive) = (self.idle_since.take(), self.policy) {
+            self.average_gap = (self.average_gap * 7 + since.elapsed()) / 8;
+        }
+    }
+
+    /// Whether to poll again rather than block
+    pub fn spin(&mut self) -> bool {
+        let bound = match self.policy {
+            IdlePolicy::Park => return false,
+            IdlePolicy::Spin(bound) => bound,
+            // Gaps this short are likely to end within the spin
+            IdlePolicy::Adaptive if self.average_gap * 2 <= ADAPTIVE_MAX_SPIN => self.average_gap * 2,
+            IdlePolicy::Adaptive => Duration::ZERO,
+        };
+        let now = Instant::now();
+        now.duration_since(*self.idle_since.get_or_insert(now)) < bound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idle_policies() {
+        assert_eq!("park".parse(), Ok(IdlePolicy::Park));
+        assert_eq!("spin".parse(), Ok(IdlePolicy::Spin(DEFAULT_SPIN)));
+        assert_eq!("spin:20".parse(), Ok(IdlePolicy::Spin(Duration::from_micros(20))));
+        assert_eq!(IdlePolicy::Spin(Duration::from_micros(20)).to_string(), "spin:20");
+        assert!("spin:fast".parse::<IdlePolicy>().is_err());
+        assert!("poll".parse::<IdlePolicy>().is_err());
+
+        let mut park = IdleState::new(IdlePolicy::Park);
+        assert!(!park.spin());
+
+        let mut spin = IdleState::new(IdlePolicy::Spin(Duration::from_secs(60)));
+        assert!(spin.spin() && spin.spin());
+        let mut expired = IdleState::new(IdlePolicy::Spin(Duration::ZERO));
+        assert!(!expired.spin());
+
+        // Adaptive parks at first, and spins once gaps have been short
+        let mut adaptive = IdleState::new(IdlePolicy::Adaptive);
+        assert!(!adaptive.spin());
+        adaptive.average_gap = Duration::from_micros(10);
+        adaptive.busy();
+        assert!(adaptive.spin());
+        assert!(adaptive.average_gap < ADAPTIVE_MAX_SPIN / 2);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        assert_eq!("30".parse(), Ok(IdleTimeout(Duration::from_secs(30))));
+        assert!("0".parse::<IdleTimeout>().is_err());
+        assert!("30s".parse::<IdleTimeout>().is_err());
+
+        let clock = IdleClock::new(Duration::from_millis(100));
+        let started = Instant::now();
+        let touches = async {
+            for _ in 0..3 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                clock.touch();
+            }
+        };
+        tokio::join!(clock.expired(), touches);
+        // The last touch came 150ms in
+        assert!(started.elapsed() >= Duration::from_millis(250));
+    }
+}
@@ -0,0 +1,123 @@
+//! Kill switch: pull the plug on all order flow at once
+//!
+//! Engaging the switch (SIGUSR1, or `POST /kill-switch/engage` on the
+//! admin listener) resets every TCP connection that is being relayed, on
+//! both legs, and every connection accepted from then on, until the switch
+//! is released again (SIGUSR2, `POST /kill-switch/release`). Connections
+//! still being set up (dialing, handshakes) have their client leg reset.
+//! The switch is process wide, across all listeners.
+//!
+//! `tcpstrip_kill_switch_engaged` is 1 while the switch is engaged; reset
+//! connections are counted in `tcpstrip_kill_switch_resets_total`, and
+//! connections refused on accept in `tcpstrip_kill_switch_refused_total`.
+//!
+//! With `--protocol udp` the switch drops every session and the datagrams
+//! that arrive while it is engaged. The bridge, TUN and divert modes
+//! forward packets rather than connections, so there is nothing for the
+//! switch to reset: they mark it `unavailable` and it cannot be engaged.
+
+use std::sync::{Arc, OnceLock};
+
+use tokio::sync::watch;
+
+use crate::metrics::{self, Metric};
+
+struct Switch {
+    engaged: watch::Sender<bool>,
+    /// The mode that has no connections to reset
+    unavailable: OnceLock<&'static str>,
+    gauge: Arc<Metric>,
+    resets: Arc<Metric>,
+    refused: Arc<Metric>,
+}
+
+fn switch() -> &'static Switch {
+    static SWITCH: OnceLock<Switch> = OnceLock::new();
+    SWITCH.get_or_init(|| {
+        let registry = metrics::registry();
+        Switch {
+            engaged: watch::Sender::new(false),
+            unavailable: OnceLock::new(),
+            gauge: registry.gauge("tcpstrip_kill_switch_engaged", "1 while the kill switch is engaged"),
+            resets: registry.counter("tcpstrip_kill_switch_resets_total", "Connections reset by the kill switch"),
+            refused: registry.counter("tcpstrip_kill_switch_refused_total", "Connections reset on accept while the kill switch was engaged"),
+        }
+    })
+}
+
+/// Engage the switch; whether it was released before. Never engages
+/// while the switch is `unavailable`
+pub fn engage() -> bool {
+    unavailable_in().is_none() && set(true)
+}
+
+/// Release the switch; whether it was engaged before
+pub fn release() -> bool {
+    set(false)
+}
+
+fn set(engaged: bool) -> bool {
+    let switch = switch();
+    switch.gauge.set(engaged.into());
+    switch.engaged.send_replace(engaged) != engaged
+}
+
+/// Refuse to engage from now on: `mode` forwards packets, not connections
+pub fn unavailable(mode: &'static str) {
+    let _ = switch().unavailable.set(mode);
+}
+
+/// The mode the switch has nothing to act on in, if it was marked so
+pub fn unavailable_in() -> Option<&'static str> {
+    switch().unavailable.get().copied()
+}
+
+pub fn is_engaged() -> bool {
+    *switch().engaged.borrow()
+}
+
+/// Resolves once the switch is engaged, right away if it is already
+pub async fn engaged() {
+    let mut engaged = switch().engaged.subscribe();
+    let _ = engaged.wait_for(|engaged| *engaged).await;
+}
+
+/// Count a connection reset by the switch
+pub fn count_reset() {
+    switch().resets.inc();
+}
+
+/// Count a connection refused on accept
+pub fn count_refused() {
+    switch().refused.inc();
+}
+
+/// Held by tests that engage the switch or that the switch would disturb,
+/// as it is process wide
+#[cfg(test)]
+pub(crate) static SERIAL: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_engage_and_release() {
+        let _serial = SERIAL.lock().await;
+        let waiting = tokio::spawn(engaged());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+
+        assert!(engage());
+        assert!(!engage());
+        assert!(is_engaged());
+        tokio::time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap();
+        // Anything waiting from now on is let through straight away
+        tokio::time::timeout(Duration::from_secs(1), engaged()).await.unwrap();
+
+        assert!(release());
+        assert!(!release());
+        assert!(!is_engaged());
+    }
+}
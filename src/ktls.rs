@@ -0,0 +1,388 @@
+//! Kernel TLS offload of established TLS legs (Linux only)
+//!
+//! rustls only has to run the handshake. With `--ktls` the proxy then
+//! moves each TLS leg's session keys into the kernel (the `tls` TCP ULP),
+//! which encrypts what is written to the socket and decrypts what is read
+//! from it, on the NIC where it supports TLS offload. The relay loop only
+//! moves plaintext, with no per-record work of its own.
+//!
+//! No record may be left half read inside rustls when the keys move, so
+//! the handshake reads whole records (`tls::Socket`). Application data
+//! rustls already decrypted, as when a client writes right after its
+//! Finished, is taken out first and read before anything from the kernel.
+//! A leg part way through a record stays in rustls, as does one whose
+//! kernel lacks the ULP (`modprobe tls`). Such legs are counted in
+//! `tcpstrip_ktls_declined_total{reason}`, offloaded ones in
+//! `tcpstrip_ktls_offloaded_total`.
+//!
+//! Records other than application data reach the proxy marked with their
+//! type: a close_notify alert is EOF, TLS 1.3 session tickets are dropped
+//! (so offloaded upstream connections do not resume sessions), and a TLS
+//! 1.3 KeyUpdate ends the connection, since the kernel keeps the keys it
+//! was given. For the same reason offloaded legs never update their own
+//! keys, which rustls would do when AES-GCM nears its record limit.
+
+use std::io::{self, Read};
+use std::mem;
+use std::os::fd::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use rustls::{ConnectionTrafficSecrets, ProtocolVersion};
+use tokio::io::{AsyncRead, AsyncWrite, Interest, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::metrics;
+use crate::tls::{Originated, Socket, Terminated};
+
+const TCP_ULP: libc::c_int = 31;
+const SOL_TLS: libc::c_int = 282;
+const TLS_TX: libc::c_int = 1;
+const TLS_RX: libc::c_int = 2;
+const TLS_SET_RECORD_TYPE: libc::c_int = 1;
+const TLS_GET_RECORD_TYPE: libc::c_int = 2;
+
+const TLS_1_2_VERSION: u16 = 0x0303;
+const TLS_1_3_VERSION: u16 = 0x0304;
+const TLS_CIPHER_AES_GCM_128: u16 = 51;
+const TLS_CIPHER_AES_GCM_256: u16 = 52;
+const TLS_CIPHER_CHACHA20_POLY1305: u16 = 54;
+
+const RECORD_ALERT: u8 = 21;
+const RECORD_HANDSHAKE: u8 = 22;
+const RECORD_APPLICATION_DATA: u8 = 23;
+const ALERT_CLOSE_NOTIFY: u8 = 0;
+const HANDSHAKE_NEW_SESSION_TICKET: u8 = 4;
+
+/// tls12_crypto_info_* (linux/tls.h); the AES-GCM ciphers split rustls'
+/// 12-byte IV into a 4-byte salt and an 8-byte IV
+#[repr(C)]
+struct CryptoInfo<const IV: usize, const KEY: usize, const SALT: usize> {
+    version: u16,
+    cipher_type: u16,
+    iv: [u8; IV],
+    key: [u8; KEY],
+    salt: [u8; SALT],
+    rec_seq: [u8; 8],
+}
+
+impl<const IV: usize, const KEY: usize, const SALT: usize> CryptoInfo<IV, KEY, SALT> {
+    fn new(version: u16, cipher_type: u16, seq: u64, key: &[u8], iv: &[u8]) -> Self {
+        let mut info = CryptoInfo {
+            version,
+            cipher_type,
+            iv: [0; IV],
+            key: [0; KEY],
+            salt: [0; SALT],
+            rec_seq: seq.to_be_bytes(),
+        };
+        info.key.copy_from_slice(key);
+        info.salt.copy_from_slice(&iv[..SALT]);
+        info.iv.copy_from_slice(&iv[SALT..]);
+        info
+    }
+
+    fn set(&self, fd: RawFd, direction: libc::c_int) -> io::Result<()> {
+        let rc = unsafe {
+            libc::setsockopt(fd, SOL_TLS, direction, self as *const Self as *const libc::c_void, mem::size_of::<Self>() as libc::socklen_t)
+        };
+        check(rc)
+    }
+}
+
+fn check(rc: libc::c_int) -> io::Result<()> {
+    match rc {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Hand one direction's keys for a `version` session to the kernel
+fn install(fd: RawFd, direction: libc::c_int, version: u16, (seq, secrets): (u64, ConnectionTrafficSecrets)) -> io::Result<()> {
+    match secrets {
+        ConnectionTrafficSecrets::Aes128Gcm { key, iv } => {
+            CryptoInfo::<8, 16, 4>::new(version, TLS_CIPHER_AES_GCM_128, seq, key.as_ref(), iv.as_ref()).set(fd, direction)
+        }
+        ConnectionTrafficSecrets::Aes256Gcm { key, iv } => {
+            CryptoInfo::<8, 32, 4>::new(version, TLS_CIPHER_AES_GCM_256, seq, key.as_ref(), iv.as_ref()).set(fd, direction)
+        }
+        ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv } => {
+            CryptoInfo::<12, 32, 0>::new(version, TLS_CIPHER_CHACHA20_POLY1305, seq, key.as_ref(), iv.as_ref()).set(fd, direction)
+        }
+        _ => Err(io::Error::new(io::ErrorKind::Unsupported, "cipher not supported by kernel TLS")),
+    }
+}
+
+/// Why a leg stays in rustls
+fn declined(reason: &str) {
+    metrics::registry()
+        .labeled_counter("tcpstrip_ktls_declined_total", "TLS legs left in userspace instead of kernel TLS", "reason")
+        .with(reason)
+        .inc();
+}
+
+/// Check that `session` can move to the kernel, take out the plaintext it
+/// already decrypted and attach the TLS ULP to `socket`, which leaves its
+/// bytes untouched until keys are installed
+fn prepare<D>(socket: &Socket, session: &mut rustls::ConnectionCommon<D>) -> Result<(u16, Vec<u8>), &'static str> {
+    let version = match session.protocol_version() {
+        Some(ProtocolVersion::TLSv1_2) => TLS_1_2_VERSION,
+        Some(ProtocolVersion::TLSv1_3) => TLS_1_3_VERSION,
+        _ => return Err("version"),
+    };
+    let state = session.process_new_packets().map_err(|_| "buffered")?;
+    if session.is_handshaking() || session.wants_write() || socket.mid_record() {
+        return Err("buffered");
+    }
+    if state.peer_has_closed() {
+        return Err("closed");
+    }
+    let ulp = b"tls";
+    let rc = unsafe {
+        libc::setsockopt(socket.get_ref().as_raw_fd(), libc::SOL_TCP, TCP_ULP, ulp.as_ptr() as *const libc::c_void, ulp.len() as libc::socklen_t)
+    };
+    check(rc).map_err(|_| "unsupported")?;
+    // Past the last way back to rustls, so nothing it holds can be lost
+    let mut pending = vec![0; state.plaintext_bytes_to_read()];
+    session.reader().read_exact(&mut pending).map_err(|_| "buffered")?;
+    Ok((version, pending))
+}
+
+/// Install the session's keys on its socket
+fn offload(stream: TcpStream, (version, pending): (u16, Vec<u8>), session: rustls::Connection) -> io::Result<KtlsStream> {
+    let secrets = session
+        .dangerous_extract_secrets()
+        .map_err(|e| io::Error::other(format!("could not extract TLS secrets: {}", e)))?;
+    install(stream.as_raw_fd(), TLS_TX, version, secrets.tx)?;
+    install(stream.as_raw_fd(), TLS_RX, version, secrets.rx)?;
+    metrics::registry()
+        .counter("tcpstrip_ktls_offloaded_total", "TLS legs handed to kernel TLS")
+        .inc();
+    Ok(KtlsStream { stream, pending, pending_at: 0 })
+}
+
+/// Move a terminated client leg into the kernel, or hand it back if it
+/// must stay in rustls; an error means the connection was lost part way
+pub fn offload_terminated(mut tls: Terminated) -> io::Result<Result<KtlsStream, Terminated>> {
+    let (socket, session) = tls.get_mut();
+    match prepare(socket, &mut **session) {
+        Ok(prepared) => {
+            let (socket, session) = tls.into_inner();
+            offload(socket.into_inner(), prepared, session.into()).map(Ok)
+        }
+        Err(reason) => {
+            declined(reason);
+            Ok(Err(tls))
+        }
+    }
+}
+
+/// Move an originated upstream leg into the kernel, or hand it back if it
+/// must stay in rustls; an error means the connection was lost part way
+pub fn offload_originated(mut tls: Originated) -> io::Result<Result<KtlsStream, Originated>> {
+    let (socket, session) = tls.get_mut();
+    match prepare(socket, &mut **session) {
+        Ok(prepared) => {
+            let (socket, session) = tls.into_inner();
+            offload(socket.into_inner(), prepared, session.into()).map(Ok)
+        }
+        Err(reason) => {
+            declined(reason);
+            Ok(Err(tls))
+        }
+    }
+}
+
+/// What one recvmsg() returned
+enum Record {
+    Data,
+    Closed,
+    /// A record that carries nothing for the relay
+    Skipped,
+}
+
+/// A socket the kernel en- and decrypts; reads return application data
+/// only and shutting it down sends a close_notify
+pub struct KtlsStream {
+    stream: TcpStream,
+    /// Plaintext rustls had decrypted before the offload, and how much of
+    /// it has been read
+    pending: Vec<u8>,
+    pending_at: usize,
+}
+
+impl KtlsStream {
+    /// Receive one record's worth into `buf`, which is only filled with
+    /// application data
+    fn recv(&self, buf: &mut ReadBuf<'_>) -> io::Result<Record> {
+        let mut control = [0u64; 4];
+        let mut iov = libc::iovec {
+            // SAFETY: recvmsg() only writes to the buffer
+            iov_base: unsafe { buf.unfilled_mut() }.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.remaining(),
+        };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+        let n = unsafe { libc::recvmsg(self.stream.as_raw_fd(), &mut msg, libc::MSG_DONTWAIT) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let n = n as usize;
+        // SAFETY: recvmsg() wrote the first n bytes
+        unsafe { buf.assume_init(n) };
+        let start = buf.filled().len();
+        let record = &buf.initialized()[start..start + n];
+
+        let mut record_type = RECORD_APPLICATION_DATA;
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cmsg.is_null() {
+            let header = unsafe { &*cmsg };
+            if header.cmsg_level == SOL_TLS && header.cmsg_type == TLS_GET_RECORD_TYPE {
+                record_type = unsafe { *libc::CMSG_DATA(cmsg) };
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+        match (record_type, record) {
+            (RECORD_APPLICATION_DATA, []) => Ok(Record::Closed),
+            (RECORD_APPLICATION_DATA, _) => {
+                buf.advance(n);
+                Ok(Record::Data)
+            }
+            (RECORD_ALERT, [_, ALERT_CLOSE_NOTIFY, ..]) => Ok(Record::Closed),
+            (RECORD_ALERT, [_, alert, ..]) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, format!("peer sent TLS alert {}", alert))),
+            (RECORD_HANDSHAKE, [HANDSHAKE_NEW_SESSION_TICKET, ..]) => Ok(Record::Skipped),
+            (record_type, data) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("TLS record type {} ({:?}) after kernel offload", record_type, data.first()),
+            )),
+        }
+    }
+
+    /// Send a close_notify alert
+    fn close_notify(&self) -> io::Result<()> {
+        let mut alert = [1u8, ALERT_CLOSE_NOTIFY];
+        let mut control = [0u64; 4];
+        let mut iov = libc::iovec {
+            iov_base: alert.as_mut_ptr() as *mut libc::c_void,
+            iov_len: alert.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(1) } as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = SOL_TLS;
+            (*cmsg).cmsg_type = TLS_SET_RECORD_TYPE;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(1) as _;
+            *libc::CMSG_DATA(cmsg) = RECORD_ALERT;
+        }
+        let n = unsafe { libc::sendmsg(self.stream.as_raw_fd(), &msg, libc::MSG_DONTWAIT) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for KtlsStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pending_at < this.pending.len() {
+            let n = buf.remaining().min(this.pending.len() - this.pending_at);
+            buf.put_slice(&this.pending[this.pending_at..this.pending_at + n]);
+            this.pending_at += n;
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            std::task::ready!(this.stream.poll_read_ready(cx))?;
+            match this.stream.try_io(Interest::READABLE, || this.recv(buf)) {
+                Ok(Record::Data | Record::Closed) => return Poll::Ready(Ok(())),
+                Ok(Record::Skipped) => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for KtlsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            std::task::ready!(this.stream.poll_write_ready(cx))?;
+            match this.stream.try_io(Interest::WRITABLE, || this.close_notify()) {
+                Ok(()) => break,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                // The peer is gone; there is no one left to notify
+                Err(_) => break,
+            }
+        }
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::{Leg, Originator, Terminator};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_offload() {
+        // The sizes of linux/tls.h's structs
+        assert_eq!(mem::size_of::<CryptoInfo<8, 16, 4>>(), 40);
+        assert_eq!(mem::size_of::<CryptoInfo<8, 32, 4>>(), 56);
+        assert_eq!(mem::size_of::<CryptoInfo<12, 32, 0>>(), 56);
+
+        let (dir, cert, key) = crate::tls::tests::pem_files("ktls");
+        let terminator = Terminator::load(&cert, &key, None, true).unwrap();
+        let originator = Originator::new(Some(&cert), Some("localhost"), 0, true, None).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        // Without the module both legs stay in rustls and work the same
+        let available = std::fs::read_to_string("/proc/sys/net/ipv4/tcp_available_ulp").unwrap_or_default().contains("tls");
+
+        // An echo server offloading its side, and a client offloading its own
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let tls = terminator.accept(stream).await.unwrap();
+            let mut leg: Box<dyn Leg> = match offload_terminated(tls).unwrap() {
+                Ok(stream) => Box::new(stream),
+                Err(tls) => Box::new(tls),
+            };
+            let mut buf = [0u8; 64];
+            let n = leg.read(&mut buf).await.unwrap();
+            leg.write_all(&buf[..n]).await.unwrap();
+            leg.shutdown().await.unwrap();
+        });
+        let tls = originator.connect(TcpStream::connect(addr).await.unwrap(), addr).await.unwrap();
+        let mut leg: Box<dyn Leg> = match offload_originated(tls).unwrap() {
+            Ok(stream) => Box::new(stream),
+            Err(tls) => {
+                assert!(!available, "kernel TLS declined");
+                Box::new(tls)
+            }
+        };
+        leg.write_all(b"8=FIX.4.4").await.unwrap();
+        leg.flush().await.unwrap();
+        let mut echoed = Vec::new();
+        leg.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, b"8=FIX.4.4");
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+//! TCP timestamp proxy library
+//!
+//! The proxy binary (`src/main.rs`) is a thin CLI wrapper; the packet
+//! analysis and option handling logic lives here so it can be shared by
+//! the different datapaths and exercised directly from tests.
+
+pub mod accept;
+pub mod acl;
+pub mod admission;
+#[cfg(feature = "admin")]
+pub mod admin;
+#[cfg(feature = "analyze")]
+pub mod analyze;
+#[cfg(test)]
+mod alloc_count;
+pub mod arena;
+#[cfg(target_os = "linux")]
+pub mod backend_watch;
+pub mod balance;
+pub mod bench;
+#[cfg(target_os = "linux")]
+mod bpf;
+#[cfg(target_os = "linux")]
+pub mod bridge;
+#[cfg(target_os = "linux")]
+pub mod capture;
+pub mod connections;
+#[cfg(unix)]
+pub mod daemon;
+pub mod datapath;
+pub mod dial;
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+pub mod divert;
+#[cfg(all(windows, feature = "windivert"))]
+pub mod divert_windows;
+pub mod dns;
+#[cfg(target_os = "linux")]
+pub mod doctor;
+pub mod dscp;
+pub mod egress;
+pub mod entropy;
+pub mod eyeballs;
+pub mod features;
+#[cfg(target_os = "linux")]
+pub mod firewall;
+pub mod fix;
+pub mod fix_session;
+pub mod forward;
+pub mod health;
+#[cfg(feature = "history")]
+pub mod history;
+pub mod idle;
+pub mod killswitch;
+#[cfg(all(target_os = "linux", feature = "tls"))]
+pub mod ktls;
+pub mod logfile;
+pub mod metrics;
+pub mod mirror;
+pub mod monitor;
+pub mod packet;
+pub mod personality;
+#[cfg(target_os = "linux")]
+pub mod privileges;
+#[cfg(feature = "wasm-plugins")]
+pub mod plugin;
+#[cfg(all(feature = "profiling", unix))]
+pub mod profile;
+pub mod proxy_protocol;
+pub mod quic;
+pub mod ratelimit;
+pub mod record;
+pub mod route;
+pub mod scrub;
+#[cfg(feature = "scripting")]
+pub mod script;
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub mod seccomp;
+pub mod sni;
+pub mod sniff;
+pub mod socks;
+#[cfg(target_os = "linux")]
+pub mod sock_diag;
+#[cfg(target_os = "linux")]
+pub mod sockmap;
+pub mod source_limit;
+pub mod source_stats;
+pub mod spoof;
+#[cfg(target_os = "linux")]
+pub mod systemd;
+pub mod tcp_analysis;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod trading_hours;
+#[cfg(target_os = "linux")]
+pub mod tun;
+pub mod tuning;
+pub mod tz;
+pub mod udp;
+#[cfg(target_os = "linux")]
+pub mod verify;
+pub mod via;
+#[cfg(target_os = "linux")]
+pub mod xdp;
+#[cfg(target_os = "linux")]
+pub mod xdp_ingress;
+
+#[cfg(test)]
+mod tests {
+    /// Features the minimal build must not end up with, whether enabled
+    /// directly or through a dependency
+    const EXCLUDED_FEATURES: &[&str] = &["admin", "analyze", "history", "profiling", "scripting", "tls", "wasm-plugins", "windivert"];
+    /// Optional dependencies those features pull in
+    const EXCLUDED_CRATES: &[&str] = &["pprof", "rhai", "ring", "rusqlite", "rustls", "wasmi", "webpki-roots", "windivert"];
+
+    /// `cargo tree` for the minimal build, with the given extra arguments
+    fn minimal_tree(args: &[&str]) -> String {
+        let output = std::process::Command::new(env!("CARGO"))
+            .args(["tree", "--offline", "--prefix", "none", "--no-default-features", "--features", "minimal"])
+            .args(["--manifest-path", concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml")])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    // `minimal` is a marker, so --all-features still builds; what it
+    // promises is checked here instead
+    #[test]
+    fn test_minimal_build_excludes_optional_subsystems() {
+        let features = minimal_tree(&["-e", "features", "-i", "tcp-proxy"]);
+        assert!(features.contains("tcp-proxy feature \"minimal\""), "{}", features);
+        for feature in EXCLUDED_FEATURES {
+            assert!(!features.contains(&format!("tcp-proxy feature \"{}\"", feature)), "{}", features);
+        }
+
+        let crates = minimal_tree(&["-e", "normal"]);
+        for name in EXCLUDED_CRATES {
+            assert!(!crates.lines().any(|line| line.starts_with(&format!("{} v", name))), "{} is linked in", name);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Size-rotated log file (`--log-file`)
+//!
+//! Without a supervisor collecting standard output, logs go to a file that
+//! is rotated once it would grow past `--log-max-size`: PATH becomes PATH.1,
+//! PATH.1 becomes PATH.2 and so on, keeping `--log-keep` old files. Each
+//! event is written in one piece, so none is split across files. If a
+//! rotation fails (the directory went read-only, say) logging carries on
+//! in the current file.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Default of `--log-max-size`
+pub const DEFAULT_MAX_SIZE: u64 = 64 * 1024 * 1024;
+/// Default of `--log-keep`
+pub const DEFAULT_KEEP: usize = 5;
+
+/// A log file that rotates itself
+#[derive(Debug)]
+pub struct RotatingFile {
+    /// Absolute, so rotation works after the daemon left the directory
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    /// Append to `path`, rotating at `max_size` bytes and keeping `keep`
+    /// rotated files
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<Self> {
+        let path = std::path::absolute(path)?;
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                match fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            if let Err(e) = self.rotate() {
+                // Nowhere better to say so than the log itself
+                let _ = writeln!(self.file, "Could not rotate {}: {}", self.path.display(), e);
+                self.size = 0;
+            }
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join(format!("tcpstrip-logs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("proxy.log");
+        let mut log = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            log.write_all(line.as_bytes()).unwrap();
+        }
+        // Every line would have pushed the file past 10 bytes
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(dir.join("proxy.log.1")).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(dir.join("proxy.log.2")).unwrap(), "second\n");
+        assert!(!dir.join("proxy.log.3").exists());
+
+        // Reopening appends, counting what is already there
+        let mut log = RotatingFile::open(&path, 10, 0).unwrap();
+        log.write_all(b"x\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\nx\n");
+        log.write_all(b"fifth\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fifth\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
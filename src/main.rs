@@ -1,15 +1,16 @@
 use anyhow::Result;
-use bytes::BytesMut;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use socket2::{Domain, Protocol, Socket, Type};
 use std::net::{SocketAddr, ToSocketAddrs};
+use arc_swap::ArcSwap;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
+use tcp_proxy::datapath::Datapath;
+use tcp_proxy::dial::ConnectFailure;
+use tcp_proxy::entropy::EntropyConfig;
 use tracing::{debug, error, info, warn};
 
-mod tcp_analysis;
-
 /// High-performance TCP proxy designed for HFT environments
 /// 
 /// This proxy strips TCP Timestamp options (TSopt, RFC 7323) from connections
@@ -23,15 +24,292 @@ mod tcp_analysis;
 /// This proxy provides a userspace solution when kernel-level changes
 /// (net.ipv4.tcp_timestamps=0) are not feasible.
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Without a subcommand the flags are those of `proxy`, as before
+    /// subcommands existed
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the proxy, bridge, TUN hop or divert socket (the default)
+    Proxy(Box<Args>),
+    /// Report the host's SYN cookie, backlog and SYN retry settings as they
+    /// affect the proxy, then exit (Linux only)
+    Doctor(DoctorArgs),
+    /// Change the host settings doctor warns about to values that suit the
+    /// proxy, then exit (Linux only, needs root)
+    Tune(TuneArgs),
+    /// Watch outgoing segments for TCP timestamps for a while and exit
+    /// nonzero if any flow carried them (Linux only, needs CAP_NET_RAW)
+    Verify(VerifyArgs),
+    /// Print connections from a --history-db database, then exit
+    #[cfg(feature = "history")]
+    Report(ReportArgs),
+    /// Print live rates from a running proxy's admin listener
+    Monitor(MonitorArgs),
+    /// Measure round trip latency through an echoing target, then exit
+    Bench(BenchArgs),
+    /// Audit a pcap capture for flows that carried TCP timestamps, then
+    /// exit
+    #[cfg(feature = "analyze")]
+    Analyze(AnalyzeArgs),
+    /// Play the client side of a --record-dir recording against a target
+    /// at the recorded pace, then exit
+    Replay(ReplayArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct ReplayArgs {
+    /// Recording to replay
+    file: std::path::PathBuf,
+
+    /// Where to replay it to; defaults to the recorded target
+    #[arg(long, value_name = "HOST:PORT")]
+    target: Option<String>,
+
+    /// Pace relative to the recording: 2 is twice as fast, 0 sends
+    /// everything as fast as the target takes it
+    #[arg(long, default_value = "1", value_name = "FACTOR")]
+    speed: tcp_proxy::record::Speed,
+
+    /// How long the target has to close its side once the replay is done
+    #[arg(long, default_value = "5", value_name = "SECS")]
+    wait: u64,
+}
+
+#[derive(clap::Args, Debug)]
+struct DoctorArgs {
+    /// Listen backlog the proxy would be run with (--listen-backlog)
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_LISTEN_BACKLOG)]
+    listen_backlog: u32,
+
+    /// SYN retransmissions the proxy would be run with (--syn-retries)
+    #[arg(long, value_name = "N")]
+    syn_retries: Option<u8>,
+}
+
+#[derive(clap::Args, Debug)]
+struct TuneArgs {
+    /// Listen backlog the proxy will be run with (--listen-backlog)
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_LISTEN_BACKLOG)]
+    listen_backlog: u32,
+
+    /// SYN retransmissions the proxy will be run with (--syn-retries)
+    #[arg(long, value_name = "N")]
+    syn_retries: Option<u8>,
+
+    /// Print the changes without making them
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct VerifyArgs {
+    /// Interface the proxy's upstream connections leave through
+    #[arg(long, value_name = "IFACE")]
+    interface: String,
+
+    /// How long to watch
+    #[arg(long, default_value = "10", value_name = "SECS")]
+    seconds: u64,
+
+    /// Only check segments to this address (repeatable); by default every
+    /// outgoing segment is checked
+    #[arg(long, value_name = "HOST:PORT")]
+    target: Vec<String>,
+
+    /// TSval the proxy sends on purpose (--timestamp-action fixed)
+    #[arg(long, value_name = "N")]
+    allowed_ts_val: Option<u32>,
+}
+
+#[cfg(feature = "history")]
+#[derive(clap::Args, Debug)]
+struct ReportArgs {
+    /// Database written by the proxy's --history-db
+    #[arg(long, value_name = "PATH")]
+    history_db: std::path::PathBuf,
+
+    /// Client IP or IP:PORT
+    #[arg(long, value_name = "ADDR")]
+    src: Option<tcp_proxy::route::Destination>,
+
+    /// Route IP or IP:PORT
+    #[arg(long, value_name = "ADDR")]
+    route: Option<tcp_proxy::route::Destination>,
+
+    /// Accepted at or after, in Unix seconds or e.g. 2026-10-15T14:00:00Z
+    #[arg(long, value_name = "TIME")]
+    since: Option<String>,
+
+    /// Accepted before, in the same forms as --since
+    #[arg(long, value_name = "TIME")]
+    until: Option<String>,
+
+    /// Most connections to print
+    #[arg(long, default_value = "100", value_name = "N")]
+    limit: usize,
+}
+
+#[derive(clap::Args, Debug)]
+struct MonitorArgs {
+    /// Admin listener of the proxy to watch (its --admin-listen address)
+    #[arg(long, value_name = "IP:PORT")]
+    admin_listen: SocketAddr,
+
+    /// Seconds between lines
+    #[arg(long, default_value = "1", value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    interval: u64,
+}
+
+#[derive(clap::Args, Debug)]
+struct BenchArgs {
+    /// Echoing target to measure, usually a proxy in front of an echo
+    /// server
+    target: String,
+
+    /// Also measure this address (the echo server itself) for comparison
+    #[arg(long, value_name = "HOST:PORT")]
+    baseline: Option<String>,
+
+    /// Concurrent connections
+    #[arg(long, default_value = "1", value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    connections: u64,
+
+    /// Round trips per connection
+    #[arg(long, default_value = "1000", value_name = "N")]
+    messages: usize,
+
+    /// Bytes per message
+    #[arg(long, default_value = "64", value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    size: u64,
+}
+
+#[cfg(feature = "analyze")]
+#[derive(clap::Args, Debug)]
+struct AnalyzeArgs {
+    /// Capture to read (classic pcap), or - for standard input
+    file: std::path::PathBuf,
+
+    /// Threads tracking flows; defaults to one per CPU besides the reader
+    #[arg(long, value_name = "N")]
+    workers: Option<usize>,
+}
+
+#[derive(clap::Args, Debug)]
 struct Args {
     /// Local port to bind the proxy to
     #[arg(short, long, default_value = "8080")]
     port: u16,
 
-    /// Target server address to forward connections to
-    #[arg(short, long, value_name = "HOST:PORT")]
-    target: String,
+    /// Address to listen on instead of 0.0.0.0:PORT, e.g. 127.0.0.1:8080
+    /// for local clients only, [::]:8080 for IPv6 clients and (unless
+    /// --ipv6-only) IPv4 ones on the same socket, or [fe80::1%eth1]:8080
+    /// for a link-local address, which needs its interface
+    #[arg(long, value_name = "ADDR:PORT", conflicts_with = "port", value_parser = tcp_proxy::route::parse_listen)]
+    listen: Option<SocketAddr>,
+
+    /// Accept only IPv6 clients on IPv6 --listen and --route addresses
+    /// (IPV6_V6ONLY), whatever the system default
+    #[arg(long)]
+    ipv6_only: bool,
+
+    /// Transport to proxy: tcp, or udp to relay datagrams with a session
+    /// (own upstream socket) per client address
+    #[arg(long, default_value = "tcp", value_name = "PROTOCOL")]
+    protocol: tcp_proxy::udp::Protocol,
+
+    /// Seconds without datagrams either way after which a UDP session is
+    /// closed
+    #[arg(long, default_value = "60", value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    udp_idle_timeout: u64,
+
+    /// Recognize QUIC in --protocol udp and keep each QUIC connection on
+    /// its target when the client's address changes (NAT rebinding),
+    /// listing connections at the admin listener's /quic
+    #[arg(long)]
+    quic: bool,
+
+    /// Target server address to forward connections to; with several
+    /// (repeated or comma-separated), --balance spreads new connections
+    /// over them, weighted by @WEIGHT (1-100) where the strategy allows
+    #[arg(short, long, value_name = "HOST:PORT[@WEIGHT]", value_delimiter = ',', required_unless_present_any = ["bridge", "tun", "divert", "windivert", "transparent", "socks5", "sni_route", "doctor", "route"])]
+    target: Vec<tcp_proxy::balance::Target>,
+
+    /// Send new connections here while every --target is unreachable, and
+    /// back as soon as one accepts connections again
+    #[arg(long, value_name = "HOST:PORT", requires = "target")]
+    backup_target: Option<String>,
+
+    /// Probe every --target (and --backup-target) this often, taking those
+    /// that fail --health-fall probes in a row out of rotation until they
+    /// pass --health-rise again
+    #[arg(long, value_name = "MS")]
+    health_interval: Option<u64>,
+
+    /// Time limit of one probe
+    #[arg(long, default_value = "1000", value_name = "MS")]
+    health_timeout: u64,
+
+    /// Good probes in a row that put a target back in rotation
+    #[arg(long, default_value = "2", value_name = "N")]
+    health_rise: u32,
+
+    /// Failed probes in a row that take a target out of rotation
+    #[arg(long, default_value = "3", value_name = "N")]
+    health_fall: u32,
+
+    /// Send this once a probe is connected (escapes: \r \n \t \\ \xHH)
+    #[arg(long, value_name = "BYTES", requires = "health_interval")]
+    health_send: Option<String>,
+
+    /// Only count a probe as good if the reply contains this (same escapes)
+    #[arg(long, value_name = "BYTES", requires = "health_interval")]
+    health_expect: Option<String>,
+
+    /// How to pick a --target for each new connection: round-robin,
+    /// weighted, least-conn, latency (quickest to accept connections) or
+    /// source-hash (the same target for every connection from a client IP)
+    #[arg(long, default_value = "round-robin", value_name = "STRATEGY")]
+    balance: tcp_proxy::balance::Strategy,
+
+    /// Forward each connection to the address it was originally sent to
+    /// before a firewall REDIRECT/TPROXY rule steered it into the proxy
+    /// (SO_ORIGINAL_DST, Linux only), instead of a fixed --target
+    #[arg(long, conflicts_with = "target")]
+    transparent: bool,
+
+    /// Act as a SOCKS5 server (CONNECT only): each client names its own
+    /// destination, instead of a fixed --target
+    #[arg(long, conflicts_with_all = ["target", "transparent", "fix_logon_guard"])]
+    socks5: bool,
+
+    /// Require SOCKS5 clients to log in with this username and password
+    #[arg(long, value_name = "USER:PASS", requires = "socks5")]
+    socks5_auth: Option<tcp_proxy::socks::Credentials>,
+
+    /// Route TLS connections by the server name in their ClientHello,
+    /// without terminating TLS: NAME (or *.SUFFIX) to HOST:PORT, repeatable.
+    /// Unmatched connections go to --target, or the original destination
+    /// with --transparent, and are dropped otherwise
+    #[arg(long, value_name = "NAME=HOST:PORT", conflicts_with_all = ["socks5", "route"])]
+    sni_route: Vec<tcp_proxy::sni::SniRoute>,
+
+    /// Listen on LISTEN (PORT or ADDR:PORT) and forward to TARGET, with
+    /// options overriding the global settings for this route:
+    /// buffer-size=BYTES, spoof-timestamps[=VALUE], spoof-source,
+    /// proxy-protocol, fix-logon[=BEGINSTRING], fix-observe,
+    /// mirror=HOST:PORT, backlog=N (flags also as no-NAME). May
+    /// be given multiple times
+    /// to serve several routes from one process
+    #[arg(long, value_name = "LISTEN=TARGET[,OPTION...]", conflicts_with_all = ["target", "transparent", "socks5", "listen", "bridge", "tun", "divert", "windivert"])]
+    route: Vec<tcp_proxy::route::ListenerRoute>,
 
     /// Enable timestamp spoofing with static pattern
     #[arg(long, default_value = "false")]
@@ -41,148 +319,3002 @@ struct Args {
     #[arg(long, default_value = "0")]
     static_timestamp: u32,
 
+    /// Spoof timestamps with a per-connection clock starting at a random
+    /// origin instead of a static value (bridge/TUN modes)
+    #[arg(long, conflicts_with = "static_timestamp")]
+    randomize_timestamps: bool,
+
+    /// TSecr of segments coming back on connections with spoofed
+    /// timestamps: echo-translated (the host's own TSval), zero, or
+    /// echo-original, which stalls connections and is refused while
+    /// spoofing (bridge/TUN/divert modes)
+    #[arg(long, default_value = "echo-translated", value_name = "POLICY")]
+    return_echo: tcp_proxy::scrub::EchoPolicy,
+
+    /// Randomness for spoofed timestamps: os, seeded:<u64> (reproducible
+    /// test runs) or file:<path> (external entropy file)
+    #[arg(long, default_value = "os", value_name = "SOURCE")]
+    entropy_source: EntropyConfig,
+
+    /// Clamp the MSS advertised in SYNs to this many bytes, or to the
+    /// smallest MTU of the bridge/TUN interfaces with "pmtu"
+    #[arg(long, value_name = "BYTES|pmtu")]
+    mss_clamp: Option<String>,
+
+    /// Window scale option of SYNs: preserve, remove, or a fixed shift
+    /// (0-14) that hides the host's own (bridge/TUN/divert modes)
+    #[arg(long, default_value = "preserve", value_name = "preserve|remove|SHIFT")]
+    window_scale: tcp_proxy::scrub::WindowScaleAction,
+
+    /// Remove the SACK-permitted option from SYNs and SACK blocks from all
+    /// segments, in both directions (bridge/TUN/divert modes,
+    /// --xdp-interface)
+    #[arg(long)]
+    strip_sack: bool,
+
+    /// Remove TCP Fast Open cookies (and cookie requests) from SYNs and
+    /// SYN-ACKs in both directions (bridge/TUN/divert modes,
+    /// --xdp-interface)
+    #[arg(long)]
+    strip_fast_open: bool,
+
+    /// Remove MPTCP options in both directions so connections fall back to
+    /// single-path TCP (bridge/TUN/divert modes, --xdp-interface)
+    #[arg(long)]
+    strip_mptcp: bool,
+
+    /// What to do with TCP options of unknown kinds, experimental 253/254
+    /// included: preserve, strip, or alert (forward them and warn for each
+    /// SYN carrying one); counted in tcpstrip_unknown_tcp_options_total
+    /// unless preserved (bridge/TUN/divert modes)
+    #[arg(long, default_value = "preserve", value_name = "ACTION")]
+    unknown_options: tcp_proxy::scrub::UnknownOptionAction,
+
+    /// Rewrite the TCP options of outgoing segments into one canonical
+    /// order and padding, whatever OS sent them (bridge/TUN/divert modes)
+    #[arg(long)]
+    canonical_options: bool,
+
+    /// Make outgoing SYNs look like this OS's: linux-5.15, windows-11 or
+    /// freebsd-14. Sets the option layout and SYN window, and the TTL, MSS
+    /// clamp and window scale unless given (bridge/TUN/divert modes)
+    #[arg(long, value_name = "OS", conflicts_with = "canonical_options")]
+    personality: Option<tcp_proxy::personality::Personality>,
+
+    /// Rewrite the IPv4 TTL / IPv6 hop limit of outgoing packets to this
+    /// value, hiding the host OS's default (bridge/TUN/divert modes)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(1..))]
+    ttl: Option<u8>,
+
+    /// Replace the IPv4 ID of outgoing packets with a random sequence per
+    /// flow, hiding the host's packet counter (bridge/TUN/divert modes)
+    #[arg(long)]
+    randomize_ip_id: bool,
+
+    /// Mark traffic with this DSCP (0-63, ef, csN, afXY), optionally only
+    /// towards DEST (IP or IP:PORT): set on upstream sockets, rewritten in
+    /// bridge/TUN/divert modes. May be given multiple times; the first
+    /// matching rule wins
+    #[arg(long, value_name = "[DEST=]VALUE")]
+    dscp: Vec<tcp_proxy::dscp::DscpRule>,
+
+    /// Which direction the userspace relay serves first when both have
+    /// data ready: fair, upstream (client to server) or downstream,
+    /// optionally only towards DEST (IP or IP:PORT). May be given multiple
+    /// times; the first matching rule wins
+    #[arg(long, value_name = "[DEST=]PRIORITY")]
+    forward_priority: Vec<tcp_proxy::route::RouteRule<tcp_proxy::route::ForwardPriority>>,
+
+    /// Forwarding buffer sizes of the userspace relay towards DEST (IP or
+    /// IP:PORT), BYTES for both directions or UP/DOWN for client to server
+    /// and server to client; where no rule matches, the --route's
+    /// buffer-size or --buffer-size. May be given multiple times; the first
+    /// matching rule wins
+    #[arg(long, value_name = "[DEST=]UP[/DOWN]")]
+    relay_buffers: Vec<tcp_proxy::route::RouteRule<tcp_proxy::route::BufferSizes>>,
+
+    /// What forwarding workers do with nothing to forward: park (block,
+    /// lowest CPU), spin[:MICROS] (poll first, lowest wake latency) or
+    /// adaptive, optionally only towards DEST (IP or IP:PORT). AF_XDP queue
+    /// workers use the rule without DEST. May be given multiple times; the
+    /// first matching rule wins
+    #[arg(long, value_name = "[DEST=]POLICY")]
+    idle_policy: Vec<tcp_proxy::route::RouteRule<tcp_proxy::idle::IdlePolicy>>,
+
+    /// Close connections towards DEST (IP or IP:PORT) that move no bytes
+    /// either way for this many seconds. May be given multiple times; the
+    /// first matching rule wins, and without one connections never time out
+    #[arg(long, value_name = "[DEST=]SECS")]
+    idle_timeout: Vec<tcp_proxy::route::RouteRule<tcp_proxy::idle::IdleTimeout>>,
+
+    /// Hold each connection towards DEST (IP or IP:PORT) to this many
+    /// kilobits per second, KBPS both ways or UP/DOWN for client to server
+    /// and server to client (0 leaves a direction unlimited). Limited
+    /// connections are never spliced. May be given multiple times; the
+    /// first matching rule wins
+    #[arg(long, value_name = "[DEST=]UP[/DOWN]")]
+    max_rate_kbps: Vec<tcp_proxy::route::RouteRule<tcp_proxy::ratelimit::RateLimit>>,
+
+    /// Drop connections towards DEST (IP or IP:PORT) whose sent data goes
+    /// unacknowledged for this many milliseconds (TCP_USER_TIMEOUT, 0 for
+    /// the system default). May be given multiple times; the first
+    /// matching rule wins, and without one it is 5000
+    #[arg(long, value_name = "[DEST=]MS")]
+    tcp_user_timeout: Vec<tcp_proxy::route::RouteRule<tcp_proxy::tuning::UserTimeout>>,
+
+    /// Probe connections towards DEST (IP or IP:PORT) after IDLE seconds
+    /// without traffic, every INTERVAL seconds up to COUNT times
+    /// (SO_KEEPALIVE), or off. May be given multiple times; the first
+    /// matching rule wins, and without one there are no probes
+    #[arg(long, value_name = "[DEST=]IDLE[/INTERVAL/COUNT]")]
+    tcp_keepalive: Vec<tcp_proxy::route::RouteRule<tcp_proxy::tuning::Keepalive>>,
+
+    /// Acknowledge at once on connections towards DEST (IP or IP:PORT)
+    /// (TCP_QUICKACK): on (set once, until the kernel clears it), rearm
+    /// (set again after every read; never spliced) or off. May be given
+    /// multiple times; the first matching rule wins, and without one it is
+    /// on
+    #[arg(long, value_name = "[DEST=]MODE")]
+    quickack: Vec<tcp_proxy::route::RouteRule<tcp_proxy::tuning::QuickAck>>,
+
+    /// Switch a forwarding feature (sockmap, spin) off with no-NAME or back
+    /// on with NAME, optionally only towards DEST (IP or IP:PORT). Features
+    /// are on by default; the admin API can override these at runtime. May
+    /// be given multiple times; the first matching rule wins
+    #[arg(long, value_name = "[DEST=][no-]NAME")]
+    feature: Vec<tcp_proxy::features::FeatureRule>,
+
+    /// Clear ECN marks (IP ECT/CE, TCP ECE/CWR) in both directions so ECN
+    /// is never negotiated (bridge/TUN/divert modes)
+    #[arg(long)]
+    clear_ecn: bool,
+
+    /// Clear URG, the urgent pointer and the reserved TCP header bits in
+    /// both directions; unusual flag combinations are counted in
+    /// tcpstrip_tcp_flag_anomalies_total either way (bridge/TUN/divert modes)
+    #[arg(long)]
+    sanitize_flags: bool,
+
+    /// Compute every rewrite (bridge/TUN/divert modes) but forward the
+    /// original packets; would-be rewrites are counted in
+    /// tcpstrip_dry_run_rewrites_total and logged at debug level
+    #[arg(long)]
+    rewrite_dry_run: bool,
+
     /// Maximum number of concurrent connections
     #[arg(long, default_value = "1000")]
     max_connections: usize,
 
+    /// What happens to a TCP connection accepted while --max-connections
+    /// are open: reject resets it, queue holds it until one closes (up to
+    /// --max-connections-queue-timeout), log only counts it
+    #[arg(long, value_name = "ACTION", default_value_t)]
+    max_connections_action: tcp_proxy::admission::Saturated,
+
+    /// Longest a connection waits for a slot with --max-connections-action
+    /// queue before it is reset
+    #[arg(long, default_value = "1000", value_name = "MS")]
+    max_connections_queue_timeout: u64,
+
+    /// Accept TCP connections only inside this window of
+    /// --trading-timezone local time: DAYS HH:MM-HH:MM, e.g. "Mon-Fri
+    /// 09:30-16:00"; a window that ends before it starts runs past
+    /// midnight. May be given multiple times
+    #[arg(long, value_name = "DAYS HH:MM-HH:MM")]
+    trading_hours: Vec<tcp_proxy::trading_hours::Window>,
+
+    /// Time zone of --trading-hours and --maintenance: a tz database name
+    /// (America/New_York) or a POSIX TZ rule
+    #[arg(long, value_name = "ZONE", default_value = "UTC")]
+    trading_timezone: String,
+
+    /// Outside --trading-hours: reject resets new connections, drain also
+    /// closes open sessions (FIN on both legs) once the last window closes
+    #[arg(long, value_name = "ACTION", default_value_t)]
+    outside_trading_hours: tcp_proxy::trading_hours::Outside,
+
+    /// Declared venue maintenance, in --trading-timezone local time and the
+    /// form of --trading-hours: targets failing health checks, backends
+    /// changing their TCP options and new upstream certificate chains are
+    /// logged without a warning then. May be given multiple times
+    #[arg(long, value_name = "DAYS HH:MM-HH:MM")]
+    maintenance: Vec<tcp_proxy::trading_hours::Window>,
+
+    /// Length of each listener's queue of connections waiting for accept();
+    /// --route backlog=N overrides it for one listener. The kernel caps it
+    /// at net.core.somaxconn
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_LISTEN_BACKLOG)]
+    listen_backlog: u32,
+
+    /// Raise the soft open files limit (RLIMIT_NOFILE) to the hard limit at
+    /// startup; each proxied connection takes two descriptors
+    #[arg(long)]
+    raise_nofile: bool,
+
+    /// Maximum number of concurrent connections from one client address;
+    /// further ones are closed on accept
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..), conflicts_with_all = ["bridge", "tun", "divert", "windivert"])]
+    max_connections_per_ip: Option<u64>,
+
+    /// Maximum rate of new connections from one client address (per
+    /// second, with bursts of up to a second's worth)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..), conflicts_with_all = ["bridge", "tun", "divert", "windivert"])]
+    max_connect_rate_per_ip: Option<u32>,
+
+    /// What happens to a connection over --max-connect-rate-per-ip: reject
+    /// closes it, delay holds it back (up to 5s) until the rate allows it
+    #[arg(long, value_name = "ACTION", default_value_t, requires = "max_connect_rate_per_ip")]
+    connect_rate_exceeded: tcp_proxy::source_limit::Exceeded,
+
     /// Buffer size for data forwarding (bytes)
     #[arg(long, default_value = "65536")]
     buffer_size: usize,
+
+    /// Run as an AF_PACKET bridge between two interfaces instead of a proxy
+    /// (Linux only, requires CAP_NET_RAW)
+    #[arg(long, num_args = 2, value_names = ["INSIDE", "OUTSIDE"], conflicts_with = "target")]
+    bridge: Option<Vec<String>>,
+
+    /// How the bridge moves frames: af_packet, or af_xdp for zero-copy
+    /// per-queue workers at the highest packet rates
+    #[arg(long, default_value = "af_packet", value_name = "DATAPATH")]
+    datapath: Datapath,
+
+    /// Number of NIC queues served by the AF_XDP datapath (one worker
+    /// thread per queue)
+    #[arg(long, default_value = "1")]
+    xdp_queues: u32,
+
+    /// Comma-separated CPUs to pin AF_XDP queue workers to (default: the
+    /// worker for queue N runs on CPU N)
+    #[arg(long, value_delimiter = ',', value_name = "CPUS")]
+    xdp_cpus: Vec<usize>,
+
+    /// Run as an inline L3 hop: scrub packets routed into this TUN device
+    /// and re-inject them (Linux only, requires CAP_NET_ADMIN + CAP_NET_RAW)
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["target", "bridge"])]
+    tun: Option<String>,
+
+    /// Firewall mark set on packets re-injected from the TUN device, so
+    /// routing rules can keep them from looping back into it
+    #[arg(long, default_value_t = 0x7473)]
+    tun_fwmark: u32,
+
+    /// Scrub packets sent to this divert port by ipfw/pf rules and
+    /// re-inject them (macOS/FreeBSD only, requires root)
+    #[arg(long, value_name = "PORT", conflicts_with_all = ["target", "bridge", "tun"])]
+    divert: Option<u16>,
+
+    /// Scrub packets matching this WinDivert filter and re-inject them
+    /// (Windows only, built with --features windivert, requires
+    /// Administrator)
+    #[arg(long, value_name = "FILTER", conflicts_with_all = ["target", "bridge", "tun", "divert"])]
+    windivert: Option<String>,
+
+    /// Address for the admin HTTP listener (serves /metrics)
+    #[cfg(feature = "admin")]
+    #[arg(long, value_name = "IP:PORT")]
+    admin_listen: Option<SocketAddr>,
+
+    /// Series each per-route metric keeps before further routes are
+    /// counted under route="other"
+    #[arg(long, default_value_t = tcp_proxy::metrics::DEFAULT_LABEL_LIMIT, value_name = "N")]
+    metrics_label_limit: usize,
+
+    /// Also count connections per client subnet, as
+    /// tcpstrip_source_*_total{source="NAME"} (the CIDR if no NAME); may be
+    /// given multiple times, the first matching group counts
+    #[arg(long, value_name = "[NAME=]CIDR")]
+    source_group: Vec<tcp_proxy::source_stats::SourceGroup>,
+
+    /// How often to sample the listener's accept/SYN queues from the
+    /// kernel (milliseconds, 0 = disabled)
+    #[arg(long, default_value = "1000")]
+    accept_queue_interval_ms: u64,
+
+    /// Splice established connections in the kernel through a BPF sockmap,
+    /// forwarding in userspace only when that is not possible (Linux 5.13+,
+    /// requires CAP_BPF + CAP_NET_ADMIN)
+    #[arg(long)]
+    sockmap: bool,
+
+    /// Blank timestamps (and the options --strip-sack, --strip-fast-open,
+    /// --strip-mptcp and --window-scale remove) from SYNs arriving for the
+    /// listeners and SYN-ACKs arriving from the targets on this interface,
+    /// with an XDP program, before the local stack sees them (Linux 5.3+,
+    /// requires CAP_BPF + CAP_NET_ADMIN)
+    #[arg(long, value_name = "INTERFACE")]
+    xdp_interface: Option<String>,
+
+    /// Carve the forwarding buffers (two per connection, up to
+    /// --max-connections) out of one region of explicit hugepages, falling
+    /// back to transparent hugepages if none are reserved
+    #[arg(long)]
+    hugepage_buffers: bool,
+
+    /// Watch this egress interface and check that proxied connections
+    /// really leave without timestamp options (Linux only, CAP_NET_RAW)
+    #[arg(long, value_name = "IFACE")]
+    verify_egress: Option<String>,
+
+    /// Check one in this many outgoing TCP segments when verifying egress
+    #[arg(long, default_value = "100", value_name = "N")]
+    verify_sample_rate: u32,
+
+    /// Watch the SYN-ACKs backends send on this interface and warn when a
+    /// backend changes the TCP options it answers with (Linux only,
+    /// CAP_NET_RAW)
+    #[arg(long, value_name = "IFACE")]
+    watch_backend_options: Option<String>,
+
+    /// Charge the CPU time of each connection's task to it, so the admin
+    /// API can rank connections by CPU use (two clock reads per wakeup)
+    #[cfg(feature = "admin")]
+    #[arg(long)]
+    cpu_accounting: bool,
+
+    /// Install firewall rules steering --intercept-ports into the proxy at
+    /// startup and remove them on shutdown (Linux only, CAP_NET_ADMIN)
+    #[cfg(target_os = "linux")]
+    #[arg(long, requires = "intercept_ports")]
+    manage_firewall: bool,
+
+    /// Print the firewall rules --manage-firewall would install and remove,
+    /// then exit
+    #[cfg(target_os = "linux")]
+    #[arg(long, requires = "intercept_ports")]
+    firewall_dry_run: bool,
+
+    /// Tool to manage the firewall rules with: nftables or iptables
+    #[cfg(target_os = "linux")]
+    #[arg(long, default_value = "nftables", value_name = "BACKEND")]
+    firewall_backend: tcp_proxy::firewall::Backend,
+
+    /// How intercepted traffic reaches the proxy: redirect (NAT) or tproxy
+    /// (the listener is made IP_TRANSPARENT)
+    #[cfg(target_os = "linux")]
+    #[arg(long, default_value = "redirect", value_name = "MODE")]
+    intercept_mode: tcp_proxy::firewall::InterceptMode,
+
+    /// Connect upstream from the client's own IP address, so the target
+    /// sees the real peer (needs --intercept-mode tproxy rules so replies
+    /// to that address reach the proxy)
+    #[cfg(target_os = "linux")]
+    #[arg(long)]
+    spoof_source: bool,
+
+    /// Comma-separated TCP destination ports to steer into the proxy
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_delimiter = ',', value_name = "PORTS")]
+    intercept_ports: Vec<u16>,
+
+    /// Switch to this user (and its primary group, or GROUP) once the
+    /// listeners, raw sockets and BPF programs are set up, keeping only the
+    /// capabilities still needed (Linux only, must be started as root)
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "USER[:GROUP]")]
+    user: Option<String>,
+
+    /// Group to switch to with --user instead of the user's primary group
+    #[cfg(target_os = "linux")]
+    #[arg(long, requires = "user", value_name = "GROUP")]
+    group: Option<String>,
+
+    /// Comma-separated capabilities to retain after switching to --user,
+    /// besides net_admin for --spoof-source and --manage-firewall, which
+    /// are kept anyway
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_delimiter = ',', requires = "user", value_name = "CAPS")]
+    keep_caps: Vec<tcp_proxy::privileges::Capability>,
+
+    /// Once set up, restrict the process to the system calls forwarding
+    /// needs: enforce (the default) kills it on any other, log only has
+    /// the kernel log them (Linux on x86_64 and aarch64 only)
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    #[arg(long, value_name = "MODE", num_args = 0..=1, require_equals = true, default_missing_value = "enforce", conflicts_with = "manage_firewall")]
+    seccomp: Option<tcp_proxy::seccomp::Mode>,
+
+    /// Detach into the background (double fork, new session); the command
+    /// returns once the proxy is serving, with status 1 if it failed to
+    /// start (Unix only)
+    #[cfg(unix)]
+    #[arg(long, requires = "log_file")]
+    daemon: bool,
+
+    /// Write the process id here and lock the file, refusing to start while
+    /// another instance holds it; removed on shutdown, or emptied if --user
+    /// left no permission to (Unix only)
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH")]
+    pidfile: Option<std::path::PathBuf>,
+
+    /// Log to this file instead of standard output, rotating it by size
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<std::path::PathBuf>,
+
+    /// Size at which --log-file is rotated to PATH.1, PATH.1 to PATH.2, ...
+    #[arg(long, value_name = "BYTES", default_value_t = tcp_proxy::logfile::DEFAULT_MAX_SIZE, requires = "log_file")]
+    log_max_size: u64,
+
+    /// Rotated log files to keep (0 truncates the log instead)
+    #[arg(long, value_name = "N", default_value_t = tcp_proxy::logfile::DEFAULT_KEEP, requires = "log_file")]
+    log_keep: usize,
+
+    /// Only intercept traffic arriving on this interface
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "IFACE")]
+    intercept_interface: Option<String>,
+
+    /// SYN retransmissions before an upstream dial fails (TCP_SYNCNT,
+    /// default: net.ipv4.tcp_syn_retries)
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(1..=127))]
+    syn_retries: Option<u8>,
+
+    /// Milliseconds a target's preferred address family gets to connect
+    /// before its other one (if its name has both) is dialed too
+    #[arg(long, value_name = "MS", default_value = "250", value_parser = clap::value_parser!(u64).range(10..=2000))]
+    happy_eyeballs_delay: u64,
+
+    /// Source address of upstream connections instead of the one the
+    /// routing table picks; repeat to give one per address family
+    #[arg(long, value_name = "IP")]
+    bind_source: Vec<std::net::IpAddr>,
+
+    /// Send upstream connections through this interface (SO_BINDTODEVICE)
+    /// whatever the routing table says, e.g. the dedicated low-latency NIC
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "IFACE")]
+    bind_device: Option<String>,
+
+    /// Local ports of upstream connections, e.g. 40000-40999 so the
+    /// exchange's firewall can be scoped to them; at most one connection
+    /// per port is open at a time
+    #[arg(long, value_name = "FIRST-LAST")]
+    source_ports: Option<tcp_proxy::egress::PortRange>,
+
+    /// Connect upstream through this SOCKS5 or HTTP CONNECT proxy, e.g. a
+    /// mandatory corporate egress proxy:
+    /// socks5://[USER:PASS@]HOST:PORT or http://[USER:PASS@]HOST:PORT
+    #[arg(long, value_name = "URL")]
+    via: Option<tcp_proxy::via::Via>,
+
+    /// Milliseconds an upstream connect attempt may take before it counts
+    /// as failed (default: until the SYN retransmissions run out)
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+    connect_timeout: Option<u64>,
+
+    /// Further attempts after a failed upstream connect, on the next target
+    /// in rotation if there are several, before the client is disconnected
+    #[arg(long, value_name = "N", default_value = "0")]
+    connect_retries: u32,
+
+    /// Milliseconds before the first retry (--connect-retries); each further
+    /// one waits twice as long, up to 5s
+    #[arg(long, value_name = "MS", default_value = "50")]
+    connect_backoff: u64,
+
+    /// Send a PROXY protocol header with the client's address to the
+    /// backend before any payload: v2 (binary, the default) or v1 (text)
+    #[arg(long, value_name = "VERSION", num_args = 0..=1, require_equals = true, default_missing_value = "v2")]
+    proxy_protocol: Option<tcp_proxy::proxy_protocol::Version>,
+
+    /// Expect every client connection to open with a PROXY protocol v1 or v2
+    /// header (from a load balancer in front), strip it, and treat the
+    /// client address it carries as the client's
+    #[arg(long)]
+    accept_proxy_protocol: bool,
+
+    /// Take PROXY protocol headers only from these load balancers (CIDR,
+    /// repeatable); connections from anywhere else are dropped
+    #[arg(long, value_name = "CIDR", requires = "accept_proxy_protocol")]
+    proxy_protocol_from: Vec<tcp_proxy::source_stats::Subnet>,
+
+    /// Admit clients from this subnet (CIDR, repeatable); once any subnet
+    /// is allowed, clients matching no rule are turned away. The most
+    /// specific matching --allow, --deny or --acl-file rule decides
+    #[arg(long, value_name = "CIDR", conflicts_with_all = ["bridge", "tun", "divert", "windivert"])]
+    allow: Vec<tcp_proxy::source_stats::Subnet>,
+
+    /// Turn away clients from this subnet (CIDR, repeatable) before
+    /// anything is dialed for them
+    #[arg(long, value_name = "CIDR", conflicts_with_all = ["bridge", "tun", "divert", "windivert"])]
+    deny: Vec<tcp_proxy::source_stats::Subnet>,
+
+    /// Read further allow/deny rules from a file, one `allow CIDR` or
+    /// `deny CIDR` per line (# starts a comment); re-read on SIGHUP
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["bridge", "tun", "divert", "windivert"])]
+    acl_file: Option<std::path::PathBuf>,
+
+    /// Append tcpstrip TLVs (route, detected protocol, fingerprint risk,
+    /// connection fingerprint) to the PROXY protocol header (v2 only)
+    #[arg(long, requires = "proxy_protocol")]
+    proxy_protocol_tlvs: bool,
+
+    /// Dial the backend only once the client's first message is a
+    /// plausible FIX Logon (BeginString, BodyLength, MsgType A, CheckSum),
+    /// dropping anything else; optionally require this BeginString
+    #[arg(long, num_args = 0..=1, default_missing_value = "any", value_name = "BEGINSTRING")]
+    fix_logon_guard: Option<tcp_proxy::fix::LogonGuard>,
+
+    /// Follow the FIX sessions on proxied connections as they are
+    /// forwarded: sequence numbers, Logon and Logout, messages per type and
+    /// late heartbeats, listed at /fix of the admin listener. Observed
+    /// connections are not spliced with --sockmap
+    #[arg(long)]
+    fix_observe: bool,
+
+    /// Send a copy of what clients send to HOST:PORT as well, such as a
+    /// shadow gateway or a capture box. The copy never holds up the
+    /// connection; a mirror that falls behind by more than --mirror-buffer
+    /// is cut off. Mirrored connections are not spliced with --sockmap
+    #[arg(long, value_name = "HOST:PORT")]
+    mirror: Option<String>,
+
+    /// Bytes a connection may queue for its mirror before the mirror is cut
+    /// off
+    #[arg(long, default_value_t = tcp_proxy::mirror::DEFAULT_BUFFER, value_name = "BYTES")]
+    mirror_buffer: usize,
+
+    /// Record every proxied connection, both directions with nanosecond
+    /// timestamps, to a file of its own in DIR, for `tcpstrip replay`. A
+    /// recording that falls behind by more than --record-buffer stops
+    /// there. Recorded connections are not spliced with --sockmap
+    #[arg(long, value_name = "DIR")]
+    record_dir: Option<std::path::PathBuf>,
+
+    /// Bytes a connection may queue for its recording before the recording
+    /// stops
+    #[arg(long, default_value_t = tcp_proxy::record::DEFAULT_BUFFER, value_name = "BYTES")]
+    record_buffer: usize,
+
+    /// Same as the doctor subcommand
+    #[arg(long)]
+    doctor: bool,
+
+    /// WASM analysis plugin to consult on every proxied connection; may be
+    /// given multiple times
+    #[cfg(feature = "wasm-plugins")]
+    #[arg(long = "plugin", value_name = "PATH")]
+    plugins: Vec<std::path::PathBuf>,
+
+    /// Rhai script whose route(conn) picks the backend (or rejects) for
+    /// each proxied connection
+    #[cfg(feature = "scripting")]
+    #[arg(long, value_name = "PATH")]
+    route_script: Option<std::path::PathBuf>,
+
+    /// SQLite database to record every routed connection in, searchable
+    /// from the admin listener's /history
+    #[cfg(feature = "history")]
+    #[arg(long, value_name = "PATH")]
+    history_db: Option<std::path::PathBuf>,
+
+    /// Terminate client TLS with this PEM certificate chain (leaf first)
+    /// and forward plaintext to the target
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PATH", requires = "tls_key", conflicts_with_all = ["socks5", "fix_logon_guard"])]
+    tls_cert: Option<std::path::PathBuf>,
+
+    /// PEM private key of --tls-cert
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    tls_key: Option<std::path::PathBuf>,
+
+    /// Require TLS clients to present a certificate issued by a CA in this
+    /// PEM file
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    tls_client_ca: Option<std::path::PathBuf>,
+
+    /// Only accept client certificates with this identity (URI or DNS
+    /// subjectAltName, else common name), or a prefix of it ending in *;
+    /// repeatable
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "IDENTITY", requires = "tls_client_ca")]
+    tls_allow: Vec<tcp_proxy::tls::IdentityPattern>,
+
+    /// Wrap upstream connections in TLS, for targets that require it
+    #[cfg(feature = "tls")]
+    #[arg(long)]
+    upstream_tls: bool,
+
+    /// Trust the certificates in this PEM file for --upstream-tls instead
+    /// of the Mozilla roots
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PATH", requires = "upstream_tls")]
+    upstream_tls_ca: Option<std::path::PathBuf>,
+
+    /// Server name to send and verify with --upstream-tls (the target's
+    /// address by default)
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "NAME", requires = "upstream_tls")]
+    upstream_tls_sni: Option<String>,
+
+    /// TLS sessions to keep for resuming --upstream-tls connections
+    /// (0 disables resumption)
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "N", default_value = "256")]
+    upstream_tls_sessions: usize,
+
+    /// Hand TLS legs to the kernel (kTLS) once their handshake is done, so
+    /// rustls no longer en- or decrypts them (Linux, `tls` module)
+    #[cfg(feature = "tls")]
+    #[arg(long)]
+    ktls: bool,
+
+    /// How long to wait for the client's first bytes (TLS SNI, protocol)
+    /// before running the route script or sending the PROXY protocol TLVs
+    /// without them (milliseconds)
+    #[arg(long, default_value = "100")]
+    sniff_timeout_ms: u64,
 }
 
+/// Backlog of the proxy's listening sockets without --listen-backlog
+const DEFAULT_LISTEN_BACKLOG: u32 = 128;
+
+/// How long a drained session has to deliver what the proxy read before
+/// --trading-hours closed, before it is closed regardless
+const TRADING_HOURS_DRAIN_GRACE: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// How long a client on a --fix-logon-guard route has to send its Logon
+const FIX_LOGON_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Settings of the socket proxy
+///
+/// Each listener publishes its settings as an immutable snapshot in an
+/// `ArcSwap`: accepting a connection takes a reference to the current one
+/// without copying it or taking a lock, and an ACL reload (SIGHUP) swaps
+/// in a new snapshot atomically while connections keep the one they
+/// started with. Each --route gets a copy with its own target and
+/// overrides.
 #[derive(Clone)]
 struct ProxyConfig {
-    target_addr: SocketAddr,
+    /// Fixed upstreams; None with --transparent, where every connection
+    /// goes to its original destination
+    targets: Option<Arc<tcp_proxy::balance::Pool>>,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    listen_port: u16,
     spoof_timestamps: bool,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
     static_timestamp: u32,
-    buffer_size: usize,
+    /// Relay buffers where no --relay-buffers rule matches
+    buffer_size: tcp_proxy::route::BufferSizes,
+    arena: Option<Arc<tcp_proxy::arena::BufferArena>>,
+    #[cfg(feature = "wasm-plugins")]
+    plugins: Option<Arc<std::sync::Mutex<tcp_proxy::plugin::PluginHost>>>,
+    #[cfg(feature = "scripting")]
+    router: Option<Arc<tcp_proxy::script::RoutingScript>>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<tcp_proxy::tls::Terminator>>,
+    /// Client certificate identities let in (--tls-allow); empty for any
+    #[cfg(feature = "tls")]
+    tls_allow: Vec<tcp_proxy::tls::IdentityPattern>,
+    #[cfg(feature = "tls")]
+    upstream_tls: Option<Arc<tcp_proxy::tls::Originator>>,
+    /// Offload TLS legs to the kernel (--ktls)
+    #[cfg(feature = "tls")]
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    ktls: bool,
+    sniff_timeout: std::time::Duration,
+    #[cfg(target_os = "linux")]
+    splicer: Option<Arc<tcp_proxy::sockmap::Splicer>>,
+    #[cfg(target_os = "linux")]
+    verified_flows: Option<Arc<tcp_proxy::verify::ProxiedFlows>>,
+    #[cfg(target_os = "linux")]
+    backend_options: Option<Arc<tcp_proxy::backend_watch::BackendOptions>>,
+    #[cfg(target_os = "linux")]
+    spoof_source: bool,
+    #[cfg(target_os = "linux")]
+    syn_retries: Option<u8>,
+    /// Head start of a target's preferred address family (Happy Eyeballs)
+    happy_eyeballs_delay: std::time::Duration,
+    connect_timeout: Option<std::time::Duration>,
+    connect_retries: u32,
+    connect_backoff: std::time::Duration,
+    /// Source address and interface of upstream connections
+    egress: tcp_proxy::egress::Egress,
+    /// Egress proxy upstream connections are tunneled through (--via)
+    via: Option<tcp_proxy::via::Via>,
+    /// MSS for upstream connections (--mss-clamp with a byte count)
+    #[cfg_attr(not(unix), allow(dead_code))]
+    mss_clamp: Option<u16>,
+    proxy_protocol: Option<tcp_proxy::proxy_protocol::Version>,
+    proxy_protocol_tlvs: bool,
+    /// Targets by TLS server name (--sni-route)
+    sni_routes: Option<Arc<tcp_proxy::sni::SniRoutes>>,
+    /// Unrouted connections go to their original destination (--transparent)
+    transparent: bool,
+    /// Clients name their destinations in a SOCKS5 handshake (--socks5)
+    socks5: bool,
+    socks5_auth: Option<tcp_proxy::socks::Credentials>,
+    /// Clients arrive through a load balancer (--accept-proxy-protocol)
+    accept_proxy_protocol: bool,
+    proxy_protocol_from: Vec<tcp_proxy::source_stats::Subnet>,
+    /// --max-connections slots, shared by every listener
+    admission: Arc<tcp_proxy::admission::Admission>,
+    /// Source address rules (--allow, --deny, --acl-file)
+    acl: Option<Arc<tcp_proxy::acl::Acl>>,
+    /// --max-connections-per-ip and --max-connect-rate-per-ip
+    source_limiter: Option<Arc<tcp_proxy::source_limit::SourceLimiter>>,
+    trading_hours: Option<Arc<tcp_proxy::trading_hours::TradingHours>>,
+    fix_logon_guard: Option<tcp_proxy::fix::LogonGuard>,
+    /// Follow FIX sessions in the userspace relay (--fix-observe)
+    fix_observe: bool,
+    /// Shadow target client bytes are copied to (--mirror)
+    mirror: Option<SocketAddr>,
+    mirror_buffer: usize,
+    /// Directory connections are recorded to (--record-dir)
+    record_dir: Option<Arc<std::path::Path>>,
+    record_buffer: usize,
+    source_groups: Option<Arc<tcp_proxy::source_stats::SourceGroups>>,
+    /// --dscp rules for upstream connections
+    dscp: Vec<tcp_proxy::dscp::DscpRule>,
+    forward_priority: Vec<tcp_proxy::route::RouteRule<tcp_proxy::route::ForwardPriority>>,
+    relay_buffers: Vec<tcp_proxy::route::RouteRule<tcp_proxy::route::BufferSizes>>,
+    idle_policy: Vec<tcp_proxy::route::RouteRule<tcp_proxy::idle::IdlePolicy>>,
+    idle_timeout: Vec<tcp_proxy::route::RouteRule<tcp_proxy::idle::IdleTimeout>>,
+    max_rate_kbps: Vec<tcp_proxy::route::RouteRule<tcp_proxy::ratelimit::RateLimit>>,
+    /// --tcp-user-timeout, --tcp-keepalive and --quickack rules
+    tuning: tcp_proxy::tuning::TuningRules,
+    listen_backlog: u32,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize tracing for performance monitoring
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .with_target(false)
-        .compact()
-        .init();
-
-    let args = Args::parse();
-    
-    // Resolve target address once at startup
-    let target_addr = args.target.to_socket_addrs()?
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("Could not resolve target address: {}", args.target))?;
+fn main() -> Result<()> {
+    let mut args = match Cli::parse() {
+        Cli {
+            command: Some(Command::Doctor(doctor)),
+            ..
+        } => {
+            init_logging(None);
+            return run_doctor(doctor.listen_backlog, doctor.syn_retries);
+        }
+        Cli {
+            command: Some(Command::Tune(tune)),
+            ..
+        } => {
+            init_logging(None);
+            return run_tune(&tune);
+        }
+        Cli {
+            command: Some(Command::Verify(verify)),
+            ..
+        } => {
+            init_logging(None);
+            return run_verify(&verify);
+        }
+        #[cfg(feature = "history")]
+        Cli {
+            command: Some(Command::Report(report)),
+            ..
+        } => {
+            init_logging(None);
+            return run_report(&report);
+        }
+        Cli {
+            command: Some(Command::Monitor(monitor)),
+            ..
+        } => {
+            init_logging(None);
+            return run_monitor(&monitor);
+        }
+        Cli {
+            command: Some(Command::Bench(bench)),
+            ..
+        } => {
+            init_logging(None);
+            return run_bench(&bench);
+        }
+        #[cfg(feature = "analyze")]
+        Cli {
+            command: Some(Command::Analyze(analyze)),
+            ..
+        } => {
+            init_logging(None);
+            return run_analyze(&analyze);
+        }
+        Cli {
+            command: Some(Command::Replay(replay)),
+            ..
+        } => {
+            init_logging(None);
+            return run_replay(&replay);
+        }
+        Cli {
+            command: Some(Command::Proxy(args)),
+            ..
+        } => *args,
+        Cli { command: None, args } => args,
+    };
+    resolve_paths(&mut args)?;
 
-    let config = ProxyConfig {
-        target_addr,
-        spoof_timestamps: args.spoof_timestamps,
-        static_timestamp: args.static_timestamp,
-        buffer_size: args.buffer_size,
+    let log = match &args.log_file {
+        Some(path) => Some(
+            tcp_proxy::logfile::RotatingFile::open(path, args.log_max_size, args.log_keep)
+                .map_err(|e| anyhow::anyhow!("Could not open log file {}: {}", path.display(), e))?,
+        ),
+        None => None,
     };
+    let logs_to_file = log.is_some();
+    init_logging(log);
+    #[cfg(unix)]
+    let pidfile = args.pidfile.clone();
+    // Before the runtime starts threads, which a fork would not take along
+    #[cfg(unix)]
+    if args.daemon {
+        tcp_proxy::daemon::daemonize().map_err(|e| anyhow::anyhow!("Could not daemonize: {}", e))?;
+    }
 
-    info!("Starting TCP proxy on port {} -> {}", args.port, target_addr);
-    info!("Timestamp spoofing: {}", config.spoof_timestamps);
-    info!("Max connections: {}", args.max_connections);
+    let result = (|| {
+        // Written while still root, so it can go in /run
+        #[cfg(unix)]
+        if let Some(path) = &pidfile {
+            tcp_proxy::daemon::write_pidfile(path).map_err(|e| anyhow::anyhow!("Could not write pidfile {}: {}", path.display(), e))?;
+        }
 
-    // Create high-performance listener socket
-    let listener = create_high_performance_listener(args.port).await?;
-    
-    // Connection counter for monitoring
-    let connection_count = Arc::new(std::sync::atomic::AtomicUsize::new(0));
-    
-    loop {
-        match listener.accept().await {
-            Ok((client_stream, client_addr)) => {
-                let config = config.clone();
-                let conn_count = connection_count.clone();
-                
-                // Spawn connection handler
-                tokio::spawn(async move {
-                    let conn_id = conn_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                    debug!("New connection {} from {}", conn_id, client_addr);
-                    
-                    if let Err(e) = handle_connection(client_stream, config, conn_id).await {
-                        error!("Connection {} error: {}", conn_id, e);
-                    }
-                    
-                    conn_count.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
-                    debug!("Connection {} closed", conn_id);
-                });
-            }
-            Err(e) => {
-                error!("Failed to accept connection: {}", e);
-            }
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| anyhow::anyhow!("Could not start the runtime: {}", e))?
+            .block_on(run(args))
+    })();
+    #[cfg(unix)]
+    tcp_proxy::daemon::remove_pidfile();
+    // Standard error may be /dev/null by now
+    if let Err(e) = &result {
+        if logs_to_file {
+            error!("{}", e);
         }
     }
+    result
 }
 
-/// Create a high-performance TCP listener with optimized socket options
-async fn create_high_performance_listener(port: u16) -> Result<TcpListener> {
-    // Use socket2 for low-level socket control
-    let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
-    
-    // Critical HFT socket options for minimal latency
-    socket.set_reuse_address(true)?;
-    socket.set_reuse_port(true)?;
-    socket.set_nodelay(true)?;  // TCP_NODELAY - disable Nagle's algorithm
-    
-    // Set TCP_USER_TIMEOUT to fail fast on connection issues  
-    #[cfg(target_os = "linux")]
-    {
-        use std::os::unix::io::AsRawFd;
-        let fd = socket.as_raw_fd();
-        
-        // Set TCP_USER_TIMEOUT to 5 seconds (5000ms)
-        let timeout: libc::c_int = 5000;
-        unsafe {
-            libc::setsockopt(
-                fd,
-                libc::IPPROTO_TCP,
-                libc::TCP_USER_TIMEOUT,
-                &timeout as *const _ as *const libc::c_void,
-                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
-            );
-        }
+/// Log INFO and up to standard output, or to --log-file
+fn init_logging(file: Option<tcp_proxy::logfile::RotatingFile>) {
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .compact();
+    match file {
+        Some(file) => subscriber.with_ansi(false).with_writer(std::sync::Mutex::new(file)).init(),
+        None => subscriber.init(),
     }
-    
-    let addr = format!("0.0.0.0:{}", port).parse::<SocketAddr>()?;
-    socket.bind(&addr.into())?;
-    socket.listen(128)?;
-    
-    // Convert to tokio TcpListener
-    let std_listener: std::net::TcpListener = socket.into();
-    std_listener.set_nonblocking(true)?;
-    let listener = TcpListener::from_std(std_listener)?;
-    
-    Ok(listener)
 }
 
-/// Handle a single client connection with timestamp option stripping
-async fn handle_connection(
-    client_stream: TcpStream,
-    config: ProxyConfig,
-    conn_id: usize,
-) -> Result<()> {
+async fn run(args: Args) -> Result<()> {
+    if args.datapath != Datapath::AfPacket && args.bridge.is_none() {
+        anyhow::bail!("--datapath {} only applies to --bridge mode", args.datapath);
+    }
+    if args.protocol == tcp_proxy::udp::Protocol::Udp {
+        check_udp_args(&args)?;
+    } else if args.quic {
+        anyhow::bail!("--quic needs --protocol udp");
+    }
+
+    tcp_proxy::metrics::registry().set_label_limit(args.metrics_label_limit);
+    tcp_proxy::features::flags().configure(args.feature.clone());
+    #[cfg(feature = "history")]
+    if let Some(path) = &args.history_db {
+        tcp_proxy::history::open(path)
+            .map_err(|e| anyhow::anyhow!("Could not open history database {}: {}", path.display(), e))?;
+        info!("Recording connection history in {}", path.display());
+    }
+    if let Some(dir) = &args.record_dir {
+        std::fs::create_dir_all(dir).map_err(|e| anyhow::anyhow!("Could not create recording directory {}: {}", dir.display(), e))?;
+    }
+    #[cfg(feature = "admin")]
+    if let Some(admin_addr) = args.admin_listen {
+        tokio::spawn(async move {
+            if let Err(e) = tcp_proxy::admin::serve(admin_addr).await {
+                error!("Admin listener failed: {}", e);
+            }
+        });
+    }
+
+    // The packet datapaths have no connections for the kill switch
+    let packet_mode = match (&args.bridge, &args.tun, args.divert, &args.windivert) {
+        (Some(_), _, _, _) => Some("bridge"),
+        (_, Some(_), _, _) => Some("TUN"),
+        (_, _, Some(_), _) => Some("divert"),
+        (_, _, _, Some(_)) => Some("WinDivert"),
+        _ => None,
+    };
+    if let Some(mode) = packet_mode {
+        tcp_proxy::killswitch::unavailable(mode);
+        info!("The kill switch is not available in {} mode, which forwards packets rather than connections", mode);
+    }
+
+    if let Some(interfaces) = &args.bridge {
+        return run_bridge(&args, &interfaces[0], &interfaces[1]).await;
+    }
+
+    if let Some(name) = &args.tun {
+        return run_tun(&args, name).await;
+    }
+
+    if let Some(port) = args.divert {
+        return run_divert(&args, port).await;
+    }
+
+    if let Some(filter) = &args.windivert {
+        return run_windivert(&args, filter).await;
+    }
+
+    // Resolve target addresses at startup; names are followed from then on
+    let targets = match args.target.is_empty() {
+        true => None,
+        false => {
+            if !args.balance.uses_weights() && args.target.iter().any(|target| target.weight != 1) {
+                anyhow::bail!("--balance {} ignores target weights; use weighted, least-conn or source-hash", args.balance);
+            }
+            let resolved = args
+                .target
+                .iter()
+                .map(|target| resolve_target(&target.host))
+                .collect::<Result<Vec<_>>>()?;
+            let backends: Vec<(SocketAddr, u32)> =
+                resolved.iter().zip(&args.target).map(|(&(addr, _), target)| (addr, target.weight)).collect();
+            let pool = tcp_proxy::balance::Pool::with_strategy(&backends, args.balance);
+            let backup = match args.backup_target.as_deref() {
+                Some(backup) => Some((backup, resolve_target(backup)?)),
+                None => None,
+            };
+            let pool = Arc::new(match backup {
+                Some((_, (addr, _))) => pool.with_backup(addr),
+                None => pool,
+            });
+            let names = args.target.iter().map(|target| target.host.as_str()).zip(resolved.iter().map(|&(_, fallback)| fallback));
+            follow_dns(&pool, names.chain(backup.map(|(name, (_, fallback))| (name, fallback))));
+            Some(pool)
+        }
+    };
+
+    #[cfg(target_os = "linux")]
+    let spoof_source = args.spoof_source || args.route.iter().any(|route| route.spoof_source == Some(true));
+    #[cfg(target_os = "linux")]
+    if spoof_source && args.intercept_mode != tcp_proxy::firewall::InterceptMode::Tproxy {
+        anyhow::bail!("--spoof-source needs --intercept-mode tproxy, or replies to the client's address never reach the proxy");
+    }
+    if args.proxy_protocol_tlvs && args.proxy_protocol == Some(tcp_proxy::proxy_protocol::Version::V1) {
+        anyhow::bail!("--proxy-protocol-tlvs needs --proxy-protocol=v2; v1 headers have no room for TLVs");
+    }
+    #[cfg(not(target_os = "linux"))]
+    if args.doctor {
+        return run_doctor(args.listen_backlog, None);
+    }
+    #[cfg(target_os = "linux")]
+    if args.doctor {
+        return run_doctor(args.listen_backlog, args.syn_retries);
+    }
+    #[cfg(target_os = "linux")]
+    if (args.manage_firewall || args.firewall_dry_run) && !args.route.is_empty() {
+        anyhow::bail!("--manage-firewall steers traffic to a single listener and cannot be combined with --route");
+    }
+    #[cfg(target_os = "linux")]
+    if args.firewall_dry_run {
+        print!("{}", tcp_proxy::firewall::RulePlan::new(&firewall_config(&args)));
+        return Ok(());
+    }
+
+    let trading_hours = match args.trading_hours.is_empty() {
+        true => None,
+        false => {
+            let zone = tcp_proxy::tz::Zone::load(&args.trading_timezone).map_err(|e| anyhow::anyhow!("--trading-timezone: {}", e))?;
+            Some(tcp_proxy::trading_hours::TradingHours::new(zone, args.trading_hours.clone(), args.outside_trading_hours))
+        }
+    };
+    let config = ProxyConfig {
+        targets,
+        listen_port: listen_addr(&args).port(),
+        spoof_timestamps: args.spoof_timestamps,
+        static_timestamp: args.static_timestamp,
+        buffer_size: tcp_proxy::route::BufferSizes::both(args.buffer_size),
+        arena: create_arena(&args)?,
+        #[cfg(feature = "wasm-plugins")]
+        plugins: load_plugins(&args.plugins)?,
+        #[cfg(feature = "scripting")]
+        router: load_route_script(args.route_script.as_deref())?,
+        #[cfg(feature = "tls")]
+        tls: load_tls(&args)?,
+        #[cfg(feature = "tls")]
+        tls_allow: args.tls_allow.clone(),
+        #[cfg(feature = "tls")]
+        upstream_tls: upstream_tls(&args)?,
+        #[cfg(feature = "tls")]
+        ktls: args.ktls,
+        sniff_timeout: std::time::Duration::from_millis(args.sniff_timeout_ms),
+        #[cfg(target_os = "linux")]
+        splicer: create_splicer(&args)?,
+        #[cfg(target_os = "linux")]
+        verified_flows: start_egress_verifier(&args)?,
+        #[cfg(target_os = "linux")]
+        backend_options: start_backend_watcher(&args)?,
+        #[cfg(target_os = "linux")]
+        spoof_source: args.spoof_source,
+        #[cfg(target_os = "linux")]
+        syn_retries: args.syn_retries,
+        happy_eyeballs_delay: std::time::Duration::from_millis(args.happy_eyeballs_delay),
+        connect_timeout: args.connect_timeout.map(std::time::Duration::from_millis),
+        connect_retries: args.connect_retries,
+        connect_backoff: std::time::Duration::from_millis(args.connect_backoff),
+        egress: egress(&args)?,
+        via: args.via.clone(),
+        mss_clamp: match mss_clamp(&args, &[])? {
+            Some(tcp_proxy::scrub::MssClamp::Fixed(mss)) => Some(mss),
+            _ => None,
+        },
+        proxy_protocol: args.proxy_protocol,
+        proxy_protocol_tlvs: args.proxy_protocol_tlvs,
+        sni_routes: sni_routes(&args)?,
+        transparent: args.transparent,
+        socks5: args.socks5,
+        socks5_auth: args.socks5_auth.clone(),
+        accept_proxy_protocol: args.accept_proxy_protocol,
+        proxy_protocol_from: args.proxy_protocol_from.clone(),
+        admission: tcp_proxy::admission::Admission::new(
+            args.max_connections,
+            args.max_connections_action,
+            std::time::Duration::from_millis(args.max_connections_queue_timeout),
+        ),
+        acl: AclSources::of(&args).load()?,
+        trading_hours,
+        source_limiter: match (args.max_connections_per_ip, args.max_connect_rate_per_ip) {
+            (None, None) => None,
+            (max_connections, rate) => Some(tcp_proxy::source_limit::SourceLimiter::new(
+                max_connections.map(|max| max as usize),
+                rate,
+                args.connect_rate_exceeded,
+            )),
+        },
+        fix_logon_guard: args.fix_logon_guard.clone(),
+        fix_observe: args.fix_observe,
+        mirror: match &args.mirror {
+            Some(mirror) => Some(resolve_target(mirror).map_err(|e| anyhow::anyhow!("Could not resolve mirror {}: {}", mirror, e))?.0),
+            None => None,
+        },
+        mirror_buffer: args.mirror_buffer,
+        record_dir: args.record_dir.as_deref().map(Arc::from),
+        record_buffer: args.record_buffer,
+        source_groups: match args.source_group.is_empty() {
+            true => None,
+            false => Some(Arc::new(tcp_proxy::source_stats::SourceGroups::new(&args.source_group))),
+        },
+        dscp: args.dscp.clone(),
+        forward_priority: args.forward_priority.clone(),
+        relay_buffers: args.relay_buffers.clone(),
+        idle_policy: args.idle_policy.clone(),
+        idle_timeout: args.idle_timeout.clone(),
+        max_rate_kbps: args.max_rate_kbps.clone(),
+        tuning: tcp_proxy::tuning::TuningRules {
+            user_timeout: args.tcp_user_timeout.clone(),
+            keepalive: args.tcp_keepalive.clone(),
+            quickack: args.quickack.clone(),
+        },
+        listen_backlog: args.listen_backlog,
+    };
+    let egress = config.egress.clone();
+    if let Some(hours) = &config.trading_hours {
+        let windows: Vec<_> = hours.windows().iter().map(ToString::to_string).collect();
+        let now = match hours.is_open() {
+            true => "open",
+            false => "closed",
+        };
+        info!("Trading hours: {} ({}), then {}; {} now", windows.join(", "), hours.zone(), hours.outside(), now);
+        tokio::spawn(hours.clone().watch());
+    }
+    if let Some(maintenance) = maintenance(&args)? {
+        let windows: Vec<_> = maintenance.windows().iter().map(ToString::to_string).collect();
+        info!("Maintenance windows: {} ({})", windows.join(", "), maintenance.zone());
+    }
+
+    // One listener per --route, or the one of --port/--listen
+    let routes = match args.route.is_empty() {
+        true => vec![(listen_addr(&args), config)],
+        false => args
+            .route
+            .iter()
+            .map(|route| Ok((route.listen, route_config(&config, route)?)))
+            .collect::<Result<Vec<_>>>()?,
+    };
+    let mode = match args.protocol {
+        tcp_proxy::udp::Protocol::Tcp => "TCP proxy",
+        tcp_proxy::udp::Protocol::Udp => "UDP relay",
+    };
+    for (listen, config) in &routes {
+        if config.sni_routes.is_some() {
+            info!("Starting TLS SNI router on {}", listen);
+        }
+        match &config.targets {
+            Some(pool) => {
+                let targets: Vec<String> = pool.addrs().map(|addr| addr.to_string()).collect();
+                info!("Starting {} on {} -> {}", mode, listen, targets.join(", "));
+                if targets.len() > 1 {
+                    info!("Balancing connections: {}", pool.strategy());
+                }
+                if let Some(backup) = pool.backup() {
+                    info!("Backup target: {}", backup);
+                }
+            }
+            None if config.socks5 => info!("Starting SOCKS5 proxy on {}", listen),
+            None if config.sni_routes.is_some() && !config.transparent => {}
+            None => info!("Starting transparent TCP proxy on {} -> original destinations", listen),
+        }
+        if args.protocol == tcp_proxy::udp::Protocol::Tcp {
+            info!("Timestamp spoofing: {}", config.spoof_timestamps);
+        }
+        if config.fix_observe {
+            info!("Observing FIX sessions on {}", listen);
+        }
+        if let Some(mirror) = config.mirror {
+            info!("Mirroring client traffic on {} to {}", listen, mirror);
+        }
+        if let Some(dir) = &config.record_dir {
+            info!("Recording connections on {} to {}", listen, dir.display());
+        }
+    }
+    #[cfg(target_os = "linux")]
+    if args.verify_egress.is_some() || args.watch_backend_options.is_some() {
+        match capture_scope(&routes) {
+            Ok(scope) => tcp_proxy::capture::captures().confine(scope),
+            Err(reason) => info!("Capturing every TCP segment on the interface: {}", reason),
+        }
+    }
+    if let Some(check) = health_check(&args, &egress)? {
+        let check = Arc::new(check);
+        for pool in routes.iter().filter_map(|(_, config)| config.targets.as_ref()) {
+            tcp_proxy::health::spawn(pool.clone(), check.clone());
+        }
+        info!("Health checking targets every {}ms", args.health_interval.unwrap_or_default());
+    }
+    match (args.protocol, args.max_connections_action) {
+        (tcp_proxy::udp::Protocol::Tcp, tcp_proxy::admission::Saturated::Queue) => info!(
+            "Max connections: {}, then queue for up to {}ms",
+            args.max_connections, args.max_connections_queue_timeout
+        ),
+        (tcp_proxy::udp::Protocol::Tcp, action) => info!("Max connections: {}, then {}", args.max_connections, action),
+        (tcp_proxy::udp::Protocol::Udp, _) => info!("Max connections: {}", args.max_connections),
+    }
+    if let Some(max) = args.max_connections_per_ip {
+        info!("Max connections per client address: {}", max);
+    }
+    if let Some(rate) = args.max_connect_rate_per_ip {
+        info!("Max new connections per client address: {}/s, then {}", rate, args.connect_rate_exceeded);
+    }
+    #[cfg(not(target_os = "linux"))]
+    if args.transparent {
+        anyhow::bail!("--transparent is only available on Linux");
+    }
+    #[cfg(not(target_os = "linux"))]
+    if args.sockmap {
+        anyhow::bail!("--sockmap is only available on Linux");
+    }
+    #[cfg(not(target_os = "linux"))]
+    if args.xdp_interface.is_some() {
+        anyhow::bail!("--xdp-interface is only available on Linux");
+    }
+    #[cfg(all(not(target_os = "linux"), feature = "tls"))]
+    if args.ktls {
+        anyhow::bail!("--ktls is only available on Linux");
+    }
+    #[cfg(feature = "tls")]
+    if args.ktls {
+        if args.tls_cert.is_none() && !args.upstream_tls {
+            anyhow::bail!("--ktls needs --tls-cert or --upstream-tls");
+        }
+        info!("Offloading TLS to the kernel once handshakes are done (kTLS)");
+    }
+    #[cfg(not(target_os = "linux"))]
+    if args.verify_egress.is_some() {
+        anyhow::bail!("--verify-egress is only available on Linux");
+    }
+    #[cfg(not(target_os = "linux"))]
+    if args.watch_backend_options.is_some() {
+        anyhow::bail!("--watch-backend-options is only available on Linux");
+    }
+    if args.protocol == tcp_proxy::udp::Protocol::Udp {
+        return run_udp(&args, routes).await;
+    }
+    open_files(&args);
+
+    // Create high-performance listener socket
+    #[cfg(target_os = "linux")]
+    let transparent = args.intercept_mode == tcp_proxy::firewall::InterceptMode::Tproxy;
+    #[cfg(not(target_os = "linux"))]
+    let transparent = false;
+    // Under socket activation systemd has bound the listeners already
+    #[cfg(target_os = "linux")]
+    let mut inherited = tcp_proxy::systemd::Inherited::take()
+        .map_err(|e| anyhow::anyhow!("Could not take over the sockets systemd passed: {}", e))?;
+    let mut listeners = Vec::with_capacity(routes.len());
+    for (listen, config) in routes {
+        #[cfg(target_os = "linux")]
+        if let Some(socket) = inherited.listener(listen) {
+            info!("Listening on {} with the socket systemd passed", listen);
+            listeners.push((inherited_listener(socket)?, Arc::new(ArcSwap::from_pointee(config))));
+            continue;
+        }
+        let listener = create_high_performance_listener(listen, args.ipv6_only, transparent, &config)
+            .await
+            .map_err(|e| match e.downcast_ref::<std::io::Error>().and_then(std::io::Error::raw_os_error) {
+                Some(libc::EADDRNOTAVAIL) => anyhow::anyhow!("Could not listen on {}: no interface has that address", listen),
+                _ => anyhow::anyhow!("Could not listen on {}: {}", listen, e),
+            })?;
+        listeners.push((listener, Arc::new(ArcSwap::from_pointee(config))));
+    }
+    #[cfg(target_os = "linux")]
+    for addr in inherited.unused() {
+        warn!("systemd passed a socket on {} that no listener uses", addr);
+    }
+
+    // Only steer traffic here once the listener is up
+    #[cfg(target_os = "linux")]
+    let rules = match args.manage_firewall {
+        true => Some(
+            tcp_proxy::firewall::install(&firewall_config(&args))
+                .map_err(|e| anyhow::anyhow!("Could not install firewall rules: {}", e))?,
+        ),
+        false => None,
+    };
+
+    #[cfg(target_os = "linux")]
+    if let Some(interface) = &args.xdp_interface {
+        start_ingress_scrubber(&args, interface, &listeners)?;
+    }
+
+    #[cfg(target_os = "linux")]
+    if args.accept_queue_interval_ms > 0 {
+        let interval = std::time::Duration::from_millis(args.accept_queue_interval_ms);
+        let ports = listeners.iter().map(|(listener, _)| listener.local_addr().map(|addr| addr.port())).collect::<std::io::Result<_>>()?;
+        tokio::spawn(monitor_accept_queue(ports, interval));
+    }
+    
+    // Connection ids are unique for the life of the process so the admin
+    // API can refer to them
+    let next_conn_id = Arc::new(std::sync::atomic::AtomicU64::new(0));
+    // Only the admin API reads the CPU times
+    #[cfg(feature = "admin")]
+    let cpu_accounting = args.cpu_accounting;
+    #[cfg(not(feature = "admin"))]
+    let cpu_accounting = false;
+    // No connection is served with root privileges
+    #[cfg(target_os = "linux")]
+    ready(&args, rules)?;
+    #[cfg(all(unix, not(target_os = "linux")))]
+    ready(&args, ())?;
+    #[cfg(unix)]
+    tokio::spawn(reload_acl_on_signal(
+        AclSources::of(&args),
+        listeners.iter().map(|(_, snapshot)| snapshot.clone()).collect(),
+    ));
+    let mut servers = tokio::task::JoinSet::new();
+    for (listener, config) in listeners {
+        servers.spawn(serve(listener, config, next_conn_id.clone(), cpu_accounting));
+    }
+    while let Some(server) = servers.join_next().await {
+        server?;
+    }
+    Ok(())
+}
+
+/// Make every path option absolute
+///
+/// The daemon works from `/`, and some files are opened long after startup
+/// (--acl-file on SIGHUP, --record-dir per connection), so relative paths
+/// are resolved against the directory the proxy was started in, once.
+fn resolve_paths(args: &mut Args) -> Result<()> {
+    let resolve = |path: &mut std::path::PathBuf| -> Result<()> {
+        *path = std::path::absolute(&*path).map_err(|e| anyhow::anyhow!("Could not resolve {}: {}", path.display(), e))?;
+        Ok(())
+    };
+    let mut paths: Vec<&mut std::path::PathBuf> = Vec::new();
+    paths.extend([&mut args.log_file, &mut args.acl_file, &mut args.record_dir].into_iter().flatten());
+    #[cfg(unix)]
+    paths.extend(&mut args.pidfile);
+    #[cfg(feature = "wasm-plugins")]
+    paths.extend(&mut args.plugins);
+    #[cfg(feature = "scripting")]
+    paths.extend(&mut args.route_script);
+    #[cfg(feature = "history")]
+    paths.extend(&mut args.history_db);
+    #[cfg(feature = "tls")]
+    paths.extend([&mut args.tls_cert, &mut args.tls_key, &mut args.tls_client_ca, &mut args.upstream_tls_ca].into_iter().flatten());
+    paths.into_iter().try_for_each(resolve)
+}
+
+/// Settings of one --route: the global ones with its target and overrides
+fn route_config(base: &ProxyConfig, route: &tcp_proxy::route::ListenerRoute) -> Result<ProxyConfig> {
+    let (target_addr, fallback) = resolve_target(&route.target)?;
+    let mut config = base.clone();
+    let pool = Arc::new(tcp_proxy::balance::Pool::new(&[target_addr]));
+    follow_dns(&pool, [(route.target.as_str(), fallback)]);
+    config.targets = Some(pool);
+    config.listen_port = route.listen.port();
+    config.buffer_size = route.buffer_size.unwrap_or(base.buffer_size);
+    config.spoof_timestamps = route.spoof_timestamps.unwrap_or(base.spoof_timestamps);
+    config.static_timestamp = route.static_timestamp.unwrap_or(base.static_timestamp);
+    #[cfg(target_os = "linux")]
+    {
+        config.spoof_source = route.spoof_source.unwrap_or(base.spoof_source);
+    }
+    #[cfg(not(target_os = "linux"))]
+    if route.spoof_source == Some(true) {
+        anyhow::bail!("spoof-source is only available on Linux");
+    }
+    config.proxy_protocol = route.proxy_protocol.unwrap_or(base.proxy_protocol);
+    config.accept_proxy_protocol = route.accept_proxy_protocol.unwrap_or(base.accept_proxy_protocol);
+    config.listen_backlog = route.backlog.unwrap_or(base.listen_backlog);
+    if let Some(guard) = &route.fix_logon {
+        config.fix_logon_guard = guard.clone();
+    }
+    config.fix_observe = route.fix_observe.unwrap_or(base.fix_observe);
+    match &route.mirror {
+        Some(Some(mirror)) => {
+            let (addr, _) = resolve_target(mirror).map_err(|e| anyhow::anyhow!("Could not resolve mirror {}: {}", mirror, e))?;
+            config.mirror = Some(addr);
+        }
+        Some(None) => config.mirror = None,
+        None => {}
+    }
+    #[cfg(feature = "tls")]
+    if config.tls.is_some() && config.fix_logon_guard.is_some() {
+        anyhow::bail!("fix-logon-guard cannot see through --tls-cert; the Logon is encrypted");
+    }
+    Ok(config)
+}
+
+/// The --health-* settings, if checks are enabled
+fn health_check(args: &Args, egress: &tcp_proxy::egress::Egress) -> Result<Option<tcp_proxy::health::HealthCheck>> {
+    use tcp_proxy::health::parse_bytes;
+
+    let Some(interval) = args.health_interval else {
+        return Ok(None);
+    };
+    let bytes = |s: &Option<String>| s.as_deref().map(parse_bytes).transpose().map_err(|e| anyhow::anyhow!(e));
+    Ok(Some(tcp_proxy::health::HealthCheck {
+        interval: std::time::Duration::from_millis(interval.max(1)),
+        timeout: std::time::Duration::from_millis(args.health_timeout),
+        rise: args.health_rise,
+        fall: args.health_fall,
+        send: bytes(&args.health_send)?,
+        expect: bytes(&args.health_expect)?,
+        egress: egress.clone(),
+        via: args.via.clone(),
+        maintenance: maintenance(args)?,
+    }))
+}
+
+/// The --maintenance windows, if any were given
+fn maintenance(args: &Args) -> Result<Option<tcp_proxy::trading_hours::Schedule>> {
+    if args.maintenance.is_empty() {
+        return Ok(None);
+    }
+    let zone = tcp_proxy::tz::Zone::load(&args.trading_timezone).map_err(|e| anyhow::anyhow!("--trading-timezone: {}", e))?;
+    Ok(Some(tcp_proxy::trading_hours::Schedule::new(zone, args.maintenance.clone())))
+}
+
+/// The --bind-source, --bind-device and --source-ports settings
+fn egress(args: &Args) -> Result<tcp_proxy::egress::Egress> {
+    #[cfg(target_os = "linux")]
+    let device = args.bind_device.clone();
+    #[cfg(not(target_os = "linux"))]
+    let device = None;
+    let egress = tcp_proxy::egress::Egress::new(args.bind_source.clone(), device, args.source_ports)
+        .map_err(|e| anyhow::anyhow!("Invalid upstream binding: {}", e))?;
+    for ip in &args.bind_source {
+        info!("Connecting upstream from {}", ip);
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(device) = &args.bind_device {
+        info!("Connecting upstream through {}", device);
+    }
+    if let Some(ports) = args.source_ports {
+        info!("Connecting upstream from ports {}", ports);
+    }
+    if let Some(via) = &args.via {
+        info!("Connecting upstream through {} ({})", via, via.addr);
+    }
+    Ok(egress)
+}
+
+/// The --sni-route map, with every target resolved
+fn sni_routes(args: &Args) -> Result<Option<Arc<tcp_proxy::sni::SniRoutes>>> {
+    if args.sni_route.is_empty() {
+        return Ok(None);
+    }
+    let resolved = args
+        .sni_route
+        .iter()
+        .map(|route| Ok((route.clone(), resolve_target(&route.target)?)))
+        .collect::<Result<Vec<_>>>()?;
+    for (route, (addr, _)) in &resolved {
+        info!("Routing TLS server name {} to {} ({})", route.name, route.target, addr);
+    }
+    Ok(Some(Arc::new(tcp_proxy::sni::SniRoutes::new(resolved))))
+}
+
+/// Give the members of `pool` the fallbacks of their `targets` (in
+/// `Pool::members` order) and move them along with their DNS records
+fn follow_dns<'a>(
+    pool: &Arc<tcp_proxy::balance::Pool>,
+    targets: impl IntoIterator<Item = (&'a str, Option<SocketAddr>)>,
+) {
+    for (member, (target, fallback)) in pool.members().zip(targets) {
+        pool.set_fallback(member, fallback);
+        let pool = pool.clone();
+        tcp_proxy::dns::spawn(target.to_string(), pool.addr(member), fallback, move |addr, fallback| {
+            if pool.addr(member) != addr {
+                pool.set_addr(member, addr);
+            }
+            pool.set_fallback(member, fallback);
+            #[cfg(target_os = "linux")]
+            tcp_proxy::capture::captures().refresh();
+        });
+    }
+}
+
+/// First address a HOST:PORT target resolves to, and the first of the
+/// other address family for Happy Eyeballs
+fn resolve_target(target: &str) -> Result<(SocketAddr, Option<SocketAddr>)> {
+    let resolved: Vec<SocketAddr> = target.to_socket_addrs()?.collect();
+    let addr = *resolved
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("Could not resolve target address: {}", target))?;
+    Ok((addr, tcp_proxy::eyeballs::fallback(addr, &resolved)))
+}
+
+/// Accept connections on one listener and proxy each in its own task
+async fn serve(
+    listener: TcpListener,
+    snapshot: Arc<ArcSwap<ProxyConfig>>,
+    next_conn_id: Arc<std::sync::atomic::AtomicU64>,
+    cpu_accounting: bool,
+) {
+    let mut backoff = tcp_proxy::accept::Backoff::default();
+    loop {
+        match listener.accept().await {
+            Ok((client_stream, client_addr)) => {
+                backoff.succeeded();
+                // IPv4 clients of a dual-stack listener arrive as ::ffff:a.b.c.d
+                let client_addr = SocketAddr::new(client_addr.ip().to_canonical(), client_addr.port());
+                let config = snapshot.load_full();
+                let conn_id = next_conn_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                debug!("New connection {} from {}", conn_id, client_addr);
+
+                use tcp_proxy::admission::{reset, Saturated};
+                if tcp_proxy::killswitch::is_engaged() {
+                    debug!("Connection {} from {} reset (kill switch engaged)", conn_id, client_addr);
+                    tcp_proxy::killswitch::count_refused();
+                    reset(client_stream);
+                    continue;
+                }
+                if let Some(hours) = config.trading_hours.as_ref().filter(|hours| !hours.is_open()) {
+                    debug!("Connection {} from {} reset (outside --trading-hours)", conn_id, client_addr);
+                    hours.count_refused();
+                    reset(client_stream);
+                    continue;
+                }
+                // Refused clients must not take a --max-connections slot;
+                // behind a load balancer they are only known once the
+                // PROXY header is in
+                let permit = if config.accept_proxy_protocol {
+                    None
+                } else {
+                    match screen(&config, conn_id, client_addr) {
+                        Some(permit) => permit,
+                        None => {
+                            reset(client_stream);
+                            continue;
+                        }
+                    }
+                };
+                match config.admission.try_admit() {
+                    Ok(slot) => admit(client_stream, client_addr, config, conn_id, cpu_accounting, slot, permit),
+                    Err(Saturated::Queue) => {
+                        debug!("Connection {} from {} queued (--max-connections reached)", conn_id, client_addr);
+                        tokio::spawn(async move {
+                            match config.admission.queue().await {
+                                Some(slot) => admit(client_stream, client_addr, config, conn_id, cpu_accounting, slot, permit),
+                                None => {
+                                    warn!("Connection {} from {} reset (no slot freed up in the queue)", conn_id, client_addr);
+                                    reset(client_stream);
+                                }
+                            }
+                        });
+                    }
+                    Err(_) => {
+                        warn!("Connection {} from {} reset (--max-connections reached)", conn_id, client_addr);
+                        reset(client_stream);
+                    }
+                }
+            }
+            Err(e) => {
+                use tcp_proxy::accept::Failure;
+
+                let failure = Failure::of(&e);
+                tcp_proxy::accept::count(failure);
+                if !failure.is_exhaustion() {
+                    // The client gave up before it was accepted
+                    debug!("Failed to accept connection: {}", e);
+                    continue;
+                }
+                if failure == Failure::FdLimit && tcp_proxy::accept::shed(&listener) {
+                    warn!("Out of file descriptors, reset a connection on accept: {}", e);
+                } else {
+                    error!("Failed to accept connection: {}", e);
+                }
+                tokio::time::sleep(backoff.failed()).await;
+            }
+        }
+    }
+}
+
+/// Apply --raise-nofile, warn when the open files limit cannot cover
+/// --max-connections, and set the accept loops' reserve descriptor aside
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn open_files(args: &Args) {
+    #[cfg(unix)]
+    {
+        if args.raise_nofile {
+            match tcp_proxy::accept::raise_nofile_limit() {
+                Ok((before, after)) if before != after => info!("Raised the open files limit from {} to {}", before, after),
+                Ok(_) => {}
+                Err(e) => warn!("Could not raise the open files limit: {}", e),
+            }
+        }
+        // Two per connection, and some for listeners, logs and the like
+        let needed = args.max_connections as u64 * 2 + 64;
+        match tcp_proxy::accept::nofile_limit() {
+            Ok(limit) if limit < needed => warn!(
+                "The open files limit ({}) is too low for {} connections; raise it to {} (--raise-nofile, ulimit -n)",
+                limit, args.max_connections, needed
+            ),
+            _ => {}
+        }
+    }
+    if let Err(e) = tcp_proxy::accept::reserve_fd() {
+        warn!("Could not set a descriptor aside for running out of them: {}", e);
+    }
+}
+
+/// A client's per-client limit permit and how long it must wait
+type ClientPermit = Option<(tcp_proxy::source_limit::SourcePermit, std::time::Duration)>;
+
+/// Run the ACL and the per-client limits on `client_addr`, None when
+/// either refuses it
+fn screen(config: &ProxyConfig, conn_id: u64, client_addr: SocketAddr) -> Option<ClientPermit> {
+    if let Some(acl) = &config.acl {
+        match acl.check(client_addr.ip()) {
+            (tcp_proxy::acl::Action::Deny, Some(rule)) => {
+                debug!("Connection {} from {} reset ({})", conn_id, client_addr, rule);
+                return None;
+            }
+            (tcp_proxy::acl::Action::Deny, None) => {
+                debug!("Connection {} from {} reset (no allow rule)", conn_id, client_addr);
+                return None;
+            }
+            (tcp_proxy::acl::Action::Allow, _) => {}
+        }
+    }
+    match &config.source_limiter {
+        Some(limiter) => match limiter.admit(client_addr.ip()) {
+            Ok(permit) => Some(Some(permit)),
+            Err(reason) => {
+                debug!("Connection {} from {} reset (per-client {} limit)", conn_id, client_addr, reason);
+                None
+            }
+        },
+        None => Some(None),
+    }
+}
+
+/// Proxy a connection that got a --max-connections slot, and passed
+/// `screen` unless it still has a PROXY header to send
+fn admit(
+    client_stream: TcpStream,
+    client_addr: SocketAddr,
+    config: Arc<ProxyConfig>,
+    conn_id: u64,
+    cpu_accounting: bool,
+    slot: tcp_proxy::admission::Slot,
+    permit: ClientPermit,
+) {
+    // Behind a load balancer the client is only known once its header is
+    // in, which must not hold up the accept loop
+    if config.accept_proxy_protocol {
+        tokio::spawn(async move {
+            let mut client_stream = client_stream;
+            match accept_proxy_header(&mut client_stream, client_addr, &config).await {
+                Ok(client_addr) => match screen(&config, conn_id, client_addr) {
+                    Some(permit) => spawn_connection(client_stream, client_addr, config, conn_id, cpu_accounting, slot, permit),
+                    None => tcp_proxy::admission::reset(client_stream),
+                },
+                Err(e) => {
+                    tcp_proxy::metrics::registry()
+                        .counter("tcpstrip_proxy_header_rejects_total", "Client connections dropped for a missing, invalid or untrusted PROXY protocol header")
+                        .inc();
+                    warn!("Connection {} from {} dropped: {}", conn_id, client_addr, e);
+                }
+            }
+        });
+    } else {
+        spawn_connection(client_stream, client_addr, config, conn_id, cpu_accounting, slot, permit);
+    }
+}
+
+/// Proxy an accepted connection from `client_addr` in its own task, which
+/// holds on to its `slot` and per-client `permit` until it ends
+fn spawn_connection(
+    client_stream: TcpStream,
+    client_addr: SocketAddr,
+    config: Arc<ProxyConfig>,
+    conn_id: u64,
+    #[cfg_attr(not(feature = "admin"), allow(unused_variables))]
+    cpu_accounting: bool,
+    slot: tcp_proxy::admission::Slot,
+    permit: ClientPermit,
+) {
+
+    // The guard keeps the connection listed until its task ends or is
+    // killed through the admin API; without it nothing lists connections
+    #[cfg(feature = "admin")]
+    let guard = tcp_proxy::connections::registry().register(conn_id, client_addr);
+    #[cfg(not(feature = "admin"))]
+    let guard = tcp_proxy::connections::ConnectionGuard::unlisted(conn_id, client_addr);
+    #[cfg(feature = "admin")]
+    let connection = guard.connection().clone();
+    #[cfg(unix)]
+    let client_fd = {
+        use std::os::unix::io::AsRawFd;
+        client_stream.as_raw_fd()
+    };
+    let task = async move {
+        let _slot = slot;
+        let connection = guard.connection();
+        let setup = async {
+            // Over its rate, the client waits before anything is dialed
+            let _permit = match permit {
+                Some((permit, delay)) => {
+                    if !delay.is_zero() {
+                        debug!("Connection {} from {} delayed {:?} (per-client rate limit)", conn_id, client_addr, delay);
+                        tokio::time::sleep(delay).await;
+                    }
+                    Some(permit)
+                }
+                None => None,
+            };
+            handle_connection(client_stream, config, connection).await
+        };
+        tokio::pin!(setup);
+        // The relay answers the kill switch itself, resetting both legs; a
+        // connection still being set up has its client reset here, while
+        // the future still holds the stream
+        let handled = tokio::select! {
+            biased;
+            handled = &mut setup => handled,
+            _ = tcp_proxy::killswitch::engaged() => {
+                #[cfg(unix)]
+                on_legs([client_fd], |leg| leg.set_linger(Some(std::time::Duration::ZERO)));
+                tcp_proxy::killswitch::count_reset();
+                debug!("Connection {} reset during setup (kill switch engaged)", conn_id);
+                return;
+            }
+        };
+        if let Err(e) = handled {
+            match connection.fingerprint() {
+                Some(fingerprint) => error!("Connection {} [{}] error: {}", conn_id, fingerprint, e),
+                None => error!("Connection {} error: {}", conn_id, e),
+            }
+        }
+        debug!("Connection {} closed", conn_id);
+    };
+
+    // Spawn connection handler
+    #[cfg(feature = "admin")]
+    {
+        let handle = if cpu_accounting {
+            tokio::spawn(tcp_proxy::connections::CpuTimed::new(connection.clone(), task))
+        } else {
+            tokio::spawn(task)
+        };
+        connection.set_abort_handle(handle.abort_handle());
+    }
+    #[cfg(not(feature = "admin"))]
+    tokio::spawn(task);
+}
+
+/// Where the ACL comes from, kept to build it again on reload
+struct AclSources {
+    allow: Vec<tcp_proxy::source_stats::Subnet>,
+    deny: Vec<tcp_proxy::source_stats::Subnet>,
+    file: Option<std::path::PathBuf>,
+}
+
+impl AclSources {
+    fn of(args: &Args) -> Self {
+        Self {
+            allow: args.allow.clone(),
+            deny: args.deny.clone(),
+            file: args.acl_file.clone(),
+        }
+    }
+
+    /// The --allow, --deny and --acl-file rules, if any were given
+    fn load(&self) -> Result<Option<Arc<tcp_proxy::acl::Acl>>> {
+        use tcp_proxy::acl::{Action, Rule};
+
+        let mut rules: Vec<Rule> = self
+            .allow
+            .iter()
+            .map(|&subnet| Rule { action: Action::Allow, subnet })
+            .chain(self.deny.iter().map(|&subnet| Rule { action: Action::Deny, subnet }))
+            .collect();
+        if let Some(path) = &self.file {
+            rules.extend(tcp_proxy::acl::load(path).map_err(|e| anyhow::anyhow!("Could not read ACL file {}: {}", path.display(), e))?);
+        }
+        if rules.is_empty() {
+            return Ok(None);
+        }
+        info!("Admitting clients by {} ACL rule(s)", rules.len());
+        Ok(Some(Arc::new(tcp_proxy::acl::Acl::new(&rules))))
+    }
+}
+
+/// Read the PROXY protocol header of a connection from the load balancer
+/// at `peer`; the client it names, or `peer` if it names none
+async fn accept_proxy_header(client_stream: &mut TcpStream, peer: SocketAddr, config: &ProxyConfig) -> Result<SocketAddr> {
+    use tcp_proxy::proxy_protocol::{read_header, HEADER_TIMEOUT};
+
+    let trusted = config.proxy_protocol_from.is_empty()
+        || config.proxy_protocol_from.iter().any(|subnet| subnet.contains(peer.ip()));
+    if !trusted {
+        anyhow::bail!("not in --proxy-protocol-from");
+    }
+    let header = tokio::time::timeout(HEADER_TIMEOUT, read_header(client_stream))
+        .await
+        .map_err(|_| anyhow::anyhow!("no PROXY protocol header within {}s", HEADER_TIMEOUT.as_secs()))?
+        .map_err(|e| anyhow::anyhow!("invalid PROXY protocol header: {}", e))?;
+    Ok(match header {
+        Some((client, _)) => {
+            let client = SocketAddr::new(client.ip().to_canonical(), client.port());
+            debug!("Connection from {} is relayed for {}", peer, client);
+            client
+        }
+        None => peer,
+    })
+}
+
+/// Refuse options that only mean something for TCP connections
+fn check_udp_args(args: &Args) -> Result<()> {
+    #[allow(unused_mut)]
+    let mut tcp_only = vec![
+        ("--bridge", args.bridge.is_some()),
+        ("--tun", args.tun.is_some()),
+        ("--divert", args.divert.is_some()),
+        ("--windivert", args.windivert.is_some()),
+        ("--transparent", args.transparent),
+        ("--socks5", args.socks5),
+        ("--sni-route", !args.sni_route.is_empty()),
+        ("--health-interval", args.health_interval.is_some()),
+        ("--via", args.via.is_some()),
+        ("--proxy-protocol", args.proxy_protocol.is_some()),
+        ("--accept-proxy-protocol", args.accept_proxy_protocol),
+        ("--fix-logon-guard", args.fix_logon_guard.is_some()),
+        ("--fix-observe", args.fix_observe),
+        ("--mirror", args.mirror.is_some()),
+        ("--record-dir", args.record_dir.is_some()),
+        ("--max-connections-action", args.max_connections_action != tcp_proxy::admission::Saturated::Reject),
+        ("--max-connections-per-ip", args.max_connections_per_ip.is_some()),
+        ("--idle-timeout", !args.idle_timeout.is_empty()),
+        ("--max-rate-kbps", !args.max_rate_kbps.is_empty()),
+        ("--tcp-user-timeout", !args.tcp_user_timeout.is_empty()),
+        ("--tcp-keepalive", !args.tcp_keepalive.is_empty()),
+        ("--quickack", !args.quickack.is_empty()),
+        ("--listen-backlog", args.listen_backlog != DEFAULT_LISTEN_BACKLOG),
+        ("--trading-hours", !args.trading_hours.is_empty()),
+        ("--max-connect-rate-per-ip", args.max_connect_rate_per_ip.is_some()),
+        ("--spoof-timestamps", args.spoof_timestamps),
+        ("--sockmap", args.sockmap),
+        ("--xdp-interface", args.xdp_interface.is_some()),
+        ("--verify-egress", args.verify_egress.is_some()),
+        (
+            "--route options other than buffer-size",
+            args.route.iter().any(|route| {
+                route.spoof_timestamps.is_some()
+                    || route.spoof_source.is_some()
+                    || route.proxy_protocol.is_some()
+                    || route.accept_proxy_protocol.is_some()
+                    || route.fix_logon.is_some()
+                    || route.fix_observe.is_some()
+                    || route.mirror.is_some()
+                    || route.backlog.is_some()
+            }),
+        ),
+    ];
+    #[cfg(target_os = "linux")]
+    tcp_only.extend([("--manage-firewall", args.manage_firewall), ("--spoof-source", args.spoof_source)]);
+    #[cfg(feature = "wasm-plugins")]
+    tcp_only.push(("--plugin", !args.plugins.is_empty()));
+    #[cfg(feature = "scripting")]
+    tcp_only.push(("--route-script", args.route_script.is_some()));
+    #[cfg(feature = "tls")]
+    tcp_only.extend([("--tls-cert", args.tls_cert.is_some()), ("--upstream-tls", args.upstream_tls)]);
+    if let Some((option, _)) = tcp_only.iter().find(|(_, given)| *given) {
+        anyhow::bail!("{} cannot be combined with --protocol udp", option);
+    }
+    Ok(())
+}
+
+/// Relay UDP datagrams on every listener instead of proxying TCP
+async fn run_udp(args: &Args, routes: Vec<(SocketAddr, ProxyConfig)>) -> Result<()> {
+    use tcp_proxy::udp::{RelayConfig, UdpRelay};
+
+    let idle_timeout = std::time::Duration::from_secs(args.udp_idle_timeout);
+    info!("UDP sessions expire after {}s idle", args.udp_idle_timeout);
+    if args.quic {
+        info!("Following QUIC connections by connection ID");
+    }
+    let mut relays = tokio::task::JoinSet::new();
+    for (listen, config) in routes {
+        let targets = config.targets.ok_or_else(|| anyhow::anyhow!("--protocol udp needs --target or --route"))?;
+        let relay = UdpRelay::bind(
+            listen,
+            args.ipv6_only,
+            RelayConfig {
+                targets,
+                egress: config.egress,
+                dscp: config.dscp,
+                idle_timeout,
+                max_sessions: args.max_connections,
+                quic: args.quic,
+                acl: config.acl,
+            },
+        )
+        .map_err(|e| anyhow::anyhow!("Could not listen for UDP on {}: {}", listen, e))?;
+        relays.spawn(relay.run());
+    }
+    #[cfg(unix)]
+    ready(args, ())?;
+    while let Some(relay) = relays.join_next().await {
+        relay?.map_err(|e| anyhow::anyhow!("UDP relay failed: {}", e))?;
+    }
+    Ok(())
+}
+
+/// Run the wire-level AF_PACKET bridge instead of the socket proxy
+#[cfg(target_os = "linux")]
+async fn run_bridge(args: &Args, inside: &str, outside: &str) -> Result<()> {
+    use tcp_proxy::bridge::{Bridge, BridgeConfig};
+
+    if args.datapath == Datapath::AfXdp {
+        return run_xdp_bridge(args, inside, outside).await;
+    }
+
+    let bridge = Bridge::open(BridgeConfig {
+        inside: inside.to_string(),
+        outside: outside.to_string(),
+        policy: scrub_policy(args, &[inside, outside])?,
+        entropy: args.entropy_source.clone(),
+        frame_buffer_size: args.buffer_size,
+    })?;
+
+    // Periodic counters so operators can see the bridge is doing its job
+    let stats = bridge.stats();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            info!("Bridge outbound: {}", stats.outbound.summary());
+            info!("Bridge inbound: {}", stats.inbound.summary());
+        }
+    });
+
+    ready(args, ())?;
+    tokio::task::spawn_blocking(move || bridge.run()).await??;
+    Ok(())
+}
+
+/// Run the bridge over AF_XDP sockets
+#[cfg(target_os = "linux")]
+async fn run_xdp_bridge(args: &Args, inside: &str, outside: &str) -> Result<()> {
+    use tcp_proxy::xdp::{XdpBridge, XdpBridgeConfig};
+
+    let bridge = XdpBridge::open(XdpBridgeConfig {
+        inside: inside.to_string(),
+        outside: outside.to_string(),
+        policy: scrub_policy(args, &[inside, outside])?,
+        entropy: args.entropy_source.clone(),
+        queues: args.xdp_queues,
+        cpus: args.xdp_cpus.clone(),
+        idle: tcp_proxy::route::catch_all(&args.idle_policy).unwrap_or_default(),
+    })?;
+
+    let stats = bridge.stats();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            info!("Bridge outbound: {}", stats.outbound.summary());
+            info!("Bridge inbound: {}", stats.inbound.summary());
+        }
+    });
+
+    ready(args, ())?;
+    tokio::task::spawn_blocking(move || bridge.run()).await??;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn run_bridge(_args: &Args, _inside: &str, _outside: &str) -> Result<()> {
+    anyhow::bail!("Bridge mode requires AF_PACKET and is only available on Linux")
+}
+
+/// Run the TUN inline scrubber instead of the socket proxy
+#[cfg(target_os = "linux")]
+async fn run_tun(args: &Args, name: &str) -> Result<()> {
+    use tcp_proxy::tun::{TunConfig, TunScrubber};
+
+    let scrubber = TunScrubber::open(TunConfig {
+        name: name.to_string(),
+        fwmark: args.tun_fwmark,
+        policy: scrub_policy(args, &[name])?,
+        entropy: args.entropy_source.clone(),
+        packet_buffer_size: args.buffer_size,
+    })?;
+
+    let stats = scrubber.stats();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            info!("TUN scrubber: {}", stats.summary());
+        }
+    });
+
+    ready(args, ())?;
+    tokio::task::spawn_blocking(move || scrubber.run()).await??;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn run_tun(_args: &Args, _name: &str) -> Result<()> {
+    anyhow::bail!("TUN mode is only available on Linux")
+}
+
+/// Run the divert-socket scrubber instead of the socket proxy
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+async fn run_divert(args: &Args, port: u16) -> Result<()> {
+    use tcp_proxy::divert::{DivertConfig, DivertScrubber};
+
+    let scrubber = DivertScrubber::open(DivertConfig {
+        port,
+        policy: scrub_policy(args, &[])?,
+        entropy: args.entropy_source.clone(),
+        packet_buffer_size: args.buffer_size,
+    })?;
+
+    let stats = scrubber.stats();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            info!("Divert scrubber: {}", stats.summary());
+        }
+    });
+
+    ready(args, ())?;
+    tokio::task::spawn_blocking(move || scrubber.run()).await??;
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "freebsd")))]
+async fn run_divert(_args: &Args, _port: u16) -> Result<()> {
+    anyhow::bail!("Divert mode is only available on macOS and FreeBSD")
+}
+
+/// Run the WinDivert scrubber instead of the socket proxy
+#[cfg(all(windows, feature = "windivert"))]
+async fn run_windivert(args: &Args, filter: &str) -> Result<()> {
+    use tcp_proxy::divert_windows::{WinDivertConfig, WinDivertScrubber};
+
+    let scrubber = WinDivertScrubber::open(WinDivertConfig {
+        filter: filter.to_string(),
+        policy: scrub_policy(args, &[])?,
+        entropy: args.entropy_source.clone(),
+        packet_buffer_size: args.buffer_size,
+    })?;
+
+    let stats = scrubber.stats();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            info!("WinDivert scrubber: {}", stats.summary());
+        }
+    });
+
+    tokio::task::spawn_blocking(move || scrubber.run()).await??;
+    Ok(())
+}
+
+#[cfg(not(all(windows, feature = "windivert")))]
+async fn run_windivert(_args: &Args, _filter: &str) -> Result<()> {
+    anyhow::bail!("WinDivert mode is only available on Windows builds with --features windivert")
+}
+
+/// Scrubbing policy for the wire-level datapaths, whose packets pass
+/// through `interfaces`
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd", all(windows, feature = "windivert")))]
+fn scrub_policy(args: &Args, interfaces: &[&str]) -> Result<tcp_proxy::scrub::ScrubPolicy> {
+    use tcp_proxy::packet::TimestampAction;
+
+    let timestamps = if args.randomize_timestamps {
+        TimestampAction::Randomize
+    } else if args.spoof_timestamps && args.static_timestamp != 0 {
+        TimestampAction::Spoof(args.static_timestamp)
+    } else {
+        TimestampAction::Strip
+    };
+    if args.rewrite_dry_run {
+        warn!("Rewrite dry run: packets are forwarded unmodified");
+    }
+
+    args.return_echo.check(timestamps).map_err(|e| anyhow::anyhow!(e))?;
+
+    Ok(tcp_proxy::scrub::ScrubPolicy {
+        timestamps,
+        echo: args.return_echo,
+        mss_clamp: mss_clamp(args, interfaces)?,
+        window_scale: args.window_scale,
+        strip_sack: args.strip_sack,
+        strip_fast_open: args.strip_fast_open,
+        strip_mptcp: args.strip_mptcp,
+        unknown_options: args.unknown_options,
+        canonical_options: args.canonical_options,
+        personality: args.personality,
+        ttl: args.ttl,
+        randomize_ip_id: args.randomize_ip_id,
+        dscp: args.dscp.clone(),
+        clear_ecn: args.clear_ecn,
+        sanitize_flags: args.sanitize_flags,
+        dry_run: args.rewrite_dry_run,
+    })
+}
+
+/// Resolve --mss-clamp for a datapath whose packets pass through
+/// `interfaces`
+///
+/// With "pmtu" the bound comes from the smallest of their MTUs; without
+/// interfaces it is None, since the kernel already sizes the segments of
+/// its own sockets to the route MTU.
+fn mss_clamp(args: &Args, interfaces: &[&str]) -> Result<Option<tcp_proxy::scrub::MssClamp>> {
+    use tcp_proxy::scrub::MssClamp;
+
+    match args.mss_clamp.as_deref() {
+        None => Ok(None),
+        Some("pmtu") => {
+            let mut mtu = None;
+            for interface in interfaces {
+                let interface_mtu = interface_mtu(interface)
+                    .map_err(|e| anyhow::anyhow!("Could not read the MTU of {}: {}", interface, e))?;
+                mtu = Some(mtu.map_or(interface_mtu, |mtu: u16| mtu.min(interface_mtu)));
+            }
+            if let Some(mtu) = mtu {
+                info!("Clamping SYN MSS to MTU {}", mtu);
+            }
+            Ok(mtu.map(MssClamp::Mtu))
+        }
+        Some(bytes) => match bytes.parse::<u16>() {
+            Ok(mss) if mss >= 536 => Ok(Some(MssClamp::Fixed(mss))),
+            _ => anyhow::bail!("--mss-clamp takes a byte count of at least 536 or \"pmtu\", not {:?}", bytes),
+        },
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn interface_mtu(name: &str) -> std::io::Result<u16> {
+    use std::os::unix::io::AsRawFd;
+
+    if name.len() >= libc::IFNAMSIZ {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "interface name too long"));
+    }
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    let mut req: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in req.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFMTU as _, &mut req) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { req.ifr_ifru.ifru_mtu } as u16)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn interface_mtu(_name: &str) -> std::io::Result<u16> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "interface MTUs are only read on Linux"))
+}
+
+/// Periodically sample the kernel's view of our listen queues into metrics,
+/// summed over the listeners on `ports`
+///
+/// Peaks are tracked between scrapes so short bursts at market open show up
+/// even when the scrape interval is much longer than the burst.
+#[cfg(target_os = "linux")]
+async fn monitor_accept_queue(ports: Vec<u16>, interval: std::time::Duration) {
+    use tcp_proxy::sock_diag::{listen_overflow_stats, listen_queue_stats, ListenQueueStats};
+
+    let registry = tcp_proxy::metrics::registry();
+    let accept_queue = registry.gauge("tcpstrip_listen_accept_queue", "Connections waiting in the accept queue");
+    let accept_queue_peak = registry.gauge("tcpstrip_listen_accept_queue_peak", "Highest accept queue depth observed");
+    let accept_backlog = registry.gauge("tcpstrip_listen_accept_backlog", "Configured accept queue limit");
+    let syn_backlog = registry.gauge("tcpstrip_listen_syn_backlog", "Half-open connections in SYN_RECV");
+    let syn_backlog_peak = registry.gauge("tcpstrip_listen_syn_backlog_peak", "Highest SYN_RECV count observed");
+    let overflows = registry.counter("tcpstrip_listen_overflows_total", "Accept queue overflows (namespace-wide)");
+    let drops = registry.counter("tcpstrip_listen_drops_total", "Dropped SYNs on listeners (namespace-wide)");
+
+    let mut ticker = tokio::time::interval(interval);
+    let mut last_overflows = None;
+
+    loop {
+        ticker.tick().await;
+
+        // Queues of all the routes' listeners add up
+        let ports = ports.clone();
+        let sample = tokio::task::spawn_blocking(move || {
+            let queues = ports.iter().try_fold(ListenQueueStats::default(), |total, &port| {
+                listen_queue_stats(port).map(|q| ListenQueueStats {
+                    accept_queue: total.accept_queue + q.accept_queue,
+                    accept_backlog: total.accept_backlog + q.accept_backlog,
+                    syn_backlog: total.syn_backlog + q.syn_backlog,
+                })
+            });
+            (queues, listen_overflow_stats())
+        }).await;
+        let (queues, overflow) = match sample {
+            Ok(sample) => sample,
+            Err(_) => continue,
+        };
+
+        match queues {
+            Ok(q) => {
+                accept_queue.set(q.accept_queue as u64);
+                accept_queue_peak.set_max(q.accept_queue as u64);
+                accept_backlog.set(q.accept_backlog as u64);
+                syn_backlog.set(q.syn_backlog as u64);
+                syn_backlog_peak.set_max(q.syn_backlog as u64);
+            }
+            Err(e) => {
+                warn!("Accept queue sampling failed, disabling: {}", e);
+                return;
+            }
+        }
+
+        if let Ok(o) = overflow {
+            if let Some(last) = last_overflows {
+                if o.overflows > last {
+                    warn!("Listen queue overflowed {} times in the last {:?}", o.overflows - last, interval);
+                }
+            }
+            last_overflows = Some(o.overflows);
+            overflows.set(o.overflows);
+            drops.set(o.drops);
+        }
+    }
+}
+
+/// Address the socket proxy listens on (--listen, or 0.0.0.0:--port)
+fn listen_addr(args: &Args) -> SocketAddr {
+    args.listen.unwrap_or(SocketAddr::from(([0, 0, 0, 0], args.port)))
+}
+
+/// Create a high-performance TCP listener with optimized socket options
+///
+/// An IPv6 listener is dual-stack unless `ipv6_only`: IPV6_V6ONLY is set
+/// either way, so the system default (net.ipv6.bindv6only) does not decide.
+async fn create_high_performance_listener(addr: SocketAddr, ipv6_only: bool, transparent: bool, config: &ProxyConfig) -> Result<TcpListener> {
+    // Use socket2 for low-level socket control
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    
+    // Critical HFT socket options for minimal latency
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_nodelay(true)?;  // TCP_NODELAY - disable Nagle's algorithm
+    
+    // Accepted connections inherit TCP_USER_TIMEOUT and keepalive, so they
+    // fail fast before their destination is known
+    config.tuning.catch_all().apply(socket2::SockRef::from(&socket))?;
+    
+    // TPROXY only delivers connections for foreign addresses to
+    // transparent sockets
+    #[cfg(target_os = "linux")]
+    if transparent {
+        set_transparent(&socket, addr)?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = transparent;
+    
+    socket.bind(&addr.into())?;
+    socket.listen(config.listen_backlog.min(i32::MAX as u32) as i32)?;
+    
+    // Convert to tokio TcpListener
+    let std_listener: std::net::TcpListener = socket.into();
+    std_listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(std_listener)?;
+    
+    Ok(listener)
+}
+
+/// The client leg, once any --tls-cert handshake is done
+enum ClientStream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<tcp_proxy::tls::Terminated>),
+}
+
+impl ClientStream {
+    /// The socket under any TLS
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            ClientStream::Plain(stream) => stream,
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(tls) => tls.get_ref().0.get_ref(),
+        }
+    }
+
+    /// The socket under any TLS, for writes that precede it (SOCKS5 replies)
+    fn tcp_mut(&mut self) -> &mut TcpStream {
+        match self {
+            ClientStream::Plain(stream) => stream,
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(tls) => tls.get_mut().0.get_mut(),
+        }
+    }
+
+    /// Identity of the client's verified certificate (--tls-client-ca)
+    #[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+    fn identity(&self) -> Option<String> {
+        match self {
+            ClientStream::Plain(_) => None,
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(tls) => tcp_proxy::tls::client_identity(tls),
+        }
+    }
+}
+
+/// Handle a single client connection with timestamp option stripping
+async fn handle_connection(
+    mut client_stream: TcpStream,
+    config: Arc<ProxyConfig>,
+    connection: &tcp_proxy::connections::Connection,
+) -> Result<()> {
+    let conn_id = connection.id;
     // Configure client socket for HFT performance
-    configure_hft_socket(&client_stream).await?;
+    configure_hft_socket(&client_stream, &config).await?;
+    let tally = match &config.source_groups {
+        Some(groups) => groups.tally(connection.client.ip()),
+        None => Default::default(),
+    };
+
+    // A TLS server name with a --sni-route beats the --target pool
+    let sni_target = match &config.sni_routes {
+        Some(routes) => {
+            let sni = peek_server_name(&client_stream, config.sniff_timeout).await?;
+            let routed = sni.as_deref().and_then(|sni| routes.lookup(sni));
+            routes.count(routed.map(|(name, _, _)| name));
+            debug!("Connection {}: server name {:?} routed by {:?}", conn_id, sni, routed.map(|(name, _, _)| name));
+            routed.map(|(_, addr, fallback)| (addr, fallback))
+        }
+        None => None,
+    };
+    let lease = match sni_target {
+        Some(_) => None,
+        None => config.targets.as_ref().map(|targets| targets.pick_for(connection.client.ip())),
+    };
+    let mut requested_fallback = None;
+    let default_target = match (&lease, sni_target) {
+        (_, Some((addr, fallback))) => {
+            requested_fallback = fallback;
+            addr
+        }
+        (Some(lease), None) => lease.addr,
+        (None, None) if config.sni_routes.is_some() && !config.transparent => {
+            warn!("Connection {} from {} dropped: no --sni-route for its server name", conn_id, connection.client);
+            tally.rejected();
+            return Ok(());
+        }
+        (None, None) if config.socks5 => match socks_destination(&mut client_stream, &config, conn_id).await? {
+            Some((addr, fallback)) => {
+                requested_fallback = fallback;
+                addr
+            }
+            None => {
+                tally.rejected();
+                return Ok(());
+            }
+        },
+        (None, None) => original_destination(&client_stream, &config)?,
+    };
+
+    // Client TLS is terminated before anything else decides on the
+    // connection, so its certificate can be checked and routed on
+    #[cfg(feature = "tls")]
+    let mut client_stream = match &config.tls {
+        Some(terminator) => match terminator.accept(client_stream).await {
+            Ok(tls) => {
+                let tls = ClientStream::Tls(Box::new(tls));
+                if let Err(reason) = check_client_identity(&tls, &config, conn_id, connection.client) {
+                    warn!("Connection {} from {} dropped: {}", conn_id, connection.client, reason);
+                    tally.rejected();
+                    return Ok(());
+                }
+                tls
+            }
+            Err(e) => {
+                warn!("Connection {} from {} dropped: TLS handshake failed: {}", conn_id, connection.client, e);
+                tally.rejected();
+                return Ok(());
+            }
+        },
+        None => ClientStream::Plain(client_stream),
+    };
+    #[cfg(not(feature = "tls"))]
+    let mut client_stream = ClientStream::Plain(client_stream);
+
+    #[cfg(feature = "scripting")]
+    let target_addr = match route_connection(&client_stream, connection.client, &config, default_target, conn_id).await? {
+        Some(addr) => addr,
+        None => {
+            tally.rejected();
+            return Ok(());
+        }
+    };
+    #[cfg(not(feature = "scripting"))]
+    let target_addr = default_target;
+    // A script may have sent the connection elsewhere
+    let lease = lease.filter(|lease| lease.addr == target_addr);
+    let requested_fallback = requested_fallback.filter(|_| target_addr == default_target);
+
+    #[cfg(feature = "wasm-plugins")]
+    let (client_addr, started) = (connection.client, std::time::Instant::now());
+    #[cfg(feature = "wasm-plugins")]
+    {
+        let event = tcp_proxy::plugin::FlowEvent::Connect {
+            client: client_addr,
+            target: target_addr,
+        };
+        if !consult_plugins(&config, conn_id, &event) {
+            info!("Connection {} from {} rejected by plugin", conn_id, client_addr);
+            tally.rejected();
+            return Ok(());
+        }
+    }
     
-    // Establish connection to target server with controlled TCP options
-    let server_stream = create_server_connection(config.target_addr, &config).await?;
+    // Keep garbage from costing a gateway session (--fix-logon-guard)
+    if let Some(guard) = &config.fix_logon_guard {
+        if let Err(reason) = await_fix_logon(client_stream.tcp(), guard).await? {
+            tcp_proxy::metrics::registry()
+                .labeled_counter("tcpstrip_fix_logon_rejects_total", "Connections dropped before dialing for not opening with a FIX Logon", "reason")
+                .with(&reason.to_string())
+                .inc();
+            warn!("Connection {} from {} dropped before dialing: no valid FIX Logon ({})", conn_id, connection.client, reason);
+            tally.rejected();
+            return Ok(());
+        }
+    }
+
+    // Establish connection to target server with controlled TCP options,
+    // racing the address of the other family if the target has one, and
+    // trying again (on the next target of a pool) as --connect-retries allows
+    #[cfg(target_os = "linux")]
+    let source_ip = match config.spoof_source {
+        true => Some(connection.client.ip()),
+        false => None,
+    };
+    #[cfg(not(target_os = "linux"))]
+    let source_ip = None;
+    #[cfg(target_os = "linux")]
+    let mut dialing = Vec::new();
+    let (mut lease, mut target_addr, mut retry) = (lease, target_addr, 0);
+    let (fingerprint, route, target_addr, mut server_stream) = loop {
+        let fallback = lease.as_ref().map_or(requested_fallback, |lease| lease.fallback);
+        #[cfg(target_os = "linux")]
+        if let Some(options) = &config.backend_options {
+            dialing.extend([Some(target_addr), fallback].into_iter().flatten().map(|addr| options.dialing(addr)));
+        }
+        let fingerprint = connection.set_backend(target_addr);
+        let route = RouteMetrics::new(target_addr);
+        let dialed = std::time::Instant::now();
+        let dial = |addr| create_server_connection(addr, source_ip, &config);
+        let attempt = tcp_proxy::eyeballs::race(target_addr, fallback, config.happy_eyeballs_delay, dial);
+        let result = match config.connect_timeout {
+            Some(limit) => tokio::time::timeout(limit, attempt).await.unwrap_or_else(|_| {
+                Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timed out").into())
+            }),
+            None => attempt.await,
+        };
+        let e = match result {
+            Ok((addr, stream)) => {
+                if let Some(lease) = &lease {
+                    lease.connected(dialed.elapsed());
+                }
+                // Once per connection, on the route it ends up on; the
+                // attempts before show in connect_errors
+                route.connections.inc();
+                break (fingerprint, route, addr, stream);
+            }
+            Err(e) => e,
+        };
+
+        let failure = e.downcast_ref::<std::io::Error>().map_or(ConnectFailure::Other, ConnectFailure::of);
+        failure.count();
+        route.connect_errors.inc();
+        // Running out of local ports is no fault of the target
+        if let Some(lease) = lease.as_ref().filter(|_| failure != ConnectFailure::NoLocalAddress) {
+            lease.failed();
+        }
+        if retry == config.connect_retries {
+            route.connections.inc();
+            tally.connect_failed();
+            if config.socks5 {
+                let answer = e.downcast_ref::<std::io::Error>().map_or(tcp_proxy::socks::Reply::GeneralFailure, tcp_proxy::socks::Reply::of);
+                let _ = tcp_proxy::socks::reply(client_stream.tcp_mut(), answer, None).await;
+            }
+            return Err(anyhow::anyhow!("Could not connect to {} ({}): {}", target_addr, failure, e));
+        }
+        let pause = tcp_proxy::dial::backoff(config.connect_backoff, retry);
+        warn!(
+            "Connection {}: could not connect to {} ({}: {}), retrying in {}ms",
+            conn_id,
+            target_addr,
+            failure,
+            e,
+            pause.as_millis()
+        );
+        tokio::time::sleep(pause).await;
+        retry += 1;
+        // The failed target is out of rotation, so the pool picks another
+        if let (Some(_), Some(targets)) = (&lease, &config.targets) {
+            let next = targets.pick_for(connection.client.ip());
+            target_addr = next.addr;
+            lease = Some(next);
+        }
+    };
+    tally.connected(connection.started.elapsed());
+    let tuning = config.tuning.lookup(target_addr);
+    tuning.apply(socket2::SockRef::from(client_stream.tcp()))?;
+    tuning.apply(socket2::SockRef::from(&server_stream))?;
+    if config.socks5 {
+        tcp_proxy::socks::reply(client_stream.tcp_mut(), tcp_proxy::socks::Reply::Succeeded, Some(server_stream.local_addr()?)).await?;
+    }
+    info!(
+        "Connection {} [{}]: {} -> {} proxied from {} -> {}",
+        conn_id,
+        fingerprint,
+        connection.client,
+        client_stream.tcp().local_addr()?,
+        server_stream.local_addr()?,
+        target_addr
+    );
+    #[cfg(feature = "history")]
+    let mut history_entry = match tcp_proxy::history::get() {
+        Some(history) => Some(history.record(tcp_proxy::history::Opened {
+            conn_id,
+            fingerprint,
+            accepted_at: connection.accepted_at,
+            client: connection.client,
+            listener: client_stream.tcp().local_addr()?,
+            source: server_stream.local_addr()?,
+            route: target_addr,
+        })),
+        None => None,
+    };
+    #[cfg(target_os = "linux")]
+    let _verified = match &config.verified_flows {
+        Some(flows) => Some(flows.register(server_stream.local_addr()?, server_stream.peer_addr()?)),
+        None => None,
+    };
+    if let Some(version) = config.proxy_protocol {
+        send_proxy_header(&client_stream, &mut server_stream, &config, version, connection.client, target_addr, fingerprint).await?;
+    }
     
     // Forward data bidirectionally with minimal copying
-    forward_data(client_stream, server_stream, config.buffer_size, conn_id).await?;
+    let fix = config.fix_observe.then(|| tcp_proxy::fix_session::registry().register(conn_id, connection.client, target_addr));
+    let mirror = config.mirror.map(|mirror| tcp_proxy::mirror::spawn(mirror, config.mirror_buffer, conn_id));
+    let recorder = config.record_dir.as_ref().map(|dir| {
+        let header = tcp_proxy::record::Header {
+            conn_id,
+            started: std::time::SystemTime::now(),
+            client: connection.client,
+            target: target_addr,
+        };
+        tcp_proxy::record::spawn(dir, config.record_buffer, header)
+    });
+    let idle = tcp_proxy::route::lookup(&config.idle_timeout, target_addr).map(|timeout| tcp_proxy::idle::IdleClock::new(timeout.0));
+    let cut = tcp_proxy::forward::Cut::default();
+    let taps = tcp_proxy::forward::Taps {
+        fix: fix.as_ref().map(|guard| guard.session()),
+        mirror: mirror.as_ref().map(|guard| guard.mirror()),
+        record: recorder.as_ref().map(|guard| guard.recorder()),
+        idle: idle.as_ref(),
+        cut: Some(&cut),
+    };
+    #[cfg(unix)]
+    let legs = {
+        use std::os::unix::io::AsRawFd;
+        [client_stream.tcp().as_raw_fd(), server_stream.as_raw_fd()]
+    };
+    let relayed = relay(client_stream, server_stream, &config, target_addr, taps, conn_id);
+    tokio::pin!(relayed);
+    // The relay holds both streams until it returns, so the descriptors
+    // are still theirs while it runs. Cutting it short still returns the
+    // totals, so the connection is accounted for like any other
+    let mut drain_deadline = None;
+    let (bytes_up, bytes_down) = loop {
+        tokio::select! {
+            relayed = &mut relayed => break relayed
+                .inspect_err(|e| tally.failed(e.downcast_ref::<std::io::Error>().map_or(std::io::ErrorKind::Other, |e| e.kind())))?,
+            _ = tcp_proxy::killswitch::engaged(), if !cut.is_made() => {
+                // The relay returns at once and closes both legs with a RST
+                #[cfg(unix)]
+                on_legs(legs, |leg| leg.set_linger(Some(std::time::Duration::ZERO)));
+                tcp_proxy::killswitch::count_reset();
+                debug!("Connection {} reset by the kill switch", conn_id);
+                cut.make();
+            }
+            _ = tcp_proxy::trading_hours::draining(config.trading_hours.as_deref()), if drain_deadline.is_none() && !cut.is_made() => {
+                // Each direction forwards what it has read, then passes
+                // the EOF on as a FIN
+                #[cfg(unix)]
+                on_legs(legs, |leg| leg.shutdown(std::net::Shutdown::Read));
+                if let Some(hours) = &config.trading_hours {
+                    hours.count_drained();
+                }
+                info!("Connection {} draining: --trading-hours closed", conn_id);
+                drain_deadline = Some(tokio::time::Instant::now() + TRADING_HOURS_DRAIN_GRACE);
+            }
+            _ = tokio::time::sleep_until(drain_deadline.unwrap_or_else(tokio::time::Instant::now)), if drain_deadline.is_some() && !cut.is_made() => {
+                debug!("Connection {} closed after draining for {:?}", conn_id, TRADING_HOURS_DRAIN_GRACE);
+                cut.make();
+            }
+        }
+    };
+    route.bytes_up.add(bytes_up);
+    route.bytes_down.add(bytes_down);
+    #[cfg(feature = "history")]
+    if let Some(entry) = &mut history_entry {
+        entry.finish(bytes_up, bytes_down);
+    }
+
+    #[cfg(feature = "wasm-plugins")]
+    consult_plugins(&config, conn_id, &tcp_proxy::plugin::FlowEvent::Close {
+        client: client_addr,
+        target: target_addr,
+        bytes_up,
+        bytes_down,
+        duration_ms: started.elapsed().as_millis() as u64,
+    });
     
     Ok(())
 }
 
+/// Apply a socket option to the legs of a connection, by the descriptors
+/// of streams that are known to be open
+#[cfg(unix)]
+fn on_legs<const N: usize>(legs: [std::os::unix::io::RawFd; N], apply: impl Fn(socket2::SockRef<'_>) -> std::io::Result<()>) {
+    for fd in legs {
+        let fd = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) };
+        let _ = apply(socket2::SockRef::from(&fd));
+    }
+}
+
+/// Write the PROXY protocol header for a connection to its backend
+///
+/// With --proxy-protocol-tlvs this waits up to --sniff-timeout-ms for the
+/// client's first bytes to name the protocol (v2 headers only).
+async fn send_proxy_header(
+    client_stream: &ClientStream,
+    server_stream: &mut TcpStream,
+    config: &ProxyConfig,
+    version: tcp_proxy::proxy_protocol::Version,
+    client: SocketAddr,
+    target_addr: SocketAddr,
+    fingerprint: tcp_proxy::connections::Fingerprint,
+) -> Result<()> {
+    use tcp_proxy::proxy_protocol::{header, Metadata, Version};
+
+    let metadata = match config.proxy_protocol_tlvs && version == Version::V2 {
+        true => {
+            let protocol = match client_stream {
+                ClientStream::Plain(stream) => {
+                    let first_bytes = peek_first_bytes(stream, config.sniff_timeout).await?;
+                    (!first_bytes.is_empty()).then(|| tcp_proxy::sniff::detect(&first_bytes))
+                }
+                #[cfg(feature = "tls")]
+                ClientStream::Tls(_) => Some(tcp_proxy::sniff::Protocol::Tls),
+            };
+            Some(Metadata {
+                route: target_addr.to_string(),
+                protocol,
+                fingerprint_risk: client_fingerprint_risk(client_stream.tcp()),
+                connection_hash: fingerprint,
+            })
+        }
+        false => None,
+    };
+    let header = header(version, client, client_stream.tcp().local_addr()?, metadata.as_ref());
+    server_stream.write_all(&header).await?;
+    Ok(())
+}
+
+/// How much the client's own TCP options would give away without the proxy
+///
+/// The proxy never sees the client's TSvals, only whether its connection
+/// negotiated timestamps, so this is high if it did and low otherwise.
+#[cfg(target_os = "linux")]
+fn client_fingerprint_risk(stream: &TcpStream) -> Option<tcp_proxy::tcp_analysis::FingerprintRisk> {
+    use std::os::unix::io::AsRawFd;
+    use tcp_proxy::tcp_analysis::FingerprintRisk;
+    /// tcpi_options bit (linux/tcp.h)
+    const TCPI_OPT_TIMESTAMPS: u8 = 1;
+
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc < 0 {
+        return None;
+    }
+    Some(match info.tcpi_options & TCPI_OPT_TIMESTAMPS != 0 {
+        true => FingerprintRisk::High,
+        false => FingerprintRisk::Low,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn client_fingerprint_risk(_stream: &TcpStream) -> Option<tcp_proxy::tcp_analysis::FingerprintRisk> {
+    None
+}
+
+/// Counters for the backend a connection was routed to
+///
+/// With --transparent or a route script the number of routes is open-ended,
+/// so they are subject to --metrics-label-limit.
+struct RouteMetrics {
+    connections: Arc<tcp_proxy::metrics::Metric>,
+    connect_errors: Arc<tcp_proxy::metrics::Metric>,
+    bytes_up: Arc<tcp_proxy::metrics::Metric>,
+    bytes_down: Arc<tcp_proxy::metrics::Metric>,
+}
+
+impl RouteMetrics {
+    fn new(target: SocketAddr) -> Self {
+        let registry = tcp_proxy::metrics::registry();
+        let route = target.to_string();
+        Self {
+            connections: registry.labeled_counter("tcpstrip_route_connections_total", "Connections per backend", "route").with(&route),
+            connect_errors: registry
+                .labeled_counter("tcpstrip_route_connect_errors_total", "Failed connection attempts per backend", "route")
+                .with(&route),
+            bytes_up: registry.labeled_counter("tcpstrip_route_bytes_up_total", "Bytes forwarded to each backend", "route").with(&route),
+            bytes_down: registry
+                .labeled_counter("tcpstrip_route_bytes_down_total", "Bytes forwarded from each backend", "route")
+                .with(&route),
+        }
+    }
+}
+
+/// Load the WASM analysis plugins given on the command line
+#[cfg(feature = "wasm-plugins")]
+fn load_plugins(
+    paths: &[std::path::PathBuf],
+) -> Result<Option<Arc<std::sync::Mutex<tcp_proxy::plugin::PluginHost>>>> {
+    if paths.is_empty() {
+        return Ok(None);
+    }
+    let host = tcp_proxy::plugin::PluginHost::load(paths)?;
+    info!("Loaded {} analysis plugin(s)", paths.len());
+    Ok(Some(Arc::new(std::sync::Mutex::new(host))))
+}
+
+/// Run a flow event through the loaded plugins, returning false if any of
+/// them rejected it
+#[cfg(feature = "wasm-plugins")]
+fn consult_plugins(config: &ProxyConfig, conn_id: u64, event: &tcp_proxy::plugin::FlowEvent) -> bool {
+    use tcp_proxy::plugin::Verdict;
+
+    let Some(host) = &config.plugins else {
+        return true;
+    };
+    let decision = host.lock().unwrap_or_else(|e| e.into_inner()).evaluate(event);
+
+    let registry = tcp_proxy::metrics::registry();
+    registry.counter("tcpstrip_plugin_events_total", "Flow events handed to analysis plugins").inc();
+    if !decision.labels.is_empty() {
+        registry.counter("tcpstrip_plugin_labels_total", "Labels attached by analysis plugins").add(decision.labels.len() as u64);
+        info!("Connection {} labelled by plugins: {}", conn_id, decision.labels.join(", "));
+    }
+    if decision.verdict == Verdict::Reject {
+        registry.counter("tcpstrip_plugin_rejects_total", "Flow events rejected by analysis plugins").inc();
+        return false;
+    }
+    true
+}
+
+/// Compile the routing script given on the command line
+#[cfg(feature = "scripting")]
+fn load_route_script(
+    path: Option<&std::path::Path>,
+) -> Result<Option<Arc<tcp_proxy::script::RoutingScript>>> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    let script = tcp_proxy::script::RoutingScript::load(path)?;
+    info!("Routing connections with {}", path.display());
+    Ok(Some(Arc::new(script)))
+}
+
+/// The --tls-cert/--tls-key terminator, if client TLS is terminated
+#[cfg(feature = "tls")]
+fn load_tls(args: &Args) -> Result<Option<Arc<tcp_proxy::tls::Terminator>>> {
+    let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) else {
+        return Ok(None);
+    };
+    let terminator = tcp_proxy::tls::Terminator::load(cert, key, args.tls_client_ca.as_deref(), args.ktls)
+        .map_err(|e| anyhow::anyhow!("Could not load TLS certificate and key: {}", e))?;
+    info!("Terminating client TLS with {}", cert.display());
+    if let Some(client_ca) = &args.tls_client_ca {
+        info!("Requiring client certificates issued by {}", client_ca.display());
+    }
+    Ok(Some(Arc::new(terminator)))
+}
+
+/// Log a terminated client's certificate identity and check it against
+/// --tls-allow, returning why it is turned away if it is
+#[cfg(feature = "tls")]
+fn check_client_identity(client_stream: &ClientStream, config: &ProxyConfig, conn_id: u64, client: SocketAddr) -> Result<(), String> {
+    let ClientStream::Tls(tls) = client_stream else {
+        return Ok(());
+    };
+    let (_, session) = tls.get_ref();
+    debug!("Connection {}: terminated {:?}, server name {:?}", conn_id, session.protocol_version(), session.server_name());
+    let identity = client_stream.identity();
+    if let Some(identity) = &identity {
+        info!("Connection {} from {}: client certificate {}", conn_id, client, identity);
+    }
+    if config.tls_allow.is_empty() {
+        return Ok(());
+    }
+    match &identity {
+        Some(identity) if config.tls_allow.iter().any(|pattern| pattern.matches(identity)) => Ok(()),
+        _ => {
+            tcp_proxy::metrics::registry()
+                .counter("tcpstrip_tls_identity_rejects_total", "TLS clients whose certificate identity --tls-allow does not list")
+                .inc();
+            Err(format!("client certificate {} is not allowed by --tls-allow", identity.as_deref().unwrap_or("without an identity")))
+        }
+    }
+}
+
+/// The --upstream-tls client, if upstream connections are wrapped in TLS
+#[cfg(feature = "tls")]
+fn upstream_tls(args: &Args) -> Result<Option<Arc<tcp_proxy::tls::Originator>>> {
+    if !args.upstream_tls {
+        return Ok(None);
+    }
+    let originator = tcp_proxy::tls::Originator::new(
+        args.upstream_tls_ca.as_deref(),
+        args.upstream_tls_sni.as_deref(),
+        args.upstream_tls_sessions,
+        args.ktls,
+        maintenance(args)?,
+    )
+    .map_err(|e| anyhow::anyhow!("Could not set up upstream TLS: {}", e))?;
+    info!(
+        "Wrapping upstream connections in TLS for {}, trusting {}",
+        args.upstream_tls_sni.as_deref().unwrap_or("the target's address"),
+        args.upstream_tls_ca.as_ref().map_or("the Mozilla roots".to_string(), |ca| ca.display().to_string())
+    );
+    Ok(Some(Arc::new(originator)))
+}
+
+/// The client's first bytes, or none if it sent nothing within `timeout`
+///
+/// Server-speaks-first protocols send nothing, so only wait briefly.
+/// Peeking leaves the bytes in the socket for the relay.
+async fn peek_first_bytes(client_stream: &TcpStream, timeout: std::time::Duration) -> Result<Vec<u8>> {
+    let mut first_bytes = vec![0u8; 4096];
+    let n = match tokio::time::timeout(timeout, client_stream.peek(&mut first_bytes)).await {
+        Ok(peeked) => peeked?,
+        Err(_) => 0,
+    };
+    first_bytes.truncate(n);
+    Ok(first_bytes)
+}
+
+/// Peek at the client's ClientHello for the server name it asks for
+///
+/// Waits up to `timeout` for the whole first TLS record, since a
+/// ClientHello may arrive in several segments. None if the client sends
+/// no TLS, or no server name, in time.
+async fn peek_server_name(client_stream: &TcpStream, timeout: std::time::Duration) -> Result<Option<String>> {
+    use tcp_proxy::sniff::{tls_record_len, tls_sni, MAX_TLS_RECORD_LEN};
+
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut buf = vec![0u8; MAX_TLS_RECORD_LEN];
+    let mut peeked = 0;
+    loop {
+        let n = match tokio::time::timeout_at(deadline, client_stream.peek(&mut buf)).await {
+            Ok(n) => n?,
+            Err(_) => return Ok(tls_sni(&buf[..peeked])),
+        };
+        match tls_record_len(&buf[..n]) {
+            Some(len) if n < len && tokio::time::Instant::now() < deadline => {
+                // As in await_fix_logon, peeking returns at once while
+                // anything is buffered
+                if n == peeked {
+                    tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                }
+                peeked = n;
+            }
+            _ => return Ok(tls_sni(&buf[..n])),
+        }
+    }
+}
+
+/// Wait for the client's first message and check it is a FIX Logon
+///
+/// The message is peeked at, so it is still forwarded as sent. A client
+/// that sends nothing within FIX_LOGON_TIMEOUT is rejected as incomplete.
+async fn await_fix_logon(
+    client_stream: &TcpStream,
+    guard: &tcp_proxy::fix::LogonGuard,
+) -> Result<Result<(), tcp_proxy::fix::LogonError>> {
+    use tcp_proxy::fix::{check_logon, LogonError, MAX_LOGON_LEN};
+
+    let deadline = tokio::time::Instant::now() + FIX_LOGON_TIMEOUT;
+    let mut buf = vec![0u8; MAX_LOGON_LEN];
+    let mut peeked = 0;
+    loop {
+        let n = match tokio::time::timeout_at(deadline, client_stream.peek(&mut buf)).await {
+            Ok(_) if tokio::time::Instant::now() >= deadline => return Ok(Err(LogonError::Incomplete)),
+            Ok(n) => n?,
+            Err(_) => return Ok(Err(LogonError::Incomplete)),
+        };
+        match check_logon(&buf[..n], guard) {
+            Err(LogonError::Incomplete) if n > 0 => {
+                // Peeking returns at once while anything is buffered, so
+                // wait a little for the rest to arrive
+                if n == peeked {
+                    tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                }
+                peeked = n;
+            }
+            result => return Ok(result.map(|_| ())),
+        }
+    }
+}
+
+/// Run the SOCKS5 handshake and resolve the destination the client asked
+/// for, with the first address of the other family for Happy Eyeballs;
+/// None if the client was turned away
+async fn socks_destination(
+    client_stream: &mut TcpStream,
+    config: &ProxyConfig,
+    conn_id: u64,
+) -> Result<Option<(SocketAddr, Option<SocketAddr>)>> {
+    use tcp_proxy::socks::{handshake, reply, Destination, Reply, HANDSHAKE_TIMEOUT};
+
+    let destination = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(client_stream, config.socks5_auth.as_ref())).await {
+        Ok(Ok(destination)) => destination,
+        Ok(Err(e)) => {
+            warn!("Connection {}: SOCKS5 handshake failed: {}", conn_id, e);
+            return Ok(None);
+        }
+        Err(_) => {
+            warn!("Connection {}: SOCKS5 handshake timed out", conn_id);
+            return Ok(None);
+        }
+    };
+    let resolved: Vec<SocketAddr> = match &destination {
+        Destination::Addr(addr) => vec![*addr],
+        Destination::Domain(name, port) => tokio::net::lookup_host((name.as_str(), *port))
+            .await
+            .map(|addrs| addrs.collect())
+            .unwrap_or_default(),
+    };
+    let Some(&addr) = resolved.first() else {
+        warn!("Connection {}: could not resolve SOCKS5 destination {}", conn_id, destination);
+        reply(client_stream, Reply::HostUnreachable, None).await?;
+        return Ok(None);
+    };
+    debug!("Connection {}: SOCKS5 CONNECT to {} ({})", conn_id, destination, addr);
+    Ok(Some((addr, tcp_proxy::eyeballs::fallback(addr, &resolved))))
+}
+
+/// Pick the backend for a connection with the routing script
+///
+/// Returns None if the script rejected the connection. Without a script,
+/// or if it fails, the configured target is used.
+#[cfg(feature = "scripting")]
+async fn route_connection(
+    client_stream: &ClientStream,
+    client: SocketAddr,
+    config: &ProxyConfig,
+    default_target: SocketAddr,
+    conn_id: u64,
+) -> Result<Option<SocketAddr>> {
+    use tcp_proxy::script::{ConnectionInfo, RouteDecision};
+    use tcp_proxy::sniff;
+
+    let Some(router) = &config.router else {
+        return Ok(Some(default_target));
+    };
+
+    // A terminated client has already said what it is
+    let (sni, protocol) = match client_stream {
+        ClientStream::Plain(stream) => {
+            let first_bytes = &peek_first_bytes(stream, config.sniff_timeout).await?;
+            (sniff::tls_sni(first_bytes), sniff::detect(first_bytes))
+        }
+        #[cfg(feature = "tls")]
+        ClientStream::Tls(tls) => (tls.get_ref().1.server_name().map(str::to_string), sniff::Protocol::Tls),
+    };
+
+    let conn = ConnectionInfo {
+        client,
+        sni,
+        protocol,
+        identity: client_stream.identity(),
+        time: std::time::SystemTime::now(),
+    };
+
+    let registry = tcp_proxy::metrics::registry();
+    registry.counter("tcpstrip_route_script_calls_total", "Connections routed by the route script").inc();
+    let decision = router.decide(&conn).unwrap_or_else(|e| {
+        registry.counter("tcpstrip_route_script_errors_total", "Route script failures (default target used)").inc();
+        warn!("Connection {}: route script failed, using default target: {}", conn_id, e);
+        RouteDecision::Default
+    });
+
+    match decision {
+        RouteDecision::Default => Ok(Some(default_target)),
+        RouteDecision::Backend(backend) => {
+            let addr = tokio::net::lookup_host(backend.as_str()).await?
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("Could not resolve backend chosen by route script: {}", backend))?;
+            registry.counter("tcpstrip_route_script_redirects_total", "Connections sent to a script-chosen backend").inc();
+            debug!("Connection {} ({}, sni {:?}) routed to {}", conn_id, conn.protocol, conn.sni, addr);
+            Ok(Some(addr))
+        }
+        RouteDecision::Reject => {
+            registry.counter("tcpstrip_route_script_rejects_total", "Connections rejected by the route script").inc();
+            info!("Connection {} from {} rejected by route script", conn_id, conn.client);
+            Ok(None)
+        }
+    }
+}
+
 /// Create connection to target server with timestamp options controlled
 async fn create_server_connection(
     target_addr: SocketAddr,
-    _config: &ProxyConfig,
+    source_ip: Option<std::net::IpAddr>,
+    config: &ProxyConfig,
 ) -> Result<TcpStream> {
+    // The socket goes to the egress proxy, if there is one (--via)
+    let next_hop = config.via.as_ref().map_or(target_addr, |via| via.addr);
+
     // Create socket with controlled options before connecting
-    let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
+    let socket = tcp_proxy::egress::stream_socket(next_hop)?;
     
     // Critical: Disable TCP timestamps at socket level if possible
     // Note: This is a userspace proxy limitation - we can't directly strip
@@ -191,6 +3323,23 @@ async fn create_server_connection(
     
     // Configure for HFT performance
     socket.set_nodelay(true)?;
+
+    // Advertise the clamped MSS in our SYN (--mss-clamp)
+    #[cfg(unix)]
+    if let Some(mss) = config.mss_clamp {
+        socket.set_mss(mss as u32)?;
+    }
+
+    // Mark the upstream leg for its route (--dscp)
+    if let Some(dscp) = tcp_proxy::route::lookup(&config.dscp, target_addr) {
+        match next_hop {
+            SocketAddr::V4(_) => socket.set_tos(dscp.tos() as u32)?,
+            #[cfg(unix)]
+            SocketAddr::V6(_) => socket.set_tclass_v6(dscp.tos() as u32)?,
+            #[cfg(not(unix))]
+            SocketAddr::V6(_) => debug!("No traffic class control for IPv6 sockets on this platform"),
+        }
+    }
     
     #[cfg(target_os = "linux")]
     {
@@ -199,8 +3348,8 @@ async fn create_server_connection(
         
         // Attempt to disable TCP timestamps for this socket
         // This may not work without root, but we try anyway
-        let disable_timestamps: libc::c_int = if _config.spoof_timestamps { 
-            _config.static_timestamp as libc::c_int 
+        let disable_timestamps: libc::c_int = if config.spoof_timestamps { 
+            config.static_timestamp as libc::c_int 
         } else { 
             0 
         };
@@ -217,120 +3366,878 @@ async fn create_server_connection(
         }
     }
     
-    // Connect to target
-    socket.connect(&target_addr.into())?;
-    
-    // Convert to tokio TcpStream
-    let std_stream: std::net::TcpStream = socket.into();
-    std_stream.set_nonblocking(true)?;
-    let stream = TcpStream::from_std(std_stream)?;
-    
-    Ok(stream)
-}
-
-/// Configure socket for HFT performance characteristics
-async fn configure_hft_socket(stream: &TcpStream) -> Result<()> {
-    // Essential HFT socket options - use TcpStream's built-in methods
-    stream.set_nodelay(true)?;  // Disable Nagle's algorithm
-    
+    // Give up on unresponsive backends sooner (--syn-retries)
     #[cfg(target_os = "linux")]
-    {
+    if let Some(retries) = config.syn_retries {
         use std::os::unix::io::AsRawFd;
-        let fd = stream.as_raw_fd();
-        
-        // Set TCP_USER_TIMEOUT for fast failure detection
-        let timeout: libc::c_int = 5000; // 5 seconds
-        unsafe {
+        let retries = retries as libc::c_int;
+        let rc = unsafe {
             libc::setsockopt(
-                fd,
+                socket.as_raw_fd(),
                 libc::IPPROTO_TCP,
-                libc::TCP_USER_TIMEOUT,
-                &timeout as *const _ as *const libc::c_void,
+                libc::TCP_SYNCNT,
+                &retries as *const _ as *const libc::c_void,
                 std::mem::size_of::<libc::c_int>() as libc::socklen_t,
-            );
+            )
+        };
+        if rc < 0 {
+            return Err(std::io::Error::last_os_error().into());
         }
-        
-        // Set TCP_QUICKACK to send ACKs immediately
-        let quickack: libc::c_int = 1;
-        unsafe {
-            libc::setsockopt(
-                fd,
-                libc::IPPROTO_TCP,
-                libc::TCP_QUICKACK,
-                &quickack as *const _ as *const libc::c_void,
-                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
-            );
+    }
+
+    // Appear to the target as the client itself (--spoof-source)
+    #[cfg(target_os = "linux")]
+    if let Some(ip) = source_ip {
+        if ip.is_ipv4() != next_hop.is_ipv4() {
+            anyhow::bail!("cannot connect to {} from client address {}: address families differ", next_hop, ip);
         }
+        set_transparent(&socket, next_hop)?;
     }
+    // Leave from the client's address or else --bind-source, from a
+    // --source-ports port and through --bind-device
+    config.egress.bind(&socket, next_hop, source_ip)?;
     
-    Ok(())
+    // Connect to target without blocking the worker, so a Happy Eyeballs
+    // race or --connect-timeout can drop the attempt
+    let mut stream = tcp_proxy::egress::connect(socket, next_hop).await?;
+    if let Some(via) = &config.via {
+        via.tunnel(&mut stream, target_addr).await?;
+    }
+    
+    Ok(stream)
 }
 
-/// Forward data bidirectionally between client and server with minimal copying
-async fn forward_data(
-    mut client_stream: TcpStream,
-    mut server_stream: TcpStream,
-    buffer_size: usize,
-    conn_id: usize,
-) -> Result<()> {
-    // Split streams for bidirectional forwarding
-    let (mut client_read, mut client_write) = client_stream.split();
-    let (mut server_read, mut server_write) = server_stream.split();
-    
-    // Pre-allocate buffers to minimize allocations
-    let mut client_to_server_buf = BytesMut::with_capacity(buffer_size);
-    let mut server_to_client_buf = BytesMut::with_capacity(buffer_size);
-    
-    // Bidirectional forwarding with minimal copying
-    let client_to_server = async {
+/// Load the sockmap splicing program if --sockmap was given
+#[cfg(target_os = "linux")]
+fn create_splicer(args: &Args) -> Result<Option<Arc<tcp_proxy::sockmap::Splicer>>> {
+    if !args.sockmap {
+        return Ok(None);
+    }
+    let splicer = tcp_proxy::sockmap::Splicer::new(args.max_connections as u32)
+        .map_err(|e| anyhow::anyhow!("Could not set up sockmap splicing: {}", e))?;
+    info!("Splicing established connections in the kernel (sockmap)");
+    Ok(Some(splicer))
+}
+
+/// The traffic of `routes` for the capture sockets, or why it cannot be
+/// told apart from the rest
+#[cfg(target_os = "linux")]
+fn capture_scope(routes: &[(SocketAddr, ProxyConfig)]) -> Result<tcp_proxy::capture::Scope, &'static str> {
+    let mut scope = tcp_proxy::capture::Scope::default();
+    for (listen, config) in routes {
+        if config.transparent {
+            return Err("--transparent connections go to any destination");
+        }
+        if config.socks5 {
+            return Err("SOCKS5 clients name any destination");
+        }
+        #[cfg(feature = "scripting")]
+        if config.router.is_some() {
+            return Err("the route script picks any destination");
+        }
+        scope.listen_ports.insert(listen.port());
+        scope.pools.extend(config.targets.clone());
+        if let Some(sni_routes) = &config.sni_routes {
+            scope.targets.extend(sni_routes.addrs());
+        }
+        scope.targets.extend(config.mirror);
+        scope.targets.extend(config.via.as_ref().map(|via| via.addr));
+    }
+    Ok(scope)
+}
+
+/// Start watching backend SYN-ACK options if --watch-backend-options was
+/// given
+#[cfg(target_os = "linux")]
+fn start_backend_watcher(args: &Args) -> Result<Option<Arc<tcp_proxy::backend_watch::BackendOptions>>> {
+    use tcp_proxy::backend_watch::{BackendOptions, BackendWatcher};
+
+    let Some(interface) = &args.watch_backend_options else {
+        return Ok(None);
+    };
+    let options = Arc::new(BackendOptions::default());
+    let watcher = BackendWatcher::open(interface, options.clone(), maintenance(args)?)
+        .map_err(|e| anyhow::anyhow!("Could not capture on {}: {}", interface, e))?;
+
+    std::thread::Builder::new().name("watch-backends".to_string()).spawn(move || {
+        if let Err(e) = watcher.run() {
+            error!("Backend option watch stopped: {}", e);
+        }
+    })?;
+    Ok(Some(options))
+}
+
+/// Attach the XDP ingress scrubber to --xdp-interface and protect every
+/// listener and target port with it
+#[cfg(target_os = "linux")]
+fn start_ingress_scrubber(args: &Args, interface: &str, listeners: &[(TcpListener, Arc<ArcSwap<ProxyConfig>>)]) -> Result<()> {
+    use tcp_proxy::xdp_ingress::{IngressScrubber, Side, TIMESTAMPS};
+
+    let mut kinds = vec![TIMESTAMPS];
+    for (kind, strip) in [
+        (4, args.strip_sack),
+        (34, args.strip_fast_open),
+        (30, args.strip_mptcp),
+        (3, args.window_scale == tcp_proxy::scrub::WindowScaleAction::Remove),
+    ] {
+        if strip {
+            kinds.push(kind);
+        }
+    }
+    let scrubber = IngressScrubber::attach(interface, &kinds)
+        .map_err(|e| anyhow::anyhow!("Could not set up XDP ingress scrubbing: {}", e))?;
+
+    let mut ports = Vec::new();
+    for (listener, config) in listeners {
+        ports.push((listener.local_addr()?.port(), Side::Listener));
+        if let Some(pool) = &config.load().targets {
+            ports.extend(pool.addrs().chain(pool.backup()).map(|addr| (addr.port(), Side::Target)));
+        }
+    }
+    for (port, side) in ports {
+        scrubber
+            .protect(port, side)
+            .map_err(|e| anyhow::anyhow!("Could not protect port {} with XDP: {}", port, e))?;
+    }
+    info!("Scrubbing inbound SYNs and SYN-ACKs on {} with XDP", interface);
+
+    // The task keeps the program attached for the life of the proxy
+    let scrubbed = tcp_proxy::metrics::registry().counter("tcpstrip_xdp_ingress_scrubbed_total", "Inbound handshake segments whose options the XDP program blanked");
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
         loop {
-            client_to_server_buf.clear();
-            client_to_server_buf.resize(buffer_size, 0);
-            
-            match client_read.read(&mut client_to_server_buf).await {
-                Ok(0) => break, // EOF
-                Ok(n) => {
-                    client_to_server_buf.truncate(n);
-                    if let Err(e) = server_write.write_all(&client_to_server_buf).await {
-                        warn!("Connection {} client->server write error: {}", conn_id, e);
-                        break;
-                    }
-                }
-                Err(e) => {
-                    warn!("Connection {} client->server read error: {}", conn_id, e);
-                    break;
-                }
+            interval.tick().await;
+            if let Ok(count) = scrubber.scrubbed() {
+                scrubbed.set(count);
             }
         }
+    });
+    Ok(())
+}
+
+/// Print the doctor report
+#[cfg(target_os = "linux")]
+fn run_doctor(backlog: u32, syn_retries: Option<u8>) -> Result<()> {
+    use tcp_proxy::doctor::{ListenerConfig, Report};
+
+    print!("{}", Report::collect(ListenerConfig {
+        backlog,
+        syn_retries,
+    })?);
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn run_doctor(_backlog: u32, _syn_retries: Option<u8>) -> Result<()> {
+    anyhow::bail!("doctor is only available on Linux")
+}
+
+/// Bring the settings doctor warns about in line, printing them as
+/// sysctl.conf lines (and everything else as comments) so they can be made
+/// permanent
+#[cfg(target_os = "linux")]
+fn run_tune(args: &TuneArgs) -> Result<()> {
+    use tcp_proxy::doctor::{ListenerConfig, Report, Status};
+
+    let report = Report::collect(ListenerConfig {
+        backlog: args.listen_backlog,
+        syn_retries: args.syn_retries,
+    })?;
+    let changes: Vec<_> = report.findings.iter().filter(|f| f.fix.is_some()).collect();
+    if !args.dry_run {
+        report
+            .apply()
+            .map_err(|e| anyhow::anyhow!("Could not change the host settings (tune needs root): {}", e))?;
+    }
+    for finding in &changes {
+        println!("{} = {}  # was {}", finding.setting, finding.fix.unwrap_or_default(), finding.value);
+    }
+    if changes.is_empty() {
+        println!("# nothing to change");
+    } else if args.dry_run {
+        println!("# dry run: nothing was changed");
+    } else {
+        println!("# changed for now; add the lines above to /etc/sysctl.d/ to keep them across reboots");
+    }
+    for finding in report.findings.iter().filter(|f| f.status == Status::Warn && f.fix.is_none()) {
+        println!("# {} = {} is left as it is: {}", finding.setting, finding.value, finding.note);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn run_tune(_args: &TuneArgs) -> Result<()> {
+    anyhow::bail!("tune is only available on Linux")
+}
+
+/// Print every flow that leaked timestamps, failing if there was one
+#[cfg(target_os = "linux")]
+fn run_verify(args: &VerifyArgs) -> Result<()> {
+    let targets = args.target.iter().map(|target| Ok(resolve_target(target)?.0)).collect::<Result<Vec<_>>>()?;
+    let audit = tcp_proxy::verify::audit(
+        &args.interface,
+        &targets,
+        std::time::Duration::from_secs(args.seconds),
+        args.allowed_ts_val,
+    )
+    .map_err(|e| anyhow::anyhow!("Could not capture on {} (verify needs CAP_NET_RAW): {}", args.interface, e))?;
+    for (flow, ts_val) in &audit.leaking {
+        println!(
+            "LEAK {}:{} -> {}:{} TSval {}",
+            flow.src, flow.src_port, flow.dst, flow.dst_port, ts_val
+        );
+    }
+    println!(
+        "Checked {} segments in {}s; {} flows carried timestamps",
+        audit.checked,
+        args.seconds,
+        audit.leaking.len()
+    );
+    if !audit.leaking.is_empty() {
+        anyhow::bail!("TCP timestamps leaked on {}", args.interface);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn run_verify(_args: &VerifyArgs) -> Result<()> {
+    anyhow::bail!("verify is only available on Linux")
+}
+
+/// Print the connections of the history database matching the filters
+#[cfg(feature = "history")]
+fn run_report(args: &ReportArgs) -> Result<()> {
+    // Opening would create an empty database for a mistyped path
+    if !args.history_db.exists() {
+        anyhow::bail!("{} does not exist", args.history_db.display());
+    }
+    let history = tcp_proxy::history::History::open(&args.history_db)
+        .map_err(|e| anyhow::anyhow!("Could not open history database {}: {}", args.history_db.display(), e))?;
+    let query = tcp_proxy::history::Query {
+        src: args.src,
+        route: args.route,
+        since: args.since.clone(),
+        until: args.until.clone(),
+        limit: args.limit,
     };
-    
-    let server_to_client = async {
-        loop {
-            server_to_client_buf.clear();
-            server_to_client_buf.resize(buffer_size, 0);
-            
-            match server_read.read(&mut server_to_client_buf).await {
-                Ok(0) => break, // EOF
-                Ok(n) => {
-                    server_to_client_buf.truncate(n);
-                    if let Err(e) = client_write.write_all(&server_to_client_buf).await {
-                        warn!("Connection {} server->client write error: {}", conn_id, e);
-                        break;
+    print!(
+        "{}",
+        history
+            .search(&query)
+            .map_err(|e| anyhow::anyhow!("Could not search {}: {}", args.history_db.display(), e))?
+    );
+    Ok(())
+}
+
+/// Print a line of rates every interval until interrupted
+fn run_monitor(args: &MonitorArgs) -> Result<()> {
+    use tcp_proxy::monitor::{scrape, Rates};
+
+    let interval = std::time::Duration::from_secs(args.interval);
+    let scrape = || scrape(args.admin_listen).map_err(|e| anyhow::anyhow!("Could not scrape {}/metrics: {}", args.admin_listen, e));
+    let mut before = scrape()?;
+    let mut scraped = std::time::Instant::now();
+    loop {
+        std::thread::sleep(interval.saturating_sub(scraped.elapsed()));
+        let after = scrape()?;
+        let now = std::time::Instant::now();
+        println!("{}", Rates::between(&before, &after, now - scraped));
+        (before, scraped) = (after, now);
+    }
+}
+
+/// Measure the target, and the baseline if there is one
+fn run_bench(args: &BenchArgs) -> Result<()> {
+    let load = tcp_proxy::bench::Load {
+        connections: args.connections as usize,
+        messages: args.messages,
+        size: args.size as usize,
+    };
+    let mut runs = vec![("target", &args.target)];
+    runs.extend(args.baseline.as_ref().map(|baseline| ("baseline", baseline)));
+    for (name, address) in runs {
+        let addr = resolve_target(address)?.0;
+        let latencies = tcp_proxy::bench::run(addr, load).map_err(|e| anyhow::anyhow!("Benchmark against {} failed: {}", addr, e))?;
+        println!("{:<8} {}: {}", name, addr, latencies);
+    }
+    Ok(())
+}
+
+/// Print a line for every flow of the capture that carried timestamps as
+/// it is done, then the totals
+#[cfg(feature = "analyze")]
+fn run_analyze(args: &AnalyzeArgs) -> Result<()> {
+    let workers = args.workers.unwrap_or_else(|| {
+        std::thread::available_parallelism().map_or(1, |cpus| cpus.get().saturating_sub(1).max(1))
+    });
+    let input: Box<dyn std::io::Read> = match args.file.to_str() {
+        Some("-") => Box::new(std::io::stdin().lock()),
+        _ => Box::new(
+            std::fs::File::open(&args.file)
+                .map_err(|e| anyhow::anyhow!("Could not open {}: {}", args.file.display(), e))?,
+        ),
+    };
+    let input = std::io::BufReader::with_capacity(1 << 20, input);
+
+    let summary = tcp_proxy::analyze::analyze(input, workers, |report| println!("{}", report))
+        .map_err(|e| anyhow::anyhow!("Could not analyze {}: {}", args.file.display(), e))?;
+    print!("{}", summary);
+    Ok(())
+}
+
+/// Replay a recording and print how it went
+fn run_replay(args: &ReplayArgs) -> Result<()> {
+    use tcp_proxy::record::Reader;
+
+    let file = std::fs::File::open(&args.file).map_err(|e| anyhow::anyhow!("Could not open {}: {}", args.file.display(), e))?;
+    let mut recording = Reader::new(std::io::BufReader::new(file))
+        .map_err(|e| anyhow::anyhow!("Could not read {}: {}", args.file.display(), e))?;
+    let header = *recording.header();
+    let target = match &args.target {
+        Some(target) => resolve_target(target)?.0,
+        None => header.target,
+    };
+    info!(
+        "Replaying connection {} ({} -> {}) against {} at {}x",
+        header.conn_id,
+        header.client,
+        header.target,
+        target,
+        args.speed.0
+    );
+
+    let replayed = tcp_proxy::record::replay(&mut recording, target, args.speed.0, std::time::Duration::from_secs(args.wait))
+        .map_err(|e| anyhow::anyhow!("Replay against {} failed: {}", target, e))?;
+    if recording.truncated() {
+        warn!("{} ends in the middle of a record; replayed up to there", args.file.display());
+    }
+    println!(
+        "Sent {} bytes in {} writes (at most {}us late); received {} bytes, {} in the recording",
+        replayed.sent,
+        replayed.writes,
+        replayed.max_lag.as_micros(),
+        replayed.received,
+        replayed.recorded
+    );
+    Ok(())
+}
+
+/// Become --user, keeping the --keep-caps capabilities and those the
+/// configuration needs after startup
+#[cfg(target_os = "linux")]
+fn switch_user(args: &Args, spec: &str) -> Result<()> {
+    use tcp_proxy::privileges::{drop_privileges, Capability, User};
+
+    let mut user = User::lookup(spec).map_err(|e| anyhow::anyhow!("Could not look up user {}: {}", spec, e))?;
+    if let Some(group) = &args.group {
+        if spec.contains(':') {
+            anyhow::bail!("Give the group either as --user USER:GROUP or with --group, not both");
+        }
+        user = user.in_group(group).map_err(|e| anyhow::anyhow!("Could not look up group {}: {}", group, e))?;
+    }
+    let mut keep = args.keep_caps.clone();
+    let spoof_source = args.spoof_source || args.route.iter().any(|route| route.spoof_source == Some(true));
+    // IP_TRANSPARENT on every upstream socket, and removing the rules
+    for (needed, flag) in [(spoof_source, "--spoof-source"), (args.manage_firewall, "--manage-firewall")] {
+        if needed && !keep.contains(&Capability::NetAdmin) {
+            info!("Keeping net_admin for {}", flag);
+            keep.push(Capability::NetAdmin);
+        }
+    }
+    drop_privileges(user, &keep).map_err(|e| anyhow::anyhow!("Could not switch to user {}: {}", spec, e))?;
+    let caps: Vec<String> = keep.iter().map(|cap| cap.to_string()).collect();
+    info!(
+        "Running as {} (uid {}, gid {}), keeping capabilities: {}",
+        spec,
+        user.uid,
+        user.gid,
+        if caps.is_empty() { "none".to_string() } else { caps.join(",") }
+    );
+    Ok(())
+}
+
+/// Firewall rules for --manage-firewall / --firewall-dry-run
+#[cfg(target_os = "linux")]
+fn firewall_config(args: &Args) -> tcp_proxy::firewall::FirewallConfig {
+    tcp_proxy::firewall::FirewallConfig {
+        backend: args.firewall_backend,
+        mode: args.intercept_mode,
+        proxy_port: listen_addr(args).port(),
+        ports: args.intercept_ports.clone(),
+        interface: args.intercept_interface.clone(),
+    }
+}
+
+/// The service is set up: switch to --user, tell systemd (Type=notify
+/// units) and the command that started the daemon, ping systemd's watchdog
+/// if the unit has WatchdogSec, and shut down cleanly on a signal, dropping
+/// `teardown` (the firewall rules) first
+#[cfg(unix)]
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+fn ready<T: Send + 'static>(args: &Args, teardown: T) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    if let Some(user) = &args.user {
+        switch_user(args, user)?;
+    }
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    if let Some(mode) = args.seccomp {
+        tcp_proxy::seccomp::install(mode).map_err(|e| anyhow::anyhow!("Could not install the seccomp filter: {}", e))?;
+        info!("Restricted to the system calls forwarding needs (seccomp {})", mode);
+    }
+    #[cfg(target_os = "linux")]
+    {
+        use tcp_proxy::systemd;
+
+        match systemd::notify("READY=1") {
+            Ok(true) => debug!("Told systemd the service is ready"),
+            Ok(false) => {}
+            Err(e) => warn!("Could not notify systemd: {}", e),
+        }
+        if let Some(interval) = systemd::watchdog_interval() {
+            info!("Pinging the systemd watchdog every {}ms", interval.as_millis());
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                loop {
+                    ticker.tick().await;
+                    if let Err(e) = systemd::notify("WATCHDOG=1") {
+                        warn!("Could not ping the systemd watchdog: {}", e);
                     }
                 }
-                Err(e) => {
-                    warn!("Connection {} server->client read error: {}", conn_id, e);
-                    break;
+            });
+        }
+    }
+    tcp_proxy::daemon::ready();
+    tokio::spawn(shut_down_on_signal(teardown));
+    tokio::spawn(kill_switch_on_signal());
+    Ok(())
+}
+
+/// Engage the kill switch on SIGUSR1, release it on SIGUSR2
+#[cfg(unix)]
+async fn kill_switch_on_signal() {
+    use tcp_proxy::killswitch;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let (mut engage, mut release) = match (signal(SignalKind::user_defined1()), signal(SignalKind::user_defined2())) {
+        (Ok(engage), Ok(release)) => (engage, release),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Cannot watch for SIGUSR1/SIGUSR2, the kill switch is only available from the admin API: {}", e);
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            Some(()) = engage.recv() => {
+                if let Some(mode) = killswitch::unavailable_in() {
+                    warn!("Ignoring SIGUSR1: the kill switch has no connections to reset in {} mode", mode);
+                } else if killswitch::engage() {
+                    warn!("Kill switch engaged (SIGUSR1): resetting every connection");
+                }
+            }
+            Some(()) = release.recv() => {
+                if killswitch::release() {
+                    info!("Kill switch released (SIGUSR2): accepting connections again");
                 }
             }
+            else => return,
+        }
+    }
+}
+
+/// On SIGHUP re-read the ACL (--allow, --deny and --acl-file) and publish
+/// a new configuration snapshot with it to every listener; connections
+/// already accepted keep the snapshot they started with. Nothing else is
+/// reloaded: every other setting takes a restart
+#[cfg(unix)]
+async fn reload_acl_on_signal(sources: AclSources, snapshots: Vec<Arc<ArcSwap<ProxyConfig>>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Cannot watch for SIGHUP, the ACL will not be reloaded: {}", e);
+            return;
         }
     };
-    
-    // Run both directions concurrently
-    tokio::select! {
-        _ = client_to_server => {},
-        _ = server_to_client => {},
+    let reloads = tcp_proxy::metrics::registry()
+        .labeled_counter("tcpstrip_acl_reloads_total", "ACL reloads (SIGHUP) by outcome", "result");
+    while hangup.recv().await.is_some() {
+        let acl = match sources.load() {
+            Ok(acl) => acl,
+            Err(e) => {
+                error!("ACL reload failed, keeping the current rules: {}", e);
+                reloads.with("failure").inc();
+                continue;
+            }
+        };
+        for snapshot in &snapshots {
+            let mut next = ProxyConfig::clone(&snapshot.load());
+            next.acl = acl.clone();
+            snapshot.store(Arc::new(next));
+        }
+        info!("Reloaded the ACL (SIGHUP)");
+        reloads.with("success").inc();
+    }
+}
+
+/// On SIGINT/SIGTERM tell systemd the service is stopping, drop
+/// `teardown` and the pidfile, then exit
+#[cfg(unix)]
+async fn shut_down_on_signal<T>(teardown: T) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        },
+        Err(e) => {
+            error!("Cannot watch for SIGTERM, shutting down cleanly only on SIGINT: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+    info!("Shutting down");
+    #[cfg(target_os = "linux")]
+    let _ = tcp_proxy::systemd::notify("STOPPING=1");
+    drop(teardown);
+    tcp_proxy::daemon::remove_pidfile();
+    std::process::exit(0);
+}
+
+/// A listener on a socket systemd bound and passed
+#[cfg(target_os = "linux")]
+fn inherited_listener(socket: Socket) -> Result<TcpListener> {
+    // Accepted connections inherit it, as from our own listeners
+    socket.set_nodelay(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Set up the hugepage buffer arena if --hugepage-buffers was given
+fn create_arena(args: &Args) -> Result<Option<Arc<tcp_proxy::arena::BufferArena>>> {
+    if !args.hugepage_buffers {
+        return Ok(None);
+    }
+    let slots = (args.max_connections * 2) as u32;
+    // Slots fit the largest route's buffers
+    let route_sizes = args.route.iter().filter_map(|route| route.buffer_size);
+    let slot_size = route_sizes
+        .chain(args.relay_buffers.iter().map(|rule| rule.value))
+        .map(tcp_proxy::route::BufferSizes::max)
+        .fold(args.buffer_size, usize::max);
+    let arena = tcp_proxy::arena::BufferArena::new(slot_size, slots)?;
+    if arena.backing() != tcp_proxy::arena::Backing::HugeTlb {
+        warn!("No hugepages reserved (vm.nr_hugepages); buffer arena uses regular memory");
+    }
+    Ok(Some(arena))
+}
+
+/// Start checking the egress interface for escaped timestamps if
+/// --verify-egress was given
+#[cfg(target_os = "linux")]
+fn start_egress_verifier(args: &Args) -> Result<Option<Arc<tcp_proxy::verify::ProxiedFlows>>> {
+    use tcp_proxy::verify::{EgressVerifier, ProxiedFlows, VerifyConfig};
+
+    let Some(interface) = &args.verify_egress else {
+        return Ok(None);
+    };
+    let allowed_ts_val = match args.spoof_timestamps && args.static_timestamp != 0 {
+        true => Some(args.static_timestamp),
+        false => None,
+    };
+
+    let flows = Arc::new(ProxiedFlows::default());
+    let verifier = EgressVerifier::open(
+        VerifyConfig {
+            interface: interface.clone(),
+            sample_rate: args.verify_sample_rate,
+            allowed_ts_val,
+        },
+        flows.clone(),
+    )
+    .map_err(|e| anyhow::anyhow!("Could not capture on {}: {}", interface, e))?;
+
+    std::thread::Builder::new().name("verify-egress".to_string()).spawn(move || {
+        if let Err(e) = verifier.run() {
+            error!("Egress verification stopped: {}", e);
+        }
+    })?;
+    Ok(Some(flows))
+}
+
+/// Move data between the two legs until one of them closes
+///
+/// With --sockmap the kernel does the forwarding; connections it cannot
+/// take fall back to the userspace loop, as do connections with `taps`
+/// (FIX observation, mirroring, recording) or a --max-rate-kbps. Splicing and spinning
+/// are skipped for routes whose feature flags are off.
+async fn relay(
+    client_stream: ClientStream,
+    server_stream: TcpStream,
+    config: &ProxyConfig,
+    target_addr: SocketAddr,
+    taps: tcp_proxy::forward::Taps<'_>,
+    conn_id: u64,
+) -> Result<(u64, u64)> {
+    use tcp_proxy::features::{self, Feature};
+
+    let rate = tcp_proxy::route::lookup(&config.max_rate_kbps, target_addr).unwrap_or_default();
+    let quickack = config.tuning.lookup(target_addr).quickack;
+    // The kernel would splice ciphertext, so TLS connections always go
+    // through userspace
+    #[cfg(feature = "tls")]
+    let client_stream = match client_stream {
+        ClientStream::Plain(stream) if config.upstream_tls.is_none() => stream,
+        client_stream => return relay_tls(client_stream, server_stream, config, target_addr, rate, taps, conn_id).await,
+    };
+    #[cfg(not(feature = "tls"))]
+    let ClientStream::Plain(client_stream) = client_stream;
+
+    #[cfg(target_os = "linux")]
+    if let Some(splicer) = config
+        .splicer
+        .as_ref()
+        .filter(|_| taps.is_empty() && rate.is_unlimited() && quickack != tcp_proxy::tuning::QuickAck::Rearm && features::flags().enabled(Feature::Sockmap, target_addr))
+    {
+        let registry = tcp_proxy::metrics::registry();
+        match splicer.splice(&client_stream, &server_stream) {
+            Ok(splice) => {
+                registry.counter("tcpstrip_sockmap_spliced_total", "Connections forwarded in the kernel via sockmap").inc();
+                return wait_spliced(&client_stream, &server_stream, splice, taps.idle.map(|clock| clock.timeout()), taps.cut, conn_id).await;
+            }
+            Err(e) => {
+                registry.counter("tcpstrip_sockmap_fallbacks_total", "Connections forwarded in userspace because splicing failed").inc();
+                debug!("Connection {}: sockmap splice failed, forwarding in userspace: {}", conn_id, e);
+            }
+        }
+    }
+
+    let priority = tcp_proxy::route::lookup(&config.forward_priority, target_addr).unwrap_or_default();
+    let buffers = tcp_proxy::route::lookup(&config.relay_buffers, target_addr).unwrap_or(config.buffer_size);
+    let idle = match features::flags().enabled(Feature::Spin, target_addr) {
+        true => tcp_proxy::route::lookup(&config.idle_policy, target_addr).unwrap_or_default(),
+        false => tcp_proxy::idle::IdlePolicy::Park,
+    };
+    let bytes = tcp_proxy::forward::forward_data(
+        client_stream,
+        server_stream,
+        buffers,
+        config.arena.as_ref(),
+        priority,
+        idle,
+        rate,
+        quickack,
+        taps,
+        conn_id,
+    )
+    .await?;
+    Ok(bytes)
+}
+
+/// Relay a connection with TLS on either leg or both, after the handshakes
+#[cfg(feature = "tls")]
+async fn relay_tls(
+    client_stream: ClientStream,
+    server_stream: TcpStream,
+    config: &ProxyConfig,
+    target_addr: SocketAddr,
+    rate: tcp_proxy::ratelimit::RateLimit,
+    taps: tcp_proxy::forward::Taps<'_>,
+    conn_id: u64,
+) -> Result<(u64, u64)> {
+    use tcp_proxy::tls::{forward, Leg};
+
+    let buffers = tcp_proxy::route::lookup(&config.relay_buffers, target_addr).unwrap_or(config.buffer_size);
+    // Ok for a server leg now speaking TLS, Err for one left plain
+    let server_tls = match &config.upstream_tls {
+        Some(originator) => {
+            let tls = tokio::select! {
+                tls = originator.connect(server_stream, target_addr) => tls,
+                // Nothing was forwarded yet
+                _ = tcp_proxy::forward::cut(taps.cut) => return Ok((0, 0)),
+            };
+            let tls = tls.map_err(|e| anyhow::anyhow!("TLS handshake with {} failed: {}", target_addr, e))?;
+            let (_, session) = tls.get_ref();
+            debug!("Connection {}: originated {:?}, {:?} handshake", conn_id, session.protocol_version(), session.handshake_kind());
+            Ok(tls)
+        }
+        None => Err(server_stream),
+    };
+    // With --ktls, TLS legs the kernel can take over go to it
+    let client: Box<dyn Leg> = match client_stream {
+        #[cfg(target_os = "linux")]
+        ClientStream::Tls(tls) if config.ktls => match tcp_proxy::ktls::offload_terminated(*tls)
+            .map_err(|e| anyhow::anyhow!("Could not hand client TLS to the kernel: {}", e))?
+        {
+            Ok(stream) => {
+                debug!("Connection {}: client TLS offloaded to the kernel", conn_id);
+                Box::new(stream)
+            }
+            Err(tls) => Box::new(tls),
+        },
+        ClientStream::Tls(tls) => tls,
+        ClientStream::Plain(stream) => Box::new(stream),
+    };
+    let server: Box<dyn Leg> = match server_tls {
+        #[cfg(target_os = "linux")]
+        Ok(tls) if config.ktls => match tcp_proxy::ktls::offload_originated(tls)
+            .map_err(|e| anyhow::anyhow!("Could not hand upstream TLS to the kernel: {}", e))?
+        {
+            Ok(stream) => {
+                debug!("Connection {}: upstream TLS offloaded to the kernel", conn_id);
+                Box::new(stream)
+            }
+            Err(tls) => Box::new(tls),
+        },
+        Ok(tls) => Box::new(tls),
+        Err(stream) => Box::new(stream),
+    };
+    Ok(forward(client, server, buffers, rate, taps, conn_id).await?)
+}
+
+/// Wait for both legs of a spliced connection to close, for it to go
+/// `idle_timeout` without payload, or for `cut`
+///
+/// A FIN from one side is passed on as a shutdown of the other leg's
+/// sending side once the kernel has moved what came before it; the other
+/// direction keeps flowing until its side closes too.
+#[cfg(target_os = "linux")]
+async fn wait_spliced(
+    client_stream: &TcpStream,
+    server_stream: &TcpStream,
+    splice: tcp_proxy::sockmap::Splice,
+    idle_timeout: Option<std::time::Duration>,
+    cut: Option<&tcp_proxy::forward::Cut>,
+    conn_id: u64,
+) -> Result<(u64, u64)> {
+    const DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+    // Let the kernel finish moving what it already received before a leg
+    // is shut down or the sockets leave the map
+    let drain = |drained: fn(&tcp_proxy::sockmap::Splice) -> std::io::Result<bool>| {
+        let splice = &splice;
+        async move {
+            let deadline = tokio::time::Instant::now() + DRAIN_TIMEOUT;
+            while !drained(splice).unwrap_or(true) && tokio::time::Instant::now() < deadline {
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            }
+        }
+    };
+
+    // Payload never reaches our receive queues, so the sockets only become
+    // readable at EOF or on error
+    let (mut client_probe, mut server_probe) = ([0u8; 1], [0u8; 1]);
+    let expired = async {
+        match idle_timeout {
+            Some(timeout) => tcp_proxy::idle::wait_idle(timeout, || splice.idle_for().unwrap_or_default()).await,
+            None => std::future::pending().await,
+        }
+    };
+    let cut = tcp_proxy::forward::cut(cut);
+    tokio::pin!(expired, cut);
+    let (mut client_open, mut server_open) = (true, true);
+    while client_open || server_open {
+        let (from_client, closed) = tokio::select! {
+            r = client_stream.peek(&mut client_probe), if client_open => (true, r),
+            r = server_stream.peek(&mut server_probe), if server_open => (false, r),
+            _ = &mut expired => {
+                debug!("Connection {} closed after {:?} idle", conn_id, idle_timeout.unwrap_or_default());
+                break;
+            }
+            // Closed as it is, without waiting for the kernel to drain
+            _ = &mut cut => return Ok(splice.bytes().unwrap_or_default()),
+        };
+        match closed {
+            Ok(0) if from_client => {
+                client_open = false;
+                drain(tcp_proxy::sockmap::Splice::drained_from_client).await;
+                let _ = socket2::SockRef::from(server_stream).shutdown(std::net::Shutdown::Write);
+            }
+            Ok(0) => {
+                server_open = false;
+                drain(tcp_proxy::sockmap::Splice::drained_from_server).await;
+                let _ = socket2::SockRef::from(client_stream).shutdown(std::net::Shutdown::Write);
+            }
+            Ok(_) => {
+                warn!("Connection {}: unexpected data on a spliced socket", conn_id);
+                break;
+            }
+            Err(e) => {
+                debug!("Connection {} spliced socket error: {}", conn_id, e);
+                break;
+            }
+        }
+    }
+    drain(tcp_proxy::sockmap::Splice::drained).await;
+
+    // The sockets leave the map as `splice` is dropped on return
+    Ok(splice.bytes().unwrap_or_default())
+}
+
+/// Where a connection was headed before a firewall rule steered it into
+/// the proxy (--transparent)
+#[cfg(target_os = "linux")]
+fn original_destination(stream: &TcpStream, config: &ProxyConfig) -> Result<SocketAddr> {
+    let local = stream.local_addr()?;
+    // IPv4 connections to a dual-stack listener are tracked as IPv4
+    let local = SocketAddr::new(local.ip().to_canonical(), local.port());
+    let socket = socket2::SockRef::from(stream);
+    let original = match local {
+        SocketAddr::V4(_) => socket.original_dst(),
+        SocketAddr::V6(_) => socket.original_dst_ipv6(),
+    };
+    // Without a conntrack entry there was no NAT; TPROXY'd connections are
+    // accepted on the address they were sent to
+    let original = match original {
+        Ok(addr) => addr.as_socket().ok_or_else(|| anyhow::anyhow!("SO_ORIGINAL_DST returned a non-IP address"))?,
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => local,
+        Err(e) => return Err(e.into()),
+    };
+
+    // A client connecting to the proxy port directly would otherwise make
+    // the proxy connect to itself
+    if original == local && local.port() == config.listen_port {
+        anyhow::bail!("connection is addressed to the proxy itself, not redirected to it");
+    }
+    Ok(original)
+}
+
+/// Let `socket` use foreign addresses (TPROXY, --spoof-source)
+#[cfg(target_os = "linux")]
+fn set_transparent(socket: &Socket, addr: SocketAddr) -> std::io::Result<()> {
+    if addr.is_ipv4() {
+        return socket.set_ip_transparent(true);
     }
+    use std::os::unix::io::AsRawFd;
+    let enable: libc::c_int = 1;
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_IPV6,
+            libc::IPV6_TRANSPARENT,
+            &enable as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn original_destination(_stream: &TcpStream, _config: &ProxyConfig) -> Result<SocketAddr> {
+    anyhow::bail!("transparent proxying is only available on Linux")
+}
+
+/// Configure socket for HFT performance characteristics
+async fn configure_hft_socket(stream: &TcpStream, config: &ProxyConfig) -> Result<()> {
+    // Essential HFT socket options - use TcpStream's built-in methods
+    stream.set_nodelay(true)?;  // Disable Nagle's algorithm
+    
+    // Fast failure detection and immediate ACKs until the destination's
+    // own settings apply
+    config.tuning.catch_all().apply(socket2::SockRef::from(stream))?;
     
     Ok(())
-} 
\ No newline at end of file
+}
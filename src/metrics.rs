@@ -0,0 +1,260 @@
+//! Process-wide metrics registry
+//!
+//! Metrics are plain atomics registered once under a name and rendered in
+//! the Prometheus text exposition format by the admin listener. Hot paths
+//! hold on to the returned `Arc<Metric>` and never touch the registry lock.
+//!
+//! Metrics broken down by route (or another label) are kept in a
+//! `LabeledMetric`. Each label value gets its own series until the
+//! registry's label limit is reached; values seen after that are folded
+//! into a single `other` series, so a label that turns out to be high
+//! cardinality (a client port, say) cannot blow up the scrape.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+impl MetricKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+        }
+    }
+}
+
+/// A single named counter or gauge
+#[derive(Debug)]
+pub struct Metric {
+    kind: MetricKind,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Metric {
+    fn new(kind: MetricKind, help: &'static str) -> Self {
+        Self {
+            kind,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.value.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Overwrite the value (gauges, or counters mirrored from the kernel)
+    pub fn set(&self, n: u64) {
+        self.value.store(n, Ordering::Relaxed);
+    }
+
+    /// Raise the value to `n` if it is currently lower (peak tracking)
+    pub fn set_max(&self, n: u64) {
+        self.value.fetch_max(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// Series a labeled metric keeps before folding new values into `other`
+pub const DEFAULT_LABEL_LIMIT: usize = 100;
+/// Label value of the series that absorbs values beyond the limit
+pub const OVERFLOW_LABEL_VALUE: &str = "other";
+
+/// A counter or gauge with one series per value of a label
+#[derive(Debug)]
+pub struct LabeledMetric {
+    kind: MetricKind,
+    help: &'static str,
+    label: &'static str,
+    limit: usize,
+    series: Mutex<BTreeMap<String, Arc<Metric>>>,
+    /// Lookups of values that did not get a series of their own
+    overflowed: Arc<Metric>,
+}
+
+impl LabeledMetric {
+    /// The series for `value`, or the `other` series once the limit is hit
+    pub fn with(&self, value: &str) -> Arc<Metric> {
+        let mut series = self.series.lock().unwrap();
+        if let Some(metric) = series.get(value) {
+            return metric.clone();
+        }
+        let value = match series.len() < self.limit {
+            true => value,
+            false => {
+                self.overflowed.inc();
+                OVERFLOW_LABEL_VALUE
+            }
+        };
+        series.entry(value.to_string()).or_insert_with(|| Arc::new(Metric::new(self.kind, self.help))).clone()
+    }
+}
+
+/// Registry of all metrics in the process
+#[derive(Debug)]
+pub struct Registry {
+    metrics: Mutex<BTreeMap<String, Arc<Metric>>>,
+    labeled: Mutex<BTreeMap<String, Arc<LabeledMetric>>>,
+    label_limit: AtomicUsize,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            metrics: Mutex::default(),
+            labeled: Mutex::default(),
+            label_limit: AtomicUsize::new(DEFAULT_LABEL_LIMIT),
+        }
+    }
+}
+
+impl Registry {
+    /// Get or create a counter
+    pub fn counter(&self, name: &str, help: &'static str) -> Arc<Metric> {
+        self.register(name, help, MetricKind::Counter)
+    }
+
+    /// Get or create a gauge
+    pub fn gauge(&self, name: &str, help: &'static str) -> Arc<Metric> {
+        self.register(name, help, MetricKind::Gauge)
+    }
+
+    /// Get or create a counter with one series per value of `label`
+    pub fn labeled_counter(&self, name: &str, help: &'static str, label: &'static str) -> Arc<LabeledMetric> {
+        self.register_labeled(name, help, label, MetricKind::Counter)
+    }
+
+    /// Get or create a gauge with one series per value of `label`
+    pub fn labeled_gauge(&self, name: &str, help: &'static str, label: &'static str) -> Arc<LabeledMetric> {
+        self.register_labeled(name, help, label, MetricKind::Gauge)
+    }
+
+    /// Series each labeled metric registered from now on may keep
+    pub fn set_label_limit(&self, limit: usize) {
+        self.label_limit.store(limit, Ordering::Relaxed);
+    }
+
+    fn register(&self, name: &str, help: &'static str, kind: MetricKind) -> Arc<Metric> {
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(Metric::new(kind, help)))
+            .clone()
+    }
+
+    fn register_labeled(&self, name: &str, help: &'static str, label: &'static str, kind: MetricKind) -> Arc<LabeledMetric> {
+        let overflowed = self.counter(
+            "tcpstrip_metric_label_overflows_total",
+            "Lookups of label values folded into the \"other\" series by the label limit",
+        );
+        let mut labeled = self.labeled.lock().unwrap();
+        labeled
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                Arc::new(LabeledMetric {
+                    kind,
+                    help,
+                    label,
+                    limit: self.label_limit.load(Ordering::Relaxed),
+                    series: Mutex::default(),
+                    overflowed,
+                })
+            })
+            .clone()
+    }
+
+    /// Render every metric in the Prometheus text format
+    pub fn render(&self) -> String {
+        let metrics = self.metrics.lock().unwrap();
+        let mut out = String::new();
+        for (name, metric) in metrics.iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, metric.help);
+            let _ = writeln!(out, "# TYPE {} {}", name, metric.kind.as_str());
+            let _ = writeln!(out, "{} {}", name, metric.get());
+        }
+
+        let labeled = self.labeled.lock().unwrap();
+        for (name, family) in labeled.iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, family.help);
+            let _ = writeln!(out, "# TYPE {} {}", name, family.kind.as_str());
+            for (value, metric) in family.series.lock().unwrap().iter() {
+                let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, family.label, escape_label_value(value), metric.get());
+            }
+        }
+        out
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// The global registry
+pub fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(Registry::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus_text() {
+        let registry = Registry::default();
+        let accepted = registry.counter("tcpstrip_accepted_total", "Accepted connections");
+        let depth = registry.gauge("tcpstrip_queue_depth", "Queue depth");
+
+        accepted.inc();
+        accepted.add(2);
+        depth.set(7);
+        depth.set_max(5);
+
+        // Registering again hands back the same metric
+        registry.counter("tcpstrip_accepted_total", "Accepted connections").inc();
+
+        let text = registry.render();
+        assert!(text.contains("# TYPE tcpstrip_accepted_total counter\ntcpstrip_accepted_total 4\n"));
+        assert!(text.contains("# TYPE tcpstrip_queue_depth gauge\ntcpstrip_queue_depth 7\n"));
+    }
+
+    #[test]
+    fn test_label_limit_folds_into_other() {
+        let registry = Registry::default();
+        registry.set_label_limit(2);
+        let connections = registry.labeled_counter("tcpstrip_route_connections_total", "Connections per route", "route");
+
+        connections.with("10.1.0.5:9000").inc();
+        connections.with("10.1.0.6:9000").inc();
+        connections.with("10.1.0.5:9000").inc();
+        connections.with("10.1.0.7:9000").inc();
+        connections.with("10.1.0.8:9000").add(2);
+
+        let text = registry.render();
+        assert!(text.contains("tcpstrip_route_connections_total{route=\"10.1.0.5:9000\"} 2\n"));
+        assert!(text.contains("tcpstrip_route_connections_total{route=\"10.1.0.6:9000\"} 1\n"));
+        assert!(text.contains("tcpstrip_route_connections_total{route=\"other\"} 3\n"));
+        assert!(!text.contains("10.1.0.7"));
+        assert!(text.contains("tcpstrip_metric_label_overflows_total 2\n"));
+        assert_eq!(escape_label_value("a\"b\\"), "a\\\"b\\\\");
+    }
+}
@@ -0,0 +1,238 @@
+//! Traffic mirroring to a shadow target (`--mirror`)
+//!
+//! A mirrored connection gets a second upstream connection of its own, to
+//! the mirror target, which is sent a copy of everything the client sends:
+//! the bytes the target receives after any PROXY protocol header, in
+//! plaintext where a leg speaks TLS. The copy never holds the primary path
+//! up. The relay only appends to a buffer of fixed size that a task of the
+//! mirror's own empties into its socket; a mirror that falls so far behind
+//! that the buffer overflows is cut off rather than sent a stream with a
+//! hole in it, and one that cannot be reached or fails is dropped as well.
+//! Either way the primary connection carries on. Whatever the mirror target
+//! answers is read and discarded.
+//!
+//! Mirror connections are counted in `tcpstrip_mirror_connections_total`,
+//! the bytes they were sent in `tcpstrip_mirror_bytes_total` and the
+//! mirrors dropped in `tcpstrip_mirror_failures_total{reason}` (`connect`,
+//! `overflow` or `write`).
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tracing::debug;
+
+use crate::metrics;
+
+/// Default of `--mirror-buffer`
+pub const DEFAULT_BUFFER: usize = 256 * 1024;
+
+/// How long the mirror target has to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Most bytes the mirror task takes out of the buffer at a time
+const BATCH: usize = 16 * 1024;
+
+#[derive(Debug)]
+struct Queue {
+    bytes: VecDeque<u8>,
+    /// The primary connection is done; send what is left and close
+    finished: bool,
+    /// The mirror was dropped; nothing more is queued
+    abandoned: bool,
+}
+
+/// The copy of one connection's client->server bytes on its way to the
+/// mirror target
+#[derive(Debug)]
+pub struct Mirror {
+    queue: Mutex<Queue>,
+    ready: Notify,
+    capacity: usize,
+}
+
+impl Mirror {
+    /// Queue bytes that were just forwarded; never waits
+    pub fn copy(&self, bytes: &[u8]) {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        if queue.abandoned {
+            return;
+        }
+        if queue.bytes.len() + bytes.len() > self.capacity {
+            drop(queue);
+            self.abandon("overflow");
+            return;
+        }
+        queue.bytes.extend(bytes);
+        drop(queue);
+        self.ready.notify_one();
+    }
+
+    /// Whether the mirror was dropped
+    pub fn abandoned(&self) -> bool {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner()).abandoned
+    }
+
+    fn abandon(&self, reason: &str) {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        if queue.abandoned {
+            return;
+        }
+        queue.abandoned = true;
+        queue.bytes = VecDeque::new();
+        drop(queue);
+        self.ready.notify_one();
+        metrics::registry()
+            .labeled_counter("tcpstrip_mirror_failures_total", "Mirror connections dropped, by reason", "reason")
+            .with(reason)
+            .inc();
+    }
+
+    /// Write the queue to `target` until the primary connection finishes
+    async fn run(self: Arc<Self>, target: SocketAddr, conn_id: u64) {
+        let stream = match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(target)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                debug!("Connection {}: could not connect to mirror {}: {}", conn_id, target, e);
+                return self.abandon("connect");
+            }
+            Err(_) => {
+                debug!("Connection {}: mirror {} did not accept in time", conn_id, target);
+                return self.abandon("connect");
+            }
+        };
+        let _ = stream.set_nodelay(true);
+        let registry = metrics::registry();
+        registry.counter("tcpstrip_mirror_connections_total", "Connections mirrored to a shadow target").inc();
+        let sent = registry.counter("tcpstrip_mirror_bytes_total", "Bytes sent to mirror targets");
+
+        let (mut read, mut write) = stream.into_split();
+        // Answers must not fill the mirror's window, so they are drained
+        let discard = async {
+            let _ = tokio::io::copy(&mut read, &mut tokio::io::sink()).await;
+            std::future::pending::<()>().await
+        };
+        let send = async {
+            let mut buf = Vec::with_capacity(BATCH);
+            loop {
+                let finished = {
+                    let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+                    if queue.abandoned {
+                        return Ok(());
+                    }
+                    let n = queue.bytes.len().min(BATCH);
+                    buf.clear();
+                    buf.extend(queue.bytes.drain(..n));
+                    queue.finished && queue.bytes.is_empty()
+                };
+                if !buf.is_empty() {
+                    write.write_all(&buf).await?;
+                    sent.add(buf.len() as u64);
+                }
+                if finished {
+                    return write.shutdown().await;
+                }
+                if buf.is_empty() {
+                    self.ready.notified().await;
+                }
+            }
+        };
+        let result: io::Result<()> = tokio::select! {
+            result = send => result,
+            _ = discard => Ok(()),
+        };
+        if let Err(e) = result {
+            debug!("Connection {}: mirror {} failed: {}", conn_id, target, e);
+            self.abandon("write");
+        }
+    }
+}
+
+/// Mirror a connection to `target`, queueing at most `capacity` bytes
+///
+/// The mirror connects in the background; what the client sends meanwhile
+/// is queued. Dropping the guard lets it send the rest and close.
+pub fn spawn(target: SocketAddr, capacity: usize, conn_id: u64) -> MirrorGuard {
+    let mirror = Arc::new(Mirror {
+        queue: Mutex::new(Queue {
+            bytes: VecDeque::with_capacity(capacity),
+            finished: false,
+            abandoned: false,
+        }),
+        ready: Notify::new(),
+        capacity,
+    });
+    tokio::spawn(mirror.clone().run(target, conn_id));
+    MirrorGuard { mirror }
+}
+
+/// Ends a connection's mirror once the connection is done
+#[derive(Debug)]
+pub struct MirrorGuard {
+    mirror: Arc<Mirror>,
+}
+
+impl MirrorGuard {
+    pub fn mirror(&self) -> &Mirror {
+        &self.mirror
+    }
+}
+
+impl Drop for MirrorGuard {
+    fn drop(&mut self) {
+        self.mirror.queue.lock().unwrap_or_else(|e| e.into_inner()).finished = true;
+        self.mirror.ready.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_mirror() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let guard = spawn(listener.local_addr().unwrap(), 1024, 0);
+        // Queued before the mirror is even connected
+        guard.mirror().copy(b"8=FIX.4.4\x01");
+        let (mut shadow, _) = listener.accept().await.unwrap();
+        guard.mirror().copy(b"35=D\x01");
+        shadow.write_all(b"ignored").await.unwrap();
+        drop(guard);
+        let mut copied = Vec::new();
+        shadow.read_to_end(&mut copied).await.unwrap();
+        assert_eq!(copied, b"8=FIX.4.4\x0135=D\x01");
+
+        // Too much for the buffer: cut off, and further copies are ignored
+        let guard = spawn(listener.local_addr().unwrap(), 8, 1);
+        guard.mirror().copy(b"12345");
+        assert!(!guard.mirror().abandoned());
+        guard.mirror().copy(b"6789");
+        assert!(guard.mirror().abandoned());
+        guard.mirror().copy(b"0");
+        drop(guard);
+        let (mut shadow, _) = listener.accept().await.unwrap();
+        copied.clear();
+        shadow.read_to_end(&mut copied).await.unwrap();
+        assert!(copied.is_empty());
+
+        // Nobody listening
+        let closed = listener.local_addr().unwrap();
+        drop(listener);
+        let guard = spawn(closed, 8, 2);
+        for _ in 0..100 {
+            if guard.mirror().abandoned() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(guard.mirror().abandoned());
+    }
+}
@@ -0,0 +1,155 @@
+//! Live view of a running proxy
+//!
+//! `tcp-proxy monitor` scrapes another proxy's admin listener (`GET
+//! /metrics`) at a fixed interval and prints one line per scrape: active
+//! connections, and the new connection, connect failure, byte and timestamp
+//! leak rates since the previous scrape. Labeled samples are summed per
+//! metric, so the line covers every route and source.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// How long a scrape may take before it is given up
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Metric totals from one scrape, labels summed away
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sample(HashMap<String, f64>);
+
+impl Sample {
+    /// Parse a Prometheus text exposition
+    pub fn parse(text: &str) -> Self {
+        let mut totals = HashMap::new();
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            let Some((series, value)) = line.rsplit_once(' ') else {
+                continue;
+            };
+            let Ok(value) = value.parse::<f64>() else {
+                continue;
+            };
+            let name = series.split('{').next().unwrap_or(series);
+            *totals.entry(name.to_string()).or_insert(0.0) += value;
+        }
+        Self(totals)
+    }
+
+    /// A metric's total, 0 for one the proxy does not export
+    pub fn get(&self, name: &str) -> f64 {
+        self.0.get(name).copied().unwrap_or(0.0)
+    }
+}
+
+/// Fetch and parse `/metrics` from the admin listener at `admin`
+pub fn scrape(admin: SocketAddr) -> io::Result<Sample> {
+    let mut stream = TcpStream::connect_timeout(&admin, SCRAPE_TIMEOUT)?;
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+    write!(stream, "GET /metrics HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", admin)?;
+    // The admin listener closes the connection after its response
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated response"))?;
+    let status = head.lines().next().unwrap_or_default();
+    if status.split(' ').nth(1) != Some("200") {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("/metrics answered '{}'", status)));
+    }
+    Ok(Sample::parse(body))
+}
+
+/// What happened between two scrapes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rates {
+    pub active: f64,
+    pub connections: f64,
+    pub connect_failures: f64,
+    pub bytes_up: f64,
+    pub bytes_down: f64,
+    pub leaks: f64,
+}
+
+impl Rates {
+    /// Per second rates from `before` to `after`, `elapsed` apart
+    pub fn between(before: &Sample, after: &Sample, elapsed: Duration) -> Self {
+        let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+        // Counters only go back down when the proxy restarts
+        let rate = |name: &str| (after.get(name) - before.get(name)).max(0.0) / secs;
+        Self {
+            active: after.get("tcpstrip_connections_active"),
+            connections: rate("tcpstrip_route_connections_total"),
+            connect_failures: rate("tcpstrip_connect_failures_total"),
+            bytes_up: rate("tcpstrip_route_bytes_up_total"),
+            bytes_down: rate("tcpstrip_route_bytes_down_total"),
+            leaks: rate("tcpstrip_verify_leaks_total"),
+        }
+    }
+}
+
+impl fmt::Display for Rates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "active {:>6}  connections/s {:>8.1}  connect failures/s {:>6.1}  up {:>10}/s  down {:>10}/s  leaks/s {:>5.1}",
+            self.active,
+            self.connections,
+            self.connect_failures,
+            Bytes(self.bytes_up),
+            Bytes(self.bytes_down),
+            self.leaks
+        )
+    }
+}
+
+/// A byte count with a binary unit
+struct Bytes(f64);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut value = self.0;
+        let mut unit = "B";
+        for next in ["KiB", "MiB", "GiB"] {
+            if value < 1024.0 {
+                break;
+            }
+            value /= 1024.0;
+            unit = next;
+        }
+        let text = format!("{:.1}{}", value, unit);
+        f.pad(&text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rates_sum_labels() {
+        let before = Sample::parse(
+            "# HELP tcpstrip_route_connections_total Connections per backend\n\
+             # TYPE tcpstrip_route_connections_total counter\n\
+             tcpstrip_route_connections_total{route=\"10.0.0.1:80\"} 10\n\
+             tcpstrip_route_bytes_up_total{route=\"10.0.0.1:80\"} 1000\n\
+             tcpstrip_route_bytes_up_total{route=\"10.0.0.2:80\"} 24\n",
+        );
+        let after = Sample::parse(
+            "tcpstrip_route_connections_total{route=\"10.0.0.1:80\"} 30\n\
+             tcpstrip_connections_active 4\n\
+             tcpstrip_route_bytes_up_total{route=\"10.0.0.1:80\"} 3048\n\
+             tcpstrip_route_bytes_up_total{route=\"10.0.0.2:80\"} 24\n",
+        );
+        assert_eq!(after.get("tcpstrip_route_bytes_up_total"), 3072.0);
+
+        let rates = Rates::between(&before, &after, Duration::from_secs(2));
+        assert_eq!(rates.active, 4.0);
+        assert_eq!(rates.connections, 10.0);
+        assert_eq!(rates.bytes_up, 1024.0);
+        assert_eq!(rates.connect_failures, 0.0);
+        assert!(rates.to_string().contains("up     1.0KiB/s"), "{}", rates);
+    }
+}
@@ -0,0 +1,952 @@
+//! Wire-level frame parsing and TCP option rewriting
+//!
+//! The socket proxy can only influence what the local kernel puts on the
+//! wire. The raw datapaths (see `bridge`) see complete Ethernet frames
+//! instead, so they can locate the TCP header, rewrite its options with the
+//! `tcp_analysis` helpers and put a valid frame back on the wire.
+//!
+//! Stripping an option changes the TCP header length, so every rewrite also
+//! fixes up the data offset, the IP length field and both checksums. Frames
+//! that are not plain TCP over IPv4/IPv6 (ARP, UDP, IPv6 extension headers,
+//! IP fragments) are reported as "not TCP" and should be forwarded untouched.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::metrics::{self, Metric};
+use crate::tcp_analysis::{
+    apply_layout, echo_timestamp_option, normalize_options, spoof_timestamp_option, strip_timestamp_option,
+    OptionPolicy, TcpOptionType,
+};
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const VLAN_TAG_LEN: usize = 4;
+const IPV6_HEADER_LEN: usize = 40;
+const TCP_MIN_HEADER_LEN: usize = 20;
+const TCP_MAX_OPTIONS_LEN: usize = 40;
+const IPPROTO_TCP: u8 = 6;
+
+/// TCP header flag bits (byte 13 of the header)
+pub const TCP_FLAG_FIN: u8 = 0x01;
+pub const TCP_FLAG_SYN: u8 = 0x02;
+pub const TCP_FLAG_RST: u8 = 0x04;
+pub const TCP_FLAG_PSH: u8 = 0x08;
+pub const TCP_FLAG_ACK: u8 = 0x10;
+pub const TCP_FLAG_URG: u8 = 0x20;
+pub const TCP_FLAG_ECE: u8 = 0x40;
+pub const TCP_FLAG_CWR: u8 = 0x80;
+/// Accurate ECN flag (formerly NS), in the byte before the other flags
+const TCP_FLAG_AE: u8 = 0x01;
+/// Reserved bits of the same byte
+const TCP_RESERVED_BITS: u8 = 0x0e;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpVersion {
+    V4,
+    V6,
+}
+
+/// Location of the IP and TCP headers inside a frame or packet buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpSegment {
+    pub ip_version: IpVersion,
+    /// Offset of the IP header
+    pub l3_offset: usize,
+    /// Offset of the TCP header
+    pub l4_offset: usize,
+    /// TCP header length including options (data offset * 4)
+    pub tcp_header_len: usize,
+    /// End of the IP packet; anything after this is link-layer padding
+    pub packet_end: usize,
+}
+
+impl TcpSegment {
+    /// Raw TCP option bytes (everything after the fixed 20-byte header)
+    pub fn options<'a>(&self, buf: &'a [u8]) -> &'a [u8] {
+        &buf[self.l4_offset + TCP_MIN_HEADER_LEN..self.l4_offset + self.tcp_header_len]
+    }
+
+    pub fn flags(&self, buf: &[u8]) -> u8 {
+        buf[self.l4_offset + 13]
+    }
+
+    /// IPv4 TTL or IPv6 hop limit
+    pub fn ttl(&self, buf: &[u8]) -> u8 {
+        match self.ip_version {
+            IpVersion::V4 => buf[self.l3_offset + 8],
+            IpVersion::V6 => buf[self.l3_offset + 7],
+        }
+    }
+
+    /// DSCP, the upper six bits of the IPv4 TOS / IPv6 traffic class
+    pub fn dscp(&self, buf: &[u8]) -> u8 {
+        let l3 = self.l3_offset;
+        match self.ip_version {
+            IpVersion::V4 => buf[l3 + 1] >> 2,
+            IpVersion::V6 => ((buf[l3] & 0x0f) << 2) | (buf[l3 + 1] >> 6),
+        }
+    }
+
+    /// The reserved header bits, in place (`0x0e` of the data offset byte)
+    pub fn reserved_bits(&self, buf: &[u8]) -> u8 {
+        buf[self.l4_offset + 12] & TCP_RESERVED_BITS
+    }
+
+    pub fn urgent_pointer(&self, buf: &[u8]) -> u16 {
+        u16::from_be_bytes([buf[self.l4_offset + 18], buf[self.l4_offset + 19]])
+    }
+
+    /// Whether the segment has URG, an urgent pointer or reserved bits set
+    pub fn has_unusual_bits(&self, buf: &[u8]) -> bool {
+        self.flags(buf) & TCP_FLAG_URG != 0 || self.urgent_pointer(buf) != 0 || self.reserved_bits(buf) != 0
+    }
+
+    /// The IP ECN field (ECT/CE), the low two bits of the TOS / traffic
+    /// class
+    pub fn ecn(&self, buf: &[u8]) -> u8 {
+        match self.ip_version {
+            IpVersion::V4 => buf[self.l3_offset + 1] & 0x03,
+            IpVersion::V6 => (buf[self.l3_offset + 1] >> 4) & 0x03,
+        }
+    }
+
+    /// Whether the segment carries any ECN signal, in the IP header or as
+    /// TCP ECE/CWR/AE flags
+    pub fn has_ecn(&self, buf: &[u8]) -> bool {
+        self.ecn(buf) != 0
+            || self.flags(buf) & (TCP_FLAG_ECE | TCP_FLAG_CWR) != 0
+            || buf[self.l4_offset + 12] & TCP_FLAG_AE != 0
+    }
+
+    /// IPv4 Identification; None for IPv6, which only has one in
+    /// fragment headers
+    pub fn ip_id(&self, buf: &[u8]) -> Option<u16> {
+        match self.ip_version {
+            IpVersion::V4 => Some(u16::from_be_bytes([buf[self.l3_offset + 4], buf[self.l3_offset + 5]])),
+            IpVersion::V6 => None,
+        }
+    }
+
+    /// The window field, unscaled
+    pub fn window(&self, buf: &[u8]) -> u16 {
+        u16::from_be_bytes([buf[self.l4_offset + 14], buf[self.l4_offset + 15]])
+    }
+
+    /// The value of the MSS option, if the segment carries one
+    pub fn mss(&self, buf: &[u8]) -> Option<u16> {
+        let i = find_option(buf, self, 2, 4)?;
+        Some(u16::from_be_bytes([buf[i + 2], buf[i + 3]]))
+    }
+
+    pub fn is_syn(&self, buf: &[u8]) -> bool {
+        self.flags(buf) & TCP_FLAG_SYN != 0
+    }
+
+    pub fn src_port(&self, buf: &[u8]) -> u16 {
+        u16::from_be_bytes([buf[self.l4_offset], buf[self.l4_offset + 1]])
+    }
+
+    pub fn dst_port(&self, buf: &[u8]) -> u16 {
+        u16::from_be_bytes([buf[self.l4_offset + 2], buf[self.l4_offset + 3]])
+    }
+
+    /// Directional 4-tuple identifying the connection this segment belongs to
+    pub fn flow_key(&self, buf: &[u8]) -> FlowKey {
+        let l3 = self.l3_offset;
+        let (src, dst) = match self.ip_version {
+            IpVersion::V4 => {
+                let src: [u8; 4] = buf[l3 + 12..l3 + 16].try_into().unwrap();
+                let dst: [u8; 4] = buf[l3 + 16..l3 + 20].try_into().unwrap();
+                (IpAddr::V4(Ipv4Addr::from(src)), IpAddr::V4(Ipv4Addr::from(dst)))
+            }
+            IpVersion::V6 => {
+                let src: [u8; 16] = buf[l3 + 8..l3 + 24].try_into().unwrap();
+                let dst: [u8; 16] = buf[l3 + 24..l3 + 40].try_into().unwrap();
+                (IpAddr::V6(Ipv6Addr::from(src)), IpAddr::V6(Ipv6Addr::from(dst)))
+            }
+        };
+
+        FlowKey {
+            src,
+            dst,
+            src_port: self.src_port(buf),
+            dst_port: self.dst_port(buf),
+        }
+    }
+}
+
+/// Directional TCP 4-tuple
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub src_port: u16,
+    pub dst_port: u16,
+}
+
+impl FlowKey {
+    /// The same connection seen from the other direction
+    pub fn reversed(&self) -> FlowKey {
+        FlowKey {
+            src: self.dst,
+            dst: self.src,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+        }
+    }
+}
+
+/// What to do with the TCP timestamp option when rewriting a segment
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimestampAction {
+    /// Leave the option as it is
+    #[default]
+    Preserve,
+    /// Remove the option entirely
+    Strip,
+    /// Keep the option but replace TSval with a fixed value
+    Spoof(u32),
+    /// Keep the option but replace TSval with a per-connection clock that
+    /// starts at a random origin (needs per-flow state, see `spoof`)
+    Randomize,
+    /// Keep the option but replace TSecr; the return path of a spoofed
+    /// connection uses this to hand the host back its own TSval
+    Echo(u32),
+}
+
+/// Counters kept by a wire-level datapath for one direction of traffic
+#[derive(Debug, Default)]
+pub struct ScrubStats {
+    pub packets: AtomicU64,
+    pub tcp_segments: AtomicU64,
+    pub rewritten: AtomicU64,
+    pub send_errors: AtomicU64,
+}
+
+impl ScrubStats {
+    /// One-line summary for periodic logging
+    pub fn summary(&self) -> String {
+        format!(
+            "packets={} tcp={} rewritten={} send_errors={}",
+            self.packets.load(Ordering::Relaxed),
+            self.tcp_segments.load(Ordering::Relaxed),
+            self.rewritten.load(Ordering::Relaxed),
+            self.send_errors.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Counters of one RX queue of a wire-level datapath
+///
+/// Exported as `tcpstrip_queue_*_total{queue}`, so a queue that RSS steers
+/// all (or none) of the TCP flows to, or one whose frames fail to parse,
+/// stands out next to its siblings.
+#[derive(Debug)]
+pub struct QueueStats {
+    packets: Arc<Metric>,
+    tcp_segments: Arc<Metric>,
+    rewritten: Arc<Metric>,
+    parse_failures: Arc<Metric>,
+    checksum_fixes: Arc<Metric>,
+}
+
+impl QueueStats {
+    pub fn new(queue: &str) -> Self {
+        let registry = metrics::registry();
+        let counter = |name, help| registry.labeled_counter(name, help, "queue").with(queue);
+        Self {
+            packets: counter("tcpstrip_queue_packets_total", "Frames received on each RX queue"),
+            tcp_segments: counter("tcpstrip_queue_tcp_segments_total", "TCP segments received on each RX queue"),
+            rewritten: counter("tcpstrip_queue_rewritten_total", "Segments whose options were rewritten, per RX queue"),
+            parse_failures: counter(
+                "tcpstrip_queue_parse_failures_total",
+                "Frames claiming to carry TCP that could not be parsed, per RX queue",
+            ),
+            checksum_fixes: counter(
+                "tcpstrip_queue_checksum_fixes_total",
+                "Rewritten segments that arrived with a bad checksum, per RX queue",
+            ),
+        }
+    }
+
+    /// Count a received frame, the segment found in it and whether it was
+    /// rewritten; call before the frame is modified
+    pub fn record(&self, frame: &[u8], segment: Option<&TcpSegment>, rewritten: bool) {
+        self.packets.inc();
+        match segment {
+            Some(segment) => {
+                self.tcp_segments.inc();
+                if rewritten {
+                    self.rewritten.inc();
+                    if !verify_checksums(frame, segment) {
+                        self.checksum_fixes.inc();
+                    }
+                }
+            }
+            None if claims_tcp(frame) => self.parse_failures.inc(),
+            None => {}
+        }
+    }
+}
+
+/// The ethertype and L3 offset of an Ethernet frame, past up to two
+/// 802.1Q / 802.1ad tags
+fn ethernet_l3(frame: &[u8]) -> Option<(u16, usize)> {
+    if frame.len() < ETHERNET_HEADER_LEN {
+        return None;
+    }
+
+    let mut offset = 12;
+    let mut ethertype = u16::from_be_bytes([frame[offset], frame[offset + 1]]);
+
+    for _ in 0..2 {
+        if ethertype != ETHERTYPE_VLAN && ethertype != ETHERTYPE_QINQ {
+            break;
+        }
+        offset += VLAN_TAG_LEN;
+        if frame.len() < offset + 2 {
+            return None;
+        }
+        ethertype = u16::from_be_bytes([frame[offset], frame[offset + 1]]);
+    }
+    Some((ethertype, offset + 2))
+}
+
+/// Locate the TCP segment inside an Ethernet frame (optionally VLAN tagged)
+pub fn parse_ethernet_frame(frame: &[u8]) -> Option<TcpSegment> {
+    match ethernet_l3(frame)? {
+        (ETHERTYPE_IPV4 | ETHERTYPE_IPV6, l3_offset) => parse_ip(frame, l3_offset),
+        _ => None,
+    }
+}
+
+/// Whether an IP header in the frame says a TCP header follows it, even if
+/// `parse_ethernet_frame` finds none (truncated or bogus lengths)
+///
+/// Non-first fragments and IPv6 extension headers do not count, as those
+/// are passed through by design.
+fn claims_tcp(frame: &[u8]) -> bool {
+    let ip = match ethernet_l3(frame) {
+        Some((ETHERTYPE_IPV4 | ETHERTYPE_IPV6, l3_offset)) => &frame[l3_offset..],
+        _ => return false,
+    };
+    match ip.first().map(|b| b >> 4) {
+        Some(4) if ip.len() >= 10 => {
+            let frag = u16::from_be_bytes([ip[6], ip[7]]);
+            ip[9] == IPPROTO_TCP && frag & 0x3fff == 0
+        }
+        Some(6) if ip.len() >= 7 => ip[6] == IPPROTO_TCP,
+        _ => false,
+    }
+}
+
+/// Locate the TCP segment inside a bare IPv4/IPv6 packet
+pub fn parse_ip_packet(packet: &[u8]) -> Option<TcpSegment> {
+    parse_ip(packet, 0)
+}
+
+fn parse_ip(buf: &[u8], l3_offset: usize) -> Option<TcpSegment> {
+    let ip = buf.get(l3_offset..)?;
+    let version = *ip.first()? >> 4;
+
+    let (ip_version, l4_offset, packet_end) = match version {
+        4 => {
+            if ip.len() < 20 {
+                return None;
+            }
+            let ihl = (ip[0] & 0x0f) as usize * 4;
+            let total_len = u16::from_be_bytes([ip[2], ip[3]]) as usize;
+            if ihl < 20 || total_len < ihl || total_len > ip.len() {
+                return None;
+            }
+            // Only the first fragment carries the TCP header, and resizing a
+            // fragment would break reassembly, so leave fragments alone.
+            let frag = u16::from_be_bytes([ip[6], ip[7]]);
+            if frag & 0x3fff != 0 || ip[9] != IPPROTO_TCP {
+                return None;
+            }
+            (IpVersion::V4, l3_offset + ihl, l3_offset + total_len)
+        }
+        6 => {
+            if ip.len() < IPV6_HEADER_LEN {
+                return None;
+            }
+            let payload_len = u16::from_be_bytes([ip[4], ip[5]]) as usize;
+            // Extension headers are rare on trading networks; pass them through
+            if ip[6] != IPPROTO_TCP || IPV6_HEADER_LEN + payload_len > ip.len() {
+                return None;
+            }
+            (
+                IpVersion::V6,
+                l3_offset + IPV6_HEADER_LEN,
+                l3_offset + IPV6_HEADER_LEN + payload_len,
+            )
+        }
+        _ => return None,
+    };
+
+    if packet_end < l4_offset + TCP_MIN_HEADER_LEN {
+        return None;
+    }
+    let tcp_header_len = (buf[l4_offset + 12] >> 4) as usize * 4;
+    if tcp_header_len < TCP_MIN_HEADER_LEN || l4_offset + tcp_header_len > packet_end {
+        return None;
+    }
+
+    Some(TcpSegment {
+        ip_version,
+        l3_offset,
+        l4_offset,
+        tcp_header_len,
+        packet_end,
+    })
+}
+
+/// Apply a timestamp action to a segment
+///
+/// Returns the rewritten buffer, or `None` when the segment does not need to
+/// change (no timestamp option present, or `Preserve`).
+pub fn apply_timestamp_action(
+    buf: &[u8],
+    segment: &TcpSegment,
+    action: TimestampAction,
+) -> Option<Vec<u8>> {
+    let options = segment.options(buf);
+    let new_options = match action {
+        // Randomize is resolved to a concrete Spoof value by the caller
+        TimestampAction::Preserve | TimestampAction::Randomize => return None,
+        TimestampAction::Strip => {
+            let stripped = strip_timestamp_option(options);
+            if stripped == options {
+                return None;
+            }
+            stripped
+        }
+        TimestampAction::Spoof(ts_val) => spoof_timestamp_option(options, ts_val)?,
+        TimestampAction::Echo(ts_ecr) => echo_timestamp_option(options, ts_ecr)?,
+    };
+
+    Some(rewrite_tcp_options(buf, segment, &new_options).0)
+}
+
+/// Rebuild a segment's options according to `policy`
+///
+/// Returns the rebuilt segment, or `None` if the options did not change.
+pub fn normalize_tcp_options(
+    buf: &[u8],
+    segment: &TcpSegment,
+    policy: &OptionPolicy,
+) -> Option<(Vec<u8>, TcpSegment)> {
+    if policy.is_noop() {
+        return None;
+    }
+    let options = segment.options(buf);
+    let normalized = normalize_options(options, policy);
+    if normalized == options {
+        return None;
+    }
+    Some(rewrite_tcp_options(buf, segment, &normalized))
+}
+
+/// Rebuild a segment with its options laid out like one of `layouts`
+/// (see `tcp_analysis::apply_layout`); None if that changes nothing
+pub fn layout_tcp_options(
+    buf: &[u8],
+    segment: &TcpSegment,
+    layouts: &[&[TcpOptionType]],
+) -> Option<(Vec<u8>, TcpSegment)> {
+    let options = segment.options(buf);
+    let laid_out = apply_layout(options, layouts);
+    if laid_out == options {
+        return None;
+    }
+    Some(rewrite_tcp_options(buf, segment, &laid_out))
+}
+
+/// Set the window field of a segment in place; false if it already had
+/// that value
+pub fn set_window(buf: &mut [u8], segment: &TcpSegment, window: u16) -> bool {
+    if segment.window(buf) == window {
+        return false;
+    }
+    buf[segment.l4_offset + 14..segment.l4_offset + 16].copy_from_slice(&window.to_be_bytes());
+    update_checksums(buf, segment);
+    true
+}
+
+/// Lower the MSS option of a segment to `limit` in place
+///
+/// Returns false if there is no MSS option or it is already within the
+/// limit; otherwise the value is rewritten and the checksums updated. The
+/// layout does not change, so `segment` stays valid.
+pub fn clamp_mss(buf: &mut [u8], segment: &TcpSegment, limit: u16) -> bool {
+    let Some(i) = find_option(buf, segment, 2, 4) else {
+        return false;
+    };
+    let mss = u16::from_be_bytes([buf[i + 2], buf[i + 3]]);
+    if mss <= limit {
+        return false;
+    }
+    buf[i + 2..i + 4].copy_from_slice(&limit.to_be_bytes());
+    update_checksums(buf, segment);
+    true
+}
+
+/// Replace the window scale option of a segment in place, or overwrite it
+/// with NOPs when `shift` is None
+///
+/// Returns the shift the segment carried, or None if it had no window
+/// scale option. Checksums are updated if anything changed.
+pub fn set_window_scale(buf: &mut [u8], segment: &TcpSegment, shift: Option<u8>) -> Option<u8> {
+    let i = find_option(buf, segment, 3, 3)?;
+    let original = buf[i + 2];
+    match shift {
+        Some(shift) if shift == original => return Some(original),
+        Some(shift) => buf[i + 2] = shift,
+        None => buf[i..i + 3].fill(1),
+    }
+    update_checksums(buf, segment);
+    Some(original)
+}
+
+/// Set the IPv4 TTL or IPv6 hop limit of a segment in place
+///
+/// Only the IPv4 header checksum covers the field; the TCP checksum does
+/// not, so it is left alone.
+pub fn set_ttl(buf: &mut [u8], segment: &TcpSegment, ttl: u8) {
+    let l3 = segment.l3_offset;
+    match segment.ip_version {
+        IpVersion::V4 => {
+            buf[l3 + 8] = ttl;
+            update_ipv4_checksum(buf, segment);
+        }
+        IpVersion::V6 => buf[l3 + 7] = ttl,
+    }
+}
+
+/// Set the DSCP of a segment in place, keeping its ECN bits
+pub fn set_dscp(buf: &mut [u8], segment: &TcpSegment, dscp: u8) {
+    let l3 = segment.l3_offset;
+    match segment.ip_version {
+        IpVersion::V4 => {
+            buf[l3 + 1] = (dscp << 2) | (buf[l3 + 1] & 0x03);
+            update_ipv4_checksum(buf, segment);
+        }
+        IpVersion::V6 => {
+            buf[l3] = (buf[l3] & 0xf0) | (dscp >> 2);
+            buf[l3 + 1] = ((dscp & 0x03) << 6) | (buf[l3 + 1] & 0x3f);
+        }
+    }
+}
+
+/// Clear the IP ECN field and the TCP ECE/CWR/AE flags of a segment in
+/// place; false if there was nothing to clear
+///
+/// Cleared on SYNs, these flags no longer request ECN, so connections
+/// through the scrubber never negotiate it.
+pub fn clear_ecn(buf: &mut [u8], segment: &TcpSegment) -> bool {
+    if !segment.has_ecn(buf) {
+        return false;
+    }
+    let l3 = segment.l3_offset;
+    let l4 = segment.l4_offset;
+    match segment.ip_version {
+        IpVersion::V4 => buf[l3 + 1] &= !0x03,
+        IpVersion::V6 => buf[l3 + 1] &= !0x30,
+    }
+    buf[l4 + 12] &= !TCP_FLAG_AE;
+    buf[l4 + 13] &= !(TCP_FLAG_ECE | TCP_FLAG_CWR);
+    update_checksums(buf, segment);
+    true
+}
+
+/// Clear URG, the urgent pointer and the reserved bits of a segment in
+/// place; false if none were set
+///
+/// Urgent data is then delivered inline like the rest of the stream.
+pub fn sanitize_flags(buf: &mut [u8], segment: &TcpSegment) -> bool {
+    if !segment.has_unusual_bits(buf) {
+        return false;
+    }
+    let l4 = segment.l4_offset;
+    buf[l4 + 12] &= !TCP_RESERVED_BITS;
+    buf[l4 + 13] &= !TCP_FLAG_URG;
+    buf[l4 + 18..l4 + 20].copy_from_slice(&[0, 0]);
+    update_checksums(buf, segment);
+    true
+}
+
+/// Set the IPv4 Identification of a segment in place; IPv6 segments are
+/// left alone
+pub fn set_ip_id(buf: &mut [u8], segment: &TcpSegment, id: u16) {
+    if segment.ip_version == IpVersion::V4 {
+        let l3 = segment.l3_offset;
+        buf[l3 + 4..l3 + 6].copy_from_slice(&id.to_be_bytes());
+        update_ipv4_checksum(buf, segment);
+    }
+}
+
+fn update_ipv4_checksum(buf: &mut [u8], segment: &TcpSegment) {
+    let l3 = segment.l3_offset;
+    buf[l3 + 10..l3 + 12].copy_from_slice(&[0, 0]);
+    let checksum = fold_checksum(checksum_add(&buf[l3..segment.l4_offset], 0));
+    buf[l3 + 10..l3 + 12].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Rewrite the window field of a segment sent with window scale `from` so
+/// the receiver, which assumes scale `to`, sees the same window
+///
+/// The result is rounded down and capped at 65535, so the window is never
+/// overstated. Returns false if the field did not change.
+pub fn rescale_window(buf: &mut [u8], segment: &TcpSegment, from: u8, to: u8) -> bool {
+    let field = segment.l4_offset + 14;
+    let window = segment.window(buf);
+    let bytes = (window as u64) << from;
+    let rescaled = (bytes >> to).min(u16::MAX as u64) as u16;
+    if rescaled == window {
+        return false;
+    }
+    buf[field..field + 2].copy_from_slice(&rescaled.to_be_bytes());
+    update_checksums(buf, segment);
+    true
+}
+
+/// Offset of the first option of `kind` with length `len`
+fn find_option(buf: &[u8], segment: &TcpSegment, kind: u8, len: usize) -> Option<usize> {
+    let end = segment.l4_offset + segment.tcp_header_len;
+    let mut i = segment.l4_offset + TCP_MIN_HEADER_LEN;
+    while i < end {
+        match buf[i] {
+            0 => break,
+            1 => i += 1,
+            option => {
+                let option_len = match buf.get(i + 1) {
+                    Some(&option_len) if option_len >= 2 && i + option_len as usize <= end => option_len as usize,
+                    _ => break,
+                };
+                if option == kind && option_len == len {
+                    return Some(i);
+                }
+                i += option_len;
+            }
+        }
+    }
+    None
+}
+
+/// Rebuild a segment with a new set of TCP options
+///
+/// `new_options` must already be padded to a multiple of four bytes. The
+/// data offset, IP length field and checksums are updated to match, and any
+/// link-layer padding after the IP packet is dropped.
+pub fn rewrite_tcp_options(
+    buf: &[u8],
+    segment: &TcpSegment,
+    new_options: &[u8],
+) -> (Vec<u8>, TcpSegment) {
+    assert!(new_options.len().is_multiple_of(4) && new_options.len() <= TCP_MAX_OPTIONS_LEN);
+
+    let options_start = segment.l4_offset + TCP_MIN_HEADER_LEN;
+    let old_header_end = segment.l4_offset + segment.tcp_header_len;
+
+    let mut out = Vec::with_capacity(segment.packet_end + TCP_MAX_OPTIONS_LEN);
+    out.extend_from_slice(&buf[..options_start]);
+    out.extend_from_slice(new_options);
+    out.extend_from_slice(&buf[old_header_end..segment.packet_end]);
+
+    let new_header_len = TCP_MIN_HEADER_LEN + new_options.len();
+    let rewritten = TcpSegment {
+        tcp_header_len: new_header_len,
+        packet_end: out.len(),
+        ..*segment
+    };
+
+    // Data offset lives in the high nibble; keep the reserved/NS bits
+    let doff = &mut out[segment.l4_offset + 12];
+    *doff = ((new_header_len / 4) as u8) << 4 | (*doff & 0x0f);
+
+    let l3 = segment.l3_offset;
+    match segment.ip_version {
+        IpVersion::V4 => {
+            let total_len = (rewritten.packet_end - l3) as u16;
+            out[l3 + 2..l3 + 4].copy_from_slice(&total_len.to_be_bytes());
+        }
+        IpVersion::V6 => {
+            let payload_len = (rewritten.packet_end - l3 - IPV6_HEADER_LEN) as u16;
+            out[l3 + 4..l3 + 6].copy_from_slice(&payload_len.to_be_bytes());
+        }
+    }
+
+    update_checksums(&mut out, &rewritten);
+    (out, rewritten)
+}
+
+/// Recompute the IPv4 header checksum (if any) and the TCP checksum
+pub fn update_checksums(buf: &mut [u8], segment: &TcpSegment) {
+    let l3 = segment.l3_offset;
+    let l4 = segment.l4_offset;
+
+    if segment.ip_version == IpVersion::V4 {
+        update_ipv4_checksum(buf, segment);
+    }
+
+    let tcp_len = segment.packet_end - l4;
+    let mut sum = match segment.ip_version {
+        IpVersion::V4 => {
+            let sum = checksum_add(&buf[l3 + 12..l3 + 20], 0);
+            sum + IPPROTO_TCP as u32 + tcp_len as u32
+        }
+        IpVersion::V6 => {
+            let sum = checksum_add(&buf[l3 + 8..l3 + 40], 0);
+            sum + IPPROTO_TCP as u32 + (tcp_len as u32 >> 16) + (tcp_len as u32 & 0xffff)
+        }
+    };
+
+    buf[l4 + 16..l4 + 18].copy_from_slice(&[0, 0]);
+    sum = checksum_add(&buf[l4..segment.packet_end], sum);
+    let checksum = fold_checksum(sum);
+    buf[l4 + 16..l4 + 18].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Whether the IPv4 header checksum (if any) and the TCP checksum are
+/// correct
+pub fn verify_checksums(buf: &[u8], segment: &TcpSegment) -> bool {
+    let l3 = segment.l3_offset;
+    let l4 = segment.l4_offset;
+    let tcp_len = (segment.packet_end - l4) as u32;
+    let (ip_ok, pseudo) = match segment.ip_version {
+        IpVersion::V4 => (
+            fold_checksum(checksum_add(&buf[l3..l4], 0)) == 0,
+            checksum_add(&buf[l3 + 12..l3 + 20], 0) + IPPROTO_TCP as u32 + tcp_len,
+        ),
+        IpVersion::V6 => (
+            true,
+            checksum_add(&buf[l3 + 8..l3 + 40], 0) + IPPROTO_TCP as u32 + (tcp_len >> 16) + (tcp_len & 0xffff),
+        ),
+    };
+    ip_ok && fold_checksum(checksum_add(&buf[l4..segment.packet_end], pseudo)) == 0
+}
+
+/// Add 16-bit big-endian words to a running one's complement sum
+fn checksum_add(data: &[u8], mut sum: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for word in &mut chunks {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+fn fold_checksum(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tcp_analysis::{parse_tcp_options, TcpOptionType};
+
+    /// Ethernet + IPv4 + TCP SYN carrying MSS, SACK-permitted, timestamp, NOP, wscale
+    fn ipv4_syn_frame() -> Vec<u8> {
+        let mut frame = vec![
+            0x02, 0, 0, 0, 0, 0x01, 0x02, 0, 0, 0, 0, 0x02, 0x08, 0x00, // Ethernet
+            0x45, 0x00, 0x00, 0x3c, 0x12, 0x34, 0x40, 0x00, 0x40, 0x06, 0x00, 0x00, // IPv4
+            10, 0, 0, 1, 10, 0, 0, 2,
+            0xc3, 0x50, 0x23, 0x28, 0, 0, 0, 1, 0, 0, 0, 0, // TCP ports, seq, ack
+            0xa0, TCP_FLAG_SYN, 0xfa, 0xf0, 0, 0, 0, 0, // doff=10, flags, window
+            2, 4, 0x05, 0xb4, 4, 2, // MSS, SACK-permitted
+            8, 10, 0x12, 0x34, 0x56, 0x78, 0, 0, 0, 0, // timestamp
+            1, 3, 3, 7, // NOP, wscale
+        ];
+        let segment = parse_ethernet_frame(&frame).unwrap();
+        update_checksums(&mut frame, &segment);
+        frame
+    }
+
+    #[test]
+    fn test_parse_ipv4_syn() {
+        let frame = ipv4_syn_frame();
+        let segment = parse_ethernet_frame(&frame).unwrap();
+
+        assert_eq!(segment.ip_version, IpVersion::V4);
+        assert_eq!(segment.l4_offset, 34);
+        assert_eq!(segment.tcp_header_len, 40);
+        assert!(segment.is_syn(&frame));
+        assert_eq!(segment.dst_port(&frame), 9000);
+        assert!(verify_checksums(&frame, &segment));
+    }
+
+    #[test]
+    fn test_strip_rewrites_lengths_and_checksums() {
+        let frame = ipv4_syn_frame();
+        let segment = parse_ethernet_frame(&frame).unwrap();
+
+        let rewritten = apply_timestamp_action(&frame, &segment, TimestampAction::Strip).unwrap();
+        let new_segment = parse_ethernet_frame(&rewritten).unwrap();
+
+        assert_eq!(new_segment.tcp_header_len, 32);
+        assert_eq!(rewritten.len(), frame.len() - 8);
+        assert!(verify_checksums(&rewritten, &new_segment));
+
+        let options = parse_tcp_options(new_segment.options(&rewritten));
+        assert!(options.iter().all(|o| o.kind != TcpOptionType::Timestamp));
+        assert!(options.iter().any(|o| o.kind == TcpOptionType::WindowScale));
+
+        // Nothing left to strip the second time around
+        assert!(apply_timestamp_action(&rewritten, &new_segment, TimestampAction::Strip).is_none());
+    }
+
+    #[test]
+    fn test_spoof_keeps_layout() {
+        let frame = ipv4_syn_frame();
+        let segment = parse_ethernet_frame(&frame).unwrap();
+
+        let rewritten = apply_timestamp_action(&frame, &segment, TimestampAction::Spoof(42)).unwrap();
+        let new_segment = parse_ethernet_frame(&rewritten).unwrap();
+
+        assert_eq!(new_segment, segment);
+        assert!(verify_checksums(&rewritten, &new_segment));
+        assert_eq!(&rewritten[segment.l4_offset + 28..segment.l4_offset + 32], &42u32.to_be_bytes());
+    }
+
+    #[test]
+    fn test_clamp_mss() {
+        let mut frame = ipv4_syn_frame();
+        let segment = parse_ethernet_frame(&frame).unwrap();
+
+        assert!(!clamp_mss(&mut frame, &segment, 1460));
+        assert!(clamp_mss(&mut frame, &segment, 1380));
+        assert_eq!(&frame[segment.l4_offset + 22..segment.l4_offset + 24], &1380u16.to_be_bytes());
+        assert!(verify_checksums(&frame, &segment));
+    }
+
+    #[test]
+    fn test_window_scale_rewrite() {
+        let mut frame = ipv4_syn_frame();
+        let segment = parse_ethernet_frame(&frame).unwrap();
+        let wscale = segment.l4_offset + 20 + 6 + 10 + 1;
+
+        assert_eq!(set_window_scale(&mut frame, &segment, Some(7)), Some(7));
+        assert_eq!(set_window_scale(&mut frame, &segment, Some(10)), Some(7));
+        assert_eq!(frame[wscale + 2], 10);
+        assert!(verify_checksums(&frame, &segment));
+
+        // Window 0xfaf0 sent with scale 10 does not fit scale 7
+        assert!(rescale_window(&mut frame, &segment, 10, 7));
+        assert_eq!(&frame[segment.l4_offset + 14..segment.l4_offset + 16], &[0xff, 0xff]);
+        assert!(rescale_window(&mut frame, &segment, 7, 10));
+        assert_eq!(&frame[segment.l4_offset + 14..segment.l4_offset + 16], &0x1fffu16.to_be_bytes());
+        assert!(verify_checksums(&frame, &segment));
+
+        assert_eq!(set_window_scale(&mut frame, &segment, None), Some(10));
+        assert_eq!(&frame[wscale..wscale + 3], &[1, 1, 1]);
+        assert_eq!(set_window_scale(&mut frame, &segment, None), None);
+        assert!(verify_checksums(&frame, &segment));
+    }
+
+    #[test]
+    fn test_ip_header_rewrites() {
+        let mut frame = ipv4_syn_frame();
+        let segment = parse_ethernet_frame(&frame).unwrap();
+        assert_eq!(segment.ttl(&frame), 64);
+
+        set_ttl(&mut frame, &segment, 128);
+        assert_eq!(segment.ttl(&frame), 128);
+        assert!(verify_checksums(&frame, &segment));
+
+        set_ip_id(&mut frame, &segment, 0xbeef);
+        assert_eq!(segment.ip_id(&frame), Some(0xbeef));
+        assert!(verify_checksums(&frame, &segment));
+
+        frame[segment.l3_offset + 1] = 0x01; // ECT(1)
+        set_dscp(&mut frame, &segment, 46);
+        assert_eq!(segment.dscp(&frame), 46);
+        assert_eq!(frame[segment.l3_offset + 1] & 0x03, 0x01);
+        assert!(verify_checksums(&frame, &segment));
+
+        // ECN-setup SYN from an ECT(1)-marking host
+        frame[segment.l4_offset + 13] |= TCP_FLAG_ECE | TCP_FLAG_CWR;
+        assert!(clear_ecn(&mut frame, &segment));
+        assert!(!segment.has_ecn(&frame));
+        assert_eq!(segment.dscp(&frame), 46);
+        assert_eq!(segment.flags(&frame), TCP_FLAG_SYN);
+        assert!(verify_checksums(&frame, &segment));
+        assert!(!clear_ecn(&mut frame, &segment));
+
+        frame[segment.l4_offset + 12] |= 0x04;
+        frame[segment.l4_offset + 13] |= TCP_FLAG_URG;
+        frame[segment.l4_offset + 19] = 1;
+        assert!(sanitize_flags(&mut frame, &segment));
+        assert!(!segment.has_unusual_bits(&frame));
+        assert_eq!(frame[segment.l4_offset + 12], 0xa0);
+        assert!(verify_checksums(&frame, &segment));
+    }
+
+    #[test]
+    fn test_strip_sack() {
+        let frame = ipv4_syn_frame();
+        let segment = parse_ethernet_frame(&frame).unwrap();
+        let policy = OptionPolicy {
+            strip: vec![TcpOptionType::SackPermitted, TcpOptionType::Sack],
+            strip_unknown: false,
+            canonical: false,
+        };
+
+        let (stripped, rewritten) = normalize_tcp_options(&frame, &segment, &policy).unwrap();
+        let options = rewritten.options(&stripped);
+        assert_eq!(&options[..6], &[2, 4, 0x05, 0xb4, 8, 10]);
+        assert_eq!(&options[14..], &[1, 3, 3, 7, 0, 0]);
+        assert!(verify_checksums(&stripped, &rewritten));
+        assert!(normalize_tcp_options(&stripped, &rewritten, &policy).is_none());
+    }
+
+    #[test]
+    fn test_non_tcp_and_fragments_ignored() {
+        let mut frame = ipv4_syn_frame();
+        frame[12..14].copy_from_slice(&0x0806u16.to_be_bytes()); // ARP
+        assert!(parse_ethernet_frame(&frame).is_none());
+
+        let mut frame = ipv4_syn_frame();
+        frame[20] = 0x20; // More fragments
+        assert!(parse_ethernet_frame(&frame).is_none());
+    }
+
+    #[test]
+    fn test_queue_stats() {
+        let stats = QueueStats::new("test0/3");
+        let frame = ipv4_syn_frame();
+        let segment = parse_ethernet_frame(&frame).unwrap();
+        stats.record(&frame, Some(&segment), true);
+
+        let mut corrupted = frame.clone();
+        corrupted[50] ^= 0xff;
+        stats.record(&corrupted, Some(&segment), true);
+
+        // Says TCP but its total length runs past the frame
+        let mut truncated = frame.clone();
+        truncated.truncate(40);
+        assert!(parse_ethernet_frame(&truncated).is_none());
+        stats.record(&truncated, None, false);
+
+        let mut fragment = frame.clone();
+        fragment[20] = 0x20;
+        stats.record(&fragment, None, false);
+
+        assert_eq!(stats.packets.get(), 4);
+        assert_eq!(stats.tcp_segments.get(), 2);
+        assert_eq!(stats.rewritten.get(), 2);
+        assert_eq!(stats.checksum_fixes.get(), 1);
+        assert_eq!(stats.parse_failures.get(), 1);
+    }
+}
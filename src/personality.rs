@@ -0,0 +1,122 @@
+//! OS personalities for outbound SYNs
+//!
+//! Passive fingerprinting tools such as p0f tell a host's OS from its SYNs:
+//! the TTL, the window size and scale, the MSS and above all which options
+//! it sends in which order. `--personality` rewrites all of them to what
+//! the chosen OS sends, so the host behind the scrubber passes for it.
+//!
+//! The scrubber cannot make a host negotiate options it did not offer, so a
+//! profile's option layout is followed as far as the host's options allow
+//! (see `tcp_analysis::apply_layout`); options the emulated OS would not
+//! send are dropped. Settings given explicitly (`--ttl`, `--mss-clamp`,
+//! `--window-scale`) take precedence over the profile's.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::tcp_analysis::TcpOptionType;
+
+const MSS: TcpOptionType = TcpOptionType::MaximumSegmentSize;
+const NOP: TcpOptionType = TcpOptionType::NoOperation;
+const WS: TcpOptionType = TcpOptionType::WindowScale;
+const SOK: TcpOptionType = TcpOptionType::SackPermitted;
+const TS: TcpOptionType = TcpOptionType::Timestamp;
+
+/// An OS whose SYNs the scrubber can imitate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Personality {
+    Linux515,
+    Windows11,
+    FreeBsd14,
+}
+
+/// Window field of a SYN
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SynWindow {
+    Fixed(u16),
+    /// The largest multiple of the MSS that fits the field, as Linux sends
+    /// (64240 for an MSS of 1460)
+    MssMultiple,
+}
+
+impl SynWindow {
+    pub fn value(self, mss: Option<u16>) -> u16 {
+        match self {
+            SynWindow::Fixed(window) => window,
+            SynWindow::MssMultiple => {
+                // RFC 9293's default for SYNs without the option
+                let mss = mss.unwrap_or(536).max(1);
+                u16::MAX / mss * mss
+            }
+        }
+    }
+}
+
+/// What a personality's SYNs look like
+#[derive(Debug, Clone, Copy)]
+pub struct Profile {
+    pub ttl: u8,
+    /// MTU the MSS is derived from; every profile assumes Ethernet
+    pub mtu: u16,
+    pub window: SynWindow,
+    pub window_scale: u8,
+    /// Option layouts in p0f order, preferred first; the first one whose
+    /// options the host offered is used
+    pub layouts: &'static [&'static [TcpOptionType]],
+}
+
+impl Personality {
+    pub fn profile(self) -> Profile {
+        match self {
+            Personality::Linux515 => Profile {
+                ttl: 64,
+                mtu: 1500,
+                window: SynWindow::MssMultiple,
+                window_scale: 7,
+                layouts: &[&[MSS, SOK, TS, NOP, WS], &[MSS, NOP, NOP, SOK, NOP, WS]],
+            },
+            // Timestamps are off by default, so a layout with them would
+            // stand out more than dropping them
+            Personality::Windows11 => Profile {
+                ttl: 128,
+                mtu: 1500,
+                window: SynWindow::Fixed(64240),
+                window_scale: 8,
+                layouts: &[&[MSS, NOP, WS, NOP, NOP, SOK]],
+            },
+            Personality::FreeBsd14 => Profile {
+                ttl: 64,
+                mtu: 1500,
+                window: SynWindow::Fixed(65535),
+                window_scale: 6,
+                layouts: &[&[MSS, NOP, WS, SOK, TS], &[MSS, NOP, WS, SOK]],
+            },
+        }
+    }
+}
+
+impl fmt::Display for Personality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Personality::Linux515 => write!(f, "linux-5.15"),
+            Personality::Windows11 => write!(f, "windows-11"),
+            Personality::FreeBsd14 => write!(f, "freebsd-14"),
+        }
+    }
+}
+
+impl FromStr for Personality {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linux-5.15" => Ok(Personality::Linux515),
+            "windows-11" => Ok(Personality::Windows11),
+            "freebsd-14" => Ok(Personality::FreeBsd14),
+            _ => Err(format!(
+                "unknown personality '{}' (expected linux-5.15, windows-11 or freebsd-14)",
+                s
+            )),
+        }
+    }
+}
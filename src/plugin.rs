@@ -0,0 +1,327 @@
+//! WASM analysis plugins
+//!
+//! Operators with their own detection or classification logic can load it
+//! as a WebAssembly module instead of forking the crate. Every flow event is
+//! serialized as a small JSON object and handed to each loaded plugin, which
+//! answers with a verdict and may attach free-form labels.
+//!
+//! A plugin module (built from Rust, Go/TinyGo, or anything targeting
+//! `wasm32`) must export:
+//!
+//! ```text
+//! memory                                            linear memory
+//! tcpstrip_alloc(len: i32) -> i32                   buffer for the next event
+//! tcpstrip_on_event(ptr: i32, len: i32) -> i32      0 = allow, 1 = reject
+//! ```
+//!
+//! and may import `tcpstrip.label(ptr: i32, len: i32)` to attach a UTF-8
+//! label to the event being processed. Events look like:
+//!
+//! ```text
+//! {"event":"connect","client":"10.0.0.7:51234","target":"10.1.0.5:9000"}
+//! {"event":"close","client":"10.0.0.7:51234","target":"10.1.0.5:9000","bytes_up":812,"bytes_down":40960,"duration_ms":1532}
+//! ```
+//!
+//! Plugins run under a fuel limit so a buggy module cannot stall the proxy;
+//! a trapping or exhausted plugin is treated as having allowed the event.
+
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use tracing::warn;
+use wasmi::{Caller, Config, Engine, Linker, Memory, Module, Store, TypedFunc};
+
+/// Instructions a plugin may execute per event
+const FUEL_PER_EVENT: u64 = 10_000_000;
+/// Labels longer than this are truncated
+const MAX_LABEL_LEN: usize = 256;
+const MAX_LABELS_PER_EVENT: usize = 16;
+
+/// Something that happened to a flow, as seen by plugins
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlowEvent {
+    /// A client connected to the proxy and is about to be forwarded
+    Connect { client: SocketAddr, target: SocketAddr },
+    /// A proxied connection finished
+    Close {
+        client: SocketAddr,
+        target: SocketAddr,
+        bytes_up: u64,
+        bytes_down: u64,
+        duration_ms: u64,
+    },
+}
+
+impl FlowEvent {
+    /// JSON encoding handed to plugins
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        match self {
+            FlowEvent::Connect { client, target } => {
+                let _ = write!(
+                    json,
+                    r#"{{"event":"connect","client":"{}","target":"{}"}}"#,
+                    client, target
+                );
+            }
+            FlowEvent::Close {
+                client,
+                target,
+                bytes_up,
+                bytes_down,
+                duration_ms,
+            } => {
+                let _ = write!(
+                    json,
+                    r#"{{"event":"close","client":"{}","target":"{}","bytes_up":{},"bytes_down":{},"duration_ms":{}}}"#,
+                    client, target, bytes_up, bytes_down, duration_ms
+                );
+            }
+        }
+        json
+    }
+}
+
+/// What a plugin decided about an event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Reject,
+}
+
+/// Combined outcome of running an event through the loaded plugins
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    pub verdict: Verdict,
+    pub labels: Vec<String>,
+}
+
+#[derive(Default)]
+struct HostState {
+    labels: Vec<String>,
+}
+
+/// One instantiated plugin module
+pub struct Plugin {
+    name: String,
+    store: Store<HostState>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    on_event: TypedFunc<(i32, i32), i32>,
+}
+
+impl Plugin {
+    /// Compile and instantiate a module from `.wasm` (or `.wat`) bytes
+    pub fn from_bytes(name: impl Into<String>, bytes: &[u8]) -> io::Result<Self> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, bytes).map_err(invalid)?;
+
+        let mut linker = Linker::<HostState>::new(&engine);
+        linker
+            .func_wrap("tcpstrip", "label", host_label)
+            .map_err(invalid)?;
+
+        let mut store = Store::new(&engine, HostState::default());
+        store.set_fuel(FUEL_PER_EVENT).map_err(invalid)?;
+        let instance = linker
+            .instantiate_and_start(&mut store, &module)
+            .map_err(invalid)?;
+
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| invalid("plugin does not export `memory`"))?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&store, "tcpstrip_alloc")
+            .map_err(invalid)?;
+        let on_event = instance
+            .get_typed_func::<(i32, i32), i32>(&store, "tcpstrip_on_event")
+            .map_err(invalid)?;
+
+        Ok(Self {
+            name: name.into(),
+            store,
+            memory,
+            alloc,
+            on_event,
+        })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        Self::from_bytes(path.display().to_string(), &bytes)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Hand one event to the plugin
+    pub fn on_event(&mut self, event: &FlowEvent) -> io::Result<Decision> {
+        let json = event.to_json();
+        let len = i32::try_from(json.len()).map_err(invalid)?;
+
+        self.store.set_fuel(FUEL_PER_EVENT).map_err(invalid)?;
+        self.store.data_mut().labels.clear();
+
+        let ptr = self.alloc.call(&mut self.store, len).map_err(invalid)?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, json.as_bytes())
+            .map_err(invalid)?;
+        let verdict = match self.on_event.call(&mut self.store, (ptr, len)).map_err(invalid)? {
+            0 => Verdict::Allow,
+            _ => Verdict::Reject,
+        };
+
+        Ok(Decision {
+            verdict,
+            labels: std::mem::take(&mut self.store.data_mut().labels),
+        })
+    }
+}
+
+/// `tcpstrip.label(ptr, len)` import
+fn host_label(mut caller: Caller<'_, HostState>, ptr: i32, len: i32) {
+    let Some(memory) = caller.get_export("memory").and_then(|e| e.into_memory()) else {
+        return;
+    };
+    let mut buf = vec![0u8; (len.max(0) as usize).min(MAX_LABEL_LEN)];
+    if memory.read(&caller, ptr as u32 as usize, &mut buf).is_err() {
+        return;
+    }
+    let labels = &mut caller.data_mut().labels;
+    if labels.len() < MAX_LABELS_PER_EVENT {
+        labels.push(String::from_utf8_lossy(&buf).into_owned());
+    }
+}
+
+/// All plugins loaded with `--plugin`, consulted in order
+#[derive(Default)]
+pub struct PluginHost {
+    plugins: Vec<Plugin>,
+}
+
+impl PluginHost {
+    pub fn load(paths: &[PathBuf]) -> io::Result<Self> {
+        let plugins = paths
+            .iter()
+            .map(|path| {
+                Plugin::load(path).map_err(|e| {
+                    io::Error::new(e.kind(), format!("plugin {}: {}", path.display(), e))
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self { plugins })
+    }
+
+    pub fn with_plugins(plugins: Vec<Plugin>) -> Self {
+        Self { plugins }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+
+    /// Run an event through every plugin
+    ///
+    /// Any reject wins; labels from all plugins are collected. Plugin
+    /// failures are logged and count as allow, so a broken module degrades
+    /// to a no-op rather than taking traffic down with it.
+    pub fn evaluate(&mut self, event: &FlowEvent) -> Decision {
+        let mut decision = Decision {
+            verdict: Verdict::Allow,
+            labels: Vec::new(),
+        };
+
+        for plugin in &mut self.plugins {
+            match plugin.on_event(event) {
+                Ok(d) => {
+                    if d.verdict == Verdict::Reject {
+                        decision.verdict = Verdict::Reject;
+                    }
+                    decision.labels.extend(d.labels);
+                }
+                Err(e) => warn!("Plugin {} failed: {}", plugin.name(), e),
+            }
+        }
+
+        decision
+    }
+}
+
+fn invalid(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Labels every event and rejects the (longer) close events
+    const TEST_PLUGIN: &str = r#"
+        (module
+          (import "tcpstrip" "label" (func $label (param i32 i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "seen")
+          (func (export "tcpstrip_alloc") (param i32) (result i32)
+            (i32.const 1024))
+          (func (export "tcpstrip_on_event") (param $ptr i32) (param $len i32) (result i32)
+            (call $label (i32.const 0) (i32.const 4))
+            (i32.gt_u (local.get $len) (i32.const 100))))
+    "#;
+
+    const SPIN_PLUGIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "tcpstrip_alloc") (param i32) (result i32)
+            (i32.const 0))
+          (func (export "tcpstrip_on_event") (param i32 i32) (result i32)
+            (loop $forever (br $forever))
+            (i32.const 1)))
+    "#;
+
+    fn connect() -> FlowEvent {
+        FlowEvent::Connect {
+            client: "10.0.0.7:51234".parse().unwrap(),
+            target: "10.1.0.5:9000".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_event_json() {
+        assert_eq!(
+            connect().to_json(),
+            r#"{"event":"connect","client":"10.0.0.7:51234","target":"10.1.0.5:9000"}"#
+        );
+    }
+
+    #[test]
+    fn test_plugin_verdicts_and_labels() {
+        let mut plugin = Plugin::from_bytes("test", TEST_PLUGIN.as_bytes()).unwrap();
+        let decision = plugin.on_event(&connect()).unwrap();
+        assert_eq!(decision.verdict, Verdict::Allow);
+        assert_eq!(decision.labels, vec!["seen".to_string()]);
+
+        let close = FlowEvent::Close {
+            client: "10.0.0.7:51234".parse().unwrap(),
+            target: "10.1.0.5:9000".parse().unwrap(),
+            bytes_up: 812,
+            bytes_down: 40960,
+            duration_ms: 1532,
+        };
+        let mut host = PluginHost::with_plugins(vec![plugin]);
+        let decision = host.evaluate(&close);
+        assert_eq!(decision.verdict, Verdict::Reject);
+        assert_eq!(decision.labels.len(), 1, "labels are reset between events");
+    }
+
+    #[test]
+    fn test_runaway_plugin_is_allowed() {
+        let spin = Plugin::from_bytes("spin", SPIN_PLUGIN.as_bytes()).unwrap();
+        let mut host = PluginHost::with_plugins(vec![spin]);
+        assert_eq!(host.evaluate(&connect()).verdict, Verdict::Allow);
+    }
+}
@@ -0,0 +1,406 @@
+//! Running as an unprivileged user with a few retained capabilities (Linux only)
+//!
+//! Most of the proxy needs no privileges at all, but deployments that mix
+//! proxying with monitoring need one or two: CAP_NET_RAW for the egress
+//! verifier and backend watcher, CAP_NET_BIND_SERVICE for a low listen
+//! port, CAP_NET_ADMIN for `--spoof-source`. Rather than running all of it
+//! as root, `--user` (and `--group`) switches to an ordinary account once
+//! the listeners, raw sockets and BPF programs are set up, and `--keep-caps`
+//! names the capabilities that survive the switch.
+//!
+//! User ids and capabilities belong to threads on Linux, and by then the
+//! runtime has spawned its workers. Like glibc does for `setuid`, the switch
+//! is therefore made by every thread itself: each is sent a signal whose
+//! handler makes the same system calls, and the process is only considered
+//! switched once all of them (including threads spawned meanwhile) have.
+//! Retained capabilities are also raised in the ambient set, so helpers run
+//! for `--manage-firewall` (`nft`, `ip`) get them too; all others are
+//! dropped from the bounding set and cannot be regained, not even through
+//! a setuid binary.
+
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI32, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// The capabilities worth retaining for the proxy's own features
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    NetBindService,
+    NetAdmin,
+    NetRaw,
+    IpcLock,
+    SysAdmin,
+    SysNice,
+    Bpf,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 7] = [
+        Capability::NetBindService,
+        Capability::NetAdmin,
+        Capability::NetRaw,
+        Capability::IpcLock,
+        Capability::SysAdmin,
+        Capability::SysNice,
+        Capability::Bpf,
+    ];
+
+    /// The capability's number (linux/capability.h)
+    fn number(self) -> u32 {
+        match self {
+            Capability::NetBindService => 10,
+            Capability::NetAdmin => 12,
+            Capability::NetRaw => 13,
+            Capability::IpcLock => 14,
+            Capability::SysAdmin => 21,
+            Capability::SysNice => 23,
+            Capability::Bpf => 39,
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Capability::NetBindService => write!(f, "net_bind_service"),
+            Capability::NetAdmin => write!(f, "net_admin"),
+            Capability::NetRaw => write!(f, "net_raw"),
+            Capability::IpcLock => write!(f, "ipc_lock"),
+            Capability::SysAdmin => write!(f, "sys_admin"),
+            Capability::SysNice => write!(f, "sys_nice"),
+            Capability::Bpf => write!(f, "bpf"),
+        }
+    }
+}
+
+impl FromStr for Capability {
+    type Err = String;
+
+    /// A name such as `net_raw`, in either case and with or without `cap_`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.to_ascii_lowercase();
+        let name = name.strip_prefix("cap_").unwrap_or(&name);
+        Capability::ALL
+            .into_iter()
+            .find(|cap| cap.to_string() == name)
+            .ok_or_else(|| {
+                let names: Vec<String> = Capability::ALL.iter().map(|cap| cap.to_string()).collect();
+                format!("unknown capability '{}' (expected one of {})", s, names.join(", "))
+            })
+    }
+}
+
+/// The account to switch to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct User {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+impl User {
+    /// Resolve `USER[:GROUP]`, by name or number; without a group the
+    /// user's primary group is used
+    pub fn lookup(spec: &str) -> io::Result<Self> {
+        let (user, group) = match spec.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (spec, None),
+        };
+        let (uid, primary_gid) = match user.parse() {
+            Ok(uid) => (uid, None),
+            Err(_) => {
+                let (uid, gid) = lookup_user(user)?;
+                (uid, Some(gid))
+            }
+        };
+        let gid = match primary_gid {
+            Some(gid) => gid,
+            None => lookup_uid(uid)?,
+        };
+        match group {
+            Some(group) => Self { uid, gid }.in_group(group),
+            None => Ok(Self { uid, gid }),
+        }
+    }
+
+    /// The same user with `group`, by name or number, as its group
+    pub fn in_group(self, group: &str) -> io::Result<Self> {
+        let gid = match group.parse() {
+            Ok(gid) => gid,
+            Err(_) => lookup_group(group)?,
+        };
+        Ok(Self { gid, ..self })
+    }
+}
+
+fn not_found(what: &str, name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no such {} '{}'", what, name))
+}
+
+fn lookup_user(name: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let c_name = CString::new(name).map_err(|_| not_found("user", name))?;
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = std::ptr::null_mut();
+    let rc = unsafe { libc::getpwnam_r(c_name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    match (rc, result.is_null()) {
+        (0, false) => Ok((pwd.pw_uid, pwd.pw_gid)),
+        (0, true) => Err(not_found("user", name)),
+        (rc, _) => Err(io::Error::from_raw_os_error(rc)),
+    }
+}
+
+/// The primary group of a numeric user
+fn lookup_uid(uid: libc::uid_t) -> io::Result<libc::gid_t> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = std::ptr::null_mut();
+    let rc = unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    match (rc, result.is_null()) {
+        (0, false) => Ok(pwd.pw_gid),
+        (0, true) => Err(not_found("user", &uid.to_string())),
+        (rc, _) => Err(io::Error::from_raw_os_error(rc)),
+    }
+}
+
+fn lookup_group(name: &str) -> io::Result<libc::gid_t> {
+    let c_name = CString::new(name).map_err(|_| not_found("group", name))?;
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = std::ptr::null_mut();
+    let rc = unsafe { libc::getgrnam_r(c_name.as_ptr(), &mut grp, buf.as_mut_ptr(), buf.len(), &mut result) };
+    match (rc, result.is_null()) {
+        (0, false) => Ok(grp.gr_gid),
+        (0, true) => Err(not_found("group", name)),
+        (rc, _) => Err(io::Error::from_raw_os_error(rc)),
+    }
+}
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// The two 32-bit halves of a capability mask
+fn cap_mask(keep: &[Capability]) -> [u32; 2] {
+    let mut mask = [0u32; 2];
+    for cap in keep {
+        let number = cap.number();
+        mask[(number / 32) as usize] |= 1 << (number % 32);
+    }
+    mask
+}
+
+/// How long the other threads get to switch
+const SWITCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the signalled threads switch to
+static TARGET_UID: AtomicU32 = AtomicU32::new(0);
+static TARGET_GID: AtomicU32 = AtomicU32::new(0);
+static KEEP_MASK: AtomicU64 = AtomicU64::new(0);
+/// Errno of the first thread that failed, 0 while none has
+static FAILED: AtomicI32 = AtomicI32::new(0);
+/// Thread ids of the threads that switched, written by their handlers
+static SWITCHED: AtomicPtr<AtomicI32> = AtomicPtr::new(std::ptr::null_mut());
+static SWITCHED_LEN: AtomicUsize = AtomicUsize::new(0);
+static SWITCHED_NEXT: AtomicUsize = AtomicUsize::new(0);
+
+fn check(rc: libc::c_long) -> Result<(), libc::c_int> {
+    match rc {
+        0 => Ok(()),
+        _ => Err(unsafe { *libc::__errno_location() }),
+    }
+}
+
+/// Switch the calling thread; only async-signal-safe system calls, and
+/// none of the libc wrappers that would pass the change on to the other
+/// threads themselves
+fn switch_thread(uid: libc::uid_t, gid: libc::gid_t, keep: u64) -> Result<(), libc::c_int> {
+    // Spawned by a thread that already switched, so it did too
+    if uid != 0 && unsafe { libc::getuid() } == uid {
+        return Ok(());
+    }
+    // Empty the bounding set except for what is kept; numbers past the
+    // kernel's last capability fail with EINVAL
+    for number in 0..64 {
+        if keep & (1 << number) != 0 {
+            continue;
+        }
+        if let Err(errno) = check(unsafe { libc::prctl(libc::PR_CAPBSET_DROP, number as libc::c_ulong, 0, 0, 0) } as libc::c_long) {
+            if errno == libc::EINVAL {
+                break;
+            }
+            return Err(errno);
+        }
+    }
+
+    check(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) } as libc::c_long)?;
+    check(unsafe { libc::syscall(libc::SYS_setgroups, 1, &gid) })?;
+    check(unsafe { libc::syscall(libc::SYS_setresgid, gid, gid, gid) })?;
+    check(unsafe { libc::syscall(libc::SYS_setresuid, uid, uid, uid) })?;
+    check(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0) } as libc::c_long)?;
+
+    // setresuid cleared the effective set; bring back just what is kept
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [keep as u32, (keep >> 32) as u32].map(|bits| CapData {
+        effective: bits,
+        permitted: bits,
+        inheritable: bits,
+    });
+    check(unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) })?;
+
+    for number in (0..64).filter(|number| keep & (1 << number) != 0) {
+        check(unsafe {
+            libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong, number as libc::c_ulong, 0, 0)
+        } as libc::c_long)?;
+    }
+    Ok(())
+}
+
+extern "C" fn switch_on_signal(_: libc::c_int) {
+    let errno = unsafe { *libc::__errno_location() };
+    let result = switch_thread(
+        TARGET_UID.load(Ordering::SeqCst),
+        TARGET_GID.load(Ordering::SeqCst),
+        KEEP_MASK.load(Ordering::SeqCst),
+    );
+    match result {
+        Ok(()) => {
+            let slot = SWITCHED_NEXT.fetch_add(1, Ordering::SeqCst);
+            let slots = SWITCHED.load(Ordering::SeqCst);
+            if slot < SWITCHED_LEN.load(Ordering::SeqCst) && !slots.is_null() {
+                let tid = unsafe { libc::gettid() };
+                unsafe { (*slots.add(slot)).store(tid, Ordering::SeqCst) };
+            }
+        }
+        Err(errno) => {
+            let _ = FAILED.compare_exchange(0, errno, Ordering::SeqCst, Ordering::SeqCst);
+        }
+    }
+    unsafe { *libc::__errno_location() = errno };
+}
+
+/// The process's threads
+fn threads() -> io::Result<Vec<libc::pid_t>> {
+    std::fs::read_dir("/proc/self/task")?
+        .map(|entry| Ok(entry?.file_name().to_str().and_then(|tid| tid.parse().ok()).unwrap_or(0)))
+        .filter(|tid| !matches!(tid, Ok(0)))
+        .collect()
+}
+
+fn alive(tid: libc::pid_t) -> bool {
+    std::path::Path::new(&format!("/proc/self/task/{}", tid)).exists()
+}
+
+/// Have every thread but the calling one switch, until a look at the
+/// thread list finds none left
+fn switch_other_threads(signal: libc::c_int) -> io::Result<()> {
+    let me = unsafe { libc::gettid() };
+    let pid = std::process::id() as libc::pid_t;
+    let mut switched = HashSet::new();
+    loop {
+        let pending: Vec<_> = threads()?.into_iter().filter(|&tid| tid != me && !switched.contains(&tid)).collect();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        // Slots for every signalled thread to sign in; leaked if a thread
+        // never answers, as its handler may still write to them
+        let slots: &'static [AtomicI32] = Vec::from_iter(pending.iter().map(|_| AtomicI32::new(0))).leak();
+        SWITCHED_NEXT.store(0, Ordering::SeqCst);
+        SWITCHED_LEN.store(slots.len(), Ordering::SeqCst);
+        SWITCHED.store(slots.as_ptr() as *mut AtomicI32, Ordering::SeqCst);
+        for &tid in &pending {
+            // A thread that exited meanwhile needs no switching
+            if unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, signal) } != 0 && io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH) {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        let deadline = Instant::now() + SWITCH_TIMEOUT;
+        loop {
+            if let errno @ 1.. = FAILED.load(Ordering::SeqCst) {
+                return Err(io::Error::from_raw_os_error(errno));
+            }
+            let answered: HashSet<_> = slots.iter().map(|slot| slot.load(Ordering::SeqCst)).collect();
+            match pending.iter().find(|&&tid| !answered.contains(&tid) && alive(tid)) {
+                None => {
+                    switched.extend(answered);
+                    break;
+                }
+                Some(tid) if Instant::now() >= deadline => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, format!("thread {} did not switch", tid)));
+                }
+                Some(_) => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        // Exited threads stay pending, but are gone from the list
+        switched.extend(pending.into_iter().filter(|&tid| !alive(tid)));
+    }
+}
+
+/// Switch every thread of the process to `user`, keeping only the `keep`
+/// capabilities
+///
+/// Must be called by root. Threads that block the signal used (the first
+/// real-time one) cannot switch, and make this fail after a few seconds.
+pub fn drop_privileges(user: User, keep: &[Capability]) -> io::Result<()> {
+    if unsafe { libc::geteuid() } != 0 {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "switching users needs root"));
+    }
+    let [low, high] = cap_mask(keep);
+    TARGET_UID.store(user.uid, Ordering::SeqCst);
+    TARGET_GID.store(user.gid, Ordering::SeqCst);
+    KEEP_MASK.store(u64::from(high) << 32 | u64::from(low), Ordering::SeqCst);
+    FAILED.store(0, Ordering::SeqCst);
+
+    let signal = libc::SIGRTMIN();
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = switch_on_signal as *const () as libc::sighandler_t;
+    action.sa_flags = libc::SA_RESTART;
+    let mut previous: libc::sigaction = unsafe { std::mem::zeroed() };
+    if unsafe { libc::sigaction(signal, &action, &mut previous) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let others = switch_other_threads(signal);
+    // Left in place after a timeout, for the thread that may still answer
+    if others.as_ref().is_ok() {
+        unsafe { libc::sigaction(signal, &previous, std::ptr::null_mut()) };
+    }
+    others?;
+    switch_thread(user.uid, user.gid, KEEP_MASK.load(Ordering::SeqCst)).map_err(io::Error::from_raw_os_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities_and_users() {
+        assert_eq!("net_raw".parse(), Ok(Capability::NetRaw));
+        assert_eq!("CAP_NET_BIND_SERVICE".parse(), Ok(Capability::NetBindService));
+        assert!("net_fly".parse::<Capability>().is_err());
+        assert_eq!(cap_mask(&[Capability::NetRaw, Capability::Bpf]), [1 << 13, 1 << 7]);
+
+        assert_eq!(User::lookup("root").unwrap(), User { uid: 0, gid: 0 });
+        assert_eq!(User::lookup("0:0").unwrap(), User { uid: 0, gid: 0 });
+        assert!(User::lookup("no-such-user-here").is_err());
+        assert_eq!(User::lookup("root").unwrap().in_group("1").unwrap(), User { uid: 0, gid: 1 });
+        assert!(User::lookup("0").unwrap().in_group("no-such-group-here").is_err());
+    }
+}
@@ -0,0 +1,112 @@
+//! CPU profiles of the running proxy
+//!
+//! With the `profiling` feature the admin listener serves
+//! `GET /debug/pprof/profile?seconds=N`: every thread's stack is sampled
+//! `SAMPLE_HZ` times a second for N seconds (`DEFAULT_SECONDS` if omitted,
+//! at most `MAX_SECONDS`) and the samples come back as a flamegraph SVG, so
+//! a slow production host can be looked at without perf or a restart.
+//!
+//! Sampling uses a SIGPROF interval timer that is only armed while a
+//! profile is being taken; the rest of the time the forwarding path pays
+//! nothing. One profile runs at a time.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use tracing::info;
+
+/// Profile length when the request does not give one
+pub const DEFAULT_SECONDS: u64 = 30;
+/// Longest profile a request may ask for
+pub const MAX_SECONDS: u64 = 300;
+/// Samples per second, off the round numbers other timers tick at
+const SAMPLE_HZ: i32 = 99;
+/// Samples taken inside these libraries are dropped: unwinding a thread
+/// interrupted in the unwinder can deadlock. libc is not among them, as
+/// that is where a proxy's time in send and recv shows up
+const BLOCKLIST: &[&str] = &["libgcc", "libunwind"];
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// The profile length asked for by a `seconds=N` query
+pub fn parse_seconds(query: &str) -> Result<Duration, String> {
+    let seconds = match query.split('&').find_map(|kv| kv.strip_prefix("seconds=")) {
+        Some(seconds) => seconds
+            .parse()
+            .ok()
+            .filter(|seconds| (1..=MAX_SECONDS).contains(seconds))
+            .ok_or_else(|| format!("invalid seconds '{}' (expected 1-{})", seconds, MAX_SECONDS))?,
+        None => DEFAULT_SECONDS,
+    };
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Sample the process for `duration` and render the flamegraph, None if
+/// no thread was on a CPU when sampled; fails with `WouldBlock` if another
+/// profile is running
+pub async fn flamegraph(duration: Duration) -> io::Result<Option<String>> {
+    if RUNNING.swap(true, Ordering::AcqRel) {
+        return Err(io::Error::new(io::ErrorKind::WouldBlock, "a profile is already running"));
+    }
+    info!("Taking a {}s CPU profile", duration.as_secs());
+    // The profiler is not Send, so it lives on a blocking thread throughout
+    let result = tokio::task::spawn_blocking(move || {
+        let guard = pprof::ProfilerGuardBuilder::default()
+            .frequency(SAMPLE_HZ)
+            .blocklist(BLOCKLIST)
+            .build()
+            .map_err(io::Error::other)?;
+        std::thread::sleep(duration);
+        let report = guard.report().build().map_err(io::Error::other)?;
+        drop(guard);
+        if report.data.is_empty() {
+            return Ok(None);
+        }
+        let mut svg = Vec::new();
+        report.flamegraph(&mut svg).map_err(io::Error::other)?;
+        String::from_utf8(svg).map(Some).map_err(io::Error::other)
+    })
+    .await
+    .unwrap_or_else(|e| Err(io::Error::other(e)));
+    RUNNING.store(false, Ordering::Release);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_flamegraph() {
+        assert_eq!(parse_seconds(""), Ok(Duration::from_secs(DEFAULT_SECONDS)));
+        assert_eq!(parse_seconds("seconds=5"), Ok(Duration::from_secs(5)));
+        assert!(parse_seconds("seconds=0").is_err());
+        assert!(parse_seconds("seconds=301").is_err());
+
+        // Keep a thread busy so there is something to sample
+        let stop = std::sync::Arc::new(AtomicBool::new(false));
+        let busy = std::thread::spawn({
+            let stop = stop.clone();
+            move || {
+                let mut x = 0u64;
+                while !stop.load(Ordering::Relaxed) {
+                    x = std::hint::black_box(x.wrapping_mul(31).wrapping_add(7));
+                }
+            }
+        });
+        let profile = flamegraph(Duration::from_millis(500));
+        tokio::pin!(profile);
+        // A second request while the first samples is turned away
+        tokio::select! {
+            _ = &mut profile => panic!("profile finished early"),
+            _ = tokio::time::sleep(Duration::from_millis(50)) => {}
+        }
+        let second = flamegraph(Duration::from_millis(10)).await;
+        assert_eq!(second.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        let svg = profile.await.unwrap();
+        stop.store(true, Ordering::Relaxed);
+        busy.join().unwrap();
+        assert!(svg.is_some_and(|svg| svg.starts_with("<?xml") && svg.contains("<svg")));
+    }
+}
@@ -0,0 +1,376 @@
+//! PROXY protocol headers for upstream connections
+//!
+//! The socket proxy terminates the client's TCP connection, so a backend
+//! only ever sees the proxy's address. With `--proxy-protocol` the proxy
+//! writes a header carrying the client's address before any payload: v2,
+//! the binary format HAProxy, nginx and most load balancers accept, unless
+//! `--proxy-protocol=v1` (or a route's `proxy-protocol=v1`) asks for the
+//! text line older backends only understand.
+//!
+//! `--proxy-protocol-tlvs` appends tcpstrip's own metadata as TLVs from the
+//! application-specific range (0xE0-0xEF), so a backend that knows them can
+//! see what the proxy saw without querying it. All values are ASCII:
+//!
+//! | Type | Value                                              |
+//! |------|----------------------------------------------------|
+//! | 0xE0 | route (the backend address the connection went to) |
+//! | 0xE1 | detected protocol (`tls`, `http`, `fix`, `unknown`) |
+//! | 0xE2 | fingerprint risk of the client leg (`low`...`critical`) |
+//! | 0xE3 | connection fingerprint, as logged                  |
+//!
+//! Receivers that do not know a type skip it by its length, as the spec
+//! requires. v1 has no room for TLVs, so v1 headers go without them.
+//!
+//! Behind a load balancer the proxy is on the receiving end: with
+//! `--accept-proxy-protocol` every client connection must open with a v1 or
+//! v2 header, which `read_header` consumes so the backend never sees it.
+//! The client address it carries then stands in for the balancer's
+//! everywhere the proxy looks at clients.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::connections::Fingerprint;
+use crate::sniff::Protocol;
+use crate::tcp_analysis::FingerprintRisk;
+
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Version 2, PROXY command
+const VERSION_COMMAND: u8 = 0x21;
+const TCP_OVER_IPV4: u8 = 0x11;
+const TCP_OVER_IPV6: u8 = 0x21;
+/// Longest v1 header, CRLF included
+const MAX_V1_LEN: usize = 107;
+
+/// How long a client may take to send its header
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub const TLV_ROUTE: u8 = 0xE0;
+pub const TLV_PROTOCOL: u8 = 0xE1;
+pub const TLV_FINGERPRINT_RISK: u8 = 0xE2;
+pub const TLV_CONNECTION_HASH: u8 = 0xE3;
+
+/// Which header to send
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Version {
+    /// Human-readable text line
+    V1,
+    /// Binary, with room for TLVs
+    #[default]
+    V2,
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(Version::V1),
+            "v2" => Ok(Version::V2),
+            _ => Err(format!("unknown PROXY protocol version '{}' (expected v1 or v2)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Version::V1 => write!(f, "v1"),
+            Version::V2 => write!(f, "v2"),
+        }
+    }
+}
+
+/// What the proxy knows about a connection, sent as TLVs
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub route: String,
+    /// None if the client sent nothing within the sniff timeout
+    pub protocol: Option<Protocol>,
+    /// None where the client's negotiated options cannot be read
+    pub fingerprint_risk: Option<FingerprintRisk>,
+    pub connection_hash: Fingerprint,
+}
+
+impl Metadata {
+    fn tlvs(&self) -> Vec<(u8, String)> {
+        let mut tlvs = vec![(TLV_ROUTE, self.route.clone())];
+        if let Some(protocol) = self.protocol {
+            tlvs.push((TLV_PROTOCOL, protocol.to_string()));
+        }
+        if let Some(risk) = self.fingerprint_risk {
+            tlvs.push((TLV_FINGERPRINT_RISK, risk.to_string()));
+        }
+        tlvs.push((TLV_CONNECTION_HASH, self.connection_hash.to_string()));
+        tlvs
+    }
+}
+
+/// Build a header of `version` for a connection from `source` to
+/// `destination`; `metadata` only goes into v2 headers
+pub fn header(version: Version, source: SocketAddr, destination: SocketAddr, metadata: Option<&Metadata>) -> Vec<u8> {
+    match version {
+        Version::V1 => header_v1(source, destination),
+        Version::V2 => header_v2(source, destination, metadata),
+    }
+}
+
+/// Build a v1 PROXY header (`PROXY TCP4 src dst sport dport\r\n`) for a
+/// connection from `source` to `destination`, with addresses mapped as in
+/// `header_v2`
+pub fn header_v1(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let line = match (source.ip().to_canonical(), destination.ip().to_canonical()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => format!("PROXY TCP4 {} {}", src, dst),
+        (src, dst) => format!("PROXY TCP6 {} {}", to_ipv6(src), to_ipv6(dst)),
+    };
+    format!("{} {} {}\r\n", line, source.port(), destination.port()).into_bytes()
+}
+
+/// Build a v2 PROXY header for a connection from `source` to `destination`
+///
+/// IPv4-mapped IPv6 addresses (IPv4 clients of a dual-stack listener) are
+/// sent as IPv4. Mixed address families are sent as IPv6, with the IPv4
+/// side mapped.
+pub fn header_v2(source: SocketAddr, destination: SocketAddr, metadata: Option<&Metadata>) -> Vec<u8> {
+    let mut body = Vec::new();
+    let family = match (source.ip().to_canonical(), destination.ip().to_canonical()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            body.extend_from_slice(&src.octets());
+            body.extend_from_slice(&dst.octets());
+            TCP_OVER_IPV4
+        }
+        (src, dst) => {
+            body.extend_from_slice(&to_ipv6(src).octets());
+            body.extend_from_slice(&to_ipv6(dst).octets());
+            TCP_OVER_IPV6
+        }
+    };
+    body.extend_from_slice(&source.port().to_be_bytes());
+    body.extend_from_slice(&destination.port().to_be_bytes());
+
+    for (kind, value) in metadata.map(Metadata::tlvs).unwrap_or_default() {
+        // Values are short strings; cap them so the length always fits
+        let value = &value.as_bytes()[..value.len().min(u16::MAX as usize)];
+        body.push(kind);
+        body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        body.extend_from_slice(value);
+    }
+
+    let mut header = Vec::with_capacity(16 + body.len());
+    header.extend_from_slice(&SIGNATURE);
+    header.push(VERSION_COMMAND);
+    header.push(family);
+    header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    header.extend_from_slice(&body);
+    header
+}
+
+fn invalid(reason: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.into())
+}
+
+/// The source and destination of a v1 header line (CRLF included); None
+/// for `PROXY UNKNOWN`
+pub fn parse_v1(line: &[u8]) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+    let line = line
+        .strip_suffix(b"\r\n")
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .ok_or_else(|| invalid("v1 header is not a CRLF-terminated text line"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, dst, sport, dport] => {
+            let ip = |s: &str| match *family {
+                "TCP4" => s.parse::<Ipv4Addr>().map(IpAddr::from).ok(),
+                _ => s.parse::<Ipv6Addr>().map(IpAddr::from).ok(),
+            };
+            let addr = |ip: Option<IpAddr>, port: &str| Some(SocketAddr::new(ip?, port.parse().ok()?));
+            match (addr(ip(src), sport), addr(ip(dst), dport)) {
+                (Some(source), Some(destination)) => Ok(Some((source, destination))),
+                _ => Err(invalid(format!("invalid addresses in v1 header '{}'", line))),
+            }
+        }
+        _ => Err(invalid(format!("invalid v1 header '{}'", line))),
+    }
+}
+
+/// The source and destination of a whole v2 header; None for the LOCAL
+/// command (health checks of the balancer itself) and for families other
+/// than TCP over IPv4/IPv6. TLVs are skipped
+pub fn parse_v2(header: &[u8]) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+    if header.len() < 16 || header[..12] != SIGNATURE || header[12] >> 4 != 2 {
+        return Err(invalid("not a v2 header"));
+    }
+    let body = &header[16..];
+    if body.len() != u16::from_be_bytes([header[14], header[15]]) as usize {
+        return Err(invalid("v2 header length does not match"));
+    }
+    match header[12] & 0x0f {
+        0 => return Ok(None),
+        1 => {}
+        command => return Err(invalid(format!("unknown v2 command {}", command))),
+    }
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+    match header[13] {
+        TCP_OVER_IPV4 if body.len() >= 12 => {
+            let ip = |at: usize| IpAddr::from(<[u8; 4]>::try_from(&body[at..at + 4]).unwrap());
+            Ok(Some((SocketAddr::new(ip(0), port(8)), SocketAddr::new(ip(4), port(10)))))
+        }
+        TCP_OVER_IPV6 if body.len() >= 36 => {
+            let ip = |at: usize| IpAddr::from(<[u8; 16]>::try_from(&body[at..at + 16]).unwrap());
+            Ok(Some((SocketAddr::new(ip(0), port(32)), SocketAddr::new(ip(16), port(34)))))
+        }
+        TCP_OVER_IPV4 | TCP_OVER_IPV6 => Err(invalid("v2 header too short for its addresses")),
+        _ => Ok(None),
+    }
+}
+
+/// Read the v1 or v2 header a connection opens with, and nothing after it;
+/// the source and destination it names, None if it names none
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+    // Either version is at least 15 bytes long (`PROXY UNKNOWN\r\n`)
+    let mut header = vec![0u8; 15];
+    stream.read_exact(&mut header).await?;
+    if header.starts_with(b"PROXY ") {
+        while !header.ends_with(b"\r\n") {
+            if header.len() == MAX_V1_LEN {
+                return Err(invalid("v1 header longer than 107 bytes"));
+            }
+            header.push(stream.read_u8().await?);
+        }
+        return parse_v1(&header);
+    }
+    if !header.starts_with(&SIGNATURE) {
+        return Err(invalid("connection does not open with a PROXY protocol header"));
+    }
+    header.push(stream.read_u8().await?);
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+    header.resize(16 + len, 0);
+    stream.read_exact(&mut header[16..]).await?;
+    parse_v2(&header)
+}
+
+fn to_ipv6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_header_v2() {
+        let client: SocketAddr = "192.0.2.10:40000".parse().unwrap();
+        let proxy: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+
+        let header = header_v2(client, proxy, None);
+        assert_eq!(&header[..12], &SIGNATURE);
+        assert_eq!(&header[12..16], &[0x21, 0x11, 0, 12]);
+        assert_eq!(&header[16..24], &[192, 0, 2, 10, 10, 0, 0, 1]);
+        assert_eq!(&header[24..], &[0x9c, 0x40, 0x1f, 0x90]);
+
+        let metadata = Metadata {
+            route: "10.1.0.5:9000".to_string(),
+            protocol: Some(Protocol::Fix),
+            fingerprint_risk: None,
+            connection_hash: Fingerprint::new(client, "10.1.0.5:9000".parse().unwrap(), UNIX_EPOCH),
+        };
+        let header = header_v2(client, proxy, Some(&metadata));
+        let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+        assert_eq!(header.len(), 16 + length);
+
+        let mut tlvs = Vec::new();
+        let mut rest = &header[28..];
+        while !rest.is_empty() {
+            let len = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+            tlvs.push((rest[0], String::from_utf8(rest[3..3 + len].to_vec()).unwrap()));
+            rest = &rest[3 + len..];
+        }
+        assert_eq!(
+            tlvs,
+            [
+                (TLV_ROUTE, "10.1.0.5:9000".to_string()),
+                (TLV_PROTOCOL, "fix".to_string()),
+                (TLV_CONNECTION_HASH, metadata.connection_hash.to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_header_v1() {
+        let client: SocketAddr = "192.0.2.10:40000".parse().unwrap();
+        let proxy: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        assert_eq!(header_v1(client, proxy), b"PROXY TCP4 192.0.2.10 10.0.0.1 40000 8080\r\n");
+        let client: SocketAddr = "[::ffff:192.0.2.10]:40000".parse().unwrap();
+        assert_eq!(header(Version::V1, client, proxy, None), header_v1("192.0.2.10:40000".parse().unwrap(), proxy));
+        let client: SocketAddr = "[2001:db8::1]:40000".parse().unwrap();
+        assert_eq!(header_v1(client, proxy), b"PROXY TCP6 2001:db8::1 ::ffff:10.0.0.1 40000 8080\r\n");
+
+        assert_eq!("v1".parse(), Ok(Version::V1));
+        assert_eq!(Version::default().to_string(), "v2");
+        assert!("3".parse::<Version>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let client: SocketAddr = "192.0.2.10:40000".parse().unwrap();
+        let lb: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:40000".parse().unwrap();
+
+        // Each header is consumed exactly, leaving the payload behind
+        for sent in [header_v1(client, lb), header_v2(client, lb, None), header_v1(v6, lb)] {
+            let stream = [sent.as_slice(), b"8=FIX.4.4"].concat();
+            let mut reader = stream.as_slice();
+            let (source, destination) = read_header(&mut reader).await.unwrap().unwrap();
+            assert_eq!(reader, b"8=FIX.4.4");
+            assert_eq!(destination.port(), 8080);
+            assert!(source == client || source == v6);
+        }
+        // TLVs are skipped along with the rest of the header
+        let metadata = Metadata {
+            route: "10.1.0.5:9000".to_string(),
+            protocol: None,
+            fingerprint_risk: None,
+            connection_hash: Fingerprint::new(client, lb, UNIX_EPOCH),
+        };
+        let sent = [header_v2(client, lb, Some(&metadata)).as_slice(), b"GET"].concat();
+        let mut reader = sent.as_slice();
+        assert_eq!(read_header(&mut reader).await.unwrap(), Some((client, lb)));
+        assert_eq!(reader, b"GET");
+
+        assert_eq!(read_header(&mut &b"PROXY UNKNOWN\r\nGET"[..]).await.unwrap(), None);
+        let mut local = header_v2(client, lb, None);
+        local[12] = 0x20;
+        assert_eq!(read_header(&mut local.as_slice()).await.unwrap(), None);
+        for bad in [&b"GET / HTTP/1.1\r\nHost: x\r\n"[..], b"PROXY TCP4 1.2.3.4 5.6.7.8 1 99999\r\n", b"PROXY TCP4 ::1 ::1 1 2\r\n"] {
+            let error = read_header(&mut &bad[..]).await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{:?}", String::from_utf8_lossy(bad));
+        }
+        let endless = [b"PROXY ".as_slice(), &[b'x'; 200]].concat();
+        assert!(read_header(&mut endless.as_slice()).await.is_err());
+    }
+
+    #[test]
+    fn test_mixed_families_use_ipv6() {
+        let client: SocketAddr = "[2001:db8::1]:40000".parse().unwrap();
+        let proxy: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        let header = header_v2(client, proxy, None);
+        assert_eq!(header[13], TCP_OVER_IPV6);
+        assert_eq!(header.len(), 16 + 36);
+        assert_eq!(&header[32..48], &"::ffff:10.0.0.1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+
+        // A dual-stack listener's view of an IPv4 client
+        let client: SocketAddr = "[::ffff:192.0.2.10]:40000".parse().unwrap();
+        let proxy: SocketAddr = "[::ffff:10.0.0.1]:8080".parse().unwrap();
+        assert_eq!(header_v2(client, proxy, None)[13], TCP_OVER_IPV4);
+    }
+}
@@ -0,0 +1,326 @@
+//! QUIC awareness for the UDP relay (`--quic`)
+//!
+//! QUIC clients move: a NAT rebinding gives the same connection a new
+//! source address or port, which a relay keyed only by client address would
+//! treat as a new client and send to whichever target is next. With
+//! `--quic` the relay reads the invariant parts of each QUIC header (RFC
+//! 8999), never the encrypted payload: a session opened by a long-header
+//! Initial is a QUIC connection, known by the Destination Connection ID
+//! the client chose and by the Source Connection ID the target answers
+//! with. Datagrams from an unknown address carrying one of those IDs belong
+//! to that connection and go to its target through its upstream socket, so
+//! the target never sees the move. Connection IDs a server hands out later
+//! travel encrypted, so a client that migrates deliberately onto one of
+//! them starts a session of its own.
+//!
+//! Live QUIC connections are listed by the admin listener's `/quic` with
+//! their version, addresses and per-direction packet and byte counts.
+//! Connections are counted in `tcpstrip_quic_connections_total{version}`
+//! and `tcpstrip_quic_connections_active`, address changes in
+//! `tcpstrip_quic_migrations_total`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+
+use crate::metrics;
+
+/// QUIC version 1 (RFC 9000)
+pub const VERSION_1: u32 = 0x0000_0001;
+/// QUIC version 2 (RFC 9369), which renumbers the long packet types
+pub const VERSION_2: u32 = 0x6b33_43cf;
+
+/// Kind of a long-header packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LongType {
+    Initial,
+    ZeroRtt,
+    Handshake,
+    Retry,
+    VersionNegotiation,
+    /// A version whose packet types we do not know
+    Unknown,
+}
+
+/// The invariant fields of a QUIC packet header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Header<'a> {
+    Long {
+        kind: LongType,
+        version: u32,
+        dcid: &'a [u8],
+        scid: &'a [u8],
+    },
+    /// Short header; the Destination Connection ID starts at `after_flags`
+    /// and has whatever length its issuer chose
+    Short { after_flags: &'a [u8] },
+}
+
+impl<'a> Header<'a> {
+    /// The header of the first QUIC packet in `datagram`, None if it cannot
+    /// be one
+    pub fn parse(datagram: &'a [u8]) -> Option<Self> {
+        let (&first, rest) = datagram.split_first()?;
+        if first & 0x80 == 0 {
+            // The fixed bit, which every version we know sets
+            return (first & 0x40 != 0).then_some(Header::Short { after_flags: rest });
+        }
+        let version = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?);
+        let rest = &rest[4..];
+        let (&dcid_len, rest) = rest.split_first()?;
+        let dcid = rest.get(..dcid_len as usize)?;
+        let rest = &rest[dcid_len as usize..];
+        let (&scid_len, rest) = rest.split_first()?;
+        let scid = rest.get(..scid_len as usize)?;
+        let bits = (first >> 4) & 0x03;
+        let kind = match version {
+            0 => LongType::VersionNegotiation,
+            VERSION_1 => [LongType::Initial, LongType::ZeroRtt, LongType::Handshake, LongType::Retry][bits as usize],
+            VERSION_2 => [LongType::Retry, LongType::Initial, LongType::ZeroRtt, LongType::Handshake][bits as usize],
+            _ => LongType::Unknown,
+        };
+        if matches!(version, VERSION_1 | VERSION_2) && (first & 0x40 == 0 || dcid.len() > 20 || scid.len() > 20) {
+            return None;
+        }
+        Some(Header::Long { kind, version, dcid, scid })
+    }
+
+    /// The Destination Connection ID, if it is `len` bytes long where the
+    /// header does not say
+    pub fn dcid(&self, len: usize) -> Option<&'a [u8]> {
+        match *self {
+            Header::Long { dcid, .. } => Some(dcid),
+            Header::Short { after_flags } => after_flags.get(..len),
+        }
+    }
+}
+
+/// Name of a QUIC version for logs and metrics
+pub fn version_name(version: u32) -> String {
+    match version {
+        VERSION_1 => "v1".to_string(),
+        VERSION_2 => "v2".to_string(),
+        // Reserved versions that force version negotiation
+        version if version & 0x0f0f_0f0f == 0x0a0a_0a0a => "negotiation".to_string(),
+        version => format!("{:#010x}", version),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut out, byte| {
+        let _ = write!(out, "{:02x}", byte);
+        out
+    })
+}
+
+/// Packets and bytes one way
+#[derive(Debug, Default)]
+pub struct Counts {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Counts {
+    pub fn add(&self, datagram_len: usize) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(datagram_len as u64, Ordering::Relaxed);
+    }
+
+    pub fn packets(&self) -> u64 {
+        self.packets.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+/// A QUIC connection through the relay
+#[derive(Debug)]
+pub struct Connection {
+    /// The Destination Connection ID of the client's first Initial
+    pub original_dcid: Vec<u8>,
+    pub version: u32,
+    pub target: SocketAddr,
+    pub started: Instant,
+    client: Mutex<SocketAddr>,
+    /// The target's Source Connection ID, once it answered
+    server_cid: Mutex<Option<Vec<u8>>>,
+    pub upstream: Counts,
+    pub downstream: Counts,
+    migrations: AtomicU64,
+}
+
+impl Connection {
+    pub fn client(&self) -> SocketAddr {
+        *self.client.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The client now sends from `client`
+    pub fn migrated(&self, client: SocketAddr) {
+        *self.client.lock().unwrap_or_else(|e| e.into_inner()) = client;
+        self.migrations.fetch_add(1, Ordering::Relaxed);
+        metrics::registry()
+            .counter("tcpstrip_quic_migrations_total", "QUIC connections followed to a new client address")
+            .inc();
+    }
+
+    pub fn migrations(&self) -> u64 {
+        self.migrations.load(Ordering::Relaxed)
+    }
+
+    /// Note the target's connection ID; true if it is a new one
+    pub fn set_server_cid(&self, cid: &[u8]) -> bool {
+        let mut server_cid = self.server_cid.lock().unwrap_or_else(|e| e.into_inner());
+        if server_cid.as_deref() == Some(cid) {
+            return false;
+        }
+        *server_cid = Some(cid.to_vec());
+        true
+    }
+}
+
+/// Live QUIC connections, for the admin listener
+#[derive(Debug, Default)]
+pub struct Registry {
+    connections: Mutex<BTreeMap<u64, Arc<Connection>>>,
+    next_id: AtomicU64,
+}
+
+impl Registry {
+    /// Register a connection opened by an Initial from `client`; it stays
+    /// listed until the guard is dropped
+    pub fn register(&'static self, original_dcid: &[u8], version: u32, client: SocketAddr, target: SocketAddr) -> ConnectionGuard {
+        let connection = Arc::new(Connection {
+            original_dcid: original_dcid.to_vec(),
+            version,
+            target,
+            started: Instant::now(),
+            client: Mutex::new(client),
+            server_cid: Mutex::new(None),
+            upstream: Counts::default(),
+            downstream: Counts::default(),
+            migrations: AtomicU64::new(0),
+        });
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.connections.lock().unwrap_or_else(|e| e.into_inner()).insert(id, connection.clone());
+        let registry = metrics::registry();
+        registry
+            .labeled_counter("tcpstrip_quic_connections_total", "QUIC connections relayed, by version", "version")
+            .with(&version_name(version))
+            .inc();
+        registry.gauge("tcpstrip_quic_connections_active", "QUIC connections currently relayed").inc();
+        ConnectionGuard {
+            registry: self,
+            id,
+            connection,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Plain-text table of the live connections, oldest first
+    pub fn render(&self) -> String {
+        let mut out = String::from("dcid\tversion\tclient\ttarget\tserver_cid\tage_s\tpackets_up\tbytes_up\tpackets_down\tbytes_down\tmigrations\n");
+        for c in self.connections.lock().unwrap_or_else(|e| e.into_inner()).values() {
+            let server_cid = c.server_cid.lock().unwrap_or_else(|e| e.into_inner()).as_deref().map_or("-".to_string(), hex);
+            let _ = writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                hex(&c.original_dcid),
+                version_name(c.version),
+                c.client(),
+                c.target,
+                server_cid,
+                c.started.elapsed().as_secs(),
+                c.upstream.packets(),
+                c.upstream.bytes(),
+                c.downstream.packets(),
+                c.downstream.bytes(),
+                c.migrations()
+            );
+        }
+        out
+    }
+}
+
+/// Removes a connection from the registry when its session ends
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    registry: &'static Registry,
+    id: u64,
+    connection: Arc<Connection>,
+}
+
+impl ConnectionGuard {
+    pub fn connection(&self) -> &Arc<Connection> {
+        &self.connection
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.registry.connections.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.id);
+        metrics::registry().gauge("tcpstrip_quic_connections_active", "QUIC connections currently relayed").dec();
+    }
+}
+
+/// The global registry
+pub fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(Registry::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_headers() {
+        // A v1 Initial: 8-byte DCID, 0-byte SCID, then token length etc.
+        let mut initial = vec![0xc3, 0, 0, 0, 1, 8, 1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0x41, 0x00];
+        assert_eq!(
+            Header::parse(&initial),
+            Some(Header::Long {
+                kind: LongType::Initial,
+                version: VERSION_1,
+                dcid: &[1, 2, 3, 4, 5, 6, 7, 8],
+                scid: &[],
+            })
+        );
+        // The same type bits mean Retry in v2
+        initial[1..5].copy_from_slice(&VERSION_2.to_be_bytes());
+        assert!(matches!(Header::parse(&initial), Some(Header::Long { kind: LongType::Retry, .. })));
+
+        // A v1 Handshake from the server with its 4-byte SCID
+        let handshake = [0xe0, 0, 0, 0, 1, 0, 4, 9, 9, 9, 9, 0x40, 0x10];
+        let header = Header::parse(&handshake).unwrap();
+        assert!(matches!(header, Header::Long { kind: LongType::Handshake, scid: [9, 9, 9, 9], .. }));
+
+        let short = [0x41, 9, 9, 9, 9, 0xaa, 0xbb];
+        assert_eq!(Header::parse(&short).unwrap().dcid(4), Some(&[9, 9, 9, 9][..]));
+        assert_eq!(Header::parse(&short[..3]).unwrap().dcid(4), None);
+
+        // Not QUIC: fixed bit clear, truncated, oversized v1 connection ID
+        assert_eq!(Header::parse(b"\x01\x02"), None);
+        assert_eq!(Header::parse(&initial[..8]), None);
+        let mut oversized = vec![0xc0, 0, 0, 0, 1, 21];
+        oversized.extend([0; 22]);
+        assert_eq!(Header::parse(&oversized), None);
+        assert_eq!(Header::parse(&[]), None);
+
+        assert_eq!(version_name(VERSION_1), "v1");
+        assert_eq!(version_name(0x1a2a3a4a), "negotiation");
+        assert_eq!(version_name(0xff00001d), "0xff00001d");
+        assert_eq!(hex(&[0x0a, 0xff]), "0aff");
+    }
+}
@@ -0,0 +1,171 @@
+//! Per-connection bandwidth limits (`--max-rate-kbps`)
+//!
+//! Recovery and replay sessions can pull a day of messages as fast as the
+//! link allows, starving the order flow that shares it. A route's
+//! connections can be held to a rate per direction: each direction of
+//! each connection has a token bucket of its own that refills at the rate
+//! and holds up to 100ms worth, and the relay waits for the bucket after
+//! forwarding what it read. Limited connections are never spliced in the
+//! kernel, where they could not be held back.
+//!
+//! Time spent waiting is counted in
+//! `tcpstrip_rate_limit_wait_micros_total{direction}`.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::fix_session::Direction;
+use crate::metrics::{self, Metric};
+
+/// Share of a second's worth of bytes a bucket holds
+const BURST: f64 = 0.1;
+
+/// Rates of the two directions of a connection, in kilobits per second;
+/// `None` leaves a direction unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Client to server
+    pub upstream: Option<u64>,
+    /// Server to client
+    pub downstream: Option<u64>,
+}
+
+impl RateLimit {
+    pub fn is_unlimited(&self) -> bool {
+        self.upstream.is_none() && self.downstream.is_none()
+    }
+
+    /// A bucket for one direction, if that direction is limited
+    pub fn bucket(&self, direction: Direction) -> Option<TokenBucket> {
+        let kbps = match direction {
+            Direction::Upstream => self.upstream,
+            Direction::Downstream => self.downstream,
+        };
+        kbps.map(|kbps| TokenBucket::new(kbps, direction))
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kbps = |rate: Option<u64>| rate.unwrap_or(0);
+        match self.upstream == self.downstream {
+            true => write!(f, "{}", kbps(self.upstream)),
+            false => write!(f, "{}/{}", kbps(self.upstream), kbps(self.downstream)),
+        }
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    /// `KBPS` for both directions, or `UP/DOWN`; 0 leaves a direction
+    /// unlimited
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let kbps = |rate: &str| {
+            rate.parse::<u64>()
+                .map(|kbps| (kbps > 0).then_some(kbps))
+                .map_err(|_| format!("invalid rate '{}' (expected KBPS or UP/DOWN)", s))
+        };
+        match s.split_once('/') {
+            Some((up, down)) => Ok(Self {
+                upstream: kbps(up)?,
+                downstream: kbps(down)?,
+            }),
+            None => {
+                let both = kbps(s)?;
+                Ok(Self {
+                    upstream: both,
+                    downstream: both,
+                })
+            }
+        }
+    }
+}
+
+/// The bucket of one direction of one connection
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Bytes per second
+    rate: f64,
+    burst: f64,
+    /// Bytes that may go out right away; negative while in debt
+    tokens: f64,
+    refilled: Instant,
+    waited: Arc<Metric>,
+}
+
+impl TokenBucket {
+    pub fn new(kbps: u64, direction: Direction) -> Self {
+        let rate = kbps as f64 * 1000.0 / 8.0;
+        let direction = match direction {
+            Direction::Upstream => "upstream",
+            Direction::Downstream => "downstream",
+        };
+        Self {
+            rate,
+            burst: rate * BURST,
+            tokens: rate * BURST,
+            refilled: Instant::now(),
+            waited: metrics::registry()
+                .labeled_counter(
+                    "tcpstrip_rate_limit_wait_micros_total",
+                    "Time rate-limited connections waited for their --max-rate-kbps",
+                    "direction",
+                )
+                .with(direction),
+        }
+    }
+
+    /// Take `n` forwarded bytes out of the bucket; how long to wait before
+    /// forwarding more
+    fn take(&mut self, n: usize, now: Instant) -> Duration {
+        self.tokens = (self.tokens + now.saturating_duration_since(self.refilled).as_secs_f64() * self.rate).min(self.burst);
+        self.refilled = now;
+        self.tokens -= n as f64;
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+
+    /// Account for `n` forwarded bytes, waiting until the rate allows more
+    pub async fn throttle(&mut self, n: usize) {
+        let wait = self.take(n, Instant::now());
+        if !wait.is_zero() {
+            self.waited.add(wait.as_micros() as u64);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rates() {
+        assert_eq!("800".parse(), Ok(RateLimit { upstream: Some(800), downstream: Some(800) }));
+        assert_eq!("0/8000".parse(), Ok(RateLimit { upstream: None, downstream: Some(8000) }));
+        assert_eq!("0/8000".parse::<RateLimit>().unwrap().to_string(), "0/8000");
+        assert!("0".parse::<RateLimit>().unwrap().is_unlimited());
+        assert!("fast".parse::<RateLimit>().is_err());
+        assert!("800/".parse::<RateLimit>().is_err());
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        // 800 kbit/s is 100000 bytes a second, with bursts of 10000
+        let mut bucket = TokenBucket::new(800, Direction::Upstream);
+        let start = bucket.refilled;
+        assert_eq!(bucket.take(10000, start), Duration::ZERO);
+        assert_eq!(bucket.take(5000, start), Duration::from_millis(50));
+        // Paid off 50ms later, and refilled no further than the burst
+        assert_eq!(bucket.take(0, start + Duration::from_millis(50)), Duration::ZERO);
+        assert_eq!(bucket.take(10000, start + Duration::from_secs(10)), Duration::ZERO);
+        assert_eq!(bucket.take(1000, start + Duration::from_secs(10)), Duration::from_millis(10));
+    }
+}
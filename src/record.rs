@@ -0,0 +1,680 @@
+//! Session recording (`--record-dir`) and replay (`tcpstrip replay`)
+//!
+//! With `--record-dir`, every proxied connection is written to a file of its
+//! own in that directory: both directions, each read stamped with the
+//! nanoseconds since the connection was set up, as the bytes the target and
+//! client receive after any PROXY protocol header, in plaintext where a leg
+//! speaks TLS. `tcpstrip replay FILE` plays the client's side of such a
+//! recording against a target again, at the recorded pace, to reproduce an
+//! incident.
+//!
+//! Like a mirror, a recording never holds the connection up: the relay
+//! appends to a buffer that a task of the recording's own writes to disk.
+//! The buffer grows as reads queue up, to at most `--record-buffer`; a
+//! recording that falls so far behind that it would grow past that stops
+//! there, and its file ends with the last read that fit.
+//!
+//! The format is little-endian throughout. A file starts with
+//!
+//! - the magic `TCPSREC1`
+//! - the connection's id (u64) and when it was set up (u64, Unix
+//!   nanoseconds)
+//! - the client's and the target's address, each a family byte (4 or 6),
+//!   the 4 or 16 address bytes and the port (u16)
+//!
+//! followed by one record per read: nanoseconds since the setup (u64), the
+//! direction (u8, 0 for client->server), the length (u32) and the bytes.
+//! A record is at most 64 MiB; a reader takes a longer one for a corrupt
+//! file.
+//!
+//! Recorded connections are counted in `tcpstrip_record_connections_total`,
+//! the bytes written to disk in `tcpstrip_record_bytes_total` and the
+//! recordings cut short in `tcpstrip_record_failures_total{reason}`
+//! (`open`, `overflow` or `write`).
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+use crate::fix_session::Direction;
+use crate::metrics;
+
+/// Default of `--record-buffer`
+pub const DEFAULT_BUFFER: usize = 1024 * 1024;
+
+/// Extension of recording files
+pub const EXTENSION: &str = "tcpsrec";
+
+const MAGIC: &[u8; 8] = b"TCPSREC1";
+
+/// Timestamp, direction and length in front of every read
+const RECORD_HEADER: usize = 13;
+
+/// Most bytes the writer takes out of the buffer at a time, and what an
+/// emptied buffer is shrunk back to
+const BATCH: usize = 64 * 1024;
+
+/// Longest record a reader accepts; no relay reads more at once
+const MAX_RECORD: usize = 64 * 1024 * 1024;
+
+/// Left of a wait that replay spins through rather than sleeps, as sleeps
+/// overshoot by up to about this much
+const SPIN: Duration = Duration::from_millis(1);
+
+/// What a recording is of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub conn_id: u64,
+    pub started: SystemTime,
+    pub client: SocketAddr,
+    pub target: SocketAddr,
+}
+
+impl Header {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(64);
+        bytes.extend(MAGIC);
+        bytes.extend(self.conn_id.to_le_bytes());
+        let started = self.started.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64);
+        bytes.extend(started.to_le_bytes());
+        for addr in [self.client, self.target] {
+            match addr.ip() {
+                IpAddr::V4(ip) => {
+                    bytes.push(4);
+                    bytes.extend(ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    bytes.push(6);
+                    bytes.extend(ip.octets());
+                }
+            }
+            bytes.extend(addr.port().to_le_bytes());
+        }
+        bytes
+    }
+
+    fn decode(from: &mut impl Read) -> io::Result<Self> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("not a recording: {}", what));
+        let mut magic = [0u8; 8];
+        from.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        let conn_id = read_u64(from)?;
+        let started = UNIX_EPOCH + Duration::from_nanos(read_u64(from)?);
+        let mut addr = || -> io::Result<SocketAddr> {
+            let mut family = [0u8; 1];
+            from.read_exact(&mut family)?;
+            let ip = match family[0] {
+                4 => {
+                    let mut octets = [0u8; 4];
+                    from.read_exact(&mut octets)?;
+                    IpAddr::from(octets)
+                }
+                6 => {
+                    let mut octets = [0u8; 16];
+                    from.read_exact(&mut octets)?;
+                    IpAddr::from(octets)
+                }
+                _ => return Err(invalid("bad address family")),
+            };
+            let mut port = [0u8; 2];
+            from.read_exact(&mut port)?;
+            Ok(SocketAddr::new(ip, u16::from_le_bytes(port)))
+        };
+        let client = addr()?;
+        let target = addr()?;
+        Ok(Self { conn_id, started, client, target })
+    }
+
+    /// File name of the recording: when the connection was set up, in UTC
+    /// to the millisecond, and its id
+    pub fn file_name(&self) -> String {
+        let since = self.started.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since.as_secs() as i64;
+        let (year, month, day) = crate::tz::civil_from_days(secs.div_euclid(86400));
+        let time = secs.rem_euclid(86400);
+        format!(
+            "{:04}{:02}{:02}T{:02}{:02}{:02}.{:03}Z-{}.{}",
+            year,
+            month,
+            day,
+            time / 3600,
+            time / 60 % 60,
+            time % 60,
+            since.subsec_millis(),
+            self.conn_id,
+            EXTENSION
+        )
+    }
+}
+
+fn read_u64(from: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    from.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// One read of a recorded connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Since the connection was set up
+    pub at: Duration,
+    pub direction: Direction,
+    pub bytes: Vec<u8>,
+}
+
+/// Reads a recording back
+#[derive(Debug)]
+pub struct Reader<R> {
+    from: R,
+    header: Header,
+    truncated: bool,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(mut from: R) -> io::Result<Self> {
+        let header = Header::decode(&mut from)?;
+        Ok(Self { from, header, truncated: false })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Whether the file ended in the middle of a record, as that of a
+    /// proxy that did not get to finish it does
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// The next record, `None` at the end
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut header = [0u8; RECORD_HEADER];
+        match read_full(&mut self.from, &mut header)? {
+            0 => return Ok(None),
+            n if n < RECORD_HEADER => return self.truncate(),
+            _ => {}
+        }
+        let at = Duration::from_nanos(u64::from_le_bytes(header[..8].try_into().unwrap()));
+        let direction = match header[8] {
+            0 => Direction::Upstream,
+            1 => Direction::Downstream,
+            other => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown direction {}", other))),
+        };
+        let len = u32::from_le_bytes(header[9..].try_into().unwrap()) as usize;
+        if len > MAX_RECORD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("a record of {} bytes, more than the {} a record can have", len, MAX_RECORD),
+            ));
+        }
+        // Grown as the bytes arrive rather than allocated up front, so a
+        // length the file cannot back does not cost memory
+        let mut bytes = Vec::new();
+        if (&mut self.from).take(len as u64).read_to_end(&mut bytes)? < len {
+            return self.truncate();
+        }
+        Ok(Some(Record { at, direction, bytes }))
+    }
+
+    fn truncate(&mut self) -> io::Result<Option<Record>> {
+        self.truncated = true;
+        Ok(None)
+    }
+}
+
+/// Read until `buf` is full or the input ends; how much was read
+fn read_full(from: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match from.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[derive(Debug)]
+struct Queue {
+    bytes: VecDeque<u8>,
+    /// The connection is done; write what is left and close
+    finished: bool,
+    /// The buffer overflowed; what is queued is written, nothing more
+    full: bool,
+    /// The file is gone; nothing is queued
+    abandoned: bool,
+}
+
+/// One connection's reads on their way to its recording
+#[derive(Debug)]
+pub struct Recorder {
+    queue: Mutex<Queue>,
+    ready: Notify,
+    capacity: usize,
+    started: Instant,
+}
+
+impl Recorder {
+    /// Queue bytes that were just forwarded; never waits
+    pub fn record(&self, direction: Direction, bytes: &[u8]) {
+        let at = self.started.elapsed().as_nanos() as u64;
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        if queue.full || queue.abandoned {
+            return;
+        }
+        if queue.bytes.len() + RECORD_HEADER + bytes.len() > self.capacity || bytes.len() > MAX_RECORD {
+            queue.full = true;
+            drop(queue);
+            count_failure("overflow");
+            return;
+        }
+        queue.bytes.extend(at.to_le_bytes());
+        queue.bytes.push_back(match direction {
+            Direction::Upstream => 0,
+            Direction::Downstream => 1,
+        });
+        queue.bytes.extend((bytes.len() as u32).to_le_bytes());
+        queue.bytes.extend(bytes);
+        drop(queue);
+        self.ready.notify_one();
+    }
+
+    /// Whether the recording stopped before the connection did
+    pub fn stopped(&self) -> bool {
+        let queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        queue.full || queue.abandoned
+    }
+
+    fn abandon(&self, reason: &str) {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        queue.abandoned = true;
+        queue.bytes = VecDeque::new();
+        drop(queue);
+        count_failure(reason);
+    }
+
+    /// Write the header and then the queue to `path` until the connection
+    /// finishes
+    async fn run(self: Arc<Self>, path: PathBuf, header: Header) {
+        let mut file = match tokio::fs::OpenOptions::new().write(true).create_new(true).open(&path).await {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Connection {}: could not create recording {}: {}", header.conn_id, path.display(), e);
+                return self.abandon("open");
+            }
+        };
+        let registry = metrics::registry();
+        registry.counter("tcpstrip_record_connections_total", "Connections recorded with --record-dir").inc();
+        let written = registry.counter("tcpstrip_record_bytes_total", "Bytes written to connection recordings");
+
+        let write = async {
+            let header = header.encode();
+            file.write_all(&header).await?;
+            written.add(header.len() as u64);
+            let mut buf = Vec::with_capacity(BATCH);
+            loop {
+                let finished = {
+                    let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+                    let n = queue.bytes.len().min(BATCH);
+                    buf.clear();
+                    buf.extend(queue.bytes.drain(..n));
+                    if queue.bytes.is_empty() {
+                        // A burst does not keep its memory once written
+                        queue.bytes.shrink_to(BATCH);
+                    }
+                    queue.finished && queue.bytes.is_empty()
+                };
+                if !buf.is_empty() {
+                    file.write_all(&buf).await?;
+                    written.add(buf.len() as u64);
+                }
+                if finished {
+                    return file.flush().await;
+                }
+                if buf.is_empty() {
+                    self.ready.notified().await;
+                }
+            }
+        };
+        let result: io::Result<()> = write.await;
+        if let Err(e) = result {
+            warn!("Connection {}: could not write recording {}: {}", header.conn_id, path.display(), e);
+            self.abandon("write");
+        }
+    }
+}
+
+fn count_failure(reason: &str) {
+    metrics::registry()
+        .labeled_counter("tcpstrip_record_failures_total", "Connection recordings cut short, by reason", "reason")
+        .with(reason)
+        .inc();
+}
+
+/// Record a connection in a new file in `dir`, queueing at most `capacity`
+/// bytes, which are only allocated as reads queue up
+///
+/// Timestamps count from this call. Dropping the guard lets the recording
+/// write the rest and close.
+pub fn spawn(dir: &Path, capacity: usize, header: Header) -> RecorderGuard {
+    let recorder = Arc::new(Recorder {
+        queue: Mutex::new(Queue {
+            bytes: VecDeque::new(),
+            finished: false,
+            full: false,
+            abandoned: false,
+        }),
+        ready: Notify::new(),
+        capacity,
+        started: Instant::now(),
+    });
+    tokio::spawn(recorder.clone().run(dir.join(header.file_name()), header));
+    RecorderGuard { recorder }
+}
+
+/// Ends a connection's recording once the connection is done
+#[derive(Debug)]
+pub struct RecorderGuard {
+    recorder: Arc<Recorder>,
+}
+
+impl RecorderGuard {
+    pub fn recorder(&self) -> &Recorder {
+        &self.recorder
+    }
+}
+
+impl Drop for RecorderGuard {
+    fn drop(&mut self) {
+        self.recorder.queue.lock().unwrap_or_else(|e| e.into_inner()).finished = true;
+        self.recorder.ready.notify_one();
+    }
+}
+
+/// `tcpstrip replay --speed` factor: 2 replays twice as fast as recorded,
+/// 0 as fast as the target takes it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Speed(pub f64);
+
+impl FromStr for Speed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<f64>() {
+            Ok(speed) if speed.is_finite() && speed >= 0.0 => Ok(Self(speed)),
+            _ => Err(format!("invalid speed '{}' (expected a factor such as 1 or 0.5, or 0 for no pacing)", s)),
+        }
+    }
+}
+
+/// How a replay went
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Replayed {
+    /// Client reads replayed, and their bytes
+    pub writes: u64,
+    pub sent: u64,
+    /// Bytes the target answered with, and what it answered in the
+    /// recording
+    pub received: u64,
+    pub recorded: u64,
+    /// Furthest a write fell behind its recorded time
+    pub max_lag: Duration,
+}
+
+/// Play the client side of a recording against `target`
+///
+/// Each client read is written at its recorded time from the connection,
+/// divided by `speed`, or straight away with a `speed` of 0. What the
+/// target answers is read and counted. Once the client's side is done it is
+/// shut down, and the target has `wait` to close its side.
+///
+/// This blocks: writes are paced by spinning through the last stretch of
+/// each wait, which must not happen on an async runtime's thread, so the
+/// calling thread sends and a thread of its own reads the answers.
+pub fn replay<R: Read>(recording: &mut Reader<R>, target: SocketAddr, speed: f64, wait: Duration) -> io::Result<Replayed> {
+    let mut stream = std::net::TcpStream::connect(target)?;
+    stream.set_nodelay(true)?;
+    let mut answers_from = stream.try_clone()?;
+    let started = Instant::now();
+    let mut replayed = Replayed::default();
+
+    std::thread::scope(|scope| {
+        // Answers are read while the client's side is sent, so the target is
+        // never stuck on a full window
+        let (closed_tx, closed) = mpsc::channel();
+        let answers = scope.spawn(move || {
+            let mut buf = vec![0u8; BATCH];
+            let mut received = 0u64;
+            let result = loop {
+                match answers_from.read(&mut buf) {
+                    Ok(0) => break Ok(received),
+                    Ok(n) => received += n as u64,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => break Err(e),
+                }
+            };
+            let _ = closed_tx.send(());
+            result
+        });
+        let answered = |answers: std::thread::ScopedJoinHandle<io::Result<u64>>| {
+            answers.join().unwrap_or_else(|_| Err(io::Error::other("the thread reading answers panicked")))
+        };
+
+        let mut send = || {
+            while let Some(record) = recording.next_record()? {
+                if record.direction == Direction::Downstream {
+                    replayed.recorded += record.bytes.len() as u64;
+                    continue;
+                }
+                if speed > 0.0 {
+                    let due = started + record.at.div_f64(speed);
+                    // Sleep through most of the wait, waking if the target
+                    // closes, then spin to the exact time
+                    if let Some(left) = due.saturating_duration_since(Instant::now()).checked_sub(SPIN) {
+                        if closed.recv_timeout(left).is_ok() {
+                            return Ok(false);
+                        }
+                    }
+                    while Instant::now() < due {
+                        std::hint::spin_loop();
+                    }
+                    replayed.max_lag = replayed.max_lag.max(due.elapsed());
+                }
+                if closed.try_recv().is_ok() {
+                    return Ok(false);
+                }
+                stream.write_all(&record.bytes)?;
+                replayed.writes += 1;
+                replayed.sent += record.bytes.len() as u64;
+            }
+            stream.shutdown(Shutdown::Write)?;
+            io::Result::Ok(true)
+        };
+        match send() {
+            Ok(true) => {}
+            Ok(false) => {
+                answered(answers)?;
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the target closed the connection before the replay was done"));
+            }
+            Err(e) => {
+                let _ = stream.shutdown(Shutdown::Both);
+                let _ = answered(answers);
+                return Err(e);
+            }
+        }
+        if closed.recv_timeout(wait).is_err() {
+            debug!("Target {} still open {:?} after the replay", target, wait);
+            // Wakes the thread reading answers
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        replayed.received = answered(answers)?;
+        Ok(replayed)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    fn header() -> Header {
+        Header {
+            conn_id: 42,
+            started: UNIX_EPOCH + Duration::from_millis(1_783_000_000_123),
+            client: "10.0.0.1:50000".parse().unwrap(),
+            target: "[2001:db8::5]:9000".parse().unwrap(),
+        }
+    }
+
+    fn scratch_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tcpstrip-record-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    /// Drop the guard and wait for the recording to be written
+    async fn finish(guard: RecorderGuard) {
+        let recorder = guard.recorder.clone();
+        drop(guard);
+        while Arc::strong_count(&recorder) > 1 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    fn recording(dir: &Path) -> PathBuf {
+        std::fs::read_dir(dir).unwrap().next().unwrap().unwrap().path()
+    }
+
+    #[tokio::test]
+    async fn test_record_and_read_back() {
+        let dir = scratch_dir("read-back");
+        let guard = spawn(&dir, 1024, header());
+        guard.recorder().record(Direction::Upstream, b"8=FIX.4.4\x0135=A\x01");
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        guard.recorder().record(Direction::Downstream, b"8=FIX.4.4\x0135=A\x01");
+        finish(guard).await;
+
+        let path = recording(&dir);
+        assert_eq!(path.file_name().unwrap(), "20260702T134640.123Z-42.tcpsrec");
+        let mut reader = Reader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.header(), &header());
+        let logon = reader.next_record().unwrap().unwrap();
+        assert_eq!((logon.direction, &logon.bytes[..]), (Direction::Upstream, &b"8=FIX.4.4\x0135=A\x01"[..]));
+        let answer = reader.next_record().unwrap().unwrap();
+        assert_eq!(answer.direction, Direction::Downstream);
+        assert!(answer.at >= logon.at + Duration::from_millis(5));
+        assert_eq!(reader.next_record().unwrap(), None);
+        assert!(!reader.truncated());
+
+        // A file cut off mid-record reads up to the last whole one
+        let bytes = std::fs::read(&path).unwrap();
+        let mut reader = Reader::new(&bytes[..bytes.len() - 3]).unwrap();
+        assert!(reader.next_record().unwrap().is_some());
+        assert_eq!(reader.next_record().unwrap(), None);
+        assert!(reader.truncated());
+        assert!(Reader::new(&b"GARBAGE!"[..]).is_err());
+
+        // A length no record can have is an error, not an allocation
+        let mut bogus = header().encode();
+        bogus.extend([0u8; 9]);
+        bogus.extend(u32::MAX.to_le_bytes());
+        let mut reader = Reader::new(&bogus[..]).unwrap();
+        assert_eq!(reader.next_record().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_overflow_keeps_what_fit() {
+        let dir = scratch_dir("overflow");
+        let guard = spawn(&dir, 2 * RECORD_HEADER + 8, header());
+        guard.recorder().record(Direction::Upstream, b"1234");
+        guard.recorder().record(Direction::Upstream, b"5678");
+        assert!(!guard.recorder().stopped());
+        guard.recorder().record(Direction::Upstream, b"9");
+        assert!(guard.recorder().stopped());
+        finish(guard).await;
+
+        let mut reader = Reader::new(std::fs::File::open(recording(&dir)).unwrap()).unwrap();
+        assert_eq!(reader.next_record().unwrap().unwrap().bytes, b"1234");
+        assert_eq!(reader.next_record().unwrap().unwrap().bytes, b"5678");
+        assert_eq!(reader.next_record().unwrap(), None);
+        assert!(!reader.truncated());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_speed() {
+        assert_eq!("2".parse(), Ok(Speed(2.0)));
+        assert_eq!("0".parse(), Ok(Speed(0.0)));
+        for invalid in ["-1", "inf", "fast"] {
+            assert!(invalid.parse::<Speed>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_keeps_pacing() {
+        let mut file = header().encode();
+        for (at, direction, bytes) in [(0, 0, &b"first"[..]), (10, 1, b"answer"), (40, 0, b"second")] {
+            file.extend((Duration::from_millis(at).as_nanos() as u64).to_le_bytes());
+            file.push(direction);
+            file.extend((bytes.len() as u32).to_le_bytes());
+            file.extend(bytes);
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut first = [0u8; 5];
+            stream.read_exact(&mut first).await.unwrap();
+            let at = Instant::now();
+            stream.write_all(b"answered").await.unwrap();
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).await.unwrap();
+            (rest, at.elapsed())
+        });
+
+        let paced = file.clone();
+        let replayed = tokio::task::spawn_blocking(move || {
+            let mut reader = Reader::new(&paced[..]).unwrap();
+            replay(&mut reader, target, 1.0, Duration::from_secs(1))
+        });
+        let replayed = replayed.await.unwrap().unwrap();
+        let (rest, gap) = server.await.unwrap();
+        assert_eq!(rest, b"second");
+        assert!(gap >= Duration::from_millis(35), "{:?}", gap);
+        assert_eq!((replayed.writes, replayed.sent, replayed.received, replayed.recorded), (2, 11, 8, 6));
+
+        // Unpaced
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut all = Vec::new();
+            stream.read_to_end(&mut all).await.unwrap();
+            all
+        });
+        let elapsed = tokio::task::spawn_blocking(move || {
+            let mut reader = Reader::new(&file[..]).unwrap();
+            let started = Instant::now();
+            replay(&mut reader, target, 0.0, Duration::from_secs(1)).unwrap();
+            started.elapsed()
+        });
+        assert!(elapsed.await.unwrap() < Duration::from_millis(35));
+        assert_eq!(server.await.unwrap(), b"firstsecond");
+    }
+}
@@ -0,0 +1,390 @@
+//! Per-route settings
+//!
+//! Settings that can differ between backends are given as `[DEST=]VALUE`
+//! rules, where DEST is an address (`10.1.0.5`) or an address and port
+//! (`10.1.0.5:9000`) and a rule without one applies to every destination.
+//! The first matching rule wins, so catch-all rules go last.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::str::FromStr;
+
+use crate::fix::LogonGuard;
+use crate::proxy_protocol;
+
+/// Destinations a rule applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    Any,
+    Host(IpAddr),
+    Service(SocketAddr),
+}
+
+impl Destination {
+    pub fn matches(&self, destination: SocketAddr) -> bool {
+        match self {
+            Destination::Any => true,
+            Destination::Host(ip) => destination.ip() == *ip,
+            Destination::Service(addr) => destination == *addr,
+        }
+    }
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match (s.parse::<SocketAddr>(), s.parse::<IpAddr>()) {
+            (Ok(addr), _) => Ok(Destination::Service(addr)),
+            (_, Ok(ip)) => Ok(Destination::Host(ip)),
+            _ => Err(format!("invalid destination '{}' (expected IP or IP:PORT)", s)),
+        }
+    }
+}
+
+/// One `[DEST=]VALUE` argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RouteRule<T> {
+    pub destination: Destination,
+    pub value: T,
+}
+
+impl<T: FromStr<Err = String>> FromStr for RouteRule<T> {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (destination, value) = match s.rsplit_once('=') {
+            None => (Destination::Any, s),
+            Some((dest, value)) => (dest.parse()?, value),
+        };
+        Ok(Self {
+            destination,
+            value: value.parse()?,
+        })
+    }
+}
+
+/// A listen address: `ADDR:PORT`, where an IPv6 link-local address needs
+/// its interface as a zone, by name or index (`[fe80::1%eth1]:9000`)
+pub fn parse_listen(s: &str) -> Result<SocketAddr, String> {
+    let invalid = || format!("invalid listen address '{}' (expected ADDR:PORT)", s);
+    let addr = match s.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(_) => {
+            // Only numeric zones parse as they are
+            let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+            let (ip, zone) = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).and_then(|host| host.split_once('%')).ok_or_else(invalid)?;
+            let ip: Ipv6Addr = ip.parse().map_err(|_| invalid())?;
+            let port: u16 = port.parse().map_err(|_| invalid())?;
+            SocketAddr::V6(SocketAddrV6::new(ip, port, 0, interface_index(zone)?))
+        }
+    };
+    match addr {
+        SocketAddr::V6(v6) if is_link_local(v6.ip()) && v6.scope_id() == 0 => {
+            Err(format!("link-local listen address '{}' needs an interface (e.g. [{}%eth1]:{})", s, v6.ip(), v6.port()))
+        }
+        addr => Ok(addr),
+    }
+}
+
+fn is_link_local(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> Result<u32, String> {
+    let unknown = || format!("unknown interface '{}'", name);
+    let c_name = std::ffi::CString::new(name).map_err(|_| unknown())?;
+    match unsafe { libc::if_nametoindex(c_name.as_ptr()) } {
+        0 => Err(unknown()),
+        index => Ok(index),
+    }
+}
+
+#[cfg(not(unix))]
+fn interface_index(name: &str) -> Result<u32, String> {
+    Err(format!("unknown interface '{}'", name))
+}
+
+/// The value for traffic to `destination`, if any rule matches
+pub fn lookup<T: Copy>(rules: &[RouteRule<T>], destination: SocketAddr) -> Option<T> {
+    rules.iter().find(|rule| rule.destination.matches(destination)).map(|rule| rule.value)
+}
+
+/// The value of the first rule without a destination, for traffic that is
+/// not tied to one
+pub fn catch_all<T: Copy>(rules: &[RouteRule<T>]) -> Option<T> {
+    rules.iter().find(|rule| rule.destination == Destination::Any).map(|rule| rule.value)
+}
+
+/// Which direction of a connection the userspace relay serves first when
+/// both have data ready
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ForwardPriority {
+    /// No preference; the ready directions are served in random order
+    #[default]
+    Fair,
+    /// Client to server first (order entry ahead of acks and market data)
+    Upstream,
+    /// Server to client first
+    Downstream,
+}
+
+impl fmt::Display for ForwardPriority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForwardPriority::Fair => write!(f, "fair"),
+            ForwardPriority::Upstream => write!(f, "upstream"),
+            ForwardPriority::Downstream => write!(f, "downstream"),
+        }
+    }
+}
+
+impl FromStr for ForwardPriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fair" => Ok(ForwardPriority::Fair),
+            "upstream" => Ok(ForwardPriority::Upstream),
+            "downstream" => Ok(ForwardPriority::Downstream),
+            _ => Err(format!("unknown priority '{}' (expected fair, upstream or downstream)", s)),
+        }
+    }
+}
+
+/// Forwarding buffer sizes of the userspace relay, per direction
+///
+/// Order entry is a trickle of small messages while market data and drop
+/// copies come back in bursts, so one size rarely suits both directions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferSizes {
+    /// Client to server
+    pub upstream: usize,
+    /// Server to client
+    pub downstream: usize,
+}
+
+impl BufferSizes {
+    pub fn both(size: usize) -> Self {
+        Self {
+            upstream: size,
+            downstream: size,
+        }
+    }
+
+    pub fn max(self) -> usize {
+        self.upstream.max(self.downstream)
+    }
+}
+
+impl fmt::Display for BufferSizes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.upstream == self.downstream {
+            true => write!(f, "{}", self.upstream),
+            false => write!(f, "{}/{}", self.upstream, self.downstream),
+        }
+    }
+}
+
+impl FromStr for BufferSizes {
+    type Err = String;
+
+    /// `BYTES` for both directions, or `UP/DOWN`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = |size: &str| {
+            size.parse()
+                .ok()
+                .filter(|&size| size > 0)
+                .ok_or_else(|| format!("invalid buffer size '{}' (expected BYTES or UP/DOWN)", s))
+        };
+        match s.split_once('/') {
+            Some((up, down)) => Ok(Self {
+                upstream: bytes(up)?,
+                downstream: bytes(down)?,
+            }),
+            None => Ok(Self::both(bytes(s)?)),
+        }
+    }
+}
+
+/// One `--route LISTEN=TARGET[,OPTION...]` argument: a listener of its own
+/// forwarding to a fixed target
+///
+/// LISTEN is a port (on 0.0.0.0) or ADDR:PORT. The options override the
+/// global settings for the route's connections: `buffer-size=BYTES` (or
+/// `UP/DOWN`, see `BufferSizes`),
+/// `spoof-timestamps[=VALUE]`, `spoof-source`, `proxy-protocol[=v1|v2]`,
+/// `accept-proxy-protocol`, `fix-logon[=BEGINSTRING]`, `fix-observe` and
+/// `mirror=HOST:PORT`, the flags and `mirror` also as `no-NAME`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenerRoute {
+    pub listen: SocketAddr,
+    /// HOST:PORT, resolved at startup
+    pub target: String,
+    pub buffer_size: Option<BufferSizes>,
+    pub spoof_timestamps: Option<bool>,
+    pub static_timestamp: Option<u32>,
+    pub spoof_source: Option<bool>,
+    /// Some(None) turns a global `--proxy-protocol` off
+    pub proxy_protocol: Option<Option<proxy_protocol::Version>>,
+    pub accept_proxy_protocol: Option<bool>,
+    /// Some(None) turns a global `--fix-logon-guard` off
+    pub fix_logon: Option<Option<LogonGuard>>,
+    pub fix_observe: Option<bool>,
+    /// HOST:PORT, resolved at startup; Some(None) turns a global
+    /// `--mirror` off
+    pub mirror: Option<Option<String>>,
+    pub backlog: Option<u32>,
+}
+
+impl FromStr for ListenerRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let mapping = parts.next().unwrap_or_default();
+        let Some((listen, target)) = mapping.split_once('=') else {
+            return Err(format!("invalid route '{}' (expected LISTEN=TARGET)", mapping));
+        };
+        let listen = match listen.parse::<u16>() {
+            Ok(port) => SocketAddr::from(([0, 0, 0, 0], port)),
+            Err(_) => parse_listen(listen)?,
+        };
+        if target.is_empty() {
+            return Err(format!("route '{}' has no target", mapping));
+        }
+        let mut route = ListenerRoute {
+            listen,
+            target: target.to_string(),
+            buffer_size: None,
+            spoof_timestamps: None,
+            static_timestamp: None,
+            spoof_source: None,
+            proxy_protocol: None,
+            accept_proxy_protocol: None,
+            fix_logon: None,
+            fix_observe: None,
+            mirror: None,
+            backlog: None,
+        };
+
+        for option in parts {
+            let (name, enabled) = match option.strip_prefix("no-") {
+                Some(name) => (name, false),
+                None => (option, true),
+            };
+            match (name.split_once('='), enabled) {
+                (Some(("buffer-size", bytes)), true) => {
+                    route.buffer_size = Some(bytes.parse()?);
+                }
+                (Some(("spoof-timestamps", value)), true) => {
+                    route.spoof_timestamps = Some(true);
+                    route.static_timestamp =
+                        Some(value.parse().map_err(|_| format!("invalid timestamp value '{}'", value))?);
+                }
+                (None, _) if name == "spoof-timestamps" => route.spoof_timestamps = Some(enabled),
+                (None, _) if name == "spoof-source" => route.spoof_source = Some(enabled),
+                (Some(("proxy-protocol", version)), true) => route.proxy_protocol = Some(Some(version.parse()?)),
+                (None, true) if name == "proxy-protocol" => route.proxy_protocol = Some(Some(Default::default())),
+                (None, false) if name == "proxy-protocol" => route.proxy_protocol = Some(None),
+                (None, _) if name == "accept-proxy-protocol" => route.accept_proxy_protocol = Some(enabled),
+                (Some(("fix-logon", begin_string)), true) => route.fix_logon = Some(Some(begin_string.parse()?)),
+                (None, true) if name == "fix-logon" => route.fix_logon = Some(Some(LogonGuard::default())),
+                (None, false) if name == "fix-logon" => route.fix_logon = Some(None),
+                (None, _) if name == "fix-observe" => route.fix_observe = Some(enabled),
+                (Some(("mirror", mirror)), true) if !mirror.is_empty() => route.mirror = Some(Some(mirror.to_string())),
+                (None, false) if name == "mirror" => route.mirror = Some(None),
+                (Some(("backlog", backlog)), true) => {
+                    route.backlog = Some(backlog.parse().map_err(|_| format!("invalid backlog '{}'", backlog))?);
+                }
+                _ => return Err(format!("unknown route option '{}'", option)),
+            }
+        }
+        Ok(route)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules() {
+        let rules: Vec<RouteRule<ForwardPriority>> = ["10.1.0.5:9000=upstream", "10.1.0.5=downstream", "fair"]
+            .iter()
+            .map(|rule| rule.parse().unwrap())
+            .collect();
+        let priority = |dest: &str| lookup(&rules, dest.parse().unwrap());
+        assert_eq!(priority("10.1.0.5:9000"), Some(ForwardPriority::Upstream));
+        assert_eq!(priority("10.1.0.5:9001"), Some(ForwardPriority::Downstream));
+        assert_eq!(priority("192.0.2.1:80"), Some(ForwardPriority::Fair));
+        assert_eq!(catch_all(&rules), Some(ForwardPriority::Fair));
+        assert_eq!(catch_all(&rules[..2]), None);
+        assert!("10.1.0.5:=upstream".parse::<RouteRule<ForwardPriority>>().is_err());
+        assert!("10.1.0.5=first".parse::<RouteRule<ForwardPriority>>().is_err());
+    }
+
+    #[test]
+    fn test_listener_routes() {
+        let route: ListenerRoute = "8081=gw2.example:9000,buffer-size=4096,spoof-timestamps=7,no-proxy-protocol,backlog=1024"
+            .parse()
+            .unwrap();
+        assert_eq!(route, ListenerRoute {
+            listen: "0.0.0.0:8081".parse().unwrap(),
+            target: "gw2.example:9000".to_string(),
+            buffer_size: Some(BufferSizes::both(4096)),
+            spoof_timestamps: Some(true),
+            static_timestamp: Some(7),
+            spoof_source: None,
+            proxy_protocol: Some(None),
+            accept_proxy_protocol: None,
+            fix_logon: None,
+            fix_observe: None,
+            mirror: None,
+            backlog: Some(1024),
+        });
+        let v6: ListenerRoute = "[::1]:8080=[::1]:9000,spoof-source".parse().unwrap();
+        assert_eq!(v6.listen, "[::1]:8080".parse().unwrap());
+        assert_eq!(v6.spoof_source, Some(true));
+        let fix: ListenerRoute = "9001=fixgw:9878,fix-logon=FIX.4.4".parse().unwrap();
+        assert_eq!(fix.fix_logon, Some(Some("FIX.4.4".parse().unwrap())));
+        assert!("9001=fixgw:9878,fix-logon=FIX.9".parse::<ListenerRoute>().is_err());
+        let observed: ListenerRoute = "9002=fixgw:9878,fix-observe".parse().unwrap();
+        assert_eq!(observed.fix_observe, Some(true));
+        let shadowed: ListenerRoute = "9002=fixgw:9878,mirror=shadow:9878".parse().unwrap();
+        assert_eq!(shadowed.mirror, Some(Some("shadow:9878".to_string())));
+        assert_eq!("9002=fixgw:9878,no-mirror".parse::<ListenerRoute>().unwrap().mirror, Some(None));
+        assert!("9002=fixgw:9878,mirror=".parse::<ListenerRoute>().is_err());
+        let v1: ListenerRoute = "9003=legacy:80,proxy-protocol=v1".parse().unwrap();
+        assert_eq!(v1.proxy_protocol, Some(Some(proxy_protocol::Version::V1)));
+        assert!("9003=legacy:80,proxy-protocol=v3".parse::<ListenerRoute>().is_err());
+        let behind_lb: ListenerRoute = "9004=gw1:9000,accept-proxy-protocol".parse().unwrap();
+        assert_eq!(behind_lb.accept_proxy_protocol, Some(true));
+        let split: ListenerRoute = "9002=mdgw:9443,buffer-size=1024/262144".parse().unwrap();
+        assert_eq!(split.buffer_size.map(|sizes| (sizes.upstream, sizes.downstream)), Some((1024, 262144)));
+        assert_eq!(split.buffer_size.unwrap().to_string(), "1024/262144");
+        assert!("9002=mdgw:9443,buffer-size=1024/0".parse::<ListenerRoute>().is_err());
+        let rule: RouteRule<BufferSizes> = "10.1.0.5:9000=512/1048576".parse().unwrap();
+        assert_eq!(rule.value.max(), 1048576);
+
+        assert!("8080".parse::<ListenerRoute>().is_err());
+        assert!("8080=".parse::<ListenerRoute>().is_err());
+        assert!("http=gw1:9000".parse::<ListenerRoute>().is_err());
+        assert!("8080=gw1:9000,no-buffer-size=1".parse::<ListenerRoute>().is_err());
+        assert!("8080=gw1:9000,turbo".parse::<ListenerRoute>().is_err());
+    }
+
+    #[test]
+    fn test_parse_listen() {
+        assert_eq!(parse_listen("127.0.0.1:9000"), Ok("127.0.0.1:9000".parse().unwrap()));
+        assert_eq!(parse_listen("[fe80::1%2]:9000"), Ok("[fe80::1%2]:9000".parse().unwrap()));
+        #[cfg(target_os = "linux")]
+        assert_eq!(parse_listen("[fe80::1%lo]:9000"), Ok("[fe80::1%1]:9000".parse().unwrap()));
+        assert!(parse_listen("[fe80::1]:9000").is_err());
+        assert!(parse_listen("[fe80::1%nosuchif0]:9000").is_err());
+        assert!(parse_listen("[10.0.0.1%lo]:9000").is_err());
+        let route: ListenerRoute = "[fe80::1%2]:8080=gw1:9000".parse().unwrap();
+        assert_eq!(route.listen.to_string(), "[fe80::1%2]:8080");
+    }
+}
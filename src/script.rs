@@ -0,0 +1,221 @@
+//! Rhai routing hooks for the socket proxy
+//!
+//! For routing rules that don't justify a WASM plugin, a Rhai script can
+//! choose the backend for each connection. The script must define
+//! `route(conn)`, which is called once per accepted connection before the
+//! backend is dialled. `conn` is a map with:
+//!
+//! ```text
+//! client_ip, client_port   peer address of the client
+//! sni                      TLS server name, or () if none was seen
+//! protocol                 "tls", "http", "fix" or "unknown"
+//! client_identity          identity of the client's TLS certificate
+//!                          (--tls-client-ca), or () without one
+//! unix_time                seconds since the epoch
+//! hour, minute, weekday    UTC wall clock (weekday 0 = Sunday)
+//! ```
+//!
+//! and its return value selects what happens next:
+//!
+//! ```text
+//! ()               forward to the --target backend
+//! "host:port"      forward to this backend instead
+//! false            reject the connection
+//! ```
+//!
+//! For example:
+//!
+//! ```text
+//! fn route(conn) {
+//!     if conn.sni == "md.venue.example" { return "10.1.0.5:9443"; }
+//!     if conn.protocol == "http" && conn.hour >= 22 { return false; }
+//! }
+//! ```
+//!
+//! Scripts run under an operation limit so a runaway loop cannot stall
+//! connection setup; a failing script falls back to the default target.
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+
+use crate::sniff::Protocol;
+
+/// Operations a script may perform per connection
+const MAX_OPERATIONS: u64 = 100_000;
+const ROUTE_FN: &str = "route";
+
+/// What the proxy knows about a connection when it is routed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub client: SocketAddr,
+    pub sni: Option<String>,
+    pub protocol: Protocol,
+    /// Identity of the verified client certificate
+    pub identity: Option<String>,
+    pub time: SystemTime,
+}
+
+impl ConnectionInfo {
+    fn to_map(&self) -> Map {
+        let unix_time = self.time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let days = unix_time / 86_400;
+        let secs_of_day = unix_time % 86_400;
+
+        let mut map = Map::new();
+        map.insert("client_ip".into(), self.client.ip().to_string().into());
+        map.insert("client_port".into(), (self.client.port() as i64).into());
+        map.insert(
+            "sni".into(),
+            self.sni.clone().map(Dynamic::from).unwrap_or(Dynamic::UNIT),
+        );
+        map.insert("protocol".into(), self.protocol.to_string().into());
+        map.insert(
+            "client_identity".into(),
+            self.identity.clone().map(Dynamic::from).unwrap_or(Dynamic::UNIT),
+        );
+        map.insert("unix_time".into(), (unix_time as i64).into());
+        map.insert("hour".into(), ((secs_of_day / 3600) as i64).into());
+        map.insert("minute".into(), ((secs_of_day % 3600 / 60) as i64).into());
+        // 1970-01-01 was a Thursday
+        map.insert("weekday".into(), (((days + 4) % 7) as i64).into());
+        map
+    }
+}
+
+/// Routing decision returned by a script
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteDecision {
+    /// Use the configured target
+    Default,
+    /// Forward to this "host:port" instead
+    Backend(String),
+    /// Close the client connection
+    Reject,
+}
+
+/// A compiled routing script
+pub struct RoutingScript {
+    engine: Engine,
+    ast: AST,
+}
+
+impl RoutingScript {
+    /// Compile a script from source
+    pub fn compile(source: &str) -> io::Result<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+
+        let ast = engine
+            .compile(source)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        if !ast.iter_functions().any(|f| f.name == ROUTE_FN && f.params.len() == 1) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "script does not define route(conn)",
+            ));
+        }
+
+        Ok(Self { engine, ast })
+    }
+
+    /// Read and compile a script file
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let source = std::fs::read_to_string(path)?;
+        Self::compile(&source)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    /// Run `route(conn)` for a connection
+    pub fn decide(&self, conn: &ConnectionInfo) -> io::Result<RouteDecision> {
+        let mut scope = Scope::new();
+        let result: Dynamic = self
+            .engine
+            .call_fn(&mut scope, &self.ast, ROUTE_FN, (conn.to_map(),))
+            .map_err(|e| io::Error::other(e.to_string()))?;
+
+        if result.is_unit() {
+            return Ok(RouteDecision::Default);
+        }
+        if let Ok(allow) = result.as_bool() {
+            return Ok(match allow {
+                true => RouteDecision::Default,
+                false => RouteDecision::Reject,
+            });
+        }
+        let type_name = result.type_name();
+        match result.into_string() {
+            Ok(backend) => Ok(RouteDecision::Backend(backend)),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("route() returned {}, expected (), a string or false", type_name),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const TEST_SCRIPT: &str = r#"
+        fn route(conn) {
+            if conn.sni == "md.venue.example" { return "10.1.0.5:9443"; }
+            if conn.client_identity == "spiffe://venue.example/trader/7" { return "10.1.0.7:9443"; }
+            if conn.protocol == "http" && conn.hour >= 22 { return false; }
+            if conn.client_ip == "10.0.0.9" { return 42; }
+        }
+    "#;
+
+    fn conn(sni: Option<&str>, protocol: Protocol, unix_time: u64) -> ConnectionInfo {
+        ConnectionInfo {
+            client: "10.0.0.7:51234".parse().unwrap(),
+            sni: sni.map(str::to_string),
+            protocol,
+            identity: None,
+            time: UNIX_EPOCH + Duration::from_secs(unix_time),
+        }
+    }
+
+    #[test]
+    fn test_route_decisions() {
+        let script = RoutingScript::compile(TEST_SCRIPT).unwrap();
+        // 2024-01-01 (a Monday) 12:00 and 23:00 UTC
+        let noon = 1_704_110_400;
+        let late = noon + 11 * 3600;
+
+        assert_eq!(
+            script.decide(&conn(Some("md.venue.example"), Protocol::Tls, noon)).unwrap(),
+            RouteDecision::Backend("10.1.0.5:9443".to_string())
+        );
+        assert_eq!(script.decide(&conn(None, Protocol::Http, noon)).unwrap(), RouteDecision::Default);
+        assert_eq!(script.decide(&conn(None, Protocol::Http, late)).unwrap(), RouteDecision::Reject);
+
+        let mut trader = conn(None, Protocol::Tls, late);
+        trader.identity = Some("spiffe://venue.example/trader/7".to_string());
+        assert_eq!(script.decide(&trader).unwrap(), RouteDecision::Backend("10.1.0.7:9443".to_string()));
+
+        let mut bad = conn(None, Protocol::Unknown, noon);
+        bad.client = "10.0.0.9:4000".parse().unwrap();
+        assert!(script.decide(&bad).is_err());
+    }
+
+    #[test]
+    fn test_time_fields() {
+        let map = conn(None, Protocol::Unknown, 1_704_110_400 + 5 * 60).to_map();
+        assert_eq!(map["hour"].as_int(), Ok(12));
+        assert_eq!(map["minute"].as_int(), Ok(5));
+        assert_eq!(map["weekday"].as_int(), Ok(1));
+    }
+
+    #[test]
+    fn test_runaway_script_is_stopped() {
+        let script = RoutingScript::compile("fn route(conn) { loop {} }").unwrap();
+        assert!(script.decide(&conn(None, Protocol::Unknown, 0)).is_err());
+        assert!(RoutingScript::compile("fn other(x) {}").is_err());
+    }
+}
@@ -0,0 +1,976 @@
+//! Per-direction scrubbing pipeline for the wire-level datapaths
+//!
+//! The bridge and TUN datapaths hand every TCP segment to a `Scrubber`,
+//! which applies the configured policy and returns the rewritten packet
+//! (or `None` to forward the original untouched). Each datapath thread owns
+//! its scrubber, so per-flow state needs no locking.
+//!
+//! In dry-run mode the rewrite is still computed in full, checksums
+//! included, but only counted and logged; the datapath forwards the
+//! original. Per-flow state advances as if the rewrites had gone out, so
+//! decisions that depend on what the peer echoes back (spoofed timestamps)
+//! are approximate.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::{debug, warn};
+
+use crate::dscp::{Dscp, DscpRule};
+use crate::entropy::{EntropyConfig, EntropySource};
+use crate::metrics::{self, LabeledMetric, Metric};
+use crate::packet::{
+    apply_timestamp_action, clamp_mss, clear_ecn, layout_tcp_options, normalize_tcp_options, rescale_window,
+    sanitize_flags, set_dscp, set_ip_id, set_ttl, set_window, set_window_scale, FlowKey, IpVersion, TcpSegment,
+    TimestampAction, TCP_FLAG_ACK, TCP_FLAG_RST, TCP_FLAG_SYN,
+};
+use crate::personality::{Personality, Profile};
+use crate::route;
+use crate::spoof::TimestampSpoofer;
+use crate::tcp_analysis::{
+    extract_timestamp, flag_anomalies, parse_tcp_options, FlagAnomaly, OptionPolicy, TcpOptionType, TcpTimestamp,
+};
+
+/// Upper bound for the MSS option of SYN segments
+///
+/// Clamping keeps segments within what the network can carry and, since
+/// every host behind the scrubber then advertises the same value, removes
+/// the MSS as a fingerprinting vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MssClamp {
+    /// Advertise at most this many bytes
+    Fixed(u16),
+    /// Derive the bound from this MTU, less the IPv4/IPv6 and TCP headers
+    Mtu(u16),
+}
+
+impl MssClamp {
+    pub fn limit(self, version: IpVersion) -> u16 {
+        match (self, version) {
+            (MssClamp::Fixed(mss), _) => mss,
+            (MssClamp::Mtu(mtu), IpVersion::V4) => mtu.saturating_sub(40),
+            (MssClamp::Mtu(mtu), IpVersion::V6) => mtu.saturating_sub(60),
+        }
+    }
+}
+
+/// Largest window scale shift RFC 7323 allows
+pub const MAX_WINDOW_SCALE: u8 = 14;
+
+/// What to do with the window scale option of SYNs
+///
+/// The shift a host asks for depends on its OS and buffer tuning, so it
+/// fingerprints the host much like its timestamps do.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WindowScaleAction {
+    #[default]
+    Preserve,
+    /// Remove the option from SYNs, so windows are never scaled
+    Remove,
+    /// Advertise this shift instead, rescaling the window field of every
+    /// later segment the host sends on the connection
+    Fixed(u8),
+}
+
+impl fmt::Display for WindowScaleAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WindowScaleAction::Preserve => write!(f, "preserve"),
+            WindowScaleAction::Remove => write!(f, "remove"),
+            WindowScaleAction::Fixed(shift) => write!(f, "{}", shift),
+        }
+    }
+}
+
+impl FromStr for WindowScaleAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(WindowScaleAction::Preserve),
+            "remove" => Ok(WindowScaleAction::Remove),
+            _ => match s.parse::<u8>() {
+                Ok(shift) if shift <= MAX_WINDOW_SCALE => Ok(WindowScaleAction::Fixed(shift)),
+                _ => Err(format!(
+                    "invalid window scale '{}' (expected preserve, remove or a shift of 0-{})",
+                    s, MAX_WINDOW_SCALE
+                )),
+            },
+        }
+    }
+}
+
+/// What to do with TCP options of kinds the parser does not know
+///
+/// That includes the experimental kinds 253 and 254, except for Fast Open
+/// cookies sent as the latter. Unless they are preserved, such options are
+/// counted by kind in `tcpstrip_unknown_tcp_options_total`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownOptionAction {
+    /// Forward them untouched
+    #[default]
+    Preserve,
+    /// Remove them from every segment
+    Strip,
+    /// Forward them, logging a warning for each SYN that carries one
+    Alert,
+}
+
+impl fmt::Display for UnknownOptionAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnknownOptionAction::Preserve => write!(f, "preserve"),
+            UnknownOptionAction::Strip => write!(f, "strip"),
+            UnknownOptionAction::Alert => write!(f, "alert"),
+        }
+    }
+}
+
+impl FromStr for UnknownOptionAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(UnknownOptionAction::Preserve),
+            "strip" => Ok(UnknownOptionAction::Strip),
+            "alert" => Ok(UnknownOptionAction::Alert),
+            _ => Err(format!("unknown action '{}' (expected preserve, strip or alert)", s)),
+        }
+    }
+}
+
+/// What the return path puts in the TSecr of the peer's segments when our
+/// own TSvals are spoofed
+///
+/// The peer echoes the spoofed TSval, which the host never sent. Linux
+/// drops a SYN-ACK whose TSecr lies outside the time since its SYN went
+/// out and takes RTT samples from every later echo, so the echo is either
+/// translated back or cleared: a TSecr of 0 means "no echo" to every
+/// common stack, which then falls back to RTT samples taken without
+/// timestamps. The peer's TSval, which PAWS on the host checks, is never
+/// touched.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EchoPolicy {
+    /// Hand the host back the TSval it actually sent
+    #[default]
+    Translated,
+    /// Forward the echo as the peer sent it
+    Original,
+    /// Clear TSecr; SYN cookies that the host encodes in its TSval are lost
+    Zero,
+}
+
+impl EchoPolicy {
+    /// Check that the return path can keep connections using `timestamps`
+    /// alive with this policy
+    pub fn check(self, timestamps: TimestampAction) -> Result<(), String> {
+        match (self, timestamps) {
+            (EchoPolicy::Original, TimestampAction::Spoof(_) | TimestampAction::Randomize) => Err(format!(
+                "--return-echo {} hands the host echoes of spoofed TSvals it never sent, so connections stall; \
+                 use echo-translated or zero",
+                self
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for EchoPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EchoPolicy::Translated => write!(f, "echo-translated"),
+            EchoPolicy::Original => write!(f, "echo-original"),
+            EchoPolicy::Zero => write!(f, "zero"),
+        }
+    }
+}
+
+impl FromStr for EchoPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "echo-translated" => Ok(EchoPolicy::Translated),
+            "echo-original" => Ok(EchoPolicy::Original),
+            "zero" => Ok(EchoPolicy::Zero),
+            _ => Err(format!(
+                "unknown echo policy '{}' (expected echo-translated, echo-original or zero)",
+                s
+            )),
+        }
+    }
+}
+
+/// What the scrubber does to segments flowing in one direction
+///
+/// The default leaves every segment as it is.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubPolicy {
+    pub timestamps: TimestampAction,
+    /// TSecr of the peer's segments on the return path
+    pub echo: EchoPolicy,
+    /// Clamp the MSS of SYNs in both directions
+    pub mss_clamp: Option<MssClamp>,
+    pub window_scale: WindowScaleAction,
+    /// Remove SACK-permitted from SYNs and SACK blocks from every segment,
+    /// in both directions
+    pub strip_sack: bool,
+    /// Remove TCP Fast Open cookies and cookie requests in both directions
+    pub strip_fast_open: bool,
+    /// Remove MPTCP options in both directions, so connections stay
+    /// single-path TCP
+    pub strip_mptcp: bool,
+    /// Options of kinds the parser does not know, in both directions
+    pub unknown_options: UnknownOptionAction,
+    /// Give every segment the same option layout (see
+    /// `tcp_analysis::normalize_options`) so it does not reveal the
+    /// sender's OS
+    pub canonical_options: bool,
+    /// Make outbound SYNs look like this OS's; it also supplies the TTL,
+    /// MSS clamp and window scale where those are left unset
+    pub personality: Option<Personality>,
+    /// Rewrite the IPv4 TTL / IPv6 hop limit to this value
+    pub ttl: Option<u8>,
+    /// Replace the IPv4 Identification with a random per-flow sequence, so
+    /// it no longer counts the sender's packets
+    pub randomize_ip_id: bool,
+    /// DSCP to mark outgoing segments with, by destination (first match)
+    pub dscp: Vec<DscpRule>,
+    /// Clear ECN marks from IP headers and TCP flags in both directions,
+    /// so ECN is never negotiated
+    pub clear_ecn: bool,
+    /// Clear URG, the urgent pointer and the reserved header bits in both
+    /// directions
+    pub sanitize_flags: bool,
+    /// Compute rewrites but forward every segment unmodified
+    pub dry_run: bool,
+}
+
+impl ScrubPolicy {
+    /// Policy for traffic coming back from the network
+    ///
+    /// Stripping has to happen on both sides of the handshake so timestamps
+    /// are never negotiated; spoofing only concerns our own TSval, so the
+    /// peer's segments are left alone apart from translating its echoes
+    /// (see `Scrubber::pair`).
+    pub fn for_return_path(&self) -> Self {
+        let timestamps = match self.timestamps {
+            TimestampAction::Strip => TimestampAction::Strip,
+            _ => TimestampAction::Preserve,
+        };
+        // Removing the option from the peer's SYNs as well keeps scaling off
+        // for connections it opens; a fixed shift only applies to ours
+        let window_scale = match self.window_scale {
+            WindowScaleAction::Remove => WindowScaleAction::Remove,
+            _ => WindowScaleAction::Preserve,
+        };
+        Self {
+            timestamps,
+            echo: self.echo,
+            mss_clamp: self.mss_clamp,
+            window_scale,
+            strip_sack: self.strip_sack,
+            strip_fast_open: self.strip_fast_open,
+            strip_mptcp: self.strip_mptcp,
+            unknown_options: self.unknown_options,
+            // Only what leaves towards the network needs to look uniform
+            canonical_options: false,
+            personality: None,
+            ttl: None,
+            randomize_ip_id: false,
+            dscp: Vec::new(),
+            clear_ecn: self.clear_ecn,
+            sanitize_flags: self.sanitize_flags,
+            dry_run: self.dry_run,
+        }
+    }
+
+    /// Fill the settings left unset from the personality's profile
+    fn with_personality_defaults(mut self) -> Self {
+        if let Some(profile) = self.personality.map(Personality::profile) {
+            self.ttl = self.ttl.or(Some(profile.ttl));
+            self.mss_clamp = self.mss_clamp.or(Some(MssClamp::Mtu(profile.mtu)));
+            if self.window_scale == WindowScaleAction::Preserve {
+                self.window_scale = WindowScaleAction::Fixed(profile.window_scale);
+            }
+        }
+        self
+    }
+
+    /// The option rebuild this policy calls for
+    fn option_policy(&self) -> OptionPolicy {
+        let mut strip = Vec::new();
+        if self.timestamps == TimestampAction::Strip {
+            strip.push(TcpOptionType::Timestamp);
+        }
+        if self.strip_sack {
+            strip.extend([TcpOptionType::SackPermitted, TcpOptionType::Sack]);
+        }
+        if self.strip_fast_open {
+            strip.push(TcpOptionType::FastOpen);
+        }
+        if self.strip_mptcp {
+            strip.push(TcpOptionType::Mptcp);
+        }
+        OptionPolicy {
+            strip,
+            strip_unknown: self.unknown_options == UnknownOptionAction::Strip,
+            canonical: self.canonical_options,
+        }
+    }
+}
+
+/// Stateful application of a `ScrubPolicy`
+///
+/// Clones share their per-flow state, so a datapath with several workers
+/// can hand each of them a copy of the same pair.
+#[derive(Clone)]
+pub struct Scrubber {
+    policy: ScrubPolicy,
+    options: OptionPolicy,
+    /// Whether this scrubber handles traffic coming back from the network
+    return_path: bool,
+    spoofer: Option<Arc<Mutex<TimestampSpoofer>>>,
+    /// Connections whose advertised window scale was replaced
+    scaled: Option<Arc<Mutex<ScaledFlows>>>,
+    /// IP ID sequences of outgoing flows (--randomize-ip-id)
+    ip_ids: Option<Arc<Mutex<IpIdSequences>>>,
+    /// Rewrites computed but not applied in dry-run mode
+    dry_run_rewrites: Arc<Metric>,
+    /// Segments seen with each `FlagAnomaly`, in `FlagAnomaly::ALL` order
+    flag_anomalies: [Arc<Metric>; FlagAnomaly::ALL.len()],
+    unknown_options: Arc<LabeledMetric>,
+}
+
+impl Scrubber {
+    pub fn new(policy: ScrubPolicy, entropy: &EntropyConfig) -> io::Result<Self> {
+        policy
+            .echo
+            .check(policy.timestamps)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let policy = policy.with_personality_defaults();
+        let spoofer = match policy.timestamps {
+            TimestampAction::Randomize => Some(TimestampSpoofer::new(entropy.open()?)),
+            TimestampAction::Spoof(ts_val) => Some(TimestampSpoofer::fixed(ts_val)),
+            _ => None,
+        };
+        let scaled = match policy.window_scale {
+            WindowScaleAction::Fixed(_) => Some(Arc::new(Mutex::new(ScaledFlows::default()))),
+            _ => None,
+        };
+        let ip_ids = match policy.randomize_ip_id {
+            true => Some(Arc::new(Mutex::new(IpIdSequences::new(entropy.open()?)))),
+            false => None,
+        };
+        let dry_run_rewrites = metrics::registry()
+            .counter("tcpstrip_dry_run_rewrites_total", "Segments that would have been rewritten (--rewrite-dry-run)");
+        let anomalies = metrics::registry().labeled_counter(
+            "tcpstrip_tcp_flag_anomalies_total",
+            "Segments with unusual TCP flags or header bits",
+            "anomaly",
+        );
+        let flag_anomalies = FlagAnomaly::ALL.map(|anomaly| anomalies.with(&anomaly.to_string()));
+        let unknown_options = metrics::registry().labeled_counter(
+            "tcpstrip_unknown_tcp_options_total",
+            "TCP options of kinds the parser does not know (unless --unknown-options preserve)",
+            "kind",
+        );
+        Ok(Self {
+            options: policy.option_policy(),
+            policy,
+            return_path: false,
+            spoofer: spoofer.map(|s| Arc::new(Mutex::new(s))),
+            scaled,
+            ip_ids,
+            dry_run_rewrites,
+            flag_anomalies,
+            unknown_options,
+        })
+    }
+
+    /// Scrubbers for both directions of a datapath that sees return traffic
+    ///
+    /// The return-path scrubber applies `policy.for_return_path()` and, when
+    /// spoofing, rewrites the peer's TSecr as `policy.echo` says (by default
+    /// back to the values the host actually sent); with a fixed window scale
+    /// it notices peers that turn scaling down.
+    pub fn pair(policy: ScrubPolicy, entropy: &EntropyConfig) -> io::Result<(Self, Self)> {
+        let outbound = Self::new(policy, entropy)?;
+        let return_policy = outbound.policy.for_return_path();
+        let inbound = Self {
+            options: return_policy.option_policy(),
+            policy: return_policy,
+            return_path: true,
+            spoofer: outbound.spoofer.clone(),
+            scaled: outbound.scaled.clone(),
+            ip_ids: None,
+            dry_run_rewrites: outbound.dry_run_rewrites.clone(),
+            flag_anomalies: outbound.flag_anomalies.clone(),
+            unknown_options: outbound.unknown_options.clone(),
+        };
+        Ok((outbound, inbound))
+    }
+
+    /// Scrub one segment, returning the rewritten packet if anything changed
+    /// (never in dry-run mode)
+    pub fn scrub(&mut self, buf: &[u8], segment: &TcpSegment) -> Option<Vec<u8>> {
+        for anomaly in flag_anomalies(segment.flags(buf), segment.reserved_bits(buf), segment.urgent_pointer(buf)) {
+            self.flag_anomalies[anomaly as usize].inc();
+        }
+        if self.policy.unknown_options != UnknownOptionAction::Preserve {
+            self.report_unknown_options(buf, segment);
+        }
+
+        let rewritten = self.rewrite(buf, segment)?;
+        if !self.policy.dry_run {
+            return Some(rewritten);
+        }
+
+        self.dry_run_rewrites.inc();
+        let flow = segment.flow_key(buf);
+        debug!(
+            "Dry run: would rewrite {}:{} -> {}:{} (flags {:#04x}, {} -> {} bytes)",
+            flow.src,
+            flow.src_port,
+            flow.dst,
+            flow.dst_port,
+            segment.flags(buf),
+            segment.packet_end - segment.l3_offset,
+            rewritten.len() - segment.l3_offset,
+        );
+        None
+    }
+
+    /// Count the unknown options of a segment, warning about SYNs in alert
+    /// mode
+    fn report_unknown_options(&self, buf: &[u8], segment: &TcpSegment) {
+        for option in parse_tcp_options(segment.options(buf)) {
+            let TcpOptionType::Unknown(kind) = option.effective_kind() else {
+                continue;
+            };
+            self.unknown_options.with(&kind.to_string()).inc();
+            if self.policy.unknown_options == UnknownOptionAction::Alert && segment.is_syn(buf) {
+                let flow = segment.flow_key(buf);
+                warn!(
+                    "Unknown TCP option kind {} ({} bytes) in SYN {}:{} -> {}:{}",
+                    kind, option.length, flow.src, flow.src_port, flow.dst, flow.dst_port
+                );
+            }
+        }
+    }
+
+    fn rewrite(&mut self, buf: &[u8], segment: &TcpSegment) -> Option<Vec<u8>> {
+        // TTL, MSS and window edits keep the layout, so they are done on a copy
+        // before the timestamp option is dealt with
+        let edited = self.edit_in_place(buf, segment);
+        let buf = edited.as_deref().unwrap_or(buf);
+
+        // Stripping and reordering options changes the layout
+        let (normalized, segment) = match normalize_tcp_options(buf, segment, &self.options) {
+            Some((normalized, segment)) => (Some(normalized), segment),
+            None => (None, *segment),
+        };
+        let buf = normalized.as_deref().unwrap_or(buf);
+
+        // A personality dictates the whole layout of the SYN's options
+        let (laid_out, segment) = match self.syn_profile(buf, &segment) {
+            Some(profile) => match layout_tcp_options(buf, &segment, profile.layouts) {
+                Some((laid_out, segment)) => (Some(laid_out), segment),
+                None => (None, segment),
+            },
+            None => (None, segment),
+        };
+        let buf = laid_out.as_deref().unwrap_or(buf);
+
+        let rewritten = match self.timestamp_action(buf, &segment) {
+            Some(action) => apply_timestamp_action(buf, &segment, action),
+            None => None,
+        };
+        rewritten.or(laid_out).or(normalized).or(edited)
+    }
+
+    /// Edits that keep the segment's layout; None if nothing changed
+    fn edit_in_place(&mut self, buf: &[u8], segment: &TcpSegment) -> Option<Vec<u8>> {
+        let syn = segment.is_syn(buf);
+        let ttl = self.policy.ttl.filter(|&ttl| segment.ttl(buf) != ttl);
+        let ip_id = self.next_ip_id(buf, segment);
+        let dscp = self.dscp(buf, segment).filter(|&dscp| segment.dscp(buf) != dscp);
+        let ecn = self.policy.clear_ecn && segment.has_ecn(buf);
+        let sanitize = self.policy.sanitize_flags && segment.has_unusual_bits(buf);
+        let rescale = match syn {
+            true => None,
+            false => self.window_rescale(buf, segment),
+        };
+        let profile = self.syn_profile(buf, segment);
+        let syn_edits = syn
+            && (self.policy.mss_clamp.is_some()
+                || profile.is_some()
+                || self.policy.window_scale != WindowScaleAction::Preserve
+                || self.scaled.is_some());
+        if ttl.is_none() && ip_id.is_none() && dscp.is_none() && !ecn && !sanitize && rescale.is_none() && !syn_edits {
+            return None;
+        }
+
+        let mut copy = buf.to_vec();
+        let mut changed = false;
+        if let Some(ttl) = ttl {
+            set_ttl(&mut copy, segment, ttl);
+            changed = true;
+        }
+        if let Some(id) = ip_id {
+            set_ip_id(&mut copy, segment, id);
+            changed = true;
+        }
+        if let Some(dscp) = dscp {
+            set_dscp(&mut copy, segment, dscp);
+            changed = true;
+        }
+        if ecn {
+            changed |= clear_ecn(&mut copy, segment);
+        }
+        if sanitize {
+            changed |= sanitize_flags(&mut copy, segment);
+        }
+        if let Some((from, to)) = rescale {
+            changed |= rescale_window(&mut copy, segment, from, to);
+        }
+        if syn_edits {
+            if let Some(clamp) = self.policy.mss_clamp {
+                changed |= clamp_mss(&mut copy, segment, clamp.limit(segment.ip_version));
+            }
+            if let Some(profile) = profile {
+                let window = profile.window.value(segment.mss(&copy));
+                changed |= set_window(&mut copy, segment, window);
+            }
+            changed |= self.normalize_window_scale(&mut copy, segment);
+        }
+        changed.then_some(copy)
+    }
+
+    /// Apply the window scale policy to a SYN or SYN-ACK; true if changed
+    /// The personality's profile, for SYNs opening a connection
+    fn syn_profile(&self, buf: &[u8], segment: &TcpSegment) -> Option<Profile> {
+        let profile = self.policy.personality?.profile();
+        (segment.flags(buf) & (TCP_FLAG_SYN | TCP_FLAG_ACK) == TCP_FLAG_SYN).then_some(profile)
+    }
+
+    fn normalize_window_scale(&mut self, buf: &mut [u8], segment: &TcpSegment) -> bool {
+        let syn_ack = segment.flags(buf) & TCP_FLAG_ACK != 0;
+        match self.policy.window_scale {
+            WindowScaleAction::Preserve => {
+                // A SYN-ACK without the option turns scaling off for the
+                // connection, so the host's windows must be left alone
+                if let (Some(scaled), true) = (&self.scaled, syn_ack) {
+                    let options = parse_tcp_options(segment.options(buf));
+                    if !options.iter().any(|option| option.kind == TcpOptionType::WindowScale) {
+                        let flow = segment.flow_key(buf).reversed();
+                        scaled.lock().unwrap_or_else(|e| e.into_inner()).forget(&flow);
+                    }
+                }
+                false
+            }
+            // Only a SYN may drop the option: a SYN-ACK without it would
+            // leave its sender scaling windows the peer reads unscaled
+            WindowScaleAction::Remove => !syn_ack && set_window_scale(buf, segment, None).is_some(),
+            WindowScaleAction::Fixed(shift) => match set_window_scale(buf, segment, Some(shift)) {
+                Some(original) if original != shift => {
+                    if let Some(scaled) = &self.scaled {
+                        let flow = segment.flow_key(buf);
+                        scaled.lock().unwrap_or_else(|e| e.into_inner()).insert(flow, original, Instant::now());
+                    }
+                    true
+                }
+                _ => false,
+            },
+        }
+    }
+
+    /// Window scale the host uses on the segment's connection and the one
+    /// the peer was told about, if they differ
+    /// DSCP the segment's destination should be marked with, if any
+    fn dscp(&self, buf: &[u8], segment: &TcpSegment) -> Option<u8> {
+        if self.policy.dscp.is_empty() {
+            return None;
+        }
+        let flow = segment.flow_key(buf);
+        route::lookup(&self.policy.dscp, SocketAddr::new(flow.dst, flow.dst_port)).map(Dscp::value)
+    }
+
+    /// Next IP ID of the segment's flow; None for IPv6 or when not
+    /// randomizing
+    fn next_ip_id(&self, buf: &[u8], segment: &TcpSegment) -> Option<u16> {
+        let ip_ids = self.ip_ids.as_ref()?;
+        segment.ip_id(buf)?;
+        let flow = segment.flow_key(buf);
+        let mut ip_ids = ip_ids.lock().unwrap_or_else(|e| e.into_inner());
+        let id = ip_ids.next(flow, Instant::now());
+        if segment.flags(buf) & TCP_FLAG_RST != 0 {
+            ip_ids.forget(&flow);
+        }
+        Some(id)
+    }
+
+    fn window_rescale(&self, buf: &[u8], segment: &TcpSegment) -> Option<(u8, u8)> {
+        let WindowScaleAction::Fixed(shift) = self.policy.window_scale else {
+            return None;
+        };
+        let flow = segment.flow_key(buf);
+        let mut scaled = self.scaled.as_ref()?.lock().unwrap_or_else(|e| e.into_inner());
+        let original = scaled.shift(&flow, Instant::now())?;
+        if segment.flags(buf) & TCP_FLAG_RST != 0 {
+            scaled.forget(&flow);
+        }
+        Some((original, shift))
+    }
+
+    /// What to do with the segment's timestamp option; None to leave it
+    fn timestamp_action(&mut self, buf: &[u8], segment: &TcpSegment) -> Option<TimestampAction> {
+        let action = match &self.spoofer {
+            Some(spoofer) => {
+                let timestamp = timestamp_option(buf, segment)?;
+                let flow = segment.flow_key(buf);
+                let mut spoofer = spoofer.lock().unwrap_or_else(|e| e.into_inner());
+
+                if self.return_path {
+                    match self.policy.echo {
+                        EchoPolicy::Translated => {
+                            TimestampAction::Echo(spoofer.original_ts_val(&flow.reversed(), timestamp.ts_ecr)?)
+                        }
+                        EchoPolicy::Original => return None,
+                        EchoPolicy::Zero if timestamp.ts_ecr == 0 => return None,
+                        EchoPolicy::Zero => TimestampAction::Echo(0),
+                    }
+                } else {
+                    let ts_val = spoofer.ts_val(flow, timestamp.ts_val, Instant::now());
+                    // Keep the clock across FIN so retransmissions and the
+                    // final ACK stay monotonic; idle flows are evicted by
+                    // the spoofer
+                    if segment.flags(buf) & TCP_FLAG_RST != 0 {
+                        spoofer.forget(&flow);
+                    }
+                    TimestampAction::Spoof(ts_val)
+                }
+            }
+            // Stripping is part of the option policy
+            None => match self.policy.timestamps {
+                TimestampAction::Strip => return None,
+                action => action,
+            },
+        };
+        Some(action)
+    }
+}
+
+/// Flows not seen for this long are forgotten when the table is full
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+const MAX_FLOWS: usize = 65536;
+
+struct ScaledFlow {
+    /// The shift the host really scales its windows by
+    shift: u8,
+    last_seen: Instant,
+}
+
+/// The real window scale of connections advertised with a fixed one
+#[derive(Default)]
+struct ScaledFlows {
+    flows: HashMap<FlowKey, ScaledFlow>,
+}
+
+impl ScaledFlows {
+    fn insert(&mut self, flow: FlowKey, shift: u8, now: Instant) {
+        if self.flows.len() >= MAX_FLOWS {
+            self.flows.retain(|_, f| now.duration_since(f.last_seen) < FLOW_IDLE_TIMEOUT);
+        }
+        if self.flows.len() < MAX_FLOWS {
+            self.flows.insert(flow, ScaledFlow { shift, last_seen: now });
+        }
+    }
+
+    fn shift(&mut self, flow: &FlowKey, now: Instant) -> Option<u8> {
+        let scaled = self.flows.get_mut(flow)?;
+        scaled.last_seen = now;
+        Some(scaled.shift)
+    }
+
+    fn forget(&mut self, flow: &FlowKey) {
+        self.flows.remove(flow);
+    }
+}
+
+struct IpIdFlow {
+    next: u16,
+    last_seen: Instant,
+}
+
+/// IP ID sequences of outgoing flows, each starting at a random value
+///
+/// Within a flow the ID still increases by one per segment, as receivers
+/// and middleboxes expect, but it says nothing about how many packets the
+/// host sent on other flows.
+struct IpIdSequences {
+    entropy: Box<dyn EntropySource>,
+    flows: HashMap<FlowKey, IpIdFlow>,
+}
+
+impl IpIdSequences {
+    fn new(entropy: Box<dyn EntropySource>) -> Self {
+        Self {
+            entropy,
+            flows: HashMap::new(),
+        }
+    }
+
+    fn next(&mut self, flow: FlowKey, now: Instant) -> u16 {
+        if let Some(sequence) = self.flows.get_mut(&flow) {
+            let id = sequence.next;
+            sequence.next = id.wrapping_add(1);
+            sequence.last_seen = now;
+            return id;
+        }
+
+        let id = self.entropy.next_u32() as u16;
+        if self.flows.len() >= MAX_FLOWS {
+            self.flows.retain(|_, f| now.duration_since(f.last_seen) < FLOW_IDLE_TIMEOUT);
+        }
+        if self.flows.len() < MAX_FLOWS {
+            self.flows.insert(
+                flow,
+                IpIdFlow {
+                    next: id.wrapping_add(1),
+                    last_seen: now,
+                },
+            );
+        }
+        id
+    }
+
+    fn forget(&mut self, flow: &FlowKey) {
+        self.flows.remove(flow);
+    }
+}
+
+fn timestamp_option(buf: &[u8], segment: &TcpSegment) -> Option<TcpTimestamp> {
+    parse_tcp_options(segment.options(buf))
+        .iter()
+        .find(|option| option.kind == TcpOptionType::Timestamp)
+        .and_then(extract_timestamp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{parse_ip_packet, update_checksums, TCP_FLAG_SYN};
+
+    /// IPv4 + TCP segment carrying only a timestamp option
+    fn segment(src: u8, dst: u8, ts_val: u32, ts_ecr: u32) -> Vec<u8> {
+        let mut packet = vec![
+            0x45, 0x00, 0x00, 0x34, 0x12, 0x34, 0x40, 0x00, 0x40, 0x06, 0x00, 0x00, // IPv4
+            10, 0, 0, src, 10, 0, 0, dst,
+            0x23, 0x28, 0x23, 0x28, 0, 0, 0, 1, 0, 0, 0, 0, // TCP ports, seq, ack
+            0x80, 0x12, 0xfa, 0xf0, 0, 0, 0, 0, // doff=8, SYN+ACK, window
+            1, 1, 8, 10, // NOP, NOP, timestamp
+        ];
+        packet.extend_from_slice(&ts_val.to_be_bytes());
+        packet.extend_from_slice(&ts_ecr.to_be_bytes());
+        let segment = parse_ip_packet(&packet).unwrap();
+        update_checksums(&mut packet, &segment);
+        packet
+    }
+
+    fn timestamps(packet: &[u8]) -> TcpTimestamp {
+        timestamp_option(packet, &parse_ip_packet(packet).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_return_path_translates_echoes() {
+        let policy = ScrubPolicy {
+            timestamps: TimestampAction::Spoof(777),
+            ..ScrubPolicy::default()
+        };
+        let (mut outbound, mut inbound) = Scrubber::pair(policy, &EntropyConfig::Seeded(1)).unwrap();
+
+        let syn = segment(1, 2, 1000, 0);
+        let spoofed = outbound.scrub(&syn, &parse_ip_packet(&syn).unwrap()).unwrap();
+        assert_eq!(timestamps(&spoofed).ts_val, 777);
+
+        let syn_ack = segment(2, 1, 5555, 777);
+        let echoed = inbound.scrub(&syn_ack, &parse_ip_packet(&syn_ack).unwrap()).unwrap();
+        let ts = timestamps(&echoed);
+        assert_eq!((ts.ts_val, ts.ts_ecr), (5555, 1000));
+
+        // Unrelated flows pass untouched
+        let other = segment(3, 1, 5555, 777);
+        assert!(inbound.scrub(&other, &parse_ip_packet(&other).unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_return_echo_policies() {
+        let policy = |timestamps, echo| ScrubPolicy {
+            timestamps,
+            echo,
+            ..ScrubPolicy::default()
+        };
+        let seeded = EntropyConfig::Seeded(1);
+        assert_eq!("zero".parse(), Ok(EchoPolicy::Zero));
+        assert!("echo".parse::<EchoPolicy>().is_err());
+        let stalls = policy(TimestampAction::Randomize, EchoPolicy::Original);
+        assert_eq!(Scrubber::pair(stalls, &seeded).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        assert!(EchoPolicy::Original.check(TimestampAction::Strip).is_ok());
+
+        let zero = policy(TimestampAction::Spoof(777), EchoPolicy::Zero);
+        let (mut outbound, mut inbound) = Scrubber::pair(zero, &seeded).unwrap();
+        let syn = segment(1, 2, 1000, 0);
+        outbound.scrub(&syn, &parse_ip_packet(&syn).unwrap()).unwrap();
+        let syn_ack = segment(2, 1, 5555, 777);
+        let cleared = inbound.scrub(&syn_ack, &parse_ip_packet(&syn_ack).unwrap()).unwrap();
+        let ts = timestamps(&cleared);
+        assert_eq!((ts.ts_val, ts.ts_ecr), (5555, 0));
+        let peer_syn = segment(2, 1, 5555, 0);
+        assert!(inbound.scrub(&peer_syn, &parse_ip_packet(&peer_syn).unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_syn_mss_clamped_while_stripping() {
+        let policy = ScrubPolicy {
+            timestamps: TimestampAction::Strip,
+            mss_clamp: Some(MssClamp::Mtu(1440)),
+            ..ScrubPolicy::default()
+        };
+        let mut scrubber = Scrubber::new(policy, &EntropyConfig::Seeded(1)).unwrap();
+
+        // MSS 1460 ahead of the timestamp option
+        let mut syn = segment(1, 2, 1000, 0);
+        syn[3] += 4;
+        syn[32] += 0x10;
+        syn.splice(40..40, [2, 4, 0x05, 0xb4]);
+        let parsed = parse_ip_packet(&syn).unwrap();
+        update_checksums(&mut syn, &parsed);
+
+        let scrubbed = scrubber.scrub(&syn, &parsed).unwrap();
+        let scrubbed_segment = parse_ip_packet(&scrubbed).unwrap();
+        let options = parse_tcp_options(scrubbed_segment.options(&scrubbed));
+        let mss = options.iter().find(|o| o.kind == TcpOptionType::MaximumSegmentSize).unwrap();
+        assert_eq!(mss.data, 1400u16.to_be_bytes());
+        assert!(options.iter().all(|o| o.kind != TcpOptionType::Timestamp));
+    }
+
+    #[test]
+    fn test_fixed_window_scale_rescales_windows() {
+        let policy = ScrubPolicy {
+            window_scale: WindowScaleAction::Fixed(7),
+            ..ScrubPolicy::default()
+        };
+        let (mut outbound, mut inbound) = Scrubber::pair(policy, &EntropyConfig::Seeded(1)).unwrap();
+
+        // SYN from port 1 asking for scale 10, then window 100 (100 KiB)
+        let with_wscale = |mut packet: Vec<u8>| {
+            packet[3] += 4;
+            packet[32] += 0x10;
+            packet.splice(40..40, [1, 3, 3, 10]);
+            let parsed = parse_ip_packet(&packet).unwrap();
+            update_checksums(&mut packet, &parsed);
+            packet
+        };
+        let window = |packet: &[u8]| u16::from_be_bytes([packet[34], packet[35]]);
+        let ack = |src, dst, window: u16| {
+            let mut packet = segment(src, dst, 1, 1);
+            packet[33] = TCP_FLAG_ACK;
+            packet[34..36].copy_from_slice(&window.to_be_bytes());
+            let parsed = parse_ip_packet(&packet).unwrap();
+            update_checksums(&mut packet, &parsed);
+            packet
+        };
+
+        let mut syn = with_wscale(segment(1, 2, 1000, 0));
+        syn[33] = TCP_FLAG_SYN;
+        let scrubbed = outbound.scrub(&syn, &parse_ip_packet(&syn).unwrap()).unwrap();
+        assert_eq!(scrubbed[40..44], [1, 3, 3, 7]);
+
+        let data = ack(1, 2, 100);
+        let scrubbed = outbound.scrub(&data, &parse_ip_packet(&data).unwrap()).unwrap();
+        assert_eq!(window(&scrubbed), 800);
+
+        // The peer answers without the option: scaling is off, nothing to do
+        let syn_ack = segment(2, 1, 5555, 1000);
+        assert!(inbound.scrub(&syn_ack, &parse_ip_packet(&syn_ack).unwrap()).is_none());
+        assert!(outbound.scrub(&data, &parse_ip_packet(&data).unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_personality_rewrites_syn() {
+        let policy = ScrubPolicy {
+            timestamps: TimestampAction::Strip,
+            personality: Some(Personality::Linux515),
+            ..ScrubPolicy::default()
+        };
+        let mut scrubber = Scrubber::new(policy, &EntropyConfig::Seeded(1)).unwrap();
+
+        // A Windows SYN: TTL 128, window 8192, mss, nop, ws 8, nop, nop, sackOK
+        let mut syn = segment(1, 2, 0, 0);
+        syn[8] = 128;
+        syn[33] = TCP_FLAG_SYN;
+        syn[34..36].copy_from_slice(&8192u16.to_be_bytes());
+        syn[40..52].copy_from_slice(&[2, 4, 0x05, 0xb4, 1, 3, 3, 8, 1, 1, 4, 2]);
+        let parsed = parse_ip_packet(&syn).unwrap();
+        update_checksums(&mut syn, &parsed);
+
+        let scrubbed = scrubber.scrub(&syn, &parsed).unwrap();
+        let scrubbed_segment = parse_ip_packet(&scrubbed).unwrap();
+        assert_eq!(scrubbed_segment.ttl(&scrubbed), 64);
+        assert_eq!(scrubbed_segment.window(&scrubbed), 64240);
+        // Linux's layout without timestamps
+        assert_eq!(scrubbed_segment.options(&scrubbed), [2, 4, 0x05, 0xb4, 1, 1, 4, 2, 1, 3, 3, 7]);
+
+        // Later segments only take the TTL
+        let mut ack = segment(1, 2, 0, 0);
+        ack[8] = 128;
+        ack[33] = TCP_FLAG_ACK;
+        let parsed = parse_ip_packet(&ack).unwrap();
+        update_checksums(&mut ack, &parsed);
+        let scrubbed = scrubber.scrub(&ack, &parsed).unwrap();
+        let scrubbed_segment = parse_ip_packet(&scrubbed).unwrap();
+        assert_eq!(scrubbed_segment.ttl(&scrubbed), 64);
+    }
+
+    #[test]
+    fn test_dry_run_forwards_original() {
+        let policy = ScrubPolicy {
+            timestamps: TimestampAction::Strip,
+            dry_run: true,
+            ..ScrubPolicy::default()
+        };
+        let (mut outbound, _) = Scrubber::pair(policy, &EntropyConfig::Seeded(1)).unwrap();
+        let before = outbound.dry_run_rewrites.get();
+
+        let syn = segment(1, 2, 1000, 0);
+        assert!(outbound.scrub(&syn, &parse_ip_packet(&syn).unwrap()).is_none());
+        assert!(outbound.dry_run_rewrites.get() > before);
+    }
+
+    #[test]
+    fn test_ip_id_randomized_per_flow() {
+        let policy = ScrubPolicy {
+            randomize_ip_id: true,
+            ..ScrubPolicy::default()
+        };
+        let (mut outbound, mut inbound) = Scrubber::pair(policy, &EntropyConfig::Seeded(1)).unwrap();
+        let mut ip_id = |src: u8| {
+            let packet = segment(src, 2, 1000, 0);
+            let rewritten = outbound.scrub(&packet, &parse_ip_packet(&packet).unwrap()).unwrap();
+            parse_ip_packet(&rewritten).unwrap().ip_id(&rewritten).unwrap()
+        };
+
+        let first = ip_id(1);
+        assert_eq!(ip_id(1), first.wrapping_add(1));
+        assert_ne!(ip_id(3), first.wrapping_add(2));
+
+        let reply = segment(2, 1, 2000, 1000);
+        assert!(inbound.scrub(&reply, &parse_ip_packet(&reply).unwrap()).is_none());
+    }
+}
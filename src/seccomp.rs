@@ -0,0 +1,434 @@
+//! seccomp sandbox for the data plane (`--seccomp`, Linux on x86_64 and
+//! aarch64 only)
+//!
+//! Once the listeners, raw sockets and BPF programs are set up (and the
+//! process has switched to `--user`), forwarding needs only a small set of
+//! system calls: moving bytes between sockets, polling, timers, memory,
+//! threads, and the odd file for logs, DNS or the history database. `--seccomp`
+//! installs a seccomp-bpf filter on every thread that allows those and
+//! nothing else, so a compromised proxy in front of order flow cannot
+//! start programs, trace or load anything into the kernel.
+//!
+//! A few allowed calls are checked further:
+//!
+//! - `socket` only for inet, unix (systemd notifications, nscd) and netlink
+//!   (sock_diag, getaddrinfo) sockets, not packet capture ones
+//! - `clone` only for threads, and `clone3` (whose flags a filter cannot
+//!   see) fails with ENOSYS so libc falls back to `clone`
+//! - `bpf` only for map lookups and updates, as `--sockmap` makes
+//! - `prctl` only for thread names, `tgkill` only within the process
+//!
+//! Such calls fail with EPERM; any other call kills the process. With
+//! `--seccomp=log` nothing is refused and every call the filter would have
+//! refused is logged by the kernel instead (`dmesg`, or the audit log, as
+//! type 1326 records with the `syscall` number), to try a configuration out
+//! before enforcing it.
+//!
+//! `--manage-firewall` runs `nft` and `ip` on shutdown and cannot be
+//! combined with it.
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use crate::capture::{insn, JEQ_K, JSET_K, LD_W_ABS, RET_K};
+
+/// What to do with a system call the filter does not allow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// Kill the process (EPERM for disallowed arguments)
+    #[default]
+    Enforce,
+    /// Allow it, but have the kernel log it
+    Log,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mode::Enforce => write!(f, "enforce"),
+            Mode::Log => write!(f, "log"),
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "enforce" => Ok(Mode::Enforce),
+            "log" => Ok(Mode::Log),
+            _ => Err(format!("unknown seccomp mode '{}' (expected enforce or log)", s)),
+        }
+    }
+}
+
+// struct seccomp_data (linux/seccomp.h); arguments are read as their low
+// 32 bits, which is all any checked one uses
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+const fn arg_offset(index: u32) -> u32 {
+    16 + 8 * index
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Set in the numbers of x32 system calls, which share the x86_64 entry
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+// bpf(2) commands
+const BPF_MAP_LOOKUP_ELEM: u32 = 1;
+const BPF_MAP_UPDATE_ELEM: u32 = 2;
+const BPF_MAP_DELETE_ELEM: u32 = 3;
+const BPF_MAP_GET_NEXT_KEY: u32 = 4;
+
+/// System calls allowed with any arguments
+const ALLOWED: &[libc::c_long] = &[
+    // Files: logs, pidfile, DNS configuration, /proc, the history database
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_preadv,
+    libc::SYS_pwritev,
+    libc::SYS_openat,
+    libc::SYS_close,
+    libc::SYS_lseek,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_getdents64,
+    libc::SYS_readlinkat,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_flock,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_ftruncate,
+    libc::SYS_fallocate,
+    // SQLite hands journals to the database's owner when run as root
+    libc::SYS_fchown,
+    libc::SYS_fchmod,
+    libc::SYS_fadvise64,
+    libc::SYS_renameat2,
+    libc::SYS_unlinkat,
+    libc::SYS_getcwd,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    // Sockets
+    libc::SYS_socketpair,
+    libc::SYS_connect,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_shutdown,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg,
+    libc::SYS_sendfile,
+    libc::SYS_splice,
+    libc::SYS_tee,
+    // Polling and waiting
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_epoll_pwait2,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    libc::SYS_eventfd2,
+    libc::SYS_futex,
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_gettimeofday,
+    libc::SYS_setitimer,
+    libc::SYS_getitimer,
+    // Threads and memory
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_sched_setaffinity,
+    libc::SYS_getcpu,
+    libc::SYS_membarrier,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_restart_syscall,
+    // Process information
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_getppid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_getresuid,
+    libc::SYS_getresgid,
+    libc::SYS_getgroups,
+    libc::SYS_uname,
+    libc::SYS_prlimit64,
+    libc::SYS_getrusage,
+    libc::SYS_sysinfo,
+    libc::SYS_getrandom,
+];
+
+/// The older forms x86_64 still has and libc still uses
+#[cfg(target_arch = "x86_64")]
+const ALLOWED_LEGACY: &[libc::c_long] = &[
+    libc::SYS_open,
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_access,
+    libc::SYS_readlink,
+    libc::SYS_rename,
+    libc::SYS_renameat,
+    libc::SYS_unlink,
+    libc::SYS_dup2,
+    libc::SYS_pipe,
+    libc::SYS_poll,
+    libc::SYS_select,
+    libc::SYS_epoll_wait,
+    libc::SYS_time,
+];
+#[cfg(target_arch = "aarch64")]
+const ALLOWED_LEGACY: &[libc::c_long] = &[];
+
+/// The filter for this process, refusing what it does not allow as `mode`
+/// says
+fn filter(mode: Mode, pid: u32) -> Vec<libc::sock_filter> {
+    let (denied, refused) = match mode {
+        Mode::Enforce => (libc::SECCOMP_RET_KILL_PROCESS, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32),
+        Mode::Log => (libc::SECCOMP_RET_LOG, libc::SECCOMP_RET_LOG),
+    };
+    let allow = insn(RET_K, 0, 0, libc::SECCOMP_RET_ALLOW);
+
+    // Numbers mean other calls on other architectures
+    let mut program = vec![
+        insn(LD_W_ABS, 0, 0, ARCH_OFFSET),
+        insn(JEQ_K, 1, 0, AUDIT_ARCH),
+        insn(RET_K, 0, 0, libc::SECCOMP_RET_KILL_PROCESS),
+        insn(LD_W_ABS, 0, 0, NR_OFFSET),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    program.extend([insn(JSET_K, 0, 1, X32_SYSCALL_BIT), insn(RET_K, 0, 0, libc::SECCOMP_RET_KILL_PROCESS)]);
+
+    for &nr in ALLOWED.iter().chain(ALLOWED_LEGACY) {
+        program.extend([insn(JEQ_K, 0, 1, nr as u32), allow]);
+    }
+
+    // Calls allowed for some values of one argument; the accumulator holds
+    // the call's number again past each block
+    let one_of: [(libc::c_long, u32, &[u32]); 4] = [
+        (
+            libc::SYS_socket,
+            0,
+            &[libc::AF_UNIX as u32, libc::AF_INET as u32, libc::AF_INET6 as u32, libc::AF_NETLINK as u32],
+        ),
+        (
+            libc::SYS_bpf,
+            0,
+            &[BPF_MAP_LOOKUP_ELEM, BPF_MAP_UPDATE_ELEM, BPF_MAP_DELETE_ELEM, BPF_MAP_GET_NEXT_KEY],
+        ),
+        (libc::SYS_prctl, 0, &[libc::PR_SET_NAME as u32, libc::PR_GET_NAME as u32]),
+        (libc::SYS_tgkill, 0, &[pid]),
+    ];
+    for (nr, arg, values) in one_of {
+        let n = values.len() as u8;
+        program.extend([insn(JEQ_K, 0, n + 3, nr as u32), insn(LD_W_ABS, 0, 0, arg_offset(arg))]);
+        for (i, &value) in values.iter().enumerate() {
+            program.push(insn(JEQ_K, n - i as u8, 0, value));
+        }
+        program.extend([insn(RET_K, 0, 0, refused), allow]);
+    }
+    program.extend([
+        insn(JEQ_K, 0, 4, libc::SYS_clone as u32),
+        insn(LD_W_ABS, 0, 0, arg_offset(0)),
+        insn(JSET_K, 1, 0, libc::CLONE_THREAD as u32),
+        insn(RET_K, 0, 0, refused),
+        allow,
+        insn(JEQ_K, 0, 1, libc::SYS_clone3 as u32),
+        insn(RET_K, 0, 0, libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32),
+        insn(RET_K, 0, 0, denied),
+    ]);
+    program
+}
+
+/// Restrict every thread of the process, and those it starts later, to
+/// the system calls forwarding needs
+///
+/// Irreversible. Also sets no_new_privs, so nothing the process might still
+/// execute could gain privileges either.
+pub fn install(mode: Mode) -> io::Result<()> {
+    let program = filter(mode, std::process::id());
+    let prog = libc::sock_fprog {
+        len: program.len() as libc::c_ushort,
+        filter: program.as_ptr() as *mut libc::sock_filter,
+    };
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let rc = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &prog as *const libc::sock_fprog,
+        )
+    };
+    match rc {
+        0 => Ok(()),
+        -1 => Err(io::Error::last_os_error()),
+        tid => Err(io::Error::other(format!("thread {} could not take the filter", tid))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::JA;
+
+    /// What the filter returns for a call, run by a minimal classic BPF
+    /// interpreter rather than the kernel, which would keep it for the
+    /// rest of the tests
+    fn run(program: &[libc::sock_filter], arch: u32, nr: libc::c_long, args: [u32; 6]) -> u32 {
+        let word = |offset: u32| match offset {
+            NR_OFFSET => nr as u32,
+            ARCH_OFFSET => arch,
+            offset => args[((offset - 16) / 8) as usize],
+        };
+        let (mut pc, mut acc) = (0, 0);
+        loop {
+            let insn = program[pc];
+            pc += 1;
+            match insn.code {
+                LD_W_ABS => acc = word(insn.k),
+                JEQ_K => pc += if acc == insn.k { insn.jt } else { insn.jf } as usize,
+                JSET_K => pc += if acc & insn.k != 0 { insn.jt } else { insn.jf } as usize,
+                JA => pc += insn.k as usize,
+                RET_K => return insn.k,
+                code => panic!("unexpected opcode {:#x}", code),
+            }
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let program = filter(Mode::Enforce, 42);
+        let call = |nr, arg0: u32| run(&program, AUDIT_ARCH, nr, [arg0, 0, 0, 0, 0, 0]);
+        let eperm = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+        assert_eq!(call(libc::SYS_recvfrom, 0), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(call(libc::SYS_epoll_pwait, 0), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(call(libc::SYS_fchown, 0), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(call(libc::SYS_execve, 0), libc::SECCOMP_RET_KILL_PROCESS);
+        assert_eq!(call(libc::SYS_ptrace, 0), libc::SECCOMP_RET_KILL_PROCESS);
+        assert_eq!(call(libc::SYS_setuid, 0), libc::SECCOMP_RET_KILL_PROCESS);
+
+        assert_eq!(call(libc::SYS_socket, libc::AF_INET6 as u32), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(call(libc::SYS_socket, libc::AF_NETLINK as u32), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(call(libc::SYS_socket, libc::AF_PACKET as u32), eperm);
+        assert_eq!(call(libc::SYS_bpf, BPF_MAP_UPDATE_ELEM), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(call(libc::SYS_bpf, 5), eperm); // BPF_PROG_LOAD
+        assert_eq!(call(libc::SYS_prctl, libc::PR_SET_NAME as u32), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(call(libc::SYS_prctl, libc::PR_SET_DUMPABLE as u32), eperm);
+        assert_eq!(call(libc::SYS_tgkill, 42), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(call(libc::SYS_tgkill, 1), eperm);
+
+        let thread = (libc::CLONE_VM | libc::CLONE_THREAD | libc::CLONE_SIGHAND) as u32;
+        assert_eq!(call(libc::SYS_clone, thread), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(call(libc::SYS_clone, libc::SIGCHLD as u32), eperm);
+        assert_eq!(call(libc::SYS_clone3, 0), libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32);
+
+        // Another architecture's numbers are never trusted
+        assert_eq!(run(&program, 0x4000_0003, libc::SYS_read, [0; 6]), libc::SECCOMP_RET_KILL_PROCESS);
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(call(libc::SYS_read | X32_SYSCALL_BIT as libc::c_long, 0), libc::SECCOMP_RET_KILL_PROCESS);
+
+        let program = filter(Mode::Log, 42);
+        let call = |nr, arg0: u32| run(&program, AUDIT_ARCH, nr, [arg0, 0, 0, 0, 0, 0]);
+        assert_eq!(call(libc::SYS_read, 0), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(call(libc::SYS_execve, 0), libc::SECCOMP_RET_LOG);
+        assert_eq!(call(libc::SYS_socket, libc::AF_PACKET as u32), libc::SECCOMP_RET_LOG);
+    }
+
+    /// Set in the copy of the test binary that runs under the filter
+    #[cfg(feature = "history")]
+    const UNDER_FILTER: &str = "TCPSTRIP_TEST_UNDER_SECCOMP";
+
+    /// The history writer under the enforcing filter; the filter stays for
+    /// the rest of a process, so this runs in a copy of the test binary
+    #[cfg(feature = "history")]
+    #[test]
+    fn test_history_under_filter() {
+        use crate::connections::Fingerprint;
+        use crate::history::{History, Opened, Query};
+        use std::time::{Duration, SystemTime};
+
+        let Some(path) = std::env::var_os(UNDER_FILTER) else {
+            let path = std::env::temp_dir().join(format!("tcpstrip-seccomp-history-{}.db", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "seccomp::tests::test_history_under_filter", "--test-threads=1"])
+                .env(UNDER_FILTER, &path)
+                .stdout(std::process::Stdio::null())
+                .status()
+                .unwrap();
+            let _ = std::fs::remove_file(&path);
+            assert!(status.success(), "history writer under the filter: {}", status);
+            return;
+        };
+
+        let history = History::open(std::path::Path::new(&path)).unwrap();
+        install(Mode::Enforce).unwrap();
+        let (client, route) = ("10.0.0.7:52706".parse().unwrap(), "10.1.0.5:9000".parse().unwrap());
+        let accepted_at = SystemTime::now();
+        history
+            .record(Opened {
+                conn_id: 0,
+                fingerprint: Fingerprint::new(client, route, accepted_at),
+                accepted_at,
+                client,
+                listener: "10.0.0.1:8080".parse().unwrap(),
+                source: "10.0.0.1:51804".parse().unwrap(),
+                route,
+            })
+            .finish(1, 2);
+        let query = Query::parse("").unwrap();
+        for _ in 0..200 {
+            if history.search(&query).unwrap().contains("1 bytes up") {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        panic!("the history row was never completed");
+    }
+}
@@ -0,0 +1,120 @@
+//! TLS passthrough routing by server name
+//!
+//! With `--sni-route NAME=HOST:PORT` one listener fronts several TLS
+//! gateways: the proxy peeks at the ClientHello (see `sniff`), reads the
+//! server name the client asked for and connects to the target mapped to
+//! it. TLS is not terminated; the ClientHello and everything after it are
+//! forwarded untouched. NAME is a server name or `*.SUFFIX` for every name
+//! under SUFFIX; exact names win over wildcards and longer suffixes over
+//! shorter ones. Connections whose name matches nothing, or that send no
+//! ClientHello, go to `--target` if there is one.
+//!
+//! Connections are counted per matched NAME, or `unmatched`, in
+//! `tcpstrip_sni_routed_total{sni}`.
+
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use crate::metrics;
+
+/// Label value of connections no route matched
+pub const UNMATCHED: &str = "unmatched";
+
+/// One `--sni-route NAME=HOST:PORT`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SniRoute {
+    /// Lowercase server name, or `*.suffix`
+    pub name: String,
+    /// HOST:PORT, resolved at startup
+    pub target: String,
+}
+
+impl FromStr for SniRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((name, target)) = s.split_once('=') else {
+            return Err(format!("invalid SNI route '{}' (expected NAME=HOST:PORT)", s));
+        };
+        let host = name.strip_prefix("*.").unwrap_or(name);
+        if host.is_empty() || host.contains('*') || target.is_empty() {
+            return Err(format!("invalid SNI route '{}' (expected NAME or *.SUFFIX, then =HOST:PORT)", s));
+        }
+        Ok(SniRoute {
+            name: name.to_ascii_lowercase(),
+            target: target.to_string(),
+        })
+    }
+}
+
+/// The resolved `--sni-route` map
+#[derive(Debug)]
+pub struct SniRoutes {
+    /// Route name and its target's address, with the fallback address of
+    /// the other family; exact names first, then wildcards by descending
+    /// suffix length
+    routes: Vec<(String, SocketAddr, Option<SocketAddr>)>,
+}
+
+impl SniRoutes {
+    /// `routes` with the addresses their targets resolved to
+    pub fn new(routes: impl IntoIterator<Item = (SniRoute, (SocketAddr, Option<SocketAddr>))>) -> Self {
+        let mut routes: Vec<_> = routes.into_iter().map(|(route, (addr, fallback))| (route.name, addr, fallback)).collect();
+        // Stable, so among equals the first given wins
+        routes.sort_by_key(|(name, _, _)| match name.strip_prefix("*.") {
+            Some(suffix) => usize::MAX - suffix.len(),
+            None => 0,
+        });
+        SniRoutes { routes }
+    }
+
+    /// The route name, address and fallback address for server name `sni`
+    pub fn lookup(&self, sni: &str) -> Option<(&str, SocketAddr, Option<SocketAddr>)> {
+        let sni = sni.trim_end_matches('.');
+        self.routes
+            .iter()
+            .find(|(name, _, _)| match name.strip_prefix("*.") {
+                Some(suffix) => sni.len() > suffix.len() + 1 && sni.ends_with(suffix) && sni[..sni.len() - suffix.len()].ends_with('.'),
+                None => name == sni,
+            })
+            .map(|(name, addr, fallback)| (name.as_str(), *addr, *fallback))
+    }
+
+    /// Every address a route leads to, fallbacks included
+    pub fn addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.routes.iter().flat_map(|&(_, addr, fallback)| [Some(addr), fallback]).flatten()
+    }
+
+    /// Count a connection routed by `name`, or by none
+    pub fn count(&self, name: Option<&str>) {
+        metrics::registry()
+            .labeled_counter("tcpstrip_sni_routed_total", "Connections by the SNI route they matched", "sni")
+            .with(name.unwrap_or(UNMATCHED))
+            .inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let route = |s: &str, addr: &str| (s.parse::<SniRoute>().unwrap(), (addr.parse().unwrap(), None));
+        let routes = SniRoutes::new([
+            route("*.example.com=gw:1", "192.0.2.1:443"),
+            route("MD.Venue.example.com=md:1", "192.0.2.2:443"),
+            route("*.venue.example.com=venue:1", "192.0.2.3:443"),
+        ]);
+        let lookup = |sni| routes.lookup(sni).map(|(name, addr, _)| (name.to_string(), addr.to_string()));
+        assert_eq!(lookup("md.venue.example.com"), Some(("md.venue.example.com".into(), "192.0.2.2:443".into())));
+        assert_eq!(lookup("oe.venue.example.com"), Some(("*.venue.example.com".into(), "192.0.2.3:443".into())));
+        assert_eq!(lookup("www.example.com."), Some(("*.example.com".into(), "192.0.2.1:443".into())));
+        assert_eq!(lookup("example.com"), None);
+        assert_eq!(lookup("notexample.com"), None);
+
+        assert!("example.com".parse::<SniRoute>().is_err());
+        assert!("*=gw:1".parse::<SniRoute>().is_err());
+        assert!("a.*.com=gw:1".parse::<SniRoute>().is_err());
+    }
+}
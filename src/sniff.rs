@@ -0,0 +1,209 @@
+//! Application protocol detection from the first client bytes
+//!
+//! The socket proxy can peek at what a client sends before picking a
+//! backend. This looks just far enough into it to tell the common protocols
+//! apart and to pull the server name out of a TLS ClientHello; nothing is
+//! consumed, the bytes are still forwarded as-is.
+
+use std::fmt;
+
+/// Protocol spoken by a client, judged from its first bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// TLS handshake record
+    Tls,
+    /// HTTP/1.x request line
+    Http,
+    /// FIX session (`8=FIX...` begin string)
+    Fix,
+    /// Anything else, or nothing sent yet
+    Unknown,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tls => write!(f, "tls"),
+            Protocol::Http => write!(f, "http"),
+            Protocol::Fix => write!(f, "fix"),
+            Protocol::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ", b"POST ", b"PUT ", b"HEAD ", b"DELETE ", b"OPTIONS ", b"PATCH ", b"CONNECT ",
+];
+
+/// TLS record type for handshake messages
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_EXT_SERVER_NAME: u16 = 0x0000;
+/// Longest TLS record, header included
+pub const MAX_TLS_RECORD_LEN: usize = 5 + 16384;
+
+/// Classify the first bytes a client sent
+pub fn detect(buf: &[u8]) -> Protocol {
+    if buf.len() >= 3 && buf[0] == TLS_HANDSHAKE && buf[1] == 0x03 && buf[2] <= 0x04 {
+        Protocol::Tls
+    } else if HTTP_METHODS.iter().any(|m| buf.starts_with(m)) {
+        Protocol::Http
+    } else if buf.starts_with(b"8=FIX") {
+        Protocol::Fix
+    } else {
+        Protocol::Unknown
+    }
+}
+
+/// Server name from the SNI extension of a TLS ClientHello
+///
+/// Returns None unless the whole extension is present in `buf`; a
+/// ClientHello split across several records is not reassembled.
+pub fn tls_sni(buf: &[u8]) -> Option<String> {
+    let mut r = Reader(buf);
+
+    // Record header
+    if r.u8()? != TLS_HANDSHAKE {
+        return None;
+    }
+    r.skip(2)?;
+    let record_len = r.u16()? as usize;
+    let mut r = Reader(r.take(record_len.min(r.0.len()))?);
+
+    // Handshake header
+    if r.u8()? != TLS_CLIENT_HELLO {
+        return None;
+    }
+    r.skip(3)?;
+
+    // client_version, random, session_id, cipher_suites, compression_methods
+    r.skip(2 + 32)?;
+    let session_id_len = r.u8()? as usize;
+    r.skip(session_id_len)?;
+    let cipher_suites_len = r.u16()? as usize;
+    r.skip(cipher_suites_len)?;
+    let compression_len = r.u8()? as usize;
+    r.skip(compression_len)?;
+
+    let extensions_len = r.u16()? as usize;
+    let mut extensions = Reader(r.take(extensions_len)?);
+    while !extensions.0.is_empty() {
+        let ext_type = extensions.u16()?;
+        let ext_len = extensions.u16()? as usize;
+        let mut ext = Reader(extensions.take(ext_len)?);
+        if ext_type != TLS_EXT_SERVER_NAME {
+            continue;
+        }
+
+        let list_len = ext.u16()? as usize;
+        let mut list = Reader(ext.take(list_len)?);
+        while !list.0.is_empty() {
+            let name_type = list.u8()?;
+            let name_len = list.u16()? as usize;
+            let name = list.take(name_len)?;
+            // host_name is the only name type ever defined
+            if name_type == 0 {
+                return std::str::from_utf8(name).ok().map(|s| s.to_ascii_lowercase());
+            }
+        }
+        return None;
+    }
+    None
+}
+
+/// Length of the handshake record `buf` starts with, header included, so a
+/// caller can wait for all of a ClientHello (just the header's while only
+/// part of it is in); None if it is not one
+pub fn tls_record_len(buf: &[u8]) -> Option<usize> {
+    match buf {
+        [TLS_HANDSHAKE, 0x03, _, hi, lo, ..] => Some(5 + u16::from_be_bytes([*hi, *lo]) as usize),
+        [TLS_HANDSHAKE] | [TLS_HANDSHAKE, 0x03, ..] if buf.len() < 5 => Some(5),
+        _ => None,
+    }
+}
+
+/// Bounds-checked big-endian cursor
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimal ClientHello carrying an SNI extension for `name`
+    fn client_hello(name: &str) -> Vec<u8> {
+        let mut sni = Vec::new();
+        sni.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        sni.push(0);
+        sni.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni.extend_from_slice(name.as_bytes());
+
+        let mut extensions = Vec::new();
+        // An unrelated extension first (supported_groups)
+        extensions.extend_from_slice(&[0x00, 0x0a, 0x00, 0x04, 0x00, 0x02, 0x00, 0x1d]);
+        extensions.extend_from_slice(&TLS_EXT_SERVER_NAME.to_be_bytes());
+        extensions.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&sni);
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0u8; 32]);
+        hello.push(0); // session_id
+        hello.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); // one cipher suite
+        hello.extend_from_slice(&[0x01, 0x00]); // null compression
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut handshake = vec![TLS_CLIENT_HELLO, 0];
+        handshake.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![TLS_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(detect(&client_hello("a.example")), Protocol::Tls);
+        assert_eq!(detect(b"GET / HTTP/1.1\r\n"), Protocol::Http);
+        assert_eq!(detect(b"8=FIX.4.4\x019=65\x01"), Protocol::Fix);
+        assert_eq!(detect(b""), Protocol::Unknown);
+        assert_eq!(detect(b"\x00\x01binary"), Protocol::Unknown);
+    }
+
+    #[test]
+    fn test_tls_sni() {
+        let hello = client_hello("MD.Venue.example");
+        assert_eq!(tls_sni(&hello).as_deref(), Some("md.venue.example"));
+
+        // Truncated before the extension ends
+        assert_eq!(tls_sni(&hello[..hello.len() - 4]), None);
+        assert_eq!(tls_record_len(&hello[..5]), Some(hello.len()));
+        assert_eq!(tls_record_len(&hello[..2]), Some(5));
+        assert_eq!(tls_record_len(b"GET / HTTP/1.1\r\n"), None);
+        assert_eq!(tls_sni(b"GET / HTTP/1.1\r\n"), None);
+    }
+}
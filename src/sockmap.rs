@@ -0,0 +1,253 @@
+//! Kernel splicing of established proxy connections (Linux only)
+//!
+//! Once both legs of a proxied connection are up, the socket proxy can
+//! hand them to the kernel: with both sockets in a BPF sockmap, an SK_SKB
+//! verdict program redirects every segment received on one socket straight
+//! into the send queue of the other. Payload never crosses into userspace,
+//! saving two copies and two syscalls per segment; the proxy task only
+//! waits for one side to close.
+//!
+//! Two sockmaps are used so a connection can be spliced without a window
+//! in which data is dropped or reordered. Sockets are first added to the
+//! `targets` map, which has no programs and only makes them valid redirect
+//! targets, and then to the `verdict` map, whose program starts handling
+//! each socket's receive path, including anything already queued. The
+//! program finds the peer through a hash keyed by socket cookie:
+//!
+//! ```text
+//! r6 = r1
+//! call bpf_get_socket_cookie
+//! *(u64 *)(r10 - 8) = r0
+//! r2 = r10
+//! r2 += -8
+//! r1 = peers ll
+//! call bpf_map_lookup_elem        ; cookie -> peer's index in targets
+//! if r0 == 0 goto pass
+//! r3 = *(u32 *)(r0 + 0)
+//! r1 = r6
+//! r2 = targets ll
+//! r4 = 0                          ; egress of the peer
+//! call bpf_sk_redirect_map
+//! exit
+//! pass:
+//! r0 = SK_PASS                    ; unknown socket: leave it to userspace
+//! exit
+//! ```
+//!
+//! Requires Linux 5.13+ (SK_SKB verdict without a stream parser) and
+//! CAP_BPF + CAP_NET_ADMIN (or CAP_SYS_ADMIN). The TCP options of both
+//! legs are still chosen by the proxy's own sockets, so splicing does not
+//! change what the network sees.
+
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::bpf::{self, BpfInsn};
+
+const SO_COOKIE: libc::c_int = 57;
+const SK_PASS: i32 = 1;
+
+/// Maps and verdict program shared by all spliced connections
+pub struct Splicer {
+    targets: OwnedFd,
+    verdict: OwnedFd,
+    peers: OwnedFd,
+    _prog: OwnedFd,
+    /// Unused connection slots; slot N owns entries 2N and 2N+1
+    free: Mutex<Vec<u32>>,
+}
+
+impl Splicer {
+    /// Create the maps and load the program, with room for `max_flows`
+    /// spliced connections
+    pub fn new(max_flows: u32) -> io::Result<Arc<Self>> {
+        let entries = max_flows * 2;
+        let targets = bpf::create_map(bpf::BPF_MAP_TYPE_SOCKMAP, 4, 4, entries)?;
+        let verdict = bpf::create_map(bpf::BPF_MAP_TYPE_SOCKMAP, 4, 4, entries)?;
+        let peers = bpf::create_map(bpf::BPF_MAP_TYPE_HASH, 8, 4, entries)?;
+
+        let insns = [
+            BpfInsn::new(0xbf, 6, 1, 0, 0), // mov r6, r1
+            BpfInsn::new(0x85, 0, 0, 0, bpf::BPF_FUNC_GET_SOCKET_COOKIE), // call
+            BpfInsn::new(0x7b, 10, 0, -8, 0), // stxdw [r10 - 8], r0
+            BpfInsn::new(0xbf, 2, 10, 0, 0), // mov r2, r10
+            BpfInsn::new(0x07, 2, 0, 0, -8), // add r2, -8
+            BpfInsn::new(0x18, 1, bpf::BPF_PSEUDO_MAP_FD, 0, peers.as_raw_fd()), // lddw r1, peers
+            BpfInsn::new(0, 0, 0, 0, 0),
+            BpfInsn::new(0x85, 0, 0, 0, bpf::BPF_FUNC_MAP_LOOKUP_ELEM), // call
+            BpfInsn::new(0x15, 0, 0, 7, 0), // jeq r0, 0, pass
+            BpfInsn::new(0x61, 3, 0, 0, 0), // ldxw r3, [r0 + 0]
+            BpfInsn::new(0xbf, 1, 6, 0, 0), // mov r1, r6
+            BpfInsn::new(0x18, 2, bpf::BPF_PSEUDO_MAP_FD, 0, targets.as_raw_fd()), // lddw r2, targets
+            BpfInsn::new(0, 0, 0, 0, 0),
+            BpfInsn::new(0xb7, 4, 0, 0, 0), // mov r4, 0
+            BpfInsn::new(0x85, 0, 0, 0, bpf::BPF_FUNC_SK_REDIRECT_MAP), // call
+            BpfInsn::new(0x95, 0, 0, 0, 0), // exit
+            BpfInsn::new(0xb7, 0, 0, 0, SK_PASS), // pass: mov r0, SK_PASS
+            BpfInsn::new(0x95, 0, 0, 0, 0), // exit
+        ];
+        let prog = bpf::load_program(
+            bpf::BPF_PROG_TYPE_SK_SKB,
+            bpf::BPF_SK_SKB_VERDICT,
+            "tcpstrip_splice",
+            &insns,
+        )?;
+        bpf::attach_to_map(&prog, &verdict, bpf::BPF_SK_SKB_VERDICT)?;
+
+        Ok(Arc::new(Self {
+            targets,
+            verdict,
+            peers,
+            _prog: prog,
+            free: Mutex::new((0..max_flows).rev().collect()),
+        }))
+    }
+
+    /// Hand forwarding between two connected sockets to the kernel
+    ///
+    /// Nothing must have been read from either socket that has not been
+    /// written to the other. The returned `Splice` must be dropped before
+    /// the sockets are closed.
+    pub fn splice(self: &Arc<Self>, client: &impl AsRawFd, server: &impl AsRawFd) -> io::Result<Splice> {
+        let fds = [client.as_raw_fd(), server.as_raw_fd()];
+        let cookies = [socket_cookie(fds[0])?, socket_cookie(fds[1])?];
+        let slot = self
+            .free
+            .lock()
+            .unwrap()
+            .pop()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::OutOfMemory, "all sockmap slots in use"))?;
+
+        // From here on, dropping the splice undoes whatever was set up
+        let splice = Splice {
+            splicer: self.clone(),
+            slot,
+            fds,
+            cookies,
+        };
+
+        let [client_key, server_key] = splice.keys();
+        bpf::update_elem(&self.targets, &client_key, &(fds[0] as u32), bpf::BPF_NOEXIST)?;
+        bpf::update_elem(&self.targets, &server_key, &(fds[1] as u32), bpf::BPF_NOEXIST)?;
+        bpf::update_elem(&self.peers, &cookies[0], &server_key, bpf::BPF_NOEXIST)?;
+        bpf::update_elem(&self.peers, &cookies[1], &client_key, bpf::BPF_NOEXIST)?;
+        bpf::update_elem(&self.verdict, &client_key, &(fds[0] as u32), bpf::BPF_NOEXIST)?;
+        bpf::update_elem(&self.verdict, &server_key, &(fds[1] as u32), bpf::BPF_NOEXIST)?;
+        Ok(splice)
+    }
+}
+
+/// A connection whose payload is forwarded by the kernel
+pub struct Splice {
+    splicer: Arc<Splicer>,
+    slot: u32,
+    fds: [RawFd; 2],
+    cookies: [u64; 2],
+}
+
+impl Splice {
+    fn keys(&self) -> [u32; 2] {
+        [self.slot * 2, self.slot * 2 + 1]
+    }
+
+    /// Payload bytes received from the client and from the server
+    pub fn bytes(&self) -> io::Result<(u64, u64)> {
+        Ok((
+            tcp_info(self.fds[0])?.tcpi_bytes_received,
+            tcp_info(self.fds[1])?.tcpi_bytes_received,
+        ))
+    }
+
+    /// How long since either socket last received payload
+    pub fn idle_for(&self) -> io::Result<Duration> {
+        let last = tcp_info(self.fds[0])?.tcpi_last_data_recv.min(tcp_info(self.fds[1])?.tcpi_last_data_recv);
+        Ok(Duration::from_millis(last as u64))
+    }
+
+    /// Whether everything received on each socket has been queued for
+    /// sending on the other
+    ///
+    /// Redirected segments pass through a kernel work queue, so after one
+    /// side closes, the last of its data may still be on its way to the
+    /// peer; removing the sockets from the maps before this returns true
+    /// would discard it.
+    pub fn drained(&self) -> io::Result<bool> {
+        Ok(self.drained_from_client()? && self.drained_from_server()?)
+    }
+
+    /// Whether everything received from the client has been queued for
+    /// sending to the server, so the server leg may be shut down after it
+    pub fn drained_from_client(&self) -> io::Result<bool> {
+        Ok(queued_for_send(self.fds[1])? >= tcp_info(self.fds[0])?.tcpi_bytes_received)
+    }
+
+    /// Whether everything received from the server has been queued for
+    /// sending to the client
+    pub fn drained_from_server(&self) -> io::Result<bool> {
+        Ok(queued_for_send(self.fds[0])? >= tcp_info(self.fds[1])?.tcpi_bytes_received)
+    }
+}
+
+impl Drop for Splice {
+    fn drop(&mut self) {
+        // Entries that were never added just fail with ENOENT
+        let splicer = &self.splicer;
+        for key in self.keys() {
+            let _ = bpf::delete_elem(&splicer.verdict, &key);
+            let _ = bpf::delete_elem(&splicer.targets, &key);
+        }
+        for cookie in &self.cookies {
+            let _ = bpf::delete_elem(&splicer.peers, cookie);
+        }
+        splicer.free.lock().unwrap().push(self.slot);
+    }
+}
+
+fn socket_cookie(fd: RawFd) -> io::Result<u64> {
+    let mut cookie = 0u64;
+    let mut len = mem::size_of::<u64>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            SO_COOKIE,
+            &mut cookie as *mut u64 as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cookie)
+}
+
+fn tcp_info(fd: RawFd) -> io::Result<libc::tcp_info> {
+    let mut info: libc::tcp_info = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(info)
+}
+
+/// Payload bytes written to a socket so far: acknowledged plus still queued
+fn queued_for_send(fd: RawFd) -> io::Result<u64> {
+    let acked = tcp_info(fd)?.tcpi_bytes_acked;
+    let mut outq: libc::c_int = 0;
+    if unsafe { libc::ioctl(fd, libc::TIOCOUTQ, &mut outq) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(acked + outq as u64)
+}
@@ -0,0 +1,267 @@
+//! SOCKS5 server side (RFC 1928, RFC 1929)
+//!
+//! With `--socks5` the client names the destination of each connection in
+//! a SOCKS5 handshake instead of the proxy being tied to a `--target`. Only
+//! CONNECT is supported; BIND and UDP ASSOCIATE are refused. With
+//! `--socks5-auth USER:PASS` clients must log in with username/password
+//! authentication, otherwise no authentication is asked for.
+//!
+//! The handshake only decides where to dial: the upstream connection is
+//! made like any other, with the same socket tuning and timestamp policy,
+//! and `reply` tells the client how the dial went.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// How long a client may take over the handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const VERSION: u8 = 0x05;
+const NO_AUTHENTICATION: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// `--socks5-auth USER:PASS`
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl FromStr for Credentials {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((username, password))
+                if (1..=255).contains(&username.len()) && (1..=255).contains(&password.len()) =>
+            {
+                Ok(Credentials {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                })
+            }
+            _ => Err("invalid credentials (expected USER:PASS, each 1-255 bytes)".to_string()),
+        }
+    }
+}
+
+// Keep the password out of logs and debug output
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Credentials({}:***)", self.username)
+    }
+}
+
+/// Where the client asked to connect
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    Addr(SocketAddr),
+    /// A name for the proxy to resolve
+    Domain(String, u16),
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Destination::Addr(addr) => write!(f, "{}", addr),
+            Destination::Domain(name, port) => write!(f, "{}:{}", name, port),
+        }
+    }
+}
+
+/// The outcome of the upstream dial, as reported to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reply {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    NotAllowed = 0x02,
+    NetworkUnreachable = 0x03,
+    HostUnreachable = 0x04,
+    ConnectionRefused = 0x05,
+    TtlExpired = 0x06,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+impl Reply {
+    /// The reply for a failed dial
+    pub fn of(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
+            io::ErrorKind::NetworkUnreachable => Reply::NetworkUnreachable,
+            io::ErrorKind::HostUnreachable | io::ErrorKind::NotFound => Reply::HostUnreachable,
+            io::ErrorKind::TimedOut => Reply::TtlExpired,
+            io::ErrorKind::PermissionDenied => Reply::NotAllowed,
+            _ => Reply::GeneralFailure,
+        }
+    }
+}
+
+fn invalid(reason: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.into())
+}
+
+/// Run the server side of the handshake up to the client's request: the
+/// destination it asked for. Requests the proxy cannot serve are answered
+/// and fail with `Unsupported`, bad logins with `PermissionDenied`
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    auth: Option<&Credentials>,
+) -> io::Result<Destination> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != VERSION {
+        return Err(invalid(format!("not a SOCKS5 client (version {})", header[0])));
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    let method = if auth.is_some() { USERNAME_PASSWORD } else { NO_AUTHENTICATION };
+    if !methods.contains(&method) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "client offered no acceptable authentication method"));
+    }
+    stream.write_all(&[VERSION, method]).await?;
+    if let Some(credentials) = auth {
+        authenticate(stream, credentials).await?;
+    }
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    if request[0] != VERSION {
+        return Err(invalid("bad request version"));
+    }
+    let destination = match request[3] {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            Destination::Addr(SocketAddr::new(Ipv4Addr::from(ip).into(), stream.read_u16().await?))
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            Destination::Addr(SocketAddr::new(Ipv6Addr::from(ip).into(), stream.read_u16().await?))
+        }
+        ATYP_DOMAIN => {
+            let mut name = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut name).await?;
+            let name = String::from_utf8(name).map_err(|_| invalid("domain name is not UTF-8"))?;
+            Destination::Domain(name, stream.read_u16().await?)
+        }
+        atyp => {
+            reply(stream, Reply::AddressTypeNotSupported, None).await?;
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("address type {}", atyp)));
+        }
+    };
+    if request[1] != CMD_CONNECT {
+        reply(stream, Reply::CommandNotSupported, None).await?;
+        return Err(io::Error::new(io::ErrorKind::Unsupported, format!("command {} (only CONNECT is)", request[1])));
+    }
+    Ok(destination)
+}
+
+/// Username/password subnegotiation (RFC 1929)
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, credentials: &Credentials) -> io::Result<()> {
+    if stream.read_u8().await? != 0x01 {
+        return Err(invalid("bad username/password subnegotiation version"));
+    }
+    let mut username = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut username).await?;
+    let mut password = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut password).await?;
+    if username != credentials.username.as_bytes() || password != credentials.password.as_bytes() {
+        stream.write_all(&[0x01, 0x01]).await?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("bad credentials for user '{}'", String::from_utf8_lossy(&username)),
+        ));
+    }
+    stream.write_all(&[0x01, 0x00]).await
+}
+
+/// Answer the client's request; `bound` is the proxy's address for the
+/// upstream connection on success
+pub async fn reply<S: AsyncWrite + Unpin>(stream: &mut S, reply: Reply, bound: Option<SocketAddr>) -> io::Result<()> {
+    let bound = bound.unwrap_or_else(|| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
+    let mut message = vec![VERSION, reply as u8, 0x00];
+    match bound.ip().to_canonical() {
+        IpAddr::V4(ip) => {
+            message.push(ATYP_IPV4);
+            message.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            message.push(ATYP_IPV6);
+            message.extend_from_slice(&ip.octets());
+        }
+    }
+    message.extend_from_slice(&bound.port().to_be_bytes());
+    stream.write_all(&message).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let credentials: Credentials = "trader:s3cret".parse().unwrap();
+        assert!("trader".parse::<Credentials>().is_err());
+        assert!(!format!("{:?}", credentials).contains("s3cret"));
+
+        // No authentication, CONNECT to a name
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&[5, 1, 0, 5, 1, 0, 3, 9]).await.unwrap();
+        client.write_all(b"gw1.local\x23\x28").await.unwrap();
+        let destination = handshake(&mut server, None).await.unwrap();
+        assert_eq!(destination, Destination::Domain("gw1.local".to_string(), 9000));
+        reply(&mut server, Reply::Succeeded, Some("10.0.0.1:40000".parse().unwrap())).await.unwrap();
+        let mut answer = [0u8; 12];
+        client.read_exact(&mut answer).await.unwrap();
+        assert_eq!(answer, [5, 0, 5, 0, 0, 1, 10, 0, 0, 1, 0x9c, 0x40]);
+
+        // Username/password, CONNECT to an IPv4 address
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&[5, 2, 0, 2, 1, 6]).await.unwrap();
+        client.write_all(b"trader\x06s3cret").await.unwrap();
+        client.write_all(&[5, 1, 0, 1, 192, 0, 2, 7, 0x23, 0x28]).await.unwrap();
+        let destination = handshake(&mut server, Some(&credentials)).await.unwrap();
+        assert_eq!(destination, Destination::Addr("192.0.2.7:9000".parse().unwrap()));
+        let mut answer = [0u8; 4];
+        client.read_exact(&mut answer).await.unwrap();
+        assert_eq!(answer, [5, 2, 1, 0]);
+
+        // A wrong password is turned away
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&[5, 1, 2, 1, 6]).await.unwrap();
+        client.write_all(b"trader\x05guess").await.unwrap();
+        let error = handshake(&mut server, Some(&credentials)).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+
+        // Without the method the proxy insists on, nothing is accepted
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        assert!(handshake(&mut server, Some(&credentials)).await.is_err());
+        let mut answer = [0u8; 2];
+        client.read_exact(&mut answer).await.unwrap();
+        assert_eq!(answer, [5, 0xff]);
+
+        // BIND is refused with a reply
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&[5, 1, 0, 5, 2, 0, 1, 192, 0, 2, 7, 0, 80]).await.unwrap();
+        assert_eq!(handshake(&mut server, None).await.unwrap_err().kind(), io::ErrorKind::Unsupported);
+        let mut answer = [0u8; 4];
+        client.read_exact(&mut answer).await.unwrap();
+        assert_eq!(answer, [5, 0, 5, Reply::CommandNotSupported as u8]);
+
+        assert_eq!(Reply::of(&io::Error::from(io::ErrorKind::ConnectionRefused)), Reply::ConnectionRefused);
+    }
+}
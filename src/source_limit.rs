@@ -0,0 +1,255 @@
+//! Per-client limits (`--max-connections-per-ip`, `--max-connect-rate-per-ip`)
+//!
+//! One misbehaving host in the racks (a reconnect loop, a leaking pool)
+//! should not be able to take every slot of the gateway behind the proxy.
+//! Each client address may hold at most so many connections at a time,
+//! and open new ones at most so many times a second, with bursts of up to
+//! a second's worth. Both are checked as a connection is accepted, before
+//! anything is dialed for it.
+//!
+//! A connection over the concurrency limit is closed. One over the rate is
+//! closed too, or with `Exceeded::Delay` held back until the client's rate
+//! allows it, as long as that is at most `MAX_DELAY` away; a client that
+//! keeps reconnecting then only slows itself down.
+//!
+//! Refused connections are counted in
+//! `tcpstrip_source_limit_rejects_total{reason}` (`connections` or `rate`)
+//! and held back ones in `tcpstrip_source_limit_delays_total`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::metrics::{self, Metric};
+
+/// Longest a connection over the rate is held back
+pub const MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// Track at least this many clients before forgetting idle ones
+const PRUNE_MIN: usize = 1024;
+
+/// What happens to a connection over the rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Exceeded {
+    #[default]
+    Reject,
+    Delay,
+}
+
+impl fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exceeded::Reject => write!(f, "reject"),
+            Exceeded::Delay => write!(f, "delay"),
+        }
+    }
+}
+
+impl FromStr for Exceeded {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Exceeded::Reject),
+            "delay" => Ok(Exceeded::Delay),
+            _ => Err(format!("unknown limit action '{}' (expected reject or delay)", s)),
+        }
+    }
+}
+
+/// Why a connection was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejected {
+    /// The client already holds its maximum of connections
+    Connections,
+    /// The client opens connections faster than its rate
+    Rate,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejected::Connections => write!(f, "connections"),
+            Rejected::Rate => write!(f, "rate"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Client {
+    active: usize,
+    /// Connections the client may still open at once; negative while
+    /// delayed ones are waiting for their turn
+    tokens: f64,
+    refilled: Instant,
+}
+
+#[derive(Debug)]
+struct Clients {
+    by_ip: HashMap<IpAddr, Client>,
+    next_prune: usize,
+}
+
+/// The limits shared by all listeners
+#[derive(Debug)]
+pub struct SourceLimiter {
+    max_connections: Option<usize>,
+    /// New connections per second
+    rate: Option<u32>,
+    exceeded: Exceeded,
+    clients: Mutex<Clients>,
+    rejects_connections: Arc<Metric>,
+    rejects_rate: Arc<Metric>,
+    delays: Arc<Metric>,
+}
+
+impl SourceLimiter {
+    pub fn new(max_connections: Option<usize>, rate: Option<u32>, exceeded: Exceeded) -> Arc<Self> {
+        let rejects = metrics::registry().labeled_counter(
+            "tcpstrip_source_limit_rejects_total",
+            "Client connections refused for exceeding a per-client limit",
+            "reason",
+        );
+        Arc::new(Self {
+            max_connections,
+            rate,
+            exceeded,
+            clients: Mutex::new(Clients {
+                by_ip: HashMap::new(),
+                next_prune: PRUNE_MIN,
+            }),
+            rejects_connections: rejects.with(&Rejected::Connections.to_string()),
+            rejects_rate: rejects.with(&Rejected::Rate.to_string()),
+            delays: metrics::registry().counter("tcpstrip_source_limit_delays_total", "Client connections held back for exceeding their rate"),
+        })
+    }
+
+    /// Admit a connection from `ip`: a permit to hold for as long as it is
+    /// open, and how long to wait before dialing for it
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<(SourcePermit, Duration), Rejected> {
+        let result = self.admit_at(ip, Instant::now());
+        match result {
+            Err(Rejected::Connections) => self.rejects_connections.inc(),
+            Err(Rejected::Rate) => self.rejects_rate.inc(),
+            Ok((_, delay)) if !delay.is_zero() => self.delays.inc(),
+            Ok(_) => {}
+        }
+        result
+    }
+
+    fn admit_at(self: &Arc<Self>, ip: IpAddr, now: Instant) -> Result<(SourcePermit, Duration), Rejected> {
+        let ip = ip.to_canonical();
+        let burst = self.rate.unwrap_or(0) as f64;
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        if clients.by_ip.len() >= clients.next_prune {
+            self.prune(&mut clients, now);
+        }
+        let client = clients.by_ip.entry(ip).or_insert(Client {
+            active: 0,
+            tokens: burst,
+            refilled: now,
+        });
+        if self.max_connections.is_some_and(|max| client.active >= max) {
+            return Err(Rejected::Connections);
+        }
+        let mut delay = Duration::ZERO;
+        if let Some(rate) = self.rate {
+            let rate = rate as f64;
+            client.tokens = (client.tokens + now.saturating_duration_since(client.refilled).as_secs_f64() * rate).min(burst);
+            client.refilled = now;
+            if client.tokens < 1.0 {
+                delay = Duration::from_secs_f64((1.0 - client.tokens) / rate);
+                if self.exceeded == Exceeded::Reject || delay > MAX_DELAY {
+                    return Err(Rejected::Rate);
+                }
+            }
+            client.tokens -= 1.0;
+        }
+        client.active += 1;
+        Ok((
+            SourcePermit {
+                limiter: self.clone(),
+                ip,
+            },
+            delay,
+        ))
+    }
+
+    /// Forget the clients with no connections whose rate has recovered
+    fn prune(&self, clients: &mut Clients, now: Instant) {
+        let (rate, burst) = match self.rate {
+            Some(rate) => (rate as f64, rate as f64),
+            None => (0.0, 0.0),
+        };
+        clients.by_ip.retain(|_, client| {
+            client.active > 0 || client.tokens + now.saturating_duration_since(client.refilled).as_secs_f64() * rate < burst
+        });
+        clients.next_prune = (clients.by_ip.len() * 2).max(PRUNE_MIN);
+    }
+}
+
+/// One admitted connection, counted against its client until dropped
+#[derive(Debug)]
+pub struct SourcePermit {
+    limiter: Arc<SourceLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for SourcePermit {
+    fn drop(&mut self) {
+        let mut clients = self.limiter.clients.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(client) = clients.by_ip.get_mut(&self.ip) {
+            client.active -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let a: IpAddr = "10.9.0.1".parse().unwrap();
+        let b: IpAddr = "::ffff:10.9.0.2".parse().unwrap();
+        let start = Instant::now();
+
+        let limiter = SourceLimiter::new(Some(2), None, Exceeded::Reject);
+        let first = limiter.admit_at(a, start).unwrap();
+        let _second = limiter.admit_at(a, start).unwrap();
+        assert_eq!(limiter.admit_at(a, start).unwrap_err(), Rejected::Connections);
+        assert!(limiter.admit_at(b, start).is_ok());
+        drop(first);
+        assert!(limiter.admit_at(a, start).is_ok());
+
+        // Bursts of a second's worth, then the rate
+        let limiter = SourceLimiter::new(None, Some(2), Exceeded::Reject);
+        assert_eq!(limiter.admit_at(a, start).unwrap().1, Duration::ZERO);
+        assert_eq!(limiter.admit_at(a, start).unwrap().1, Duration::ZERO);
+        assert_eq!(limiter.admit_at(a, start).unwrap_err(), Rejected::Rate);
+        assert!(limiter.admit_at(b, start).is_ok());
+        assert!(limiter.admit_at(a, start + Duration::from_millis(500)).is_ok());
+        assert_eq!(limiter.admit_at(a, start + Duration::from_millis(500)).unwrap_err(), Rejected::Rate);
+
+        // Delayed connections queue up behind each other, up to MAX_DELAY
+        let limiter = SourceLimiter::new(None, Some(2), Exceeded::Delay);
+        let mut permits = Vec::new();
+        for expected in [0, 0, 500, 1000, 1500, 2000, 2500, 3000, 3500, 4000, 4500, 5000] {
+            let (permit, delay) = limiter.admit_at(a, start).unwrap();
+            assert_eq!(delay.as_millis(), expected);
+            permits.push(permit);
+        }
+        assert_eq!(limiter.admit_at(a, start).unwrap_err(), Rejected::Rate);
+
+        // Idle clients are forgotten once their rate has recovered
+        permits.clear();
+        let mut clients = limiter.clients.lock().unwrap();
+        limiter.prune(&mut clients, start + Duration::from_secs(5));
+        assert_eq!(clients.by_ip.len(), 1);
+        limiter.prune(&mut clients, start + Duration::from_secs(7));
+        assert!(clients.by_ip.is_empty());
+    }
+}
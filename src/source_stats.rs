@@ -0,0 +1,253 @@
+//! Connection outcomes broken down by client subnet
+//!
+//! When one client rack or counterparty site goes bad, the per-backend
+//! metrics only show a slight dip across the board. `--source-group
+//! [NAME=]CIDR` names the subnets clients connect from, and every
+//! connection is also counted under the first group its address falls in
+//! (clients outside all groups under `unmatched`):
+//!
+//! - `tcpstrip_source_connections_total`: connections accepted
+//! - `tcpstrip_source_rejects_total`: dropped before dialing (FIX logon
+//!   guard, plugin or route script)
+//! - `tcpstrip_source_connect_errors_total`: the backend could not be
+//!   reached
+//! - `tcpstrip_source_setup_micros_total`: time from accept until the
+//!   backend leg was up, summed over connections that got that far
+//! - `tcpstrip_source_resets_total` / `tcpstrip_source_errors_total`:
+//!   established connections that ended in a reset or another error
+//!
+//! all labeled `source`. Several subnets may share a name and are then
+//! counted together.
+
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::metrics::{self, Metric};
+
+/// Label value of clients outside every group
+pub const UNMATCHED: &str = "unmatched";
+
+/// An IPv4 or IPv6 network in CIDR notation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Subnet {
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = String;
+
+    /// `ADDR/LEN`, or a bare address for a single host
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let network: IpAddr = addr.parse().map_err(|_| format!("invalid subnet '{}'", s))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|&len| len <= max)
+                .ok_or_else(|| format!("invalid prefix length in '{}' (expected 0-{})", s, max))?,
+            None => max,
+        };
+        Ok(Self { network, prefix_len })
+    }
+}
+
+/// One `--source-group` argument
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceGroup {
+    pub name: String,
+    pub subnet: Subnet,
+}
+
+impl FromStr for SourceGroup {
+    type Err = String;
+
+    /// `NAME=CIDR`, or a bare CIDR that doubles as the name
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((name, subnet)) if !name.is_empty() => Ok(Self {
+                name: name.to_string(),
+                subnet: subnet.parse()?,
+            }),
+            Some(_) => Err(format!("missing group name in '{}'", s)),
+            None => {
+                let subnet: Subnet = s.parse()?;
+                Ok(Self {
+                    name: subnet.to_string(),
+                    subnet,
+                })
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SourceMetrics {
+    connections: Arc<Metric>,
+    rejects: Arc<Metric>,
+    connect_errors: Arc<Metric>,
+    setup_micros: Arc<Metric>,
+    resets: Arc<Metric>,
+    errors: Arc<Metric>,
+}
+
+impl SourceMetrics {
+    fn new(source: &str) -> Self {
+        let registry = metrics::registry();
+        let counter = |name, help| registry.labeled_counter(name, help, "source").with(source);
+        Self {
+            connections: counter("tcpstrip_source_connections_total", "Connections accepted per client subnet"),
+            rejects: counter("tcpstrip_source_rejects_total", "Connections dropped before dialing, per client subnet"),
+            connect_errors: counter(
+                "tcpstrip_source_connect_errors_total",
+                "Connections whose backend could not be reached, per client subnet",
+            ),
+            setup_micros: counter(
+                "tcpstrip_source_setup_micros_total",
+                "Microseconds from accept until the backend leg was up, per client subnet",
+            ),
+            resets: counter("tcpstrip_source_resets_total", "Established connections ended by a reset, per client subnet"),
+            errors: counter(
+                "tcpstrip_source_errors_total",
+                "Established connections ended by another error, per client subnet",
+            ),
+        }
+    }
+}
+
+/// The configured groups, first match wins
+#[derive(Debug)]
+pub struct SourceGroups {
+    groups: Vec<(Subnet, Arc<SourceMetrics>)>,
+    unmatched: Arc<SourceMetrics>,
+}
+
+impl SourceGroups {
+    pub fn new(groups: &[SourceGroup]) -> Self {
+        Self {
+            groups: groups
+                .iter()
+                .map(|group| (group.subnet, Arc::new(SourceMetrics::new(&group.name))))
+                .collect(),
+            unmatched: Arc::new(SourceMetrics::new(UNMATCHED)),
+        }
+    }
+
+    /// Start counting a connection from `client`
+    pub fn tally(&self, client: IpAddr) -> Tally {
+        let metrics = self
+            .groups
+            .iter()
+            .find(|(subnet, _)| subnet.contains(client))
+            .map(|(_, metrics)| metrics)
+            .unwrap_or(&self.unmatched);
+        metrics.connections.inc();
+        Tally(Some(metrics.clone()))
+    }
+}
+
+/// How one connection went, for its source group; does nothing if no
+/// groups are configured
+#[derive(Debug, Default)]
+pub struct Tally(Option<Arc<SourceMetrics>>);
+
+impl Tally {
+    pub fn rejected(&self) {
+        if let Some(metrics) = &self.0 {
+            metrics.rejects.inc();
+        }
+    }
+
+    pub fn connect_failed(&self) {
+        if let Some(metrics) = &self.0 {
+            metrics.connect_errors.inc();
+        }
+    }
+
+    /// The backend leg is up, `setup` after the accept
+    pub fn connected(&self, setup: Duration) {
+        if let Some(metrics) = &self.0 {
+            metrics.setup_micros.add(setup.as_micros() as u64);
+        }
+    }
+
+    /// An established connection ended with `error`
+    pub fn failed(&self, error: io::ErrorKind) {
+        if let Some(metrics) = &self.0 {
+            match error {
+                io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe => {
+                    metrics.resets.inc()
+                }
+                _ => metrics.errors.inc(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_groups() {
+        let rack: SourceGroup = "rack-a=10.1.0.0/24".parse().unwrap();
+        assert!(rack.subnet.contains("10.1.0.77".parse().unwrap()));
+        assert!(rack.subnet.contains("::ffff:10.1.0.77".parse().unwrap()));
+        assert!(!rack.subnet.contains("10.1.1.77".parse().unwrap()));
+        let site: SourceGroup = "2001:db8:7::/48".parse().unwrap();
+        assert_eq!(site.name, "2001:db8:7::/48");
+        assert!(site.subnet.contains("2001:db8:7:1::5".parse().unwrap()));
+        assert!("10.1.0.0/33".parse::<Subnet>().is_err());
+        assert!("=10.1.0.0/24".parse::<SourceGroup>().is_err());
+        assert!("0.0.0.0/0".parse::<Subnet>().unwrap().contains("192.0.2.1".parse().unwrap()));
+
+        let groups = SourceGroups::new(&[rack, "rack-a=10.2.0.0/24".parse().unwrap()]);
+        let tally = groups.tally("10.2.0.9".parse().unwrap());
+        tally.connected(Duration::from_micros(250));
+        tally.failed(io::ErrorKind::ConnectionReset);
+        groups.tally("10.1.0.9".parse().unwrap()).rejected();
+        groups.tally("192.0.2.9".parse().unwrap()).connect_failed();
+
+        let rack_a = &groups.groups[0].1;
+        assert_eq!(rack_a.connections.get(), 2);
+        assert_eq!(rack_a.setup_micros.get(), 250);
+        assert_eq!((rack_a.resets.get(), rack_a.rejects.get()), (1, 1));
+        assert_eq!(groups.unmatched.connect_errors.get(), 1);
+        Tally::default().rejected();
+    }
+}
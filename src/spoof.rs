@@ -0,0 +1,215 @@
+//! Per-connection spoofed timestamp clocks
+//!
+//! A fixed TSval hides the host clock but is itself a fingerprint, and
+//! timestamps that jump around break PAWS on the receiver. The spoofer
+//! instead gives every connection its own millisecond clock starting at a
+//! random origin: values increase monotonically like a real stack's, but
+//! reveal nothing about host uptime or tick rate and cannot be correlated
+//! across connections.
+//!
+//! Whatever TSval we put on the wire, the peer echoes back in TSecr, and
+//! the host rejects a SYN-ACK whose TSecr it never sent. The spoofer
+//! therefore remembers the last few values it replaced per flow so the
+//! return path can translate echoes back to the host's own values.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+use crate::entropy::EntropySource;
+use crate::packet::FlowKey;
+use crate::tcp_analysis::generate_spoofed_timestamp;
+
+/// Flows not seen for this long are forgotten when the table is full
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+const MAX_FLOWS: usize = 65536;
+/// Replaced values remembered per flow for translating echoes
+const ECHO_HISTORY: usize = 8;
+
+enum Clock {
+    /// Per-flow clocks starting at a random origin
+    Random(Box<dyn EntropySource>),
+    /// The same value on every segment
+    Fixed(u32),
+}
+
+struct FlowClock {
+    origin: u32,
+    started: Instant,
+    last_seen: Instant,
+    /// (spoofed, original) TSval pairs, most recent at `next - 1`
+    sent: [(u32, u32); ECHO_HISTORY],
+    next: usize,
+}
+
+/// Per-flow TSval generator for one direction of traffic
+pub struct TimestampSpoofer {
+    clock: Clock,
+    flows: HashMap<FlowKey, FlowClock>,
+}
+
+impl TimestampSpoofer {
+    pub fn new(entropy: Box<dyn EntropySource>) -> Self {
+        Self {
+            clock: Clock::Random(entropy),
+            flows: HashMap::new(),
+        }
+    }
+
+    /// Spoofer that puts `ts_val` on every segment
+    pub fn fixed(ts_val: u32) -> Self {
+        Self {
+            clock: Clock::Fixed(ts_val),
+            flows: HashMap::new(),
+        }
+    }
+
+    /// TSval to put on the next segment of `flow` in place of `original`
+    pub fn ts_val(&mut self, flow: FlowKey, original: u32, now: Instant) -> u32 {
+        if !self.flows.contains_key(&flow) {
+            self.make_room(now);
+            let origin = match &mut self.clock {
+                Clock::Random(entropy) => {
+                    let seed = entropy.next_u32();
+                    generate_spoofed_timestamp(seed, 0, entropy.as_mut()).ts_val
+                }
+                Clock::Fixed(ts_val) => *ts_val,
+            };
+            self.flows.insert(
+                flow,
+                FlowClock {
+                    origin,
+                    started: now,
+                    last_seen: now,
+                    sent: [(origin, original); ECHO_HISTORY],
+                    next: 0,
+                },
+            );
+        }
+
+        let clock = self.flows.get_mut(&flow).expect("flow just inserted");
+        clock.last_seen = now;
+        let spoofed = match self.clock {
+            Clock::Random(_) => {
+                let elapsed_ms = now.duration_since(clock.started).as_millis() as u32;
+                clock.origin.wrapping_add(elapsed_ms)
+            }
+            Clock::Fixed(ts_val) => ts_val,
+        };
+
+        clock.sent[clock.next] = (spoofed, original);
+        clock.next = (clock.next + 1) % ECHO_HISTORY;
+        spoofed
+    }
+
+    /// Map a TSecr the peer echoed on `flow` back to the TSval the host sent
+    ///
+    /// Echoes of recently replaced values translate exactly; older ones are
+    /// shifted by the most recent offset between the two clocks.
+    pub fn original_ts_val(&self, flow: &FlowKey, echoed: u32) -> Option<u32> {
+        let clock = self.flows.get(flow)?;
+        let newest_first = (1..=ECHO_HISTORY).map(|back| clock.sent[(clock.next + ECHO_HISTORY - back) % ECHO_HISTORY]);
+
+        let mut latest = None;
+        for (spoofed, original) in newest_first {
+            if spoofed == echoed {
+                return Some(original);
+            }
+            latest.get_or_insert((spoofed, original));
+        }
+
+        let (spoofed, original) = latest?;
+        Some(original.wrapping_add(echoed.wrapping_sub(spoofed)))
+    }
+
+    /// Drop the clock for a finished connection
+    pub fn forget(&mut self, flow: &FlowKey) {
+        self.flows.remove(flow);
+    }
+
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+
+    fn make_room(&mut self, now: Instant) {
+        if self.flows.len() < MAX_FLOWS {
+            return;
+        }
+        self.flows
+            .retain(|_, clock| now.duration_since(clock.last_seen) < FLOW_IDLE_TIMEOUT);
+        if self.flows.len() >= MAX_FLOWS {
+            debug!("Timestamp spoofer flow table full, resetting");
+            self.flows.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entropy::SeededEntropy;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn flow(port: u16) -> FlowKey {
+        FlowKey {
+            src: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            dst: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            src_port: port,
+            dst_port: 9000,
+        }
+    }
+
+    #[test]
+    fn test_clock_is_monotonic_per_flow() {
+        let mut spoofer = TimestampSpoofer::new(Box::new(SeededEntropy::new(1)));
+        let start = Instant::now();
+
+        let first = spoofer.ts_val(flow(1), 100, start);
+        let later = spoofer.ts_val(flow(1), 350, start + Duration::from_millis(250));
+        assert_eq!(later.wrapping_sub(first), 250);
+    }
+
+    #[test]
+    fn test_flows_get_independent_origins() {
+        let mut spoofer = TimestampSpoofer::new(Box::new(SeededEntropy::new(1)));
+        let now = Instant::now();
+
+        assert_ne!(spoofer.ts_val(flow(1), 7, now), spoofer.ts_val(flow(2), 7, now));
+        assert_eq!(spoofer.len(), 2);
+
+        spoofer.forget(&flow(1));
+        assert_eq!(spoofer.len(), 1);
+    }
+
+    #[test]
+    fn test_seeded_runs_are_reproducible() {
+        let now = Instant::now();
+        let mut a = TimestampSpoofer::new(Box::new(SeededEntropy::new(99)));
+        let mut b = TimestampSpoofer::new(Box::new(SeededEntropy::new(99)));
+        assert_eq!(a.ts_val(flow(5), 1, now), b.ts_val(flow(5), 1, now));
+    }
+
+    #[test]
+    fn test_echoes_translate_back() {
+        let mut spoofer = TimestampSpoofer::new(Box::new(SeededEntropy::new(3)));
+        let start = Instant::now();
+
+        let first = spoofer.ts_val(flow(1), 1000, start);
+        let second = spoofer.ts_val(flow(1), 1010, start + Duration::from_millis(10));
+        assert_eq!(spoofer.original_ts_val(&flow(1), first), Some(1000));
+        assert_eq!(spoofer.original_ts_val(&flow(1), second), Some(1010));
+        // Unknown echo: shifted by the latest offset
+        assert_eq!(spoofer.original_ts_val(&flow(1), second.wrapping_sub(5)), Some(1005));
+        assert_eq!(spoofer.original_ts_val(&flow(2), first), None);
+
+        let mut fixed = TimestampSpoofer::fixed(42);
+        assert_eq!(fixed.ts_val(flow(1), 500, start), 42);
+        assert_eq!(fixed.ts_val(flow(1), 507, start), 42);
+        assert_eq!(fixed.original_ts_val(&flow(1), 42), Some(507));
+    }
+}
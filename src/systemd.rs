@@ -0,0 +1,170 @@
+//! systemd socket activation and service notifications (Linux only)
+//!
+//! With socket activation systemd binds the listening sockets itself and
+//! passes them on as file descriptors (`LISTEN_FDS`, from fd 3 up). A
+//! listener whose address one of them is bound to uses it instead of
+//! binding a socket of its own, so the socket, and the connections queued
+//! on it, outlive a restart of the service: clients that connect while the
+//! proxy restarts wait in the backlog instead of being refused. A listener
+//! on the unspecified address takes an inherited socket on the unspecified
+//! address of either family, as systemd binds `ListenStream=PORT` to
+//! `[::]:PORT`.
+//!
+//! Under `Type=notify` units the proxy reports through `NOTIFY_SOCKET`:
+//! `READY=1` once it is serving, `STOPPING=1` when told to stop and, with
+//! `WatchdogSec`, `WATCHDOG=1` at half the interval. Outside systemd none of
+//! the variables is set and none of this does anything.
+
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{self, UnixDatagram};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use socket2::{Socket, Type};
+
+/// First descriptor systemd passes (SD_LISTEN_FDS_START)
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The passed descriptors were taken over
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Whether a variable systemd sets for one process is meant for this one
+fn for_this_process(pid_var: &str) -> bool {
+    match std::env::var(pid_var) {
+        Ok(pid) => pid.parse::<u32>().ok() == Some(std::process::id()),
+        Err(_) => false,
+    }
+}
+
+/// Sockets systemd passed to this process
+#[derive(Debug, Default)]
+pub struct Inherited {
+    sockets: Vec<(SocketAddr, Socket)>,
+}
+
+impl Inherited {
+    /// Take over the inet sockets passed in `LISTEN_FDS`; the first call
+    /// gets them, later ones none
+    pub fn take() -> io::Result<Self> {
+        if !for_this_process("LISTEN_PID") || TAKEN.swap(true, Ordering::Relaxed) {
+            return Ok(Self::default());
+        }
+        let count: RawFd = std::env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|count| count.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "LISTEN_FDS is not a number"))?;
+        let mut sockets = Vec::with_capacity(count.max(0) as usize);
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count) {
+            // systemd passed it to us and nothing else in the process knows
+            // of it
+            let socket = Socket::from(unsafe { OwnedFd::from_raw_fd(fd) });
+            socket.set_cloexec(true)?;
+            // Other kinds (unix sockets, FIFOs) are closed
+            if let Some(addr) = socket.local_addr().ok().and_then(|addr| addr.as_socket()) {
+                sockets.push((SocketAddr::new(addr.ip().to_canonical(), addr.port()), socket));
+            }
+        }
+        Ok(Self { sockets })
+    }
+
+    /// The inherited stream socket for a listener on `addr`, if there is one
+    pub fn listener(&mut self, addr: SocketAddr) -> Option<Socket> {
+        let index = self.sockets.iter().position(|(bound, socket)| {
+            let matches = match addr.ip().is_unspecified() {
+                true => bound.ip().is_unspecified() && bound.port() == addr.port(),
+                false => *bound == addr,
+            };
+            matches && socket.r#type().ok() == Some(Type::STREAM)
+        })?;
+        Some(self.sockets.swap_remove(index).1)
+    }
+
+    /// Addresses of the inherited sockets no listener took
+    pub fn unused(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.sockets.iter().map(|(addr, _)| *addr)
+    }
+}
+
+/// Send `state` (newline-separated assignments such as `READY=1`) to the
+/// service manager; false when not run by one that listens
+pub fn notify(state: &str) -> io::Result<bool> {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let socket = UnixDatagram::unbound()?;
+    let addr = match path.as_bytes() {
+        [b'@', name @ ..] => net::SocketAddr::from_abstract_name(name)?,
+        [b'/', ..] => net::SocketAddr::from_pathname(&path)?,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "NOTIFY_SOCKET is neither a path nor an abstract address")),
+    };
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(true)
+}
+
+/// How often to ping the watchdog: half its timeout, if the unit has one
+/// for this process
+pub fn watchdog_interval() -> Option<Duration> {
+    if std::env::var_os("WATCHDOG_PID").is_some() && !for_this_process("WATCHDOG_PID") {
+        return None;
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listener_matching() {
+        let bind = |addr: &str, kind: Type| {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let socket = Socket::new(socket2::Domain::for_address(addr), kind, None).unwrap();
+            socket.bind(&addr.into()).unwrap();
+            let bound = socket.local_addr().unwrap().as_socket().unwrap();
+            (bound, socket)
+        };
+        let (v6_any, dual) = bind("[::]:0", Type::STREAM);
+        let (loopback, local) = bind("127.0.0.1:0", Type::STREAM);
+        let (udp, datagram) = bind("127.0.0.1:0", Type::DGRAM);
+        let mut inherited = Inherited {
+            sockets: vec![(v6_any, dual), (loopback, local), (udp, datagram)],
+        };
+
+        // A datagram socket is no listener, whatever its address
+        assert!(inherited.listener(udp).is_none());
+        assert!(inherited.listener(SocketAddr::new([127, 0, 0, 2].into(), loopback.port())).is_none());
+        assert!(inherited.listener(loopback).is_some());
+        // ListenStream=PORT serves a listener on 0.0.0.0:PORT
+        assert!(inherited.listener(SocketAddr::from(([0, 0, 0, 0], v6_any.port()))).is_some());
+        assert_eq!(inherited.unused().collect::<Vec<_>>(), vec![udp]);
+    }
+
+    #[test]
+    fn test_notify() {
+        let dir = std::env::temp_dir().join(format!("tcpstrip-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&dir);
+        let manager = UnixDatagram::bind(&dir).unwrap();
+        // Nothing else in the tests reads these
+        std::env::set_var("NOTIFY_SOCKET", &dir);
+        assert!(notify("READY=1\nSTATUS=Serving").unwrap());
+        let mut buf = [0u8; 64];
+        let n = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1\nSTATUS=Serving");
+        std::env::remove_var("NOTIFY_SOCKET");
+        assert!(!notify("READY=1").unwrap());
+        let _ = std::fs::remove_file(&dir);
+
+        std::env::set_var("WATCHDOG_USEC", "3000000");
+        std::env::set_var("WATCHDOG_PID", std::process::id().to_string());
+        assert_eq!(watchdog_interval(), Some(Duration::from_millis(1500)));
+        std::env::set_var("WATCHDOG_PID", "1");
+        assert_eq!(watchdog_interval(), None);
+        std::env::remove_var("WATCHDOG_USEC");
+        std::env::remove_var("WATCHDOG_PID");
+    }
+}
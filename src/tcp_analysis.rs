@@ -1,31 +1,34 @@
-/// TCP packet analysis and timestamp option handling
-/// 
-/// This module provides utilities for analyzing TCP packets and handling
-/// timestamp options as specified in RFC 7323. In HFT environments, TCP
-/// timestamps can leak sensitive timing information that reveals:
-/// 
-/// 1. Host timing characteristics:
-///    - CPU frequency scaling patterns
-///    - System load and performance variations
-///    - Kernel scheduling behavior
-/// 
-/// 2. Network timing patterns:
-///    - NIC interrupt coalescence settings
-///    - Network stack processing delays
-///    - Link-layer timing variations
-/// 
-/// 3. Security implications:
-///    - Host fingerprinting based on timestamp generation
-///    - Timing side-channel attacks
-///    - Covert channel establishment
-/// 
-/// References:
-/// - RFC 7323: TCP Extensions for High Performance
-/// - RFC 1323: TCP Extensions for High Performance (obsoleted by RFC 7323)
-/// - Linux kernel: net/ipv4/tcp_output.c (timestamp generation)
+//! TCP packet analysis and timestamp option handling
+//! 
+//! This module provides utilities for analyzing TCP packets and handling
+//! timestamp options as specified in RFC 7323. In HFT environments, TCP
+//! timestamps can leak sensitive timing information that reveals:
+//! 
+//! 1. Host timing characteristics:
+//!    - CPU frequency scaling patterns
+//!    - System load and performance variations
+//!    - Kernel scheduling behavior
+//! 
+//! 2. Network timing patterns:
+//!    - NIC interrupt coalescence settings
+//!    - Network stack processing delays
+//!    - Link-layer timing variations
+//! 
+//! 3. Security implications:
+//!    - Host fingerprinting based on timestamp generation
+//!    - Timing side-channel attacks
+//!    - Covert channel establishment
+//! 
+//! References:
+//! - RFC 7323: TCP Extensions for High Performance
+//! - RFC 1323: TCP Extensions for High Performance (obsoleted by RFC 7323)
+//! - Linux kernel: net/ipv4/tcp_output.c (timestamp generation)
 
 use tracing::{debug, warn};
 
+use crate::entropy::EntropySource;
+use crate::packet::{TCP_FLAG_ACK, TCP_FLAG_FIN, TCP_FLAG_PSH, TCP_FLAG_RST, TCP_FLAG_SYN, TCP_FLAG_URG};
+
 /// TCP option types as defined in RFC 793 and extensions
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -37,6 +40,8 @@ pub enum TcpOptionType {
     SackPermitted = 4,
     Sack = 5,
     Timestamp = 8,  // RFC 7323 - This is our primary concern
+    Mptcp = 30,     // RFC 8684 - multipath negotiation and addresses
+    FastOpen = 34,  // RFC 7413 - cookie identifies the client host
     Unknown(u8),
 }
 
@@ -50,6 +55,8 @@ impl From<u8> for TcpOptionType {
             4 => TcpOptionType::SackPermitted,
             5 => TcpOptionType::Sack,
             8 => TcpOptionType::Timestamp,
+            30 => TcpOptionType::Mptcp,
+            34 => TcpOptionType::FastOpen,
             other => TcpOptionType::Unknown(other),
         }
     }
@@ -83,6 +90,25 @@ pub struct TcpOption {
     pub data: Vec<u8>,
 }
 
+/// Kind of the shared experimental options (RFC 6994)
+const EXPERIMENTAL_OPTION: u8 = 254;
+/// ExID of Fast Open cookies sent as an experimental option, which Linux
+/// falls back to when the kind 34 option goes unanswered
+const FAST_OPEN_EXID: [u8; 2] = [0xf9, 0x89];
+
+impl TcpOption {
+    /// The option's kind, counting the experimental Fast Open encoding as
+    /// `FastOpen`
+    pub fn effective_kind(&self) -> TcpOptionType {
+        match self.kind {
+            TcpOptionType::Unknown(EXPERIMENTAL_OPTION) if self.data.starts_with(&FAST_OPEN_EXID) => {
+                TcpOptionType::FastOpen
+            }
+            kind => kind,
+        }
+    }
+}
+
 /// Results of TCP packet analysis
 #[derive(Debug, Clone)]
 pub struct TcpAnalysisResult {
@@ -101,6 +127,17 @@ pub enum FingerprintRisk {
     Critical, // Timestamp reveals clear system characteristics
 }
 
+impl std::fmt::Display for FingerprintRisk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FingerprintRisk::Low => write!(f, "low"),
+            FingerprintRisk::Medium => write!(f, "medium"),
+            FingerprintRisk::High => write!(f, "high"),
+            FingerprintRisk::Critical => write!(f, "critical"),
+        }
+    }
+}
+
 /// Parse TCP options from a packet
 /// 
 /// This function parses TCP options from the TCP header. In a real implementation,
@@ -178,6 +215,103 @@ pub fn extract_timestamp(option: &TcpOption) -> Option<TcpTimestamp> {
     Some(TcpTimestamp { ts_val, ts_ecr })
 }
 
+/// MPTCP option subtypes (RFC 8684, section 3)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MptcpSubtype {
+    MpCapable,
+    MpJoin,
+    Dss,
+    /// Advertises another address of the sender
+    AddAddr,
+    RemoveAddr,
+    MpPrio,
+    MpFail,
+    MpFastclose,
+    MpTcpRst,
+    Unknown(u8),
+}
+
+/// Extract the subtype from an MPTCP option (upper four bits of its first
+/// data byte)
+pub fn extract_mptcp_subtype(option: &TcpOption) -> Option<MptcpSubtype> {
+    if option.kind != TcpOptionType::Mptcp {
+        return None;
+    }
+    let subtype = match option.data.first()? >> 4 {
+        0 => MptcpSubtype::MpCapable,
+        1 => MptcpSubtype::MpJoin,
+        2 => MptcpSubtype::Dss,
+        3 => MptcpSubtype::AddAddr,
+        4 => MptcpSubtype::RemoveAddr,
+        5 => MptcpSubtype::MpPrio,
+        6 => MptcpSubtype::MpFail,
+        7 => MptcpSubtype::MpFastclose,
+        8 => MptcpSubtype::MpTcpRst,
+        other => MptcpSubtype::Unknown(other),
+    };
+    Some(subtype)
+}
+
+/// Flag combinations and header bits ordinary stacks do not send
+///
+/// Scanners and crafted packets use them to tell stacks apart, and old
+/// gateways have been known to mishandle them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlagAnomaly {
+    /// URG set, or an urgent pointer without it
+    Urgent,
+    /// Any of the three reserved header bits
+    ReservedBits,
+    /// SYN together with FIN or RST
+    SynWithFinOrRst,
+    /// No flags at all
+    NoFlags,
+    PshWithoutAck,
+    FinWithoutAck,
+}
+
+impl FlagAnomaly {
+    pub const ALL: [FlagAnomaly; 6] = [
+        FlagAnomaly::Urgent,
+        FlagAnomaly::ReservedBits,
+        FlagAnomaly::SynWithFinOrRst,
+        FlagAnomaly::NoFlags,
+        FlagAnomaly::PshWithoutAck,
+        FlagAnomaly::FinWithoutAck,
+    ];
+
+    fn present(self, flags: u8, reserved: u8, urgent_pointer: u16) -> bool {
+        let without_ack = |flag: u8| flags & flag != 0 && flags & TCP_FLAG_ACK == 0;
+        match self {
+            FlagAnomaly::Urgent => flags & TCP_FLAG_URG != 0 || urgent_pointer != 0,
+            FlagAnomaly::ReservedBits => reserved != 0,
+            FlagAnomaly::SynWithFinOrRst => flags & TCP_FLAG_SYN != 0 && flags & (TCP_FLAG_FIN | TCP_FLAG_RST) != 0,
+            FlagAnomaly::NoFlags => flags == 0,
+            FlagAnomaly::PshWithoutAck => without_ack(TCP_FLAG_PSH),
+            FlagAnomaly::FinWithoutAck => without_ack(TCP_FLAG_FIN),
+        }
+    }
+}
+
+impl std::fmt::Display for FlagAnomaly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FlagAnomaly::Urgent => write!(f, "urgent"),
+            FlagAnomaly::ReservedBits => write!(f, "reserved_bits"),
+            FlagAnomaly::SynWithFinOrRst => write!(f, "syn_with_fin_or_rst"),
+            FlagAnomaly::NoFlags => write!(f, "no_flags"),
+            FlagAnomaly::PshWithoutAck => write!(f, "psh_without_ack"),
+            FlagAnomaly::FinWithoutAck => write!(f, "fin_without_ack"),
+        }
+    }
+}
+
+/// Anomalies in a segment's flags byte, reserved bits (as they sit in the
+/// header) and urgent pointer
+pub fn flag_anomalies(flags: u8, reserved: u8, urgent_pointer: u16) -> impl Iterator<Item = FlagAnomaly> {
+    FlagAnomaly::ALL.into_iter().filter(move |anomaly| anomaly.present(flags, reserved, urgent_pointer))
+}
+
 /// Analyze TCP packet for timestamp options and fingerprinting risks
 pub fn analyze_tcp_packet(options_data: &[u8]) -> TcpAnalysisResult {
     let options = parse_tcp_options(options_data);
@@ -235,13 +369,13 @@ fn assess_timestamp_risk(ts: TcpTimestamp) -> FingerprintRisk {
     // Linux systems often use 100Hz, 250Hz, 1000Hz tick rates
     let common_hz_values = [100, 250, 300, 1000];
     for &hz in &common_hz_values {
-        if ts_val % hz == 0 {
+        if ts_val.is_multiple_of(hz) {
             return FingerprintRisk::High;
         }
     }
     
     // Check for suspiciously regular patterns
-    if ts_val % 1000 == 0 {
+    if ts_val.is_multiple_of(1000) {
         return FingerprintRisk::Medium;
     }
     
@@ -262,9 +396,16 @@ fn assess_timestamp_risk(ts: TcpTimestamp) -> FingerprintRisk {
 /// 1. Use randomized increments to avoid predictable patterns
 /// 2. Avoid values that align with common system tick rates
 /// 3. Maintain temporal consistency within connections
-pub fn generate_spoofed_timestamp(base_time: u32, increment: u32) -> TcpTimestamp {
+///
+/// The randomization comes from the caller's entropy source, so production
+/// can use the OS CSPRNG while tests use a seeded, reproducible stream.
+pub fn generate_spoofed_timestamp(
+    base_time: u32,
+    increment: u32,
+    entropy: &mut dyn EntropySource,
+) -> TcpTimestamp {
     // Generate timestamp with some randomization to avoid patterns
-    let random_offset = (base_time.wrapping_mul(1103515245).wrapping_add(12345)) % 1000;
+    let random_offset = entropy.next_u32() % 1000;
     let spoofed_ts_val = base_time.wrapping_add(increment).wrapping_add(random_offset);
     
     TcpTimestamp {
@@ -278,44 +419,209 @@ pub fn generate_spoofed_timestamp(base_time: u32, increment: u32) -> TcpTimestam
 /// This function reconstructs TCP options with the timestamp option removed.
 /// It preserves all other options and maintains proper padding.
 pub fn strip_timestamp_option(original_options: &[u8]) -> Vec<u8> {
-    let options = parse_tcp_options(original_options);
+    let policy = OptionPolicy {
+        strip: vec![TcpOptionType::Timestamp],
+        strip_unknown: false,
+        canonical: false,
+    };
+    normalize_options(original_options, &policy)
+}
+
+/// How `normalize_options` rebuilds a segment's options
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OptionPolicy {
+    /// Option kinds to remove
+    pub strip: Vec<TcpOptionType>,
+    /// Also remove every option of a kind the parser does not know
+    /// (`TcpOptionType::Unknown`)
+    pub strip_unknown: bool,
+    /// Lay the remaining options out canonically instead of keeping the
+    /// sender's order and padding
+    pub canonical: bool,
+}
+
+impl OptionPolicy {
+    /// Whether the policy can change anything at all
+    pub fn is_noop(&self) -> bool {
+        self.strip.is_empty() && !self.strip_unknown && !self.canonical
+    }
+
+    fn strips(&self, kind: TcpOptionType) -> bool {
+        self.strip.contains(&kind) || (self.strip_unknown && matches!(kind, TcpOptionType::Unknown(_)))
+    }
+}
+
+/// Canonical option order; anything else follows in the sender's order
+const CANONICAL_ORDER: [TcpOptionType; 5] = [
+    TcpOptionType::MaximumSegmentSize,
+    TcpOptionType::SackPermitted,
+    TcpOptionType::Timestamp,
+    TcpOptionType::WindowScale,
+    TcpOptionType::Sack,
+];
+
+/// Create TCP option bytes according to `policy`
+///
+/// Stripped options are dropped. Without `canonical` everything else keeps
+/// its place and the result is padded with EOL. In canonical form the
+/// options are sorted into `CANONICAL_ORDER` and each is preceded by the
+/// NOPs that align it to four bytes, the way Linux lays out its SYNs:
+///
+/// ```text
+/// mss | nop nop sackOK | nop nop ts | nop ws | nop nop sack
+/// ```
+///
+/// so the layout no longer depends on the sender's OS. If aligning would
+/// not fit the 40 option bytes, the options are packed without NOPs and
+/// padded with EOL instead.
+pub fn normalize_options(original_options: &[u8], policy: &OptionPolicy) -> Vec<u8> {
+    let mut options: Vec<TcpOption> = parse_tcp_options(original_options)
+        .into_iter()
+        .filter(|option| !policy.strips(option.effective_kind()))
+        .collect();
+
     let mut result = Vec::new();
-    
-    for option in options {
-        if option.kind != TcpOptionType::Timestamp {
-            // Keep non-timestamp options
-            let kind_byte = match option.kind {
-                TcpOptionType::EndOfOptionList => 0,
-                TcpOptionType::NoOperation => 1,
-                TcpOptionType::MaximumSegmentSize => 2,
-                TcpOptionType::WindowScale => 3,
-                TcpOptionType::SackPermitted => 4,
-                TcpOptionType::Sack => 5,
-                TcpOptionType::Timestamp => 8,
-                TcpOptionType::Unknown(val) => val,
-            };
-            result.push(kind_byte);
-            
-            match option.kind {
-                TcpOptionType::EndOfOptionList | TcpOptionType::NoOperation => {
-                    // These options don't have length or data fields
-                }
-                _ => {
-                    result.push(option.length);
-                    result.extend_from_slice(&option.data);
-                }
+    if policy.canonical {
+        options.retain(|option| option.kind != TcpOptionType::NoOperation);
+        // Stable, so unknown options keep their relative order
+        options.sort_by_key(|option| {
+            CANONICAL_ORDER.iter().position(|&kind| kind == option.kind).unwrap_or(CANONICAL_ORDER.len())
+        });
+
+        for option in &options {
+            let padding = (4 - (option.length as usize % 4)) % 4;
+            result.extend(std::iter::repeat_n(1, padding));
+            push_option(&mut result, option);
+        }
+        if result.len() > 40 {
+            result.clear();
+            for option in &options {
+                push_option(&mut result, option);
             }
         }
+    } else {
+        for option in &options {
+            push_option(&mut result, option);
+        }
     }
     
     // Pad to 4-byte boundary if necessary
-    while result.len() % 4 != 0 {
+    while !result.len().is_multiple_of(4) {
         result.push(0); // End of option list padding
     }
     
     result
 }
 
+/// Create TCP option bytes laid out like one of `layouts`
+///
+/// Each layout lists option kinds and NOPs in the order an OS sends them.
+/// The first layout whose options are all present in `original_options` is
+/// followed exactly; if there is none, the first layout is followed with
+/// the missing options, and the NOPs that would have preceded them, left
+/// out. Options no layout mentions are dropped, and the result is padded
+/// with EOL.
+pub fn apply_layout(original_options: &[u8], layouts: &[&[TcpOptionType]]) -> Vec<u8> {
+    let options = parse_tcp_options(original_options);
+    let present = |kind: TcpOptionType| options.iter().find(|option| option.kind == kind);
+    let layout = layouts
+        .iter()
+        .find(|layout| layout.iter().all(|&kind| kind == TcpOptionType::NoOperation || present(kind).is_some()))
+        .or(layouts.first())
+        .map_or(&[][..], |layout| *layout);
+
+    let mut result = Vec::new();
+    let mut nops = 0;
+    for &kind in layout {
+        if kind == TcpOptionType::NoOperation {
+            nops += 1;
+            continue;
+        }
+        if let Some(option) = present(kind) {
+            result.extend(std::iter::repeat_n(1, nops));
+            push_option(&mut result, option);
+        }
+        nops = 0;
+    }
+    while !result.len().is_multiple_of(4) {
+        result.push(0);
+    }
+    result
+}
+
+fn push_option(result: &mut Vec<u8>, option: &TcpOption) {
+    let kind_byte = match option.kind {
+        TcpOptionType::EndOfOptionList => 0,
+        TcpOptionType::NoOperation => 1,
+        TcpOptionType::MaximumSegmentSize => 2,
+        TcpOptionType::WindowScale => 3,
+        TcpOptionType::SackPermitted => 4,
+        TcpOptionType::Sack => 5,
+        TcpOptionType::Timestamp => 8,
+        TcpOptionType::Mptcp => 30,
+        TcpOptionType::FastOpen => 34,
+        TcpOptionType::Unknown(val) => val,
+    };
+    result.push(kind_byte);
+    
+    match option.kind {
+        TcpOptionType::EndOfOptionList | TcpOptionType::NoOperation => {
+            // These options don't have length or data fields
+        }
+        _ => {
+            result.push(option.length);
+            result.extend_from_slice(&option.data);
+        }
+    }
+}
+
+/// Create TCP option bytes with the timestamp value replaced
+///
+/// Unlike stripping, this keeps the option layout intact so the segment
+/// length does not change. Only TSval is rewritten; TSecr still carries
+/// whatever the peer sent. Returns `None` if there is no timestamp option.
+pub fn spoof_timestamp_option(original_options: &[u8], ts_val: u32) -> Option<Vec<u8>> {
+    replace_timestamp_field(original_options, 2, ts_val)
+}
+
+/// Create TCP option bytes with the timestamp echo reply replaced
+///
+/// Used on the return path of spoofed connections: the peer echoes the
+/// spoofed TSval, which has to be mapped back to the value our host sent
+/// before the host sees it.
+pub fn echo_timestamp_option(original_options: &[u8], ts_ecr: u32) -> Option<Vec<u8>> {
+    replace_timestamp_field(original_options, 6, ts_ecr)
+}
+
+/// Overwrite the 32-bit field at `field_offset` within the timestamp option
+fn replace_timestamp_field(original_options: &[u8], field_offset: usize, value: u32) -> Option<Vec<u8>> {
+    let mut pos = 0;
+
+    while pos < original_options.len() {
+        match TcpOptionType::from(original_options[pos]) {
+            TcpOptionType::EndOfOptionList => break,
+            TcpOptionType::NoOperation => pos += 1,
+            kind => {
+                let length = *original_options.get(pos + 1)? as usize;
+                if length < 2 || pos + length > original_options.len() {
+                    return None;
+                }
+
+                if kind == TcpOptionType::Timestamp && length == 10 {
+                    let mut result = original_options.to_vec();
+                    let field = pos + field_offset;
+                    result[field..field + 4].copy_from_slice(&value.to_be_bytes());
+                    return Some(result);
+                }
+
+                pos += length;
+            }
+        }
+    }
+
+    None
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -356,4 +662,107 @@ mod tests {
         assert_eq!(options[0].kind, TcpOptionType::MaximumSegmentSize);
         assert_eq!(options[1].kind, TcpOptionType::NoOperation);
     }
+
+    #[test]
+    fn test_canonical_layout() {
+        let canonical = OptionPolicy {
+            strip: vec![TcpOptionType::Timestamp],
+            strip_unknown: false,
+            canonical: true,
+        };
+        let expected = [2, 4, 0x05, 0xb4, 1, 1, 4, 2, 1, 3, 3, 7];
+
+        // Linux: mss, sackOK, ts, nop, ws
+        let linux = [2, 4, 0x05, 0xb4, 4, 2, 8, 10, 0, 0, 0, 1, 0, 0, 0, 0, 1, 3, 3, 7];
+        // Windows: mss, nop, ws, nop, nop, sackOK
+        let windows = [2, 4, 0x05, 0xb4, 1, 3, 3, 7, 1, 1, 4, 2];
+        // macOS: mss, nop, ws, nop, nop, ts, sackOK, eol
+        let macos = [2, 4, 0x05, 0xb4, 1, 3, 3, 7, 1, 1, 8, 10, 0, 0, 0, 1, 0, 0, 0, 0, 4, 2, 0, 0];
+
+        assert_eq!(normalize_options(&linux, &canonical), expected);
+        assert_eq!(normalize_options(&windows, &canonical), expected);
+        assert_eq!(normalize_options(&macos, &canonical), expected);
+    }
+
+    #[test]
+    fn test_apply_layout() {
+        use TcpOptionType::*;
+        let layouts: [&[TcpOptionType]; 2] = [
+            &[MaximumSegmentSize, SackPermitted, Timestamp, NoOperation, WindowScale],
+            &[MaximumSegmentSize, NoOperation, NoOperation, SackPermitted, NoOperation, WindowScale],
+        ];
+
+        // Windows: mss, nop, ws, nop, nop, sackOK
+        let windows = [2, 4, 0x05, 0xb4, 1, 3, 3, 8, 1, 1, 4, 2];
+        assert_eq!(apply_layout(&windows, &layouts), [2, 4, 0x05, 0xb4, 1, 1, 4, 2, 1, 3, 3, 8]);
+        // macOS, timestamps included: mss, nop, ws, nop, nop, ts, sackOK, eol
+        let macos = [2, 4, 0x05, 0xb4, 1, 3, 3, 6, 1, 1, 8, 10, 0, 0, 0, 1, 0, 0, 0, 0, 4, 2, 0, 0];
+        assert_eq!(
+            apply_layout(&macos, &layouts),
+            [2, 4, 0x05, 0xb4, 4, 2, 8, 10, 0, 0, 0, 1, 0, 0, 0, 0, 1, 3, 3, 6]
+        );
+        // Neither layout fits without sackOK; a TFO cookie request is dropped
+        assert_eq!(apply_layout(&[2, 4, 0x05, 0xb4, 34, 2, 1, 3, 3, 8], &layouts), [2, 4, 0x05, 0xb4, 1, 3, 3, 8]);
+    }
+
+    #[test]
+    fn test_strip_unknown_options() {
+        let policy = OptionPolicy {
+            strip: Vec::new(),
+            strip_unknown: true,
+            canonical: false,
+        };
+        // mss, experimental 253, user timeout, TFO request as experimental 254, nop, ws
+        let syn = [2, 4, 0x05, 0xb4, 253, 4, 0xab, 0xcd, 28, 4, 0, 60, 254, 4, 0xf9, 0x89, 1, 3, 3, 7];
+        assert_eq!(normalize_options(&syn, &policy), [2, 4, 0x05, 0xb4, 254, 4, 0xf9, 0x89, 1, 3, 3, 7]);
+    }
+
+    #[test]
+    fn test_strip_fast_open_cookie() {
+        let policy = OptionPolicy {
+            strip: vec![TcpOptionType::FastOpen],
+            strip_unknown: false,
+            canonical: false,
+        };
+        // mss, sackOK, nop, nop, TFO cookie (8 bytes), nop, ws
+        let syn = [2, 4, 0x05, 0xb4, 4, 2, 1, 1, 34, 10, 1, 2, 3, 4, 5, 6, 7, 8, 1, 3, 3, 7];
+        assert_eq!(normalize_options(&syn, &policy), [2, 4, 0x05, 0xb4, 4, 2, 1, 1, 1, 3, 3, 7]);
+        // A cookie request is an empty option
+        assert_eq!(normalize_options(&[2, 4, 0x05, 0xb4, 34, 2], &policy), [2, 4, 0x05, 0xb4]);
+        // The experimental encoding, but not other experimental options
+        let experimental = [254, 4, 0xf9, 0x89, 254, 4, 0x12, 0x34];
+        assert_eq!(normalize_options(&experimental, &policy), [254, 4, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn test_flag_anomalies() {
+        let anomalies = |flags, reserved, urgent| flag_anomalies(flags, reserved, urgent).collect::<Vec<_>>();
+        assert!(anomalies(TCP_FLAG_SYN, 0, 0).is_empty());
+        assert!(anomalies(TCP_FLAG_PSH | TCP_FLAG_ACK, 0, 0).is_empty());
+        // nmap's "Xmas" probe
+        assert_eq!(
+            anomalies(TCP_FLAG_FIN | TCP_FLAG_PSH | TCP_FLAG_URG, 0, 0),
+            [FlagAnomaly::Urgent, FlagAnomaly::PshWithoutAck, FlagAnomaly::FinWithoutAck]
+        );
+        assert_eq!(
+            anomalies(TCP_FLAG_SYN | TCP_FLAG_FIN, 0x02, 0),
+            [FlagAnomaly::ReservedBits, FlagAnomaly::SynWithFinOrRst, FlagAnomaly::FinWithoutAck]
+        );
+        assert_eq!(anomalies(0, 0, 7), [FlagAnomaly::Urgent, FlagAnomaly::NoFlags]);
+    }
+
+    #[test]
+    fn test_mptcp_options() {
+        // mss, sackOK, MP_CAPABLE v1 (flags 0x81), nop, ws
+        let syn = [2, 4, 0x05, 0xb4, 4, 2, 30, 4, 0x01, 0x81, 1, 3, 3, 7];
+        let subtypes: Vec<_> = parse_tcp_options(&syn).iter().filter_map(extract_mptcp_subtype).collect();
+        assert_eq!(subtypes, [MptcpSubtype::MpCapable]);
+
+        let policy = OptionPolicy {
+            strip: vec![TcpOptionType::Mptcp],
+            strip_unknown: false,
+            canonical: false,
+        };
+        assert_eq!(normalize_options(&syn, &policy), [2, 4, 0x05, 0xb4, 4, 2, 1, 3, 3, 7, 0, 0]);
+    }
 } 
\ No newline at end of file
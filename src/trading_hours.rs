@@ -0,0 +1,371 @@
+//! Trading-hours accept schedule (`--trading-hours`)
+//!
+//! Sessions to the exchange should only be opened while the desk is
+//! trading. With windows such as `Mon-Fri 09:30-16:00` in a time zone
+//! (`--trading-timezone`, so the schedule follows the venue's daylight
+//! saving), connections accepted outside every window are reset straight
+//! away. What happens to sessions still open when the last window closes
+//! is up to `Outside`:
+//!
+//! - `reject` leaves them be; only new connections are refused
+//! - `drain` closes them: both legs stop reading, what the proxy already
+//!   read is delivered, and each side gets a FIN
+//!
+//! The schedule is evaluated once a second. `tcpstrip_trading_hours_open`
+//! is 1 inside a window; refused connections are counted in
+//! `tcpstrip_trading_hours_refused_total` and drained ones in
+//! `tcpstrip_trading_hours_drained_total`.
+//!
+//! The same windows declare the venue's maintenance (`--maintenance`):
+//! alerts raised inside one (a target failing its health checks, a backend
+//! answering with other TCP options, a new upstream certificate chain) are
+//! expected then, so they are logged at info instead of as warnings and
+//! counted with `window="maintenance"` rather than `window="unexpected"`.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::metrics::{self, Metric};
+use crate::tz::Zone;
+
+const DAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// How often the schedule is evaluated
+const TICK: Duration = Duration::from_secs(1);
+
+/// One `--trading-hours` window: `DAYS HH:MM-HH:MM` in local time, DAYS a
+/// comma-separated list of days (`Mon`) and ranges (`Mon-Fri`, `Sun-Thu`).
+/// A window that ends at or before its start runs past midnight into the
+/// next day, e.g. `Sun-Thu 18:00-17:00` for futures sessions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    /// Bit 0 for Monday through bit 6 for Sunday: days the window opens on
+    days: u8,
+    /// Minutes after midnight
+    start: u16,
+    end: u16,
+}
+
+impl Window {
+    /// Whether the window is open at `minute` of `weekday` (0 = Monday)
+    fn contains(&self, weekday: u8, minute: u16) -> bool {
+        let opens_on = |day: u8| self.days & (1 << day) != 0;
+        match self.start < self.end {
+            true => opens_on(weekday) && (self.start..self.end).contains(&minute),
+            false => (opens_on(weekday) && minute >= self.start) || (opens_on((weekday + 6) % 7) && minute < self.end),
+        }
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let days: Vec<_> = (0..7).filter(|day| self.days & (1 << day) != 0).map(|day| DAYS[day]).collect();
+        write!(
+            f,
+            "{} {:02}:{:02}-{:02}:{:02}",
+            days.join(","),
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid trading hours '{}' (expected DAYS HH:MM-HH:MM, e.g. Mon-Fri 09:30-16:00)", s);
+        let (days, hours) = s.trim().split_once(' ').ok_or_else(invalid)?;
+        let day = |name: &str| DAYS.iter().position(|day| day.eq_ignore_ascii_case(name)).ok_or_else(invalid);
+        let mut mask = 0u8;
+        for part in days.split(',') {
+            let (first, last) = match part.split_once('-') {
+                Some((first, last)) => (day(first)?, day(last)?),
+                None => (day(part)?, day(part)?),
+            };
+            // Ranges may wrap around the weekend
+            let mut day = first;
+            loop {
+                mask |= 1 << day;
+                if day == last {
+                    break;
+                }
+                day = (day + 1) % 7;
+            }
+        }
+        let minute = |time: &str| {
+            let (hours, minutes) = time.trim().split_once(':').filter(|(_, minutes)| minutes.len() == 2).ok_or_else(invalid)?;
+            match (hours.parse::<u16>(), minutes.parse::<u16>()) {
+                (Ok(hours), Ok(minutes)) if hours < 24 && minutes < 60 => Ok(hours * 60 + minutes),
+                // 24:00 for the end of a day
+                (Ok(24), Ok(0)) => Ok(24 * 60),
+                _ => Err(invalid()),
+            }
+        };
+        let (start, end) = hours.trim().split_once('-').ok_or_else(invalid)?;
+        let (start, end) = (minute(start)?, minute(end)?);
+        if start == 24 * 60 {
+            return Err(invalid());
+        }
+        Ok(Self { days: mask, start, end })
+    }
+}
+
+/// Windows of local time in a zone; also what `--maintenance` is made of
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    zone: Zone,
+    windows: Vec<Window>,
+}
+
+impl Schedule {
+    pub fn new(zone: Zone, windows: Vec<Window>) -> Self {
+        Self { zone, windows }
+    }
+
+    pub fn zone(&self) -> &Zone {
+        &self.zone
+    }
+
+    pub fn windows(&self) -> &[Window] {
+        &self.windows
+    }
+
+    /// Whether a window is open at `unix` (seconds since the epoch)
+    pub fn contains(&self, unix: i64) -> bool {
+        let local = unix + self.zone.offset_at(unix) as i64;
+        let days = local.div_euclid(86400);
+        // 1970-01-01 was a Thursday
+        let weekday = (days + 3).rem_euclid(7) as u8;
+        let minute = (local.rem_euclid(86400) / 60) as u16;
+        self.windows.iter().any(|window| window.contains(weekday, minute))
+    }
+
+    /// Whether a window is open now
+    pub fn contains_now(&self) -> bool {
+        self.contains(now())
+    }
+}
+
+/// Whether an alert falls into a `--maintenance` window, its `window` label
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlertWindow {
+    Maintenance,
+    Unexpected,
+}
+
+impl AlertWindow {
+    /// The window of an alert raised at `unix` under `maintenance`
+    pub fn at(maintenance: Option<&Schedule>, unix: i64) -> Self {
+        match maintenance.is_some_and(|schedule| schedule.contains(unix)) {
+            true => AlertWindow::Maintenance,
+            false => AlertWindow::Unexpected,
+        }
+    }
+
+    /// The window of an alert raised now
+    pub fn now(maintenance: Option<&Schedule>) -> Self {
+        Self::at(maintenance, now())
+    }
+}
+
+impl fmt::Display for AlertWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AlertWindow::Maintenance => write!(f, "maintenance"),
+            AlertWindow::Unexpected => write!(f, "unexpected"),
+        }
+    }
+}
+
+/// What happens to open sessions once the schedule closes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Outside {
+    #[default]
+    Reject,
+    Drain,
+}
+
+impl fmt::Display for Outside {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outside::Reject => write!(f, "reject"),
+            Outside::Drain => write!(f, "drain"),
+        }
+    }
+}
+
+impl FromStr for Outside {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Outside::Reject),
+            "drain" => Ok(Outside::Drain),
+            _ => Err(format!("unknown action '{}' (expected reject or drain)", s)),
+        }
+    }
+}
+
+/// The schedule and whether it is open now
+#[derive(Debug)]
+pub struct TradingHours {
+    schedule: Schedule,
+    outside: Outside,
+    open: watch::Sender<bool>,
+    gauge: Arc<Metric>,
+    refused: Arc<Metric>,
+    drained: Arc<Metric>,
+}
+
+impl TradingHours {
+    pub fn new(zone: Zone, windows: Vec<Window>, outside: Outside) -> Arc<Self> {
+        let registry = metrics::registry();
+        let hours = Self {
+            schedule: Schedule::new(zone, windows),
+            outside,
+            open: watch::Sender::new(false),
+            gauge: registry.gauge("tcpstrip_trading_hours_open", "1 while a --trading-hours window is open"),
+            refused: registry.counter("tcpstrip_trading_hours_refused_total", "Connections reset on accept outside --trading-hours"),
+            drained: registry.counter("tcpstrip_trading_hours_drained_total", "Sessions closed as --trading-hours closed"),
+        };
+        hours.set_open(hours.is_open_at(now()));
+        Arc::new(hours)
+    }
+
+    pub fn zone(&self) -> &Zone {
+        self.schedule.zone()
+    }
+
+    pub fn windows(&self) -> &[Window] {
+        self.schedule.windows()
+    }
+
+    pub fn outside(&self) -> Outside {
+        self.outside
+    }
+
+    /// Whether a window is open at `unix` (seconds since the epoch)
+    pub fn is_open_at(&self, unix: i64) -> bool {
+        self.schedule.contains(unix)
+    }
+
+    /// Whether a window is open, as of the last evaluation
+    pub fn is_open(&self) -> bool {
+        *self.open.borrow()
+    }
+
+    fn set_open(&self, open: bool) -> bool {
+        self.gauge.set(open.into());
+        self.open.send_replace(open) != open
+    }
+
+    /// Evaluate the schedule every second, for as long as the proxy runs
+    pub async fn watch(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(TICK);
+        loop {
+            ticker.tick().await;
+            let open = self.is_open_at(now());
+            if self.set_open(open) {
+                match (open, self.outside) {
+                    (true, _) => info!("Trading hours open: accepting connections"),
+                    (false, Outside::Reject) => warn!("Trading hours closed: refusing new connections"),
+                    (false, Outside::Drain) => warn!("Trading hours closed: refusing new connections and draining open sessions"),
+                }
+            }
+        }
+    }
+
+    pub fn count_refused(&self) {
+        self.refused.inc();
+    }
+
+    pub fn count_drained(&self) {
+        self.drained.inc();
+    }
+}
+
+/// Resolves once `hours` close with `drain`; never without a schedule
+/// or with `reject`
+pub async fn draining(hours: Option<&TradingHours>) {
+    match hours.filter(|hours| hours.outside == Outside::Drain) {
+        Some(hours) => {
+            let mut open = hours.open.subscribe();
+            let _ = open.wait_for(|open| !open).await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tz::days_from_civil;
+
+    fn unix(year: i64, month: u32, day: u32, hour: i64, minute: i64) -> i64 {
+        days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60
+    }
+
+    #[test]
+    fn test_parse_windows() {
+        let window: Window = "Mon-Fri 09:30-16:00".parse().unwrap();
+        assert_eq!(window.to_string(), "Mon,Tue,Wed,Thu,Fri 09:30-16:00");
+        assert_eq!("sun-tue,Fri 18:00-24:00".parse::<Window>().unwrap().to_string(), "Mon,Tue,Fri,Sun 18:00-24:00");
+        for invalid in ["Mon-Fri", "Mon-Fri 9-16", "Weekdays 09:30-16:00", "Mon 24:00-01:00", "Mon 09:60-10:00", "Mon 09:030-10:00"] {
+            assert!(invalid.parse::<Window>().is_err(), "{}", invalid);
+        }
+        assert_eq!("drain".parse(), Ok(Outside::Drain));
+    }
+
+    #[test]
+    fn test_schedule() {
+        let new_york = Zone::load("EST5EDT,M3.2.0,M11.1.0").unwrap();
+        let hours = TradingHours::new(new_york, vec!["Mon-Fri 09:30-16:00".parse().unwrap()], Outside::Reject);
+        // Wednesday 2024-07-03, EDT (UTC-4)
+        assert!(!hours.is_open_at(unix(2024, 7, 3, 13, 29)));
+        assert!(hours.is_open_at(unix(2024, 7, 3, 13, 30)));
+        assert!(!hours.is_open_at(unix(2024, 7, 3, 20, 0)));
+        // Wednesday 2024-01-03, EST (UTC-5)
+        assert!(!hours.is_open_at(unix(2024, 1, 3, 14, 29)));
+        assert!(hours.is_open_at(unix(2024, 1, 3, 14, 30)));
+        // Saturday
+        assert!(!hours.is_open_at(unix(2024, 7, 6, 15, 0)));
+
+        // Overnight: Sunday 18:00 to Friday 17:00, closed an hour each day
+        let futures = TradingHours::new(Zone::utc(), vec!["Sun-Thu 18:00-17:00".parse().unwrap()], Outside::Drain);
+        assert!(futures.is_open_at(unix(2024, 7, 7, 18, 0)));
+        assert!(futures.is_open_at(unix(2024, 7, 8, 16, 59)));
+        assert!(!futures.is_open_at(unix(2024, 7, 8, 17, 30)));
+        assert!(futures.is_open_at(unix(2024, 7, 12, 16, 0)));
+        assert!(!futures.is_open_at(unix(2024, 7, 12, 18, 0)));
+        assert!(!futures.is_open_at(unix(2024, 7, 13, 12, 0)));
+
+        // Saturday mornings in New York, 10:00-14:00 UTC in July
+        let maintenance = Schedule::new(Zone::load("EST5EDT,M3.2.0,M11.1.0").unwrap(), vec!["Sat 06:00-10:00".parse().unwrap()]);
+        assert_eq!(AlertWindow::at(Some(&maintenance), unix(2024, 7, 6, 11, 0)), AlertWindow::Maintenance);
+        assert_eq!(AlertWindow::at(Some(&maintenance), unix(2024, 7, 6, 14, 0)), AlertWindow::Unexpected);
+        assert_eq!(AlertWindow::at(None, unix(2024, 7, 6, 11, 0)).to_string(), "unexpected");
+    }
+
+    #[tokio::test]
+    async fn test_draining() {
+        let hours = TradingHours::new(Zone::utc(), vec![], Outside::Reject);
+        assert!(!hours.is_open());
+        let pending = tokio::time::timeout(Duration::from_millis(20), draining(Some(&hours))).await;
+        assert!(pending.is_err());
+
+        let hours = TradingHours::new(Zone::utc(), vec![], Outside::Drain);
+        tokio::time::timeout(Duration::from_secs(1), draining(Some(&hours))).await.unwrap();
+    }
+}
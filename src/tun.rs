@@ -0,0 +1,259 @@
+//! TUN inline scrubbing datapath (Linux only)
+//!
+//! In TUN mode tcpstrip behaves like an extra L3 hop: the operator routes
+//! the traffic to be scrubbed into a TUN device (typically with a policy
+//! routing rule), we read the IP packets, rewrite their TCP options and
+//! re-inject them into the kernel through a raw IP socket so they continue
+//! towards their real destination.
+//!
+//! Re-injected packets carry a firewall mark so that the routing rule
+//! steering traffic into the TUN device can exclude them, e.g.:
+//!
+//! ```text
+//! ip addr add 169.254.77.1/30 dev tcpstrip0
+//! ip route add default dev tcpstrip0 table 77
+//! ip rule add to 203.0.113.0/24 not fwmark 0x7473 table 77
+//! ```
+//!
+//! Requires CAP_NET_ADMIN (to create the device and set SO_MARK) and
+//! CAP_NET_RAW (for the raw sockets).
+
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+
+use tracing::{info, warn};
+
+use crate::datapath::{self, PacketBackend};
+use crate::entropy::EntropyConfig;
+use crate::packet::{ScrubStats, TimestampAction};
+use crate::scrub::{ScrubPolicy, Scrubber};
+
+/// TUN datapath configuration
+#[derive(Debug, Clone)]
+pub struct TunConfig {
+    /// Name of the TUN device to create or attach to
+    pub name: String,
+    /// Firewall mark applied to re-injected packets
+    pub fwmark: u32,
+    /// Scrubbing applied to packets routed through the device
+    pub policy: ScrubPolicy,
+    /// Randomness for spoofed timestamps
+    pub entropy: EntropyConfig,
+    /// Size of the packet read buffer
+    pub packet_buffer_size: usize,
+}
+
+/// Raw IPv4 and IPv6 sockets used to hand packets back to the kernel
+struct Reinjector {
+    v4: OwnedFd,
+    v6: OwnedFd,
+}
+
+impl Reinjector {
+    fn open(fwmark: u32) -> io::Result<Self> {
+        Ok(Self {
+            v4: open_raw_socket(libc::AF_INET, fwmark)?,
+            v6: open_raw_socket(libc::AF_INET6, fwmark)?,
+        })
+    }
+
+    /// Send a complete IP packet (header included) back into the stack
+    fn send(&self, packet: &[u8]) -> io::Result<()> {
+        let rc = match packet.first().map(|b| b >> 4) {
+            Some(4) if packet.len() >= 20 => {
+                let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+                let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+                addr.sin_family = libc::AF_INET as libc::sa_family_t;
+                addr.sin_addr.s_addr = u32::from(dst).to_be();
+                unsafe {
+                    libc::sendto(
+                        self.v4.as_raw_fd(),
+                        packet.as_ptr() as *const libc::c_void,
+                        packet.len(),
+                        0,
+                        &addr as *const _ as *const libc::sockaddr,
+                        mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                    )
+                }
+            }
+            Some(6) if packet.len() >= 40 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&packet[24..40]);
+                let mut addr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+                addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                addr.sin6_addr.s6_addr = Ipv6Addr::from(octets).octets();
+                unsafe {
+                    libc::sendto(
+                        self.v6.as_raw_fd(),
+                        packet.as_ptr() as *const libc::c_void,
+                        packet.len(),
+                        0,
+                        &addr as *const _ as *const libc::sockaddr,
+                        mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                    )
+                }
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "not an IP packet")),
+        };
+
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// The TUN device plus the sockets its packets leave through
+struct TunBackend {
+    name: String,
+    device: File,
+    reinjector: Reinjector,
+}
+
+impl PacketBackend for TunBackend {
+    type Origin = ();
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<(usize, ())> {
+        Ok((self.device.read(buf)?, ()))
+    }
+
+    fn reinject(&mut self, packet: &[u8], _origin: &()) -> io::Result<()> {
+        self.reinjector.send(packet)
+    }
+}
+
+/// An opened TUN device, ready to run
+pub struct TunScrubber {
+    config: TunConfig,
+    backend: TunBackend,
+    scrubbers: (Scrubber, Scrubber),
+    stats: Arc<ScrubStats>,
+}
+
+impl TunScrubber {
+    /// Create (or attach to) the TUN device and open the re-injection sockets
+    pub fn open(config: TunConfig) -> io::Result<Self> {
+        let device = open_tun(&config.name)?;
+        set_link_up(&config.name)?;
+        let reinjector = Reinjector::open(config.fwmark)?;
+        let scrubbers = Scrubber::pair(config.policy.clone(), &config.entropy)?;
+
+        Ok(Self {
+            backend: TunBackend {
+                name: config.name.clone(),
+                device,
+                reinjector,
+            },
+            config,
+            scrubbers,
+            stats: Arc::new(ScrubStats::default()),
+        })
+    }
+
+    pub fn stats(&self) -> Arc<ScrubStats> {
+        self.stats.clone()
+    }
+
+    /// Scrub packets until reading from the device fails
+    pub fn run(mut self) -> io::Result<()> {
+        info!(
+            "Scrubbing packets routed into {} (fwmark {:#x}, timestamps: {:?})",
+            self.config.name, self.config.fwmark, self.config.policy.timestamps
+        );
+        if matches!(self.config.policy.timestamps, TimestampAction::Spoof(_) | TimestampAction::Randomize) {
+            // Return traffic is delivered locally without entering the device
+            warn!(
+                "{}: peers echo spoofed timestamps that cannot be translated back; prefer stripping in TUN mode",
+                self.config.name
+            );
+        }
+
+        datapath::run(
+            &mut self.backend,
+            &mut self.scrubbers,
+            self.config.packet_buffer_size,
+            &self.stats,
+        )
+    }
+}
+
+fn ifreq_for(name: &str) -> io::Result<libc::ifreq> {
+    let c_name = CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"))?;
+    let bytes = c_name.as_bytes_with_nul();
+    if bytes.len() > libc::IFNAMSIZ {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "interface name too long"));
+    }
+
+    let mut ifr: libc::ifreq = unsafe { mem::zeroed() };
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(bytes) {
+        *dst = *src as libc::c_char;
+    }
+    Ok(ifr)
+}
+
+fn open_tun(name: &str) -> io::Result<File> {
+    let device = OpenOptions::new().read(true).write(true).open("/dev/net/tun")?;
+
+    let mut ifr = ifreq_for(name)?;
+    ifr.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+    let rc = unsafe { libc::ioctl(device.as_raw_fd(), libc::TUNSETIFF as _, &mut ifr) };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(device)
+}
+
+fn set_link_up(name: &str) -> io::Result<()> {
+    let raw = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if raw < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(raw) };
+
+    let mut ifr = ifreq_for(name)?;
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFFLAGS as _, &mut ifr) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe { ifr.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short };
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCSIFFLAGS as _, &mut ifr) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Open a header-including raw socket whose packets carry `fwmark`
+fn open_raw_socket(family: libc::c_int, fwmark: u32) -> io::Result<OwnedFd> {
+    // IPPROTO_RAW implies IP_HDRINCL (and IPV6_HDRINCL since Linux 4.5)
+    let raw = unsafe { libc::socket(family, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::IPPROTO_RAW) };
+    if raw < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(raw) };
+
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_MARK,
+            &fwmark as *const _ as *const libc::c_void,
+            mem::size_of::<u32>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(socket)
+}
@@ -0,0 +1,255 @@
+//! Per-route TCP failure detection and acknowledgement settings
+//!
+//! How fast a dead peer is noticed is a trade-off that differs between
+//! venues: a matching engine that goes silent should fail over in a
+//! couple of seconds, a drop-copy feed on a flaky WAN link should not. Both
+//! legs of a connection towards a destination get that destination's
+//! settings once it is dialed:
+//!
+//! - `--tcp-user-timeout` (TCP_USER_TIMEOUT): how long sent data may go
+//!   unacknowledged before the connection is dropped; 5000ms unless a rule
+//!   says otherwise, 0 leaves the system default
+//! - `--tcp-keepalive` (SO_KEEPALIVE, TCP_KEEPIDLE/KEEPINTVL/KEEPCNT):
+//!   probes that find a peer gone quiet without anything in flight; off
+//!   unless a rule turns it on
+//! - `--quickack` (TCP_QUICKACK): acknowledge at once rather than after
+//!   the delayed-ACK timer. The kernel clears the flag again once it
+//!   decides the connection is interactive, so `on` (the default without a
+//!   rule) only covers the first segments; `rearm` sets it again after
+//!   every read in the userspace relay, at the cost of a syscall per read,
+//!   and keeps the connection out of the kernel splice
+//!
+//! Listeners get the settings of the catch-all rules, so the user timeout
+//! and keepalive are in force from accept until the destination is known.
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use socket2::SockRef;
+
+use crate::route::{self, RouteRule};
+
+/// TCP_USER_TIMEOUT of connections no rule matches
+pub const DEFAULT_USER_TIMEOUT: Duration = Duration::from_millis(5000);
+
+/// `--tcp-user-timeout` value in milliseconds; 0 leaves the system default
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserTimeout(pub Option<Duration>);
+
+impl FromStr for UserTimeout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ms: u32 = s.parse().map_err(|_| format!("invalid user timeout '{}' (expected MS)", s))?;
+        Ok(Self((ms > 0).then(|| Duration::from_millis(ms.into()))))
+    }
+}
+
+/// `--tcp-keepalive` value: `off`, or `IDLE[/INTERVAL/COUNT]` in seconds;
+/// IDLE alone leaves the interval and count at the system defaults
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive(pub Option<KeepaliveProbes>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveProbes {
+    /// Quiet time before the first probe
+    pub idle: Duration,
+    /// Time between unanswered probes, and how many before giving up
+    pub retry: Option<(Duration, u32)>,
+}
+
+impl FromStr for Keepalive {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "off" {
+            return Ok(Self(None));
+        }
+        let invalid = || format!("invalid keepalive '{}' (expected off or IDLE[/INTERVAL/COUNT])", s);
+        let secs = |value: &str| match value.parse::<u64>() {
+            Ok(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+            _ => Err(invalid()),
+        };
+        let mut parts = s.split('/');
+        let idle = secs(parts.next().unwrap_or_default())?;
+        let retry = match (parts.next(), parts.next(), parts.next()) {
+            (None, _, _) => None,
+            (Some(interval), Some(count), None) => {
+                let count = count.parse::<u32>().ok().filter(|&count| count > 0).ok_or_else(invalid)?;
+                Some((secs(interval)?, count))
+            }
+            _ => return Err(invalid()),
+        };
+        Ok(Self(Some(KeepaliveProbes { idle, retry })))
+    }
+}
+
+/// `--quickack` value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuickAck {
+    /// Delay ACKs as the kernel normally does
+    Off,
+    /// Set TCP_QUICKACK once the connection is set up; the kernel clears
+    /// it again as it sees fit
+    #[default]
+    On,
+    /// Set it again after every read from the connection
+    Rearm,
+}
+
+impl fmt::Display for QuickAck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuickAck::Off => write!(f, "off"),
+            QuickAck::On => write!(f, "on"),
+            QuickAck::Rearm => write!(f, "rearm"),
+        }
+    }
+}
+
+impl FromStr for QuickAck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(QuickAck::Off),
+            "on" => Ok(QuickAck::On),
+            "rearm" => Ok(QuickAck::Rearm),
+            _ => Err(format!("unknown quickack mode '{}' (expected on, off or rearm)", s)),
+        }
+    }
+}
+
+/// The `[DEST=]VALUE` rules of the three flags
+#[derive(Debug, Clone, Default)]
+pub struct TuningRules {
+    pub user_timeout: Vec<RouteRule<UserTimeout>>,
+    pub keepalive: Vec<RouteRule<Keepalive>>,
+    pub quickack: Vec<RouteRule<QuickAck>>,
+}
+
+impl TuningRules {
+    /// Settings for connections towards `destination`
+    pub fn lookup(&self, destination: SocketAddr) -> Tuning {
+        Tuning {
+            user_timeout: route::lookup(&self.user_timeout, destination).map_or(Some(DEFAULT_USER_TIMEOUT), |timeout| timeout.0),
+            keepalive: route::lookup(&self.keepalive, destination).and_then(|keepalive| keepalive.0),
+            quickack: route::lookup(&self.quickack, destination).unwrap_or_default(),
+        }
+    }
+
+    /// Settings for sockets not tied to a destination yet
+    pub fn catch_all(&self) -> Tuning {
+        Tuning {
+            user_timeout: route::catch_all(&self.user_timeout).map_or(Some(DEFAULT_USER_TIMEOUT), |timeout| timeout.0),
+            keepalive: route::catch_all(&self.keepalive).and_then(|keepalive| keepalive.0),
+            quickack: route::catch_all(&self.quickack).unwrap_or_default(),
+        }
+    }
+}
+
+/// The settings one socket gets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tuning {
+    pub user_timeout: Option<Duration>,
+    pub keepalive: Option<KeepaliveProbes>,
+    pub quickack: QuickAck,
+}
+
+impl Tuning {
+    /// Apply to a listening or connected socket, undoing what the
+    /// listener's settings left on an accepted one
+    pub fn apply(&self, socket: SockRef<'_>) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        socket.set_tcp_user_timeout(self.user_timeout)?;
+        match self.keepalive {
+            Some(probes) => {
+                #[allow(unused_mut)]
+                let mut keepalive = socket2::TcpKeepalive::new().with_time(probes.idle);
+                #[cfg(target_os = "linux")]
+                if let Some((interval, count)) = probes.retry {
+                    keepalive = keepalive.with_interval(interval).with_retries(count);
+                }
+                socket.set_tcp_keepalive(&keepalive)?;
+            }
+            None => socket.set_keepalive(false)?,
+        }
+        #[cfg(target_os = "linux")]
+        socket.set_quickack(self.quickack != QuickAck::Off)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("0".parse(), Ok(UserTimeout(None)));
+        assert_eq!("1500".parse(), Ok(UserTimeout(Some(Duration::from_millis(1500)))));
+        assert!("1.5s".parse::<UserTimeout>().is_err());
+
+        assert_eq!("off".parse(), Ok(Keepalive(None)));
+        assert_eq!(
+            "30".parse(),
+            Ok(Keepalive(Some(KeepaliveProbes { idle: Duration::from_secs(30), retry: None })))
+        );
+        assert_eq!(
+            "5/1/3".parse(),
+            Ok(Keepalive(Some(KeepaliveProbes {
+                idle: Duration::from_secs(5),
+                retry: Some((Duration::from_secs(1), 3)),
+            })))
+        );
+        for invalid in ["0", "5/1", "5/1/0", "5/1/3/2", "soon"] {
+            assert!(invalid.parse::<Keepalive>().is_err(), "{}", invalid);
+        }
+
+        assert_eq!("off".parse(), Ok(QuickAck::Off));
+        assert_eq!("rearm".parse::<QuickAck>().unwrap().to_string(), "rearm");
+        assert!("always".parse::<QuickAck>().is_err());
+    }
+
+    #[test]
+    fn test_lookup() {
+        let rules = TuningRules {
+            user_timeout: vec!["10.1.0.5=1000".parse().unwrap(), "0".parse().unwrap()],
+            keepalive: vec!["10.1.0.5:9000=5/1/3".parse().unwrap()],
+            quickack: vec!["10.1.0.7=off".parse().unwrap()],
+        };
+        let venue = rules.lookup("10.1.0.5:9000".parse().unwrap());
+        assert_eq!(venue.user_timeout, Some(Duration::from_millis(1000)));
+        assert!(venue.keepalive.is_some());
+        assert_eq!(venue.quickack, QuickAck::On);
+        let other = rules.lookup("10.1.0.7:9000".parse().unwrap());
+        assert_eq!(other.user_timeout, None);
+        assert_eq!(other.keepalive, None);
+        assert_eq!(other.quickack, QuickAck::Off);
+
+        let defaults = TuningRules::default().catch_all();
+        assert_eq!(defaults.user_timeout, Some(DEFAULT_USER_TIMEOUT));
+        assert_eq!(defaults.quickack, QuickAck::On);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_apply() {
+        let rules = TuningRules {
+            user_timeout: vec!["1500".parse().unwrap()],
+            keepalive: vec!["5/1/3".parse().unwrap()],
+            quickack: vec![],
+        };
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = SockRef::from(&listener);
+        rules.catch_all().apply(socket).unwrap();
+        let socket = SockRef::from(&listener);
+        assert_eq!(socket.tcp_user_timeout().unwrap(), Some(Duration::from_millis(1500)));
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(5));
+        assert_eq!(socket.keepalive_retries().unwrap(), 3);
+    }
+}
@@ -0,0 +1,511 @@
+//! Time zones for schedules kept in exchange local time
+//!
+//! A zone is read from the system's compiled tz database (TZif files under
+//! `$TZDIR` or /usr/share/zoneinfo), or given directly as a POSIX TZ rule
+//! such as `EST5EDT,M3.2.0,M11.1.0`. Only UTC offsets are looked up, never
+//! abbreviations or leap seconds. Times past a file's last transition
+//! follow the rule in its footer, which is all that "slim" files carry for
+//! the present day.
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// A time zone: UTC offsets by instant
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Zone {
+    name: String,
+    /// Unix times at which the offset changes, ascending
+    transitions: Vec<i64>,
+    /// Offset (seconds east of UTC) from each transition on
+    offsets: Vec<i32>,
+    /// Offset before the first transition
+    initial: i32,
+    /// Offsets after the last transition
+    rule: Option<Rule>,
+}
+
+impl Zone {
+    pub fn utc() -> Self {
+        Self::fixed("UTC", 0)
+    }
+
+    fn fixed(name: &str, offset: i32) -> Self {
+        Self {
+            name: name.to_string(),
+            transitions: Vec::new(),
+            offsets: Vec::new(),
+            initial: offset,
+            rule: None,
+        }
+    }
+
+    /// A zone by tz database name (`America/New_York`), or a POSIX TZ rule
+    pub fn load(name: &str) -> io::Result<Self> {
+        if name == "UTC" {
+            return Ok(Self::utc());
+        }
+        let unknown = || io::Error::new(io::ErrorKind::NotFound, format!("unknown time zone '{}'", name));
+        if name.split('/').any(|part| part.is_empty() || part == "." || part == "..") {
+            return Err(unknown());
+        }
+        let dir = std::env::var_os("TZDIR").map_or_else(|| PathBuf::from("/usr/share/zoneinfo"), PathBuf::from);
+        match std::fs::read(dir.join(name)) {
+            Ok(data) => Self::from_tzif(name, &data),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => match name.parse::<Rule>() {
+                Ok(rule) => Ok(Self {
+                    name: name.to_string(),
+                    transitions: Vec::new(),
+                    offsets: Vec::new(),
+                    initial: rule.std_offset,
+                    rule: Some(rule),
+                }),
+                Err(_) => Err(unknown()),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Parse a compiled zone (TZif, RFC 8536)
+    pub fn from_tzif(name: &str, data: &[u8]) -> io::Result<Self> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("time zone '{}': {}", name, what));
+        let mut reader = Reader { data, pos: 0 };
+        let header = reader.header().ok_or_else(|| invalid("not a TZif file"))?;
+        // Version 2 and later repeat the data with 64-bit times, followed
+        // by the rule for later times
+        let (header, time_size) = match header.version {
+            0 => (header, 4),
+            _ => {
+                reader.skip(header.data_len(4)).ok_or_else(|| invalid("truncated"))?;
+                (reader.header().ok_or_else(|| invalid("truncated"))?, 8)
+            }
+        };
+        let mut transitions = Vec::with_capacity(header.timecnt);
+        for _ in 0..header.timecnt {
+            transitions.push(reader.time(time_size).ok_or_else(|| invalid("truncated"))?);
+        }
+        let indices = reader.bytes(header.timecnt).ok_or_else(|| invalid("truncated"))?.to_vec();
+        let mut types = Vec::with_capacity(header.typecnt);
+        for _ in 0..header.typecnt {
+            let ttinfo = reader.bytes(6).ok_or_else(|| invalid("truncated"))?;
+            types.push((i32::from_be_bytes([ttinfo[0], ttinfo[1], ttinfo[2], ttinfo[3]]), ttinfo[4] != 0));
+        }
+        if types.is_empty() {
+            return Err(invalid("no local time types"));
+        }
+        let offsets = indices
+            .iter()
+            .map(|&i| types.get(i as usize).map(|&(offset, _)| offset))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| invalid("bad local time type"))?;
+        // Before the first transition: the first standard time type
+        let initial = types.iter().find(|(_, dst)| !dst).unwrap_or(&types[0]).0;
+        let rule = match header.version {
+            0 => None,
+            _ => {
+                reader.skip(header.charcnt + header.leapcnt * (time_size + 4) + header.isstdcnt + header.isutcnt)
+                    .ok_or_else(|| invalid("truncated"))?;
+                let footer = reader.rest();
+                let footer = footer.strip_prefix(b"\n").and_then(|f| f.split(|&b| b == b'\n').next()).unwrap_or_default();
+                match footer.is_empty() {
+                    true => None,
+                    false => Some(
+                        std::str::from_utf8(footer)
+                            .ok()
+                            .and_then(|rule| rule.parse().ok())
+                            .ok_or_else(|| invalid("bad TZ rule in footer"))?,
+                    ),
+                }
+            }
+        };
+        Ok(Self {
+            name: name.to_string(),
+            transitions,
+            offsets,
+            initial,
+            rule,
+        })
+    }
+
+    /// Seconds east of UTC at `unix` (seconds since the epoch)
+    pub fn offset_at(&self, unix: i64) -> i32 {
+        match self.transitions.partition_point(|&t| t <= unix) {
+            0 if self.transitions.is_empty() => self.rule.as_ref().map_or(self.initial, |rule| rule.offset_at(unix)),
+            0 => self.initial,
+            n if n == self.transitions.len() => match &self.rule {
+                Some(rule) => rule.offset_at(unix),
+                None => self.offsets[n - 1],
+            },
+            n => self.offsets[n - 1],
+        }
+    }
+}
+
+impl fmt::Display for Zone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+struct Header {
+    version: u8,
+    isutcnt: usize,
+    isstdcnt: usize,
+    leapcnt: usize,
+    timecnt: usize,
+    typecnt: usize,
+    charcnt: usize,
+}
+
+impl Header {
+    fn data_len(&self, time_size: usize) -> usize {
+        self.timecnt * (time_size + 1)
+            + self.typecnt * 6
+            + self.charcnt
+            + self.leapcnt * (time_size + 4)
+            + self.isstdcnt
+            + self.isutcnt
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(bytes)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.bytes(n).map(|_| ())
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.data[self.pos..]
+    }
+
+    fn count(&mut self) -> Option<usize> {
+        let bytes = self.bytes(4)?;
+        Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn time(&mut self, size: usize) -> Option<i64> {
+        let bytes = self.bytes(size)?;
+        Some(match size {
+            4 => i32::from_be_bytes(bytes.try_into().ok()?) as i64,
+            _ => i64::from_be_bytes(bytes.try_into().ok()?),
+        })
+    }
+
+    fn header(&mut self) -> Option<Header> {
+        if self.bytes(4)? != b"TZif" {
+            return None;
+        }
+        let version = match self.bytes(1)?[0] {
+            0 => 0,
+            version => version - b'0',
+        };
+        self.skip(15)?;
+        Some(Header {
+            version,
+            isutcnt: self.count()?,
+            isstdcnt: self.count()?,
+            leapcnt: self.count()?,
+            timecnt: self.count()?,
+            typecnt: self.count()?,
+            charcnt: self.count()?,
+        })
+    }
+}
+
+/// A POSIX TZ rule: a standard offset, and optionally a daylight saving
+/// offset with the dates it starts and ends on each year
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rule {
+    /// Seconds east of UTC
+    std_offset: i32,
+    dst: Option<Dst>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Dst {
+    offset: i32,
+    /// Local (standard) time it starts, local (daylight) time it ends
+    start: (Date, i32),
+    end: (Date, i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Date {
+    /// `Jn`: day 1-365, February 29 never counted
+    Julian(u16),
+    /// `n`: day 0-365, counting February 29
+    Ordinal(u16),
+    /// `Mm.w.d`: day d (0 = Sunday) of week w (5 = last) of month m
+    Month(u8, u8, u8),
+}
+
+impl Rule {
+    fn offset_at(&self, unix: i64) -> i32 {
+        let Some(dst) = &self.dst else {
+            return self.std_offset;
+        };
+        let year = civil_from_days((unix + self.std_offset as i64).div_euclid(86400)).0;
+        let start = days_from_civil(year, 1, 1) * 86400 + dst.start.0.day_of_year(year) * 86400 + dst.start.1 as i64 - self.std_offset as i64;
+        let end = days_from_civil(year, 1, 1) * 86400 + dst.end.0.day_of_year(year) * 86400 + dst.end.1 as i64 - dst.offset as i64;
+        let in_dst = match start < end {
+            true => start <= unix && unix < end,
+            // Southern hemisphere: daylight saving spans the new year
+            false => !(end <= unix && unix < start),
+        };
+        match in_dst {
+            true => dst.offset,
+            false => self.std_offset,
+        }
+    }
+}
+
+impl Date {
+    /// Days after January 1 of `year`
+    fn day_of_year(self, year: i64) -> i64 {
+        let leap = is_leap(year);
+        match self {
+            Date::Julian(day) => day as i64 - 1 + (leap && day > 59) as i64,
+            Date::Ordinal(day) => day as i64,
+            Date::Month(month, week, weekday) => {
+                let first = days_from_civil(year, month as u32, 1);
+                // 1970-01-01 was a Thursday
+                let first_weekday = (first + 4).rem_euclid(7);
+                let mut day = (weekday as i64 - first_weekday).rem_euclid(7) + (week as i64 - 1) * 7;
+                while day >= month_length(year, month) {
+                    day -= 7;
+                }
+                first + day - days_from_civil(year, 1, 1)
+            }
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid TZ rule '{}'", s);
+        let mut rest = s;
+        name(&mut rest).ok_or_else(invalid)?;
+        // POSIX offsets count hours west of UTC
+        let std_offset = -offset(&mut rest, false).ok_or_else(invalid)?;
+        if rest.is_empty() {
+            return Ok(Self { std_offset, dst: None });
+        }
+        name(&mut rest).ok_or_else(invalid)?;
+        let dst_offset = match rest.starts_with(',') || rest.is_empty() {
+            true => std_offset + 3600,
+            false => -offset(&mut rest, false).ok_or_else(invalid)?,
+        };
+        // Without dates, the US rules are the traditional default
+        let (start, end) = match rest.strip_prefix(',') {
+            Some(dates) => {
+                let (start, end) = dates.split_once(',').ok_or_else(invalid)?;
+                (transition(start).ok_or_else(invalid)?, transition(end).ok_or_else(invalid)?)
+            }
+            None if rest.is_empty() => ((Date::Month(3, 2, 0), 7200), (Date::Month(11, 1, 0), 7200)),
+            None => return Err(invalid()),
+        };
+        Ok(Self {
+            std_offset,
+            dst: Some(Dst {
+                offset: dst_offset,
+                start,
+                end,
+            }),
+        })
+    }
+}
+
+/// Take a zone abbreviation: `<...>` or three or more letters
+fn name(s: &mut &str) -> Option<()> {
+    let len = match s.strip_prefix('<') {
+        Some(quoted) => quoted.find('>')? + 2,
+        None => s.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(s.len()),
+    };
+    if len < 3 {
+        return None;
+    }
+    *s = &s[len..];
+    Some(())
+}
+
+/// Take `[+-]hh[:mm[:ss]]`, in seconds; hours up to 24, or 167 for the
+/// transition times of `extended` rules
+fn offset(s: &mut &str, extended: bool) -> Option<i32> {
+    let (sign, digits) = match s.as_bytes().first()? {
+        b'-' => (-1, &s[1..]),
+        b'+' => (1, &s[1..]),
+        _ => (1, *s),
+    };
+    let len = digits.find(|c: char| !(c.is_ascii_digit() || c == ':')).unwrap_or(digits.len());
+    let mut parts = digits[..len].split(':');
+    let hours: i32 = parts.next()?.parse().ok()?;
+    let minutes: i32 = parts.next().map_or(Some(0), |m| m.parse().ok())?;
+    let seconds: i32 = parts.next().map_or(Some(0), |s| s.parse().ok())?;
+    if parts.next().is_some() || hours > if extended { 167 } else { 24 } || minutes > 59 || seconds > 59 {
+        return None;
+    }
+    *s = &digits[len..];
+    Some(sign * (hours * 3600 + minutes * 60 + seconds))
+}
+
+/// `date[/time]`, the time 02:00 if not given
+fn transition(s: &str) -> Option<(Date, i32)> {
+    let (date, time) = match s.split_once('/') {
+        Some((date, mut time)) => {
+            let seconds = offset(&mut time, true)?;
+            if !time.is_empty() {
+                return None;
+            }
+            (date, seconds)
+        }
+        None => (s, 7200),
+    };
+    let date = if let Some(day) = date.strip_prefix('J') {
+        Date::Julian(day.parse().ok().filter(|day| (1..=365).contains(day))?)
+    } else if let Some(date) = date.strip_prefix('M') {
+        let mut parts = date.split('.');
+        let mut field = |range: std::ops::RangeInclusive<u8>| parts.next()?.parse().ok().filter(|v| range.contains(v));
+        let date = Date::Month(field(1..=12)?, field(1..=5)?, field(0..=6)?);
+        if parts.next().is_some() {
+            return None;
+        }
+        date
+    } else {
+        Date::Ordinal(date.parse().ok().filter(|&day| day <= 365)?)
+    };
+    Some((date, time))
+}
+
+fn is_leap(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn month_length(year: i64, month: u8) -> i64 {
+    match month {
+        2 if is_leap(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The date (year, month, day) `days` after 1970-01-01
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    (year_of_era + era * 400 + (month <= 2) as i64, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unix(year: i64, month: u32, day: u32, hour: i64, minute: i64) -> i64 {
+        days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60
+    }
+
+    #[test]
+    fn test_civil_days() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2024, 2, 29), 19782);
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+
+    #[test]
+    fn test_posix_rules() {
+        let new_york: Rule = "EST5EDT,M3.2.0,M11.1.0".parse().unwrap();
+        // 2024: March 10 02:00 EST to November 3 02:00 EDT
+        assert_eq!(new_york.offset_at(unix(2024, 3, 10, 6, 59)), -5 * 3600);
+        assert_eq!(new_york.offset_at(unix(2024, 3, 10, 7, 0)), -4 * 3600);
+        assert_eq!(new_york.offset_at(unix(2024, 11, 3, 5, 59)), -4 * 3600);
+        assert_eq!(new_york.offset_at(unix(2024, 11, 3, 6, 0)), -5 * 3600);
+
+        // Last Sundays of March and October, at 01:00 UTC either way
+        let london: Rule = "GMT0BST,M3.5.0/1,M10.5.0".parse().unwrap();
+        assert_eq!(london.offset_at(unix(2024, 3, 31, 0, 59)), 0);
+        assert_eq!(london.offset_at(unix(2024, 3, 31, 1, 0)), 3600);
+        assert_eq!(london.offset_at(unix(2024, 10, 27, 1, 0)), 0);
+
+        // Daylight saving over the new year
+        let sydney: Rule = "AEST-10AEDT,M10.1.0,M4.1.0/3".parse().unwrap();
+        assert_eq!(sydney.offset_at(unix(2024, 1, 15, 0, 0)), 11 * 3600);
+        assert_eq!(sydney.offset_at(unix(2024, 6, 15, 0, 0)), 10 * 3600);
+
+        let tokyo: Rule = "JST-9".parse().unwrap();
+        assert_eq!(tokyo.offset_at(0), 9 * 3600);
+        assert_eq!("<-03>3".parse::<Rule>().unwrap().offset_at(0), -3 * 3600);
+        assert_eq!("<+0330>-3:30".parse::<Rule>().unwrap().offset_at(0), 12600);
+
+        for invalid in ["", "E5", "EST", "EST5EDT,M3.2.0", "EST5EDT,M13.2.0,M11.1.0", "EST5EDT,J0,J100"] {
+            assert!(invalid.parse::<Rule>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_tzif() {
+        // Version 2: a 1970 transition to UTC+1, then the rule
+        let mut data = Vec::new();
+        let header = |data: &mut Vec<u8>, timecnt: u32, typecnt: u32| {
+            data.extend_from_slice(b"TZif2");
+            data.extend_from_slice(&[0; 15]);
+            for count in [0, 0, 0, timecnt, typecnt, 4] {
+                data.extend_from_slice(&u32::to_be_bytes(count));
+            }
+        };
+        header(&mut data, 1, 2);
+        data.extend_from_slice(&0i32.to_be_bytes());
+        data.push(1);
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 14, 16, 0, 0]);
+        data.extend_from_slice(b"UTC\0");
+        header(&mut data, 1, 2);
+        data.extend_from_slice(&0i64.to_be_bytes());
+        data.push(1);
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 14, 16, 0, 0]);
+        data.extend_from_slice(b"UTC\0");
+        data.extend_from_slice(b"\nCET-1CEST,M3.5.0,M10.5.0/3\n");
+        let zone = Zone::from_tzif("Test/Zone", &data).unwrap();
+        assert_eq!(zone.offset_at(-1), 0);
+        assert_eq!(zone.offset_at(unix(2024, 1, 15, 0, 0)), 3600);
+        assert_eq!(zone.offset_at(unix(2024, 7, 15, 0, 0)), 7200);
+        assert!(Zone::from_tzif("Test/Zone", &data[..60]).is_err());
+
+        assert!(Zone::load("../etc/passwd").is_err());
+        assert_eq!(Zone::load("EST5EDT,M3.2.0,M11.1.0").unwrap().offset_at(unix(2024, 7, 1, 0, 0)), -4 * 3600);
+        // Where the system has a tz database
+        if let Ok(zone) = Zone::load("America/New_York") {
+            assert_eq!(zone.offset_at(unix(2024, 7, 1, 0, 0)), -4 * 3600);
+            assert_eq!(zone.offset_at(unix(2024, 1, 1, 0, 0)), -5 * 3600);
+        }
+    }
+}
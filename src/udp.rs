@@ -0,0 +1,587 @@
+//! UDP relay (`--protocol udp`)
+//!
+//! Market data and monitoring flows in the same racks are often UDP. With
+//! `--protocol udp` each listener is a UDP socket and every client address
+//! that sends to it gets a session, NAT style: an upstream socket of its
+//! own, bound like upstream TCP connections (`--bind-source`,
+//! `--bind-device`, `--source-ports`, `--dscp`) and connected to a target
+//! picked by `--balance`. The client's datagrams go out on that socket and
+//! whatever the target sends back on it goes to the client from the
+//! listener's address, one datagram for one datagram.
+//!
+//! A session with no datagrams either way for the idle timeout is expired
+//! and its socket closed; the client's next datagram opens a new one. At
+//! most `max_sessions` are open at a time, and datagrams from further
+//! clients are dropped until one expires. So are datagrams from clients
+//! `acl` turns away, before any session is opened for them (each counts as
+//! a hit of the rule that decided). Engaging the kill switch closes every
+//! session, and datagrams that arrive while it is engaged are dropped.
+//!
+//! With `quic` set, sessions opened by a QUIC Initial follow their
+//! connection to a new client address (see `quic`).
+//!
+//! Sessions are counted in `tcpstrip_udp_sessions_total`,
+//! `tcpstrip_udp_sessions_active` and `tcpstrip_udp_sessions_expired_total`,
+//! datagrams in `tcpstrip_udp_datagrams_total{direction}` and dropped ones
+//! in `tcpstrip_udp_dropped_total{reason}`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Socket, Type};
+use tokio::net::UdpSocket;
+use tracing::{debug, warn};
+
+use crate::acl::{Acl, Action};
+use crate::balance::{Lease, Pool};
+use crate::dscp::DscpRule;
+use crate::egress::Egress;
+use crate::{killswitch, metrics, quic};
+
+/// Largest UDP payload
+const MAX_DATAGRAM: usize = 65535;
+
+/// Transport the proxy listens for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "tcp" => Ok(Protocol::Tcp),
+            "udp" => Ok(Protocol::Udp),
+            _ => Err(format!("invalid protocol '{}' (expected tcp or udp)", s)),
+        }
+    }
+}
+
+/// Where and how a relay's sessions go upstream
+pub struct RelayConfig {
+    pub targets: Arc<Pool>,
+    pub egress: Egress,
+    pub dscp: Vec<DscpRule>,
+    pub idle_timeout: Duration,
+    pub max_sessions: usize,
+    /// Recognize QUIC and route by connection ID (`--quic`)
+    pub quic: bool,
+    /// Source address rules (`--allow`, `--deny`)
+    pub acl: Option<Arc<Acl>>,
+}
+
+/// One client's upstream socket
+struct Session {
+    upstream: UdpSocket,
+    /// Held for the life of the session, so least-conn counts sessions
+    lease: Lease,
+    /// Where replies go; a QUIC client may move
+    client: Mutex<SocketAddr>,
+    /// Milliseconds since the relay started, at the last datagram either way
+    last_active: AtomicU64,
+    /// The QUIC connection the session carries, if an Initial opened it
+    quic: Option<quic::ConnectionGuard>,
+    /// Connection IDs that lead to this session
+    cids: Mutex<Vec<Vec<u8>>>,
+}
+
+impl Session {
+    fn client(&self) -> SocketAddr {
+        *self.client.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Open sessions by client address and by QUIC connection ID
+#[derive(Default)]
+struct Sessions {
+    by_client: HashMap<SocketAddr, Arc<Session>>,
+    by_cid: HashMap<Vec<u8>, Arc<Session>>,
+    /// Lengths of the targets' connection IDs, which short headers do not
+    /// give
+    cid_lens: Vec<usize>,
+}
+
+impl Sessions {
+    /// The session a QUIC packet's Destination Connection ID leads to
+    fn find(&self, header: &quic::Header<'_>) -> Option<Arc<Session>> {
+        match header {
+            quic::Header::Long { dcid, .. } => self.by_cid.get(*dcid).cloned(),
+            quic::Header::Short { .. } => self
+                .cid_lens
+                .iter()
+                .find_map(|&len| header.dcid(len).and_then(|dcid| self.by_cid.get(dcid)))
+                .cloned(),
+        }
+    }
+
+    /// Let `cid` lead to `session`
+    fn add_cid(&mut self, cid: &[u8], session: &Arc<Session>) {
+        if !self.cid_lens.contains(&cid.len()) {
+            self.cid_lens.push(cid.len());
+        }
+        self.by_cid.insert(cid.to_vec(), session.clone());
+        session.cids.lock().unwrap_or_else(|e| e.into_inner()).push(cid.to_vec());
+    }
+
+    /// Forget `session` under every address and ID
+    fn remove(&mut self, session: &Arc<Session>) {
+        let client = session.client();
+        if self.by_client.get(&client).is_some_and(|open| Arc::ptr_eq(open, session)) {
+            self.by_client.remove(&client);
+        }
+        for cid in session.cids.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            if self.by_cid.get(cid).is_some_and(|open| Arc::ptr_eq(open, session)) {
+                self.by_cid.remove(cid);
+            }
+        }
+    }
+}
+
+/// A UDP listener and the sessions of the clients sending to it
+pub struct UdpRelay {
+    socket: UdpSocket,
+    config: RelayConfig,
+    sessions: Mutex<Sessions>,
+    started: Instant,
+}
+
+impl UdpRelay {
+    /// Bind the listener; an IPv6 `addr` takes IPv4 clients too unless
+    /// `ipv6_only`
+    pub fn bind(addr: SocketAddr, ipv6_only: bool, config: RelayConfig) -> io::Result<Arc<Self>> {
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(socket2::Protocol::UDP))?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(ipv6_only)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into())?;
+        socket.set_nonblocking(true)?;
+        Ok(Arc::new(UdpRelay {
+            socket: UdpSocket::from_std(socket.into())?,
+            config,
+            sessions: Mutex::new(Sessions::default()),
+            started: Instant::now(),
+        }))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Sessions currently open
+    pub fn sessions(&self) -> usize {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner()).by_client.len()
+    }
+
+    /// Relay datagrams until the listener fails
+    pub async fn run(self: Arc<Self>) -> io::Result<()> {
+        let registry = metrics::registry();
+        let upstream = registry
+            .labeled_counter("tcpstrip_udp_datagrams_total", "UDP datagrams relayed", "direction")
+            .with("upstream");
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let (len, client) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                // ICMP errors for earlier replies surface here on some
+                // platforms; they concern one client, not the listener
+                Err(e) if matches!(e.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionRefused) => continue,
+                Err(e) => return Err(e),
+            };
+            // IPv4 clients of a dual-stack listener arrive as ::ffff:a.b.c.d
+            let client = SocketAddr::new(client.ip().to_canonical(), client.port());
+            if killswitch::is_engaged() {
+                killswitch::count_refused();
+                dropped("kill_switch");
+                continue;
+            }
+            let Some(session) = self.session(client, &buf[..len]) else {
+                continue;
+            };
+            session.last_active.store(self.now(), Ordering::Relaxed);
+            if let Some(quic) = &session.quic {
+                quic.connection().upstream.add(len);
+            }
+            // Sends on a UDP socket wait only for its buffer to drain
+            match session.upstream.send(&buf[..len]).await {
+                Ok(_) => upstream.inc(),
+                // The target answered an earlier datagram with ICMP port
+                // unreachable; it may be back for the next one
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => dropped("refused"),
+                Err(e) => {
+                    debug!("UDP session {} -> {}: send failed: {}", client, session.lease.addr, e);
+                    dropped("error");
+                }
+            }
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    /// The session of `client`, sending `datagram`: its own, the one of
+    /// the QUIC connection it moved, or a new one; None if it cannot have
+    /// one
+    fn session(self: &Arc<Self>, client: SocketAddr, datagram: &[u8]) -> Option<Arc<Session>> {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(session) = sessions.by_client.get(&client) {
+            return Some(session.clone());
+        }
+        if let Some(acl) = &self.config.acl {
+            if acl.check(client.ip()).0 == Action::Deny {
+                dropped("acl");
+                return None;
+            }
+        }
+        let header = match self.config.quic {
+            true => quic::Header::parse(datagram),
+            false => None,
+        };
+        if let Some(session) = header.and_then(|header| sessions.find(&header)) {
+            let previous = session.client();
+            if sessions.by_client.get(&previous).is_some_and(|open| Arc::ptr_eq(open, &session)) {
+                sessions.by_client.remove(&previous);
+            }
+            sessions.by_client.insert(client, session.clone());
+            *session.client.lock().unwrap_or_else(|e| e.into_inner()) = client;
+            if let Some(quic) = &session.quic {
+                quic.connection().migrated(client);
+            }
+            debug!("QUIC connection to {} moved from {} to {}", session.lease.addr, previous, client);
+            return Some(session);
+        }
+        if sessions.by_client.len() >= self.config.max_sessions {
+            dropped("sessions");
+            return None;
+        }
+        let lease = self.config.targets.pick_for(client.ip());
+        let upstream = match self.open(lease.addr) {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                warn!("UDP session {} -> {}: could not open upstream socket: {}", client, lease.addr, e);
+                dropped("error");
+                return None;
+            }
+        };
+        debug!("UDP session {} -> {} opened", client, lease.addr);
+        let initial = match header {
+            Some(quic::Header::Long {
+                kind: quic::LongType::Initial,
+                version,
+                dcid,
+                ..
+            }) => Some((version, dcid)),
+            _ => None,
+        };
+        let session = Arc::new(Session {
+            quic: initial.map(|(version, dcid)| quic::registry().register(dcid, version, client, lease.addr)),
+            upstream,
+            lease,
+            client: Mutex::new(client),
+            last_active: AtomicU64::new(self.now()),
+            cids: Mutex::new(Vec::new()),
+        });
+        sessions.by_client.insert(client, session.clone());
+        if let Some((_, dcid)) = initial {
+            sessions.add_cid(dcid, &session);
+        }
+        let registry = metrics::registry();
+        registry.counter("tcpstrip_udp_sessions_total", "UDP sessions opened").inc();
+        registry.gauge("tcpstrip_udp_sessions_active", "UDP sessions currently open").inc();
+        tokio::spawn(self.clone().replies(session.clone()));
+        Some(session)
+    }
+
+    /// An upstream socket for `target`, bound the way upstream TCP
+    /// connections are
+    fn open(&self, target: SocketAddr) -> io::Result<UdpSocket> {
+        let socket = Socket::new(Domain::for_address(target), Type::DGRAM, Some(socket2::Protocol::UDP))?;
+        if let Some(dscp) = crate::route::lookup(&self.config.dscp, target) {
+            match target {
+                SocketAddr::V4(_) => socket.set_tos(dscp.tos() as u32)?,
+                #[cfg(unix)]
+                SocketAddr::V6(_) => socket.set_tclass_v6(dscp.tos() as u32)?,
+                #[cfg(not(unix))]
+                SocketAddr::V6(_) => {}
+            }
+        }
+        self.config.egress.bind(&socket, target, None)?;
+        socket.connect(&target.into())?;
+        socket.set_nonblocking(true)?;
+        UdpSocket::from_std(socket.into())
+    }
+
+    /// Send what the target returns on `session` to its client until the
+    /// session has been idle for the timeout
+    async fn replies(self: Arc<Self>, session: Arc<Session>) {
+        let downstream = metrics::registry()
+            .labeled_counter("tcpstrip_udp_datagrams_total", "UDP datagrams relayed", "direction")
+            .with("downstream");
+        let idle_timeout = self.config.idle_timeout.as_millis() as u64;
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let killed = loop {
+            let idle = self.now().saturating_sub(session.last_active.load(Ordering::Relaxed));
+            if idle >= idle_timeout {
+                break false;
+            }
+            let received = tokio::select! {
+                biased;
+                _ = killswitch::engaged() => break true,
+                received = tokio::time::timeout(Duration::from_millis(idle_timeout - idle), session.upstream.recv(&mut buf)) => received,
+            };
+            match received {
+                // Look again: the client may have sent in the meantime
+                Err(_) => continue,
+                Ok(Ok(len)) => {
+                    session.last_active.store(self.now(), Ordering::Relaxed);
+                    if let Some(quic) = &session.quic {
+                        quic.connection().downstream.add(len);
+                        self.learn_server_cid(&session, &buf[..len]);
+                    }
+                    match self.socket.send_to(&buf[..len], session.client()).await {
+                        Ok(_) => downstream.inc(),
+                        Err(e) => {
+                            debug!("UDP session {} -> {}: reply failed: {}", session.client(), session.lease.addr, e);
+                            dropped("error");
+                        }
+                    }
+                }
+                Ok(Err(e)) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                Ok(Err(e)) => {
+                    debug!("UDP session {} -> {}: {}", session.client(), session.lease.addr, e);
+                    break false;
+                }
+            }
+        };
+
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner()).remove(&session);
+        let registry = metrics::registry();
+        registry.gauge("tcpstrip_udp_sessions_active", "UDP sessions currently open").dec();
+        match killed {
+            true => {
+                killswitch::count_reset();
+                debug!("UDP session {} -> {} closed by the kill switch", session.client(), session.lease.addr);
+            }
+            false => {
+                registry.counter("tcpstrip_udp_sessions_expired_total", "UDP sessions closed after their idle timeout").inc();
+                debug!("UDP session {} -> {} expired", session.client(), session.lease.addr);
+            }
+        }
+    }
+
+    /// Route packets to the connection ID the target chose in a long
+    /// header it sent on `session`
+    fn learn_server_cid(&self, session: &Arc<Session>, datagram: &[u8]) {
+        let Some(quic::Header::Long { scid, .. }) = quic::Header::parse(datagram) else {
+            return;
+        };
+        let Some(quic) = &session.quic else {
+            return;
+        };
+        // Zero-length IDs cannot tell connections apart
+        if !scid.is_empty() && quic.connection().set_server_cid(scid) {
+            self.sessions.lock().unwrap_or_else(|e| e.into_inner()).add_cid(scid, session);
+        }
+    }
+}
+
+/// Count a datagram that was not relayed
+fn dropped(reason: &str) {
+    metrics::registry()
+        .labeled_counter("tcpstrip_udp_dropped_total", "UDP datagrams not relayed", "reason")
+        .with(reason)
+        .inc();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send a datagram through the relay; the reply, if one comes
+    async fn ask(client: &UdpSocket) -> Option<String> {
+        client.send(b"8=FIX").await.unwrap();
+        let mut buf = [0u8; 64];
+        let n = tokio::time::timeout(Duration::from_millis(100), client.recv(&mut buf)).await.ok()?.unwrap();
+        Some(String::from_utf8_lossy(&buf[..n]).into_owned())
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        let _serial = killswitch::SERIAL.lock().await;
+        assert_eq!("UDP".parse(), Ok(Protocol::Udp));
+        assert_eq!(Protocol::Tcp.to_string(), "tcp");
+        assert!("sctp".parse::<Protocol>().is_err());
+
+        // A target that answers each datagram with its sender's address
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            loop {
+                let (_, from) = target.recv_from(&mut buf).await.unwrap();
+                target.send_to(from.to_string().as_bytes(), from).await.unwrap();
+            }
+        });
+
+        let relay = UdpRelay::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            false,
+            RelayConfig {
+                targets: Arc::new(Pool::new(&[target_addr])),
+                egress: Egress::default(),
+                dscp: Vec::new(),
+                idle_timeout: Duration::from_millis(200),
+                max_sessions: 2,
+                quic: false,
+                acl: None,
+            },
+        )
+        .unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        tokio::spawn(relay.clone().run());
+
+        // Each client gets its own upstream socket, the same one every time
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.connect(relay_addr).await.unwrap();
+            clients.push(client);
+        }
+        let first = ask(&clients[0]).await.unwrap();
+        assert_eq!(ask(&clients[0]).await.unwrap(), first);
+        let second = ask(&clients[1]).await.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(relay.sessions(), 2);
+        // The third is over the limit
+        assert_eq!(ask(&clients[2]).await, None);
+
+        // Idle sessions expire, and the next datagram opens a new one
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(relay.sessions(), 0);
+        assert!(ask(&clients[2]).await.is_some());
+        assert_eq!(relay.sessions(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_quic_migration() {
+        let _serial = killswitch::SERIAL.lock().await;
+        // A target that answers with a Handshake packet from connection ID
+        // 09090909, followed by its sender's address
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            loop {
+                let (_, from) = target.recv_from(&mut buf).await.unwrap();
+                let mut reply = vec![0xe0, 0, 0, 0, 1, 0, 4, 9, 9, 9, 9];
+                reply.extend_from_slice(from.to_string().as_bytes());
+                target.send_to(&reply, from).await.unwrap();
+            }
+        });
+        let relay = UdpRelay::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            false,
+            RelayConfig {
+                targets: Arc::new(Pool::new(&[target_addr])),
+                egress: Egress::default(),
+                dscp: Vec::new(),
+                idle_timeout: Duration::from_secs(5),
+                max_sessions: 10,
+                quic: true,
+                acl: None,
+            },
+        )
+        .unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        tokio::spawn(relay.clone().run());
+
+        let send = |packet: &'static [u8]| async move {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.send_to(packet, relay_addr).await.unwrap();
+            let mut buf = [0u8; 64];
+            let n = tokio::time::timeout(Duration::from_millis(500), client.recv(&mut buf)).await.unwrap().unwrap();
+            String::from_utf8_lossy(&buf[11..n]).into_owned()
+        };
+        // An Initial with DCID 0102030405060708 opens the connection
+        let upstream = send(&[0xc3, 0, 0, 0, 1, 8, 1, 2, 3, 4, 5, 6, 7, 8, 0, 0]).await;
+        // A short header for the target's ID from another port is the same
+        // connection, as is a retransmitted Initial
+        assert_eq!(send(&[0x41, 9, 9, 9, 9, 0xaa]).await, upstream);
+        assert_eq!(send(&[0xc3, 0, 0, 0, 1, 8, 1, 2, 3, 4, 5, 6, 7, 8, 0, 0]).await, upstream);
+        assert_eq!(relay.sessions(), 1);
+        let table = quic::registry().render();
+        assert!(table.lines().any(|line| line.starts_with("0102030405060708\tv1\t") && line.contains("\t09090909\t") && line.ends_with("\t2")), "{}", table);
+
+        // An ID nobody chose is a new client
+        assert_ne!(send(&[0x41, 7, 7, 7, 7, 0xaa]).await, upstream);
+        assert_eq!(relay.sessions(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_kill_switch() {
+        let _serial = killswitch::SERIAL.lock().await;
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            loop {
+                let (n, from) = target.recv_from(&mut buf).await.unwrap();
+                target.send_to(&buf[..n], from).await.unwrap();
+            }
+        });
+        let relay = UdpRelay::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            false,
+            RelayConfig {
+                targets: Arc::new(Pool::new(&[target_addr])),
+                egress: Egress::default(),
+                dscp: Vec::new(),
+                idle_timeout: Duration::from_secs(60),
+                max_sessions: 2,
+                quic: false,
+                acl: None,
+            },
+        )
+        .unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(relay.local_addr().unwrap()).await.unwrap();
+        tokio::spawn(relay.clone().run());
+        assert!(ask(&client).await.is_some());
+        assert_eq!(relay.sessions(), 1);
+
+        // Every session is closed and nothing more is relayed
+        killswitch::engage();
+        for _ in 0..100 {
+            if relay.sessions() == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(relay.sessions(), 0);
+        assert_eq!(ask(&client).await, None);
+        assert_eq!(relay.sessions(), 0);
+
+        killswitch::release();
+        assert!(ask(&client).await.is_some());
+    }
+}
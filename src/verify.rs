@@ -0,0 +1,285 @@
+//! Egress self-verification (Linux only)
+//!
+//! The socket proxy can only ask the kernel not to use TCP timestamps on
+//! its upstream connections; whether that worked depends on the kernel,
+//! sysctls and privileges. To find out, the verifier watches the egress
+//! interface with an AF_PACKET socket, samples outgoing segments that
+//! belong to proxied flows and checks their options with `tcp_analysis`.
+//! A timestamp option that shows up anyway is logged and counted, so
+//! operators can tell from the metrics whether the protection is effective.
+//! `tcp-proxy verify` runs the same check on its own for a while (`audit`),
+//! on every outgoing segment to the given targets, for use before a proxy
+//! goes into service or from a monitoring job.
+//!
+//! A classic BPF filter keeps everything but the sampled outgoing TCP
+//! segments in the kernel:
+//!
+//! ```text
+//! ld  pkttype         ; jne #PACKET_OUTGOING, drop
+//! ldh [12]            ; IPv4 or IPv6 carrying TCP, otherwise drop
+//! ld  rand            ; mod #sample_rate ; jne #0, drop
+//! ret #262144
+//! ```
+//!
+//! Alongside the proxy the filter is confined further to the routes' traffic
+//! (see `capture::Scope`); `audit` confines it to the given targets.
+//!
+//! Requires CAP_NET_RAW.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::OwnedFd;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use crate::capture;
+use crate::metrics;
+use crate::packet::{parse_ethernet_frame, FlowKey};
+use crate::tcp_analysis::{analyze_tcp_packet, TcpTimestamp};
+
+/// Bytes of each accepted packet to copy (covers GSO super-packets)
+const CAPTURE_LEN: u32 = 262_144;
+
+/// Verifier configuration
+#[derive(Debug, Clone)]
+pub struct VerifyConfig {
+    /// Interface the proxy's upstream connections leave through
+    pub interface: String,
+    /// Check one in this many outgoing TCP segments
+    pub sample_rate: u32,
+    /// Timestamp value that is expected on the wire (static spoofing);
+    /// any other timestamp option is a leak
+    pub allowed_ts_val: Option<u32>,
+}
+
+/// Upstream connections the verifier should watch
+#[derive(Debug, Default)]
+pub struct ProxiedFlows {
+    /// Outgoing 4-tuple -> whether a leak was already reported for it
+    flows: Mutex<HashMap<FlowKey, bool>>,
+}
+
+impl ProxiedFlows {
+    /// Watch the connection from `local` to `remote` until the returned
+    /// registration is dropped
+    pub fn register(self: &Arc<Self>, local: SocketAddr, remote: SocketAddr) -> FlowRegistration {
+        let key = FlowKey {
+            src: local.ip(),
+            dst: remote.ip(),
+            src_port: local.port(),
+            dst_port: remote.port(),
+        };
+        self.flows.lock().unwrap().insert(key, false);
+        FlowRegistration {
+            flows: self.clone(),
+            key,
+        }
+    }
+
+    /// Some(first_report) if the flow is proxied; marks it as reported
+    fn report(&self, key: &FlowKey, leaked: bool) -> Option<bool> {
+        let mut flows = self.flows.lock().unwrap();
+        let reported = flows.get_mut(key)?;
+        let first = leaked && !*reported;
+        *reported |= leaked;
+        Some(first)
+    }
+}
+
+/// Keeps a flow watched; dropping it stops verification for the flow
+pub struct FlowRegistration {
+    flows: Arc<ProxiedFlows>,
+    key: FlowKey,
+}
+
+impl Drop for FlowRegistration {
+    fn drop(&mut self) {
+        self.flows.flows.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// The timestamp that escaped in a segment's options, if any
+pub fn find_leak(options: &[u8], allowed_ts_val: Option<u32>) -> Option<TcpTimestamp> {
+    let analysis = analyze_tcp_packet(options);
+    if !analysis.has_timestamp {
+        return None;
+    }
+    match (analysis.timestamp, allowed_ts_val) {
+        (Some(ts), Some(allowed)) if ts.ts_val == allowed => None,
+        (Some(ts), _) => Some(ts),
+        // Malformed timestamp option: still a leak
+        (None, _) => Some(TcpTimestamp { ts_val: 0, ts_ecr: 0 }),
+    }
+}
+
+/// A capture socket on the egress interface
+pub struct EgressVerifier {
+    config: VerifyConfig,
+    fd: Arc<OwnedFd>,
+    flows: Arc<ProxiedFlows>,
+}
+
+impl EgressVerifier {
+    pub fn open(config: VerifyConfig, flows: Arc<ProxiedFlows>) -> io::Result<Self> {
+        let fd = capture::captures().open("verify-egress", &config.interface, capture_filter(config.sample_rate.max(1)))?;
+        Ok(Self { config, fd, flows })
+    }
+
+    /// Check sampled segments until the capture socket fails
+    pub fn run(self) -> io::Result<()> {
+        info!(
+            "Verifying egress on {} (1 in {} outgoing TCP segments)",
+            self.config.interface,
+            self.config.sample_rate.max(1)
+        );
+
+        let registry = metrics::registry();
+        let sampled = registry.counter("tcpstrip_verify_sampled_total", "Outgoing segments of proxied flows checked on the wire");
+        let leaks = registry.counter("tcpstrip_verify_leaks_total", "Checked segments that still carried a timestamp option");
+        let leaking_flows = registry.counter("tcpstrip_verify_leaking_flows_total", "Proxied flows seen leaking timestamps");
+
+        let mut buf = vec![0u8; CAPTURE_LEN as usize];
+        loop {
+            let n = capture::recv(&self.fd, &mut buf)?;
+            let frame = &buf[..n];
+            let Some(segment) = parse_ethernet_frame(frame) else {
+                continue;
+            };
+
+            let key = segment.flow_key(frame);
+            let leak = find_leak(segment.options(frame), self.config.allowed_ts_val);
+            let Some(first_report) = self.flows.report(&key, leak.is_some()) else {
+                continue;
+            };
+            sampled.inc();
+
+            if let Some(ts) = leak {
+                leaks.inc();
+                if first_report {
+                    leaking_flows.inc();
+                    warn!(
+                        "Timestamp option escaped on proxied flow {}:{} -> {}:{} (TSval={}); protection is not effective",
+                        key.src, key.src_port, key.dst, key.dst_port, ts.ts_val
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// What `audit` saw
+#[derive(Debug, Default)]
+pub struct Audit {
+    /// Outgoing segments checked
+    pub checked: u64,
+    /// Flows that carried a timestamp option, with the first TSval seen
+    pub leaking: Vec<(FlowKey, u32)>,
+}
+
+/// Check every outgoing TCP segment on `interface` to one of `targets` (to
+/// anywhere if there are none) for `duration`
+pub fn audit(interface: &str, targets: &[SocketAddr], duration: Duration, allowed_ts_val: Option<u32>) -> io::Result<Audit> {
+    let scope = capture::Scope {
+        targets: targets.iter().copied().collect(),
+        ..Default::default()
+    };
+    let filter = match targets.is_empty() {
+        true => capture_filter(1),
+        false => scope.confine(&capture_filter(1)),
+    };
+    let fd = capture::open(interface, &filter)?;
+    let deadline = Instant::now() + duration;
+    let mut audit = Audit::default();
+    let mut reported = HashSet::new();
+    let mut buf = vec![0u8; CAPTURE_LEN as usize];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Ok(audit);
+        }
+        // A zero timeout would block for good
+        socket2::SockRef::from(&fd).set_read_timeout(Some(left.max(Duration::from_millis(1))))?;
+        let n = match capture::recv(&fd, &mut buf) {
+            Ok(n) => n,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e),
+        };
+        let frame = &buf[..n];
+        let Some(segment) = parse_ethernet_frame(frame) else {
+            continue;
+        };
+        let key = segment.flow_key(frame);
+        if !targets.is_empty() && !targets.iter().any(|target| target.ip() == key.dst && target.port() == key.dst_port) {
+            continue;
+        }
+        audit.checked += 1;
+        if let Some(ts) = find_leak(segment.options(frame), allowed_ts_val) {
+            if reported.insert(key) {
+                audit.leaking.push((key, ts.ts_val));
+            }
+        }
+    }
+}
+
+/// Classic BPF program accepting one in `sample_rate` outgoing TCP segments
+fn capture_filter(sample_rate: u32) -> Vec<libc::sock_filter> {
+    use capture::*;
+
+    vec![
+        insn(LD_W_ABS, 0, 0, SKF_AD_OFF + SKF_AD_PKTTYPE),
+        insn(JEQ_K, 0, 11, libc::PACKET_OUTGOING as u32),
+        insn(LD_H_ABS, 0, 0, 12),
+        insn(JEQ_K, 3, 0, libc::ETH_P_IP as u32),
+        insn(JEQ_K, 0, 8, libc::ETH_P_IPV6 as u32),
+        insn(LD_B_ABS, 0, 0, 14 + 6), // IPv6 next header
+        insn(JEQ_K, 2, 6, libc::IPPROTO_TCP as u32),
+        insn(LD_B_ABS, 0, 0, 14 + 9), // IPv4 protocol
+        insn(JEQ_K, 0, 4, libc::IPPROTO_TCP as u32),
+        insn(LD_W_ABS, 0, 0, SKF_AD_OFF + SKF_AD_RANDOM),
+        insn(MOD_K, 0, 0, sample_rate),
+        insn(JEQ_K, 0, 1, 0),
+        insn(RET_K, 0, 0, CAPTURE_LEN),
+        insn(RET_K, 0, 0, 0),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_leak() {
+        let nop_nop_ts = [1, 1, 8, 10, 0, 0, 0x30, 0x39, 0, 0, 0, 7];
+        let mss_only = [2, 4, 0x05, 0xb4];
+
+        assert!(find_leak(&mss_only, None).is_none());
+        assert_eq!(find_leak(&nop_nop_ts, None).map(|ts| ts.ts_val), Some(12345));
+        assert!(find_leak(&nop_nop_ts, Some(12345)).is_none());
+        assert!(find_leak(&nop_nop_ts, Some(1)).is_some());
+    }
+
+    #[test]
+    fn test_leaks_reported_once_per_flow() {
+        let flows = Arc::new(ProxiedFlows::default());
+        let local: SocketAddr = "10.0.0.7:51234".parse().unwrap();
+        let remote: SocketAddr = "10.1.0.5:9000".parse().unwrap();
+        let key = FlowKey {
+            src: local.ip(),
+            dst: remote.ip(),
+            src_port: local.port(),
+            dst_port: remote.port(),
+        };
+
+        let registration = flows.register(local, remote);
+        assert_eq!(flows.report(&key, false), Some(false));
+        assert_eq!(flows.report(&key, true), Some(true));
+        assert_eq!(flows.report(&key, true), Some(false));
+        assert_eq!(flows.report(&key.reversed(), true), None);
+
+        drop(registration);
+        assert_eq!(flows.report(&key, true), None);
+    }
+}
@@ -0,0 +1,503 @@
+//! XDP ingress option scrubber (Linux only)
+//!
+//! The socket proxy cannot choose the options its peers send: a client's
+//! SYN carrying timestamps makes the local stack negotiate them, and so
+//! does a target's SYN-ACK. An XDP program on the interface blanks the
+//! unwanted options of those handshake segments with NOPs before the
+//! stack sees them, so the proxy's own sockets never agree to use them.
+//!
+//! Only segments of protected ports are touched: SYNs to a port the proxy
+//! listens on and SYN-ACKs from a port it dials. The proxy fills a hash
+//! map with those ports (key: port in network order, value: a bit for
+//! each role), so the program needs no reloading when they change:
+//!
+//! ```text
+//! r6 = ctx->data, r7 = ctx->data_end
+//! r8 = TCP header                 ; Ethernet, IPv4 (unfragmented) or IPv6
+//!                                 ; without extension headers
+//! if flags == SYN:     key = dport, role = LISTENER
+//! if flags == SYN|ACK: key = sport, role = TARGET
+//! r0 = bpf_map_lookup_elem(ports, &key)
+//! if r0 == 0 || !(*r0 & role) goto pass
+//! for off in options:             ; bounded loop over <= 40 bytes
+//!     kind == EOL: break
+//!     kind == NOP: off += 1; continue
+//!     kind in blanked: mask |= ((1 << len) - 1) << off
+//!     off += len
+//! if mask == 0 goto pass
+//! for byte in 0..40:              ; unrolled
+//!     if mask & 1 << byte: sum the old byte, write a NOP, sum the NOP
+//! checksum = ~(~checksum + ~old + new) ; RFC 1624 incremental update
+//! stats[0] += 1
+//! pass:
+//! r0 = XDP_PASS
+//! exit
+//! ```
+//!
+//! Every frame is passed; anything the program does not understand (VLAN
+//! tags, IP options it cannot skip, malformed option lists) goes up the
+//! stack untouched. Blanking keeps the segment's length, so nothing but
+//! the TCP checksum has to be fixed up.
+//!
+//! Requires Linux 5.3+ (bounded loops) and CAP_BPF + CAP_NET_ADMIN (or
+//! CAP_SYS_ADMIN). Drivers without native XDP support get the generic
+//! (skb) mode.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::sync::Mutex;
+
+use crate::bpf::{self, BpfInsn};
+
+/// Most ports the map can hold
+const MAX_PORTS: u32 = 1024;
+
+const XDP_PASS: i32 = 2;
+
+/// Option kinds blanked by default: timestamps
+pub const TIMESTAMPS: u8 = 8;
+
+/// Which handshake segments of a port are scrubbed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    /// SYNs arriving for a port the proxy listens on
+    Listener = 1,
+    /// SYN-ACKs arriving from a port the proxy connects to
+    Target = 2,
+}
+
+/// The program attached to one interface and the maps it reads
+///
+/// Dropping it closes the BPF link, which detaches the program.
+pub struct IngressScrubber {
+    ports: OwnedFd,
+    stats: OwnedFd,
+    /// Roles of each protected port, as last written to `ports`
+    roles: Mutex<HashMap<u16, u8>>,
+    _link: OwnedFd,
+}
+
+impl IngressScrubber {
+    /// Attach a program blanking options of the `kinds` given on
+    /// `interface`; no port is protected until [`protect`](Self::protect)
+    pub fn attach(interface: &str, kinds: &[u8]) -> io::Result<Self> {
+        let ifindex = interface_index(interface)?;
+        let ports = bpf::create_map(bpf::BPF_MAP_TYPE_HASH, 2, 1, MAX_PORTS)?;
+        let stats = bpf::create_map(bpf::BPF_MAP_TYPE_ARRAY, 4, 8, 1)?;
+        let prog = load(&ports, &stats, kinds)?;
+        let link = bpf::link_create(&prog, ifindex, bpf::BPF_XDP)
+            .map_err(|e| io::Error::new(e.kind(), format!("attaching to {}: {}", interface, e)))?;
+
+        Ok(Self {
+            ports,
+            stats,
+            roles: Mutex::new(HashMap::new()),
+            _link: link,
+        })
+    }
+
+    /// Scrub the handshake segments `side` sees on `port`
+    pub fn protect(&self, port: u16, side: Side) -> io::Result<()> {
+        let mut roles = self.roles.lock().unwrap();
+        let role = roles.get(&port).copied().unwrap_or(0) | side as u8;
+        bpf::update_elem(&self.ports, &port.to_be_bytes(), &role, 0)?;
+        roles.insert(port, role);
+        Ok(())
+    }
+
+    /// Handshake segments the program has rewritten so far
+    pub fn scrubbed(&self) -> io::Result<u64> {
+        let mut count = 0u64;
+        bpf::lookup_elem(&self.stats, &0u32, &mut count)?;
+        Ok(count)
+    }
+}
+
+/// One instruction, or a jump whose offset is resolved once every label
+/// is placed
+enum Item {
+    Insn(BpfInsn),
+    Jump {
+        code: u8,
+        dst: u8,
+        src: u8,
+        imm: i32,
+        to: &'static str,
+    },
+    Label(&'static str),
+}
+
+/// Just enough of an assembler to keep the program's jumps readable
+#[derive(Default)]
+struct Asm {
+    items: Vec<Item>,
+}
+
+impl Asm {
+    fn insn(&mut self, code: u8, dst: u8, src: u8, off: i16, imm: i32) {
+        self.items.push(Item::Insn(BpfInsn::new(code, dst, src, off, imm)));
+    }
+
+    /// A 64-bit immediate load of a map's file descriptor (two slots)
+    fn map(&mut self, dst: u8, map: &OwnedFd) {
+        self.insn(0x18, dst, bpf::BPF_PSEUDO_MAP_FD, 0, map.as_raw_fd());
+        self.insn(0, 0, 0, 0, 0);
+    }
+
+    fn jump(&mut self, code: u8, dst: u8, src: u8, imm: i32, to: &'static str) {
+        self.items.push(Item::Jump { code, dst, src, imm, to });
+    }
+
+    fn label(&mut self, name: &'static str) {
+        self.items.push(Item::Label(name));
+    }
+
+    fn finish(self) -> Vec<BpfInsn> {
+        let mut labels = HashMap::new();
+        let mut pc = 0;
+        for item in &self.items {
+            match item {
+                Item::Label(name) => {
+                    labels.insert(*name, pc);
+                }
+                _ => pc += 1,
+            }
+        }
+        let mut insns = Vec::with_capacity(pc);
+        for item in self.items {
+            match item {
+                Item::Insn(insn) => insns.push(insn),
+                Item::Jump { code, dst, src, imm, to } => {
+                    let target: usize = labels[to];
+                    let off = target as isize - insns.len() as isize - 1;
+                    insns.push(BpfInsn::new(code, dst, src, off as i16, imm));
+                }
+                Item::Label(_) => {}
+            }
+        }
+        insns
+    }
+}
+
+/// A 16-bit field as `ldxh` loads it, given its value in network order
+fn wire16(value: u16) -> i32 {
+    u16::from_ne_bytes(value.to_be_bytes()) as i32
+}
+
+/// Assemble the scrubbing program and load it
+fn load(ports: &OwnedFd, stats: &OwnedFd, kinds: &[u8]) -> io::Result<OwnedFd> {
+    // Registers: r6 packet start, r7 packet end, r8 TCP header, r9 offset
+    // into the options (after the map lookup), r4/r5 sums of the old and
+    // new option bytes
+    let mut asm = Asm::default();
+    asm.insn(0x61, 6, 1, 0, 0); // ldxw r6, [r1 + 0]
+    asm.insn(0x61, 7, 1, 4, 0); // ldxw r7, [r1 + 4]
+    asm.insn(0xbf, 2, 6, 0, 0); // mov r2, r6
+    asm.insn(0x07, 2, 0, 0, 14); // add r2, 14
+    asm.jump(0x2d, 2, 7, 0, "pass"); // jgt r2, r7
+    asm.insn(0x69, 3, 6, 12, 0); // ldxh r3, [r6 + 12]
+    asm.jump(0x15, 3, 0, wire16(0x0800), "ipv4"); // jeq r3, ETH_P_IP
+    asm.jump(0x55, 3, 0, wire16(0x86dd), "pass"); // jne r3, ETH_P_IPV6
+
+    asm.insn(0xbf, 2, 6, 0, 0); // mov r2, r6
+    asm.insn(0x07, 2, 0, 0, 54); // add r2, 14 + 40
+    asm.jump(0x2d, 2, 7, 0, "pass"); // jgt r2, r7
+    asm.insn(0x71, 3, 6, 20, 0); // ldxb r3, [r6 + 20] (next header)
+    asm.jump(0x55, 3, 0, libc::IPPROTO_TCP, "pass"); // jne r3, TCP
+    asm.insn(0xbf, 8, 6, 0, 0); // mov r8, r6
+    asm.insn(0x07, 8, 0, 0, 54); // add r8, 54
+    asm.jump(0x05, 0, 0, 0, "tcp"); // ja
+
+    asm.label("ipv4");
+    asm.insn(0xbf, 2, 6, 0, 0); // mov r2, r6
+    asm.insn(0x07, 2, 0, 0, 34); // add r2, 14 + 20
+    asm.jump(0x2d, 2, 7, 0, "pass"); // jgt r2, r7
+    asm.insn(0x71, 3, 6, 23, 0); // ldxb r3, [r6 + 23] (protocol)
+    asm.jump(0x55, 3, 0, libc::IPPROTO_TCP, "pass"); // jne r3, TCP
+    asm.insn(0x69, 3, 6, 20, 0); // ldxh r3, [r6 + 20] (flags, fragment offset)
+    asm.insn(0x57, 3, 0, 0, wire16(0x3fff)); // and r3, MF | offset
+    asm.jump(0x55, 3, 0, 0, "pass"); // jne r3, 0
+    asm.insn(0x71, 3, 6, 14, 0); // ldxb r3, [r6 + 14]
+    asm.insn(0x57, 3, 0, 0, 0x0f); // and r3, 0x0f (IHL)
+    asm.insn(0x67, 3, 0, 0, 2); // lsh r3, 2
+    asm.jump(0xa5, 3, 0, 20, "pass"); // jlt r3, 20
+    asm.insn(0xbf, 8, 6, 0, 0); // mov r8, r6
+    asm.insn(0x07, 8, 0, 0, 14); // add r8, 14
+    asm.insn(0x0f, 8, 3, 0, 0); // add r8, r3
+
+    asm.label("tcp");
+    asm.insn(0xbf, 2, 8, 0, 0); // mov r2, r8
+    asm.insn(0x07, 2, 0, 0, 20); // add r2, 20
+    asm.jump(0x2d, 2, 7, 0, "pass"); // jgt r2, r7
+    asm.insn(0x71, 3, 8, 13, 0); // ldxb r3, [r8 + 13] (flags)
+    asm.insn(0x57, 3, 0, 0, 0x17); // and r3, ACK | RST | SYN | FIN
+    asm.jump(0x15, 3, 0, 0x02, "syn"); // jeq r3, SYN
+    asm.jump(0x55, 3, 0, 0x12, "pass"); // jne r3, SYN | ACK
+    asm.insn(0x69, 3, 8, 0, 0); // ldxh r3, [r8 + 0] (source port)
+    asm.insn(0xb7, 9, 0, 0, Side::Target as i32); // mov r9, TARGET
+    asm.jump(0x05, 0, 0, 0, "lookup"); // ja
+    asm.label("syn");
+    asm.insn(0x69, 3, 8, 2, 0); // ldxh r3, [r8 + 2] (destination port)
+    asm.insn(0xb7, 9, 0, 0, Side::Listener as i32); // mov r9, LISTENER
+
+    asm.label("lookup");
+    asm.insn(0x6b, 10, 3, -2, 0); // stxh [r10 - 2], r3
+    asm.map(1, ports); // lddw r1, ports
+    asm.insn(0xbf, 2, 10, 0, 0); // mov r2, r10
+    asm.insn(0x07, 2, 0, 0, -2); // add r2, -2
+    asm.insn(0x85, 0, 0, 0, bpf::BPF_FUNC_MAP_LOOKUP_ELEM); // call
+    asm.jump(0x15, 0, 0, 0, "pass"); // jeq r0, 0
+    asm.insn(0x71, 0, 0, 0, 0); // ldxb r0, [r0 + 0]
+    asm.insn(0x5f, 0, 9, 0, 0); // and r0, r9
+    asm.jump(0x15, 0, 0, 0, "pass"); // jeq r0, 0
+
+    asm.insn(0x71, 6, 8, 12, 0); // ldxb r6, [r8 + 12]
+    asm.insn(0x77, 6, 0, 0, 4); // rsh r6, 4 (data offset)
+    asm.insn(0x67, 6, 0, 0, 2); // lsh r6, 2
+    asm.jump(0xb5, 6, 0, 20, "pass"); // jle r6, 20: no options
+    asm.insn(0x17, 6, 0, 0, 20); // sub r6, 20 (option bytes)
+    asm.insn(0xb7, 9, 0, 0, 0); // mov r9, 0
+    asm.insn(0xb7, 4, 0, 0, 0); // mov r4, 0
+
+    // Walk the options, setting a bit in r4 for each byte to blank
+    asm.label("option");
+    asm.jump(0x3d, 9, 6, 0, "walked"); // jge r9, r6
+    asm.insn(0xbf, 1, 8, 0, 0); // mov r1, r8
+    asm.insn(0x07, 1, 0, 0, 20); // add r1, 20
+    asm.insn(0x0f, 1, 9, 0, 0); // add r1, r9
+    asm.insn(0xbf, 2, 1, 0, 0); // mov r2, r1
+    asm.insn(0x07, 2, 0, 0, 2); // add r2, 2
+    asm.jump(0x2d, 2, 7, 0, "walked"); // jgt r2, r7 (a lone trailing byte is padding)
+    asm.insn(0x71, 2, 1, 0, 0); // ldxb r2, [r1 + 0] (kind)
+    asm.jump(0x15, 2, 0, 0, "walked"); // jeq r2, EOL
+    asm.jump(0x55, 2, 0, 1, "sized"); // jne r2, NOP
+    asm.insn(0x07, 9, 0, 0, 1); // add r9, 1
+    asm.jump(0x05, 0, 0, 0, "option"); // ja
+    asm.label("sized");
+    asm.insn(0x71, 3, 1, 1, 0); // ldxb r3, [r1 + 1] (length)
+    asm.jump(0xa5, 3, 0, 2, "walked"); // jlt r3, 2
+    asm.insn(0xbf, 0, 9, 0, 0); // mov r0, r9
+    asm.insn(0x0f, 0, 3, 0, 0); // add r0, r3
+    asm.jump(0x2d, 0, 6, 0, "walked"); // jgt r0, r6 (overruns the header)
+    for &kind in kinds {
+        asm.jump(0x15, 2, 0, kind as i32, "blank"); // jeq r2, kind
+    }
+    asm.insn(0x0f, 9, 3, 0, 0); // add r9, r3
+    asm.jump(0x05, 0, 0, 0, "option"); // ja
+    asm.label("blank");
+    asm.insn(0xb7, 0, 0, 0, 1); // mov r0, 1
+    asm.insn(0x6f, 0, 3, 0, 0); // lsh r0, r3
+    asm.insn(0x17, 0, 0, 0, 1); // sub r0, 1
+    asm.insn(0x6f, 0, 9, 0, 0); // lsh r0, r9
+    asm.insn(0x4f, 4, 0, 0, 0); // or r4, r0
+    asm.insn(0x0f, 9, 3, 0, 0); // add r9, r3
+    asm.jump(0x05, 0, 0, 0, "option"); // ja
+
+    // Overwrite the marked bytes, summing them before (r5) and after (r6)
+    // as halves of checksum words. Byte N of the options sits at offset
+    // 20 + N of the header, the first half of a word when N is even; ldxh
+    // reads that half into the low byte on little-endian hosts
+    asm.label("walked");
+    asm.jump(0x15, 4, 0, 0, "pass"); // jeq r4, 0: nothing to blank
+    asm.insn(0xb7, 5, 0, 0, 0); // mov r5, 0
+    asm.insn(0xb7, 6, 0, 0, 0); // mov r6, 0
+    for byte in 0..40 {
+        let offset = 20 + byte;
+        let shift = match (byte % 2 == 1) == cfg!(target_endian = "little") {
+            true => 8,
+            false => 0,
+        };
+        asm.insn(0xbf, 1, 4, 0, 0); // mov r1, r4
+        asm.insn(0x77, 1, 0, 0, byte as i32); // rsh r1, byte
+        asm.insn(0x57, 1, 0, 0, 1); // and r1, 1
+        asm.insn(0x15, 1, 0, 8, 0); // jeq r1, 0, next byte
+        asm.insn(0xbf, 2, 8, 0, 0); // mov r2, r8
+        asm.insn(0x07, 2, 0, 0, offset as i32 + 1); // add r2, offset + 1
+        asm.jump(0x2d, 2, 7, 0, "checksum"); // jgt r2, r7
+        asm.insn(0x71, 1, 8, offset, 0); // ldxb r1, [r8 + offset]
+        asm.insn(0x67, 1, 0, 0, shift); // lsh r1, shift
+        asm.insn(0x0f, 5, 1, 0, 0); // add r5, r1
+        asm.insn(0x72, 8, 0, offset, 1); // stb [r8 + offset], NOP
+        asm.insn(0x07, 6, 0, 0, 1 << shift); // add r6, NOP << shift
+    }
+
+    asm.label("checksum");
+    asm.insn(0x69, 1, 8, 16, 0); // ldxh r1, [r8 + 16]
+    asm.insn(0xa7, 1, 0, 0, 0xffff); // xor r1, 0xffff
+    for _ in 0..2 {
+        asm.insn(0xbf, 2, 5, 0, 0); // mov r2, r5
+        asm.insn(0x77, 2, 0, 0, 16); // rsh r2, 16
+        asm.insn(0x57, 5, 0, 0, 0xffff); // and r5, 0xffff
+        asm.insn(0x0f, 5, 2, 0, 0); // add r5, r2
+    }
+    asm.insn(0xa7, 5, 0, 0, 0xffff); // xor r5, 0xffff
+    asm.insn(0x0f, 1, 5, 0, 0); // add r1, r5
+    asm.insn(0x0f, 1, 6, 0, 0); // add r1, r6
+    for _ in 0..2 {
+        asm.insn(0xbf, 2, 1, 0, 0); // mov r2, r1
+        asm.insn(0x77, 2, 0, 0, 16); // rsh r2, 16
+        asm.insn(0x57, 1, 0, 0, 0xffff); // and r1, 0xffff
+        asm.insn(0x0f, 1, 2, 0, 0); // add r1, r2
+    }
+    asm.insn(0xa7, 1, 0, 0, 0xffff); // xor r1, 0xffff
+    asm.insn(0x6b, 8, 1, 16, 0); // stxh [r8 + 16], r1
+
+    asm.insn(0x62, 10, 0, -8, 0); // stw [r10 - 8], 0
+    asm.map(1, stats); // lddw r1, stats
+    asm.insn(0xbf, 2, 10, 0, 0); // mov r2, r10
+    asm.insn(0x07, 2, 0, 0, -8); // add r2, -8
+    asm.insn(0x85, 0, 0, 0, bpf::BPF_FUNC_MAP_LOOKUP_ELEM); // call
+    asm.jump(0x15, 0, 0, 0, "pass"); // jeq r0, 0
+    asm.insn(0xb7, 1, 0, 0, 1); // mov r1, 1
+    asm.insn(0xdb, 0, 1, 0, 0); // lock *(u64 *)(r0 + 0) += r1
+
+    asm.label("pass");
+    asm.insn(0xb7, 0, 0, 0, XDP_PASS); // mov r0, XDP_PASS
+    asm.insn(0x95, 0, 0, 0, 0); // exit
+
+    bpf::load_program(bpf::BPF_PROG_TYPE_XDP, 0, "tcpstrip_ingress", &asm.finish())
+}
+
+fn interface_index(name: &str) -> io::Result<u32> {
+    let c_name = CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"))?;
+    let ifindex = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+    if ifindex == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ifindex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{parse_ethernet_frame, update_checksums, verify_checksums};
+
+    const MSS: [u8; 4] = [2, 4, 0x05, 0xb4];
+    const SACK_PERMITTED: [u8; 2] = [4, 2];
+    const TIMESTAMPS_OPTION: [u8; 10] = [8, 10, 0, 0, 0x12, 0x34, 0, 0, 0, 0];
+    const WINDOW_SCALE: [u8; 4] = [1, 3, 3, 7];
+
+    /// An Ethernet + IPv4 + TCP frame from port 40000 to 443 with valid
+    /// checksums
+    fn frame(flags: u8, sport: u16, dport: u16, options: &[u8]) -> Vec<u8> {
+        let tcp_len = 20 + options.len();
+        let mut frame = vec![0u8; 14];
+        frame[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+        frame.extend_from_slice(&[0x45, 0, 0, 0, 0, 1, 0x40, 0, 64, 6, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+        frame[16..18].copy_from_slice(&((20 + tcp_len) as u16).to_be_bytes());
+        frame.extend_from_slice(&sport.to_be_bytes());
+        frame.extend_from_slice(&dport.to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, (tcp_len as u8 / 4) << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        frame.extend_from_slice(options);
+        let segment = parse_ethernet_frame(&frame).unwrap();
+        update_checksums(&mut frame, &segment);
+        frame
+    }
+
+    /// The same segment over IPv6
+    fn frame6(flags: u8, sport: u16, dport: u16, options: &[u8]) -> Vec<u8> {
+        let v4 = frame(flags, sport, dport, options);
+        let mut frame = v4[..14].to_vec();
+        frame[12..14].copy_from_slice(&0x86ddu16.to_be_bytes());
+        frame.extend_from_slice(&[0x60, 0, 0, 0]);
+        frame.extend_from_slice(&((v4.len() - 34) as u16).to_be_bytes());
+        frame.extend_from_slice(&[6, 64]);
+        frame.extend_from_slice(&[0xfd; 16]);
+        frame.extend_from_slice(&[0xfe; 16]);
+        frame.extend_from_slice(&v4[34..]);
+        let segment = parse_ethernet_frame(&frame).unwrap();
+        update_checksums(&mut frame, &segment);
+        frame
+    }
+
+    /// Load the program with `ports` protected; None without CAP_BPF
+    fn program(kinds: &[u8], ports: &[(u16, Side)]) -> Option<(OwnedFd, OwnedFd)> {
+        let map = match bpf::create_map(bpf::BPF_MAP_TYPE_HASH, 2, 1, MAX_PORTS) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return None,
+            map => map.unwrap(),
+        };
+        let stats = bpf::create_map(bpf::BPF_MAP_TYPE_ARRAY, 4, 8, 1).unwrap();
+        for &(port, side) in ports {
+            bpf::update_elem(&map, &port.to_be_bytes(), &(side as u8), 0).unwrap();
+        }
+        Some((load(&map, &stats, kinds).unwrap(), stats))
+    }
+
+    fn options_of(frame: &[u8]) -> &[u8] {
+        let segment = parse_ethernet_frame(frame).unwrap();
+        &frame[segment.l4_offset + 20..segment.l4_offset + segment.tcp_header_len]
+    }
+
+    #[test]
+    fn test_blanks_protected_syns() {
+        let Some((prog, stats)) = program(&[TIMESTAMPS, 4], &[(443, Side::Listener)]) else {
+            return;
+        };
+        let options = [&MSS[..], &SACK_PERMITTED, &TIMESTAMPS_OPTION, &WINDOW_SCALE].concat();
+        let syn = frame(0x02, 40000, 443, &options);
+
+        let (verdict, out) = bpf::test_run(&prog, &syn).unwrap();
+        assert_eq!(verdict, XDP_PASS as u32);
+        let blanked = [&MSS[..], &[1; 12], &WINDOW_SCALE].concat();
+        assert_eq!(options_of(&out), &blanked[..]);
+        assert!(verify_checksums(&out, &parse_ethernet_frame(&out).unwrap()));
+
+        let mut count = 0u64;
+        bpf::lookup_elem(&stats, &0u32, &mut count).unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_blanks_odd_offsets() {
+        let Some((prog, _)) = program(&[TIMESTAMPS], &[(443, Side::Target)]) else {
+            return;
+        };
+        // A single NOP first puts the option at an odd offset
+        let options = [&[1][..], &TIMESTAMPS_OPTION, &[1, 4, 2, 0, 0]].concat();
+        let syn_ack = frame(0x12, 443, 40000, &options);
+
+        let (_, out) = bpf::test_run(&prog, &syn_ack).unwrap();
+        let blanked = [&[1; 11][..], &[1, 4, 2, 0, 0]].concat();
+        assert_eq!(options_of(&out), &blanked[..]);
+        assert!(verify_checksums(&out, &parse_ethernet_frame(&out).unwrap()));
+    }
+
+    #[test]
+    fn test_blanks_ipv6() {
+        let Some((prog, _)) = program(&[TIMESTAMPS, 3], &[(443, Side::Listener)]) else {
+            return;
+        };
+        let options = [&MSS[..], &TIMESTAMPS_OPTION, &WINDOW_SCALE, &[1, 1]].concat();
+        let syn = frame6(0x02, 40000, 443, &options);
+
+        let (_, out) = bpf::test_run(&prog, &syn).unwrap();
+        let blanked = [&MSS[..], &[1; 10], &[1, 1, 1, 1], &[1, 1]].concat();
+        assert_eq!(options_of(&out), &blanked[..]);
+        assert!(verify_checksums(&out, &parse_ethernet_frame(&out).unwrap()));
+    }
+
+    #[test]
+    fn test_leaves_other_segments() {
+        let Some((prog, stats)) = program(&[TIMESTAMPS], &[(443, Side::Listener)]) else {
+            return;
+        };
+        let options = [&MSS[..], &TIMESTAMPS_OPTION, &[1, 1]].concat();
+        for untouched in [
+            frame(0x02, 40000, 80, &options),  // unprotected port
+            frame(0x12, 443, 40000, &options), // SYN-ACK from a listener port
+            frame(0x10, 40000, 443, &options), // not a handshake segment
+            frame(0x02, 40000, 443, &[2, 4, 0x05, 0xb4, 8, 1, 0, 0]), // bogus length
+        ] {
+            let (verdict, out) = bpf::test_run(&prog, &untouched).unwrap();
+            assert_eq!(verdict, XDP_PASS as u32);
+            assert_eq!(out, untouched);
+        }
+
+        let mut count = 0u64;
+        bpf::lookup_elem(&stats, &0u32, &mut count).unwrap();
+        assert_eq!(count, 0);
+    }
+}